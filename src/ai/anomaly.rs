@@ -0,0 +1,260 @@
+//! Audit anomaly detection
+//!
+//! Permission checks in the capability audit log, denials and uses of
+//! privileged permissions, are counted per process and permission over
+//! fixed windows. Each process builds a baseline, a
+//! moving average of its per-window counts; once the baseline has settled,
+//! a window far above it raises an alert on the subscribed IPC channel.
+//! Heavy use of a permission the process never used before, and bursts of
+//! denied checks, are reported the same way.
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use crate::capability::{self, AuditEntry, AUDIT_DENIED, PERMISSION_COUNT};
+use crate::ipc::{self, MessageHeader};
+
+/// IPC message type for alerts; payload: process, kind, permission (0xff
+/// for denial bursts), count in the window, baseline per window (u32 each)
+pub const ANOMALY_ALERT_MSG: u32 = 0x4149_0010;
+
+/// Length of a counting window
+pub const WINDOW_TICKS: u64 = crate::scheduler::TICKS_PER_SECOND;
+
+/// Processes with a baseline at once
+const MAX_TRACKED: usize = 64;
+
+/// Windows a process is watched before alerts are raised
+const WARMUP_WINDOWS: u32 = 8;
+
+/// Fewest checks in a window that can be anomalous
+const MIN_COUNT: u32 = 16;
+
+/// Fewest denials in a window that count as a burst
+const MIN_DENIALS: u32 = 8;
+
+/// How far above baseline a window must be
+const SPIKE_FACTOR: u32 = 8;
+
+/// Fixed-point scale of baselines
+const FP: u32 = 16;
+
+/// Audit entries read per call
+const READ_CHUNK: usize = 64;
+
+/// Permission reported for denial bursts
+const ANY_PERMISSION: u32 = 0xff;
+
+/// What made a window anomalous
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum AnomalyKind {
+    /// Uses of a permission far above the process's baseline
+    Spike = 1,
+    /// Heavy use of a permission the process had not used before
+    Novel = 2,
+    /// Many denied checks
+    Denials = 3,
+}
+
+/// Detector counters
+#[derive(Clone, Copy, Debug)]
+pub struct AnomalyStats {
+    /// Audit entries examined
+    pub processed: u64,
+    /// Audit entries overwritten before they were read
+    pub lost: u64,
+    pub alerts: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Baseline {
+    process: u32,
+    /// Checks per permission in the current window
+    counts: [u32; PERMISSION_COUNT],
+    denied: u32,
+    /// Moving averages per window, scaled by `FP`
+    mean: [u32; PERMISSION_COUNT],
+    denied_mean: u32,
+    windows: u32,
+    last_active: u64,
+}
+
+impl Baseline {
+    fn new(process: u32, now: u64) -> Self {
+        Self {
+            process,
+            counts: [0; PERMISSION_COUNT],
+            denied: 0,
+            mean: [0; PERMISSION_COUNT],
+            denied_mean: 0,
+            windows: 0,
+            last_active: now,
+        }
+    }
+}
+
+/// Average with weight 1/8 on the newest window
+fn update_mean(mean: u32, count: u32) -> u32 {
+    (mean * 7 + count.saturating_mul(FP).min(u32::MAX / 8)) / 8
+}
+
+fn above(count: u32, mean: u32) -> bool {
+    count as u64 * FP as u64 > mean as u64 * SPIKE_FACTOR as u64
+}
+
+struct Detector {
+    cursor: u64,
+    baselines: ArrayVec<Baseline, MAX_TRACKED>,
+    window_start: u64,
+    channel: Option<u64>,
+    stats: AnomalyStats,
+}
+
+static DETECTOR: Mutex<Detector> = Mutex::new(Detector {
+    cursor: 0,
+    baselines: ArrayVec::new_const(),
+    window_start: 0,
+    channel: None,
+    stats: AnomalyStats {
+        processed: 0,
+        lost: 0,
+        alerts: 0,
+    },
+});
+
+impl Detector {
+    fn baseline(&mut self, process: u32, now: u64) -> &mut Baseline {
+        let index = match self.baselines.iter().position(|b| b.process == process) {
+            Some(index) => index,
+            None if !self.baselines.is_full() => {
+                self.baselines.push(Baseline::new(process, now));
+                self.baselines.len() - 1
+            }
+            None => {
+                let idle = (0..self.baselines.len())
+                    .min_by_key(|&i| self.baselines[i].last_active)
+                    .expect("baselines are full");
+                self.baselines[idle] = Baseline::new(process, now);
+                idle
+            }
+        };
+        &mut self.baselines[index]
+    }
+
+    fn record(&mut self, entry: &AuditEntry) {
+        let Some(permission) = entry.permission().map(usize::from).filter(|&p| p < PERMISSION_COUNT) else {
+            return;
+        };
+        let baseline = self.baseline(entry.process_id, entry.timestamp);
+        baseline.last_active = entry.timestamp;
+        baseline.counts[permission] += 1;
+        if entry.result == AUDIT_DENIED {
+            baseline.denied += 1;
+        }
+    }
+
+    /// Close the window: report anomalies and fold it into the baselines
+    fn close_window(&mut self) {
+        let channel = self.channel;
+        let mut alerts = 0;
+        for baseline in self.baselines.iter_mut() {
+            if baseline.windows >= WARMUP_WINDOWS {
+                for permission in 0..PERMISSION_COUNT {
+                    let (count, mean) = (baseline.counts[permission], baseline.mean[permission]);
+                    if count < MIN_COUNT || !above(count, mean) {
+                        continue;
+                    }
+                    let kind = if mean == 0 { AnomalyKind::Novel } else { AnomalyKind::Spike };
+                    alert(channel, baseline.process, kind, permission as u32, count, mean / FP);
+                    alerts += 1;
+                }
+                if baseline.denied >= MIN_DENIALS && above(baseline.denied, baseline.denied_mean) {
+                    let expected = baseline.denied_mean / FP;
+                    alert(channel, baseline.process, AnomalyKind::Denials, ANY_PERMISSION, baseline.denied, expected);
+                    alerts += 1;
+                }
+            }
+
+            for permission in 0..PERMISSION_COUNT {
+                baseline.mean[permission] = update_mean(baseline.mean[permission], baseline.counts[permission]);
+            }
+            baseline.denied_mean = update_mean(baseline.denied_mean, baseline.denied);
+            baseline.counts = [0; PERMISSION_COUNT];
+            baseline.denied = 0;
+            baseline.windows = baseline.windows.saturating_add(1);
+        }
+        self.stats.alerts += alerts;
+    }
+}
+
+fn alert(channel: Option<u64>, process: u32, kind: AnomalyKind, permission: u32, count: u32, expected: u32) {
+    crate::klog!(
+        Warn,
+        "Audit anomaly: process {} {:?} on permission {} ({} in window, baseline {})",
+        process,
+        kind,
+        permission,
+        count,
+        expected
+    );
+    let Some(channel) = channel else {
+        return;
+    };
+
+    let mut payload = [0u8; 20];
+    for (i, value) in [process, kind as u32, permission, count, expected].into_iter().enumerate() {
+        payload[i * 4..i * 4 + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    let header = MessageHeader {
+        id: 0,
+        sender: 0, // kernel
+        receiver: 0,
+        length: payload.len() as u32,
+        msg_type: ANOMALY_ALERT_MSG,
+    };
+    let _ = ipc::msg_send(channel, header, &payload);
+}
+
+/// Read new audit entries and close the window once it has run out, run
+/// from the AI poll loop
+pub fn poll() {
+    let now = crate::scheduler::ticks();
+    let mut detector = DETECTOR.lock();
+    let mut entries = [AuditEntry {
+        timestamp: 0,
+        process_id: 0,
+        action: 0,
+        result: 0,
+        signature: [0; 16],
+    }; READ_CHUNK];
+
+    loop {
+        let mut cursor = detector.cursor;
+        let (count, lost) = capability::audit_read(&mut cursor, &mut entries);
+        detector.cursor = cursor;
+        detector.stats.lost += lost;
+        detector.stats.processed += count as u64;
+        for entry in &entries[..count] {
+            detector.record(entry);
+        }
+        if count < READ_CHUNK {
+            break;
+        }
+    }
+
+    if now.saturating_sub(detector.window_start) >= WINDOW_TICKS {
+        detector.window_start = now;
+        detector.close_window();
+    }
+}
+
+/// Subscribe an IPC channel to anomaly alerts
+pub fn subscribe(channel: u64) {
+    DETECTOR.lock().channel = Some(channel);
+}
+
+/// Detector counters
+pub fn stats() -> AnomalyStats {
+    DETECTOR.lock().stats
+}
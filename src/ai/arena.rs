@@ -0,0 +1,155 @@
+//! Activation arena planning
+//!
+//! Every vector a graph produces gets a fixed place in one arena, decided
+//! when the model is loaded. A tensor is live from the node that writes it
+//! to the last node that reads it; in-place operations extend the life of
+//! their input rather than creating a tensor. Tensors are placed largest
+//! first at the lowest offset that does not overlap a tensor live at the
+//! same time, so vectors that are never live together share memory.
+//!
+//! The arena size is the peak activation memory of one run, known before
+//! the first request and reported with the model.
+
+use arrayvec::ArrayVec;
+
+use super::graph::{Op, MAX_NODES};
+
+/// Tensor offsets are aligned to this many values (one 64-byte line)
+pub const ALIGN: usize = 16;
+
+#[derive(Clone, Copy)]
+struct Tensor {
+    len: usize,
+    /// First and last node using the tensor
+    first: usize,
+    last: usize,
+    offset: usize,
+}
+
+impl Tensor {
+    fn live_with(&self, other: &Tensor) -> bool {
+        self.first <= other.last && other.first <= self.last
+    }
+}
+
+/// Where each node's vectors live in the arena
+#[derive(Clone, Copy, Debug)]
+pub struct Plan {
+    /// Offset of the vector node `i` reads
+    inputs: [usize; MAX_NODES],
+    /// Offset of the vector node `i` writes, the same as its input for
+    /// in-place operations
+    outputs: [usize; MAX_NODES],
+    /// Offset of node `i`'s working space, for attention layers
+    temps: [usize; MAX_NODES],
+    /// Offset of the graph input
+    pub input: usize,
+    /// Offset of the graph output
+    pub output: usize,
+    /// Arena size in values, a multiple of [`ALIGN`] so arenas can be
+    /// packed back to back
+    pub len: usize,
+}
+
+impl Plan {
+    /// Working space of an attention layer of width `d` over `context`
+    /// positions
+    pub fn attention_temp(d: usize, context: usize) -> usize {
+        5 * d + context
+    }
+
+    /// Plan the tensors of `nodes` applied to an input of `input_len`, with
+    /// attention over `context` positions
+    pub fn new(nodes: &[Op], input_len: usize, context: usize) -> Self {
+        let mut tensors = ArrayVec::<Tensor, { 2 * MAX_NODES + 1 }>::new();
+        tensors.push(Tensor {
+            len: input_len,
+            first: 0,
+            last: 0,
+            offset: 0,
+        });
+        // Tensors each node reads, writes and works in
+        let mut uses = [(0, 0, None); MAX_NODES];
+        let mut live = 0;
+        for (node, op) in nodes.iter().enumerate() {
+            tensors[live].last = node;
+            let read = live;
+            let (len, temp) = match op {
+                Op::Linear { weights, .. } => (weights.rows, 0),
+                Op::Attention { wo, .. } => (wo.rows, Self::attention_temp(wo.rows, context)),
+                _ => (0, 0),
+            };
+            let mut work = None;
+            if temp > 0 {
+                tensors.push(Tensor {
+                    len: temp,
+                    first: node,
+                    last: node,
+                    offset: 0,
+                });
+                work = Some(tensors.len() - 1);
+            }
+            if len > 0 {
+                tensors.push(Tensor {
+                    len,
+                    first: node,
+                    last: node,
+                    offset: 0,
+                });
+                live = tensors.len() - 1;
+            }
+            uses[node] = (read, live, work);
+        }
+        // The result is read after the last node
+        tensors[live].last = nodes.len();
+
+        let mut order = ArrayVec::<usize, { 2 * MAX_NODES + 1 }>::new();
+        order.extend(0..tensors.len());
+        order.sort_unstable_by_key(|&t| core::cmp::Reverse(tensors[t].len));
+
+        let mut len = 0;
+        for (placed, &t) in order.iter().enumerate() {
+            let mut offset = 0;
+            // Move past every conflict until none is left
+            while let Some(end) = order[..placed]
+                .iter()
+                .map(|&o| &tensors[o])
+                .filter(|o| o.live_with(&tensors[t]))
+                .filter(|o| o.offset < offset + tensors[t].len && offset < o.offset + o.len)
+                .map(|o| o.offset + o.len)
+                .max()
+            {
+                offset = end.next_multiple_of(ALIGN);
+            }
+            tensors[t].offset = offset;
+            len = len.max(offset + tensors[t].len);
+        }
+
+        let mut plan = Plan {
+            inputs: [0; MAX_NODES],
+            outputs: [0; MAX_NODES],
+            temps: [0; MAX_NODES],
+            input: tensors[0].offset,
+            output: tensors[live].offset,
+            len: len.next_multiple_of(ALIGN),
+        };
+        for (node, &(read, write, work)) in uses[..nodes.len()].iter().enumerate() {
+            plan.inputs[node] = tensors[read].offset;
+            plan.outputs[node] = tensors[write].offset;
+            if let Some(work) = work {
+                plan.temps[node] = tensors[work].offset;
+            }
+        }
+        plan
+    }
+
+    /// Offsets node `node` reads from and writes to
+    pub fn node(&self, node: usize) -> (usize, usize) {
+        (self.inputs[node], self.outputs[node])
+    }
+
+    /// Offset of node `node`'s working space
+    pub fn temp(&self, node: usize) -> usize {
+        self.temps[node]
+    }
+}
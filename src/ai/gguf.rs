@@ -0,0 +1,370 @@
+//! GGUF model container
+//!
+//! A GGUF file is a header, a list of typed key/value metadata, a list of
+//! tensor descriptions and an aligned data section. The parser validates
+//! the whole layout up front and then hands out borrowed views; nothing is
+//! copied.
+
+use super::quant::{Q4_0_BLOCK, Q8_0_BLOCK, QK};
+
+/// "GGUF", little-endian
+pub const GGUF_MAGIC: u32 = 0x4655_4747;
+
+/// Default alignment of tensor data
+const DEFAULT_ALIGNMENT: u64 = 32;
+/// Largest tensor data alignment accepted
+const MAX_ALIGNMENT: u64 = 4096;
+
+/// Limits that keep a corrupt file from making the parser spin
+const MAX_TENSORS: u64 = 4096;
+const MAX_METADATA: u64 = 4096;
+pub const MAX_DIMS: usize = 4;
+
+/// Element type of a tensor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GgmlType {
+    F32,
+    F16,
+    /// 4-bit values in blocks of 32 with an f16 scale
+    Q4_0,
+    /// 8-bit values in blocks of 32 with an f16 scale
+    Q8_0,
+}
+
+impl GgmlType {
+    fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            0 => Some(GgmlType::F32),
+            1 => Some(GgmlType::F16),
+            2 => Some(GgmlType::Q4_0),
+            8 => Some(GgmlType::Q8_0),
+            _ => None,
+        }
+    }
+
+    /// Nominal bits per value, without block scales
+    pub fn bits(self) -> u32 {
+        match self {
+            GgmlType::F32 => 32,
+            GgmlType::F16 => 16,
+            GgmlType::Q8_0 => 8,
+            GgmlType::Q4_0 => 4,
+        }
+    }
+
+    /// Values per block; quantized tensors hold whole blocks only
+    pub fn block_len(self) -> u64 {
+        match self {
+            GgmlType::F32 | GgmlType::F16 => 1,
+            GgmlType::Q4_0 | GgmlType::Q8_0 => QK as u64,
+        }
+    }
+
+    /// Bytes taken by `elements` values
+    pub fn size_of(self, elements: u64) -> Option<u64> {
+        if !elements.is_multiple_of(self.block_len()) {
+            return None;
+        }
+        match self {
+            GgmlType::F32 => elements.checked_mul(4),
+            GgmlType::F16 => elements.checked_mul(2),
+            GgmlType::Q4_0 => (elements / QK as u64).checked_mul(Q4_0_BLOCK as u64),
+            GgmlType::Q8_0 => (elements / QK as u64).checked_mul(Q8_0_BLOCK as u64),
+        }
+    }
+}
+
+/// A metadata value
+#[derive(Clone, Copy, Debug)]
+pub enum Value<'a> {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    Str(&'a str),
+    /// Element type and count; elements are not decoded
+    Array(u32, u64),
+}
+
+impl Value<'_> {
+    /// The value as an unsigned integer, if it is one
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::U8(v) => Some(v as u64),
+            Value::U16(v) => Some(v as u64),
+            Value::U32(v) => Some(v as u64),
+            Value::U64(v) => Some(v),
+            Value::I8(v) => u64::try_from(v).ok(),
+            Value::I16(v) => u64::try_from(v).ok(),
+            Value::I32(v) => u64::try_from(v).ok(),
+            Value::I64(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_f32(&self) -> Option<f32> {
+        match *self {
+            Value::F32(v) => Some(v),
+            Value::F64(v) => Some(v as f32),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+/// A tensor in the file
+#[derive(Clone, Copy, Debug)]
+pub struct TensorInfo<'a> {
+    pub name: &'a str,
+    /// Sizes, innermost first; unused dimensions are 1
+    pub dims: [u64; MAX_DIMS],
+    pub n_dims: usize,
+    pub ty: GgmlType,
+    pub data: &'a [u8],
+}
+
+impl TensorInfo<'_> {
+    pub fn elements(&self) -> u64 {
+        self.dims.iter().product()
+    }
+}
+
+#[derive(Debug)]
+pub enum GgufError {
+    BadMagic,
+    UnsupportedVersion,
+    Truncated,
+    BadString,
+    BadValueType,
+    UnsupportedTensorType,
+    BadTensor,
+    TooLarge,
+}
+
+/// Bounds-checked little-endian cursor
+#[derive(Clone)]
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], GgufError> {
+        let end = self.pos.checked_add(len).ok_or(GgufError::Truncated)?;
+        let bytes = self.data.get(self.pos..end).ok_or(GgufError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], GgufError> {
+        Ok(self.bytes(N)?.try_into().expect("length checked"))
+    }
+
+    fn u32(&mut self) -> Result<u32, GgufError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, GgufError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn string(&mut self) -> Result<&'a str, GgufError> {
+        let len = usize::try_from(self.u64()?).map_err(|_| GgufError::Truncated)?;
+        core::str::from_utf8(self.bytes(len)?).map_err(|_| GgufError::BadString)
+    }
+
+    fn value(&mut self, ty: u32) -> Result<Value<'a>, GgufError> {
+        Ok(match ty {
+            0 => Value::U8(self.array::<1>()?[0]),
+            1 => Value::I8(self.array::<1>()?[0] as i8),
+            2 => Value::U16(u16::from_le_bytes(self.array()?)),
+            3 => Value::I16(i16::from_le_bytes(self.array()?)),
+            4 => Value::U32(self.u32()?),
+            5 => Value::I32(self.u32()? as i32),
+            6 => Value::F32(f32::from_bits(self.u32()?)),
+            7 => Value::Bool(self.array::<1>()?[0] != 0),
+            8 => Value::Str(self.string()?),
+            9 => {
+                let elem = self.u32()?;
+                let len = self.u64()?;
+                if elem == 9 {
+                    // Nested arrays are not used by any model we load
+                    return Err(GgufError::BadValueType);
+                }
+                for _ in 0..len {
+                    self.value(elem)?;
+                }
+                Value::Array(elem, len)
+            }
+            10 => Value::U64(self.u64()?),
+            11 => Value::I64(self.u64()? as i64),
+            12 => Value::F64(f64::from_bits(self.u64()?)),
+            _ => return Err(GgufError::BadValueType),
+        })
+    }
+}
+
+/// A validated GGUF file
+pub struct Gguf<'a> {
+    data: &'a [u8],
+    version: u32,
+    tensor_count: u64,
+    metadata_count: u64,
+    /// Offset of the first metadata entry
+    metadata_start: usize,
+    /// Offset of the first tensor description
+    tensors_start: usize,
+    /// Offset of the data section
+    data_start: usize,
+}
+
+impl<'a> Gguf<'a> {
+    /// Check the header, every metadata entry and every tensor description
+    pub fn parse(data: &'a [u8]) -> Result<Self, GgufError> {
+        let mut r = Reader { data, pos: 0 };
+        if r.u32()? != GGUF_MAGIC {
+            return Err(GgufError::BadMagic);
+        }
+        let version = r.u32()?;
+        if !(2..=3).contains(&version) {
+            return Err(GgufError::UnsupportedVersion);
+        }
+        let tensor_count = r.u64()?;
+        let metadata_count = r.u64()?;
+        if tensor_count > MAX_TENSORS || metadata_count > MAX_METADATA {
+            return Err(GgufError::TooLarge);
+        }
+
+        let metadata_start = r.pos;
+        let mut alignment = DEFAULT_ALIGNMENT;
+        for _ in 0..metadata_count {
+            let key = r.string()?;
+            let ty = r.u32()?;
+            let value = r.value(ty)?;
+            if key == "general.alignment" {
+                alignment = value
+                    .as_u64()
+                    .filter(|a| a.is_power_of_two() && *a <= MAX_ALIGNMENT)
+                    .ok_or(GgufError::BadValueType)?;
+            }
+        }
+
+        let tensors_start = r.pos;
+        let mut info = Reader { data, pos: tensors_start };
+        for _ in 0..tensor_count {
+            Self::tensor_header(&mut info)?;
+        }
+        let data_start = (info.pos as u64)
+            .checked_next_multiple_of(alignment)
+            .map(|start| start as usize)
+            .filter(|&start| start <= data.len())
+            .ok_or(GgufError::Truncated)?;
+
+        let gguf = Self {
+            data,
+            version,
+            tensor_count,
+            metadata_count,
+            metadata_start,
+            tensors_start,
+            data_start,
+        };
+        // Resolving every tensor checks its type, size and data range
+        let mut r = Reader { data, pos: tensors_start };
+        for _ in 0..tensor_count {
+            gguf.tensor_info(&mut r)?;
+        }
+        Ok(gguf)
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn tensor_count(&self) -> u64 {
+        self.tensor_count
+    }
+
+    /// Name, dimensions, raw type and data offset of the next tensor
+    fn tensor_header(r: &mut Reader<'a>) -> Result<(&'a str, [u64; MAX_DIMS], usize, u32, u64), GgufError> {
+        let name = r.string()?;
+        let n_dims = r.u32()? as usize;
+        if n_dims == 0 || n_dims > MAX_DIMS {
+            return Err(GgufError::BadTensor);
+        }
+        let mut dims = [1u64; MAX_DIMS];
+        for dim in dims.iter_mut().take(n_dims) {
+            *dim = r.u64()?;
+        }
+        let ty = r.u32()?;
+        let offset = r.u64()?;
+        Ok((name, dims, n_dims, ty, offset))
+    }
+
+    fn tensor_info(&self, r: &mut Reader<'a>) -> Result<TensorInfo<'a>, GgufError> {
+        let (name, dims, n_dims, ty, offset) = Self::tensor_header(r)?;
+        let ty = GgmlType::from_raw(ty).ok_or(GgufError::UnsupportedTensorType)?;
+        let elements = dims
+            .iter()
+            .try_fold(1u64, |acc, d| acc.checked_mul(*d))
+            .ok_or(GgufError::BadTensor)?;
+        let size = ty.size_of(elements).ok_or(GgufError::BadTensor)?;
+        let start = (self.data_start as u64).checked_add(offset).ok_or(GgufError::BadTensor)?;
+        let end = start.checked_add(size).ok_or(GgufError::BadTensor)?;
+        if end > self.data.len() as u64 {
+            return Err(GgufError::Truncated);
+        }
+        Ok(TensorInfo {
+            name,
+            dims,
+            n_dims,
+            ty,
+            data: &self.data[start as usize..end as usize],
+        })
+    }
+
+    /// Look up a metadata value
+    pub fn metadata(&self, key: &str) -> Option<Value<'a>> {
+        let mut r = Reader {
+            data: self.data,
+            pos: self.metadata_start,
+        };
+        for _ in 0..self.metadata_count {
+            let name = r.string().ok()?;
+            let ty = r.u32().ok()?;
+            let value = r.value(ty).ok()?;
+            if name == key {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    /// Every tensor, in file order
+    pub fn tensors(&self) -> impl Iterator<Item = TensorInfo<'a>> + '_ {
+        let mut r = Reader {
+            data: self.data,
+            pos: self.tensors_start,
+        };
+        // Already validated by `parse`
+        (0..self.tensor_count).map_while(move |_| self.tensor_info(&mut r).ok())
+    }
+
+    /// Look up a tensor by name
+    pub fn tensor(&self, name: &str) -> Option<TensorInfo<'a>> {
+        self.tensors().find(|t| t.name == name)
+    }
+}
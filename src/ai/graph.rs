@@ -0,0 +1,440 @@
+//! Model graphs
+//!
+//! A graph is the sequence of operations a model applies to its input
+//! vector, built from a GGUF file's metadata and tensors. Weights are not
+//! copied: operations point into the loaded file.
+//!
+//! The supported architecture is `zen-mlp`, a stack of fully connected
+//! blocks. Block `i` has a `blk.<i>.weight` matrix (inputs innermost), an
+//! optional `blk.<i>.bias`, and an optional pre-normalization with
+//! `blk.<i>.norm.weight` / `blk.<i>.norm.bias`. Metadata keys:
+//! `zen-mlp.block_count`, `zen-mlp.activation` (`relu`, `gelu`, `silu` or
+//! `none`, applied between blocks), `zen-mlp.norm_epsilon` and
+//! `zen-mlp.output` (`softmax` or `none`).
+//!
+//! A block may start with single-head causal self-attention over earlier
+//! inputs: square `blk.<i>.attn_q.weight`, `attn_k`, `attn_v` and
+//! `attn_output` matrices, added to the block input as a residual. Earlier
+//! inputs are only remembered within a session, which keeps their keys and
+//! values in a [`KvCache`] of `zen-mlp.context_length` positions; without
+//! one, a block attends to the current input alone. Positions are not
+//! encoded, so models that care about order must put it in their input.
+//!
+//! Weight matrices may be f32, f16, Q8_0 or Q4_0; norms and biases are f32.
+//! Intermediate vectors live in an arena laid out by [`Plan`].
+
+use arrayvec::{ArrayString, ArrayVec};
+use core::fmt::Write;
+use core::ops::Range;
+
+use super::arena::Plan;
+use super::gguf::{GgmlType, Gguf, TensorInfo};
+use super::offload::Offload;
+use super::simd::{self, Vector};
+use super::{kernels, quant, AiError};
+
+/// Operations a graph can hold
+pub const MAX_NODES: usize = 64;
+
+/// Architecture name in `general.architecture`
+pub const ARCH_MLP: &str = "zen-mlp";
+
+/// Longest attention context a model may ask for
+pub const MAX_CONTEXT: usize = 1024;
+
+const DEFAULT_NORM_EPSILON: f32 = 1e-5;
+
+const DEFAULT_CONTEXT: usize = 128;
+
+/// A weight matrix in the loaded model
+#[derive(Clone, Copy, Debug)]
+pub struct Weights {
+    pub ty: GgmlType,
+    /// Inputs per row
+    pub cols: usize,
+    /// Outputs
+    pub rows: usize,
+    pub data: &'static [u8],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Activation {
+    Relu,
+    Gelu,
+    Silu,
+}
+
+/// One step of a graph
+#[derive(Clone, Copy, Debug)]
+pub enum Op {
+    LayerNorm {
+        gamma: &'static [f32],
+        beta: Option<&'static [f32]>,
+        eps: f32,
+    },
+    Linear {
+        weights: Weights,
+        bias: Option<&'static [f32]>,
+    },
+    Activation(Activation),
+    Softmax,
+    Attention {
+        wq: Weights,
+        wk: Weights,
+        wv: Weights,
+        wo: Weights,
+        /// Offset of this layer's keys, then values, in a [`KvCache`]
+        cache: usize,
+    },
+}
+
+/// Progress of one vector through a graph, between steps
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Activations {
+    /// Length of the live vector
+    pub len: usize,
+    /// Arena offset of the live vector
+    offset: usize,
+}
+
+/// Keys and values of earlier positions, kept by a session
+pub struct KvCache<'a> {
+    /// [`Graph::kv_len`] values
+    pub data: &'a mut [f32],
+    /// Positions seen before the current one
+    pub position: u64,
+}
+
+pub struct Graph {
+    pub nodes: ArrayVec<Op, MAX_NODES>,
+    pub input_len: usize,
+    pub output_len: usize,
+    /// Widest intermediate vector
+    pub max_width: usize,
+    /// Positions attention layers look back over
+    pub context: usize,
+    /// Values of key/value cache a session needs, zero without attention
+    pub kv_len: usize,
+    /// Arena layout of the intermediate vectors
+    pub plan: Plan,
+}
+
+/// View an f32 tensor's data in place
+fn f32_data(tensor: &TensorInfo<'static>) -> Result<&'static [f32], AiError> {
+    if tensor.ty != GgmlType::F32 {
+        return Err(AiError::UnsupportedModel);
+    }
+    // The data section is aligned within a page-aligned load region
+    let (head, values, tail) = unsafe { tensor.data.align_to::<f32>() };
+    if !head.is_empty() || !tail.is_empty() {
+        return Err(AiError::UnsupportedModel);
+    }
+    Ok(values)
+}
+
+/// An optional vector tensor that must have `len` elements
+fn vector(gguf: &Gguf<'static>, name: &str, len: usize) -> Result<Option<&'static [f32]>, AiError> {
+    let Some(tensor) = gguf.tensor(name) else {
+        return Ok(None);
+    };
+    let values = f32_data(&tensor)?;
+    if values.len() != len {
+        return Err(AiError::UnsupportedModel);
+    }
+    Ok(Some(values))
+}
+
+/// A `rows` x `cols` weight matrix, checking its layout now rather than at
+/// inference
+fn weights(tensor: &TensorInfo<'static>, cols: usize, rows: usize) -> Result<Weights, AiError> {
+    if tensor.n_dims != 2 || tensor.dims[0] as usize != cols || tensor.dims[1] as usize != rows {
+        return Err(AiError::UnsupportedModel);
+    }
+    match tensor.ty {
+        GgmlType::F32 => {
+            f32_data(tensor)?;
+        }
+        GgmlType::F16 => {}
+        GgmlType::Q4_0 | GgmlType::Q8_0 => {
+            if !cols.is_multiple_of(quant::QK) {
+                return Err(AiError::UnsupportedModel);
+            }
+        }
+    }
+    Ok(Weights {
+        ty: tensor.ty,
+        cols,
+        rows,
+        data: tensor.data,
+    })
+}
+
+/// `y = W x + bias` on the CPU
+fn matvec(v: Vector, weights: &Weights, x: &[f32], bias: Option<&[f32]>, y: &mut [f32]) {
+    match weights.ty {
+        GgmlType::F32 => {
+            let values = unsafe { weights.data.align_to::<f32>().1 };
+            kernels::matvec_f32(v, values, x, bias, y);
+        }
+        GgmlType::F16 => quant::matvec_f16(weights.data, x, bias, y),
+        GgmlType::Q4_0 => quant::matvec_q4_0(v, weights.data, x, bias, y),
+        GgmlType::Q8_0 => quant::matvec_q8_0(v, weights.data, x, bias, y),
+    }
+}
+
+/// `x` read-only and `y` writable, from disjoint ranges of `arena`
+fn split(arena: &mut [f32], x: Range<usize>, y: Range<usize>) -> (&[f32], &mut [f32]) {
+    if x.start < y.start {
+        let (lo, hi) = arena.split_at_mut(y.start);
+        (&lo[x], &mut hi[..y.len()])
+    } else {
+        let (lo, hi) = arena.split_at_mut(x.start);
+        (&hi[..x.len()], &mut lo[y])
+    }
+}
+
+fn tensor_name(block: u64, suffix: &str) -> ArrayString<64> {
+    let mut name = ArrayString::new();
+    let _ = write!(name, "blk.{}.{}", block, suffix);
+    name
+}
+
+impl Graph {
+    /// Build the graph of a parsed model
+    pub fn from_gguf(gguf: &Gguf<'static>) -> Result<Self, AiError> {
+        let arch = gguf.metadata("general.architecture");
+        if arch.as_ref().and_then(|a| a.as_str()) != Some(ARCH_MLP) {
+            return Err(AiError::UnsupportedModel);
+        }
+        let key = |suffix: &str| {
+            let mut key = ArrayString::<64>::new();
+            let _ = write!(key, "{}.{}", ARCH_MLP, suffix);
+            gguf.metadata(&key)
+        };
+
+        let blocks = key("block_count").and_then(|v| v.as_u64()).unwrap_or(0);
+        if blocks == 0 || blocks as usize > MAX_NODES / 4 {
+            return Err(AiError::UnsupportedModel);
+        }
+        let activation = match key("activation").as_ref().and_then(|v| v.as_str()) {
+            None | Some("relu") => Some(Activation::Relu),
+            Some("gelu") => Some(Activation::Gelu),
+            Some("silu") => Some(Activation::Silu),
+            Some("none") => None,
+            Some(_) => return Err(AiError::UnsupportedModel),
+        };
+        let eps = key("norm_epsilon").and_then(|v| v.as_f32()).unwrap_or(DEFAULT_NORM_EPSILON);
+        let softmax = match key("output").as_ref().and_then(|v| v.as_str()) {
+            None | Some("none") => false,
+            Some("softmax") => true,
+            Some(_) => return Err(AiError::UnsupportedModel),
+        };
+        let context = key("context_length")
+            .and_then(|v| v.as_u64())
+            .map_or(DEFAULT_CONTEXT, |n| n as usize);
+        if context == 0 || context > MAX_CONTEXT {
+            return Err(AiError::UnsupportedModel);
+        }
+
+        let mut graph = Graph {
+            nodes: ArrayVec::new(),
+            input_len: 0,
+            output_len: 0,
+            max_width: 0,
+            context,
+            kv_len: 0,
+            plan: Plan::new(&[], 0, 0),
+        };
+        let mut width = 0;
+        for block in 0..blocks {
+            let weight = gguf
+                .tensor(&tensor_name(block, "weight"))
+                .ok_or(AiError::UnsupportedModel)?;
+            let (cols, rows) = (weight.dims[0] as usize, weight.dims[1] as usize);
+            if weight.n_dims != 2 || cols == 0 || rows == 0 || (block > 0 && cols != width) {
+                return Err(AiError::UnsupportedModel);
+            }
+            if block == 0 {
+                graph.input_len = cols;
+            }
+
+            if let Some(wq) = gguf.tensor(&tensor_name(block, "attn_q.weight")) {
+                let square = |suffix: &str| {
+                    let tensor = gguf.tensor(&tensor_name(block, suffix)).ok_or(AiError::UnsupportedModel)?;
+                    weights(&tensor, cols, cols)
+                };
+                graph.push(Op::Attention {
+                    wq: weights(&wq, cols, cols)?,
+                    wk: square("attn_k.weight")?,
+                    wv: square("attn_v.weight")?,
+                    wo: square("attn_output.weight")?,
+                    cache: graph.kv_len,
+                })?;
+                graph.kv_len += 2 * context * cols;
+            }
+            if let Some(gamma) = vector(gguf, &tensor_name(block, "norm.weight"), cols)? {
+                let beta = vector(gguf, &tensor_name(block, "norm.bias"), cols)?;
+                graph.push(Op::LayerNorm { gamma, beta, eps })?;
+            }
+            graph.push(Op::Linear {
+                weights: weights(&weight, cols, rows)?,
+                bias: vector(gguf, &tensor_name(block, "bias"), rows)?,
+            })?;
+            if let Some(activation) = activation.filter(|_| block + 1 < blocks) {
+                graph.push(Op::Activation(activation))?;
+            }
+
+            width = rows;
+            graph.max_width = graph.max_width.max(cols).max(rows);
+        }
+        if softmax {
+            graph.push(Op::Softmax)?;
+        }
+        graph.output_len = width;
+        graph.plan = Plan::new(&graph.nodes, graph.input_len, context);
+        Ok(graph)
+    }
+
+    fn push(&mut self, op: Op) -> Result<(), AiError> {
+        self.nodes.try_push(op).map_err(|_| AiError::UnsupportedModel)
+    }
+
+    /// Values of arena one run needs, its peak activation memory
+    pub fn scratch_len(&self) -> usize {
+        self.plan.len
+    }
+
+    /// Copy `input` into the arena `scratch` ready for the first node
+    pub fn start(&self, input: &[f32], scratch: &mut [f32]) -> Result<Activations, AiError> {
+        if input.len() != self.input_len || scratch.len() < self.scratch_len() {
+            return Err(AiError::InvalidInput);
+        }
+        let offset = self.plan.input;
+        scratch[offset..offset + input.len()].copy_from_slice(input);
+        Ok(Activations {
+            len: input.len(),
+            offset,
+        })
+    }
+
+    /// Apply node `node` to the vector in `scratch`
+    ///
+    /// Linear layers held by `offload` run on the GPU. Attention layers
+    /// look back over `kv` when given one.
+    pub fn step(
+        &self,
+        node: usize,
+        v: Vector,
+        offload: Option<&Offload>,
+        kv: Option<&mut KvCache>,
+        scratch: &mut [f32],
+        act: &mut Activations,
+    ) {
+        let (input, output) = self.plan.node(node);
+        let len = act.len;
+        let cur = &mut scratch[input..input + len];
+        match self.nodes[node] {
+            Op::LayerNorm { gamma, beta, eps } => kernels::layernorm(v, cur, gamma, beta, eps),
+            Op::Linear { weights, bias } => {
+                let (x, y) = split(scratch, input..input + len, output..output + weights.rows);
+                if !offload.is_some_and(|o| o.matvec(node, x, y)) {
+                    matvec(v, &weights, x, bias, y);
+                }
+                act.len = weights.rows;
+            }
+            Op::Attention { wq, wk, wv, wo, cache } => {
+                let temp = self.plan.temp(node);
+                let temp = temp..temp + Plan::attention_temp(len, self.context);
+                let (x, t) = split(scratch, input..input + len, temp.clone());
+                let xc = &mut t[..len];
+                xc.copy_from_slice(x);
+                self.attention(v, [&wq, &wk, &wv], cache, kv, t, len);
+                let (t, y) = split(scratch, temp, output..output + len);
+                let (xc, a) = (&t[..len], &t[4 * len..5 * len]);
+                matvec(v, &wo, a, None, y);
+                for (y, x) in y.iter_mut().zip(xc) {
+                    *y += x;
+                }
+            }
+            Op::Activation(Activation::Relu) => kernels::relu(cur),
+            Op::Activation(Activation::Gelu) => kernels::gelu(cur),
+            Op::Activation(Activation::Silu) => kernels::silu(cur),
+            Op::Softmax => kernels::softmax(v, cur),
+        }
+        act.offset = output;
+    }
+
+    /// Attend from the input copied to the start of `temp`, leaving the
+    /// weighted sum of values at `4 * d`
+    ///
+    /// `temp` holds the input, query, key, value and sum, `d` each, then a
+    /// score per context position.
+    fn attention(&self, v: Vector, w: [&Weights; 3], cache: usize, kv: Option<&mut KvCache>, temp: &mut [f32], d: usize) {
+        let (x, rest) = temp.split_at_mut(d);
+        let (q, rest) = rest.split_at_mut(d);
+        let (k, rest) = rest.split_at_mut(d);
+        let (val, rest) = rest.split_at_mut(d);
+        let (sum, scores) = rest.split_at_mut(d);
+        matvec(v, w[0], x, None, q);
+        matvec(v, w[1], x, None, k);
+        matvec(v, w[2], x, None, val);
+
+        let Some(kv) = kv else {
+            // Only the current position: its value carries all the weight
+            sum.copy_from_slice(val);
+            return;
+        };
+        let ctx = self.context;
+        let (keys, values) = kv.data[cache..cache + 2 * ctx * d].split_at_mut(ctx * d);
+        let slot = (kv.position % ctx as u64) as usize;
+        keys[slot * d..(slot + 1) * d].copy_from_slice(k);
+        values[slot * d..(slot + 1) * d].copy_from_slice(val);
+
+        let n = (kv.position as usize + 1).min(ctx);
+        let scale = 1.0 / super::math::sqrt(d as f32);
+        for (j, score) in scores[..n].iter_mut().enumerate() {
+            *score = kernels::dot(v, q, &keys[j * d..(j + 1) * d]) * scale;
+        }
+        kernels::softmax(v, &mut scores[..n]);
+        sum.fill(0.0);
+        for (j, &p) in scores[..n].iter().enumerate() {
+            for (s, x) in sum.iter_mut().zip(&values[j * d..(j + 1) * d]) {
+                *s += p * x;
+            }
+        }
+    }
+
+    /// Copy the result of the last node out of `scratch`
+    pub fn finish(&self, scratch: &[f32], act: &Activations, output: &mut [f32]) -> Result<usize, AiError> {
+        if output.len() < act.len {
+            return Err(AiError::InvalidInput);
+        }
+        output[..act.len].copy_from_slice(&scratch[act.offset..act.offset + act.len]);
+        Ok(act.len)
+    }
+
+    /// Run the graph on `input`, writing the result to `output`
+    ///
+    /// `scratch` needs [`Graph::scratch_len`] values. Returns the number of
+    /// outputs written; the caller advances `kv` past the position.
+    pub fn run(
+        &self,
+        input: &[f32],
+        output: &mut [f32],
+        scratch: &mut [f32],
+        offload: Option<&Offload>,
+        kv: Option<&mut KvCache>,
+    ) -> Result<usize, AiError> {
+        if output.len() < self.output_len {
+            return Err(AiError::InvalidInput);
+        }
+        let mut act = self.start(input, scratch)?;
+        let guard = simd::begin();
+        let mut kv = kv;
+        for node in 0..self.nodes.len() {
+            self.step(node, guard.vector(), offload, kv.as_deref_mut(), scratch, &mut act);
+        }
+        drop(guard);
+        self.finish(scratch, &act, output)
+    }
+}
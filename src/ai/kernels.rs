@@ -0,0 +1,84 @@
+//! Tensor kernels
+//!
+//! The building blocks graphs are executed with. Vectors are plain `f32`
+//! slices; weight matrices are stored row-major, one row per output.
+//! Each kernel takes the `Vector` level to run at and falls back to the
+//! scalar loop when no vector unit is available.
+
+use super::math;
+use super::simd::{self, Level, Vector};
+
+/// Dot product of two equal-length vectors
+pub fn dot(v: Vector, a: &[f32], b: &[f32]) -> f32 {
+    match v.level() {
+        Level::Avx512 => unsafe { simd::dot_f32_avx512(a, b) },
+        Level::Avx2 => unsafe { simd::dot_f32_avx2(a, b) },
+        Level::Scalar => a.iter().zip(b).map(|(x, y)| x * y).sum(),
+    }
+}
+
+/// `out[r] = dot(weights[r], x) (+ bias[r])` for a `rows` x `x.len()` matrix
+pub fn matvec_f32(v: Vector, weights: &[f32], x: &[f32], bias: Option<&[f32]>, out: &mut [f32]) {
+    let cols = x.len();
+    for (r, y) in out.iter_mut().enumerate() {
+        let row = &weights[r * cols..(r + 1) * cols];
+        *y = dot(v, row, x) + bias.map_or(0.0, |b| b[r]);
+    }
+}
+
+/// Normalize `x` to zero mean and unit variance, then scale and shift
+pub fn layernorm(v: Vector, x: &mut [f32], gamma: &[f32], beta: Option<&[f32]>, eps: f32) {
+    match v.level() {
+        Level::Avx512 => return unsafe { simd::layernorm_avx512(x, gamma, beta, eps) },
+        Level::Avx2 => return unsafe { simd::layernorm_avx2(x, gamma, beta, eps) },
+        Level::Scalar => {}
+    }
+    let n = x.len() as f32;
+    let mean = x.iter().sum::<f32>() / n;
+    let variance = x.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / n;
+    let inv = 1.0 / math::sqrt(variance + eps);
+    for (i, v) in x.iter_mut().enumerate() {
+        *v = (*v - mean) * inv * gamma[i] + beta.map_or(0.0, |b| b[i]);
+    }
+}
+
+/// Turn `x` into a probability distribution
+pub fn softmax(v: Vector, x: &mut [f32]) {
+    match v.level() {
+        Level::Avx512 => return unsafe { simd::softmax_avx512(x) },
+        Level::Avx2 => return unsafe { simd::softmax_avx2(x) },
+        Level::Scalar => {}
+    }
+    let max = x.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut sum = 0.0;
+    for v in x.iter_mut() {
+        *v = math::exp(*v - max);
+        sum += *v;
+    }
+    if sum > 0.0 {
+        for v in x.iter_mut() {
+            *v /= sum;
+        }
+    }
+}
+
+pub fn relu(x: &mut [f32]) {
+    for v in x.iter_mut() {
+        *v = v.max(0.0);
+    }
+}
+
+/// GELU, tanh approximation
+pub fn gelu(x: &mut [f32]) {
+    const SQRT_2_OVER_PI: f32 = 0.797_884_6;
+    for v in x.iter_mut() {
+        *v = 0.5 * *v * (1.0 + math::tanh(SQRT_2_OVER_PI * (*v + 0.044_715 * *v * *v * *v)));
+    }
+}
+
+/// SiLU (swish)
+pub fn silu(x: &mut [f32]) {
+    for v in x.iter_mut() {
+        *v /= 1.0 + math::exp(-*v);
+    }
+}
@@ -0,0 +1,46 @@
+//! Scalar float functions
+//!
+//! `core` has no transcendental functions, so the few that inference needs
+//! are approximated here to within a few ULP over the ranges models use.
+
+use core::f32::consts::{LN_2, LOG2_E};
+
+/// Square root (0 for negative inputs)
+pub fn sqrt(x: f32) -> f32 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    // Halve the exponent for a first guess, then refine with Newton steps
+    let mut y = f32::from_bits(0x1FBD_1DF5 + (x.to_bits() >> 1));
+    for _ in 0..3 {
+        y = 0.5 * (y + x / y);
+    }
+    y
+}
+
+/// e^x
+pub fn exp(x: f32) -> f32 {
+    if x > 88.0 {
+        return f32::INFINITY;
+    }
+    if x < -87.0 {
+        return 0.0;
+    }
+    // e^x = 2^k * e^r with |r| <= ln(2)/2
+    let t = x * LOG2_E;
+    let k = (if t >= 0.0 { t + 0.5 } else { t - 0.5 }) as i32;
+    let r = x - k as f32 * LN_2;
+    let p = 1.0 + r * (1.0 + r * (0.5 + r * (1.0 / 6.0 + r * (1.0 / 24.0 + r * (1.0 / 120.0 + r * (1.0 / 720.0))))));
+    p * f32::from_bits(((k + 127) as u32) << 23)
+}
+
+/// Hyperbolic tangent
+pub fn tanh(x: f32) -> f32 {
+    if x > 9.0 {
+        return 1.0;
+    }
+    if x < -9.0 {
+        return -1.0;
+    }
+    1.0 - 2.0 / (exp(2.0 * x) + 1.0)
+}
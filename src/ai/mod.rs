@@ -1,15 +1,103 @@
 //! AI inference engine for on-device intelligence
+//!
+//! Models are GGUF files stored in TagFS. Loading one validates the file
+//! and builds a graph of kernels that runs on the CPU, with linear layers
+//! offloaded to a GPU compute engine when one is present. Weights may be
+//! f32, f16 or block-quantized to 8 or 4 bits.
+//!
+//! The engine also tags new TagFS objects by their content and learns
+//! TagFS read patterns to prefetch into the storage cache, and watches the
+//! capability audit log for unusual permission use.
+
+pub mod anomaly;
+pub mod arena;
+pub mod gguf;
+pub mod graph;
+pub mod kernels;
+pub mod math;
+pub mod model;
+pub mod offload;
+pub mod prefetch;
+pub mod quant;
+pub mod sched;
+pub mod service;
+pub mod session;
+pub mod simd;
+pub mod tagger;
+
+use arrayvec::ArrayVec;
+use model::{ModelInfo, MAX_MODELS};
+
+/// Tag of the model loaded at boot when `model=` is not given, one the
+/// initrd can carry
+pub const DEFAULT_MODEL_TAG: &str = "boot:model";
 
 /// Initialize AI inference engine
 pub fn init() {
-    // TODO: Load inference engine
-    // TODO: Set up GPU-accelerated inference
+    simd::init();
+    match service::init() {
+        Ok(channel) => crate::klog!(Info, "Inference service on channel {}", channel),
+        Err(e) => crate::klog!(Error, "Inference service setup failed: {:?}", e),
+    }
+    if let Err(e) = tagger::init() {
+        crate::klog!(Warn, "Automatic tagging unavailable: {:?}", e);
+    }
+    prefetch::init();
+
+    let tag = crate::boot::cmdline::get("model").unwrap_or(DEFAULT_MODEL_TAG);
+    if let Some(object) = crate::tagfs::tagfs_query(&crate::tagfs::Tag::new(tag)) {
+        match load_model(object) {
+            Ok(model) => crate::klog!(Info, "Model {} loaded from {}", model, tag),
+            Err(e) => crate::klog!(Warn, "Model {} failed to load: {:?}", tag, e),
+        }
+    }
+}
+
+/// Serve inference requests, tag new objects, issue prefetch hints and
+/// check the audit log, run from the idle loop
+pub fn poll() {
+    service::dispatch();
+    tagger::poll();
+    prefetch::poll();
+    anomaly::poll();
+}
+
+/// Load a model from a TagFS object, returning its model ID
+pub fn load_model(object_id: u64) -> Result<u64, AiError> {
+    model::load_model(object_id)
 }
 
-/// Run inference
-pub fn infer(_model_id: u64, _input: &[f32]) -> Result<Vec<f32>, AiError> {
-    // TODO: Implement GPU-accelerated inference
-    Ok(Vec::new())
+/// Unload a model; its ID and sessions stop working
+pub fn unload_model(model_id: u64) -> Result<(), AiError> {
+    model::unload_model(model_id)?;
+    session::forget_model(model_id);
+    Ok(())
+}
+
+/// Every loaded model with its size, quantization and shape
+pub fn list_models() -> ArrayVec<ModelInfo, MAX_MODELS> {
+    model::list_models()
+}
+
+/// Run a loaded model on `input`, returning the number of values written
+/// to `output`
+pub fn infer(model_id: u64, input: &[f32], output: &mut [f32]) -> Result<usize, AiError> {
+    model::run(model_id, input, output, None)
+}
+
+/// Open a kernel session on a loaded model, returning its session ID
+pub fn session_open(model_id: u64) -> Result<u64, AiError> {
+    session::open(model_id, 0)
+}
+
+/// Run the next position of a kernel session
+pub fn session_infer(session_id: u64, input: &[f32], output: &mut [f32]) -> Result<usize, AiError> {
+    session::run(session_id, 0, input, output)
+}
+
+/// Close a kernel session
+pub fn session_close(session_id: u64) -> Result<(), AiError> {
+    session::close(session_id, 0)
 }
 
 /// AI errors
@@ -17,17 +105,28 @@ pub fn infer(_model_id: u64, _input: &[f32]) -> Result<Vec<f32>, AiError> {
 pub enum AiError {
     ModelNotFound,
     InferenceFailed,
+    InvalidModel(gguf::GgufError),
+    /// Valid GGUF, but an architecture or tensor layout we cannot run
+    UnsupportedModel,
+    TooManyModels,
+    OutOfMemory,
+    /// Input or output vector does not match the model
+    InvalidInput,
+    Storage(crate::tagfs::TagFsError),
+    SessionNotFound,
+    /// The session's context was dropped to make room; reset it
+    SessionEvicted,
+    TooManySessions,
 }
 
-// Placeholder Vec for no_std
-struct Vec<T> {
-    _phantom: core::marker::PhantomData<T>,
+impl From<gguf::GgufError> for AiError {
+    fn from(e: gguf::GgufError) -> Self {
+        AiError::InvalidModel(e)
+    }
 }
 
-impl<T> Vec<T> {
-    fn new() -> Self {
-        Self {
-            _phantom: core::marker::PhantomData,
-        }
+impl From<crate::tagfs::TagFsError> for AiError {
+    fn from(e: crate::tagfs::TagFsError) -> Self {
+        AiError::Storage(e)
     }
 }
@@ -0,0 +1,361 @@
+//! Model registry
+//!
+//! A model is loaded by copying a TagFS object into kernel memory, parsing
+//! it as GGUF and building its graph. The file stays resident for as long
+//! as the model does: graph weights point straight into it. Layers that fit
+//! are also copied to video memory and run on the GPU.
+//!
+//! Each model has an activation arena for each of [`MAX_BATCH`] batched
+//! requests, stepped a node at a time by the inference scheduler, plus one
+//! more for direct calls to [`run`]. Arenas are sized by the graph's plan,
+//! so a model's memory is fixed once it is loaded.
+//!
+//! Kernel regions cannot be unmapped, so unloading a model keeps its file
+//! and scratch regions for later loads that fit in them.
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use super::gguf::{GgmlType, Gguf, GgufError};
+use super::graph::{Activations, Graph, KvCache, Op};
+use super::offload::Offload;
+use super::AiError;
+use crate::tagfs;
+
+/// Models that can be loaded at once
+pub const MAX_MODELS: usize = 8;
+
+/// Requests one model can run as a batch
+pub const MAX_BATCH: usize = 4;
+
+/// Unloaded regions kept for reuse
+const MAX_SPARE_REGIONS: usize = 16;
+
+/// Bytes read from TagFS per call
+const READ_CHUNK: usize = 64 * 1024;
+
+/// What a loaded model is and what it costs
+#[derive(Clone, Copy, Debug)]
+pub struct ModelInfo {
+    pub id: u64,
+    /// TagFS object the model was loaded from
+    pub object: u64,
+    /// Size of the model file
+    pub file_size: usize,
+    /// Kernel memory held, including page rounding
+    pub memory: usize,
+    /// Peak activation memory of one request, in bytes
+    pub arena_size: usize,
+    /// Video memory held by offloaded layers
+    pub gpu_memory: u64,
+    /// Narrowest weight type in the model
+    pub quantization: GgmlType,
+    pub input_len: usize,
+    pub output_len: usize,
+    /// Output is a probability distribution
+    pub classifier: bool,
+    pub layers: usize,
+    /// Linear layers running on the GPU
+    pub gpu_layers: usize,
+}
+
+/// Kernel memory used by the registry, in bytes
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryStats {
+    /// Held by loaded models
+    pub in_use: usize,
+    /// Kept from unloaded models
+    pub spare: usize,
+}
+
+/// `size` bytes of kernel memory at `base`
+#[derive(Clone, Copy)]
+pub(super) struct Region {
+    pub(super) base: u64,
+    pub(super) size: usize,
+}
+
+struct Model {
+    info: ModelInfo,
+    file: Region,
+    graph: Graph,
+    scratch_region: Region,
+    /// `MAX_BATCH + 1` arenas, batch slots first
+    scratch: &'static mut [f32],
+    /// Progress of each batch slot
+    batch: [Activations; MAX_BATCH],
+    offload: Option<Offload>,
+}
+
+impl Model {
+    /// Graph, arena of run slot `slot` and offload, borrowed together
+    fn slot(&mut self, slot: usize) -> (&Graph, &mut [f32], Option<&Offload>) {
+        let len = self.graph.scratch_len();
+        (&self.graph, &mut self.scratch[slot * len..(slot + 1) * len], self.offload.as_ref())
+    }
+}
+
+struct Registry {
+    models: [Option<Model>; MAX_MODELS],
+    spare: ArrayVec<Region, MAX_SPARE_REGIONS>,
+    next_id: u64,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    models: [const { None }; MAX_MODELS],
+    spare: ArrayVec::new_const(),
+    next_id: 1,
+});
+
+impl Registry {
+    fn get_mut(&mut self, id: u64) -> Result<&mut Model, AiError> {
+        self.models
+            .iter_mut()
+            .flatten()
+            .find(|m| m.info.id == id)
+            .ok_or(AiError::ModelNotFound)
+    }
+
+    /// Keep a region for reuse; with the list full the smallest region is
+    /// dropped for good
+    fn recycle(&mut self, region: Region) {
+        if self.spare.is_full() {
+            let smallest = (0..self.spare.len())
+                .min_by_key(|&i| self.spare[i].size)
+                .expect("spare list is full");
+            if self.spare[smallest].size >= region.size {
+                return;
+            }
+            self.spare.swap_remove(smallest);
+        }
+        self.spare.push(region);
+    }
+}
+
+/// A region of at least `size` bytes, reusing the smallest spare that fits
+pub(super) fn take_region(size: usize) -> Result<Region, AiError> {
+    {
+        let mut registry = REGISTRY.lock();
+        let best = (0..registry.spare.len())
+            .filter(|&i| registry.spare[i].size >= size)
+            .min_by_key(|&i| registry.spare[i].size);
+        if let Some(i) = best {
+            return Ok(registry.spare.swap_remove(i));
+        }
+    }
+    let base = crate::kernel::memory::allocate_region(size).map_err(|_| AiError::OutOfMemory)?;
+    Ok(Region {
+        base: base.as_u64(),
+        size: size.next_multiple_of(4096),
+    })
+}
+
+/// Keep a region no longer needed for later loads and sessions
+pub(super) fn recycle_region(region: Region) {
+    REGISTRY.lock().recycle(region);
+}
+
+/// Copy a TagFS object to the start of `region`
+fn read_object(object_id: u64, region: Region, size: usize) -> Result<&'static [u8], AiError> {
+    let data = unsafe { core::slice::from_raw_parts_mut(region.base as *mut u8, size) };
+    let mut offset = 0;
+    while offset < size {
+        let end = (offset + READ_CHUNK).min(size);
+        let read = tagfs::tagfs_read(object_id, offset as u64, &mut data[offset..end])?;
+        if read == 0 {
+            return Err(AiError::Storage(tagfs::TagFsError::IoError));
+        }
+        offset += read;
+    }
+    Ok(data)
+}
+
+/// Parse a loaded file and describe the model
+fn build(object_id: u64, data: &'static [u8]) -> Result<(Graph, ModelInfo), AiError> {
+    let gguf = Gguf::parse(data)?;
+    let graph = Graph::from_gguf(&gguf)?;
+
+    let mut layers = 0;
+    let mut quantization = GgmlType::F32;
+    for op in graph.nodes.iter() {
+        if let Op::Linear { weights, .. } = op {
+            layers += 1;
+            if weights.ty.bits() < quantization.bits() {
+                quantization = weights.ty;
+            }
+        }
+    }
+    let info = ModelInfo {
+        id: 0,
+        object: object_id,
+        file_size: data.len(),
+        memory: 0,
+        arena_size: graph.scratch_len() * 4,
+        gpu_memory: 0,
+        quantization,
+        input_len: graph.input_len,
+        output_len: graph.output_len,
+        classifier: matches!(graph.nodes.last(), Some(Op::Softmax)),
+        layers,
+        gpu_layers: 0,
+    };
+    Ok((graph, info))
+}
+
+/// Load the model stored in a TagFS object, returning its model ID
+pub fn load_model(object_id: u64) -> Result<u64, AiError> {
+    let meta = tagfs::tagfs_meta(object_id).ok_or(AiError::ModelNotFound)?;
+    let file_size = meta.size as usize;
+    if file_size == 0 {
+        return Err(AiError::InvalidModel(GgufError::Truncated));
+    }
+
+    let file = take_region(file_size)?;
+    let built = read_object(object_id, file, file_size).and_then(|data| build(object_id, data));
+    let (graph, mut info) = match built {
+        Ok(built) => built,
+        Err(e) => {
+            REGISTRY.lock().recycle(file);
+            return Err(e);
+        }
+    };
+
+    let scratch_len = graph.scratch_len() * (MAX_BATCH + 1);
+    let scratch_region = match take_region(scratch_len * 4) {
+        Ok(region) => region,
+        Err(e) => {
+            REGISTRY.lock().recycle(file);
+            return Err(e);
+        }
+    };
+    let scratch = unsafe { core::slice::from_raw_parts_mut(scratch_region.base as *mut f32, scratch_len) };
+    info.memory = file.size + scratch_region.size;
+
+    let offload = Offload::new(&graph);
+    if let Some(offload) = offload.as_ref() {
+        info.gpu_layers = offload.resident_layers();
+        info.gpu_memory = offload.memory();
+    }
+
+    let mut registry = REGISTRY.lock();
+    let Some(index) = registry.models.iter().position(|m| m.is_none()) else {
+        if let Some(offload) = offload {
+            offload.release();
+        }
+        registry.recycle(file);
+        registry.recycle(scratch_region);
+        return Err(AiError::TooManyModels);
+    };
+    info.id = registry.next_id;
+    registry.next_id += 1;
+    registry.models[index] = Some(Model {
+        info,
+        file,
+        graph,
+        scratch_region,
+        scratch,
+        batch: [Activations::default(); MAX_BATCH],
+        offload,
+    });
+    Ok(info.id)
+}
+
+/// Unload a model, releasing its video memory and keeping its kernel
+/// memory for later loads
+pub fn unload_model(model_id: u64) -> Result<(), AiError> {
+    let mut registry = REGISTRY.lock();
+    let model = registry
+        .models
+        .iter_mut()
+        .find(|m| m.as_ref().is_some_and(|m| m.info.id == model_id))
+        .and_then(|slot| slot.take())
+        .ok_or(AiError::ModelNotFound)?;
+    if let Some(offload) = model.offload {
+        offload.release();
+    }
+    registry.recycle(model.file);
+    registry.recycle(model.scratch_region);
+    Ok(())
+}
+
+/// Every loaded model
+pub fn list_models() -> ArrayVec<ModelInfo, MAX_MODELS> {
+    REGISTRY.lock().models.iter().flatten().map(|m| m.info).collect()
+}
+
+/// Details of a loaded model
+pub fn model_info(model_id: u64) -> Result<ModelInfo, AiError> {
+    REGISTRY.lock().get_mut(model_id).map(|m| m.info)
+}
+
+/// Kernel memory held by the registry
+pub fn memory_stats() -> MemoryStats {
+    let registry = REGISTRY.lock();
+    MemoryStats {
+        in_use: registry.models.iter().flatten().map(|m| m.info.memory).sum(),
+        spare: registry.spare.iter().map(|r| r.size).sum(),
+    }
+}
+
+/// Run a loaded model, with a session's cache if given
+pub fn run(model_id: u64, input: &[f32], output: &mut [f32], kv: Option<&mut KvCache>) -> Result<usize, AiError> {
+    let mut registry = REGISTRY.lock();
+    let (graph, scratch, offload) = registry.get_mut(model_id)?.slot(MAX_BATCH);
+    graph.run(input, output, scratch, offload, kv)
+}
+
+/// Values of key/value cache a session on the model needs
+pub fn kv_len(model_id: u64) -> Result<usize, AiError> {
+    REGISTRY.lock().get_mut(model_id).map(|m| m.graph.kv_len)
+}
+
+/// Nodes in a model's graph
+pub fn node_count(model_id: u64) -> Result<usize, AiError> {
+    REGISTRY.lock().get_mut(model_id).map(|m| m.graph.nodes.len())
+}
+
+/// Put `input` in batch slot `slot`, ready for node 0
+pub fn batch_start(model_id: u64, slot: usize, input: &[f32]) -> Result<(), AiError> {
+    if slot >= MAX_BATCH {
+        return Err(AiError::InvalidInput);
+    }
+    let mut registry = REGISTRY.lock();
+    let model = registry.get_mut(model_id)?;
+    let (graph, scratch, _) = model.slot(slot);
+    let act = graph.start(input, scratch)?;
+    model.batch[slot] = act;
+    Ok(())
+}
+
+/// Run node `node` for batch slots `0..slots`
+///
+/// Each node is applied to the whole batch before the next, so a layer's
+/// weights are read once while they are hot in cache. A session's cache
+/// can only be given for a batch of one.
+pub fn batch_step(model_id: u64, node: usize, slots: usize, mut kv: Option<&mut KvCache>) -> Result<(), AiError> {
+    let mut registry = REGISTRY.lock();
+    let model = registry.get_mut(model_id)?;
+    if node >= model.graph.nodes.len() || slots > MAX_BATCH || (kv.is_some() && slots != 1) {
+        return Err(AiError::InvalidInput);
+    }
+    let guard = super::simd::begin();
+    for slot in 0..slots {
+        let mut act = model.batch[slot];
+        let (graph, scratch, offload) = model.slot(slot);
+        graph.step(node, guard.vector(), offload, kv.as_deref_mut(), scratch, &mut act);
+        model.batch[slot] = act;
+    }
+    Ok(())
+}
+
+/// Copy the result in batch slot `slot` to `output`
+pub fn batch_finish(model_id: u64, slot: usize, output: &mut [f32]) -> Result<usize, AiError> {
+    if slot >= MAX_BATCH {
+        return Err(AiError::InvalidInput);
+    }
+    let mut registry = REGISTRY.lock();
+    let model = registry.get_mut(model_id)?;
+    let act = model.batch[slot];
+    let (graph, scratch, _) = model.slot(slot);
+    graph.finish(scratch, &act, output)
+}
@@ -0,0 +1,211 @@
+//! GPU offload
+//!
+//! When a compute engine is installed, a model's weight matrices are copied
+//! into video memory at load time and its linear layers are dispatched as
+//! compute commands on a kernel command context, each followed by a fence
+//! wait. Weights only go where there is free space, so loading never evicts
+//! anything; a layer that did not fit, was evicted since, or fails on the
+//! engine runs on the CPU instead.
+
+use super::gguf::GgmlType;
+use super::graph::{Graph, Op, MAX_NODES};
+use crate::gpu::cmd::{self, Command};
+use crate::gpu::compute::{self, MatVec, WeightFormat};
+use crate::gpu::fence::{self, Fence};
+use crate::gpu::vram::{self, Placement, VramHandle};
+use crate::gpu::GpuError;
+
+/// How long one layer may take on the engine
+const FENCE_TIMEOUT_TICKS: u64 = 2 * crate::scheduler::TICKS_PER_SECOND;
+
+#[derive(Clone, Copy)]
+struct Layer {
+    weights: VramHandle,
+    bias: Option<VramHandle>,
+    format: WeightFormat,
+    rows: u32,
+    cols: u32,
+}
+
+/// Video memory copies of a model's layers
+pub struct Offload {
+    context: u32,
+    /// Indexed by graph node
+    layers: [Option<Layer>; MAX_NODES],
+    /// Activation vectors in and out of a layer
+    input: VramHandle,
+    output: VramHandle,
+    io_size: u64,
+}
+
+/// Weights are still in the loaded model file, so nothing is copied back;
+/// the next dispatch of the layer fails and it runs on the CPU.
+fn evicted(_: VramHandle, _: Placement) {}
+
+fn format(ty: GgmlType) -> WeightFormat {
+    match ty {
+        GgmlType::F32 => WeightFormat::F32,
+        GgmlType::F16 => WeightFormat::F16,
+        GgmlType::Q8_0 => WeightFormat::Q8_0,
+        GgmlType::Q4_0 => WeightFormat::Q4_0,
+    }
+}
+
+/// Copy `data` into free video memory
+fn upload(data: &[u8], evict: Option<vram::EvictFn>) -> Option<VramHandle> {
+    if data.len() as u64 > vram::vram_stats().free {
+        return None;
+    }
+    let (handle, placement) = vram::vram_alloc(data.len() as u64, evict).ok()?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(data.as_ptr(), placement.cpu_addr as *mut u8, data.len());
+    }
+    Some(handle)
+}
+
+/// Let the engine run until `fence` signals
+///
+/// Inference may run on the same thread as the GPU poll loop, so the engine
+/// is driven from here rather than only waited on.
+fn wait(fence: Fence) -> Result<(), GpuError> {
+    while !fence::fence_signaled(fence) {
+        if cmd::process() == 0 {
+            return fence::fence_wait(fence, Some(FENCE_TIMEOUT_TICKS));
+        }
+    }
+    Ok(())
+}
+
+impl Offload {
+    /// Upload as many of `graph`'s layers as fit
+    ///
+    /// Returns `None` without a compute engine or if no layer fits.
+    pub fn new(graph: &Graph) -> Option<Self> {
+        if !compute::has_backend() {
+            return None;
+        }
+        let io_size = graph.max_width as u64 * 4;
+        let context = cmd::kernel_context_create().ok()?;
+        let mut offload = Self {
+            context,
+            layers: [None; MAX_NODES],
+            input: VramHandle(0),
+            output: VramHandle(0),
+            io_size,
+        };
+        let buffers = vram::vram_alloc(io_size, None).and_then(|(input, _)| {
+            vram::vram_alloc(io_size, None)
+                .map(|(output, _)| (input, output))
+                .inspect_err(|_| {
+                    let _ = vram::vram_free(input);
+                })
+        });
+        let Ok((input, output)) = buffers else {
+            let _ = cmd::context_destroy(context, 0);
+            return None;
+        };
+        offload.input = input;
+        offload.output = output;
+
+        for (node, op) in graph.nodes.iter().enumerate() {
+            let Op::Linear { weights, bias } = *op else {
+                continue;
+            };
+            let Some(handle) = upload(weights.data, Some(evicted)) else {
+                continue;
+            };
+            let bias = match bias {
+                Some(b) => {
+                    let bytes = unsafe { core::slice::from_raw_parts(b.as_ptr() as *const u8, b.len() * 4) };
+                    match upload(bytes, Some(evicted)) {
+                        Some(b) => Some(b),
+                        None => {
+                            let _ = vram::vram_free(handle);
+                            continue;
+                        }
+                    }
+                }
+                None => None,
+            };
+            offload.layers[node] = Some(Layer {
+                weights: handle,
+                bias,
+                format: format(weights.ty),
+                rows: weights.rows as u32,
+                cols: weights.cols as u32,
+            });
+        }
+
+        if offload.layers.iter().all(|l| l.is_none()) {
+            offload.release();
+            return None;
+        }
+        Some(offload)
+    }
+
+    /// Layers held in video memory
+    pub fn resident_layers(&self) -> usize {
+        self.layers.iter().flatten().count()
+    }
+
+    /// Bytes of video memory taken at upload
+    pub fn memory(&self) -> u64 {
+        let layers = self.layers.iter().flatten().map(|l| {
+            let bias = if l.bias.is_some() { l.rows as u64 * 4 } else { 0 };
+            l.format.matrix_size(l.rows, l.cols) + bias
+        });
+        layers.sum::<u64>() + 2 * self.io_size
+    }
+
+    /// Run linear layer `node` on the engine
+    ///
+    /// Returns `false` if the layer must run on the CPU instead.
+    pub fn matvec(&self, node: usize, x: &[f32], y: &mut [f32]) -> bool {
+        let Some(layer) = self.layers.get(node).copied().flatten() else {
+            return false;
+        };
+        self.dispatch(layer, x, y).is_ok()
+    }
+
+    fn dispatch(&self, layer: Layer, x: &[f32], y: &mut [f32]) -> Result<(), GpuError> {
+        let input = vram::vram_placement(self.input)?;
+        let output = vram::vram_placement(self.output)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(x.as_ptr(), input.cpu_addr as *mut f32, x.len());
+        }
+
+        let op = MatVec {
+            weights: layer.weights,
+            format: layer.format,
+            rows: layer.rows,
+            cols: layer.cols,
+            input: self.input,
+            bias: layer.bias,
+            output: self.output,
+        };
+        let errors = cmd::context_errors(self.context, 0)?;
+        let fence = cmd::submit(self.context, 0, &[Command::MatVec(op)])?;
+        wait(fence)?;
+        if cmd::context_errors(self.context, 0)? != errors {
+            return Err(GpuError::RenderingFailed);
+        }
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(output.cpu_addr as *const f32, y.as_mut_ptr(), y.len());
+        }
+        Ok(())
+    }
+
+    /// Free the video memory and command context
+    pub fn release(self) {
+        for layer in self.layers.iter().flatten() {
+            let _ = vram::vram_free(layer.weights);
+            if let Some(bias) = layer.bias {
+                let _ = vram::vram_free(bias);
+            }
+        }
+        let _ = vram::vram_free(self.input);
+        let _ = vram::vram_free(self.output);
+        let _ = cmd::context_destroy(self.context, 0);
+    }
+}
@@ -0,0 +1,195 @@
+//! Learned prefetch hints
+//!
+//! TagFS reads are observed through its access hook and replayed from the
+//! AI poll loop. Two patterns are learned: reads that continue where the
+//! last one on the same object ended get the next range read ahead, and
+//! for every object the objects read right after it are counted, so the
+//! likely successor is hinted as soon as the object is opened again.
+//!
+//! Hints only warm the storage unit cache; its counters show how many of
+//! them were used, and `storage::cache::set_prefetch` switches them off.
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use crate::tagfs;
+
+/// Reads waiting to be learned from
+const MAX_EVENTS: usize = 64;
+
+/// Objects whose successors are remembered
+const MAX_TRACKED: usize = 128;
+
+/// Successor candidates per object
+const SUCCESSORS: usize = 4;
+
+/// Times a successor must have followed before it is hinted
+const MIN_COUNT: u32 = 2;
+
+/// Successor counts are halved once one reaches this, so old habits fade
+const COUNT_LIMIT: u32 = 64;
+
+/// Bytes hinted ahead of a sequential read
+const READAHEAD: u64 = 64 * 1024;
+
+/// Bytes hinted from the start of a predicted object
+const PREDICT_BYTES: u64 = 64 * 1024;
+
+#[derive(Clone, Copy)]
+struct Access {
+    object: u64,
+    offset: u64,
+    len: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    object: u64,
+    /// Following objects and how often each came next
+    successors: [(u64, u32); SUCCESSORS],
+    last_seen: u64,
+}
+
+/// Prefetch counters
+#[derive(Clone, Copy, Debug)]
+pub struct PrefetchStats {
+    /// Reads learned from
+    pub observed: u64,
+    /// Reads missed with the event queue full
+    pub dropped: u64,
+    /// Successor objects hinted
+    pub predictions: u64,
+    /// Sequential ranges hinted
+    pub readaheads: u64,
+}
+
+struct Learner {
+    events: ArrayVec<Access, MAX_EVENTS>,
+    table: ArrayVec<Entry, MAX_TRACKED>,
+    last_object: Option<u64>,
+    /// End of the last read of `last_object`
+    last_end: u64,
+    clock: u64,
+    stats: PrefetchStats,
+}
+
+static LEARNER: Mutex<Learner> = Mutex::new(Learner {
+    events: ArrayVec::new_const(),
+    table: ArrayVec::new_const(),
+    last_object: None,
+    last_end: 0,
+    clock: 0,
+    stats: PrefetchStats {
+        observed: 0,
+        dropped: 0,
+        predictions: 0,
+        readaheads: 0,
+    },
+});
+
+/// TagFS access hook: queue the read for the poll loop
+fn observe(object: u64, offset: u64, len: usize) {
+    let mut learner = LEARNER.lock();
+    if learner.events.try_push(Access { object, offset, len }).is_err() {
+        learner.stats.dropped += 1;
+    }
+}
+
+/// Start observing TagFS reads
+pub fn init() {
+    tagfs::tagfs_set_access_hook(Some(observe));
+}
+
+impl Learner {
+    /// Count `next` as read right after `prev`
+    fn learn(&mut self, prev: u64, next: u64) {
+        let clock = self.clock;
+        let index = match self.table.iter().position(|e| e.object == prev) {
+            Some(index) => index,
+            None => {
+                let entry = Entry {
+                    object: prev,
+                    successors: [(0, 0); SUCCESSORS],
+                    last_seen: clock,
+                };
+                if self.table.is_full() {
+                    let stale = (0..self.table.len())
+                        .min_by_key(|&i| self.table[i].last_seen)
+                        .expect("table is full");
+                    self.table[stale] = entry;
+                    stale
+                } else {
+                    self.table.push(entry);
+                    self.table.len() - 1
+                }
+            }
+        };
+
+        let entry = &mut self.table[index];
+        entry.last_seen = clock;
+        let slot = match entry.successors.iter().position(|&(o, n)| o == next && n > 0) {
+            Some(slot) => slot,
+            None => {
+                let weakest = (0..SUCCESSORS)
+                    .min_by_key(|&i| entry.successors[i].1)
+                    .expect("successor slots");
+                entry.successors[weakest] = (next, 0);
+                weakest
+            }
+        };
+        entry.successors[slot].1 += 1;
+        if entry.successors[slot].1 >= COUNT_LIMIT {
+            for (_, count) in entry.successors.iter_mut() {
+                *count /= 2;
+            }
+        }
+    }
+
+    /// Object usually read after `object`, if one clearly dominates
+    fn predict(&self, object: u64) -> Option<u64> {
+        let entry = self.table.iter().find(|e| e.object == object)?;
+        let total: u32 = entry.successors.iter().map(|&(_, n)| n).sum();
+        let &(next, count) = entry.successors.iter().max_by_key(|&&(_, n)| n)?;
+        (count >= MIN_COUNT && count * 2 > total).then_some(next)
+    }
+
+    fn process(&mut self, access: Access) {
+        self.clock += 1;
+        self.stats.observed += 1;
+        let end = access.offset + access.len as u64;
+
+        if self.last_object == Some(access.object) {
+            if access.offset == self.last_end && tagfs::tagfs_prefetch(access.object, end, READAHEAD).is_ok() {
+                self.stats.readaheads += 1;
+            }
+            self.last_end = end;
+            return;
+        }
+
+        if let Some(prev) = self.last_object {
+            self.learn(prev, access.object);
+        }
+        self.last_object = Some(access.object);
+        self.last_end = end;
+
+        if let Some(next) = self.predict(access.object) {
+            if tagfs::tagfs_prefetch(next, 0, PREDICT_BYTES).is_ok() {
+                self.stats.predictions += 1;
+            }
+        }
+    }
+}
+
+/// Learn from queued reads and issue hints, run from the AI poll loop
+pub fn poll() {
+    let mut learner = LEARNER.lock();
+    while !learner.events.is_empty() {
+        let access = learner.events.remove(0);
+        learner.process(access);
+    }
+}
+
+/// Prefetch counters
+pub fn stats() -> PrefetchStats {
+    LEARNER.lock().stats
+}
@@ -0,0 +1,111 @@
+//! Quantized weight formats
+//!
+//! Weights are stored in blocks of `QK` values sharing one f16 scale, as in
+//! GGML: Q8_0 keeps a signed byte per value and Q4_0 a nibble offset by 8.
+//! Matrix-vector products dequantize one block at a time into registers,
+//! so a model never needs its f32 weights in memory.
+
+use super::simd::{self, Level, Vector};
+
+/// Values per quantization block
+pub const QK: usize = 32;
+
+/// Bytes per Q8_0 block: f16 scale, 32 x i8
+pub const Q8_0_BLOCK: usize = 2 + QK;
+
+/// Bytes per Q4_0 block: f16 scale, 32 x 4-bit
+pub const Q4_0_BLOCK: usize = 2 + QK / 2;
+
+/// Convert an IEEE half to f32
+pub fn f16_to_f32(bits: u16) -> f32 {
+    let sign = ((bits as u32) & 0x8000) << 16;
+    let exp = ((bits >> 10) & 0x1F) as u32;
+    let mantissa = (bits & 0x3FF) as u32;
+    let value = match (exp, mantissa) {
+        (0, 0) => sign,
+        (0, _) => {
+            // Subnormal: mantissa * 2^-24
+            let v = mantissa as f32 * (1.0 / 16_777_216.0);
+            return if sign != 0 { -v } else { v };
+        }
+        (0x1F, _) => sign | 0x7F80_0000 | (mantissa << 13),
+        _ => sign | ((exp + 127 - 15) << 23) | (mantissa << 13),
+    };
+    f32::from_bits(value)
+}
+
+fn scale(block: &[u8]) -> f32 {
+    f16_to_f32(u16::from_le_bytes([block[0], block[1]]))
+}
+
+/// Dot product of one Q8_0 block with 32 activations
+fn dot_q8_0(v: Vector, block: &[u8], x: &[f32]) -> f32 {
+    if v.level() >= Level::Avx2 {
+        return unsafe { simd::dot_q8_avx2(&block[2..], x) } * scale(block);
+    }
+    let mut sum = 0.0;
+    for (q, a) in block[2..].iter().zip(x) {
+        sum += (*q as i8) as f32 * a;
+    }
+    sum * scale(block)
+}
+
+/// Dot product of one Q4_0 block with 32 activations
+///
+/// Byte `j` holds value `j` in its low nibble and value `j + 16` in its
+/// high nibble.
+fn dot_q4_0(v: Vector, block: &[u8], x: &[f32]) -> f32 {
+    if v.level() >= Level::Avx2 {
+        return unsafe { simd::dot_q4_avx2(&block[2..], x) } * scale(block);
+    }
+    let (lo, hi) = x.split_at(QK / 2);
+    let mut sum = 0.0;
+    for (j, q) in block[2..].iter().enumerate() {
+        sum += ((q & 0x0F) as i32 - 8) as f32 * lo[j];
+        sum += ((q >> 4) as i32 - 8) as f32 * hi[j];
+    }
+    sum * scale(block)
+}
+
+fn matvec_blocks(
+    v: Vector,
+    weights: &[u8],
+    block_size: usize,
+    dot: fn(Vector, &[u8], &[f32]) -> f32,
+    x: &[f32],
+    bias: Option<&[f32]>,
+    out: &mut [f32],
+) {
+    let row_bytes = x.len() / QK * block_size;
+    for (r, y) in out.iter_mut().enumerate() {
+        let row = &weights[r * row_bytes..(r + 1) * row_bytes];
+        let mut sum = 0.0;
+        for (block, chunk) in row.chunks_exact(block_size).zip(x.chunks_exact(QK)) {
+            sum += dot(v, block, chunk);
+        }
+        *y = sum + bias.map_or(0.0, |b| b[r]);
+    }
+}
+
+/// `matvec_f32` for Q8_0 weights; `x.len()` must be a multiple of `QK`
+pub fn matvec_q8_0(v: Vector, weights: &[u8], x: &[f32], bias: Option<&[f32]>, out: &mut [f32]) {
+    matvec_blocks(v, weights, Q8_0_BLOCK, dot_q8_0, x, bias, out);
+}
+
+/// `matvec_f32` for Q4_0 weights; `x.len()` must be a multiple of `QK`
+pub fn matvec_q4_0(v: Vector, weights: &[u8], x: &[f32], bias: Option<&[f32]>, out: &mut [f32]) {
+    matvec_blocks(v, weights, Q4_0_BLOCK, dot_q4_0, x, bias, out);
+}
+
+/// `matvec_f32` for f16 weights
+pub fn matvec_f16(weights: &[u8], x: &[f32], bias: Option<&[f32]>, out: &mut [f32]) {
+    let row_bytes = x.len() * 2;
+    for (r, y) in out.iter_mut().enumerate() {
+        let row = &weights[r * row_bytes..(r + 1) * row_bytes];
+        let mut sum = 0.0;
+        for (w, v) in row.chunks_exact(2).zip(x) {
+            sum += f16_to_f32(u16::from_le_bytes([w[0], w[1]])) * v;
+        }
+        *y = sum + bias.map_or(0.0, |b| b[r]);
+    }
+}
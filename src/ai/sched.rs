@@ -0,0 +1,304 @@
+//! Inference scheduling
+//!
+//! Requests queue here until they run. Requests for the same model are
+//! gathered into a batch of up to [`MAX_BATCH`] that moves through the
+//! graph one node at a time. Between nodes the scheduler picks the most
+//! urgent job again, so a long job on a big model is preempted as soon as
+//! a more urgent request arrives, and each poll runs for at most a slice.
+//!
+//! Urgency is the request's priority class, raised one class for every
+//! [`AGING_TICKS`] it has waited so background work still finishes.
+//! Within a class, clients take turns when batches are formed, and each
+//! client has a bounded queue.
+//!
+//! A request that continues a session runs alone, since its attention
+//! layers read and extend the session's cache, and after any earlier
+//! request for the same session.
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use super::model::{self, MAX_BATCH};
+use super::service::{self, Request, STATUS_BUSY};
+use super::{session, AiError};
+
+/// Requests waiting to join a batch, across all clients
+pub const MAX_PENDING: usize = 64;
+
+/// Requests one client may have waiting
+pub const CLIENT_QUEUE_DEPTH: usize = 8;
+
+/// Batches in flight (one per model at most)
+const MAX_JOBS: usize = model::MAX_MODELS;
+
+/// Waiting this long raises a request one priority class
+pub const AGING_TICKS: u64 = crate::scheduler::TICKS_PER_SECOND / 2;
+
+/// Ticks one poll may spend running nodes
+const SLICE_TICKS: u64 = 1;
+
+/// Latency class of a request; lower runs first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Interactive = 0,
+    Normal = 1,
+    Background = 2,
+}
+
+impl Priority {
+    pub fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            0 => Some(Priority::Interactive),
+            1 => Some(Priority::Normal),
+            2 => Some(Priority::Background),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Pending {
+    client: u32,
+    request: Request,
+    priority: Priority,
+    queued_at: u64,
+}
+
+impl Pending {
+    /// Priority class after aging, lower is more urgent
+    fn urgency(&self, now: u64) -> u64 {
+        let aged = now.saturating_sub(self.queued_at) / AGING_TICKS;
+        (self.priority as u64).saturating_sub(aged)
+    }
+}
+
+/// A batch moving through a model's graph
+struct Job {
+    model: u64,
+    members: ArrayVec<Pending, MAX_BATCH>,
+    /// Next node to run
+    node: usize,
+    nodes: usize,
+}
+
+impl Job {
+    /// Most urgent member's urgency, then the oldest
+    fn rank(&self, now: u64) -> (u64, u64) {
+        let urgency = self.members.iter().map(|m| m.urgency(now)).min().unwrap_or(u64::MAX);
+        let oldest = self.members.iter().map(|m| m.queued_at).min().unwrap_or(u64::MAX);
+        (urgency, oldest)
+    }
+
+    /// Session the job continues, with the client that owns it
+    fn session(&self) -> Option<(u64, u32)> {
+        let member = self.members.first()?;
+        (member.request.session != 0).then_some((member.request.session, member.client))
+    }
+}
+
+struct Scheduler {
+    pending: ArrayVec<Pending, MAX_PENDING>,
+    jobs: ArrayVec<Job, MAX_JOBS>,
+    /// Client that went last when a batch was formed
+    last_client: u32,
+    /// Requests whose job was switched away from mid-graph
+    preemptions: u64,
+    completed: u64,
+}
+
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+    pending: ArrayVec::new_const(),
+    jobs: ArrayVec::new_const(),
+    last_client: 0,
+    preemptions: 0,
+    completed: 0,
+});
+
+/// Scheduler counters
+#[derive(Clone, Copy, Debug)]
+pub struct SchedStats {
+    pub pending: usize,
+    pub running: usize,
+    pub preemptions: u64,
+    pub completed: u64,
+}
+
+/// Queue a validated request from `client`
+///
+/// Fails with a busy status when the client's queue or the pending list
+/// is full.
+pub(super) fn submit(client: u32, request: Request, priority: Priority) -> Result<(), u32> {
+    let mut sched = SCHEDULER.lock();
+    let queued = sched.pending.iter().filter(|p| p.client == client).count();
+    if queued >= CLIENT_QUEUE_DEPTH {
+        return Err(STATUS_BUSY);
+    }
+    sched
+        .pending
+        .try_push(Pending {
+            client,
+            request,
+            priority,
+            queued_at: crate::scheduler::ticks(),
+        })
+        .map_err(|_| STATUS_BUSY)
+}
+
+impl Scheduler {
+    /// Start batches for models without one, taking the most urgent
+    /// requests first and rotating between clients on ties
+    fn form_jobs(&mut self, now: u64) {
+        while !self.pending.is_empty() && !self.jobs.is_full() {
+            let last = self.last_client;
+            let lead = (0..self.pending.len())
+                .filter(|&i| !self.jobs.iter().any(|j| j.model == self.pending[i].request.model))
+                .filter(|&i| {
+                    let session = self.pending[i].request.session;
+                    session == 0 || !self.pending[..i].iter().any(|p| p.request.session == session)
+                })
+                .min_by_key(|&i| {
+                    let p = &self.pending[i];
+                    (p.urgency(now), p.client <= last, p.client, p.queued_at)
+                });
+            let Some(lead) = lead else {
+                return;
+            };
+            let first = self.pending.remove(lead);
+            self.last_client = first.client;
+
+            let mut job = Job {
+                model: first.request.model,
+                members: ArrayVec::new(),
+                node: 0,
+                nodes: 0,
+            };
+            let alone = first.request.session != 0;
+            job.members.push(first);
+            while !alone && !job.members.is_full() {
+                let next = (0..self.pending.len())
+                    .filter(|&i| self.pending[i].request.model == job.model && self.pending[i].request.session == 0)
+                    .min_by_key(|&i| (self.pending[i].urgency(now), self.pending[i].queued_at));
+                match next {
+                    Some(i) => job.members.push(self.pending.remove(i)),
+                    None => break,
+                }
+            }
+            self.jobs.push(job);
+        }
+    }
+
+    /// Index of the job to run next
+    fn pick(&self, now: u64) -> Option<usize> {
+        (0..self.jobs.len()).min_by_key(|&i| self.jobs[i].rank(now))
+    }
+}
+
+/// Copy each member's input into its batch slot
+///
+/// Members whose tensors are no longer valid get their result now and
+/// drop out.
+fn start_job(job: &mut Job) -> Result<(), AiError> {
+    job.nodes = model::node_count(job.model)?;
+    let mut slot = 0;
+    let mut members = ArrayVec::<Pending, MAX_BATCH>::new();
+    for member in job.members.drain(..) {
+        let started = service::tensors(member.client, &member.request)
+            .and_then(|(input, _)| model::batch_start(job.model, slot, input).map_err(service::status_of));
+        match started {
+            Ok(()) => {
+                members.push(member);
+                slot += 1;
+            }
+            Err(status) => service::send_result(member.client, &member.request, status, 0),
+        }
+    }
+    job.members = members;
+    Ok(())
+}
+
+/// Write results back and reply to every member
+fn finish_job(job: &Job) {
+    if let Some((id, owner)) = job.session() {
+        let _ = session::advance(id, owner);
+    }
+    for (slot, member) in job.members.iter().enumerate() {
+        let result = service::tensors(member.client, &member.request)
+            .and_then(|(_, output)| model::batch_finish(job.model, slot, output).map_err(service::status_of));
+        match result {
+            Ok(written) => service::send_result(member.client, &member.request, service::STATUS_OK, written as u32),
+            Err(status) => service::send_result(member.client, &member.request, status, 0),
+        }
+    }
+}
+
+/// Run the next node of `job`, starting it first if needed
+fn advance(job: &mut Job) -> Result<(), AiError> {
+    if job.node == 0 {
+        start_job(job)?;
+    }
+    if job.members.is_empty() || job.node >= job.nodes {
+        return Ok(());
+    }
+    match job.session() {
+        Some((id, owner)) => session::with_cache(id, owner, |model, kv| model::batch_step(model, job.node, 1, kv))?,
+        None => model::batch_step(job.model, job.node, job.members.len(), None)?,
+    }
+    job.node += 1;
+    Ok(())
+}
+
+/// Run queued work for up to one slice
+pub fn run() {
+    let start = crate::scheduler::ticks();
+    let mut last_job = None;
+    loop {
+        let now = crate::scheduler::ticks();
+        let mut sched = SCHEDULER.lock();
+        sched.form_jobs(now);
+        let Some(index) = sched.pick(now) else {
+            return;
+        };
+        let model = sched.jobs[index].model;
+        if last_job.is_some_and(|m| m != model) {
+            // Switched away from a job that had started
+            if sched.jobs.iter().any(|j| Some(j.model) == last_job && j.node > 0) {
+                sched.preemptions += 1;
+            }
+        }
+        last_job = Some(model);
+
+        // Run one node without holding the lock, so requests keep queueing
+        let mut job = sched.jobs.remove(index);
+        drop(sched);
+
+        match advance(&mut job) {
+            Err(e) => {
+                let status = service::status_of(e);
+                for member in job.members.iter() {
+                    service::send_result(member.client, &member.request, status, 0);
+                }
+            }
+            Ok(()) if job.members.is_empty() => {}
+            Ok(()) if job.node >= job.nodes => {
+                finish_job(&job);
+                SCHEDULER.lock().completed += job.members.len() as u64;
+            }
+            Ok(()) => SCHEDULER.lock().jobs.push(job),
+        }
+
+        if crate::scheduler::ticks() >= start + SLICE_TICKS {
+            return;
+        }
+    }
+}
+
+/// Scheduler counters
+pub fn stats() -> SchedStats {
+    let sched = SCHEDULER.lock();
+    SchedStats {
+        pending: sched.pending.len(),
+        running: sched.jobs.iter().map(|j| j.members.len()).sum(),
+        preemptions: sched.preemptions,
+        completed: sched.completed,
+    }
+}
@@ -0,0 +1,302 @@
+//! Inference service
+//!
+//! Processes run loaded models through IPC on the channel registered as
+//! [`SERVICE_NAME`]. Tensors travel in shared-memory grants: the client
+//! writes its input vector into a grant it holds, names the grant and the
+//! input and output ranges in the request, and later receives a result
+//! message once the output has been written back. Every request carries
+//! the channel replies should go to; all integers are little-endian and
+//! tensor offsets are byte offsets aligned to 4.
+//!
+//! Accepted requests are handed to the inference scheduler, which batches
+//! them per model and runs them by priority class.
+//!
+//! A request may name a session opened on its model; the model then runs
+//! one position of the session's sequence and keeps its attention context
+//! for the next request. Sessions belong to the process that opened them.
+
+use spin::Mutex;
+
+use super::model::{self, ModelInfo};
+use super::sched::{self, Priority};
+use super::{session, AiError};
+use crate::capability::{self, Permission};
+use crate::ipc::{self, grant, names, MessageHeader};
+
+/// Name the service channel is registered under
+pub const SERVICE_NAME: &str = "ai.inference";
+
+/// List loaded models; payload: reply channel (u64). One model reply per
+/// model follows, then a status reply.
+pub const AI_LIST_MSG: u32 = 0x4149_0001;
+
+/// Description of a model (reply); payload: model ID (u64), input length,
+/// output length, bits per weight, flags (bit 0 classifier, bit 1 some
+/// layers on the GPU), activation bytes per request, all u32
+pub const AI_MODEL_MSG: u32 = 0x4149_0002;
+
+/// Result of a list request (reply); payload: status (u32)
+pub const AI_STATUS_MSG: u32 = 0x4149_0003;
+
+/// Run a model; payload: reply channel (u64), model ID (u64), request tag
+/// (u64), grant ID, input offset, input length in values, output offset,
+/// output capacity in values, then optionally a priority class (0
+/// interactive, 1 normal, 2 background; u32 each) and a session ID (u64,
+/// 0 for none). Needs the AI inference permission.
+pub const AI_INFER_MSG: u32 = 0x4149_0004;
+
+/// Outcome of an inference request (reply); payload: request tag (u64),
+/// status (u32), output values written (u32)
+pub const AI_RESULT_MSG: u32 = 0x4149_0005;
+
+/// Open a session; payload: reply channel (u64), model ID (u64). Needs the
+/// AI inference permission.
+pub const AI_SESSION_OPEN_MSG: u32 = 0x4149_0006;
+
+/// Outcome of opening a session (reply); payload: status (u32), session ID
+/// (u64)
+pub const AI_SESSION_MSG: u32 = 0x4149_0007;
+
+/// Forget a session's context; payload: reply channel (u64), session ID
+/// (u64). A status reply follows.
+pub const AI_SESSION_RESET_MSG: u32 = 0x4149_0008;
+
+/// Close a session; payload: reply channel (u64), session ID (u64). A
+/// status reply follows.
+pub const AI_SESSION_CLOSE_MSG: u32 = 0x4149_0009;
+
+/// Status codes
+pub const STATUS_OK: u32 = 0;
+pub const STATUS_INVALID_REQUEST: u32 = 1;
+pub const STATUS_INVALID_MODEL: u32 = 2;
+pub const STATUS_INVALID_GRANT: u32 = 3;
+pub const STATUS_PERMISSION_DENIED: u32 = 4;
+pub const STATUS_BUSY: u32 = 5;
+pub const STATUS_FAILED: u32 = 6;
+pub const STATUS_INVALID_SESSION: u32 = 7;
+/// The session lost its context to make room for others; reset it
+pub const STATUS_SESSION_EVICTED: u32 = 8;
+
+const FLAG_CLASSIFIER: u32 = 1 << 0;
+const FLAG_GPU: u32 = 1 << 1;
+
+#[derive(Clone, Copy)]
+pub(super) struct Request {
+    reply: u64,
+    tag: u64,
+    pub(super) model: u64,
+    grant: u32,
+    input_offset: u32,
+    input_len: u32,
+    output_offset: u32,
+    output_len: u32,
+    /// Session the request continues, 0 for none
+    pub(super) session: u64,
+}
+
+/// Service channel, once created
+static CHANNEL: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Create the service channel and register its name
+pub fn init() -> Result<u64, ipc::IpcError> {
+    let channel = ipc::create_channel()?;
+    names::register(SERVICE_NAME, channel)?;
+    *CHANNEL.lock() = Some(channel);
+    Ok(channel)
+}
+
+fn reply(channel: u64, receiver: u32, msg_type: u32, data: &[u8]) {
+    let header = MessageHeader {
+        id: 0,
+        sender: 0, // kernel
+        receiver,
+        length: data.len() as u32,
+        msg_type,
+    };
+    let _ = ipc::msg_send(channel, header, data);
+}
+
+fn result(channel: u64, receiver: u32, tag: u64, status: u32, written: u32) {
+    let mut msg = [0u8; 16];
+    msg[0..8].copy_from_slice(&tag.to_le_bytes());
+    msg[8..12].copy_from_slice(&status.to_le_bytes());
+    msg[12..16].copy_from_slice(&written.to_le_bytes());
+    reply(channel, receiver, AI_RESULT_MSG, &msg);
+}
+
+/// Tell `client` how `request` ended
+pub(super) fn send_result(client: u32, request: &Request, status: u32, written: u32) {
+    result(request.reply, client, request.tag, status, written);
+}
+
+/// Status code reported for an inference error
+pub(super) fn status_of(error: AiError) -> u32 {
+    match error {
+        AiError::ModelNotFound => STATUS_INVALID_MODEL,
+        AiError::InvalidInput => STATUS_INVALID_REQUEST,
+        AiError::SessionNotFound => STATUS_INVALID_SESSION,
+        AiError::SessionEvicted => STATUS_SESSION_EVICTED,
+        AiError::TooManySessions => STATUS_BUSY,
+        _ => STATUS_FAILED,
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+fn list_models(channel: u64, receiver: u32) {
+    for info in model::list_models() {
+        let ModelInfo { id, input_len, output_len, .. } = info;
+        let flags = if info.classifier { FLAG_CLASSIFIER } else { 0 } | if info.gpu_layers > 0 { FLAG_GPU } else { 0 };
+        let mut msg = [0u8; 28];
+        msg[0..8].copy_from_slice(&id.to_le_bytes());
+        msg[8..12].copy_from_slice(&(input_len as u32).to_le_bytes());
+        msg[12..16].copy_from_slice(&(output_len as u32).to_le_bytes());
+        msg[16..20].copy_from_slice(&info.quantization.bits().to_le_bytes());
+        msg[20..24].copy_from_slice(&flags.to_le_bytes());
+        msg[24..28].copy_from_slice(&(info.arena_size as u32).to_le_bytes());
+        reply(channel, receiver, AI_MODEL_MSG, &msg);
+    }
+}
+
+fn parse_request(data: &[u8]) -> Option<(Request, Priority)> {
+    let priority = match read_u32(data, 44) {
+        Some(raw) => Priority::from_raw(raw)?,
+        None => Priority::Normal,
+    };
+    let request = Request {
+        reply: read_u64(data, 0)?,
+        model: read_u64(data, 8)?,
+        tag: read_u64(data, 16)?,
+        grant: read_u32(data, 24)?,
+        input_offset: read_u32(data, 28)?,
+        input_len: read_u32(data, 32)?,
+        output_offset: read_u32(data, 36)?,
+        output_len: read_u32(data, 40)?,
+        session: read_u64(data, 48).unwrap_or(0),
+    };
+    Some((request, priority))
+}
+
+/// Input and output vectors of a request inside its grant
+///
+/// Checked again when the request runs, since the grant can be revoked
+/// while it waits.
+pub(super) fn tensors(client: u32, request: &Request) -> Result<(&'static [f32], &'static mut [f32]), u32> {
+    let mapping = grant::grant_map(request.grant, client).map_err(|_| STATUS_INVALID_GRANT)?;
+    let range = |offset: u32, len: u32| {
+        let start = offset as usize;
+        let end = start.checked_add(len as usize * 4)?;
+        (offset.is_multiple_of(4) && end <= mapping.size).then_some(start..end)
+    };
+    let input = range(request.input_offset, request.input_len).ok_or(STATUS_INVALID_REQUEST)?;
+    let output = range(request.output_offset, request.output_len).ok_or(STATUS_INVALID_REQUEST)?;
+    if input.start < output.end && output.start < input.end {
+        return Err(STATUS_INVALID_REQUEST);
+    }
+    let base = mapping.base as usize;
+    unsafe {
+        Ok((
+            core::slice::from_raw_parts((base + input.start) as *const f32, request.input_len as usize),
+            core::slice::from_raw_parts_mut((base + output.start) as *mut f32, request.output_len as usize),
+        ))
+    }
+}
+
+/// Validate an inference request and queue it for `client`
+fn submit(client: u32, request: Request, priority: Priority) -> Result<(), u32> {
+    if capability::check_permission(client, Permission::AiInference).is_err() {
+        return Err(STATUS_PERMISSION_DENIED);
+    }
+    let info = model::model_info(request.model).map_err(|_| STATUS_INVALID_MODEL)?;
+    if request.input_len as usize != info.input_len || (request.output_len as usize) < info.output_len {
+        return Err(STATUS_INVALID_REQUEST);
+    }
+    if request.session != 0 {
+        let model = session::model_of(request.session, client).map_err(status_of)?;
+        if model != request.model {
+            return Err(STATUS_INVALID_SESSION);
+        }
+    }
+    tensors(client, &request)?;
+    sched::submit(client, request, priority)
+}
+
+/// Open a session on `model` for `client`
+fn open_session(client: u32, model: u64) -> Result<u64, u32> {
+    if capability::check_permission(client, Permission::AiInference).is_err() {
+        return Err(STATUS_PERMISSION_DENIED);
+    }
+    session::open(model, client).map_err(status_of)
+}
+
+/// Take new requests and run queued ones
+pub fn dispatch() {
+    let Some(channel) = *CHANNEL.lock() else {
+        return;
+    };
+
+    let mut buffer = [0; ipc::MAX_MESSAGE_SIZE];
+    while let Ok((header, len)) = ipc::msg_recv(channel, &mut buffer) {
+        let data = &buffer[..len];
+        match header.msg_type {
+            AI_LIST_MSG => {
+                let Some(reply_channel) = read_u64(data, 0) else {
+                    continue;
+                };
+                list_models(reply_channel, header.sender);
+                reply(reply_channel, header.sender, AI_STATUS_MSG, &STATUS_OK.to_le_bytes());
+            }
+            AI_INFER_MSG => match parse_request(data) {
+                Some((request, priority)) => {
+                    if let Err(status) = submit(header.sender, request, priority) {
+                        send_result(header.sender, &request, status, 0);
+                    }
+                }
+                None => {
+                    if let Some(reply_channel) = read_u64(data, 0) {
+                        let tag = read_u64(data, 16).unwrap_or(0);
+                        result(reply_channel, header.sender, tag, STATUS_INVALID_REQUEST, 0);
+                    }
+                }
+            },
+            AI_SESSION_OPEN_MSG => {
+                let (Some(reply_channel), Some(model)) = (read_u64(data, 0), read_u64(data, 8)) else {
+                    continue;
+                };
+                let (status, id) = match open_session(header.sender, model) {
+                    Ok(id) => (STATUS_OK, id),
+                    Err(status) => (status, 0),
+                };
+                let mut msg = [0u8; 12];
+                msg[0..4].copy_from_slice(&status.to_le_bytes());
+                msg[4..12].copy_from_slice(&id.to_le_bytes());
+                reply(reply_channel, header.sender, AI_SESSION_MSG, &msg);
+            }
+            AI_SESSION_RESET_MSG | AI_SESSION_CLOSE_MSG => {
+                let Some(reply_channel) = read_u64(data, 0) else {
+                    continue;
+                };
+                let outcome = match read_u64(data, 8) {
+                    Some(id) if header.msg_type == AI_SESSION_RESET_MSG => session::reset(id, header.sender),
+                    Some(id) => session::close(id, header.sender),
+                    None => Err(AiError::InvalidInput),
+                };
+                let status = outcome.map_or_else(status_of, |()| STATUS_OK);
+                reply(reply_channel, header.sender, AI_STATUS_MSG, &status.to_le_bytes());
+            }
+            _ => {
+                if let Some(reply_channel) = read_u64(data, 0) {
+                    reply(reply_channel, header.sender, AI_STATUS_MSG, &STATUS_INVALID_REQUEST.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    sched::run();
+}
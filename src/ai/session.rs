@@ -0,0 +1,263 @@
+//! Inference sessions
+//!
+//! A session runs one model over a sequence of inputs, one position per
+//! request, and keeps the keys and values its attention layers computed
+//! for earlier positions. Each request then only computes the new
+//! position instead of the whole context again. Once the model's context
+//! is full, the oldest position is overwritten.
+//!
+//! Caches live in kernel regions, which are never paged out. All caches
+//! together are held to [`KV_BUDGET`]; opening a session that does not fit
+//! evicts the least recently used ones. An evicted session keeps its ID
+//! but has lost its context, and runs fail with `SessionEvicted` until it
+//! is reset.
+
+use spin::Mutex;
+
+use super::graph::KvCache;
+use super::model::{self, Region};
+use super::AiError;
+
+/// Sessions open at once
+pub const MAX_SESSIONS: usize = 32;
+
+/// Bytes of key/value cache all sessions may hold together
+pub const KV_BUDGET: usize = 64 * 1024 * 1024;
+
+/// A session's cache
+struct Cache {
+    region: Region,
+    data: &'static mut [f32],
+}
+
+struct Session {
+    id: u64,
+    model: u64,
+    /// Process that opened the session
+    owner: u32,
+    /// `None` once evicted, or for models without attention
+    cache: Option<Cache>,
+    evicted: bool,
+    /// Positions run so far
+    position: u64,
+    last_used: u64,
+}
+
+impl Session {
+    fn kv_bytes(&self) -> usize {
+        self.cache.as_ref().map_or(0, |c| c.region.size)
+    }
+
+    /// Release the cache, returning its region for reuse
+    fn evict(&mut self) {
+        if let Some(cache) = self.cache.take() {
+            model::recycle_region(cache.region);
+            self.evicted = true;
+        }
+        self.position = 0;
+    }
+}
+
+/// Details of an open session
+#[derive(Clone, Copy, Debug)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub model: u64,
+    pub owner: u32,
+    pub position: u64,
+    /// Bytes of cache held
+    pub kv_bytes: usize,
+    pub evicted: bool,
+}
+
+struct Sessions {
+    sessions: [Option<Session>; MAX_SESSIONS],
+    next_id: u64,
+    clock: u64,
+}
+
+static SESSIONS: Mutex<Sessions> = Mutex::new(Sessions {
+    sessions: [const { None }; MAX_SESSIONS],
+    next_id: 1,
+    clock: 0,
+});
+
+impl Sessions {
+    fn get_mut(&mut self, id: u64, owner: u32) -> Result<&mut Session, AiError> {
+        self.clock += 1;
+        let clock = self.clock;
+        let session = self
+            .sessions
+            .iter_mut()
+            .flatten()
+            .find(|s| s.id == id && s.owner == owner)
+            .ok_or(AiError::SessionNotFound)?;
+        session.last_used = clock;
+        Ok(session)
+    }
+
+    fn kv_bytes(&self) -> usize {
+        self.sessions.iter().flatten().map(Session::kv_bytes).sum()
+    }
+
+    /// Evict least recently used caches until `bytes` more fit the budget
+    fn make_room(&mut self, bytes: usize) -> Result<(), AiError> {
+        if bytes > KV_BUDGET {
+            return Err(AiError::OutOfMemory);
+        }
+        while self.kv_bytes() + bytes > KV_BUDGET {
+            let victim = self
+                .sessions
+                .iter_mut()
+                .flatten()
+                .filter(|s| s.cache.is_some())
+                .min_by_key(|s| s.last_used)
+                .ok_or(AiError::OutOfMemory)?;
+            victim.evict();
+        }
+        Ok(())
+    }
+
+    /// A free slot, reusing the least recently used evicted session if
+    /// the table is full
+    fn free_slot(&mut self) -> Result<usize, AiError> {
+        if let Some(index) = self.sessions.iter().position(|s| s.is_none()) {
+            return Ok(index);
+        }
+        let index = (0..MAX_SESSIONS)
+            .filter(|&i| self.sessions[i].as_ref().is_some_and(|s| s.cache.is_none()))
+            .min_by_key(|&i| self.sessions[i].as_ref().map_or(0, |s| s.last_used))
+            .ok_or(AiError::TooManySessions)?;
+        self.sessions[index] = None;
+        Ok(index)
+    }
+}
+
+/// Cache for `model`, evicting other sessions' caches as needed
+fn allocate(sessions: &mut Sessions, model: u64) -> Result<Option<Cache>, AiError> {
+    let len = model::kv_len(model)?;
+    if len == 0 {
+        return Ok(None);
+    }
+    sessions.make_room((len * 4).next_multiple_of(4096))?;
+    let region = model::take_region(len * 4)?;
+    let data = unsafe { core::slice::from_raw_parts_mut(region.base as *mut f32, len) };
+    Ok(Some(Cache { region, data }))
+}
+
+/// Open a session on a loaded model for process `owner`
+pub fn open(model: u64, owner: u32) -> Result<u64, AiError> {
+    let mut sessions = SESSIONS.lock();
+    let index = sessions.free_slot()?;
+    let cache = allocate(&mut sessions, model)?;
+    let id = sessions.next_id;
+    sessions.next_id += 1;
+    sessions.clock += 1;
+    let last_used = sessions.clock;
+    sessions.sessions[index] = Some(Session {
+        id,
+        model,
+        owner,
+        cache,
+        evicted: false,
+        position: 0,
+        last_used,
+    });
+    Ok(id)
+}
+
+/// Close a session and free its cache
+pub fn close(id: u64, owner: u32) -> Result<(), AiError> {
+    let mut sessions = SESSIONS.lock();
+    let slot = sessions
+        .sessions
+        .iter_mut()
+        .find(|s| s.as_ref().is_some_and(|s| s.id == id && s.owner == owner))
+        .ok_or(AiError::SessionNotFound)?;
+    if let Some(mut session) = slot.take() {
+        session.evict();
+    }
+    Ok(())
+}
+
+/// Forget the context, getting a new cache if the session was evicted
+pub fn reset(id: u64, owner: u32) -> Result<(), AiError> {
+    let mut sessions = SESSIONS.lock();
+    let session = sessions.get_mut(id, owner)?;
+    session.position = 0;
+    if !session.evicted {
+        return Ok(());
+    }
+    let model = session.model;
+    let cache = allocate(&mut sessions, model)?;
+    let session = sessions.get_mut(id, owner)?;
+    session.cache = cache;
+    session.evicted = false;
+    Ok(())
+}
+
+/// Model a session runs
+pub fn model_of(id: u64, owner: u32) -> Result<u64, AiError> {
+    SESSIONS.lock().get_mut(id, owner).map(|s| s.model)
+}
+
+/// Call `f` with the session's cache at its current position
+pub fn with_cache<T>(
+    id: u64,
+    owner: u32,
+    f: impl FnOnce(u64, Option<&mut KvCache>) -> Result<T, AiError>,
+) -> Result<T, AiError> {
+    let mut sessions = SESSIONS.lock();
+    let session = sessions.get_mut(id, owner)?;
+    if session.evicted {
+        return Err(AiError::SessionEvicted);
+    }
+    match session.cache.as_mut() {
+        Some(cache) => {
+            let mut kv = KvCache {
+                data: &mut cache.data[..],
+                position: session.position,
+            };
+            f(session.model, Some(&mut kv))
+        }
+        None => f(session.model, None),
+    }
+}
+
+/// Move a session past the position just run
+pub fn advance(id: u64, owner: u32) -> Result<(), AiError> {
+    SESSIONS.lock().get_mut(id, owner).map(|s| s.position += 1)
+}
+
+/// Run the next position of a session
+pub fn run(id: u64, owner: u32, input: &[f32], output: &mut [f32]) -> Result<usize, AiError> {
+    let written = with_cache(id, owner, |model, kv| model::run(model, input, output, kv))?;
+    advance(id, owner)?;
+    Ok(written)
+}
+
+/// Close every session on an unloaded model
+pub fn forget_model(model: u64) {
+    let mut sessions = SESSIONS.lock();
+    for slot in sessions.sessions.iter_mut() {
+        if slot.as_ref().is_some_and(|s| s.model == model) {
+            if let Some(mut session) = slot.take() {
+                session.evict();
+            }
+        }
+    }
+}
+
+/// Details of a session
+pub fn info(id: u64, owner: u32) -> Result<SessionInfo, AiError> {
+    let mut sessions = SESSIONS.lock();
+    let session = sessions.get_mut(id, owner)?;
+    Ok(SessionInfo {
+        id: session.id,
+        model: session.model,
+        owner: session.owner,
+        position: session.position,
+        kv_bytes: session.kv_bytes(),
+        evicted: session.evicted,
+    })
+}
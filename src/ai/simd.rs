@@ -0,0 +1,464 @@
+//! Vector units for inference kernels
+//!
+//! The kernel is built for SSE2 only. `init` picks AVX2+FMA or AVX-512F by
+//! what [`crate::kernel::cpu`] reports, turns on XSAVE and the matching
+//! XCR0 state components, and the kernels here are compiled per function
+//! with `target_feature`. XCR0 is per CPU, so each application processor
+//! sets it with `init_cpu` as it starts.
+//!
+//! The scheduler does not switch extended register state, so vector code
+//! only runs inside an `FpuGuard`: it XSAVEs whatever state the CPU holds
+//! into a per-CPU area and XRSTORs it when dropped. A second guard on the
+//! same CPU (from an interrupt or a nested call) gets the scalar level
+//! instead of clobbering the first one's save area.
+
+use core::arch::asm;
+use core::arch::x86_64::*;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+
+use crate::kernel::cpu::{self, Feature};
+use crate::kernel::percpu::{self, MAX_CPUS};
+
+/// Widest vector instructions the kernels may use
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Scalar,
+    /// AVX2 with FMA
+    Avx2,
+    Avx512,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Scalar as u8);
+/// Bytes of XSAVE area for the enabled state components
+static SAVE_SIZE: AtomicU32 = AtomicU32::new(0);
+static SAVE_AREAS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+static IN_USE: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// State components to enable and the level they allow, if the CPU has
+/// the vector extensions
+fn select() -> Option<(XCr0Flags, Level)> {
+    let wanted = [Feature::Xsave, Feature::Avx, Feature::Fma, Feature::Avx2];
+    if !wanted.into_iter().all(cpu::has) || __get_cpuid_max(0).0 < 0xD {
+        return None;
+    }
+    let avx512 = cpu::has(Feature::Avx512);
+
+    // State components the CPU can save
+    let supported = __cpuid_count(0xD, 0).eax as u64;
+    let zmm = XCr0Flags::OPMASK | XCr0Flags::ZMM_HI256 | XCr0Flags::HI16_ZMM;
+    let mut flags = XCr0Flags::X87 | XCr0Flags::SSE | XCr0Flags::AVX;
+    let level = if avx512 && supported & zmm.bits() == zmm.bits() {
+        flags |= zmm;
+        Level::Avx512
+    } else {
+        Level::Avx2
+    };
+    Some((flags, level))
+}
+
+/// Enable XSAVE and the selected state components on this CPU
+pub fn init_cpu() {
+    let Some((flags, _)) = select() else {
+        return;
+    };
+    unsafe {
+        Cr4::update(|cr4| cr4.insert(Cr4Flags::OSXSAVE));
+        XCr0::write(flags);
+    }
+}
+
+/// Detect vector extensions and enable the state they need
+pub fn init() {
+    let Some((_, level)) = select() else {
+        return;
+    };
+    init_cpu();
+    // EBX reports the area size for the components now enabled
+    SAVE_SIZE.store(__cpuid_count(0xD, 0).ebx, Ordering::Relaxed);
+    LEVEL.store(level as u8, Ordering::Release);
+}
+
+/// Vector level detected at boot
+pub fn level() -> Level {
+    match LEVEL.load(Ordering::Acquire) {
+        2 => Level::Avx512,
+        1 => Level::Avx2,
+        _ => Level::Scalar,
+    }
+}
+
+/// Save area of `cpu`, allocated on first use
+fn save_area(cpu: usize) -> Option<u64> {
+    let area = SAVE_AREAS[cpu].load(Ordering::Relaxed);
+    if area != 0 {
+        return Some(area);
+    }
+    let size = SAVE_SIZE.load(Ordering::Relaxed) as usize;
+    // Page aligned, which covers XSAVE's 64-byte requirement
+    let base = crate::kernel::memory::allocate_region(size).ok()?.as_u64();
+    SAVE_AREAS[cpu].store(base, Ordering::Relaxed);
+    Some(base)
+}
+
+/// Extended register state saved for the duration of vector code
+pub struct FpuGuard {
+    cpu: usize,
+    level: Level,
+    area: u64,
+    /// Must be dropped on the CPU it was taken on
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Save this CPU's extended state so vector kernels can run
+pub fn begin() -> FpuGuard {
+    let cpu = percpu::current_cpu_id() as usize;
+    let scalar = FpuGuard {
+        cpu,
+        level: Level::Scalar,
+        area: 0,
+        _not_send: PhantomData,
+    };
+    let level = level();
+    if level == Level::Scalar || IN_USE[cpu].swap(true, Ordering::Acquire) {
+        return scalar;
+    }
+    let Some(area) = save_area(cpu) else {
+        IN_USE[cpu].store(false, Ordering::Release);
+        return scalar;
+    };
+    unsafe {
+        asm!("xsave64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+    }
+    FpuGuard {
+        cpu,
+        level,
+        area,
+        _not_send: PhantomData,
+    }
+}
+
+impl FpuGuard {
+    /// Token for running kernels at this guard's level
+    pub fn vector(&self) -> Vector<'_> {
+        Vector {
+            level: self.level,
+            _guard: PhantomData,
+        }
+    }
+}
+
+impl Drop for FpuGuard {
+    fn drop(&mut self) {
+        if self.level == Level::Scalar {
+            return;
+        }
+        unsafe {
+            asm!(
+                "xrstor64 [{}]",
+                in(reg) self.area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                out("xmm0") _, out("xmm1") _, out("xmm2") _, out("xmm3") _,
+                out("xmm4") _, out("xmm5") _, out("xmm6") _, out("xmm7") _,
+                out("xmm8") _, out("xmm9") _, out("xmm10") _, out("xmm11") _,
+                out("xmm12") _, out("xmm13") _, out("xmm14") _, out("xmm15") _,
+                options(nostack),
+            );
+        }
+        IN_USE[self.cpu].store(false, Ordering::Release);
+    }
+}
+
+/// Which kernels to run; vector levels only exist while a guard is held
+#[derive(Clone, Copy)]
+pub struct Vector<'a> {
+    level: Level,
+    _guard: PhantomData<&'a FpuGuard>,
+}
+
+impl Vector<'static> {
+    /// Plain SSE2 code, usable anywhere
+    pub const SCALAR: Self = Vector {
+        level: Level::Scalar,
+        _guard: PhantomData,
+    };
+}
+
+impl Vector<'_> {
+    pub fn level(&self) -> Level {
+        self.level
+    }
+}
+
+#[target_feature(enable = "avx2,fma")]
+unsafe fn hsum_avx2(v: __m256) -> f32 {
+    let sum = _mm_add_ps(_mm256_castps256_ps128(v), _mm256_extractf128_ps(v, 1));
+    let sum = _mm_add_ps(sum, _mm_movehl_ps(sum, sum));
+    let sum = _mm_add_ss(sum, _mm_movehdup_ps(sum));
+    _mm_cvtss_f32(sum)
+}
+
+#[target_feature(enable = "avx2,fma")]
+unsafe fn hmax_avx2(v: __m256) -> f32 {
+    let max = _mm_max_ps(_mm256_castps256_ps128(v), _mm256_extractf128_ps(v, 1));
+    let max = _mm_max_ps(max, _mm_movehl_ps(max, max));
+    let max = _mm_max_ss(max, _mm_movehdup_ps(max));
+    _mm_cvtss_f32(max)
+}
+
+/// Dot product of two equal-length vectors
+#[target_feature(enable = "avx2,fma")]
+pub unsafe fn dot_f32_avx2(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len().min(b.len());
+    let (mut acc0, mut acc1) = (_mm256_setzero_ps(), _mm256_setzero_ps());
+    let mut i = 0;
+    while i + 16 <= n {
+        let (pa, pb) = (a.as_ptr().add(i), b.as_ptr().add(i));
+        acc0 = _mm256_fmadd_ps(_mm256_loadu_ps(pa), _mm256_loadu_ps(pb), acc0);
+        acc1 = _mm256_fmadd_ps(_mm256_loadu_ps(pa.add(8)), _mm256_loadu_ps(pb.add(8)), acc1);
+        i += 16;
+    }
+    let mut sum = hsum_avx2(_mm256_add_ps(acc0, acc1));
+    while i < n {
+        sum += a[i] * b[i];
+        i += 1;
+    }
+    sum
+}
+
+#[target_feature(enable = "avx512f")]
+pub unsafe fn dot_f32_avx512(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len().min(b.len());
+    let (mut acc0, mut acc1) = (_mm512_setzero_ps(), _mm512_setzero_ps());
+    let mut i = 0;
+    while i + 32 <= n {
+        let (pa, pb) = (a.as_ptr().add(i), b.as_ptr().add(i));
+        acc0 = _mm512_fmadd_ps(_mm512_loadu_ps(pa), _mm512_loadu_ps(pb), acc0);
+        acc1 = _mm512_fmadd_ps(_mm512_loadu_ps(pa.add(16)), _mm512_loadu_ps(pb.add(16)), acc1);
+        i += 32;
+    }
+    if i < n {
+        // Masked loads read zeros past the end
+        let mask = tail_mask(n, i);
+        acc0 = _mm512_fmadd_ps(
+            _mm512_maskz_loadu_ps(mask, a.as_ptr().add(i)),
+            _mm512_maskz_loadu_ps(mask, b.as_ptr().add(i)),
+            acc0,
+        );
+        i += 16;
+        if i < n {
+            let mask = tail_mask(n, i);
+            acc1 = _mm512_fmadd_ps(
+                _mm512_maskz_loadu_ps(mask, a.as_ptr().add(i)),
+                _mm512_maskz_loadu_ps(mask, b.as_ptr().add(i)),
+                acc1,
+            );
+        }
+    }
+    _mm512_reduce_add_ps(_mm512_add_ps(acc0, acc1))
+}
+
+/// e^x for 8 lanes, the same reduction and polynomial as `math::exp`
+#[target_feature(enable = "avx2,fma")]
+unsafe fn exp_avx2(x: __m256) -> __m256 {
+    let x = _mm256_min_ps(_mm256_max_ps(x, _mm256_set1_ps(-87.0)), _mm256_set1_ps(88.0));
+    let k = _mm256_round_ps(
+        _mm256_mul_ps(x, _mm256_set1_ps(core::f32::consts::LOG2_E)),
+        _MM_FROUND_TO_NEAREST_INT | _MM_FROUND_NO_EXC,
+    );
+    let r = _mm256_fnmadd_ps(k, _mm256_set1_ps(core::f32::consts::LN_2), x);
+    let mut p = _mm256_set1_ps(1.0 / 720.0);
+    for c in [1.0 / 120.0, 1.0 / 24.0, 1.0 / 6.0, 0.5, 1.0, 1.0] {
+        p = _mm256_fmadd_ps(p, r, _mm256_set1_ps(c));
+    }
+    let scale = _mm256_slli_epi32(_mm256_add_epi32(_mm256_cvtps_epi32(k), _mm256_set1_epi32(127)), 23);
+    _mm256_mul_ps(p, _mm256_castsi256_ps(scale))
+}
+
+#[target_feature(enable = "avx2,fma")]
+pub unsafe fn softmax_avx2(x: &mut [f32]) {
+    let n = x.len();
+    let chunks = n / 8 * 8;
+    let mut vmax = _mm256_set1_ps(f32::NEG_INFINITY);
+    for i in (0..chunks).step_by(8) {
+        vmax = _mm256_max_ps(vmax, _mm256_loadu_ps(x.as_ptr().add(i)));
+    }
+    let max = x[chunks..].iter().copied().fold(hmax_avx2(vmax), f32::max);
+
+    let vmax = _mm256_set1_ps(max);
+    let mut vsum = _mm256_setzero_ps();
+    for i in (0..chunks).step_by(8) {
+        let p = x.as_mut_ptr().add(i);
+        let e = exp_avx2(_mm256_sub_ps(_mm256_loadu_ps(p), vmax));
+        _mm256_storeu_ps(p, e);
+        vsum = _mm256_add_ps(vsum, e);
+    }
+    let mut sum = hsum_avx2(vsum);
+    for v in x[chunks..].iter_mut() {
+        *v = super::math::exp(*v - max);
+        sum += *v;
+    }
+
+    if sum > 0.0 {
+        let inv = _mm256_set1_ps(1.0 / sum);
+        for i in (0..chunks).step_by(8) {
+            let p = x.as_mut_ptr().add(i);
+            _mm256_storeu_ps(p, _mm256_mul_ps(_mm256_loadu_ps(p), inv));
+        }
+        for v in x[chunks..].iter_mut() {
+            *v /= sum;
+        }
+    }
+}
+
+#[target_feature(enable = "avx2,fma")]
+pub unsafe fn layernorm_avx2(x: &mut [f32], gamma: &[f32], beta: Option<&[f32]>, eps: f32) {
+    let n = x.len();
+    let chunks = n / 8 * 8;
+    let mut vsum = _mm256_setzero_ps();
+    for i in (0..chunks).step_by(8) {
+        vsum = _mm256_add_ps(vsum, _mm256_loadu_ps(x.as_ptr().add(i)));
+    }
+    let mean = (hsum_avx2(vsum) + x[chunks..].iter().sum::<f32>()) / n as f32;
+
+    let vmean = _mm256_set1_ps(mean);
+    let mut vvar = _mm256_setzero_ps();
+    for i in (0..chunks).step_by(8) {
+        let d = _mm256_sub_ps(_mm256_loadu_ps(x.as_ptr().add(i)), vmean);
+        vvar = _mm256_fmadd_ps(d, d, vvar);
+    }
+    let tail = x[chunks..].iter().map(|v| (v - mean) * (v - mean)).sum::<f32>();
+    let inv = 1.0 / super::math::sqrt((hsum_avx2(vvar) + tail) / n as f32 + eps);
+
+    let vinv = _mm256_set1_ps(inv);
+    for i in (0..chunks).step_by(8) {
+        let p = x.as_mut_ptr().add(i);
+        let norm = _mm256_mul_ps(_mm256_sub_ps(_mm256_loadu_ps(p), vmean), vinv);
+        let shift = match beta {
+            Some(b) => _mm256_loadu_ps(b.as_ptr().add(i)),
+            None => _mm256_setzero_ps(),
+        };
+        _mm256_storeu_ps(p, _mm256_fmadd_ps(norm, _mm256_loadu_ps(gamma.as_ptr().add(i)), shift));
+    }
+    for i in chunks..n {
+        x[i] = (x[i] - mean) * inv * gamma[i] + beta.map_or(0.0, |b| b[i]);
+    }
+}
+
+/// e^x for 16 lanes
+#[target_feature(enable = "avx512f")]
+unsafe fn exp_avx512(x: __m512) -> __m512 {
+    let x = _mm512_min_ps(_mm512_max_ps(x, _mm512_set1_ps(-87.0)), _mm512_set1_ps(88.0));
+    let k = _mm512_roundscale_ps(
+        _mm512_mul_ps(x, _mm512_set1_ps(core::f32::consts::LOG2_E)),
+        _MM_FROUND_TO_NEAREST_INT | _MM_FROUND_NO_EXC,
+    );
+    let r = _mm512_fnmadd_ps(k, _mm512_set1_ps(core::f32::consts::LN_2), x);
+    let mut p = _mm512_set1_ps(1.0 / 720.0);
+    for c in [1.0 / 120.0, 1.0 / 24.0, 1.0 / 6.0, 0.5, 1.0, 1.0] {
+        p = _mm512_fmadd_ps(p, r, _mm512_set1_ps(c));
+    }
+    _mm512_scalef_ps(p, k)
+}
+
+/// Lanes of the 16-wide chunk at `i` that lie inside `n`
+fn tail_mask(n: usize, i: usize) -> u16 {
+    if n - i >= 16 {
+        0xFFFF
+    } else {
+        (1u16 << (n - i)) - 1
+    }
+}
+
+#[target_feature(enable = "avx512f")]
+pub unsafe fn softmax_avx512(x: &mut [f32]) {
+    let n = x.len();
+    let mut vmax = _mm512_set1_ps(f32::NEG_INFINITY);
+    for i in (0..n).step_by(16) {
+        let m = tail_mask(n, i);
+        vmax = _mm512_mask_max_ps(vmax, m, vmax, _mm512_maskz_loadu_ps(m, x.as_ptr().add(i)));
+    }
+    let vmax = _mm512_set1_ps(_mm512_reduce_max_ps(vmax));
+
+    let mut vsum = _mm512_setzero_ps();
+    for i in (0..n).step_by(16) {
+        let m = tail_mask(n, i);
+        let p = x.as_mut_ptr().add(i);
+        let e = exp_avx512(_mm512_sub_ps(_mm512_maskz_loadu_ps(m, p), vmax));
+        _mm512_mask_storeu_ps(p, m, e);
+        vsum = _mm512_mask_add_ps(vsum, m, vsum, e);
+    }
+    let sum = _mm512_reduce_add_ps(vsum);
+
+    if sum > 0.0 {
+        let inv = _mm512_set1_ps(1.0 / sum);
+        for i in (0..n).step_by(16) {
+            let m = tail_mask(n, i);
+            let p = x.as_mut_ptr().add(i);
+            _mm512_mask_storeu_ps(p, m, _mm512_mul_ps(_mm512_maskz_loadu_ps(m, p), inv));
+        }
+    }
+}
+
+#[target_feature(enable = "avx512f")]
+pub unsafe fn layernorm_avx512(x: &mut [f32], gamma: &[f32], beta: Option<&[f32]>, eps: f32) {
+    let n = x.len();
+    let mut vsum = _mm512_setzero_ps();
+    for i in (0..n).step_by(16) {
+        vsum = _mm512_add_ps(vsum, _mm512_maskz_loadu_ps(tail_mask(n, i), x.as_ptr().add(i)));
+    }
+    let vmean = _mm512_set1_ps(_mm512_reduce_add_ps(vsum) / n as f32);
+
+    let mut vvar = _mm512_setzero_ps();
+    for i in (0..n).step_by(16) {
+        let m = tail_mask(n, i);
+        let d = _mm512_maskz_sub_ps(m, _mm512_maskz_loadu_ps(m, x.as_ptr().add(i)), vmean);
+        vvar = _mm512_fmadd_ps(d, d, vvar);
+    }
+    let inv = 1.0 / super::math::sqrt(_mm512_reduce_add_ps(vvar) / n as f32 + eps);
+
+    let vinv = _mm512_set1_ps(inv);
+    for i in (0..n).step_by(16) {
+        let m = tail_mask(n, i);
+        let p = x.as_mut_ptr().add(i);
+        let norm = _mm512_mul_ps(_mm512_sub_ps(_mm512_maskz_loadu_ps(m, p), vmean), vinv);
+        let shift = match beta {
+            Some(b) => _mm512_maskz_loadu_ps(m, b.as_ptr().add(i)),
+            None => _mm512_setzero_ps(),
+        };
+        let scaled = _mm512_fmadd_ps(norm, _mm512_maskz_loadu_ps(m, gamma.as_ptr().add(i)), shift);
+        _mm512_mask_storeu_ps(p, m, scaled);
+    }
+}
+
+/// Dot product of a Q8_0 block's 32 values with 32 activations, unscaled
+#[target_feature(enable = "avx2,fma")]
+pub unsafe fn dot_q8_avx2(q: &[u8], x: &[f32]) -> f32 {
+    let mut acc = _mm256_setzero_ps();
+    for i in (0..32).step_by(8) {
+        let bytes = _mm_loadl_epi64(q.as_ptr().add(i) as *const __m128i);
+        let w = _mm256_cvtepi32_ps(_mm256_cvtepi8_epi32(bytes));
+        acc = _mm256_fmadd_ps(w, _mm256_loadu_ps(x.as_ptr().add(i)), acc);
+    }
+    hsum_avx2(acc)
+}
+
+/// Dot product of a Q4_0 block's 16 packed bytes with 32 activations,
+/// unscaled
+#[target_feature(enable = "avx2,fma")]
+pub unsafe fn dot_q4_avx2(q: &[u8], x: &[f32]) -> f32 {
+    let mut acc = _mm256_setzero_ps();
+    let (nibble, offset) = (_mm256_set1_epi32(0x0F), _mm256_set1_epi32(8));
+    for i in (0..16).step_by(8) {
+        let bytes = _mm256_cvtepu8_epi32(_mm_loadl_epi64(q.as_ptr().add(i) as *const __m128i));
+        let lo = _mm256_sub_epi32(_mm256_and_si256(bytes, nibble), offset);
+        let hi = _mm256_sub_epi32(_mm256_srli_epi32(bytes, 4), offset);
+        acc = _mm256_fmadd_ps(_mm256_cvtepi32_ps(lo), _mm256_loadu_ps(x.as_ptr().add(i)), acc);
+        acc = _mm256_fmadd_ps(_mm256_cvtepi32_ps(hi), _mm256_loadu_ps(x.as_ptr().add(16 + i)), acc);
+    }
+    hsum_avx2(acc)
+}
@@ -0,0 +1,243 @@
+//! Automatic tagging
+//!
+//! New TagFS objects are classified from their first bytes and given
+//! derived tags: `type=` for the kind of content, `format=` for a known
+//! binary format and `lang=` for source code and markup. Detection runs in
+//! stages: file signatures first, then a text check, then a small linear
+//! classifier over keyword counts to pick the language.
+//!
+//! The TagFS watcher only queues the object; classification happens from
+//! the AI poll loop, so creating an object never waits on it.
+
+use arrayvec::{ArrayString, ArrayVec};
+use core::fmt::Write;
+use spin::Mutex;
+
+use crate::tagfs::{self, Tag, TagFsError, WatchEvent};
+
+/// Objects waiting to be classified
+const QUEUE_LEN: usize = 64;
+
+/// Objects classified per poll
+const OBJECTS_PER_POLL: usize = 4;
+
+/// Bytes read from the start of an object
+const SAMPLE_LEN: usize = 4096;
+
+/// Lowest language score that is trusted
+const LANG_THRESHOLD: u32 = 6;
+
+/// Known file signatures: offset, magic bytes, kind, format
+const SIGNATURES: &[(usize, &[u8], &str, &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image", "png"),
+    (0, b"\xff\xd8\xff", "image", "jpeg"),
+    (0, b"GIF8", "image", "gif"),
+    (0, b"qoif", "image", "qoi"),
+    (0, b"P6\n", "image", "ppm"),
+    (8, b"WEBP", "image", "webp"),
+    (8, b"WAVE", "audio", "wav"),
+    (0, b"%PDF-", "document", "pdf"),
+    (0, b"\x7fELF", "executable", "elf"),
+    (0, b"GGUF", "model", "gguf"),
+    (0, b"\x72\xb5\x4a\x86", "font", "psf"),
+    (0, b"\x1f\x8b", "archive", "gzip"),
+    (0, b"PK\x03\x04", "archive", "zip"),
+    (0, b"\x28\xb5\x2f\xfd", "archive", "zstd"),
+];
+
+/// Languages the classifier can tell apart
+const LANGS: [&str; 6] = ["rust", "c", "python", "shell", "markdown", "html"];
+
+/// Keyword features: text, language index, weight per occurrence
+const FEATURES: &[(&str, usize, u32)] = &[
+    ("fn ", 0, 2),
+    ("let mut ", 0, 3),
+    ("impl ", 0, 3),
+    ("pub fn ", 0, 3),
+    ("#[", 0, 3),
+    ("&mut ", 0, 3),
+    ("::", 0, 1),
+    ("match ", 0, 1),
+    ("#include", 1, 4),
+    ("#define", 1, 3),
+    ("void ", 1, 2),
+    ("NULL", 1, 2),
+    ("printf(", 1, 2),
+    ("int ", 1, 1),
+    ("def ", 2, 3),
+    ("elif ", 2, 4),
+    ("self.", 2, 2),
+    ("__init__", 2, 4),
+    ("import ", 2, 1),
+    ("None", 2, 1),
+    ("#!/bin/sh", 3, 8),
+    ("#!/bin/bash", 3, 8),
+    ("esac", 3, 4),
+    ("fi\n", 3, 3),
+    ("echo ", 3, 2),
+    ("$(", 3, 2),
+    ("\n# ", 4, 2),
+    ("\n## ", 4, 3),
+    ("```", 4, 3),
+    ("](", 4, 2),
+    ("<!DOCTYPE", 5, 6),
+    ("<html", 5, 6),
+    ("<div", 5, 3),
+    ("</", 5, 1),
+];
+
+/// What the classifier found in an object
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Detection {
+    pub kind: Option<&'static str>,
+    pub format: Option<&'static str>,
+    pub lang: Option<&'static str>,
+}
+
+/// Tagging counters
+#[derive(Clone, Copy, Debug)]
+pub struct TaggerStats {
+    /// Objects given at least one tag
+    pub tagged: u64,
+    /// Objects nothing was detected in, or whose tags did not fit
+    pub unknown: u64,
+    /// Objects that arrived with the queue full
+    pub dropped: u64,
+}
+
+struct Tagger {
+    queue: ArrayVec<u64, QUEUE_LEN>,
+    sample: [u8; SAMPLE_LEN],
+    tagged: u64,
+    unknown: u64,
+    dropped: u64,
+}
+
+static TAGGER: Mutex<Tagger> = Mutex::new(Tagger {
+    queue: ArrayVec::new_const(),
+    sample: [0; SAMPLE_LEN],
+    tagged: 0,
+    unknown: 0,
+    dropped: 0,
+});
+
+/// Queue objects as they are created
+fn watch(object_id: u64, event: WatchEvent) {
+    if event != WatchEvent::Created {
+        return;
+    }
+    let mut tagger = TAGGER.lock();
+    if tagger.queue.try_push(object_id).is_err() {
+        tagger.dropped += 1;
+    }
+}
+
+/// Start watching TagFS
+pub fn init() -> Result<(), TagFsError> {
+    tagfs::tagfs_watch(watch).map(|_| ())
+}
+
+/// Text that is valid UTF-8, allowing a character cut off at the end of
+/// the sample, with no control bytes besides whitespace
+fn is_text(sample: &[u8]) -> bool {
+    let valid = match core::str::from_utf8(sample) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    valid && sample.iter().all(|&b| b >= 0x20 || matches!(b, b'\t' | b'\n' | b'\r' | 0x0c))
+}
+
+fn count(haystack: &[u8], needle: &[u8]) -> u32 {
+    haystack.windows(needle.len()).filter(|w| *w == needle).count() as u32
+}
+
+/// Language with the highest score, if it clears the threshold and is not
+/// tied
+fn detect_lang(text: &[u8]) -> Option<&'static str> {
+    let mut scores = [0u32; LANGS.len()];
+    for &(keyword, lang, weight) in FEATURES {
+        scores[lang] += count(text, keyword.as_bytes()) * weight;
+    }
+    let best = (0..LANGS.len()).max_by_key(|&i| scores[i])?;
+    let tied = (0..LANGS.len()).any(|i| i != best && scores[i] == scores[best]);
+    (scores[best] >= LANG_THRESHOLD && !tied).then_some(LANGS[best])
+}
+
+/// Classify an object from its first bytes
+pub fn classify(sample: &[u8]) -> Detection {
+    for &(offset, magic, kind, format) in SIGNATURES {
+        if sample.get(offset..offset + magic.len()) == Some(magic) {
+            return Detection {
+                kind: Some(kind),
+                format: Some(format),
+                lang: None,
+            };
+        }
+    }
+    if sample.is_empty() || !is_text(sample) {
+        return Detection::default();
+    }
+    match detect_lang(sample) {
+        Some(lang) => Detection {
+            kind: Some("source"),
+            format: None,
+            lang: Some(lang),
+        },
+        None => Detection {
+            kind: Some("text"),
+            format: None,
+            lang: None,
+        },
+    }
+}
+
+/// Attach `key=value`; an object whose tags do not fit keeps the ones
+/// that did
+fn add_tag(object_id: u64, key: &str, value: &str) -> bool {
+    let mut tag = ArrayString::<32>::new();
+    if write!(tag, "{}={}", key, value).is_err() {
+        return false;
+    }
+    tagfs::tagfs_add_tag(object_id, Tag::new(&tag)).is_ok()
+}
+
+fn tag_object(tagger: &mut Tagger, object_id: u64) {
+    let Ok(len) = tagfs::tagfs_read(object_id, 0, &mut tagger.sample) else {
+        // Deleted while queued
+        return;
+    };
+    let found = classify(&tagger.sample[..len]);
+    let mut tagged = false;
+    for (key, value) in [("type", found.kind), ("format", found.format), ("lang", found.lang)] {
+        if let Some(value) = value {
+            tagged |= add_tag(object_id, key, value);
+        }
+    }
+    if tagged {
+        tagger.tagged += 1;
+    } else {
+        tagger.unknown += 1;
+    }
+}
+
+/// Classify queued objects, run from the AI poll loop
+pub fn poll() {
+    for _ in 0..OBJECTS_PER_POLL {
+        let mut tagger = TAGGER.lock();
+        if tagger.queue.is_empty() {
+            return;
+        }
+        let object_id = tagger.queue.remove(0);
+        tag_object(&mut tagger, object_id);
+    }
+}
+
+/// Tagging counters
+pub fn stats() -> TaggerStats {
+    let tagger = TAGGER.lock();
+    TaggerStats {
+        tagged: tagger.tagged,
+        unknown: tagger.unknown,
+        dropped: tagger.dropped,
+    }
+}
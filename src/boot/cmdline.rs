@@ -0,0 +1,128 @@
+//! Kernel command line
+//!
+//! The bootloader's `BootInfo` has no field for a command line, so it is
+//! read at boot from the firmware configuration device QEMU provides:
+//! the `opt/zen-os/cmdline` file (`-fw_cfg name=opt/zen-os/cmdline,string=...`)
+//! or, failing that, the `-append` string. Without either the command line
+//! is empty. Options are whitespace-separated `key=value` pairs or bare
+//! flags.
+
+use arrayvec::ArrayString;
+use spin::Once;
+use x86_64::instructions::port::Port;
+
+/// Longest command line kept; the rest is cut off
+pub const MAX_CMDLINE: usize = 1024;
+
+/// Firmware configuration selector and data ports
+const FW_CFG_SELECTOR: u16 = 0x510;
+const FW_CFG_DATA: u16 = 0x511;
+
+/// Firmware configuration items
+const FW_CFG_SIGNATURE: u16 = 0x00;
+const FW_CFG_CMDLINE_SIZE: u16 = 0x14;
+const FW_CFG_CMDLINE_DATA: u16 = 0x15;
+const FW_CFG_FILE_DIR: u16 = 0x19;
+
+/// File holding the command line in the firmware configuration
+const CMDLINE_FILE: &[u8] = b"opt/zen-os/cmdline";
+
+/// Bytes in the name field of a file directory entry
+const FILE_NAME_LEN: usize = 56;
+
+static CMDLINE: Once<ArrayString<MAX_CMDLINE>> = Once::new();
+
+/// Read the next `out.len()` bytes of the selected item
+fn fw_cfg_next(out: &mut [u8]) {
+    let mut data = Port::<u8>::new(FW_CFG_DATA);
+    for byte in out {
+        *byte = unsafe { data.read() };
+    }
+}
+
+/// Select a firmware configuration item and read its first `out.len()` bytes
+fn fw_cfg_read(item: u16, out: &mut [u8]) {
+    unsafe {
+        Port::<u16>::new(FW_CFG_SELECTOR).write(item);
+    }
+    fw_cfg_next(out);
+}
+
+/// Item and size of the command line, if the firmware has one
+fn fw_cfg_cmdline() -> Option<(u16, usize)> {
+    let mut signature = [0; 4];
+    fw_cfg_read(FW_CFG_SIGNATURE, &mut signature);
+    if &signature != b"QEMU" {
+        return None;
+    }
+
+    let mut count = [0; 4];
+    fw_cfg_read(FW_CFG_FILE_DIR, &mut count);
+    for _ in 0..u32::from_be_bytes(count) {
+        let (mut size, mut select, mut reserved, mut name) = ([0; 4], [0; 2], [0; 2], [0; FILE_NAME_LEN]);
+        fw_cfg_next(&mut size);
+        fw_cfg_next(&mut select);
+        fw_cfg_next(&mut reserved);
+        fw_cfg_next(&mut name);
+        if name.split(|&b| b == 0).next() == Some(CMDLINE_FILE) {
+            return Some((u16::from_be_bytes(select), u32::from_be_bytes(size) as usize));
+        }
+    }
+
+    let mut size = [0; 4];
+    fw_cfg_read(FW_CFG_CMDLINE_SIZE, &mut size);
+    Some((FW_CFG_CMDLINE_DATA, u32::from_le_bytes(size) as usize)).filter(|(_, size)| *size > 0)
+}
+
+/// Read the command line from the firmware configuration
+fn read() -> ArrayString<MAX_CMDLINE> {
+    let mut cmdline = ArrayString::new();
+    let Some((item, size)) = fw_cfg_cmdline() else {
+        return cmdline;
+    };
+    let mut buffer = [0; MAX_CMDLINE];
+    let len = size.min(MAX_CMDLINE);
+    fw_cfg_read(item, &mut buffer[..len]);
+
+    // The -append string ends in a NUL; anything that is not printable
+    // ASCII ends the command line too
+    let end = buffer[..len].iter().position(|b| !(b' '..=b'~').contains(b)).unwrap_or(len);
+    let text = core::str::from_utf8(&buffer[..end]).unwrap_or("");
+    let _ = cmdline.try_push_str(text);
+    cmdline
+}
+
+/// Raw command line
+pub fn raw() -> &'static str {
+    CMDLINE.call_once(read).as_str()
+}
+
+/// Iterate over `(key, value)` pairs; bare flags have an empty value
+pub fn options() -> impl Iterator<Item = (&'static str, &'static str)> {
+    raw().split_whitespace().map(|opt| match opt.split_once('=') {
+        Some((key, value)) => (key, value),
+        None => (opt, ""),
+    })
+}
+
+/// Value of an option, if present
+pub fn get(key: &str) -> Option<&'static str> {
+    options().find(|(k, _)| *k == key).map(|(_, v)| v)
+}
+
+/// Whether a bare flag (or any value for the key) is present
+pub fn flag(key: &str) -> bool {
+    options().any(|(k, _)| k == key)
+}
+
+/// Parse a size option with an optional K/M/G suffix
+pub fn size(key: &str) -> Option<u64> {
+    let value = get(key)?;
+    let (digits, shift) = match value.as_bytes().last()? {
+        b'K' | b'k' => (&value[..value.len() - 1], 10),
+        b'M' | b'm' => (&value[..value.len() - 1], 20),
+        b'G' | b'g' => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
@@ -0,0 +1,83 @@
+//! Firmware framebuffer
+//!
+//! Before exiting boot services a UEFI loader sets a mode through the
+//! Graphics Output Protocol and passes on where the framebuffer it left is,
+//! which the GPU subsystem can scan out from when it has no driver for the
+//! adapter. The bootloader's `BootInfo` has no field for it, so the loader
+//! passes it on the command line as
+//! `fb=<address>,<width>x<height>,<stride>,<format>`: the physical address,
+//! the resolution, the pixels per scan line, and `rgb`, `bgr` or
+//! `mask:<red>:<green>:<blue>` as GOP describes the pixel layout. Without
+//! it there is no firmware framebuffer.
+
+use x86_64::PhysAddr;
+
+/// Pixel layout, as GOP's `EFI_GRAPHICS_PIXEL_FORMAT` describes it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// Red in the lowest byte of each 32-bit pixel
+    Rgb,
+    /// Blue in the lowest byte
+    Bgr,
+    /// Channels where the masks say
+    Bitmask { red: u32, green: u32, blue: u32 },
+    /// No framebuffer, only the protocol's blits
+    BltOnly,
+}
+
+/// The framebuffer the loader set up
+#[derive(Clone, Copy, Debug)]
+pub struct Framebuffer {
+    pub addr: PhysAddr,
+    pub width: u32,
+    pub height: u32,
+    /// Pixels from one scan line to the next
+    pub stride: u32,
+    pub format: PixelFormat,
+}
+
+impl Framebuffer {
+    /// Bytes the framebuffer spans
+    pub fn size(&self) -> usize {
+        self.stride as usize * self.height as usize * 4
+    }
+}
+
+fn number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn format(s: &str) -> Option<PixelFormat> {
+    match s {
+        "rgb" => Some(PixelFormat::Rgb),
+        "bgr" => Some(PixelFormat::Bgr),
+        "blt" => Some(PixelFormat::BltOnly),
+        _ => {
+            let mut masks = s.strip_prefix("mask:")?.split(':').map(number);
+            let mut mask = || Some(masks.next()?? as u32);
+            Some(PixelFormat::Bitmask {
+                red: mask()?,
+                green: mask()?,
+                blue: mask()?,
+            })
+        }
+    }
+}
+
+/// The framebuffer the loader passed on, if any
+pub fn capture() -> Option<Framebuffer> {
+    let mut fields = super::cmdline::get("fb")?.split(',');
+    let addr = number(fields.next()?)?;
+    let (width, height) = fields.next()?.split_once('x')?;
+    let framebuffer = Framebuffer {
+        addr: PhysAddr::try_new(addr).ok()?,
+        width: width.parse().ok()?,
+        height: height.parse().ok()?,
+        stride: fields.next()?.parse().ok()?,
+        format: format(fields.next()?)?,
+    };
+    (framebuffer.stride >= framebuffer.width).then_some(framebuffer)
+}
@@ -0,0 +1,138 @@
+//! Initial ramdisk
+//!
+//! The loader can hand over an archive in memory, so that init, the
+//! services it starts and the default AI model are there before any
+//! storage driver is. The bootloader's `BootInfo` has no field for it, so
+//! the loader passes it on the command line as `initrd=<address>,<size>`,
+//! and the memory it is in is kept from the frame allocator (see
+//! [`crate::kernel::regions`]).
+//!
+//! The archive is a cpio archive in the "newc" format, as
+//! `cpio -o -H newc` writes it: each entry is a 110-byte header of ASCII
+//! hex fields, the path, and the data, each padded to 4 bytes, up to the
+//! entry named `TRAILER!!!`. Every regular file becomes a TagFS object
+//! tagged `boot:<path>` whose data stays in the archive.
+
+use arrayvec::ArrayString;
+use x86_64::PhysAddr;
+
+use crate::tagfs::{self, Tag, TagFsError};
+
+/// Namespace the archive's files are tagged in
+pub const TAG_PREFIX: &str = "boot:";
+
+/// Bytes in an entry header
+const HEADER_SIZE: usize = 110;
+
+/// Name of the entry that ends the archive
+const TRAILER: &[u8] = b"TRAILER!!!";
+
+/// File type bits of an entry's mode, and those of a regular file
+const MODE_TYPE: u32 = 0o170000;
+const MODE_REGULAR: u32 = 0o100000;
+
+/// Initrd errors
+#[derive(Debug)]
+pub enum InitrdError {
+    /// `initrd=` does not give an address and a size
+    BadOption,
+    /// An entry is cut off by the end of the archive
+    Truncated,
+    /// An entry header without the newc magic, or a field that is not hex
+    BadHeader,
+    TagFs(TagFsError),
+}
+
+impl From<TagFsError> for InitrdError {
+    fn from(e: TagFsError) -> Self {
+        InitrdError::TagFs(e)
+    }
+}
+
+fn number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Physical address and size of the archive the loader passed on, if any
+pub fn location() -> Result<Option<(PhysAddr, u64)>, InitrdError> {
+    let Some(option) = super::cmdline::get("initrd") else {
+        return Ok(None);
+    };
+    let (addr, size) = option.split_once(',').ok_or(InitrdError::BadOption)?;
+    let addr = number(addr).and_then(|addr| PhysAddr::try_new(addr).ok());
+    match (addr, number(size)) {
+        (Some(addr), Some(size)) => Ok(Some((addr, size))),
+        _ => Err(InitrdError::BadOption),
+    }
+}
+
+/// The archive the loader passed on, if any
+fn archive() -> Result<Option<&'static [u8]>, InitrdError> {
+    let Some((addr, size)) = location()? else {
+        return Ok(None);
+    };
+    let start = crate::kernel::memory::phys_to_virt(addr).as_ptr::<u8>();
+    Ok(Some(unsafe { core::slice::from_raw_parts(start, size as usize) }))
+}
+
+/// Header field `index`, after the 6-byte magic
+fn field(header: &[u8], index: usize) -> Result<u32, InitrdError> {
+    let digits = &header[6 + index * 8..6 + index * 8 + 8];
+    let digits = core::str::from_utf8(digits).map_err(|_| InitrdError::BadHeader)?;
+    u32::from_str_radix(digits, 16).map_err(|_| InitrdError::BadHeader)
+}
+
+/// Round `offset` up to 4 bytes
+fn pad(offset: usize) -> usize {
+    offset.next_multiple_of(4)
+}
+
+/// Tag each regular file of the initrd, returning how many there were
+pub fn load() -> Result<usize, InitrdError> {
+    let Some(archive) = archive()? else {
+        return Ok(0);
+    };
+    let mut offset = 0;
+    let mut files = 0;
+    loop {
+        let header = archive.get(offset..offset + HEADER_SIZE).ok_or(InitrdError::Truncated)?;
+        // 070702 adds a checksum, which is not checked
+        if &header[..6] != b"070701" && &header[..6] != b"070702" {
+            return Err(InitrdError::BadHeader);
+        }
+        let mode = field(header, 1)?;
+        let size = field(header, 6)? as usize;
+        let name_size = field(header, 11)? as usize;
+
+        let name_start = offset + HEADER_SIZE;
+        // The name's size counts its terminating NUL
+        let name = archive
+            .get(name_start..name_start + name_size.saturating_sub(1))
+            .ok_or(InitrdError::Truncated)?;
+        if name == TRAILER {
+            return Ok(files);
+        }
+        let data_start = pad(name_start + name_size);
+        let data = archive.get(data_start..data_start + size).ok_or(InitrdError::Truncated)?;
+        offset = pad(data_start + size);
+
+        if mode & MODE_TYPE != MODE_REGULAR {
+            continue;
+        }
+        let Ok(path) = core::str::from_utf8(name) else {
+            crate::klog!(Warn, "Initrd file with a name that is not UTF-8 skipped");
+            continue;
+        };
+        let path = path.trim_start_matches("./").trim_start_matches('/');
+        let mut tag = ArrayString::<32>::new();
+        if tag.try_push_str(TAG_PREFIX).and(tag.try_push_str(path)).is_err() {
+            crate::klog!(Warn, "Initrd file {} skipped: its name is too long for a tag", path);
+            continue;
+        }
+        tagfs::tagfs_create_in_memory(&[Tag::new(&tag)], data)?;
+        files += 1;
+    }
+}
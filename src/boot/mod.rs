@@ -2,5 +2,10 @@
 
 pub mod serial;
 pub mod bootloader;
+pub mod cmdline;
+pub mod framebuffer;
+pub mod initrd;
+pub mod post;
+pub mod report;
 
 pub use self::bootloader::*;
@@ -0,0 +1,202 @@
+//! Power-on self tests
+//!
+//! With `post` on the command line, checks of the frame allocator, the
+//! heap, IPC rings, the TagFS index and the timers run once every subsystem
+//! is up, and each passes or fails on built-in assertions. They catch
+//! regressions on real hardware, where no test harness can run. What they
+//! create they remove again, but for a small heap block and a two-page
+//! kernel region, which cannot be given back. The run is a stage of the
+//! boot report, which fails with the number of checks that did.
+
+use crate::ipc::{self, IpcError, MessageHeader, RING_BUFFER_SIZE};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::kernel::{allocator, clocksource, hrtimer, memory};
+use crate::tagfs::{self, Tag};
+
+/// Microseconds the timer gets to tick
+const TIMER_TIMEOUT_US: u64 = 500_000;
+
+/// Nanoseconds until the high-resolution timer's deadline
+const HRTIMER_DELAY_NS: u64 = 1_000_000;
+
+/// Fail the check with `message` unless `condition` holds
+macro_rules! ensure {
+    ($condition:expr, $message:expr) => {
+        if !$condition {
+            return Err($message);
+        }
+    };
+}
+
+struct Check {
+    name: &'static str,
+    run: fn() -> Result<(), &'static str>,
+}
+
+static CHECKS: &[Check] = &[
+    Check { name: "frame allocator", run: frames },
+    Check { name: "heap", run: heap },
+    Check { name: "kernel mappings", run: mappings },
+    Check { name: "IPC ring", run: ipc_ring },
+    Check { name: "TagFS index", run: tagfs_index },
+    Check { name: "timer", run: timer },
+    Check { name: "high-resolution timer", run: hrtimer },
+];
+
+/// Whether the command line asks for the tests
+pub fn enabled() -> bool {
+    super::cmdline::flag("post")
+}
+
+/// Frames come out distinct and aligned, and a freed frame comes back
+fn frames() -> Result<(), &'static str> {
+    let first = memory::allocate_frame().ok_or("no frame")?;
+    let second = memory::allocate_frame().ok_or("no second frame")?;
+    ensure!(first != second, "the same frame twice");
+    ensure!(first.start_address().is_aligned(4096u64), "frame not page-aligned");
+    memory::free_frame(second);
+    let again = memory::allocate_frame().ok_or("no frame after a free")?;
+    ensure!(again == second, "freed frame not reused");
+    memory::free_frame(again);
+    memory::free_frame(first);
+    Ok(())
+}
+
+/// The heap is backed to its end, and an allocation is aligned, counted,
+/// and holds what is written to it
+fn heap() -> Result<(), &'static str> {
+    let (used, size) = allocator::usage();
+    ensure!(size > 0, "no heap");
+    let last = x86_64::VirtAddr::new((allocator::HEAP_START + size - 4096) as u64);
+    ensure!(memory::virt_to_phys(last).is_some(), "heap not mapped");
+    let mut block = alloc::boxed::Box::new([0u64; 64]);
+    ensure!((block.as_ptr() as usize).is_multiple_of(8), "allocation misaligned");
+    ensure!(allocator::usage().0 >= used + 512, "allocation not counted");
+    block.iter_mut().enumerate().for_each(|(i, word)| *word = i as u64);
+    ensure!(block.iter().enumerate().all(|(i, word)| *word == i as u64), "heap memory lost a write");
+    Ok(())
+}
+
+/// A fresh kernel region is zeroed and translates back to its frames
+fn mappings() -> Result<(), &'static str> {
+    let region = memory::allocate_region(2 * 4096).map_err(|_| "region not mapped")?;
+    let bytes = unsafe { core::slice::from_raw_parts_mut(region.as_mut_ptr::<u8>(), 2 * 4096) };
+    ensure!(bytes.iter().all(|byte| *byte == 0), "region not zeroed");
+    bytes[4096] = 0x5a;
+    let phys = memory::virt_to_phys(region + 4096u64).ok_or("region does not translate")?;
+    let direct = memory::phys_to_virt(phys).as_ptr::<u8>();
+    ensure!(unsafe { *direct } == 0x5a, "physical mapping disagrees");
+    Ok(())
+}
+
+/// Messages come out in order with their data, and a full ring refuses
+/// one more
+fn ipc_ring() -> Result<(), &'static str> {
+    let channel = ipc::create_channel().map_err(|_| "no channel")?;
+    let result = exercise_ring(channel);
+    ipc::destroy_channel(channel).map_err(|_| "channel not destroyed")?;
+    result
+}
+
+fn exercise_ring(channel: u64) -> Result<(), &'static str> {
+    let header = |id: u64| MessageHeader {
+        id,
+        sender: 0,
+        receiver: 0,
+        length: 8,
+        msg_type: 0,
+    };
+    // One slot always stays empty
+    let capacity = RING_BUFFER_SIZE as u64 - 1;
+    for id in 0..capacity {
+        ipc::msg_send(channel, header(id), &id.to_le_bytes()).map_err(|_| "send failed")?;
+    }
+    ensure!(
+        matches!(ipc::msg_send(channel, header(capacity), &[0; 8]), Err(IpcError::BufferFull)),
+        "full ring took a message"
+    );
+    let mut data = [0; 8];
+    let (peeked, _) = ipc::msg_peek(channel, &mut data).map_err(|_| "peek failed")?;
+    ensure!(peeked.id == 0, "peek out of order");
+    for id in 0..capacity {
+        let (received, len) = ipc::msg_recv(channel, &mut data).map_err(|_| "receive failed")?;
+        ensure!(received.id == id, "messages out of order");
+        ensure!(len == 8 && data == id.to_le_bytes(), "message data corrupted");
+    }
+    ensure!(matches!(ipc::msg_recv(channel, &mut data), Err(IpcError::BufferEmpty)), "empty ring gave a message");
+    Ok(())
+}
+
+/// Tags find their object, and find nothing once it is deleted
+fn tagfs_index() -> Result<(), &'static str> {
+    static DATA: [u8; 4] = *b"post";
+    let (tag, extra) = (Tag::new("post:check"), Tag::new("post:extra"));
+    ensure!(tagfs::tagfs_query(&tag).is_none(), "test tag already taken");
+    let object = tagfs::tagfs_create_in_memory(&[tag], &DATA).map_err(|_| "object not created")?;
+    let result = (|| {
+        ensure!(tagfs::tagfs_query(&tag) == Some(object), "tag does not find its object");
+        tagfs::tagfs_add_tag(object, extra).map_err(|_| "tag not added")?;
+        ensure!(tagfs::tagfs_query(&extra) == Some(object), "added tag does not find its object");
+        let mut buffer = [0u8; 8];
+        let len = tagfs::tagfs_read(object, 0, &mut buffer).map_err(|_| "object not read")?;
+        ensure!(buffer[..len] == DATA, "object data differs");
+        Ok(())
+    })();
+    tagfs::tagfs_delete(object).map_err(|_| "object not deleted")?;
+    ensure!(tagfs::tagfs_query(&tag).is_none(), "tag outlived its object");
+    ensure!(tagfs::tagfs_query(&extra).is_none(), "added tag outlived its object");
+    result
+}
+
+/// The tick count advances
+fn timer() -> Result<(), &'static str> {
+    ensure!(x86_64::instructions::interrupts::are_enabled(), "interrupts disabled");
+    let (start, deadline) = (crate::scheduler::ticks(), super::report::now() + TIMER_TIMEOUT_US);
+    while crate::scheduler::ticks() == start {
+        ensure!(super::report::now() < deadline, "no timer tick");
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// When the high-resolution timer's callback ran
+static FIRED_AT: AtomicU64 = AtomicU64::new(0);
+
+fn fired(_data: u64) {
+    FIRED_AT.store(clocksource::nanoseconds(), Ordering::Relaxed);
+}
+
+/// A one-shot timer fires, and not before its deadline
+fn hrtimer() -> Result<(), &'static str> {
+    FIRED_AT.store(0, Ordering::Relaxed);
+    let deadline = clocksource::nanoseconds() + HRTIMER_DELAY_NS;
+    let timeout = super::report::now() + TIMER_TIMEOUT_US;
+    hrtimer::start(deadline, fired, 0).map_err(|_| "no timer free")?;
+    while FIRED_AT.load(Ordering::Relaxed) == 0 {
+        ensure!(super::report::now() < timeout, "timer never fired");
+        hrtimer::run();
+        core::hint::spin_loop();
+    }
+    ensure!(FIRED_AT.load(Ordering::Relaxed) >= deadline, "timer fired early");
+    Ok(())
+}
+
+/// Run every check, printing how each went; fails with how many failed
+pub fn run() -> Result<usize, usize> {
+    let mut failed = 0;
+    for check in CHECKS {
+        match (check.run)() {
+            Ok(()) => crate::klog!(Info, "[PASS] {}", check.name),
+            Err(reason) => {
+                crate::klog!(Error, "[FAIL] {}: {}", check.name, reason);
+                failed += 1;
+            }
+        }
+    }
+    crate::klog!(Info, "Self tests: {} passed, {} failed", CHECKS.len() - failed, failed);
+    match failed {
+        0 => Ok(CHECKS.len()),
+        _ => Err(failed),
+    }
+}
@@ -0,0 +1,154 @@
+//! Boot report
+//!
+//! Each stage of `kernel_main` is timed and recorded with whether it
+//! succeeded, so that a slow or failing boot can be looked into afterwards
+//! rather than read off the serial log as it scrolls past. Times come from
+//! the time stamp counter, calibrated against the PIT when the report
+//! starts as no clock source is up yet, in microseconds since then. Once storage is up the report is
+//! written to TagFS as an object tagged [`REPORT_TAG`], a line naming the
+//! processor and its features then one line per stage, and written again
+//! with every stage when the boot completes; a panic prints what there is
+//! of it.
+
+use core::fmt::{Debug, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use arrayvec::{ArrayString, ArrayVec};
+use spin::Mutex;
+
+use crate::tagfs::{self, Tag, TagFsError};
+
+/// Tag of the report in TagFS
+pub const REPORT_TAG: &str = "boot-report";
+
+/// Stages a boot can record
+pub const MAX_STAGES: usize = 32;
+
+/// Bytes of a failed stage's error kept
+pub const MAX_DETAIL: usize = 64;
+
+/// Bytes of the report written to TagFS
+const MAX_REPORT_SIZE: usize = 4096;
+
+/// Whether a stage succeeded
+#[derive(Clone, Debug)]
+pub enum Status {
+    Ok,
+    /// With the error, as `Debug` prints it
+    Failed(ArrayString<MAX_DETAIL>),
+}
+
+/// One stage of the boot
+#[derive(Clone, Debug)]
+pub struct Stage {
+    pub name: &'static str,
+    /// Microseconds from the start of the report
+    pub start: u64,
+    pub duration: u64,
+    pub status: Status,
+}
+
+static STAGES: Mutex<ArrayVec<Stage, MAX_STAGES>> = Mutex::new(ArrayVec::new_const());
+
+/// Counter value the report starts at
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// Counter ticks per microsecond
+static CYCLES_PER_US: AtomicU64 = AtomicU64::new(1);
+
+fn tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Microseconds since the report started
+pub fn now() -> u64 {
+    tsc().saturating_sub(BASE.load(Ordering::Relaxed)) / CYCLES_PER_US.load(Ordering::Relaxed)
+}
+
+/// Calibrate the counter and start the report
+pub fn start() {
+    let frequency = crate::kernel::clocksource::pit_calibrate_tsc();
+    CYCLES_PER_US.store((frequency / 1_000_000).max(1), Ordering::Relaxed);
+    BASE.store(tsc(), Ordering::Relaxed);
+}
+
+fn record(name: &'static str, start: u64, status: Status) {
+    let duration = now() - start;
+    match &status {
+        Status::Ok => crate::klog!(Info, "[OK] {} ({} us)", name, duration),
+        Status::Failed(detail) => crate::klog!(Error, "[FAIL] {}: {}", name, detail),
+    }
+    let stage = Stage { name, start, duration, status };
+    if STAGES.lock().try_push(stage).is_err() {
+        crate::klog!(Warn, "Boot report full; {} not recorded", name);
+    }
+}
+
+/// Run the stage `name`, which fails if `init` returns an error
+pub fn stage<T, E: Debug>(name: &'static str, init: impl FnOnce() -> Result<T, E>) -> Option<T> {
+    let start = now();
+    match init() {
+        Ok(value) => {
+            record(name, start, Status::Ok);
+            Some(value)
+        }
+        Err(e) => {
+            let mut detail = ArrayString::new();
+            // An error too long for the report is cut short
+            let _ = write!(detail, "{:?}", e);
+            record(name, start, Status::Failed(detail));
+            None
+        }
+    }
+}
+
+/// Run the stage `name`, which cannot fail
+pub fn run<T>(name: &'static str, init: impl FnOnce() -> T) -> T {
+    let start = now();
+    let value = init();
+    record(name, start, Status::Ok);
+    value
+}
+
+/// Every stage recorded so far
+pub fn stages() -> ArrayVec<Stage, MAX_STAGES> {
+    STAGES.lock().clone()
+}
+
+/// The report as text: `cpu <vendor> <features...>`, then one
+/// `<start> <duration> <name>: <status>` line per stage
+fn format(stages: &[Stage]) -> ArrayString<MAX_REPORT_SIZE> {
+    use crate::kernel::cpu;
+
+    let mut text = ArrayString::new();
+    let vendor = cpu::vendor();
+    let _ = writeln!(text, "cpu {} {}", core::str::from_utf8(&vendor).unwrap_or("?"), cpu::features());
+    for stage in stages {
+        let _ = match &stage.status {
+            Status::Ok => writeln!(text, "{} {} {}: ok", stage.start, stage.duration, stage.name),
+            Status::Failed(detail) => {
+                writeln!(text, "{} {} {}: failed: {}", stage.start, stage.duration, stage.name, detail)
+            }
+        };
+    }
+    text
+}
+
+/// Write the report to TagFS, in place of the last one written
+pub fn persist() -> Result<u64, TagFsError> {
+    let text = format(&stages());
+    let tag = Tag::new(REPORT_TAG);
+    if let Some(old) = tagfs::tagfs_query(&tag) {
+        tagfs::tagfs_delete(old)?;
+    }
+    tagfs::tagfs_create(&[tag], text.as_bytes())
+}
+
+/// Print the report, for a boot that did not complete
+pub fn dump() {
+    // The panic may have come with the report locked
+    let Some(stages) = STAGES.try_lock() else {
+        return;
+    };
+    crate::serial_println!("Boot stages ({} us since start):", now());
+    crate::serial_print!("{}", format(&stages));
+}
@@ -34,4 +34,5 @@ macro_rules! serial_println {
 pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
     SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+    crate::gpu::console::write_fmt(args);
 }
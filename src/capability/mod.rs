@@ -1,4 +1,11 @@
 //! Capability-based security system
+//!
+//! Tokens are signed with a key drawn from the kernel's random numbers at
+//! boot, and a token whose signature does not match what it grants is not
+//! honoured, so a token cannot be made or widened by writing to it.
+
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
 
 /// Capability token size (32 bytes)
 pub const TOKEN_SIZE: usize = 32;
@@ -18,14 +25,38 @@ pub struct CapabilityToken {
 }
 
 impl CapabilityToken {
-    /// Create a new capability token
-    pub const fn new(process_id: u32, permissions: u64) -> Self {
+    /// Create a new capability token, signed
+    pub fn new(process_id: u32, permissions: u64) -> Self {
         Self {
             signature: [0; TOKEN_SIZE],
             permissions,
             process_id,
             expires_at: u64::MAX,
         }
+        .signed()
+    }
+
+    /// What the signature covers
+    fn signed_bytes(&self) -> [u8; 20] {
+        let mut bytes = [0; 20];
+        bytes[..8].copy_from_slice(&self.permissions.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.process_id.to_le_bytes());
+        bytes[12..].copy_from_slice(&self.expires_at.to_le_bytes());
+        bytes
+    }
+
+    /// The token with its signature set to match its contents
+    pub fn signed(self) -> Self {
+        let signature = crate::kernel::random::keyed_hash(signing_key(), &self.signed_bytes());
+        Self { signature, ..self }
+    }
+
+    /// Whether the signature matches the contents
+    pub fn is_valid(&self) -> bool {
+        let expected = crate::kernel::random::keyed_hash(signing_key(), &self.signed_bytes());
+        // Compare every byte, so the time taken says nothing of where they
+        // differ
+        expected.iter().zip(&self.signature).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
     }
 
     /// Check if token has a specific permission
@@ -36,6 +67,7 @@ impl CapabilityToken {
 
 /// Permission types
 #[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Permission {
     Read = 0,
     Write = 1,
@@ -46,6 +78,68 @@ pub enum Permission {
     FileDelete = 6,
     NetworkAccess = 7,
     GpuAccess = 8,
+    ScreenCapture = 9,
+    AiInference = 10,
+    /// Turn the machine off or reboot it
+    Power = 11,
+    /// Read kernel traces and performance counters
+    Trace = 12,
+}
+
+/// Number of permission types
+pub const PERMISSION_COUNT: usize = 13;
+
+impl Permission {
+    /// Every permission, in bit order
+    pub const ALL: [Permission; PERMISSION_COUNT] = [
+        Permission::Read,
+        Permission::Write,
+        Permission::Execute,
+        Permission::IpcSend,
+        Permission::IpcRecv,
+        Permission::FileCreate,
+        Permission::FileDelete,
+        Permission::NetworkAccess,
+        Permission::GpuAccess,
+        Permission::ScreenCapture,
+        Permission::AiInference,
+        Permission::Power,
+        Permission::Trace,
+    ];
+
+    /// Name used in service manifests and listings
+    pub fn name(self) -> &'static str {
+        match self {
+            Permission::Read => "read",
+            Permission::Write => "write",
+            Permission::Execute => "execute",
+            Permission::IpcSend => "ipc-send",
+            Permission::IpcRecv => "ipc-recv",
+            Permission::FileCreate => "file-create",
+            Permission::FileDelete => "file-delete",
+            Permission::NetworkAccess => "network",
+            Permission::GpuAccess => "gpu",
+            Permission::ScreenCapture => "screen-capture",
+            Permission::AiInference => "ai",
+            Permission::Power => "power",
+            Permission::Trace => "trace",
+        }
+    }
+
+    /// Whether checks of this permission are audited even when allowed
+    pub fn privileged(self) -> bool {
+        matches!(self, Permission::ScreenCapture | Permission::Power | Permission::Trace)
+    }
+
+    /// The permission called `name`
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.name() == name)
+    }
+
+    /// Bit of the permission in a token's bitmap
+    pub fn bit(self) -> u64 {
+        1 << self as u64
+    }
 }
 
 /// Per-process token storage (4 KB page)
@@ -78,41 +172,153 @@ impl ProcessTokenStorage {
     }
 }
 
+/// Process IDs with token storage, so the highest process ID is one less
+pub const MAX_PROCESSES: usize = 1024;
+
+/// Key tokens are signed with, drawn when the first token is made
+static SIGNING_KEY: Once<[u8; 32]> = Once::new();
+
+fn signing_key() -> &'static [u8; 32] {
+    SIGNING_KEY.call_once(|| {
+        let mut key = [0; 32];
+        crate::kernel::random::fill(&mut key);
+        key
+    })
+}
+
+/// Token storage of each process, by process ID
+type TokenTable = [Option<ProcessTokenStorage>; MAX_PROCESSES];
+
 /// Global process token storage
-const MAX_PROCESSES: usize = 1024;
-static mut PROCESS_TOKENS: [Option<ProcessTokenStorage>; MAX_PROCESSES] = [const { None }; MAX_PROCESSES];
+static PROCESS_TOKENS: Mutex<TokenTable> = Mutex::new([const { None }; MAX_PROCESSES]);
+
+/// Run `f` on the token table with interrupts off
+fn with_tokens<R>(f: impl FnOnce(&mut TokenTable) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut PROCESS_TOKENS.lock()))
+}
 
 /// Initialize capability system
 pub fn init() {
     // Create root process token
     let root_token = CapabilityToken::new(0, u64::MAX); // All permissions
-    unsafe {
-        PROCESS_TOKENS[0] = Some(ProcessTokenStorage::new());
-        if let Some(storage) = &mut PROCESS_TOKENS[0] {
-            let _ = storage.add_token(root_token);
+    with_tokens(|tokens| {
+        let storage = tokens[0].insert(ProcessTokenStorage::new());
+        let _ = storage.add_token(root_token);
+    });
+}
+
+/// Give a new process a copy of its parent's tokens
+pub fn inherit_tokens(parent: u32, child: u32) -> Result<(), CapabilityError> {
+    with_tokens(|table| {
+        let tokens = table
+            .get(parent as usize)
+            .and_then(|s| s.as_ref())
+            .map_or([None; TOKENS_PER_PROCESS], |s| s.tokens);
+        let slot = table.get_mut(child as usize).ok_or(CapabilityError::NoTokenStorage)?;
+        let mut storage = ProcessTokenStorage::new();
+        // Re-signing a token that does not check out would make it good
+        for token in tokens.into_iter().flatten().filter(CapabilityToken::is_valid) {
+            storage.add_token(CapabilityToken { process_id: child, ..token }.signed())?;
         }
+        *slot = Some(storage);
+        Ok(())
+    })
+}
+
+/// Every permission a process's tokens grant, if it has token storage
+pub fn permissions(process_id: u32) -> Option<u64> {
+    with_tokens(|table| {
+        let storage = table.get(process_id as usize)?.as_ref()?;
+        let valid = storage.tokens.iter().flatten().filter(|token| token.is_valid());
+        Some(valid.fold(0, |bits, token| bits | token.permissions))
+    })
+}
+
+/// Replace the tokens of a process with one holding `permissions`
+pub fn set_tokens(process_id: u32, permissions: u64) -> Result<(), CapabilityError> {
+    let mut storage = ProcessTokenStorage::new();
+    storage.add_token(CapabilityToken::new(process_id, permissions))?;
+    with_tokens(|table| {
+        *table.get_mut(process_id as usize).ok_or(CapabilityError::NoTokenStorage)? = Some(storage);
+        Ok(())
+    })
+}
+
+/// Drop the tokens of a process that has exited
+pub fn drop_tokens(process_id: u32) {
+    if process_id == 0 {
+        return;
     }
+    with_tokens(|table| {
+        if let Some(slot) = table.get_mut(process_id as usize) {
+            *slot = None;
+        }
+    });
 }
 
-/// Check IPC permission for a process
-pub fn check_ipc_permission(process_id: u32, _channel_id: u64) -> Result<(), CapabilityError> {
-    unsafe {
-        let storage = PROCESS_TOKENS[process_id as usize]
-            .as_ref()
+/// Check that a process holds a token granting `permission`
+///
+/// Denials and checks of privileged permissions for processes other than the
+/// kernel are written to the audit log; routine grants on hot paths like IPC
+/// are not.
+pub fn check_permission(process_id: u32, permission: Permission) -> Result<(), CapabilityError> {
+    let result = find_permission(process_id, permission);
+    if process_id != 0 && (result.is_err() || permission.privileged()) {
+        audit_log(AuditEntry {
+            timestamp: crate::scheduler::ticks(),
+            process_id,
+            action: AUDIT_PERMISSION_CHECK | permission as u32,
+            result: if result.is_ok() { AUDIT_ALLOWED } else { AUDIT_DENIED },
+            signature: [0; 16],
+        });
+    }
+    result
+}
+
+fn find_permission(process_id: u32, permission: Permission) -> Result<(), CapabilityError> {
+    with_tokens(|table| {
+        let storage = table
+            .get(process_id as usize)
+            .and_then(|s| s.as_ref())
             .ok_or(CapabilityError::NoTokenStorage)?;
 
-        for token in &storage.tokens {
-            if let Some(token) = token {
-                if token.has_permission(Permission::IpcSend) {
-                    return Ok(());
-                }
+        for token in storage.tokens.iter().flatten() {
+            if token.has_permission(permission) && token.is_valid() {
+                return Ok(());
             }
         }
-    }
+        Err(CapabilityError::PermissionDenied)
+    })
+}
+
+/// Check IPC permission for a process
+pub fn check_ipc_permission(process_id: u32, _channel_id: u64) -> Result<(), CapabilityError> {
+    check_permission(process_id, Permission::IpcSend)
+}
 
-    Err(CapabilityError::PermissionDenied)
+/// Check that a process may receive from a channel: it holds the receive
+/// permission and the channel was created for it
+pub fn check_ipc_recv_permission(process_id: u32, channel_id: u64) -> Result<(), CapabilityError> {
+    check_permission(process_id, Permission::IpcRecv)?;
+    match crate::ipc::receiver(channel_id) {
+        Ok(receiver) if receiver == process_id => Ok(()),
+        _ => Err(CapabilityError::PermissionDenied),
+    }
 }
 
+/// Audit action for a permission check; the low byte is the permission
+pub const AUDIT_PERMISSION_CHECK: u32 = 0x4341_0000;
+
+/// Audit action for a machine check error; the low byte is the bank
+pub const AUDIT_MACHINE_CHECK: u32 = 0x4D43_0000;
+
+/// Audit results
+pub const AUDIT_ALLOWED: u32 = 0;
+pub const AUDIT_DENIED: u32 = 1;
+/// The hardware corrected the error
+pub const AUDIT_CORRECTED: u32 = 2;
+pub const AUDIT_UNCORRECTED: u32 = 3;
+
 /// Audit log entry
 #[repr(C)]
 #[derive(Clone, Copy)]
@@ -124,23 +330,63 @@ pub struct AuditEntry {
     pub signature: [u8; 16],
 }
 
-/// Circular audit log
+impl AuditEntry {
+    /// Permission checked, for permission check entries
+    pub fn permission(&self) -> Option<u8> {
+        (self.action & !0xff == AUDIT_PERMISSION_CHECK).then_some(self.action as u8)
+    }
+}
+
 const AUDIT_LOG_SIZE: usize = 4096;
-static mut AUDIT_LOG: [AuditEntry; AUDIT_LOG_SIZE] = [AuditEntry {
-    timestamp: 0,
-    process_id: 0,
-    action: 0,
-    result: 0,
-    signature: [0; 16],
-}; AUDIT_LOG_SIZE];
-static mut AUDIT_LOG_INDEX: usize = 0;
+
+/// Circular audit log
+struct AuditLog {
+    entries: [AuditEntry; AUDIT_LOG_SIZE],
+    /// Entries ever logged
+    sequence: u64,
+}
+
+static AUDIT: Mutex<AuditLog> = Mutex::new(AuditLog {
+    entries: [AuditEntry {
+        timestamp: 0,
+        process_id: 0,
+        action: 0,
+        result: 0,
+        signature: [0; 16],
+    }; AUDIT_LOG_SIZE],
+    sequence: 0,
+});
 
 /// Log an audit entry
 pub fn audit_log(entry: AuditEntry) {
-    unsafe {
-        AUDIT_LOG[AUDIT_LOG_INDEX] = entry;
-        AUDIT_LOG_INDEX = (AUDIT_LOG_INDEX + 1) % AUDIT_LOG_SIZE;
-    }
+    interrupts::without_interrupts(|| {
+        let mut log = AUDIT.lock();
+        let index = (log.sequence % AUDIT_LOG_SIZE as u64) as usize;
+        log.entries[index] = entry;
+        log.sequence += 1;
+    });
+}
+
+/// Read audit entries logged since `cursor`, a count of entries already
+/// consumed, and advance it
+///
+/// Returns the number of entries copied to `out` and the number that were
+/// overwritten before they could be read.
+pub fn audit_read(cursor: &mut u64, out: &mut [AuditEntry]) -> (usize, u64) {
+    interrupts::without_interrupts(|| {
+        let log = AUDIT.lock();
+        let sequence = log.sequence;
+        let oldest = sequence.saturating_sub(AUDIT_LOG_SIZE as u64);
+        let lost = oldest.saturating_sub(*cursor);
+        *cursor = (*cursor).max(oldest);
+
+        let count = ((sequence - *cursor) as usize).min(out.len());
+        for (i, slot) in out[..count].iter_mut().enumerate() {
+            *slot = log.entries[((*cursor + i as u64) % AUDIT_LOG_SIZE as u64) as usize];
+        }
+        *cursor += count as u64;
+        (count, lost)
+    })
 }
 
 /// Capability errors
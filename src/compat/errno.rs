@@ -0,0 +1,246 @@
+//! POSIX error numbers
+//!
+//! What the compatibility layer fails with reaches a POSIX program as an
+//! `errno`, numbered as on Linux. Every error type of the kernel maps onto
+//! the number POSIX gives the condition, directly rather than through the
+//! native system call errors, which lump conditions together: a full TagFS
+//! is `ENOSPC` and a read-only device `EROFS`, where the native errors have
+//! only `Exhausted` and `InvalidArgument`. The reverse map gives the native
+//! error closest to a number, for native calls into the compatibility
+//! layer.
+
+use super::pipe::PipeError;
+use super::pty::PtyError;
+use super::vfs::VfsError;
+use crate::capability::CapabilityError;
+use crate::ipc::IpcError;
+use crate::storage::StorageError;
+use crate::tagfs::TagFsError;
+use crate::userspace::futex::FutexError;
+use crate::userspace::handle::HandleError;
+use crate::userspace::syscall::SyscallError;
+use crate::userspace::UserError;
+
+/// A POSIX error number, as Linux numbers it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Errno(pub i64);
+
+impl Errno {
+    pub const EPERM: Errno = Errno(1);
+    pub const ENOENT: Errno = Errno(2);
+    pub const ESRCH: Errno = Errno(3);
+    pub const EINTR: Errno = Errno(4);
+    pub const EIO: Errno = Errno(5);
+    pub const E2BIG: Errno = Errno(7);
+    pub const ENOEXEC: Errno = Errno(8);
+    pub const EBADF: Errno = Errno(9);
+    pub const ECHILD: Errno = Errno(10);
+    pub const EAGAIN: Errno = Errno(11);
+    pub const ENOMEM: Errno = Errno(12);
+    pub const EACCES: Errno = Errno(13);
+    pub const EFAULT: Errno = Errno(14);
+    pub const EBUSY: Errno = Errno(16);
+    pub const EEXIST: Errno = Errno(17);
+    pub const EXDEV: Errno = Errno(18);
+    pub const ENODEV: Errno = Errno(19);
+    pub const ENOTDIR: Errno = Errno(20);
+    pub const EISDIR: Errno = Errno(21);
+    pub const EINVAL: Errno = Errno(22);
+    pub const ENFILE: Errno = Errno(23);
+    pub const EMFILE: Errno = Errno(24);
+    pub const ENOTTY: Errno = Errno(25);
+    pub const EFBIG: Errno = Errno(27);
+    pub const ENOSPC: Errno = Errno(28);
+    pub const ESPIPE: Errno = Errno(29);
+    pub const EROFS: Errno = Errno(30);
+    pub const EPIPE: Errno = Errno(32);
+    pub const ERANGE: Errno = Errno(34);
+    pub const ENAMETOOLONG: Errno = Errno(36);
+    pub const ENOSYS: Errno = Errno(38);
+    pub const ENOTEMPTY: Errno = Errno(39);
+    pub const EBADMSG: Errno = Errno(74);
+    pub const EMSGSIZE: Errno = Errno(90);
+    pub const EPROTONOSUPPORT: Errno = Errno(93);
+    pub const EOPNOTSUPP: Errno = Errno(95);
+    pub const EAFNOSUPPORT: Errno = Errno(97);
+    pub const ETIMEDOUT: Errno = Errno(110);
+}
+
+impl From<SyscallError> for Errno {
+    fn from(e: SyscallError) -> Self {
+        match e {
+            SyscallError::InvalidSyscall => Errno::ENOSYS,
+            SyscallError::BadAddress => Errno::EFAULT,
+            SyscallError::InvalidArgument => Errno::EINVAL,
+            SyscallError::PermissionDenied => Errno::EACCES,
+            SyscallError::NotFound => Errno::ENOENT,
+            SyscallError::OutOfMemory => Errno::ENOMEM,
+            SyscallError::WouldBlock | SyscallError::Exhausted => Errno::EAGAIN,
+            SyscallError::TooLarge => Errno::E2BIG,
+            SyscallError::IoError => Errno::EIO,
+            SyscallError::BadExecutable => Errno::ENOEXEC,
+            SyscallError::NoChild => Errno::ECHILD,
+            SyscallError::BadHandle => Errno::EBADF,
+            SyscallError::BrokenPipe => Errno::EPIPE,
+            SyscallError::TimedOut => Errno::ETIMEDOUT,
+        }
+    }
+}
+
+impl From<Errno> for SyscallError {
+    fn from(e: Errno) -> Self {
+        match e {
+            Errno::ENOSYS => SyscallError::InvalidSyscall,
+            Errno::EFAULT => SyscallError::BadAddress,
+            Errno::EPERM | Errno::EACCES => SyscallError::PermissionDenied,
+            Errno::ENOENT | Errno::ESRCH => SyscallError::NotFound,
+            Errno::ENOMEM => SyscallError::OutOfMemory,
+            Errno::EAGAIN => SyscallError::WouldBlock,
+            Errno::ENFILE | Errno::EMFILE | Errno::ENOSPC => SyscallError::Exhausted,
+            Errno::E2BIG | Errno::EFBIG | Errno::EMSGSIZE => SyscallError::TooLarge,
+            Errno::EIO | Errno::ENODEV => SyscallError::IoError,
+            Errno::ENOEXEC => SyscallError::BadExecutable,
+            Errno::ECHILD => SyscallError::NoChild,
+            Errno::EBADF => SyscallError::BadHandle,
+            Errno::EPIPE => SyscallError::BrokenPipe,
+            Errno::ETIMEDOUT => SyscallError::TimedOut,
+            _ => SyscallError::InvalidArgument,
+        }
+    }
+}
+
+impl From<HandleError> for Errno {
+    fn from(e: HandleError) -> Self {
+        match e {
+            HandleError::BadHandle => Errno::EBADF,
+            HandleError::TooManyHandles | HandleError::TooManyOpen => Errno::EMFILE,
+        }
+    }
+}
+
+impl From<IpcError> for Errno {
+    fn from(e: IpcError) -> Self {
+        match e {
+            IpcError::BufferFull | IpcError::BufferEmpty => Errno::EAGAIN,
+            IpcError::MessageTooLarge => Errno::EMSGSIZE,
+            IpcError::InvalidMessage => Errno::EBADMSG,
+            IpcError::InvalidChannel => Errno::ENOENT,
+            IpcError::TooManyChannels | IpcError::TooManyGrants => Errno::ENFILE,
+            IpcError::PermissionDenied => Errno::EACCES,
+            IpcError::InvalidGrant => Errno::EINVAL,
+            IpcError::OutOfMemory => Errno::ENOMEM,
+            IpcError::NameInUse => Errno::EEXIST,
+        }
+    }
+}
+
+impl From<TagFsError> for Errno {
+    fn from(e: TagFsError) -> Self {
+        match e {
+            TagFsError::ObjectNotFound => Errno::ENOENT,
+            TagFsError::InvalidTag => Errno::EINVAL,
+            // As Linux reports running out of inotify watches
+            TagFsError::HashTableFull | TagFsError::StorageFull | TagFsError::TooManyWatchers => Errno::ENOSPC,
+            TagFsError::IoError => Errno::EIO,
+            TagFsError::DeviceGone => Errno::ENODEV,
+        }
+    }
+}
+
+impl From<StorageError> for Errno {
+    fn from(e: StorageError) -> Self {
+        match e {
+            StorageError::DeviceNotFound | StorageError::DeviceGone => Errno::ENODEV,
+            StorageError::IoError
+            | StorageError::CompressionFailed
+            | StorageError::CorruptData
+            | StorageError::NoInterruptVector
+            | StorageError::DmaMappingFailed => Errno::EIO,
+            StorageError::QueueFull => Errno::EAGAIN,
+            StorageError::InvalidRing
+            | StorageError::InvalidBuffer
+            | StorageError::InvalidQueue
+            | StorageError::OutOfRange => Errno::EINVAL,
+            StorageError::TooManyRings | StorageError::TooManyDevices | StorageError::TooManyQueues => Errno::ENFILE,
+            StorageError::ExtentMapFull | StorageError::NoSpace => Errno::ENOSPC,
+            StorageError::OutOfMemory => Errno::ENOMEM,
+            StorageError::ReadOnly => Errno::EROFS,
+            StorageError::Unsupported => Errno::EOPNOTSUPP,
+        }
+    }
+}
+
+impl From<CapabilityError> for Errno {
+    fn from(e: CapabilityError) -> Self {
+        match e {
+            // A process with no tokens holds no permission
+            CapabilityError::PermissionDenied | CapabilityError::TokenExpired | CapabilityError::NoTokenStorage => {
+                Errno::EACCES
+            }
+            CapabilityError::InvalidToken => Errno::EINVAL,
+            CapabilityError::StorageFull => Errno::ENOSPC,
+        }
+    }
+}
+
+impl From<VfsError> for Errno {
+    fn from(e: VfsError) -> Self {
+        match e {
+            VfsError::NotFound => Errno::ENOENT,
+            VfsError::NotDirectory => Errno::ENOTDIR,
+            VfsError::IsDirectory => Errno::EISDIR,
+            VfsError::NameTooLong => Errno::ENAMETOOLONG,
+            VfsError::Exists => Errno::EEXIST,
+            VfsError::NotEmpty => Errno::ENOTEMPTY,
+            VfsError::ReadOnly => Errno::EROFS,
+            VfsError::TooLarge => Errno::EFBIG,
+            VfsError::TooManyStaged => Errno::ENFILE,
+            // Which makes `mv` fall back to copying
+            VfsError::Unsupported => Errno::EXDEV,
+            VfsError::Invalid => Errno::EINVAL,
+            VfsError::Busy => Errno::EBUSY,
+            VfsError::NoSpace => Errno::ENOSPC,
+            VfsError::Corrupt => Errno::EIO,
+            VfsError::Storage(e) => e.into(),
+            VfsError::Device(e) => e.into(),
+        }
+    }
+}
+
+impl From<PipeError> for Errno {
+    fn from(e: PipeError) -> Self {
+        match e {
+            PipeError::TooManyPipes => Errno::ENFILE,
+            PipeError::Broken => Errno::EPIPE,
+            PipeError::Ipc(e) => e.into(),
+        }
+    }
+}
+
+impl From<PtyError> for Errno {
+    fn from(e: PtyError) -> Self {
+        match e {
+            // As Linux reports running out of pseudo-terminals
+            PtyError::TooManyPtys => Errno::ENOSPC,
+            PtyError::NotFound => Errno::ENOENT,
+            PtyError::Locked | PtyError::HungUp => Errno::EIO,
+        }
+    }
+}
+
+impl From<FutexError> for Errno {
+    fn from(e: FutexError) -> Self {
+        match e {
+            FutexError::Invalid => Errno::EINVAL,
+            FutexError::Changed => Errno::EAGAIN,
+            FutexError::TimedOut => Errno::ETIMEDOUT,
+            FutexError::BadAddress => Errno::EFAULT,
+        }
+    }
+}
+
+impl From<UserError> for Errno {
+    fn from(e: UserError) -> Self {
+        SyscallError::from(e).into()
+    }
+}
@@ -0,0 +1,376 @@
+//! ext2 import
+//!
+//! A disk formatted for Linux is not mounted; its files are copied into
+//! TagFS once, so someone moving to Zen OS brings their data along. The
+//! ext2 file system is read where the device starts or in one of its
+//! partitions (see [`super::partition`]), as is ext3's, whose journal is
+//! ignored. ext4's extents and 64-bit block numbers are not supported.
+//!
+//! Each regular file becomes an object tagged with its path when that fits
+//! in a tag and is not taken, so it shows up at the same place in the
+//! POSIX view (see [`super::vfs`]), and with tags made from it:
+//! `name=` for its name, `dir=` for the directory holding it and
+//! `source=ext2`. The AI tagger adds what it finds in the contents as for
+//! any new object. A file with several links is copied once for each;
+//! symbolic links, devices and sockets are left out.
+
+use core::fmt::Write;
+
+use arrayvec::{ArrayString, ArrayVec};
+use spin::Mutex;
+
+use super::partition;
+use super::vfs::MAX_TAG;
+use crate::storage::{self, StorageError};
+use crate::tagfs::{self, Tag, TagFsError};
+
+/// Largest block size read
+const MAX_BLOCK: usize = 4096;
+
+/// Directories waiting to be read during an import; one found while
+/// this many are waiting is left out
+const MAX_PENDING: usize = 64;
+
+/// Where the superblock is, from the start of the file system
+const SUPERBLOCK: u64 = 1024;
+const MAGIC: u16 = 0xef53;
+
+/// Incompatible features that do not change how files are found: the type
+/// in directory entries, and flexible block groups
+const READABLE_INCOMPAT: u32 = 0x0002 | 0x0200;
+
+const ROOT_INODE: u32 = 2;
+/// Inode size of revision 0 file systems
+const OLD_INODE_SIZE: u64 = 128;
+
+/// File type bits of `i_mode`
+const S_IFMT: u16 = 0xf000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+
+/// Block pointers in an inode: 12 direct, then single, double and triple
+/// indirect
+const DIRECT: u64 = 12;
+
+/// A tag made from a path or name
+type TagText = ArrayString<MAX_TAG>;
+
+/// ext2 errors
+#[derive(Debug)]
+pub enum Ext2Error {
+    /// No ext2 file system on the device
+    NotFound,
+    /// Features this reader does not know
+    Unsupported,
+    /// Structures that point outside the file system or make no sense
+    Corrupt,
+    Device(StorageError),
+    Storage(TagFsError),
+}
+
+impl From<StorageError> for Ext2Error {
+    fn from(e: StorageError) -> Self {
+        Ext2Error::Device(e)
+    }
+}
+
+impl From<TagFsError> for Ext2Error {
+    fn from(e: TagFsError) -> Self {
+        Ext2Error::Storage(e)
+    }
+}
+
+/// What an import did
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Imported {
+    pub files: u32,
+    pub bytes: u64,
+    /// Entries left out: links, devices, names that are not UTF-8, files
+    /// too large for TagFS and directories there was no room to queue
+    pub skipped: u32,
+}
+
+struct Volume {
+    device: u32,
+    /// Where the file system starts on the device
+    start: u64,
+    block_size: u64,
+    blocks: u64,
+    first_data_block: u64,
+    inodes_per_group: u64,
+    groups: u64,
+    inode_size: u64,
+}
+
+/// An inode's type, size and block pointers
+struct Inode {
+    mode: u16,
+    size: u64,
+    blocks: [u32; 15],
+}
+
+/// A directory waiting to be read
+struct Pending {
+    inode: u32,
+    /// Its path, if that fits in a tag
+    path: Option<TagText>,
+    /// Its name, empty for the root or a name too long for a tag
+    name: TagText,
+}
+
+struct Import {
+    pending: ArrayVec<Pending, MAX_PENDING>,
+    block: [u8; MAX_BLOCK],
+}
+
+/// One import runs at a time
+static IMPORT: Mutex<Import> = Mutex::new(Import {
+    pending: ArrayVec::new_const(),
+    block: [0; MAX_BLOCK],
+});
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl Volume {
+    /// Read the superblock of the file system at `start`, if it is ext2
+    fn probe(device: u32, start: u64) -> Result<Option<Volume>, Ext2Error> {
+        let mut sb = [0u8; 1024];
+        storage::read_raw(device, start + SUPERBLOCK, &mut sb)?;
+        if u16_at(&sb, 56) != MAGIC {
+            return Ok(None);
+        }
+        if u32_at(&sb, 96) & !READABLE_INCOMPAT != 0 {
+            return Err(Ext2Error::Unsupported);
+        }
+        let log_block = u32_at(&sb, 24);
+        let inodes_per_group = u32_at(&sb, 40) as u64;
+        let blocks_per_group = u32_at(&sb, 32) as u64;
+        let inode_size = match u32_at(&sb, 76) {
+            0 => OLD_INODE_SIZE,
+            _ => u16_at(&sb, 88) as u64,
+        };
+        if log_block > 2 {
+            return Err(Ext2Error::Unsupported);
+        }
+        if inodes_per_group == 0 || blocks_per_group == 0 || inode_size < OLD_INODE_SIZE {
+            return Err(Ext2Error::Corrupt);
+        }
+        let blocks = u32_at(&sb, 4) as u64;
+        let first_data_block = u32_at(&sb, 20) as u64;
+        Ok(Some(Volume {
+            device,
+            start,
+            block_size: 1024 << log_block,
+            blocks,
+            first_data_block,
+            inodes_per_group,
+            groups: blocks.saturating_sub(first_data_block).div_ceil(blocks_per_group),
+            inode_size,
+        }))
+    }
+
+    fn read(&self, block: u64, within: u64, buffer: &mut [u8]) -> Result<(), Ext2Error> {
+        if block >= self.blocks {
+            return Err(Ext2Error::Corrupt);
+        }
+        storage::read_raw(self.device, self.start + block * self.block_size + within, buffer)?;
+        Ok(())
+    }
+
+    fn inode(&self, number: u32) -> Result<Inode, Ext2Error> {
+        let index = (number as u64).checked_sub(1).ok_or(Ext2Error::Corrupt)?;
+        let group = index / self.inodes_per_group;
+        if group >= self.groups {
+            return Err(Ext2Error::Corrupt);
+        }
+        // The group descriptors follow the superblock's block
+        let mut table = [0u8; 4];
+        self.read(self.first_data_block + 1, group * 32 + 8, &mut table)?;
+        let at = (index % self.inodes_per_group) * self.inode_size;
+        let mut raw = [0u8; 128];
+        let table = u32::from_le_bytes(table) as u64;
+        self.read(table + at / self.block_size, at % self.block_size, &mut raw)?;
+        let mode = u16_at(&raw, 0);
+        let mut size = u32_at(&raw, 4) as u64;
+        if mode & S_IFMT == S_IFREG {
+            size |= (u32_at(&raw, 108) as u64) << 32;
+        }
+        let mut blocks = [0; 15];
+        for (i, block) in blocks.iter_mut().enumerate() {
+            *block = u32_at(&raw, 40 + i * 4);
+        }
+        Ok(Inode { mode, size, blocks })
+    }
+
+    /// The block holding block `index` of a file, 0 for a hole
+    fn block_of(&self, inode: &Inode, index: u64) -> Result<u64, Ext2Error> {
+        if index < DIRECT {
+            return Ok(inode.blocks[index as usize] as u64);
+        }
+        let per_block = self.block_size / 4;
+        let mut index = index - DIRECT;
+        let mut span = 1;
+        for (level, pointer) in inode.blocks[DIRECT as usize..].iter().enumerate() {
+            span *= per_block;
+            if index < span {
+                let mut block = *pointer as u64;
+                let mut below = span;
+                for _ in 0..=level {
+                    if block == 0 {
+                        return Ok(0);
+                    }
+                    below /= per_block;
+                    let mut entry = [0u8; 4];
+                    self.read(block, index / below * 4, &mut entry)?;
+                    block = u32::from_le_bytes(entry) as u64;
+                    index %= below;
+                }
+                return Ok(block);
+            }
+            index -= span;
+        }
+        Err(Ext2Error::Corrupt)
+    }
+
+    /// Read `buffer.len()` bytes of a file from `offset`
+    fn read_file(&self, inode: &Inode, offset: u64, buffer: &mut [u8]) -> Result<(), Ext2Error> {
+        let mut done = 0;
+        while done < buffer.len() {
+            let at = offset + done as u64;
+            let within = at % self.block_size;
+            let len = ((self.block_size - within) as usize).min(buffer.len() - done);
+            let piece = &mut buffer[done..done + len];
+            match self.block_of(inode, at / self.block_size)? {
+                0 => piece.fill(0),
+                block => self.read(block, within, piece)?,
+            }
+            done += len;
+        }
+        Ok(())
+    }
+}
+
+/// Tags for a file at `path` (if that fits in a tag), named `name`, in
+/// the directory named `dir`
+fn tags_for(path: Option<&TagText>, name: &str, dir: &str) -> ArrayVec<Tag, 4> {
+    let mut tags = ArrayVec::new();
+    if let Some(path) = path.filter(|path| tagfs::tagfs_query(&Tag::new(path)).is_none()) {
+        tags.push(Tag::new(path));
+    }
+    for (key, value) in [("name", name), ("dir", dir)] {
+        let mut tag = TagText::new();
+        if !value.is_empty() && write!(tag, "{}={}", key, value).is_ok() {
+            tags.push(Tag::new(&tag));
+        }
+    }
+    tags.push(Tag::new("source=ext2"));
+    tags
+}
+
+/// `dir/name`, if it fits in a tag
+fn join(dir: Option<&TagText>, name: &str) -> Option<TagText> {
+    let dir = dir?;
+    let mut path = TagText::new();
+    if !dir.is_empty() {
+        path.try_push_str(dir).ok()?;
+        path.try_push('/').ok()?;
+    }
+    path.try_push_str(name).ok()?;
+    Some(path)
+}
+
+/// Copy a regular file into a new TagFS object
+fn import_file(volume: &Volume, inode: &Inode, tags: &[Tag]) -> Result<(), Ext2Error> {
+    let mut failed = None;
+    let created = tagfs::tagfs_create_with(tags, inode.size, |offset, buffer| {
+        volume.read_file(inode, offset, buffer).map_err(|e| {
+            failed = Some(e);
+            TagFsError::IoError
+        })
+    });
+    match (created, failed) {
+        (_, Some(e)) => Err(e),
+        (Err(e), None) => Err(e.into()),
+        (Ok(_), None) => Ok(()),
+    }
+}
+
+/// Copy every file of the ext2 file system on `device` into TagFS
+pub fn import(device: u32) -> Result<Imported, Ext2Error> {
+    let mut volume = None;
+    for start in partition::starts(device)? {
+        if let Some(found) = Volume::probe(device, start)? {
+            volume = Some(found);
+            break;
+        }
+    }
+    let volume = volume.ok_or(Ext2Error::NotFound)?;
+
+    let mut import = IMPORT.lock();
+    let Import { pending, block } = &mut *import;
+    let block = &mut block[..volume.block_size as usize];
+    let mut imported = Imported::default();
+    pending.clear();
+    pending.push(Pending {
+        inode: ROOT_INODE,
+        path: Some(TagText::new()),
+        name: TagText::new(),
+    });
+    while let Some(Pending { inode, path, name: dir_name }) = pending.pop() {
+        let dir = volume.inode(inode)?;
+        if dir.mode & S_IFMT != S_IFDIR {
+            return Err(Ext2Error::Corrupt);
+        }
+        for index in 0..dir.size.div_ceil(volume.block_size) {
+            match volume.block_of(&dir, index)? {
+                0 => continue,
+                number => volume.read(number, 0, block)?,
+            }
+            let mut at = 0;
+            while at + 8 <= block.len() {
+                let entry = &block[at..];
+                let number = u32_at(entry, 0);
+                let len = u16_at(entry, 4) as usize;
+                let name_len = entry[6] as usize;
+                if len < 8 || at + len > block.len() || 8 + name_len > len {
+                    return Err(Ext2Error::Corrupt);
+                }
+                at += len;
+                // Deleted entries have inode 0
+                if number == 0 {
+                    continue;
+                }
+                let Ok(name) = core::str::from_utf8(&entry[8..8 + name_len]) else {
+                    imported.skipped += 1;
+                    continue;
+                };
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let inode = volume.inode(number)?;
+                let child = join(path.as_ref(), name);
+                match inode.mode & S_IFMT {
+                    S_IFDIR if !pending.is_full() => pending.push(Pending {
+                        inode: number,
+                        path: child,
+                        name: TagText::from(name).unwrap_or_default(),
+                    }),
+                    S_IFREG if inode.size <= u32::MAX as u64 => {
+                        let tags = tags_for(child.as_ref(), name, &dir_name);
+                        import_file(&volume, &inode, &tags)?;
+                        imported.files += 1;
+                        imported.bytes += inode.size;
+                    }
+                    _ => imported.skipped += 1,
+                }
+            }
+        }
+    }
+    Ok(imported)
+}
@@ -0,0 +1,974 @@
+//! FAT32 volumes
+//!
+//! USB sticks and EFI system partitions are formatted FAT32, so files are
+//! exchanged with other systems through it. A volume is found at the start
+//! of a device or in one of its partitions (see [`super::partition`]), and
+//! the VFS mounts it under `/mnt` (see [`super::vfs`]). Sectors are read and written raw, past the compression
+//! TagFS goes through, so the volume stays readable elsewhere.
+//!
+//! Long names are read and written: a name that is not already a valid
+//! 8.3 one gets long-name entries and a generated alias such as
+//! `README~1.TXT`. Names compare without regard to ASCII case, as FAT's
+//! do. There is no wall clock, so new entries are dated 1 January 1980,
+//! and the free cluster count in FSInfo is marked unknown on the first
+//! change, for the next system that mounts the volume to recount.
+//!
+//! An open file is known by where its directory entry lies, so a handle
+//! to a file that is renamed or removed no longer reaches it.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use arrayvec::{ArrayString, ArrayVec};
+use spin::Mutex;
+
+use super::partition;
+use super::vfs::{Path, VfsError};
+use crate::storage::{self, StorageError};
+
+/// Volumes mounted at once
+pub const MAX_VOLUMES: usize = 4;
+
+/// Longest name, in UTF-16 code units
+const MAX_NAME: usize = 255;
+
+/// Bytes of the boot sector read
+const SECTOR: usize = 512;
+/// Largest logical sector a volume may have
+const MAX_SECTOR: usize = 4096;
+
+const ENTRY_SIZE: u64 = 32;
+
+/// Entry attributes
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// First byte of a deleted entry; 0 ends the directory
+const DELETED: u8 = 0xe5;
+/// Order bit of the last long-name entry of a name, stored first
+const LAST_LONG: u8 = 0x40;
+/// Flags in byte 12 of a short entry marking its base or extension lower
+/// case
+const LOWER_BASE: u8 = 0x08;
+const LOWER_EXT: u8 = 0x10;
+
+/// UTF-16 code units in one long-name entry, and where they lie
+const LONG_CHARS: usize = 13;
+const LONG_OFFSETS: [usize; LONG_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// Long-name entries one name may take
+const MAX_LONG: usize = MAX_NAME.div_ceil(LONG_CHARS);
+
+/// Bytes a short name may hold besides letters and digits
+const SHORT_SPECIAL: &[u8] = b"!#$%&'()-@^_`{}~";
+
+/// FAT entries are 28 bits; the rest are reserved
+const FAT_MASK: u32 = 0x0fff_ffff;
+/// Entries from here on end a chain
+const END_OF_CHAIN: u32 = 0x0fff_fff8;
+/// Fewer clusters than this make a FAT12 or FAT16 volume
+const MIN_CLUSTERS: u64 = 65525;
+
+/// 1 January 1980, the FAT epoch
+const DATE: u16 = (1 << 5) | 1;
+
+/// FSInfo signatures, and where its free cluster count is
+const FS_INFO_LEAD: u32 = 0x4161_5252;
+const FS_INFO_STRUCT: u32 = 0x6141_7272;
+const FS_INFO_FREE: u64 = 488;
+
+/// What a path names on a volume
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Node {
+    Directory,
+    /// A file, by where its short entry lies on the device
+    File(u64),
+}
+
+#[derive(Clone, Copy)]
+struct Volume {
+    id: u32,
+    device: u32,
+    read_only: bool,
+    sector_size: u64,
+    cluster_size: u64,
+    /// Where the first FAT is on the device, each FAT's size and how many
+    /// there are
+    fat_start: u64,
+    fat_size: u64,
+    fats: u64,
+    /// Where cluster 2 is on the device
+    data_start: u64,
+    /// Highest cluster number
+    last_cluster: u32,
+    root: u32,
+    /// Where the FSInfo sector is, if the volume has a valid one
+    fs_info: Option<u64>,
+    /// Where to look for a free cluster first
+    next_free: u32,
+}
+
+/// A directory entry
+#[derive(Clone)]
+struct Entry {
+    name: Path,
+    /// Where its short entry is on the device, and its long-name ones
+    at: u64,
+    long: [u64; MAX_LONG],
+    longs: usize,
+    attr: u8,
+    cluster: u32,
+    size: u32,
+}
+
+impl Entry {
+    fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+}
+
+/// Long-name entries seen ahead of a short one
+struct LongName {
+    units: [u16; MAX_LONG * LONG_CHARS],
+    at: [u64; MAX_LONG],
+    /// Entries seen, and how many the name has; 0 when none is under way
+    count: usize,
+    expected: usize,
+    checksum: u8,
+}
+
+static VOLUMES: Mutex<[Option<Volume>; MAX_VOLUMES]> = Mutex::new([None; MAX_VOLUMES]);
+
+/// Number the next mounted volume gets; never reused, so a handle to a
+/// file on a volume that is gone cannot reach another one
+static NEXT_VOLUME: AtomicU32 = AtomicU32::new(1);
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Checksum of a short name, which its long-name entries carry
+fn checksum(short: &[u8]) -> u8 {
+    short[..11].iter().fold(0u8, |sum, b| sum.rotate_right(1).wrapping_add(*b))
+}
+
+fn trim(bytes: &[u8]) -> &[u8] {
+    &bytes[..bytes.iter().rposition(|b| *b != b' ').map_or(0, |i| i + 1)]
+}
+
+/// The name a short entry spells, lower case where it is marked so
+fn short_name(slot: &[u8; 32]) -> Path {
+    let char_of = |b: u8, lower: bool| match b {
+        b if b.is_ascii() && lower => b.to_ascii_lowercase() as char,
+        b if b.is_ascii() => b as char,
+        _ => '_',
+    };
+    let mut name = Path::new();
+    for (i, b) in trim(&slot[..8]).iter().enumerate() {
+        // A leading 0x05 stands for 0xe5, which marks deleted entries
+        let b = if i == 0 && *b == 0x05 { DELETED } else { *b };
+        name.push(char_of(b, slot[12] & LOWER_BASE != 0));
+    }
+    let ext = trim(&slot[8..11]);
+    if !ext.is_empty() {
+        name.push('.');
+        for b in ext {
+            name.push(char_of(*b, slot[12] & LOWER_EXT != 0));
+        }
+    }
+    name
+}
+
+fn is_short_char(b: u8) -> bool {
+    b.is_ascii_uppercase() || b.is_ascii_digit() || SHORT_SPECIAL.contains(&b)
+}
+
+/// `name` as a short entry name, if it is a valid 8.3 name as it is
+fn short_form(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || ext.contains('.') {
+        return None;
+    }
+    if !base.bytes().chain(ext.bytes()).all(is_short_char) {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(short)
+}
+
+/// A character of a long name as it goes into a short alias
+fn alias_char(c: char) -> Option<u8> {
+    match c {
+        ' ' | '.' => None,
+        c if c.is_ascii() && is_short_char(c.to_ascii_uppercase() as u8) => Some(c.to_ascii_uppercase() as u8),
+        _ => Some(b'_'),
+    }
+}
+
+/// Whether a FAT entry can hold `name`
+fn check_name(name: &str) -> Result<(), VfsError> {
+    if name.encode_utf16().count() > MAX_NAME {
+        return Err(VfsError::NameTooLong);
+    }
+    let reserved = name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c));
+    if reserved || name.ends_with(['.', ' ']) {
+        return Err(VfsError::Invalid);
+    }
+    Ok(())
+}
+
+fn short_entry(short: &[u8; 11], attr: u8, cluster: u32, size: u32) -> [u8; 32] {
+    let mut slot = [0u8; 32];
+    slot[..11].copy_from_slice(short);
+    slot[11] = attr;
+    for offset in [16, 18, 24] {
+        slot[offset..offset + 2].copy_from_slice(&DATE.to_le_bytes());
+    }
+    slot[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    slot[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    slot[28..32].copy_from_slice(&size.to_le_bytes());
+    slot
+}
+
+/// The directory holding `path` and the name in it
+fn split(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+impl LongName {
+    fn new() -> Self {
+        Self {
+            units: [0; MAX_LONG * LONG_CHARS],
+            at: [0; MAX_LONG],
+            count: 0,
+            expected: 0,
+            checksum: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.count = 0;
+        self.expected = 0;
+    }
+
+    /// Take the long-name entry at `at`; the entries of a name come last
+    /// part first
+    fn add(&mut self, at: u64, slot: &[u8; 32]) {
+        let order = (slot[0] & 0x1f) as usize;
+        if slot[0] & LAST_LONG != 0 {
+            self.clear();
+            if order == 0 || order > MAX_LONG {
+                return;
+            }
+            self.expected = order;
+            self.checksum = slot[13];
+            self.units.fill(0xffff);
+        }
+        if self.expected == 0 || order != self.expected - self.count || slot[13] != self.checksum {
+            self.clear();
+            return;
+        }
+        for (i, offset) in LONG_OFFSETS.iter().enumerate() {
+            self.units[(order - 1) * LONG_CHARS + i] = u16_at(slot, *offset);
+        }
+        self.at[self.count] = at;
+        self.count += 1;
+    }
+
+    /// The name the entries spell, if they belong to the short entry
+    /// `slot`
+    fn name(&self, slot: &[u8; 32]) -> Option<Path> {
+        if self.expected == 0 || self.count != self.expected || checksum(slot) != self.checksum {
+            return None;
+        }
+        let units = self.units[..self.count * LONG_CHARS].iter().copied().take_while(|u| *u != 0 && *u != 0xffff);
+        let mut name = Path::new();
+        for c in char::decode_utf16(units) {
+            name.try_push(c.ok()?).ok()?;
+        }
+        Some(name)
+    }
+
+    /// The entry for the short entry at `at`, named by the long-name
+    /// entries before it if they belong to it
+    fn entry(&mut self, at: u64, slot: &[u8; 32]) -> Entry {
+        let (name, longs) = match self.name(slot) {
+            Some(name) => (name, self.count),
+            None => (short_name(slot), 0),
+        };
+        let mut long = [0; MAX_LONG];
+        long[..longs].copy_from_slice(&self.at[..longs]);
+        self.clear();
+        Entry {
+            name,
+            at,
+            long,
+            longs,
+            attr: slot[11],
+            cluster: (u16_at(slot, 20) as u32) << 16 | u16_at(slot, 26) as u32,
+            size: u32_at(slot, 28),
+        }
+    }
+}
+
+impl Volume {
+    /// Read the boot sector at `start` on `device`, if it is a FAT32 one
+    fn probe(device: u32, start: u64) -> Result<Option<Volume>, VfsError> {
+        let mut boot = [0u8; SECTOR];
+        storage::read_raw(device, start, &mut boot)?;
+        if boot[510..] != [0x55, 0xaa] {
+            return Ok(None);
+        }
+        let sector_size = u16_at(&boot, 11) as u64;
+        let per_cluster = boot[13] as u64;
+        let reserved = u16_at(&boot, 14) as u64;
+        let fats = boot[16] as u64;
+        let total = match u16_at(&boot, 19) {
+            0 => u32_at(&boot, 32) as u64,
+            sectors => sectors as u64,
+        };
+        let fat_sectors = u32_at(&boot, 36) as u64;
+        // FAT12 and FAT16 have a fixed root directory and a 16-bit FAT size
+        let valid = sector_size.is_power_of_two()
+            && (SECTOR as u64..=MAX_SECTOR as u64).contains(&sector_size)
+            && per_cluster.is_power_of_two()
+            && reserved > 0
+            && fats > 0
+            && fat_sectors > 0
+            && u16_at(&boot, 17) == 0
+            && u16_at(&boot, 22) == 0;
+        if !valid {
+            return Ok(None);
+        }
+        let data = reserved + fats * fat_sectors;
+        let Some(clusters) = total.checked_sub(data).map(|sectors| sectors / per_cluster) else {
+            return Ok(None);
+        };
+        // The FAT has to have room for every cluster
+        let fits = fat_sectors * sector_size / 4 >= clusters + 2;
+        if !(MIN_CLUSTERS..END_OF_CHAIN as u64 - 2).contains(&clusters) || !fits {
+            return Ok(None);
+        }
+        let volume = Volume {
+            id: 0,
+            device,
+            read_only: false,
+            sector_size,
+            cluster_size: sector_size * per_cluster,
+            fat_start: start + reserved * sector_size,
+            fat_size: fat_sectors * sector_size,
+            fats,
+            data_start: start + data * sector_size,
+            last_cluster: clusters as u32 + 1,
+            root: u32_at(&boot, 44),
+            fs_info: match u16_at(&boot, 48) as u64 {
+                0 | 0xffff => None,
+                sector => Some(start + sector * sector_size),
+            },
+            next_free: 2,
+        };
+        Ok(volume.is_cluster(volume.root).then_some(volume))
+    }
+
+    /// Drop FSInfo unless it is valid and still claims a free count
+    fn check_fs_info(&mut self) -> Result<(), VfsError> {
+        if let Some(at) = self.fs_info {
+            let mut info = [0u8; SECTOR];
+            self.read(at, &mut info)?;
+            let valid = u32_at(&info, 0) == FS_INFO_LEAD && u32_at(&info, 484) == FS_INFO_STRUCT;
+            if !valid || u32_at(&info, FS_INFO_FREE as usize) == u32::MAX {
+                self.fs_info = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&self, at: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
+        storage::read_raw(self.device, at, buffer)?;
+        Ok(())
+    }
+
+    fn write(&mut self, at: u64, data: &[u8]) -> Result<(), VfsError> {
+        if self.read_only {
+            return Err(VfsError::ReadOnly);
+        }
+        // Mark the free count unknown before the first change
+        if let Some(info) = self.fs_info.take() {
+            storage::write_raw(self.device, info + FS_INFO_FREE, &u32::MAX.to_le_bytes())?;
+        }
+        storage::write_raw(self.device, at, data)?;
+        Ok(())
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (2..=self.last_cluster).contains(&cluster)
+    }
+
+    /// Where `cluster` is on the device
+    fn offset(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.cluster_size
+    }
+
+    fn fat(&self, cluster: u32) -> Result<u32, VfsError> {
+        let mut entry = [0u8; 4];
+        self.read(self.fat_start + cluster as u64 * 4, &mut entry)?;
+        Ok(u32::from_le_bytes(entry) & FAT_MASK)
+    }
+
+    /// Set the FAT entry of `cluster` in every FAT
+    fn set_fat(&mut self, cluster: u32, value: u32) -> Result<(), VfsError> {
+        let at = cluster as u64 * 4;
+        let mut entry = [0u8; 4];
+        self.read(self.fat_start + at, &mut entry)?;
+        let value = (u32::from_le_bytes(entry) & !FAT_MASK) | value;
+        for fat in 0..self.fats {
+            self.write(self.fat_start + fat * self.fat_size + at, &value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain
+    fn next(&self, cluster: u32) -> Result<Option<u32>, VfsError> {
+        match self.fat(cluster)? {
+            next if next >= END_OF_CHAIN => Ok(None),
+            next if self.is_cluster(next) => Ok(Some(next)),
+            _ => Err(VfsError::Corrupt),
+        }
+    }
+
+    /// The `index`th cluster of the chain starting at `first`
+    fn nth(&self, first: u32, index: u64) -> Result<u32, VfsError> {
+        if !self.is_cluster(first) {
+            return Err(VfsError::Corrupt);
+        }
+        let mut cluster = first;
+        for _ in 0..index {
+            cluster = self.next(cluster)?.ok_or(VfsError::Corrupt)?;
+        }
+        Ok(cluster)
+    }
+
+    /// The last cluster of the chain starting at `first`
+    fn chain_end(&self, first: u32) -> Result<u32, VfsError> {
+        let mut cluster = first;
+        for _ in 0..self.last_cluster {
+            match self.next(cluster)? {
+                Some(next) => cluster = next,
+                None => return Ok(cluster),
+            }
+        }
+        Err(VfsError::Corrupt)
+    }
+
+    /// Take a free cluster, zeroed, onto the end of the chain ending at
+    /// `last`, or as a chain of its own
+    fn allocate(&mut self, last: Option<u32>) -> Result<u32, VfsError> {
+        let mut cluster = self.next_free;
+        for _ in 2..=self.last_cluster {
+            if !self.is_cluster(cluster) {
+                cluster = 2;
+            }
+            if self.fat(cluster)? == 0 {
+                let zero = [0u8; MAX_SECTOR];
+                let start = self.offset(cluster);
+                for offset in (0..self.cluster_size).step_by(MAX_SECTOR) {
+                    let len = (self.cluster_size - offset).min(MAX_SECTOR as u64) as usize;
+                    self.write(start + offset, &zero[..len])?;
+                }
+                self.set_fat(cluster, FAT_MASK)?;
+                if let Some(last) = last {
+                    self.set_fat(last, cluster)?;
+                }
+                self.next_free = cluster + 1;
+                return Ok(cluster);
+            }
+            cluster += 1;
+        }
+        Err(VfsError::NoSpace)
+    }
+
+    /// Free the chain starting at `first`
+    fn free_chain(&mut self, first: u32) -> Result<(), VfsError> {
+        let mut cluster = Some(first).filter(|c| self.is_cluster(*c));
+        for _ in 0..self.last_cluster {
+            let Some(current) = cluster else {
+                return Ok(());
+            };
+            cluster = self.next(current)?;
+            self.set_fat(current, 0)?;
+        }
+        Err(VfsError::Corrupt)
+    }
+
+    /// Call `f` with where each 32-byte slot of the directory at `cluster`
+    /// is and what it holds, until it returns `Some`
+    fn walk_slots<R>(
+        &self,
+        cluster: u32,
+        mut f: impl FnMut(u64, &[u8; 32]) -> Option<R>,
+    ) -> Result<Option<R>, VfsError> {
+        let mut sector = [0u8; MAX_SECTOR];
+        let sector = &mut sector[..self.sector_size as usize];
+        let mut cluster = Some(self.nth(cluster, 0)?);
+        for _ in 0..self.last_cluster {
+            let Some(current) = cluster else {
+                return Ok(None);
+            };
+            let start = self.offset(current);
+            for offset in (0..self.cluster_size).step_by(self.sector_size as usize) {
+                self.read(start + offset, sector)?;
+                for (i, slot) in sector.chunks_exact(ENTRY_SIZE as usize).enumerate() {
+                    if let Some(found) = f(start + offset + i as u64 * ENTRY_SIZE, slot.try_into().unwrap()) {
+                        return Ok(Some(found));
+                    }
+                }
+            }
+            cluster = self.next(current)?;
+        }
+        Err(VfsError::Corrupt)
+    }
+
+    /// Call `f` with each entry of the directory at `cluster` until it
+    /// returns `Some`; `.`, `..` and the volume label are left out
+    fn entries<R>(&self, cluster: u32, mut f: impl FnMut(&Entry) -> Option<R>) -> Result<Option<R>, VfsError> {
+        let mut long = LongName::new();
+        let found = self.walk_slots(cluster, |at, slot| {
+            match slot[0] {
+                0 => return Some(None),
+                DELETED => long.clear(),
+                _ if slot[11] & ATTR_LONG_NAME == ATTR_LONG_NAME => long.add(at, slot),
+                _ if slot[11] & ATTR_VOLUME_ID != 0 || slot[0] == b'.' => long.clear(),
+                _ => return f(&long.entry(at, slot)).map(Some),
+            }
+            None
+        })?;
+        Ok(found.flatten())
+    }
+
+    /// The entry named `name` in the directory at `dir`
+    fn find(&self, dir: u32, name: &str) -> Result<Option<Entry>, VfsError> {
+        self.entries(dir, |entry| entry.name.eq_ignore_ascii_case(name).then(|| entry.clone()))
+    }
+
+    /// The entry `path` names, or `None` for the root
+    fn resolve(&self, path: &str) -> Result<Option<Entry>, VfsError> {
+        let mut found: Option<Entry> = None;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let dir = self.cluster_of(found.as_ref())?;
+            found = Some(self.find(dir, name)?.ok_or(VfsError::NotFound)?);
+        }
+        Ok(found)
+    }
+
+    /// The first cluster of a directory found by [`Volume::resolve`]
+    fn cluster_of(&self, dir: Option<&Entry>) -> Result<u32, VfsError> {
+        match dir {
+            None => Ok(self.root),
+            Some(entry) if !entry.is_dir() => Err(VfsError::NotDirectory),
+            // `..` of a directory in the root
+            Some(entry) if entry.cluster == 0 => Ok(self.root),
+            Some(entry) => Ok(entry.cluster),
+        }
+    }
+
+    /// The directory holding `path`, which must not exist yet
+    fn new_in(&self, path: &str) -> Result<u32, VfsError> {
+        let (parent, name) = split(path);
+        let dir = self.cluster_of(self.resolve(parent)?.as_ref())?;
+        check_name(name)?;
+        match self.find(dir, name)? {
+            Some(_) => Err(VfsError::Exists),
+            None => Ok(dir),
+        }
+    }
+
+    /// The first cluster, size and attributes of the short entry at `at`
+    fn short(&self, at: u64) -> Result<(u32, u32, u8), VfsError> {
+        let mut slot = [0u8; 32];
+        self.read(at, &mut slot)?;
+        if slot[0] == 0 || slot[0] == DELETED {
+            return Err(VfsError::NotFound);
+        }
+        Ok(((u16_at(&slot, 20) as u32) << 16 | u16_at(&slot, 26) as u32, u32_at(&slot, 28), slot[11]))
+    }
+
+    /// Point the short entry at `at` to `cluster` and give it `size`
+    fn set_short(&mut self, at: u64, cluster: u32, size: u32) -> Result<(), VfsError> {
+        let mut slot = [0u8; 32];
+        self.read(at, &mut slot)?;
+        slot[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        slot[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        slot[28..32].copy_from_slice(&size.to_le_bytes());
+        self.write(at, &slot)
+    }
+
+    /// A short name for `name` that no entry in the directory at `dir` has
+    fn alias(&self, dir: u32, name: &str) -> Result<[u8; 11], VfsError> {
+        let (base, ext) = match name.rsplit_once('.') {
+            Some((base, ext)) if !base.trim_start_matches('.').is_empty() => (base, ext),
+            _ => (name, ""),
+        };
+        let stem: ArrayVec<u8, 6> = base.chars().filter_map(alias_char).take(6).collect();
+        let mut short = [b' '; 11];
+        for (i, b) in ext.chars().filter_map(alias_char).take(3).enumerate() {
+            short[8 + i] = b;
+        }
+        for n in 1..1_000_000u32 {
+            let mut tail = ArrayString::<8>::new();
+            let _ = core::fmt::write(&mut tail, format_args!("~{}", n));
+            let keep = stem.len().min(8 - tail.len());
+            short[..8].fill(b' ');
+            short[..keep].copy_from_slice(&stem[..keep]);
+            short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+            let taken = self.walk_slots(dir, |_, slot| match slot[0] {
+                0 => Some(false),
+                DELETED => None,
+                _ => (slot[11] & ATTR_LONG_NAME != ATTR_LONG_NAME && slot[..11] == short).then_some(true),
+            })?;
+            if taken != Some(true) {
+                return Ok(short);
+            }
+        }
+        Err(VfsError::Exists)
+    }
+
+    /// `count` free slots in a row in the directory at `dir`, which grows
+    /// if it has none
+    fn free_slots(&mut self, dir: u32, count: usize) -> Result<ArrayVec<u64, { MAX_LONG + 1 }>, VfsError> {
+        let mut run = ArrayVec::new();
+        let found = self.walk_slots(dir, |at, slot| {
+            if slot[0] == 0 || slot[0] == DELETED {
+                run.push(at);
+                if run.len() == count {
+                    return Some(());
+                }
+            } else {
+                run.clear();
+            }
+            None
+        })?;
+        if found.is_none() {
+            let mut last = self.chain_end(dir)?;
+            while run.len() < count {
+                last = self.allocate(Some(last))?;
+                let start = self.offset(last);
+                for at in (start..start + self.cluster_size).step_by(ENTRY_SIZE as usize).take(count - run.len()) {
+                    run.push(at);
+                }
+            }
+        }
+        Ok(run)
+    }
+
+    /// Add an entry named `name` to the directory at `dir`, returning where
+    /// its short entry is
+    fn add_entry(&mut self, dir: u32, name: &str, attr: u8, cluster: u32, size: u32) -> Result<u64, VfsError> {
+        let (short, units) = match short_form(name) {
+            Some(short) => (short, ArrayVec::new()),
+            None => {
+                let units: ArrayVec<u16, { MAX_LONG * LONG_CHARS }> = name.encode_utf16().collect();
+                (self.alias(dir, name)?, units)
+            }
+        };
+        let longs = units.len().div_ceil(LONG_CHARS);
+        let slots = self.free_slots(dir, longs + 1)?;
+        let sum = checksum(&short);
+        for (i, at) in slots[..longs].iter().enumerate() {
+            let order = longs - i;
+            let mut slot = [0u8; 32];
+            slot[0] = order as u8 | if i == 0 { LAST_LONG } else { 0 };
+            slot[11] = ATTR_LONG_NAME;
+            slot[13] = sum;
+            for (j, offset) in LONG_OFFSETS.iter().enumerate() {
+                // The name ends with a NUL if it fits, then is padded
+                let unit = match (order - 1) * LONG_CHARS + j {
+                    k if k < units.len() => units[k],
+                    k if k == units.len() => 0,
+                    _ => 0xffff,
+                };
+                slot[*offset..*offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            self.write(*at, &slot)?;
+        }
+        self.write(slots[longs], &short_entry(&short, attr, cluster, size))?;
+        Ok(slots[longs])
+    }
+
+    fn remove_entry(&mut self, entry: &Entry) -> Result<(), VfsError> {
+        for at in entry.long[..entry.longs].iter().chain([&entry.at]) {
+            self.write(*at, &[DELETED])?;
+        }
+        Ok(())
+    }
+
+    fn read_file(&self, at: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let (first, size, _) = self.short(at)?;
+        let len = buffer.len().min(size.saturating_sub(offset.min(u32::MAX as u64) as u32) as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+        let mut cluster = self.nth(first, offset / self.cluster_size)?;
+        let mut done = 0;
+        loop {
+            let within = (offset + done as u64) % self.cluster_size;
+            let chunk = ((self.cluster_size - within) as usize).min(len - done);
+            self.read(self.offset(cluster) + within, &mut buffer[done..done + chunk])?;
+            done += chunk;
+            if done == len {
+                return Ok(len);
+            }
+            cluster = self.next(cluster)?.ok_or(VfsError::Corrupt)?;
+        }
+    }
+
+    /// The cluster after `cluster`, taking a new one if the chain ends
+    fn next_or_grow(&mut self, cluster: u32) -> Result<u32, VfsError> {
+        match self.next(cluster)? {
+            Some(next) => Ok(next),
+            None => self.allocate(Some(cluster)),
+        }
+    }
+
+    fn write_file(&mut self, at: u64, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        let (mut first, size, _) = self.short(at)?;
+        let end = offset
+            .checked_add(data.len() as u64)
+            .filter(|end| *end <= u32::MAX as u64)
+            .ok_or(VfsError::TooLarge)?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+        // What lies past the end of the file is not zeroed on disk, so a
+        // gap left by seeking past it is filled first
+        if offset > size as u64 {
+            let zero = [0u8; MAX_SECTOR];
+            let mut at_gap = size as u64;
+            while at_gap < offset {
+                let len = (offset - at_gap).min(MAX_SECTOR as u64) as usize;
+                at_gap += self.write_file(at, at_gap, &zero[..len])? as u64;
+            }
+        }
+        if first == 0 {
+            first = self.allocate(None)?;
+            self.set_short(at, first, size)?;
+        }
+        let mut cluster = first;
+        for _ in 0..offset / self.cluster_size {
+            cluster = self.next_or_grow(cluster)?;
+        }
+        let mut done = 0;
+        loop {
+            let within = (offset + done as u64) % self.cluster_size;
+            let chunk = ((self.cluster_size - within) as usize).min(data.len() - done);
+            self.write(self.offset(cluster) + within, &data[done..done + chunk])?;
+            done += chunk;
+            if done == data.len() {
+                break;
+            }
+            cluster = self.next_or_grow(cluster)?;
+        }
+        let (_, size, _) = self.short(at)?;
+        if end > size as u64 {
+            self.set_short(at, first, end as u32)?;
+        }
+        Ok(data.len())
+    }
+}
+
+/// Find the FAT32 volume on `device`
+fn find(device: u32) -> Result<Option<Volume>, VfsError> {
+    for start in partition::starts(device)? {
+        if let Some(volume) = Volume::probe(device, start)? {
+            return Ok(Some(volume));
+        }
+    }
+    Ok(None)
+}
+
+fn with_volume<R>(volume: u32, f: impl FnOnce(&mut Volume) -> Result<R, VfsError>) -> Result<R, VfsError> {
+    let mut volumes = VOLUMES.lock();
+    let volume = volumes
+        .iter_mut()
+        .flatten()
+        .find(|v| v.id == volume)
+        .ok_or(VfsError::Device(StorageError::DeviceGone))?;
+    f(volume)
+}
+
+/// Mount the FAT32 volume on `device`, if it holds one, returning its
+/// volume number
+pub fn mount(device: u32) -> Result<Option<u32>, VfsError> {
+    let info = storage::info(device)?;
+    let Some(mut volume) = find(device)? else {
+        return Ok(None);
+    };
+    volume.read_only = info.read_only;
+    volume.check_fs_info()?;
+    let mut volumes = VOLUMES.lock();
+    let slot = volumes.iter_mut().find(|slot| slot.is_none()).ok_or(VfsError::Busy)?;
+    volume.id = NEXT_VOLUME.fetch_add(1, Ordering::Relaxed);
+    *slot = Some(volume);
+    Ok(Some(volume.id))
+}
+
+pub fn unmount(volume: u32) {
+    for slot in VOLUMES.lock().iter_mut() {
+        if slot.is_some_and(|v| v.id == volume) {
+            *slot = None;
+        }
+    }
+}
+
+pub fn is_read_only(volume: u32) -> Result<bool, VfsError> {
+    with_volume(volume, |v| Ok(v.read_only))
+}
+
+/// An inode number for the file whose short entry is at `entry`
+pub fn ino(volume: u32, entry: u64) -> u64 {
+    1 << 62 | (volume as u64) << 48 | entry
+}
+
+/// What `path` names on `volume`; the empty path is the root
+pub fn lookup(volume: u32, path: &str) -> Result<Node, VfsError> {
+    with_volume(volume, |v| {
+        Ok(match v.resolve(path)? {
+            Some(entry) if !entry.is_dir() => Node::File(entry.at),
+            _ => Node::Directory,
+        })
+    })
+}
+
+/// Create the empty file `path`
+pub fn create(volume: u32, path: &str) -> Result<u64, VfsError> {
+    with_volume(volume, |v| {
+        let dir = v.new_in(path)?;
+        v.add_entry(dir, split(path).1, ATTR_ARCHIVE, 0, 0)
+    })
+}
+
+pub fn size(volume: u32, entry: u64) -> Result<u64, VfsError> {
+    with_volume(volume, |v| Ok(v.short(entry)?.1 as u64))
+}
+
+/// Empty the file whose short entry is at `entry`
+pub fn truncate(volume: u32, entry: u64) -> Result<(), VfsError> {
+    with_volume(volume, |v| {
+        let (first, _, _) = v.short(entry)?;
+        v.set_short(entry, 0, 0)?;
+        v.free_chain(first)
+    })
+}
+
+/// Read the file whose short entry is at `entry` from `offset` into
+/// `buffer`, returning how many bytes were read
+pub fn read(volume: u32, entry: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+    with_volume(volume, |v| v.read_file(entry, offset, buffer))
+}
+
+/// Write `data` to the file whose short entry is at `entry` at `offset`
+pub fn write(volume: u32, entry: u64, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+    with_volume(volume, |v| v.write_file(entry, offset, data))
+}
+
+/// The entry of directory `dir` that comes first by name after `after`,
+/// or the first one if `after` is empty
+pub fn next_entry(volume: u32, dir: &str, after: &str) -> Result<Option<(Path, Node)>, VfsError> {
+    with_volume(volume, |v| {
+        let cluster = v.cluster_of(v.resolve(dir)?.as_ref())?;
+        let mut next: Option<(Path, Node)> = None;
+        v.entries(cluster, |entry| {
+            let name = entry.name;
+            if name.as_str() > after && next.is_none_or(|(first, _)| name < first) {
+                let node = if entry.is_dir() { Node::Directory } else { Node::File(entry.at) };
+                next = Some((name, node));
+            }
+            None::<()>
+        })?;
+        Ok(next)
+    })
+}
+
+/// Make the directory `path`
+pub fn mkdir(volume: u32, path: &str) -> Result<(), VfsError> {
+    with_volume(volume, |v| {
+        let dir = v.new_in(path)?;
+        let cluster = v.allocate(None)?;
+        if let Err(e) = v.add_entry(dir, split(path).1, ATTR_DIRECTORY, cluster, 0) {
+            let _ = v.free_chain(cluster);
+            return Err(e);
+        }
+        // `..` of a directory in the root points to cluster 0
+        let parent = if dir == v.root { 0 } else { dir };
+        let start = v.offset(cluster);
+        v.write(start, &short_entry(b".          ", ATTR_DIRECTORY, cluster, 0))?;
+        v.write(start + ENTRY_SIZE, &short_entry(b"..         ", ATTR_DIRECTORY, parent, 0))
+    })
+}
+
+/// Remove the empty directory `path`
+pub fn rmdir(volume: u32, path: &str) -> Result<(), VfsError> {
+    with_volume(volume, |v| {
+        let entry = v.resolve(path)?.ok_or(VfsError::Busy)?;
+        if !entry.is_dir() {
+            return Err(VfsError::NotDirectory);
+        }
+        if v.entries(v.cluster_of(Some(&entry))?, |_| Some(()))?.is_some() {
+            return Err(VfsError::NotEmpty);
+        }
+        v.remove_entry(&entry)?;
+        v.free_chain(entry.cluster)
+    })
+}
+
+/// Remove the file `path`
+pub fn unlink(volume: u32, path: &str) -> Result<(), VfsError> {
+    with_volume(volume, |v| match v.resolve(path)? {
+        Some(entry) if !entry.is_dir() => {
+            v.remove_entry(&entry)?;
+            v.free_chain(entry.cluster)
+        }
+        _ => Err(VfsError::IsDirectory),
+    })
+}
+
+/// Move `from` to `to`, replacing any file there
+pub fn rename(volume: u32, from: &str, to: &str) -> Result<(), VfsError> {
+    with_volume(volume, |v| {
+        let entry = v.resolve(from)?.ok_or(VfsError::Busy)?;
+        let (parent, name) = split(to);
+        let dir = v.cluster_of(v.resolve(parent)?.as_ref())?;
+        check_name(name)?;
+        let into_itself = to
+            .get(..from.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(from) && to[from.len()..].starts_with('/'));
+        if entry.is_dir() && into_itself {
+            return Err(VfsError::Invalid);
+        }
+        match v.find(dir, name)? {
+            // The same entry, renamed to change case
+            Some(target) if target.at == entry.at => {}
+            Some(target) if target.is_dir() => return Err(VfsError::IsDirectory),
+            Some(_) if entry.is_dir() => return Err(VfsError::NotDirectory),
+            Some(target) => {
+                v.remove_entry(&target)?;
+                v.free_chain(target.cluster)?;
+            }
+            None => {}
+        }
+        let old_dir = v.cluster_of(v.resolve(split(from).0)?.as_ref())?;
+        v.add_entry(dir, name, entry.attr, entry.cluster, entry.size)?;
+        v.remove_entry(&entry)?;
+        if entry.is_dir() && old_dir != dir {
+            let dotdot = v.offset(entry.cluster) + ENTRY_SIZE;
+            let parent = if dir == v.root { 0 } else { dir };
+            v.set_short(dotdot, parent, 0)?;
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,120 @@
+//! Linux program startup
+//!
+//! A Linux program finds on its stack what the kernel's ELF loader leaves
+//! there: `argc`, the `argv` pointers and a null pointer, the `envp`
+//! pointers and a null pointer, then the auxiliary vector, pairs of a type
+//! and a value ending with `AT_NULL`. The C library takes the program
+//! headers from it to find the TLS template, and the stack protector's
+//! canary from the 16 random bytes `AT_RANDOM` points at. There is no
+//! vDSO, so every clock is read through a system call. Strings and the
+//! random bytes sit above the vectors, at the top of the stack, and the
+//! stack pointer is 16-byte aligned at `argc`.
+
+use x86_64::VirtAddr;
+
+use crate::kernel::memory::MapError;
+use crate::userspace::address_space::AddressSpace;
+use crate::userspace::args::Strings;
+use crate::userspace::elf::PHDR_SIZE;
+use crate::userspace::loader::Image;
+use crate::userspace::stack::STACK_TOP;
+
+/// Auxiliary vector types
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_FLAGS: u64 = 8;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_PLATFORM: u64 = 15;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+
+/// Ticks per second `times` would count in, which Linux fixes at 100
+const CLOCK_TICKS: u64 = 100;
+
+/// Entries the auxiliary vector has, `AT_NULL` included
+const AUXV_LEN: usize = 18;
+
+const PLATFORM: &[u8] = b"x86_64\0";
+
+/// Copy the arguments, environment and auxiliary vector for `image` to
+/// the top of the stack, returning the stack pointer and the addresses
+/// of `argv` and `envp`
+pub fn place_args(
+    space: &mut AddressSpace,
+    image: &Image,
+    argv: &Strings,
+    envp: &Strings,
+) -> Result<(u64, u64, u64), MapError> {
+    let envp_strings = STACK_TOP - envp.packed().len() as u64;
+    let argv_strings = envp_strings - argv.packed().len() as u64;
+    let platform = argv_strings - PLATFORM.len() as u64;
+    let random_bytes = (platform - 16) & !0xf;
+    space.write(VirtAddr::new(envp_strings), envp.packed())?;
+    space.write(VirtAddr::new(argv_strings), argv.packed())?;
+    space.write(VirtAddr::new(platform), PLATFORM)?;
+    let mut bytes = [0u8; 16];
+    crate::kernel::random::fill(&mut bytes);
+    space.write(VirtAddr::new(random_bytes), &bytes)?;
+
+    // The path the program was started by; the first argument stands in
+    let execfn = if argv.count() > 0 { argv_strings } else { 0 };
+    // SSE and the rest of what CPUID leaf 1 reports in `edx`, as on Linux
+    let hwcap = core::arch::x86_64::__cpuid(1).edx as u64;
+    let auxv: [(u64, u64); AUXV_LEN] = [
+        (AT_PHDR, image.phdr.unwrap_or(0)),
+        (AT_PHENT, PHDR_SIZE as u64),
+        (AT_PHNUM, image.phnum as u64),
+        (AT_PAGESZ, 4096),
+        (AT_BASE, 0),
+        (AT_FLAGS, 0),
+        (AT_ENTRY, image.entry),
+        (AT_UID, 0),
+        (AT_EUID, 0),
+        (AT_GID, 0),
+        (AT_EGID, 0),
+        (AT_PLATFORM, platform),
+        (AT_HWCAP, hwcap),
+        (AT_CLKTCK, CLOCK_TICKS),
+        (AT_SECURE, 0),
+        (AT_RANDOM, random_bytes),
+        (AT_EXECFN, execfn),
+        (AT_NULL, 0),
+    ];
+
+    // argc, argv, null, envp, null, auxv
+    let words = 1 + argv.count() + 1 + envp.count() + 1 + 2 * AUXV_LEN;
+    let sp = (random_bytes - words as u64 * 8) & !0xf;
+    let mut addr = sp;
+    let mut push = |space: &mut AddressSpace, value: u64| {
+        let result = space.write(VirtAddr::new(addr), &value.to_le_bytes());
+        addr += 8;
+        result
+    };
+    push(space, argv.count() as u64)?;
+    for offset in argv.offsets() {
+        push(space, argv_strings + offset as u64)?;
+    }
+    push(space, 0)?;
+    for offset in envp.offsets() {
+        push(space, envp_strings + offset as u64)?;
+    }
+    push(space, 0)?;
+    for (kind, value) in auxv {
+        push(space, kind)?;
+        push(space, value)?;
+    }
+
+    let argv_addr = sp + 8;
+    Ok((sp, argv_addr, argv_addr + (argv.count() as u64 + 1) * 8))
+}
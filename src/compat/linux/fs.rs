@@ -0,0 +1,327 @@
+//! Paths and directories
+//!
+//! Paths go through the POSIX view of TagFS (see [`crate::compat::vfs`]),
+//! relative to the process's working directory or to a directory
+//! descriptor. A file is created when it is first closed, so it cannot be
+//! found by its path while it is still being written. Directories list
+//! without `.` and `..`, which every path still resolves.
+
+use crate::capability::{self, Permission};
+use crate::compat::vfs::{self, Kind, Path};
+use crate::userspace::handle::{self, Object};
+use crate::userspace::process;
+use crate::userspace::uaccess::{self, Access};
+use crate::userspace::usermode::UserContext;
+
+use super::io::write_stat;
+use super::{user_cstr, Errno};
+
+/// Open flags
+const O_ACCMODE: u64 = 3;
+const O_RDONLY: u64 = 0;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+const O_DIRECTORY: u64 = 0o200000;
+
+/// `dirfd` for paths relative to the working directory
+pub const AT_FDCWD: u64 = -100i64 as u64;
+const AT_REMOVEDIR: u64 = 0x200;
+const AT_EMPTY_PATH: u64 = 0x1000;
+
+/// `access` mode asking about writing
+const W_OK: u64 = 2;
+
+/// `d_type` of a directory entry
+const DT_CHR: u8 = 2;
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+
+/// Where the name starts in a `struct linux_dirent64`, after the inode
+/// number, offset, record length and type
+const DIRENT_NAME: usize = 19;
+
+/// An inode number for what has none of its own, a directory or device,
+/// from its path's components; kept clear of object IDs by its top bit
+pub fn path_ino<'a>(components: impl Iterator<Item = &'a str>) -> u64 {
+    // FNV-1a over each component after a slash
+    let hash = components
+        .filter(|component| !component.is_empty())
+        .flat_map(|component| b"/".iter().chain(component.as_bytes()))
+        .fold(0xcbf2_9ce4_8422_2325u64, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100_0000_01b3));
+    hash | 1 << 63
+}
+
+/// The directory a path given with `dirfd` is relative to
+fn base(dirfd: u64, path: &str) -> Result<Path, Errno> {
+    let pid = process::current();
+    if path.starts_with('/') || dirfd == AT_FDCWD {
+        return process::cwd(pid).ok_or(Errno::ESRCH);
+    }
+    match handle::get(pid, dirfd)? {
+        Object::Directory { path, .. } => Ok(path),
+        _ => Err(Errno::ENOTDIR),
+    }
+}
+
+/// `path`, given with `dirfd`, normalized
+fn at(dirfd: u64, path: &str) -> Result<Path, Errno> {
+    Ok(vfs::normalize(&base(dirfd, path)?, path)?)
+}
+
+fn check(permission: Permission) -> Result<(), Errno> {
+    Ok(capability::check_permission(process::current(), permission)?)
+}
+
+/// The object `path`, given with `dirfd`, names, opened with `flags`
+pub fn resolve(dirfd: u64, path: &str, flags: u64) -> Result<Object, Errno> {
+    let path = at(dirfd, path)?;
+    let mut open = match flags & O_ACCMODE {
+        O_RDONLY => vfs::OPEN_READ,
+        1 => vfs::OPEN_WRITE,
+        _ => vfs::OPEN_READ | vfs::OPEN_WRITE,
+    };
+    for (linux, flag) in [
+        (O_CREAT, vfs::OPEN_CREATE),
+        (O_EXCL, vfs::OPEN_EXCLUSIVE),
+        (O_TRUNC, vfs::OPEN_TRUNCATE),
+        (O_APPEND, vfs::OPEN_APPEND),
+        (O_DIRECTORY, vfs::OPEN_DIRECTORY),
+    ] {
+        if flags & linux != 0 {
+            open |= flag;
+        }
+    }
+    // Truncating is writing, whatever the access mode says
+    if open & vfs::OPEN_TRUNCATE != 0 {
+        open |= vfs::OPEN_WRITE;
+    }
+    check(Permission::Read)?;
+    if open & vfs::OPEN_WRITE != 0 {
+        check(Permission::Write)?;
+    }
+    if open & vfs::OPEN_CREATE != 0 && vfs::lookup(&path).is_err() {
+        check(Permission::FileCreate)?;
+    }
+    Ok(vfs::open(&path, open)?)
+}
+
+/// path, flags, mode
+pub fn open(context: &mut UserContext) -> Result<u64, Errno> {
+    let [path, flags, ..] = context.args();
+    let access = Access::open();
+    let object = resolve(AT_FDCWD, user_cstr(&access, path)?, flags)?;
+    Ok(handle::open(process::current(), object)?)
+}
+
+/// directory, path, flags, mode
+pub fn openat(context: &mut UserContext) -> Result<u64, Errno> {
+    let [dirfd, path, flags, ..] = context.args();
+    let access = Access::open();
+    let object = resolve(dirfd, user_cstr(&access, path)?, flags)?;
+    Ok(handle::open(process::current(), object)?)
+}
+
+/// Describe what `path`, given with `dirfd`, names at `addr`
+fn stat_at(dirfd: u64, path: &str, addr: u64) -> Result<u64, Errno> {
+    let object = resolve(dirfd, path, O_RDONLY)?;
+    // Opening may have taken something, such as a new pseudo-terminal
+    let stat = write_stat(addr, object);
+    object.release();
+    stat
+}
+
+/// path, stat out
+pub fn stat(context: &mut UserContext) -> Result<u64, Errno> {
+    let [path, addr, ..] = context.args();
+    let access = Access::open();
+    stat_at(AT_FDCWD, user_cstr(&access, path)?, addr)
+}
+
+/// directory, path, stat out, flags; an empty path with `AT_EMPTY_PATH`
+/// means the directory descriptor itself
+pub fn newfstatat(context: &mut UserContext) -> Result<u64, Errno> {
+    let [dirfd, path, addr, flags, ..] = context.args();
+    let access = Access::open();
+    let path = user_cstr(&access, path)?;
+    if path.is_empty() && flags & AT_EMPTY_PATH != 0 {
+        return write_stat(addr, handle::get(process::current(), dirfd)?);
+    }
+    stat_at(dirfd, path, addr)
+}
+
+/// Whether `path` exists and, for `W_OK`, whether the caller may write
+fn check_access(dirfd: u64, path: &str, mode: u64) -> Result<u64, Errno> {
+    resolve(dirfd, path, O_RDONLY)?.release();
+    if mode & W_OK != 0 {
+        check(Permission::Write)?;
+    }
+    Ok(0)
+}
+
+/// path, mode
+pub fn access(context: &mut UserContext) -> Result<u64, Errno> {
+    let [path, mode, ..] = context.args();
+    let access = Access::open();
+    check_access(AT_FDCWD, user_cstr(&access, path)?, mode)
+}
+
+/// directory, path, mode, flags
+pub fn faccessat(context: &mut UserContext) -> Result<u64, Errno> {
+    let [dirfd, path, mode, ..] = context.args();
+    let access = Access::open();
+    check_access(dirfd, user_cstr(&access, path)?, mode)
+}
+
+/// fd, buffer, size; returns how many bytes of entries were written, 0
+/// once the directory has been read through
+pub fn getdents64(context: &mut UserContext) -> Result<u64, Errno> {
+    let [fd, addr, size, ..] = context.args();
+    let pid = process::current();
+    let Object::Directory { path, mut cursor } = handle::get(pid, fd)? else {
+        return Err(Errno::ENOTDIR);
+    };
+    let mut written = 0;
+    while let Some(entry) = vfs::next_entry(&path, &cursor) {
+        let reclen = (DIRENT_NAME + entry.name.len() + 1).next_multiple_of(8);
+        if written + reclen as u64 > size {
+            if written == 0 {
+                return Err(Errno::EINVAL);
+            }
+            break;
+        }
+        let (kind, ino) = match entry.kind {
+            Kind::File => (DT_REG, entry.id),
+            Kind::Directory => (DT_DIR, 0),
+            Kind::Device => (DT_CHR, 0),
+        };
+        let ino = if ino != 0 { ino } else { path_ino(path.split('/').chain([entry.name.as_str()])) };
+        let mut record = [0u8; DIRENT_NAME + vfs::MAX_PATH + 8];
+        record[..8].copy_from_slice(&ino.to_le_bytes());
+        record[8..16].copy_from_slice(&(written + reclen as u64).to_le_bytes());
+        record[16..18].copy_from_slice(&(reclen as u16).to_le_bytes());
+        record[18] = kind;
+        record[DIRENT_NAME..DIRENT_NAME + entry.name.len()].copy_from_slice(entry.name.as_bytes());
+        uaccess::copy_to_user(addr + written, &record[..reclen])?;
+        written += reclen as u64;
+        cursor = entry.name;
+    }
+    handle::update(pid, fd, |object| {
+        if let Object::Directory { cursor: at, .. } = object {
+            *at = cursor;
+        }
+    })?;
+    Ok(written)
+}
+
+/// buffer, size; returns the length of the path, NUL included
+pub fn getcwd(context: &mut UserContext) -> Result<u64, Errno> {
+    let [addr, size, ..] = context.args();
+    let cwd = process::cwd(process::current()).ok_or(Errno::ESRCH)?;
+    let mut path = [0u8; vfs::MAX_PATH + 2];
+    path[0] = b'/';
+    path[1..=cwd.len()].copy_from_slice(cwd.as_bytes());
+    let len = cwd.len() + 2;
+    if (size as usize) < len {
+        return Err(Errno::ERANGE);
+    }
+    uaccess::copy_to_user(addr, &path[..len])?;
+    Ok(len as u64)
+}
+
+fn change_dir(path: Path) -> Result<u64, Errno> {
+    process::set_cwd(process::current_thread(), path)?;
+    Ok(0)
+}
+
+/// path
+pub fn chdir(context: &mut UserContext) -> Result<u64, Errno> {
+    let access = Access::open();
+    let path = at(AT_FDCWD, user_cstr(&access, context.rdi)?)?;
+    match vfs::lookup(&path)? {
+        Object::Directory { .. } => change_dir(path),
+        _ => Err(Errno::ENOTDIR),
+    }
+}
+
+/// fd
+pub fn fchdir(context: &mut UserContext) -> Result<u64, Errno> {
+    match handle::get(process::current(), context.rdi)? {
+        Object::Directory { path, .. } => change_dir(path),
+        _ => Err(Errno::ENOTDIR),
+    }
+}
+
+fn make_dir(dirfd: u64, path: u64) -> Result<u64, Errno> {
+    let access = Access::open();
+    let path = at(dirfd, user_cstr(&access, path)?)?;
+    check(Permission::FileCreate)?;
+    vfs::mkdir(&path)?;
+    Ok(0)
+}
+
+/// path, mode
+pub fn mkdir(context: &mut UserContext) -> Result<u64, Errno> {
+    make_dir(AT_FDCWD, context.rdi)
+}
+
+/// directory, path, mode
+pub fn mkdirat(context: &mut UserContext) -> Result<u64, Errno> {
+    let [dirfd, path, ..] = context.args();
+    make_dir(dirfd, path)
+}
+
+fn remove(dirfd: u64, path: u64, directory: bool) -> Result<u64, Errno> {
+    let access = Access::open();
+    let path = at(dirfd, user_cstr(&access, path)?)?;
+    check(Permission::FileDelete)?;
+    if directory {
+        vfs::rmdir(&path)?;
+    } else {
+        vfs::unlink(&path)?;
+    }
+    Ok(0)
+}
+
+/// path
+pub fn rmdir(context: &mut UserContext) -> Result<u64, Errno> {
+    remove(AT_FDCWD, context.rdi, true)
+}
+
+/// path
+pub fn unlink(context: &mut UserContext) -> Result<u64, Errno> {
+    remove(AT_FDCWD, context.rdi, false)
+}
+
+/// directory, path, flags
+pub fn unlinkat(context: &mut UserContext) -> Result<u64, Errno> {
+    let [dirfd, path, flags, ..] = context.args();
+    remove(dirfd, path, flags & AT_REMOVEDIR != 0)
+}
+
+fn move_file(from_dirfd: u64, from: u64, to_dirfd: u64, to: u64) -> Result<u64, Errno> {
+    let access = Access::open();
+    let from = at(from_dirfd, user_cstr(&access, from)?)?;
+    let to = at(to_dirfd, user_cstr(&access, to)?)?;
+    check(Permission::FileCreate)?;
+    check(Permission::FileDelete)?;
+    vfs::rename(&from, &to)?;
+    Ok(0)
+}
+
+/// old path, new path
+pub fn rename(context: &mut UserContext) -> Result<u64, Errno> {
+    let [from, to, ..] = context.args();
+    move_file(AT_FDCWD, from, AT_FDCWD, to)
+}
+
+/// old directory, old path, new directory, new path, flags
+/// (`renameat2` only); no flag is supported
+pub fn renameat(context: &mut UserContext) -> Result<u64, Errno> {
+    let [from_dirfd, from, to_dirfd, to, flags, ..] = context.args();
+    if context.number() == super::RENAMEAT2 && flags != 0 {
+        return Err(Errno::EINVAL);
+    }
+    move_file(from_dirfd, from, to_dirfd, to)
+}
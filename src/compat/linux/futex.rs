@@ -0,0 +1,88 @@
+//! Futexes
+//!
+//! Linux's futex operations on the native primitive (see
+//! [`crate::userspace::futex`]). `FUTEX_WAIT` takes a relative timeout and
+//! `FUTEX_WAIT_BITSET` an absolute one, both on the monotonic clock since
+//! there is no other. Requeueing moves waiters rather than waking them, so
+//! a broadcast condition variable wakes one thread and hands the rest to
+//! its mutex.
+
+use crate::scheduler::TICKS_PER_SECOND;
+use crate::userspace::futex::{self, Timeout};
+use crate::userspace::process;
+use crate::userspace::uaccess;
+use crate::userspace::usermode::UserContext;
+
+use super::Errno;
+
+/// Operations, with the flags they may carry
+const FUTEX_WAIT: u64 = 0;
+const FUTEX_WAKE: u64 = 1;
+const FUTEX_REQUEUE: u64 = 3;
+const FUTEX_CMP_REQUEUE: u64 = 4;
+const FUTEX_WAIT_BITSET: u64 = 9;
+const FUTEX_WAKE_BITSET: u64 = 10;
+const FUTEX_PRIVATE_FLAG: u64 = 128;
+const FUTEX_CLOCK_REALTIME: u64 = 256;
+
+/// `struct timespec`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Timespec {
+    pub sec: i64,
+    pub nsec: i64,
+}
+
+impl Timespec {
+    /// The time in timer ticks, rounded up
+    pub fn ticks(&self) -> Result<u64, Errno> {
+        if self.sec < 0 || !(0..1_000_000_000).contains(&self.nsec) {
+            return Err(Errno::EINVAL);
+        }
+        let nsec = (self.nsec as u64 * TICKS_PER_SECOND).div_ceil(1_000_000_000);
+        Ok((self.sec as u64).saturating_mul(TICKS_PER_SECOND).saturating_add(nsec))
+    }
+}
+
+/// address, operation, value, timeout or count, second address, third
+/// value
+pub fn futex(context: &mut UserContext) -> Result<u64, Errno> {
+    let [addr, op, value, timeout, addr2, value3] = context.args();
+    let pid = process::current();
+    let op = op & !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME);
+    match op {
+        FUTEX_WAIT | FUTEX_WAIT_BITSET => {
+            let bitset = if op == FUTEX_WAIT { futex::ANY } else { value3 as u32 };
+            let timeout = match timeout {
+                0 => None,
+                timeout => {
+                    let ticks = uaccess::read_value::<Timespec>(timeout)?.ticks()?;
+                    Some(if op == FUTEX_WAIT { Timeout::After(ticks) } else { Timeout::At(ticks) })
+                }
+            };
+            futex::wait(context, addr, value as u32, bitset, timeout)?;
+            Ok(0)
+        }
+        FUTEX_WAKE => Ok(futex::wake(pid, addr, value, futex::ANY)),
+        FUTEX_WAKE_BITSET if value3 as u32 == 0 => Err(Errno::EINVAL),
+        FUTEX_WAKE_BITSET => Ok(futex::wake(pid, addr, value, value3 as u32)),
+        FUTEX_REQUEUE | FUTEX_CMP_REQUEUE => {
+            if !addr.is_multiple_of(4) || !addr2.is_multiple_of(4) {
+                return Err(Errno::EINVAL);
+            }
+            if op == FUTEX_CMP_REQUEUE && uaccess::read_value::<u32>(addr)? != value3 as u32 {
+                return Err(Errno::EAGAIN);
+            }
+            // The count to requeue travels in the timeout argument
+            let (woken, requeued) = futex::requeue(pid, addr, addr2, value, timeout);
+            Ok(if op == FUTEX_CMP_REQUEUE { woken + requeued } else { woken })
+        }
+        _ => Err(Errno::ENOSYS),
+    }
+}
+
+/// Wake up to `count` waiters on `addr` in `pid`, returning how many
+/// there were
+pub fn wake(pid: u32, addr: u64, count: u64) -> u64 {
+    futex::wake(pid, addr, count, futex::ANY)
+}
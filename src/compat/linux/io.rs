@@ -0,0 +1,413 @@
+//! Files and the console
+//!
+//! File descriptors are the process's handles (see
+//! [`crate::userspace::handle`]), so descriptors 0, 1 and 2 are the
+//! console's streams and `fork` shares the rest. Paths are resolved in
+//! [`super::fs`]. Close-on-exec is not kept, and pipes and socket pairs
+//! always block.
+
+use core::mem::size_of;
+
+use crate::compat::pipe::{self, End};
+use crate::compat::pty::{self, Termios, WinSize};
+use crate::compat::{fat32, vfs};
+use crate::gpu::bo;
+use crate::tagfs;
+use crate::userspace::console;
+use crate::userspace::handle::{self, Object, MAX_HANDLES};
+use crate::userspace::process;
+use crate::userspace::syscall::{SYS_READ, SYS_WRITE};
+use crate::userspace::uaccess::{self, Access};
+use crate::userspace::usermode::{self, Exit, UserContext};
+
+use super::fs::path_ino;
+use super::{native, Errno};
+
+/// Access modes and flags `F_GETFL` reports
+const O_RDONLY: u64 = 0;
+const O_WRONLY: u64 = 1;
+const O_RDWR: u64 = 2;
+const O_DIRECTORY: u64 = 0o200000;
+const O_CLOEXEC: u64 = 0o2000000;
+
+/// `socketpair` domain, type and flag
+const AF_UNIX: u64 = 1;
+const SOCK_STREAM: u64 = 1;
+const SOCK_CLOEXEC: u64 = O_CLOEXEC;
+
+const SEEK_SET: u64 = 0;
+const SEEK_CUR: u64 = 1;
+const SEEK_END: u64 = 2;
+
+/// `fcntl` commands
+const F_DUPFD: u64 = 0;
+const F_GETFD: u64 = 1;
+const F_SETFD: u64 = 2;
+const F_GETFL: u64 = 3;
+const F_SETFL: u64 = 4;
+const F_DUPFD_CLOEXEC: u64 = 1030;
+
+/// Terminal `ioctl` requests
+const TCGETS: u64 = 0x5401;
+const TCSETS: u64 = 0x5402;
+const TCSETSW: u64 = 0x5403;
+const TCSETSF: u64 = 0x5404;
+const TIOCSCTTY: u64 = 0x540e;
+const TIOCGPGRP: u64 = 0x540f;
+const TIOCSPGRP: u64 = 0x5410;
+const TIOCGWINSZ: u64 = 0x5413;
+const TIOCSWINSZ: u64 = 0x5414;
+const TIOCGPTN: u64 = 0x8004_5430;
+const TIOCSPTLCK: u64 = 0x4004_5431;
+
+/// Vectors one `readv` or `writev` takes
+const IOV_MAX: u64 = 1024;
+
+/// File types of `st_mode`
+const S_IFIFO: u32 = 0o010000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFSOCK: u32 = 0o140000;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct IoVec {
+    base: u64,
+    len: u64,
+}
+
+/// `struct stat`
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Stat {
+    dev: u64,
+    ino: u64,
+    nlink: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    pad: u32,
+    rdev: u64,
+    size: i64,
+    blksize: i64,
+    blocks: i64,
+    /// Access, modification and change times, as seconds and nanoseconds
+    times: [u64; 6],
+    unused: [i64; 3],
+}
+
+/// fd, vectors, count; stops at the first short write
+pub fn writev(context: &mut UserContext) -> Result<u64, Errno> {
+    let [fd, iov, count, ..] = context.args();
+    if count > IOV_MAX {
+        return Err(Errno::EINVAL);
+    }
+    let object = handle::get(process::current(), fd)?;
+    let mut total = 0;
+    for i in 0..count {
+        let vector: IoVec = uaccess::read_value(iov + i * size_of::<IoVec>() as u64)?;
+        if vector.len == 0 {
+            continue;
+        }
+        let written = match object {
+            // Waiting repeats the whole call, so it cannot go through `native`
+            Object::Pipe { write: Some(_), .. } | Object::Pty { .. } => write_waiting(object, vector),
+            _ => native(SYS_WRITE, context, [fd, vector.base, vector.len]).map(Some),
+        };
+        let written = match written {
+            Ok(Some(written)) => written,
+            Ok(None) if total > 0 => break,
+            Ok(None) => usermode::leave(Exit::Yielded(context.restart())),
+            Err(_) if total > 0 => break,
+            Err(e) => return Err(e),
+        };
+        total += written;
+        if written < vector.len {
+            break;
+        }
+    }
+    Ok(total)
+}
+
+/// fd, vectors, count; reads into the first vector with room, which a
+/// short read allows
+pub fn readv(context: &mut UserContext) -> Result<u64, Errno> {
+    let [fd, iov, count, ..] = context.args();
+    if count > IOV_MAX {
+        return Err(Errno::EINVAL);
+    }
+    let mut vector = IoVec { base: 0, len: 0 };
+    for i in 0..count {
+        vector = uaccess::read_value(iov + i * size_of::<IoVec>() as u64)?;
+        if vector.len != 0 {
+            break;
+        }
+    }
+    let pid = process::current();
+    let access = Access::open();
+    match handle::get(pid, fd)? {
+        // Waiting repeats the whole call, so it cannot go through `native`
+        Object::Console(console::STDIN) => {
+            let buffer = uaccess::user_slice_mut(&access, vector.base, vector.len as usize)?;
+            match console::read(pid, buffer) {
+                Some(read) => Ok(read as u64),
+                None => usermode::leave(Exit::Blocked(context.restart())),
+            }
+        }
+        Object::Pipe { read: Some(pipe), .. } => {
+            let buffer = uaccess::user_slice_mut(&access, vector.base, vector.len as usize)?;
+            match pipe::read(pipe, buffer)? {
+                Some(read) => Ok(read as u64),
+                None => usermode::leave(Exit::Yielded(context.restart())),
+            }
+        }
+        Object::Pty { pty, end } => {
+            let buffer = uaccess::user_slice_mut(&access, vector.base, vector.len as usize)?;
+            match pty::read(pty, end, buffer)? {
+                Some(read) => Ok(read as u64),
+                None => usermode::leave(Exit::Yielded(context.restart())),
+            }
+        }
+        Object::Timer { .. } => Err(Errno::EINVAL),
+        _ => native(SYS_READ, context, [fd, vector.base, vector.len]),
+    }
+}
+
+/// Write one vector to a pipe or pseudo-terminal: how many bytes fit, or
+/// `None` if it is full
+fn write_waiting(object: Object, vector: IoVec) -> Result<Option<u64>, Errno> {
+    let access = Access::open();
+    let data = uaccess::user_slice(&access, vector.base, vector.len as usize)?;
+    let written = match object {
+        Object::Pipe { write: Some(pipe), .. } => pipe::write(pipe, process::current(), data)?,
+        Object::Pty { pty, end } => pty::write(pty, end, data)?,
+        _ => return Err(Errno::EBADF),
+    };
+    Ok(written.map(|written| written as u64))
+}
+
+/// Give the caller handles to `first` and `second`, writing them to `addr`
+/// as two `int`s
+fn open_pair(addr: u64, first: Object, second: Object) -> Result<u64, Errno> {
+    let pid = process::current();
+    let first = handle::open(pid, first).inspect_err(|_| second.release())?;
+    let second = match handle::open(pid, second) {
+        Ok(fd) => fd,
+        Err(e) => {
+            let _ = handle::close(pid, first);
+            return Err(e.into());
+        }
+    };
+    if let Err(e) = uaccess::write_value(addr, &[first as i32, second as i32]) {
+        let _ = handle::close(pid, first);
+        let _ = handle::close(pid, second);
+        return Err(e.into());
+    }
+    Ok(0)
+}
+
+/// fds out, flags (`pipe2` only); only `O_CLOEXEC` is taken
+pub fn pipe(context: &mut UserContext) -> Result<u64, Errno> {
+    let [addr, flags, ..] = context.args();
+    if context.number() == super::PIPE2 && flags & !O_CLOEXEC != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let pipe = pipe::create()?;
+    let read = Object::Pipe {
+        read: Some(pipe),
+        write: None,
+    };
+    let write = Object::Pipe {
+        read: None,
+        write: Some(pipe),
+    };
+    open_pair(addr, read, write)
+}
+
+/// domain, type, protocol, fds out; a Unix stream socket pair is two
+/// pipes, one each way
+pub fn socketpair(context: &mut UserContext) -> Result<u64, Errno> {
+    let [domain, kind, protocol, addr, ..] = context.args();
+    if domain != AF_UNIX {
+        return Err(Errno::EAFNOSUPPORT);
+    }
+    if protocol != 0 {
+        return Err(Errno::EPROTONOSUPPORT);
+    }
+    if kind & !SOCK_CLOEXEC != SOCK_STREAM {
+        return Err(Errno::EOPNOTSUPP);
+    }
+    let there = pipe::create()?;
+    let back = match pipe::create() {
+        Ok(pipe) => pipe,
+        Err(e) => {
+            pipe::close(there, End::Read);
+            pipe::close(there, End::Write);
+            return Err(e.into());
+        }
+    };
+    let first = Object::Pipe {
+        read: Some(back),
+        write: Some(there),
+    };
+    let second = Object::Pipe {
+        read: Some(there),
+        write: Some(back),
+    };
+    open_pair(addr, first, second)
+}
+
+/// fd, offset, whence; a directory can only be rewound
+pub fn lseek(context: &mut UserContext) -> Result<u64, Errno> {
+    let [fd, by, whence, ..] = context.args();
+    let pid = process::current();
+    let end = match handle::get(pid, fd)? {
+        Object::File { id, .. } => tagfs::tagfs_meta(id).map_or(0, |meta| meta.size as u64),
+        Object::Buffer { bo, .. } => bo::bo_framebuffer(bo).map_or(0, |fb| fb.size() as u64),
+        Object::Staged { slot, .. } => vfs::staged_len(slot),
+        Object::Fat { volume, entry, .. } => fat32::size(volume, entry)?,
+        Object::Directory { .. } if whence == SEEK_SET && by == 0 => {
+            handle::update(pid, fd, |object| {
+                if let Object::Directory { cursor, .. } = object {
+                    cursor.clear();
+                }
+            })?;
+            return Ok(0);
+        }
+        Object::Directory { .. } => return Err(Errno::EINVAL),
+        Object::Null => return Ok(0),
+        _ => return Err(Errno::ESPIPE),
+    };
+    handle::update(pid, fd, |object| {
+        let Some(offset) = object.offset_mut() else {
+            return Err(Errno::ESPIPE);
+        };
+        let from = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => *offset,
+            SEEK_END => end,
+            _ => return Err(Errno::EINVAL),
+        };
+        *offset = from.checked_add_signed(by as i64).ok_or(Errno::EINVAL)?;
+        Ok(*offset)
+    })?
+}
+
+fn stat_of(object: Object) -> Stat {
+    let (mode, size, ino) = match object {
+        Object::Console(_) => (S_IFCHR | 0o620, 0, 0),
+        Object::File { id, .. } => (S_IFREG | 0o644, tagfs::tagfs_meta(id).map_or(0, |meta| meta.size as u64), id),
+        Object::Staged { slot, .. } => (S_IFREG | 0o644, vfs::staged_len(slot), 0),
+        Object::Fat { volume, entry, .. } => {
+            (S_IFREG | 0o644, fat32::size(volume, entry).unwrap_or(0), fat32::ino(volume, entry))
+        }
+        Object::Directory { path, .. } => (S_IFDIR | 0o755, 0, path_ino(path.split('/'))),
+        Object::Null => (S_IFCHR | 0o666, 0, path_ino(["dev", "null"].into_iter())),
+        Object::Channel(channel) => (S_IFIFO | 0o600, 0, channel),
+        Object::Pipe {
+            read: Some(_),
+            write: Some(_),
+        } => (S_IFSOCK | 0o600, 0, 0),
+        Object::Pipe { .. } => (S_IFIFO | 0o600, 0, 0),
+        Object::Pty { .. } => (S_IFCHR | 0o620, 0, 0),
+        Object::Ptmx => (S_IFCHR | 0o666, 0, path_ino(["dev", "ptmx"].into_iter())),
+        Object::Buffer { bo, .. } => (S_IFREG | 0o600, bo::bo_framebuffer(bo).map_or(0, |fb| fb.size() as u64), bo.0),
+        Object::Timer { .. } => (S_IFCHR | 0o400, 0, 0),
+    };
+    Stat {
+        ino,
+        nlink: 1,
+        mode,
+        size: size as i64,
+        blksize: 4096,
+        blocks: size.div_ceil(512) as i64,
+        ..Stat::default()
+    }
+}
+
+pub fn write_stat(addr: u64, object: Object) -> Result<u64, Errno> {
+    uaccess::write_value(addr, &stat_of(object))?;
+    Ok(0)
+}
+
+/// fd, stat out
+pub fn fstat(context: &mut UserContext) -> Result<u64, Errno> {
+    let [fd, addr, ..] = context.args();
+    write_stat(addr, handle::get(process::current(), fd)?)
+}
+
+/// fd, request, argument; the console answers as a terminal with the
+/// default settings, as the serial line it is has no size of its own, and
+/// a pseudo-terminal with settings and a size either end can change. Only
+/// a master numbers and unlocks its slave. There are no process groups,
+/// so a terminal's is the caller's own.
+pub fn ioctl(context: &mut UserContext) -> Result<u64, Errno> {
+    let [fd, request, arg, ..] = context.args();
+    let pid = process::current();
+    let pty = match handle::get(pid, fd)? {
+        Object::Console(_) => None,
+        Object::Pty { pty, end } => Some((pty, end)),
+        _ => return Err(Errno::ENOTTY),
+    };
+    match (request, pty) {
+        (TCGETS, None) => uaccess::write_value(arg, &Termios::default())?,
+        (TCGETS, Some((pty, _))) => uaccess::write_value(arg, &pty::termios(pty)?)?,
+        (TCSETS | TCSETSW | TCSETSF, Some((pty, _))) => {
+            pty::set_termios(pty, uaccess::read_value(arg)?, request == TCSETSF)?
+        }
+        (TIOCGWINSZ, None) => uaccess::write_value(arg, &WinSize::default())?,
+        (TIOCGWINSZ, Some((pty, _))) => uaccess::write_value(arg, &pty::size(pty)?)?,
+        (TIOCSWINSZ, Some((pty, _))) => pty::set_size(pty, uaccess::read_value(arg)?)?,
+        (TIOCGPTN, Some((pty, pty::End::Master))) => uaccess::write_value(arg, &(pty as u32))?,
+        (TIOCSPTLCK, Some((pty, pty::End::Master))) => {
+            let locked: i32 = uaccess::read_value(arg)?;
+            pty::set_locked(pty, locked != 0)?
+        }
+        (TIOCGPGRP, Some(_)) => uaccess::write_value(arg, &(pid as i32))?,
+        (TIOCSCTTY | TIOCSPGRP, Some(_)) => {}
+        _ => return Err(Errno::ENOTTY),
+    }
+    Ok(0)
+}
+
+/// fd
+pub fn dup(context: &mut UserContext) -> Result<u64, Errno> {
+    Ok(handle::dup(process::current(), context.rdi, None)?)
+}
+
+/// fd, target, flags
+pub fn dup3(context: &mut UserContext) -> Result<u64, Errno> {
+    let [fd, target, ..] = context.args();
+    if fd == target {
+        return Err(Errno::EINVAL);
+    }
+    Ok(handle::dup(process::current(), fd, Some(target))?)
+}
+
+/// fd, command, argument
+pub fn fcntl(context: &mut UserContext) -> Result<u64, Errno> {
+    let [fd, command, arg, ..] = context.args();
+    let pid = process::current();
+    let object = handle::get(pid, fd)?;
+    match command {
+        F_DUPFD | F_DUPFD_CLOEXEC => {
+            let target = (arg..MAX_HANDLES as u64)
+                .find(|target| handle::get(pid, *target).is_err())
+                .ok_or(Errno::EMFILE)?;
+            Ok(handle::dup(pid, fd, Some(target))?)
+        }
+        F_GETFD | F_SETFD | F_SETFL => Ok(0),
+        F_GETFL => Ok(match object {
+            Object::Console(console::STDIN) | Object::File { .. } | Object::Timer { .. } => O_RDONLY,
+            Object::Console(_) => O_WRONLY,
+            Object::Directory { .. } => O_RDONLY | O_DIRECTORY,
+            Object::Fat { write, .. } => if write { O_RDWR } else { O_RDONLY },
+            Object::Pipe { write: None, .. } => O_RDONLY,
+            Object::Pipe { read: None, .. } => O_WRONLY,
+            Object::Pipe { .. } | Object::Pty { .. } | Object::Ptmx => O_RDWR,
+            Object::Channel(_) | Object::Buffer { .. } | Object::Staged { .. } | Object::Null => O_RDWR,
+        }),
+        _ => Err(Errno::EINVAL),
+    }
+}
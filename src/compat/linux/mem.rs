@@ -0,0 +1,190 @@
+//! Memory mappings
+//!
+//! `mmap` places mappings it chooses the address of in a region of their
+//! own, between the shared objects and the thread stacks, and maps their
+//! pages at once. A file mapping is a private copy of the object's bytes,
+//! shared or not: what is written to it never reaches the file.
+//! `PROT_NONE` pages stay mapped but out of the program's reach, which is
+//! what guard pages need.
+
+use x86_64::structures::paging::{Page, PageTableFlags as Flags};
+use x86_64::VirtAddr;
+
+use crate::tagfs;
+use crate::userspace::address_space::{AddressSpace, USER_END, USER_START};
+use crate::userspace::handle::{self, Object};
+use crate::userspace::process;
+use crate::userspace::usermode::UserContext;
+
+use super::Errno;
+
+/// Where mappings go when the program does not choose
+const MMAP_START: u64 = 0x50_0000_0000;
+const MMAP_END: u64 = 0x60_0000_0000;
+
+const PROT_WRITE: u64 = 2;
+const PROT_EXEC: u64 = 4;
+/// Every protection bit there is; `PROT_READ` is 1
+const PROT_ALL: u64 = 7;
+
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+const MAP_FIXED_NOREPLACE: u64 = 0x10_0000;
+
+fn page_up(len: u64) -> Option<u64> {
+    Some(len.checked_add(0xfff)? & !0xfff)
+}
+
+fn pages(start: u64, len: u64) -> impl Iterator<Item = Page> {
+    (start..start + len).step_by(4096).map(|addr| Page::containing_address(VirtAddr::new(addr)))
+}
+
+/// Page flags for protection bits
+fn flags(prot: u64) -> Flags {
+    let mut flags = Flags::PRESENT;
+    if prot & PROT_ALL != 0 {
+        flags |= Flags::USER_ACCESSIBLE;
+    }
+    if prot & PROT_WRITE != 0 {
+        flags |= Flags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= Flags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// Check a range the program names, returning its length in whole pages
+fn range(addr: u64, len: u64) -> Result<u64, Errno> {
+    let len = page_up(len).ok_or(Errno::EINVAL)?;
+    let end = addr.checked_add(len).ok_or(Errno::EINVAL)?;
+    if !addr.is_multiple_of(4096) || len == 0 || addr < USER_START || end > USER_END {
+        return Err(Errno::EINVAL);
+    }
+    Ok(len)
+}
+
+fn is_free(space: &AddressSpace, start: u64, len: u64) -> bool {
+    pages(start, len).all(|page| space.flags(page.start_address()).is_none())
+}
+
+/// The lowest free range of `len` bytes in the mapping region, at or
+/// after `hint` if that lies in it
+fn find_free(space: &AddressSpace, hint: u64, len: u64) -> Option<u64> {
+    let mut start = if (MMAP_START..MMAP_END).contains(&hint) { hint & !0xfff } else { MMAP_START };
+    let mut free = 0;
+    let mut addr = start;
+    while start + len <= MMAP_END {
+        if free == len {
+            return Some(start);
+        }
+        if space.flags(VirtAddr::new(addr)).is_some() {
+            start = addr + 4096;
+            free = 0;
+        } else {
+            free += 4096;
+        }
+        addr += 4096;
+    }
+    None
+}
+
+fn unmap(space: &mut AddressSpace, start: u64, len: u64) {
+    for page in pages(start, len) {
+        if space.flags(page.start_address()).is_some() {
+            let _ = space.unmap(page);
+        }
+    }
+}
+
+/// Copy the object behind `fd` from `offset` into the pages at `start`
+fn fill(space: &mut AddressSpace, fd: u64, offset: u64, start: u64, len: u64) -> Result<(), Errno> {
+    let Object::File { id, .. } = handle::get(process::current(), fd)? else {
+        return Err(Errno::ENODEV);
+    };
+    let mut buffer = [0u8; 4096];
+    for done in (0..len).step_by(4096) {
+        let read = tagfs::tagfs_read(id, offset + done, &mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        space.write(VirtAddr::new(start + done), &buffer[..read]).map_err(|_| Errno::EFAULT)?;
+    }
+    Ok(())
+}
+
+/// address, length, protection, flags, fd, offset
+pub fn mmap(context: &mut UserContext) -> Result<u64, Errno> {
+    let [addr, len, prot, map_flags, fd, offset] = context.args();
+    if offset % 4096 != 0 || len == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let mut space = AddressSpace::active();
+    let fixed = map_flags & (MAP_FIXED | MAP_FIXED_NOREPLACE) != 0;
+    let (start, len) = if fixed {
+        let len = range(addr, len)?;
+        if map_flags & MAP_FIXED_NOREPLACE != 0 && !is_free(&space, addr, len) {
+            return Err(Errno::EEXIST);
+        }
+        unmap(&mut space, addr, len);
+        (addr, len)
+    } else {
+        let len = page_up(len).ok_or(Errno::ENOMEM)?;
+        (find_free(&space, addr, len).ok_or(Errno::ENOMEM)?, len)
+    };
+
+    for (i, page) in pages(start, len).enumerate() {
+        if space.map(page, flags(PROT_WRITE)).is_err() {
+            unmap(&mut space, start, i as u64 * 4096);
+            return Err(Errno::ENOMEM);
+        }
+    }
+    let filled = match map_flags & MAP_ANONYMOUS {
+        0 => fill(&mut space, fd, offset, start, len),
+        _ => Ok(()),
+    };
+    if let Err(e) = filled {
+        unmap(&mut space, start, len);
+        return Err(e);
+    }
+    for page in pages(start, len) {
+        let _ = space.protect(page, flags(prot));
+    }
+    Ok(start)
+}
+
+/// address, length
+pub fn munmap(context: &mut UserContext) -> Result<u64, Errno> {
+    let [addr, len, ..] = context.args();
+    let len = range(addr, len)?;
+    unmap(&mut AddressSpace::active(), addr, len);
+    Ok(0)
+}
+
+/// address, length, protection; every page must be mapped
+pub fn mprotect(context: &mut UserContext) -> Result<u64, Errno> {
+    let [addr, len, prot, ..] = context.args();
+    let len = range(addr, len)?;
+    let mut space = AddressSpace::active();
+    if !pages(addr, len).all(|page| space.flags(page.start_address()).is_some()) {
+        return Err(Errno::ENOMEM);
+    }
+    for page in pages(addr, len) {
+        space.protect(page, flags(prot)).map_err(|_| Errno::ENOMEM)?;
+    }
+    Ok(0)
+}
+
+/// address, length, advice; advice is only ever a hint
+pub fn madvise(_context: &mut UserContext) -> Result<u64, Errno> {
+    Ok(0)
+}
+
+/// address; moves the break there if it can, and returns the break
+pub fn brk(context: &mut UserContext) -> Result<u64, Errno> {
+    let tid = process::current_thread();
+    match process::set_break(tid, context.rdi) {
+        Ok(end) => Ok(end),
+        Err(_) => Ok(process::set_break(tid, 0)?),
+    }
+}
@@ -0,0 +1,238 @@
+//! Linux system calls
+//!
+//! A process with the Linux personality (see [`Personality`]) makes its
+//! system calls with Linux's x86-64 numbers and gets back a value or a
+//! negated `errno` in `rax`. Each number is translated here: a call whose
+//! arguments mean the same as a native call's goes straight to it, and the
+//! rest are emulated over the same handles, address space and process
+//! table the native calls use. A number with no translation fails with
+//! `ENOSYS`, as on a Linux kernel built without that call.
+//!
+//! That covers what a statically linked program and its C library use:
+//! files and directories through the POSIX view of TagFS (see
+//! [`crate::compat::vfs`]), the console, pipes and socket pairs (see
+//! [`crate::compat::pipe`]), pseudo-terminals (see [`crate::compat::pty`]),
+//! anonymous and file mappings, threads with futexes, and processes.
+//! Signals are accepted and never delivered, so writing to a pipe nothing
+//! reads only fails with `EPIPE`.
+//!
+//! A program branded for Linux gets the personality when it is loaded,
+//! and starts with the stack Linux would give it (see [`exec`]), so an
+//! unmodified static binary runs as it is.
+//!
+//! [`Personality`]: crate::userspace::process::Personality
+
+pub mod exec;
+mod fs;
+mod futex;
+mod io;
+mod mem;
+mod system;
+mod task;
+
+use super::errno::Errno;
+use crate::userspace::syscall;
+use crate::userspace::uaccess::{self, Access};
+use crate::userspace::usermode::UserContext;
+
+type Handler = fn(&mut UserContext) -> Result<u64, Errno>;
+
+/// What a Linux call number is translated to
+enum Call {
+    /// The native call with this number, which takes the same arguments
+    Native(u64),
+    Emulated(Handler),
+}
+
+/// Linux call numbers
+const READ: u64 = 0;
+const WRITE: u64 = 1;
+const OPEN: u64 = 2;
+const CLOSE: u64 = 3;
+const STAT: u64 = 4;
+const FSTAT: u64 = 5;
+const LSTAT: u64 = 6;
+const LSEEK: u64 = 8;
+const MMAP: u64 = 9;
+const MPROTECT: u64 = 10;
+const MUNMAP: u64 = 11;
+const BRK: u64 = 12;
+const RT_SIGACTION: u64 = 13;
+const RT_SIGPROCMASK: u64 = 14;
+const IOCTL: u64 = 16;
+const READV: u64 = 19;
+const WRITEV: u64 = 20;
+const ACCESS: u64 = 21;
+const PIPE: u64 = 22;
+const SCHED_YIELD: u64 = 24;
+const MADVISE: u64 = 28;
+const DUP: u64 = 32;
+const DUP2: u64 = 33;
+const NANOSLEEP: u64 = 35;
+const GETPID: u64 = 39;
+const SOCKETPAIR: u64 = 53;
+const CLONE: u64 = 56;
+const FORK: u64 = 57;
+const VFORK: u64 = 58;
+const EXECVE: u64 = 59;
+const EXIT: u64 = 60;
+const WAIT4: u64 = 61;
+const KILL: u64 = 62;
+const UNAME: u64 = 63;
+const FCNTL: u64 = 72;
+const GETCWD: u64 = 79;
+const CHDIR: u64 = 80;
+const FCHDIR: u64 = 81;
+const RENAME: u64 = 82;
+const MKDIR: u64 = 83;
+const RMDIR: u64 = 84;
+const UNLINK: u64 = 87;
+const GETTIMEOFDAY: u64 = 96;
+const GETUID: u64 = 102;
+const GETGID: u64 = 104;
+const GETEUID: u64 = 107;
+const GETEGID: u64 = 108;
+const GETPPID: u64 = 110;
+const SIGALTSTACK: u64 = 131;
+const ARCH_PRCTL: u64 = 158;
+const REBOOT: u64 = 169;
+const GETTID: u64 = 186;
+const TIME: u64 = 201;
+const FUTEX: u64 = 202;
+const GETDENTS64: u64 = 217;
+const SET_TID_ADDRESS: u64 = 218;
+const CLOCK_GETTIME: u64 = 228;
+const CLOCK_NANOSLEEP: u64 = 230;
+const EXIT_GROUP: u64 = 231;
+const TGKILL: u64 = 234;
+const OPENAT: u64 = 257;
+const MKDIRAT: u64 = 258;
+const NEWFSTATAT: u64 = 262;
+const UNLINKAT: u64 = 263;
+const RENAMEAT: u64 = 264;
+const FACCESSAT: u64 = 269;
+const SET_ROBUST_LIST: u64 = 273;
+const DUP3: u64 = 292;
+const PIPE2: u64 = 293;
+const PRLIMIT64: u64 = 302;
+const RENAMEAT2: u64 = 316;
+const GETRANDOM: u64 = 318;
+
+fn translate(number: u64) -> Option<Call> {
+    use Call::{Emulated, Native};
+    Some(match number {
+        READ => Native(syscall::SYS_READ),
+        WRITE => Native(syscall::SYS_WRITE),
+        OPEN => Emulated(fs::open),
+        CLOSE => Native(syscall::SYS_CLOSE),
+        STAT | LSTAT => Emulated(fs::stat),
+        FSTAT => Emulated(io::fstat),
+        LSEEK => Emulated(io::lseek),
+        MMAP => Emulated(mem::mmap),
+        MPROTECT => Emulated(mem::mprotect),
+        MUNMAP => Emulated(mem::munmap),
+        BRK => Emulated(mem::brk),
+        RT_SIGACTION => Emulated(task::sigaction),
+        RT_SIGPROCMASK => Emulated(task::sigprocmask),
+        IOCTL => Emulated(io::ioctl),
+        READV => Emulated(io::readv),
+        WRITEV => Emulated(io::writev),
+        ACCESS => Emulated(fs::access),
+        PIPE | PIPE2 => Emulated(io::pipe),
+        SCHED_YIELD => Native(syscall::SYS_YIELD),
+        MADVISE => Emulated(mem::madvise),
+        DUP => Emulated(io::dup),
+        DUP2 => Native(syscall::SYS_DUP),
+        NANOSLEEP => Emulated(system::nanosleep),
+        GETPID => Native(syscall::SYS_GETPID),
+        SOCKETPAIR => Emulated(io::socketpair),
+        CLONE => Emulated(task::clone),
+        FORK | VFORK => Native(syscall::SYS_FORK),
+        EXECVE => Emulated(task::execve),
+        EXIT => Emulated(task::exit),
+        WAIT4 => Emulated(task::wait4),
+        KILL | TGKILL => Emulated(task::kill),
+        UNAME => Emulated(system::uname),
+        FCNTL => Emulated(io::fcntl),
+        GETCWD => Emulated(fs::getcwd),
+        CHDIR => Emulated(fs::chdir),
+        FCHDIR => Emulated(fs::fchdir),
+        RENAME => Emulated(fs::rename),
+        MKDIR => Emulated(fs::mkdir),
+        RMDIR => Emulated(fs::rmdir),
+        UNLINK => Emulated(fs::unlink),
+        GETTIMEOFDAY => Emulated(system::gettimeofday),
+        GETUID | GETGID | GETEUID | GETEGID => Emulated(system::id),
+        GETPPID => Emulated(task::getppid),
+        SIGALTSTACK => Emulated(task::sigaltstack),
+        ARCH_PRCTL => Emulated(task::arch_prctl),
+        REBOOT => Emulated(system::reboot),
+        GETTID => Native(syscall::SYS_GETTID),
+        TIME => Emulated(system::time),
+        FUTEX => Emulated(futex::futex),
+        GETDENTS64 => Emulated(fs::getdents64),
+        SET_TID_ADDRESS => Emulated(task::set_tid_address),
+        CLOCK_GETTIME => Emulated(system::clock_gettime),
+        CLOCK_NANOSLEEP => Emulated(system::clock_nanosleep),
+        EXIT_GROUP => Native(syscall::SYS_EXIT),
+        OPENAT => Emulated(fs::openat),
+        MKDIRAT => Emulated(fs::mkdirat),
+        NEWFSTATAT => Emulated(fs::newfstatat),
+        UNLINKAT => Emulated(fs::unlinkat),
+        RENAMEAT | RENAMEAT2 => Emulated(fs::renameat),
+        FACCESSAT => Emulated(fs::faccessat),
+        SET_ROBUST_LIST => Emulated(task::set_robust_list),
+        DUP3 => Emulated(io::dup3),
+        PRLIMIT64 => Emulated(system::prlimit64),
+        GETRANDOM => Emulated(system::getrandom),
+        _ => return None,
+    })
+}
+
+/// Make the Linux system call in `context`, leaving its result in `rax`
+pub fn dispatch(context: &mut UserContext) {
+    let result = match translate(context.number()) {
+        Some(Call::Native(number)) => syscall::call(number, context).map_err(Errno::from),
+        Some(Call::Emulated(handler)) => handler(context),
+        None => Err(Errno::ENOSYS),
+    };
+    context.rax = match result {
+        Ok(value) => value,
+        Err(e) => (-e.0) as u64,
+    };
+}
+
+/// Make native call `number` with `args` in place of the caller's first
+/// three
+///
+/// Only for calls that never block: one that had to would be repeated
+/// with the caller's own arguments.
+fn native(number: u64, context: &UserContext, args: [u64; 3]) -> Result<u64, Errno> {
+    let mut context = UserContext {
+        rdi: args[0],
+        rsi: args[1],
+        rdx: args[2],
+        ..*context
+    };
+    Ok(syscall::call(number, &mut context)?)
+}
+
+/// Longest path a call takes, NUL included
+const PATH_MAX: usize = 4096;
+
+/// A NUL-terminated string in user memory, such as a path, for as long as
+/// `access` is open
+fn user_cstr(access: &Access, addr: u64) -> Result<&str, Errno> {
+    let mut len = 0;
+    while len < PATH_MAX {
+        // Up to the end of the page, which is all that is known to be mapped
+        let at = addr.checked_add(len as u64).ok_or(Errno::EFAULT)?;
+        let chunk = (4096 - (at % 4096) as usize).min(PATH_MAX - len);
+        let bytes = uaccess::user_slice(access, at, chunk)?;
+        if let Some(end) = bytes.iter().position(|b| *b == 0) {
+            return Ok(uaccess::user_str(access, addr, len + end)?);
+        }
+        len += chunk;
+    }
+    Err(Errno::ENAMETOOLONG)
+}
@@ -0,0 +1,186 @@
+//! Time, identity and limits
+//!
+//! Every clock reads the kernel's clock source, as time since boot;
+//! sleeps yield until their deadline tick passes, so they last whole
+//! ticks. Every process runs as root.
+
+use spin::Mutex;
+
+use crate::capability::MAX_PROCESSES;
+use crate::kernel::{clocksource, random};
+use crate::scheduler;
+use crate::userspace::process;
+use crate::userspace::stack;
+use crate::userspace::syscall;
+use crate::userspace::uaccess::{self, Access};
+use crate::userspace::usermode::{self, Exit, UserContext};
+
+use super::futex::Timespec;
+use super::Errno;
+
+/// Length of each `struct utsname` field, NUL included
+const UTSNAME_FIELD: usize = 65;
+
+const TIMER_ABSTIME: u64 = 1;
+
+const RLIMIT_STACK: u64 = 3;
+
+/// `reboot` magic numbers, the second of which can be any of four dates,
+/// and commands
+const REBOOT_MAGIC: u64 = 0xFEE1_DEAD;
+const REBOOT_MAGIC2: [u64; 4] = [0x2812_1969, 0x0512_1996, 0x1604_1998, 0x2011_2000];
+const REBOOT_RESTART: u64 = 0x0123_4567;
+const REBOOT_HALT: u64 = 0xCDEF_0123;
+const REBOOT_POWER_OFF: u64 = 0x4321_FEDC;
+const RLIM_INFINITY: u64 = u64::MAX;
+
+/// Each thread's sleep, by thread ID: its process, its request and the
+/// tick it ends at
+static SLEEPS: Mutex<[Option<(u32, u64, u64)>; MAX_PROCESSES]> = Mutex::new([None; MAX_PROCESSES]);
+
+/// `struct rlimit`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Rlimit {
+    cur: u64,
+    max: u64,
+}
+
+/// buffer
+pub fn uname(context: &mut UserContext) -> Result<u64, Errno> {
+    let fields = ["Linux", "zen", "5.15.0-zen", "#1 Zen-OS", "x86_64", "(none)"];
+    let mut uts = [0u8; UTSNAME_FIELD * 6];
+    for (field, value) in uts.chunks_mut(UTSNAME_FIELD).zip(fields) {
+        field[..value.len()].copy_from_slice(value.as_bytes());
+    }
+    uaccess::copy_to_user(context.rdi, &uts)?;
+    Ok(0)
+}
+
+/// Time since boot as seconds and nanoseconds
+fn now() -> Timespec {
+    let nanoseconds = clocksource::nanoseconds();
+    Timespec {
+        sec: (nanoseconds / 1_000_000_000) as i64,
+        nsec: (nanoseconds % 1_000_000_000) as i64,
+    }
+}
+
+/// clock, time out
+pub fn clock_gettime(context: &mut UserContext) -> Result<u64, Errno> {
+    uaccess::write_value(context.rsi, &now())?;
+    Ok(0)
+}
+
+/// time out, time zone out
+pub fn gettimeofday(context: &mut UserContext) -> Result<u64, Errno> {
+    let [addr, zone, ..] = context.args();
+    let now = now();
+    if addr != 0 {
+        uaccess::write_value(addr, &[now.sec, now.nsec / 1000])?;
+    }
+    if zone != 0 {
+        uaccess::write_value(zone, &[0u32; 2])?;
+    }
+    Ok(0)
+}
+
+/// time out (may be 0); returns the seconds
+pub fn time(context: &mut UserContext) -> Result<u64, Errno> {
+    let sec = now().sec;
+    if context.rdi != 0 {
+        uaccess::write_value(context.rdi, &sec)?;
+    }
+    Ok(sec as u64)
+}
+
+/// Sleep for the time at `request`, or until it if `absolute` is set
+fn sleep(context: &UserContext, request: u64, absolute: bool) -> Result<u64, Errno> {
+    let (pid, tid) = (process::current(), process::current_thread());
+    let now = scheduler::ticks();
+    let mut sleeps = SLEEPS.lock();
+    let slot = sleeps.get_mut(tid as usize).ok_or(Errno::EINVAL)?;
+    let deadline = match *slot {
+        Some((p, r, deadline)) if p == pid && r == request => deadline,
+        _ => {
+            let ticks = uaccess::read_value::<Timespec>(request)?.ticks()?;
+            if absolute { ticks } else { now.saturating_add(ticks) }
+        }
+    };
+    if now >= deadline {
+        *slot = None;
+        return Ok(0);
+    }
+    *slot = Some((pid, request, deadline));
+    drop(sleeps);
+    usermode::leave(Exit::Yielded(context.restart()))
+}
+
+/// request, remainder out; the sleep is never cut short
+pub fn nanosleep(context: &mut UserContext) -> Result<u64, Errno> {
+    sleep(context, context.rdi, false)
+}
+
+/// clock, flags, request, remainder out
+pub fn clock_nanosleep(context: &mut UserContext) -> Result<u64, Errno> {
+    let [_, flags, request, ..] = context.args();
+    sleep(context, request, flags & TIMER_ABSTIME != 0)
+}
+
+/// The user and group IDs, real and effective
+pub fn id(_context: &mut UserContext) -> Result<u64, Errno> {
+    Ok(0)
+}
+
+/// magic, second magic, command; powering off and restarting go to the
+/// native call, and halting powers off too
+pub fn reboot(context: &mut UserContext) -> Result<u64, Errno> {
+    let [magic, magic2, command, ..] = context.args();
+    if magic != REBOOT_MAGIC || !REBOOT_MAGIC2.contains(&magic2) {
+        return Err(Errno::EINVAL);
+    }
+    let action = match command {
+        REBOOT_POWER_OFF | REBOOT_HALT => syscall::POWER_OFF,
+        REBOOT_RESTART => syscall::POWER_REBOOT,
+        _ => return Err(Errno::EINVAL),
+    };
+    context.rdi = action;
+    syscall::call(syscall::SYS_POWER, context).map_err(Errno::from)
+}
+
+/// process (0 for the caller), resource, new limit (may be 0), old limit
+/// out (may be 0); only the stack has a limit
+pub fn prlimit64(context: &mut UserContext) -> Result<u64, Errno> {
+    let [pid, resource, new, old, ..] = context.args();
+    if pid != 0 && pid != process::current() as u64 {
+        return Err(Errno::EPERM);
+    }
+    let tid = process::current_thread();
+    let limit = match resource {
+        RLIMIT_STACK => Rlimit {
+            cur: process::set_stack_limit(tid, 0)?,
+            max: stack::MAX_LIMIT,
+        },
+        _ => Rlimit {
+            cur: RLIM_INFINITY,
+            max: RLIM_INFINITY,
+        },
+    };
+    if old != 0 {
+        uaccess::write_value(old, &limit)?;
+    }
+    if new != 0 && resource == RLIMIT_STACK {
+        let new: Rlimit = uaccess::read_value(new)?;
+        process::set_stack_limit(tid, new.cur.min(stack::MAX_LIMIT))?;
+    }
+    Ok(0)
+}
+
+/// buffer, length, flags
+pub fn getrandom(context: &mut UserContext) -> Result<u64, Errno> {
+    let [addr, len, ..] = context.args();
+    let access = Access::open();
+    let buffer = uaccess::user_slice_mut(&access, addr, len as usize)?;
+    random::fill(buffer);
+    Ok(len)
+}
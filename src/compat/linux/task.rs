@@ -0,0 +1,259 @@
+//! Threads, processes and signals
+//!
+//! `clone` with `CLONE_THREAD` starts a detached thread in the caller's
+//! process on the stack and TLS pointer it is given; anything else is a
+//! fork, which copies the address space even when `CLONE_VM` asks to
+//! share it. A thread that set a child TID address has it cleared and
+//! woken as a futex when it exits, which is how `pthread_join` waits.
+//!
+//! Signal dispositions and masks are accepted and read back empty, and
+//! nothing is ever delivered: `kill` only checks that its target exists.
+//! A process stopped by an exception is reported to `wait4` as killed by
+//! the signal Linux would have sent.
+
+use spin::Mutex;
+use x86_64::registers::model_specific::FsBase;
+use x86_64::VirtAddr;
+
+use crate::capability::{self, Permission, MAX_PROCESSES};
+use crate::userspace::address_space::USER_END;
+use crate::userspace::args::Strings;
+use crate::userspace::console;
+use crate::userspace::handle::Object;
+use crate::userspace::process::{self, FAULT_STATUS};
+use crate::userspace::uaccess::{self, Access};
+use crate::userspace::usermode::{self, Exit, UserContext};
+
+use super::fs::{self, AT_FDCWD};
+use super::{futex, user_cstr, Errno};
+
+const CLONE_VM: u64 = 0x100;
+const CLONE_THREAD: u64 = 0x1_0000;
+const CLONE_SETTLS: u64 = 0x8_0000;
+const CLONE_PARENT_SETTID: u64 = 0x10_0000;
+const CLONE_CHILD_CLEARTID: u64 = 0x20_0000;
+const CLONE_CHILD_SETTID: u64 = 0x100_0000;
+
+const WNOHANG: u64 = 1;
+
+const ARCH_SET_FS: u64 = 0x1002;
+const ARCH_GET_FS: u64 = 0x1003;
+
+/// Signals exceptions are reported as
+const SIGINT: u32 = 2;
+const SIGILL: u32 = 4;
+const SIGTRAP: u32 = 5;
+const SIGFPE: u32 = 8;
+const SIGSEGV: u32 = 11;
+
+/// `ss_flags` of a thread with no alternate signal stack
+const SS_DISABLE: u32 = 2;
+
+/// Size of `struct rusage`
+const RUSAGE_SIZE: usize = 144;
+
+/// Each thread's child TID address, by thread ID
+static CLEAR_TID: Mutex<[u64; MAX_PROCESSES]> = Mutex::new([0; MAX_PROCESSES]);
+
+/// `stack_t`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SignalStack {
+    sp: u64,
+    flags: u32,
+    size: u64,
+}
+
+/// flags, stack, parent TID out, child TID address, TLS pointer
+pub fn clone(context: &mut UserContext) -> Result<u64, Errno> {
+    let [flags, stack, parent_tid, child_tid, tls, ..] = context.args();
+    let tid = process::current_thread();
+    let child = UserContext {
+        rax: 0,
+        rsp: if stack != 0 { stack } else { context.rsp },
+        ..*context
+    };
+    let new = if flags & CLONE_THREAD != 0 {
+        if flags & CLONE_VM == 0 {
+            return Err(Errno::EINVAL);
+        }
+        if flags & CLONE_SETTLS != 0 && tls >= USER_END {
+            return Err(Errno::EFAULT);
+        }
+        let fs_base = match flags & CLONE_SETTLS {
+            0 => FsBase::read().as_u64(),
+            _ => tls,
+        };
+        let new = process::clone_thread(tid, child, Some(fs_base))?;
+        if flags & CLONE_CHILD_SETTID != 0 {
+            uaccess::write_value(child_tid, &new)?;
+        }
+        new
+    } else {
+        process::fork(tid, &child)?
+    };
+    if flags & CLONE_PARENT_SETTID != 0 {
+        uaccess::write_value(parent_tid, &new)?;
+    }
+    if let Some(slot) = CLEAR_TID.lock().get_mut(new as usize) {
+        *slot = if flags & CLONE_CHILD_CLEARTID != 0 { child_tid } else { 0 };
+    }
+    Ok(new as u64)
+}
+
+/// address; returns the caller's thread ID
+pub fn set_tid_address(context: &mut UserContext) -> Result<u64, Errno> {
+    let tid = process::current_thread();
+    if let Some(slot) = CLEAR_TID.lock().get_mut(tid as usize) {
+        *slot = context.rdi;
+    }
+    Ok(tid as u64)
+}
+
+/// status; ends the calling thread, or the process if it is the last
+pub fn exit(context: &mut UserContext) -> Result<u64, Errno> {
+    let tid = process::current_thread();
+    let addr = CLEAR_TID.lock().get_mut(tid as usize).map_or(0, core::mem::take);
+    if addr != 0 && uaccess::write_value(addr, &0u32).is_ok() {
+        futex::wake(process::current(), addr, 1);
+    }
+    usermode::leave(Exit::ThreadExited(context.rdi as i32))
+}
+
+/// Copy a null-terminated array of string pointers
+fn user_strings(addr: u64) -> Result<Strings, Errno> {
+    let mut strings = Strings::new();
+    if addr == 0 {
+        return Ok(strings);
+    }
+    let access = Access::open();
+    for i in 0.. {
+        let string: u64 = uaccess::read_value(addr + i * 8)?;
+        if string == 0 {
+            break;
+        }
+        strings.push(user_cstr(&access, string)?).map_err(|_| Errno::E2BIG)?;
+    }
+    Ok(strings)
+}
+
+/// path, arguments, environment
+pub fn execve(context: &mut UserContext) -> Result<u64, Errno> {
+    let [path, argv, envp, ..] = context.args();
+    let pid = process::current();
+    capability::check_permission(pid, Permission::Execute)?;
+    let argv = user_strings(argv)?;
+    let envp = user_strings(envp)?;
+    let access = Access::open();
+    let Object::File { id, .. } = fs::resolve(AT_FDCWD, user_cstr(&access, path)?, 0)? else {
+        return Err(Errno::EACCES);
+    };
+    let tid = process::current_thread();
+    *context = process::exec(tid, id, &argv, &envp)?;
+    if let Some(slot) = CLEAR_TID.lock().get_mut(tid as usize) {
+        *slot = 0;
+    }
+    Ok(0)
+}
+
+/// A native exit status as `wait4` reports it
+fn wait_status(status: i32) -> u32 {
+    if status == console::INTERRUPT_STATUS {
+        return SIGINT;
+    }
+    if status < FAULT_STATUS {
+        return (status as u32 & 0xff) << 8;
+    }
+    // Page faults and stack overflows among the rest
+    match status - FAULT_STATUS {
+        0 | 16 | 19 => SIGFPE,
+        3 => SIGTRAP,
+        6 => SIGILL,
+        _ => SIGSEGV,
+    }
+}
+
+/// process (-1 or 0 for any child), status out, options, usage out
+pub fn wait4(context: &mut UserContext) -> Result<u64, Errno> {
+    let [child, status_addr, options, usage, ..] = context.args();
+    let child = match child as i64 {
+        child if child > 0 => child as u32,
+        _ => 0,
+    };
+    match process::reap(process::current(), child).map_err(|_| Errno::ECHILD)? {
+        Some((pid, status)) => {
+            if status_addr != 0 {
+                uaccess::write_value(status_addr, &wait_status(status))?;
+            }
+            if usage != 0 {
+                uaccess::write_value(usage, &[0u8; RUSAGE_SIZE])?;
+            }
+            Ok(pid as u64)
+        }
+        None if options & WNOHANG != 0 => Ok(0),
+        None => usermode::leave(Exit::Blocked(context.restart())),
+    }
+}
+
+/// process, signal (`tgkill`: process, thread, signal); signals are not
+/// delivered
+pub fn kill(context: &mut UserContext) -> Result<u64, Errno> {
+    // 0 and below name process groups, which are not kept
+    if (context.rdi as i64) > 0 && !process::is_running(context.rdi as u32) {
+        return Err(Errno::ESRCH);
+    }
+    Ok(0)
+}
+
+pub fn getppid(_context: &mut UserContext) -> Result<u64, Errno> {
+    Ok(process::parent(process::current()).unwrap_or(process::INIT) as u64)
+}
+
+/// code, address
+pub fn arch_prctl(context: &mut UserContext) -> Result<u64, Errno> {
+    let [code, addr, ..] = context.args();
+    match code {
+        ARCH_SET_FS if addr < USER_END => FsBase::write(VirtAddr::new(addr)),
+        ARCH_SET_FS => return Err(Errno::EPERM),
+        ARCH_GET_FS => uaccess::write_value(addr, &FsBase::read().as_u64())?,
+        _ => return Err(Errno::EINVAL),
+    }
+    Ok(0)
+}
+
+/// head, length; the list is not walked when the thread exits
+pub fn set_robust_list(_context: &mut UserContext) -> Result<u64, Errno> {
+    Ok(0)
+}
+
+/// signal, action, old action out, mask size
+pub fn sigaction(context: &mut UserContext) -> Result<u64, Errno> {
+    let [_, _, old, ..] = context.args();
+    if old != 0 {
+        uaccess::write_value(old, &[0u64; 4])?;
+    }
+    Ok(0)
+}
+
+/// how, set, old set out, set size
+pub fn sigprocmask(context: &mut UserContext) -> Result<u64, Errno> {
+    let [_, _, old, ..] = context.args();
+    if old != 0 {
+        uaccess::write_value(old, &0u64)?;
+    }
+    Ok(0)
+}
+
+/// stack, old stack out
+pub fn sigaltstack(context: &mut UserContext) -> Result<u64, Errno> {
+    let [_, old, ..] = context.args();
+    if old != 0 {
+        let disabled = SignalStack {
+            sp: 0,
+            flags: SS_DISABLE,
+            size: 0,
+        };
+        uaccess::write_value(old, &disabled)?;
+    }
+    Ok(0)
+}
@@ -1,7 +1,17 @@
-//! Compatibility layer - POSIX VFS shim and legacy filesystem drivers
+//! Compatibility layer - POSIX VFS shim, Linux system calls and legacy
+//! filesystem drivers
+
+pub mod errno;
+pub mod ext2;
+pub mod fat32;
+pub mod linux;
+pub mod partition;
+pub mod pipe;
+pub mod pty;
+pub mod vfs;
 
 /// Initialize compatibility layer
 pub fn init() {
-    // TODO: Set up POSIX VFS shim
-    // TODO: Load legacy filesystem drivers (FAT32, ext4, NTFS, APFS)
+    vfs::init();
+    // TODO: Load legacy filesystem drivers (ext4, NTFS, APFS)
 }
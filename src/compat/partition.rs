@@ -0,0 +1,76 @@
+//! Partition tables
+//!
+//! A foreign file system sits at the start of a device, as on a stick
+//! formatted without a partition table, or in one of its partitions, which
+//! an MBR lists, or a GPT behind a protective MBR. Drivers probe each place
+//! in turn for their own signature.
+
+use arrayvec::ArrayVec;
+
+use crate::storage::{self, StorageError};
+
+/// Places looked at on one device, its start included
+pub const MAX_STARTS: usize = 16;
+
+/// Sector size of partition tables
+const SECTOR: usize = 512;
+
+/// MBR partition type protecting a GPT disk
+const MBR_GPT: u8 = 0xee;
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Where a file system may start on `device`, in bytes: the start of the
+/// device, then each partition in table order
+pub fn starts(device: u32) -> Result<ArrayVec<u64, MAX_STARTS>, StorageError> {
+    let block = storage::info(device)?.block_size as u64;
+    let mut starts = ArrayVec::new();
+    starts.push(0);
+    let mut mbr = [0u8; SECTOR];
+    storage::read_raw(device, 0, &mut mbr)?;
+    if mbr[510..] != [0x55, 0xaa] {
+        return Ok(starts);
+    }
+    for partition in mbr[446..510].chunks_exact(16) {
+        let start = u32_at(partition, 8) as u64 * block;
+        match partition[4] {
+            MBR_GPT => return gpt(device, block, starts),
+            kind if kind != 0 && start != 0 => starts.push(start),
+            _ => {}
+        }
+    }
+    Ok(starts)
+}
+
+fn gpt(
+    device: u32,
+    block: u64,
+    mut starts: ArrayVec<u64, MAX_STARTS>,
+) -> Result<ArrayVec<u64, MAX_STARTS>, StorageError> {
+    let mut header = [0u8; SECTOR];
+    storage::read_raw(device, block, &mut header)?;
+    let table = u64_at(&header, 72) * block;
+    let size = u32_at(&header, 84) as u64;
+    let mut partition = [0u8; 128];
+    if &header[..8] != b"EFI PART" || size < partition.len() as u64 {
+        return Ok(starts);
+    }
+    for i in 0..u32_at(&header, 80) as u64 {
+        if starts.is_full() {
+            break;
+        }
+        storage::read_raw(device, table + i * size, &mut partition)?;
+        let start = u64_at(&partition, 32) * block;
+        // An unused entry has a zero type GUID
+        if partition[..16].iter().any(|b| *b != 0) && start != 0 {
+            starts.push(start);
+        }
+    }
+    Ok(starts)
+}
@@ -0,0 +1,146 @@
+//! Pipes
+//!
+//! A pipe is a byte stream over an IPC channel (see [`crate::ipc`]). A
+//! write is sent as messages of at most [`MAX_MESSAGE_SIZE`] bytes, so one
+//! no larger is never interleaved with another, and a read takes bytes
+//! from as many messages as it has room for, leaving the rest of the last
+//! one for the next read. A socket pair is two pipes, one each way.
+//!
+//! A pipe counts its open read and write ends, which handles hold (see
+//! [`crate::userspace::handle`]). Once every write end is closed, what is
+//! left is read and then end of file; once every read end is closed,
+//! writing fails. The channel is destroyed with the last end.
+
+use spin::Mutex;
+
+use crate::ipc::{self, IpcError, MessageHeader, MAX_MESSAGE_SIZE};
+
+/// Pipes open at once, a socket pair counting as two
+pub const MAX_PIPES: usize = 128;
+
+/// One end of a pipe
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum End {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy)]
+struct Pipe {
+    channel: u64,
+    readers: u32,
+    writers: u32,
+    /// Bytes of the message at the head of the channel already read
+    consumed: usize,
+}
+
+static PIPES: Mutex<[Option<Pipe>; MAX_PIPES]> = Mutex::new([None; MAX_PIPES]);
+
+/// Pipe errors
+#[derive(Debug)]
+pub enum PipeError {
+    TooManyPipes,
+    /// No read end is open
+    Broken,
+    Ipc(IpcError),
+}
+
+impl From<IpcError> for PipeError {
+    fn from(e: IpcError) -> Self {
+        PipeError::Ipc(e)
+    }
+}
+
+fn get(pipes: &mut [Option<Pipe>; MAX_PIPES], pipe: usize) -> Result<&mut Pipe, PipeError> {
+    pipes
+        .get_mut(pipe)
+        .and_then(Option::as_mut)
+        .ok_or(PipeError::Ipc(IpcError::InvalidChannel))
+}
+
+/// Make a pipe with one read end and one write end open
+pub fn create() -> Result<usize, PipeError> {
+    let mut pipes = PIPES.lock();
+    let pipe = pipes.iter().position(Option::is_none).ok_or(PipeError::TooManyPipes)?;
+    pipes[pipe] = Some(Pipe {
+        channel: ipc::create_channel()?,
+        readers: 1,
+        writers: 1,
+        consumed: 0,
+    });
+    Ok(pipe)
+}
+
+/// Close one end of `pipe`, and the pipe with its last end
+pub fn close(pipe: usize, end: End) {
+    let mut pipes = PIPES.lock();
+    let Ok(state) = get(&mut pipes, pipe) else {
+        return;
+    };
+    match end {
+        End::Read => state.readers = state.readers.saturating_sub(1),
+        End::Write => state.writers = state.writers.saturating_sub(1),
+    }
+    if state.readers == 0 && state.writers == 0 {
+        let _ = ipc::destroy_channel(state.channel);
+        pipes[pipe] = None;
+    }
+}
+
+/// Read from `pipe` into `buffer`: how many bytes, 0 at end of file, or
+/// `None` if it is empty while a write end is open
+pub fn read(pipe: usize, buffer: &mut [u8]) -> Result<Option<usize>, PipeError> {
+    let mut pipes = PIPES.lock();
+    let state = get(&mut pipes, pipe)?;
+    let mut read = 0;
+    let mut message = [0; MAX_MESSAGE_SIZE];
+    while read < buffer.len() {
+        let data = match ipc::msg_peek(state.channel, &mut message) {
+            Ok((_, len)) => &message[..len],
+            Err(IpcError::BufferEmpty) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let rest = &data[state.consumed..];
+        let len = rest.len().min(buffer.len() - read);
+        buffer[read..read + len].copy_from_slice(&rest[..len]);
+        read += len;
+        state.consumed += len;
+        if state.consumed == data.len() {
+            ipc::msg_recv(state.channel, &mut [])?;
+            state.consumed = 0;
+        }
+    }
+    if read == 0 && !buffer.is_empty() && state.writers > 0 {
+        return Ok(None);
+    }
+    Ok(Some(read))
+}
+
+/// Write `data` to `pipe` for process `sender`: how many bytes fit, or
+/// `None` if the pipe is full
+pub fn write(pipe: usize, sender: u32, data: &[u8]) -> Result<Option<usize>, PipeError> {
+    let mut pipes = PIPES.lock();
+    let state = get(&mut pipes, pipe)?;
+    if state.readers == 0 {
+        return Err(PipeError::Broken);
+    }
+    let mut written = 0;
+    for chunk in data.chunks(MAX_MESSAGE_SIZE) {
+        let header = MessageHeader {
+            id: 0,
+            sender,
+            receiver: 0,
+            length: chunk.len() as u32,
+            msg_type: 0,
+        };
+        match ipc::msg_send(state.channel, header, chunk) {
+            Ok(()) => written += chunk.len(),
+            Err(IpcError::BufferFull) => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if written == 0 && !data.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(written))
+}
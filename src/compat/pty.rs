@@ -0,0 +1,443 @@
+//! Pseudo-terminals
+//!
+//! A pseudo-terminal is a pair of ends: a program such as a terminal
+//! emulator holds the master, and what it hosts, a shell or an editor, has
+//! the slave as its terminal. What the master writes is the slave's input,
+//! through a line discipline its termios sets: in canonical mode input is
+//! edited a line at a time, with erase, kill and end of file, and echoed
+//! back to the master; otherwise each byte can be read as it arrives. What
+//! the slave writes is the master's to read, each newline as a carriage
+//! return and newline.
+//!
+//! `/dev/ptmx` opens the master of a new pseudo-terminal, whose slave is
+//! `/dev/pts/<n>` once unlocked (see [`super::vfs`]). Termios flags and
+//! control characters have Linux's values. No signal can be sent, so the
+//! interrupt, quit and suspend characters only discard the line being
+//! edited. Once every master is closed the slave reads end of file and
+//! cannot be written; once every slave is closed, after one was opened,
+//! the master reads end of file.
+
+use arrayvec::ArrayVec;
+use heapless::Deque;
+use spin::Mutex;
+
+/// Pseudo-terminals open at once
+pub const MAX_PTYS: usize = 16;
+
+/// Longest line edited in canonical mode; bytes past it are dropped
+const MAX_CANON: usize = 255;
+
+/// Bytes waiting in either direction
+const BUFFER: usize = 4096;
+
+/// Complete lines waiting to be read in canonical mode
+const MAX_LINES: usize = 64;
+
+/// Input flags
+const INLCR: u32 = 0o100;
+const IGNCR: u32 = 0o200;
+const ICRNL: u32 = 0o400;
+const IXON: u32 = 0o2000;
+
+/// Output flags
+const OPOST: u32 = 0o1;
+const ONLCR: u32 = 0o4;
+
+/// Control flags: 38400 baud, eight bits, receiver on, hang up on close
+const CFLAG: u32 = 0o17 | 0o60 | 0o200 | 0o2000;
+
+/// Local flags
+const ISIG: u32 = 0o1;
+const ICANON: u32 = 0o2;
+const ECHO: u32 = 0o10;
+const ECHOE: u32 = 0o20;
+const ECHOK: u32 = 0o40;
+const ECHONL: u32 = 0o100;
+const ECHOCTL: u32 = 0o1000;
+const ECHOKE: u32 = 0o4000;
+const IEXTEN: u32 = 0o100000;
+
+/// Control characters, by their index in `cc`
+const NCCS: usize = 19;
+const VINTR: usize = 0;
+const VQUIT: usize = 1;
+const VERASE: usize = 2;
+const VKILL: usize = 3;
+const VEOF: usize = 4;
+const VMIN: usize = 6;
+const VSTART: usize = 8;
+const VSTOP: usize = 9;
+const VSUSP: usize = 10;
+const VEOL: usize = 11;
+
+/// Terminal settings, as Linux's `struct termios`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Termios {
+    pub iflag: u32,
+    pub oflag: u32,
+    pub cflag: u32,
+    pub lflag: u32,
+    pub line: u8,
+    pub cc: [u8; NCCS],
+}
+
+impl Default for Termios {
+    /// Canonical mode with echo, as a new terminal starts
+    fn default() -> Self {
+        let mut cc = [0; NCCS];
+        for (index, c) in [
+            (VINTR, 0x03),
+            (VQUIT, 0x1c),
+            (VERASE, 0x7f),
+            (VKILL, 0x15),
+            (VEOF, 0x04),
+            (VMIN, 1),
+            (VSTART, 0x11),
+            (VSTOP, 0x13),
+            (VSUSP, 0x1a),
+        ] {
+            cc[index] = c;
+        }
+        Termios {
+            iflag: ICRNL | IXON,
+            oflag: OPOST | ONLCR,
+            cflag: CFLAG,
+            lflag: ISIG | ICANON | ECHO | ECHOE | ECHOK | ECHOCTL | ECHOKE | IEXTEN,
+            line: 0,
+            cc,
+        }
+    }
+}
+
+/// Size of a terminal, as Linux's `struct winsize`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct WinSize {
+    pub rows: u16,
+    pub cols: u16,
+    pub x_pixels: u16,
+    pub y_pixels: u16,
+}
+
+impl Default for WinSize {
+    fn default() -> Self {
+        WinSize {
+            rows: 24,
+            cols: 80,
+            x_pixels: 0,
+            y_pixels: 0,
+        }
+    }
+}
+
+/// One end of a pseudo-terminal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum End {
+    Master,
+    Slave,
+}
+
+struct Pty {
+    masters: u32,
+    slaves: u32,
+    /// Whether a slave has been opened, so that closing the last one hangs
+    /// up
+    opened: bool,
+    /// Whether the slave cannot be opened by its path yet
+    locked: bool,
+    termios: Termios,
+    size: WinSize,
+    /// The line being edited in canonical mode
+    line: ArrayVec<u8, MAX_CANON>,
+    /// What the slave reads
+    input: Deque<u8, BUFFER>,
+    /// Lengths of the lines left in `input` in canonical mode, 0 for an end
+    /// of file
+    lines: Deque<u16, MAX_LINES>,
+    /// What the master reads
+    output: Deque<u8, BUFFER>,
+}
+
+static PTYS: Mutex<[Option<Pty>; MAX_PTYS]> = Mutex::new([const { None }; MAX_PTYS]);
+
+/// Pseudo-terminal errors
+#[derive(Debug)]
+pub enum PtyError {
+    TooManyPtys,
+    NotFound,
+    /// The slave has not been unlocked
+    Locked,
+    /// The other end is closed
+    HungUp,
+}
+
+impl Pty {
+    /// Whether `byte` is control character `index`, which 0 disables
+    fn is(&self, byte: u8, index: usize) -> bool {
+        self.termios.cc[index] != 0 && self.termios.cc[index] == byte
+    }
+
+    /// Queue `byte` for the master as output processing turns it; false
+    /// if there is no room
+    fn emit(&mut self, byte: u8) -> bool {
+        let crlf = byte == b'\n' && self.termios.oflag & (OPOST | ONLCR) == OPOST | ONLCR;
+        if self.output.capacity() - self.output.len() < 1 + crlf as usize {
+            return false;
+        }
+        if crlf {
+            let _ = self.output.push_back(b'\r');
+        }
+        let _ = self.output.push_back(byte);
+        true
+    }
+
+    /// Echo input back to the master, dropped if there is no room
+    fn echo(&mut self, bytes: &[u8]) {
+        if self.termios.lflag & ECHO != 0 {
+            for byte in bytes {
+                self.emit(*byte);
+            }
+        }
+    }
+
+    /// Take a byte the master wrote through the line discipline; false if
+    /// there is no room for it yet
+    fn input(&mut self, byte: u8) -> bool {
+        let Termios { iflag, lflag, .. } = self.termios;
+        let byte = match byte {
+            b'\r' if iflag & IGNCR != 0 => return true,
+            b'\r' if iflag & ICRNL != 0 => b'\n',
+            b'\n' if iflag & INLCR != 0 => b'\r',
+            byte => byte,
+        };
+        if lflag & ISIG != 0 && [VINTR, VQUIT, VSUSP].iter().any(|index| self.is(byte, *index)) {
+            self.line.clear();
+            if lflag & ECHOCTL != 0 {
+                self.echo(&[b'^', byte ^ 0x40]);
+            }
+            return true;
+        }
+        if lflag & ICANON == 0 {
+            if self.input.push_back(byte).is_err() {
+                return false;
+            }
+            self.echo(&[byte]);
+            return true;
+        }
+        if self.is(byte, VERASE) {
+            if self.line.pop().is_some() && lflag & ECHOE != 0 {
+                self.echo(b"\x08 \x08");
+            }
+            return true;
+        }
+        if self.is(byte, VKILL) {
+            if lflag & ECHOE != 0 {
+                for _ in 0..self.line.len() {
+                    self.echo(b"\x08 \x08");
+                }
+            }
+            self.line.clear();
+            return true;
+        }
+        let eof = self.is(byte, VEOF);
+        if !eof && byte != b'\n' && !self.is(byte, VEOL) {
+            if self.line.try_push(byte).is_ok() {
+                self.echo(&[byte]);
+            }
+            return true;
+        }
+        // The line is complete, its end of file character left out
+        let len = self.line.len() + !eof as usize;
+        if self.input.capacity() - self.input.len() < len || self.lines.is_full() {
+            return false;
+        }
+        for byte in self.line.drain(..) {
+            let _ = self.input.push_back(byte);
+        }
+        if !eof {
+            let _ = self.input.push_back(byte);
+        }
+        let _ = self.lines.push_back(len as u16);
+        if byte == b'\n' && lflag & ECHO == 0 && lflag & ECHONL != 0 {
+            self.emit(byte);
+        } else if !eof {
+            self.echo(&[byte]);
+        }
+        true
+    }
+
+    /// Read the slave's input into `buffer`, in canonical mode no further
+    /// than the end of a line
+    fn read_input(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        let canonical = self.termios.lflag & ICANON != 0;
+        let available = if canonical {
+            self.lines.front().map(|len| *len as usize)
+        } else if self.input.is_empty() && self.termios.cc[VMIN] != 0 {
+            None
+        } else {
+            Some(self.input.len())
+        };
+        let Some(available) = available else {
+            return (self.masters == 0).then_some(0);
+        };
+        let len = available.min(buffer.len());
+        for byte in &mut buffer[..len] {
+            *byte = self.input.pop_front().unwrap_or(0);
+        }
+        if let Some(line) = self.lines.front_mut().filter(|_| canonical) {
+            *line -= len as u16;
+            if *line == 0 {
+                self.lines.pop_front();
+            }
+        }
+        Some(len)
+    }
+}
+
+fn get(ptys: &mut [Option<Pty>; MAX_PTYS], pty: usize) -> Result<&mut Pty, PtyError> {
+    ptys.get_mut(pty).and_then(Option::as_mut).ok_or(PtyError::NotFound)
+}
+
+/// Make a pseudo-terminal with its master open and its slave locked; its
+/// number is returned
+pub fn create() -> Result<usize, PtyError> {
+    let mut ptys = PTYS.lock();
+    let pty = ptys.iter().position(Option::is_none).ok_or(PtyError::TooManyPtys)?;
+    ptys[pty] = Some(Pty {
+        masters: 1,
+        slaves: 0,
+        opened: false,
+        locked: true,
+        termios: Termios::default(),
+        size: WinSize::default(),
+        line: ArrayVec::new(),
+        input: Deque::new(),
+        lines: Deque::new(),
+        output: Deque::new(),
+    });
+    Ok(pty)
+}
+
+/// Whether pseudo-terminal `pty` is in use
+pub fn exists(pty: usize) -> bool {
+    PTYS.lock().get(pty).is_some_and(Option::is_some)
+}
+
+/// Numbers of the pseudo-terminals in use
+pub fn in_use() -> ArrayVec<usize, MAX_PTYS> {
+    let ptys = PTYS.lock();
+    (0..MAX_PTYS).filter(|pty| ptys[*pty].is_some()).collect()
+}
+
+/// Lock or unlock the slave of `pty`
+pub fn set_locked(pty: usize, locked: bool) -> Result<(), PtyError> {
+    get(&mut PTYS.lock(), pty)?.locked = locked;
+    Ok(())
+}
+
+/// Open another slave of `pty`, which must be unlocked and have a master
+pub fn open_slave(pty: usize) -> Result<(), PtyError> {
+    let mut ptys = PTYS.lock();
+    let state = get(&mut ptys, pty)?;
+    if state.locked {
+        return Err(PtyError::Locked);
+    }
+    if state.masters == 0 {
+        return Err(PtyError::HungUp);
+    }
+    state.slaves += 1;
+    state.opened = true;
+    Ok(())
+}
+
+/// Close one end of `pty`, and the pseudo-terminal with its last end
+pub fn close(pty: usize, end: End) {
+    let mut ptys = PTYS.lock();
+    let Ok(state) = get(&mut ptys, pty) else {
+        return;
+    };
+    match end {
+        End::Master => state.masters = state.masters.saturating_sub(1),
+        End::Slave => state.slaves = state.slaves.saturating_sub(1),
+    }
+    if state.masters == 0 && state.slaves == 0 {
+        ptys[pty] = None;
+    }
+}
+
+/// Read at `end` of `pty` into `buffer`: how many bytes, 0 at end of file,
+/// or `None` if there is nothing to read yet
+pub fn read(pty: usize, end: End, buffer: &mut [u8]) -> Result<Option<usize>, PtyError> {
+    let mut ptys = PTYS.lock();
+    let state = get(&mut ptys, pty)?;
+    if end == End::Slave {
+        return Ok(state.read_input(buffer));
+    }
+    if state.output.is_empty() {
+        return Ok((state.slaves == 0 && state.opened).then_some(0));
+    }
+    let len = state.output.len().min(buffer.len());
+    for byte in &mut buffer[..len] {
+        *byte = state.output.pop_front().unwrap_or(0);
+    }
+    Ok(Some(len))
+}
+
+/// Write `data` at `end` of `pty`: how many bytes were taken, or `None`
+/// if there is no room yet
+pub fn write(pty: usize, end: End, data: &[u8]) -> Result<Option<usize>, PtyError> {
+    let mut ptys = PTYS.lock();
+    let state = get(&mut ptys, pty)?;
+    if end == End::Slave && state.masters == 0 {
+        return Err(PtyError::HungUp);
+    }
+    let taken = match end {
+        End::Master => data.iter().take_while(|byte| state.input(**byte)).count(),
+        End::Slave => data.iter().take_while(|byte| state.emit(**byte)).count(),
+    };
+    if taken == 0 && !data.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(taken))
+}
+
+/// Terminal settings of `pty`
+pub fn termios(pty: usize) -> Result<Termios, PtyError> {
+    Ok(get(&mut PTYS.lock(), pty)?.termios)
+}
+
+/// Change the settings of `pty`, discarding unread input with `flush`
+pub fn set_termios(pty: usize, termios: Termios, flush: bool) -> Result<(), PtyError> {
+    let mut ptys = PTYS.lock();
+    let state = get(&mut ptys, pty)?;
+    let was_canonical = state.termios.lflag & ICANON != 0;
+    state.termios = termios;
+    let canonical = termios.lflag & ICANON != 0;
+    if flush {
+        state.line.clear();
+        state.input.clear();
+        state.lines.clear();
+    } else if was_canonical && !canonical {
+        // What was being edited becomes readable as it is
+        for byte in state.line.drain(..) {
+            let _ = state.input.push_back(byte);
+        }
+        state.lines.clear();
+    } else if canonical && !was_canonical && !state.input.is_empty() {
+        let _ = state.lines.push_back(state.input.len() as u16);
+    }
+    Ok(())
+}
+
+/// Window size of `pty`
+pub fn size(pty: usize) -> Result<WinSize, PtyError> {
+    Ok(get(&mut PTYS.lock(), pty)?.size)
+}
+
+/// Set the window size of `pty`, as its master does when the window
+/// changes
+pub fn set_size(pty: usize, size: WinSize) -> Result<(), PtyError> {
+    get(&mut PTYS.lock(), pty)?.size = size;
+    Ok(())
+}
@@ -0,0 +1,673 @@
+//! POSIX file system view of TagFS
+//!
+//! Paths name tags. An object tagged `docs/notes.txt` is the file
+//! `/docs/notes.txt`, and a tag's leading components are directories,
+//! which exist as long as some tag lies under them; a tag ending in `/`
+//! keeps an otherwise empty directory. An object with several tags is a
+//! file with several links, deleted when its last one is removed. A tag
+//! holds at most [`MAX_TAG`] bytes, so a path kept in TagFS does too once
+//! its leading slash is dropped. `/dev` holds the console streams, `null`,
+//! and `ptmx` and `pts` for pseudo-terminals (see [`super::pty`]).
+//!
+//! TagFS objects never change once created, so a file opened for writing
+//! is staged: its contents are kept in memory while it is open and replace
+//! the object, under the same tags, when the last handle to it is closed.
+//! A new file appears at that point too. Only [`MAX_STAGED`] files can be
+//! open for writing at once, each up to [`MAX_STAGED_SIZE`] bytes.
+//!
+//! A FAT32 volume is mounted at `/mnt/<device>` when its device appears
+//! and unmounted when it goes (see [`super::fat32`]). Paths below a mount
+//! point are the volume's, and its files are written in place. A file or
+//! directory cannot be moved between a volume and TagFS.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use arrayvec::{ArrayString, ArrayVec};
+use spin::Mutex;
+
+use super::fat32::{self, Node, MAX_VOLUMES};
+use super::pty::{self, PtyError};
+use crate::storage::{self, StorageError};
+use crate::tagfs::{self, Tag, TagFsError};
+use crate::userspace::console;
+use crate::userspace::handle::Object;
+
+/// Longest normalized path, without its leading slash
+pub const MAX_PATH: usize = 128;
+
+/// Longest tag, and so longest path TagFS holds
+pub const MAX_TAG: usize = 32;
+
+/// Longest path accepted before it is normalized
+pub const MAX_INPUT_PATH: usize = 256;
+
+/// Files open for writing at once
+pub const MAX_STAGED: usize = 8;
+
+/// Largest file that can be written
+pub const MAX_STAGED_SIZE: usize = 64 * 1024;
+
+/// Tags a rewritten object can keep
+const MAX_TAGS: usize = 16;
+
+/// A normalized path without its leading slash; the root is empty
+pub type Path = ArrayString<MAX_PATH>;
+
+/// Open flags
+pub const OPEN_READ: u64 = 1 << 0;
+pub const OPEN_WRITE: u64 = 1 << 1;
+/// Create the file if it does not exist
+pub const OPEN_CREATE: u64 = 1 << 2;
+/// Fail if it does, with `OPEN_CREATE`
+pub const OPEN_EXCLUSIVE: u64 = 1 << 3;
+/// Start a file opened for writing empty
+pub const OPEN_TRUNCATE: u64 = 1 << 4;
+/// Write at the end whatever the position
+pub const OPEN_APPEND: u64 = 1 << 5;
+/// Fail unless the path is a directory
+pub const OPEN_DIRECTORY: u64 = 1 << 6;
+
+/// The directory of the console streams, `null` and pseudo-terminals
+const DEV: &str = "dev";
+
+/// The pseudo-terminal multiplexer, in [`DEV`]
+const PTMX: &str = "ptmx";
+
+/// The directory of pseudo-terminal slaves, by number
+const PTS: &str = "dev/pts";
+
+/// The directory volumes are mounted in
+const MNT: &str = "mnt";
+
+/// Entries of [`DEV`], in name order
+const DEVICES: [(&str, Object); 4] = [
+    ("null", Object::Null),
+    ("stderr", Object::Console(console::STDERR)),
+    ("stdin", Object::Console(console::STDIN)),
+    ("stdout", Object::Console(console::STDOUT)),
+];
+
+/// What a path is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Directory,
+    Device,
+}
+
+/// An entry of a directory
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    pub name: Path,
+    pub kind: Kind,
+    /// The object a file is, or its inode number on a FAT32 volume; 0 for
+    /// anything else
+    pub id: u64,
+}
+
+/// A FAT32 volume and where it is mounted
+#[derive(Clone, Copy)]
+struct Mount {
+    at: Path,
+    volume: u32,
+    device: u32,
+}
+
+/// VFS errors
+#[derive(Debug)]
+pub enum VfsError {
+    NotFound,
+    NotDirectory,
+    IsDirectory,
+    NameTooLong,
+    Exists,
+    /// A directory that still has entries
+    NotEmpty,
+    /// `/dev`, which cannot be changed, or a read-only volume
+    ReadOnly,
+    /// A file written past [`MAX_STAGED_SIZE`]
+    TooLarge,
+    TooManyStaged,
+    /// Something the mapping onto tags cannot express, such as moving a
+    /// directory, or moving between file systems
+    Unsupported,
+    /// A name a FAT32 volume cannot hold, or a directory moved into itself
+    Invalid,
+    /// A mount point, which cannot be removed or moved, no room left to
+    /// mount a volume, or a pseudo-terminal slave still locked
+    Busy,
+    /// A volume that is full, or no pseudo-terminal left
+    NoSpace,
+    /// A volume whose structures make no sense
+    Corrupt,
+    Storage(TagFsError),
+    Device(StorageError),
+}
+
+impl From<TagFsError> for VfsError {
+    fn from(e: TagFsError) -> Self {
+        match e {
+            TagFsError::ObjectNotFound => VfsError::NotFound,
+            e => VfsError::Storage(e),
+        }
+    }
+}
+
+impl From<StorageError> for VfsError {
+    fn from(e: StorageError) -> Self {
+        VfsError::Device(e)
+    }
+}
+
+impl From<PtyError> for VfsError {
+    fn from(e: PtyError) -> Self {
+        match e {
+            PtyError::TooManyPtys => VfsError::NoSpace,
+            PtyError::Locked => VfsError::Busy,
+            PtyError::NotFound | PtyError::HungUp => VfsError::NotFound,
+        }
+    }
+}
+
+/// A file open for writing
+#[derive(Clone, Copy)]
+struct Staged {
+    path: Path,
+    /// The object it replaces when it is closed
+    replaces: Option<u64>,
+    len: usize,
+    append: bool,
+}
+
+struct Staging {
+    files: [Option<Staged>; MAX_STAGED],
+    data: [[u8; MAX_STAGED_SIZE]; MAX_STAGED],
+}
+
+static STAGING: Mutex<Staging> = Mutex::new(Staging {
+    files: [None; MAX_STAGED],
+    data: [[0; MAX_STAGED_SIZE]; MAX_STAGED],
+});
+
+static MOUNTS: Mutex<[Option<Mount>; MAX_VOLUMES]> = Mutex::new([None; MAX_VOLUMES]);
+
+/// Whether devices are mounted as they appear; those there at boot are
+/// mounted by [`init`]
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Mount the FAT32 volumes of the devices there are
+pub fn init() {
+    READY.store(true, Ordering::Release);
+    for (device, _) in storage::devices() {
+        mount(device);
+    }
+}
+
+/// Mount the FAT32 volume on a device that has just appeared, if it holds
+/// one
+pub fn device_added(device: u32) {
+    if READY.load(Ordering::Acquire) {
+        mount(device);
+    }
+}
+
+/// Unmount the volume of a device that is gone
+pub fn device_removed(device: u32) {
+    for slot in MOUNTS.lock().iter_mut() {
+        if let Some(mount) = slot.filter(|mount| mount.device == device) {
+            fat32::unmount(mount.volume);
+            *slot = None;
+        }
+    }
+}
+
+/// Mount the FAT32 volume on `device` at `/mnt/<device name>`
+fn mount(device: u32) {
+    let Ok(info) = storage::info(device) else {
+        return;
+    };
+    let mut at = Path::new();
+    if write!(at, "{}/{}", MNT, info.name).is_err() {
+        return;
+    }
+    match fat32::mount(device) {
+        Ok(Some(volume)) => {
+            if let Some(slot) = MOUNTS.lock().iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(Mount { at, volume, device });
+            }
+            crate::klog!(Info, "mounted the FAT32 volume on {} at /{}", info.name, at);
+        }
+        Ok(None) => {}
+        Err(e) => crate::klog!(Warn, "Could not mount {}: {:?}", info.name, e),
+    }
+}
+
+/// The volume `path` lies on, if it is below a mount point, and the path
+/// on it
+fn mounted(path: &str) -> Option<(u32, Path)> {
+    MOUNTS.lock().iter().flatten().find_map(|mount| {
+        let rest = if path == mount.at.as_str() { "" } else { below(&mount.at, path)? };
+        Some((mount.volume, Path::from(rest).ok()?))
+    })
+}
+
+/// Whether a mount point lies under `dir`
+fn has_mounts_under(dir: &str) -> bool {
+    MOUNTS.lock().iter().flatten().any(|mount| below(dir, &mount.at).is_some())
+}
+
+/// Resolve `path` against the directory `cwd`, dropping `.`, `..` and
+/// empty components
+pub fn normalize(cwd: &str, path: &str) -> Result<Path, VfsError> {
+    if path.is_empty() {
+        return Err(VfsError::NotFound);
+    }
+    if path.len() > MAX_INPUT_PATH {
+        return Err(VfsError::NameTooLong);
+    }
+    let base = if path.starts_with('/') { "" } else { cwd };
+    let mut parts = ArrayVec::<&str, MAX_INPUT_PATH>::new();
+    for part in base.split('/').chain(path.split('/')) {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    let mut normal = Path::new();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            normal.try_push('/').map_err(|_| VfsError::NameTooLong)?;
+        }
+        normal.try_push_str(part).map_err(|_| VfsError::NameTooLong)?;
+    }
+    Ok(normal)
+}
+
+/// The directory holding `path`
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+/// What is below `dir` in `tag`, if it lies there
+fn below<'a>(dir: &str, tag: &'a str) -> Option<&'a str> {
+    if dir.is_empty() {
+        return Some(tag);
+    }
+    tag.strip_prefix(dir)?.strip_prefix('/')
+}
+
+fn is_dev(path: &str) -> bool {
+    path == DEV || below(DEV, path).is_some()
+}
+
+/// Whether any tag lies under `dir`
+fn has_tags_under(dir: &str) -> bool {
+    let mut found = false;
+    tagfs::tagfs_each_tag(|tag, _| found |= below(dir, tag.as_str()).is_some());
+    found
+}
+
+/// The object `path` names, opened for reading: a TagFS object, a
+/// directory or a device
+pub fn lookup(path: &Path) -> Result<Object, VfsError> {
+    let directory = Object::Directory {
+        path: *path,
+        cursor: Path::new(),
+    };
+    if path.is_empty() || path.as_str() == DEV || path.as_str() == PTS {
+        return Ok(directory);
+    }
+    if let Some((volume, rest)) = mounted(path) {
+        return match fat32::lookup(volume, &rest)? {
+            Node::Directory => Ok(directory),
+            Node::File(entry) => Ok(Object::Fat {
+                volume,
+                entry,
+                offset: 0,
+                write: false,
+                append: false,
+            }),
+        };
+    }
+    if let Some(name) = below(PTS, path) {
+        return match name.parse() {
+            Ok(pty) if pty::exists(pty) => Ok(Object::Pty {
+                pty,
+                end: pty::End::Slave,
+            }),
+            _ => Err(VfsError::NotFound),
+        };
+    }
+    if below(DEV, path) == Some(PTMX) {
+        return Ok(Object::Ptmx);
+    }
+    if let Some(name) = below(DEV, path) {
+        return DEVICES
+            .iter()
+            .find(|(device, _)| *device == name)
+            .map(|(_, object)| *object)
+            .ok_or(VfsError::NotFound);
+    }
+    if path.len() > MAX_TAG {
+        return Err(VfsError::NotFound);
+    }
+    if let Some(id) = tagfs::tagfs_query(&Tag::new(path)) {
+        return Ok(Object::File { id, offset: 0 });
+    }
+    if has_tags_under(path) || has_mounts_under(path) {
+        return Ok(directory);
+    }
+    Err(VfsError::NotFound)
+}
+
+fn check_parent(path: &Path) -> Result<(), VfsError> {
+    if is_dev(path) {
+        return Err(VfsError::ReadOnly);
+    }
+    let mut dir = Path::new();
+    dir.push_str(parent(path));
+    match lookup(&dir)? {
+        Object::Directory { .. } => Ok(()),
+        _ => Err(VfsError::NotDirectory),
+    }
+}
+
+/// Open `path` with `flags`
+pub fn open(path: &Path, flags: u64) -> Result<Object, VfsError> {
+    let path = *path;
+    let write = flags & OPEN_WRITE != 0;
+    match lookup(&path) {
+        Ok(_) if flags & OPEN_CREATE != 0 && flags & OPEN_EXCLUSIVE != 0 => Err(VfsError::Exists),
+        Ok(Object::Directory { .. }) if write => Err(VfsError::IsDirectory),
+        Ok(object @ Object::Directory { .. }) => Ok(object),
+        Ok(_) if flags & OPEN_DIRECTORY != 0 => Err(VfsError::NotDirectory),
+        Ok(Object::File { id, .. }) if write => stage(path, Some(id), flags),
+        Ok(Object::Fat { volume, entry, .. }) => open_fat(volume, entry, flags),
+        Ok(Object::Ptmx) => Ok(Object::Pty {
+            pty: pty::create()?,
+            end: pty::End::Master,
+        }),
+        Ok(object @ Object::Pty { pty, .. }) => {
+            pty::open_slave(pty)?;
+            Ok(object)
+        }
+        Ok(object) => Ok(object),
+        Err(VfsError::NotFound) if flags & OPEN_CREATE != 0 && write => {
+            check_parent(&path)?;
+            match mounted(&path) {
+                Some((volume, rest)) => open_fat(volume, fat32::create(volume, &rest)?, flags),
+                None if path.len() > MAX_TAG => Err(VfsError::NameTooLong),
+                None => stage(path, None, flags),
+            }
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Open the file on `volume` whose entry is at `entry` with `flags`
+fn open_fat(volume: u32, entry: u64, flags: u64) -> Result<Object, VfsError> {
+    let write = flags & OPEN_WRITE != 0;
+    if write && fat32::is_read_only(volume)? {
+        return Err(VfsError::ReadOnly);
+    }
+    if write && flags & OPEN_TRUNCATE != 0 {
+        fat32::truncate(volume, entry)?;
+    }
+    Ok(Object::Fat {
+        volume,
+        entry,
+        offset: 0,
+        write,
+        append: flags & OPEN_APPEND != 0,
+    })
+}
+
+/// Start writing `path`, from the contents of the object it replaces
+/// unless the file is truncated
+fn stage(path: Path, replaces: Option<u64>, flags: u64) -> Result<Object, VfsError> {
+    let mut staging = STAGING.lock();
+    let slot = staging.files.iter().position(Option::is_none).ok_or(VfsError::TooManyStaged)?;
+    let len = match replaces {
+        Some(id) if flags & OPEN_TRUNCATE == 0 => {
+            let size = tagfs::tagfs_meta(id).ok_or(VfsError::NotFound)?.size as usize;
+            if size > MAX_STAGED_SIZE {
+                return Err(VfsError::TooLarge);
+            }
+            tagfs::tagfs_read(id, 0, &mut staging.data[slot][..size])?
+        }
+        _ => 0,
+    };
+    staging.files[slot] = Some(Staged {
+        path,
+        replaces,
+        len,
+        append: flags & OPEN_APPEND != 0,
+    });
+    Ok(Object::Staged { slot, offset: 0 })
+}
+
+/// Length of a file open for writing
+pub fn staged_len(slot: usize) -> u64 {
+    STAGING.lock().files[slot].map_or(0, |file| file.len as u64)
+}
+
+/// Read a file open for writing from `offset` into `buffer`, returning
+/// how many bytes were read
+pub fn read_staged(slot: usize, offset: u64, buffer: &mut [u8]) -> usize {
+    let staging = STAGING.lock();
+    let Some(file) = staging.files[slot] else {
+        return 0;
+    };
+    let start = (offset as usize).min(file.len);
+    let len = buffer.len().min(file.len - start);
+    buffer[..len].copy_from_slice(&staging.data[slot][start..start + len]);
+    len
+}
+
+/// Write `data` to a file open for writing at `offset`, or at its end if
+/// it was opened to append, returning how many bytes were written and the
+/// position after them
+pub fn write_staged(slot: usize, offset: u64, data: &[u8]) -> Result<(usize, u64), VfsError> {
+    let mut staging = STAGING.lock();
+    let Staging { files, data: buffers } = &mut *staging;
+    let file = files[slot].as_mut().ok_or(VfsError::NotFound)?;
+    let start = if file.append { file.len } else { offset.min(MAX_STAGED_SIZE as u64) as usize };
+    let len = data.len().min(MAX_STAGED_SIZE - start);
+    if len == 0 && !data.is_empty() {
+        return Err(VfsError::TooLarge);
+    }
+    let buffer = &mut buffers[slot];
+    // A gap left by seeking past the end reads as zeroes
+    if start > file.len {
+        buffer[file.len..start].fill(0);
+    }
+    buffer[start..start + len].copy_from_slice(&data[..len]);
+    file.len = file.len.max(start + len);
+    Ok((len, (start + len) as u64))
+}
+
+/// Replace the object a file open for writing was opened from, or create
+/// it, once the last handle to the file is closed
+pub fn commit(slot: usize) {
+    let mut staging = STAGING.lock();
+    let Some(file) = staging.files[slot].take() else {
+        return;
+    };
+    let path = Tag::new(&file.path);
+    let mut tags = ArrayVec::<Tag, MAX_TAGS>::new();
+    if let Some(old) = file.replaces {
+        // Removed while it was open, so what was written goes with it
+        if tagfs::tagfs_meta(old).is_none() {
+            return;
+        }
+        tagfs::tagfs_tags(old, |tag| {
+            let _ = tags.try_push(*tag);
+        });
+        // Its tags have to be free for the new object
+        let _ = tagfs::tagfs_delete(old);
+    }
+    if !tags.contains(&path) {
+        let _ = tags.try_push(path);
+    }
+    if let Err(e) = tagfs::tagfs_create(&tags, &staging.data[slot][..file.len]) {
+        crate::klog!(Warn, "Could not write /{}: {:?}", file.path, e);
+    }
+}
+
+/// The entry of directory `dir` that comes first by name after `after`,
+/// or the first one if `after` is empty
+pub fn next_entry(dir: &Path, after: &str) -> Option<Entry> {
+    if let Some((volume, rest)) = mounted(dir) {
+        let (name, node) = fat32::next_entry(volume, &rest, after).ok()??;
+        let (kind, id) = match node {
+            Node::Directory => (Kind::Directory, 0),
+            Node::File(entry) => (Kind::File, fat32::ino(volume, entry)),
+        };
+        return Some(Entry { name, kind, id });
+    }
+    let mut next: Option<Entry> = None;
+    let mut consider = |name: &str, kind: Kind, id: u64| {
+        if name.is_empty() || name <= after || next.is_some_and(|e| e.name.as_str() < name) {
+            return;
+        }
+        let Ok(name) = Path::from(name) else {
+            return;
+        };
+        match next.as_mut() {
+            // A name that is both a file and a directory lists as the directory
+            Some(entry) if entry.name == name => {
+                if kind == Kind::Directory {
+                    entry.kind = Kind::Directory;
+                    entry.id = 0;
+                }
+            }
+            _ => next = Some(Entry { name, kind, id }),
+        }
+    };
+    if dir.as_str() == DEV {
+        for (name, _) in DEVICES {
+            consider(name, Kind::Device, 0);
+        }
+        consider(PTMX, Kind::Device, 0);
+        consider("pts", Kind::Directory, 0);
+        return next;
+    }
+    if dir.as_str() == PTS {
+        for pty in pty::in_use() {
+            let mut name = ArrayString::<20>::new();
+            let _ = write!(name, "{}", pty);
+            consider(&name, Kind::Device, 0);
+        }
+        return next;
+    }
+    if dir.is_empty() {
+        consider(DEV, Kind::Directory, 0);
+    }
+    let mounts = *MOUNTS.lock();
+    for mount in mounts.iter().flatten() {
+        if let Some(rest) = below(dir, &mount.at) {
+            consider(rest.split('/').next().unwrap_or(rest), Kind::Directory, 0);
+        }
+    }
+    tagfs::tagfs_each_tag(|tag, id| {
+        let Some(rest) = below(dir, tag.as_str()) else {
+            return;
+        };
+        match rest.split_once('/') {
+            Some((name, _)) => consider(name, Kind::Directory, 0),
+            None => consider(rest, Kind::File, id),
+        }
+    });
+    next
+}
+
+/// Make the directory `path`
+pub fn mkdir(path: &Path) -> Result<(), VfsError> {
+    if lookup(path).is_ok() {
+        return Err(VfsError::Exists);
+    }
+    check_parent(path)?;
+    if let Some((volume, rest)) = mounted(path) {
+        return fat32::mkdir(volume, &rest);
+    }
+    let mut marker = ArrayString::<{ MAX_PATH + 1 }>::new();
+    marker.push_str(path);
+    marker.push('/');
+    if marker.len() > MAX_TAG {
+        return Err(VfsError::NameTooLong);
+    }
+    tagfs::tagfs_create(&[Tag::new(&marker)], &[])?;
+    Ok(())
+}
+
+/// Remove the empty directory `path`
+pub fn rmdir(path: &Path) -> Result<(), VfsError> {
+    if path.is_empty() || is_dev(path) {
+        return Err(VfsError::ReadOnly);
+    }
+    let Object::Directory { .. } = lookup(path)? else {
+        return Err(VfsError::NotDirectory);
+    };
+    if let Some((volume, rest)) = mounted(path) {
+        return fat32::rmdir(volume, &rest);
+    }
+    if next_entry(path, "").is_some() {
+        return Err(VfsError::NotEmpty);
+    }
+    // Only the marker can be left
+    let mut marker = ArrayString::<{ MAX_PATH + 1 }>::new();
+    marker.push_str(path);
+    marker.push('/');
+    let marker = Tag::new(&marker);
+    let id = tagfs::tagfs_query(&marker).ok_or(VfsError::NotFound)?;
+    tagfs::tagfs_remove_tag(id, &marker)?;
+    Ok(())
+}
+
+/// Remove the file `path`; the object goes with its last link
+pub fn unlink(path: &Path) -> Result<(), VfsError> {
+    if let Some((volume, rest)) = mounted(path) {
+        return fat32::unlink(volume, &rest);
+    }
+    match lookup(path)? {
+        Object::File { id, .. } => Ok(tagfs::tagfs_remove_tag(id, &Tag::new(path))?),
+        Object::Directory { .. } => Err(VfsError::IsDirectory),
+        _ => Err(VfsError::ReadOnly),
+    }
+}
+
+/// Move the file `from` to `to`, replacing any file there
+pub fn rename(from: &Path, to: &Path) -> Result<(), VfsError> {
+    match (mounted(from), mounted(to)) {
+        (Some((volume, from)), Some((to_volume, to))) if volume == to_volume => {
+            return fat32::rename(volume, &from, &to);
+        }
+        (None, None) => {}
+        _ => return Err(VfsError::Unsupported),
+    }
+    let id = match lookup(from)? {
+        Object::File { id, .. } => id,
+        Object::Directory { .. } => return Err(VfsError::Unsupported),
+        _ => return Err(VfsError::ReadOnly),
+    };
+    if from == to {
+        return Ok(());
+    }
+    match lookup(to) {
+        Ok(Object::File { id: old, .. }) => tagfs::tagfs_remove_tag(old, &Tag::new(to))?,
+        Ok(Object::Directory { .. }) => return Err(VfsError::IsDirectory),
+        Ok(_) => return Err(VfsError::ReadOnly),
+        Err(VfsError::NotFound) => check_parent(to)?,
+        Err(e) => return Err(e),
+    }
+    if to.len() > MAX_TAG {
+        return Err(VfsError::NameTooLong);
+    }
+    tagfs::tagfs_add_tag(id, Tag::new(to))?;
+    tagfs::tagfs_remove_tag(id, &Tag::new(from))?;
+    Ok(())
+}
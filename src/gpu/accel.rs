@@ -0,0 +1,176 @@
+//! 2D drawing operations
+//!
+//! Fills, copies, scaled blits and alpha blends go through here rather than
+//! open-coded pixel loops. A driver with a 2D engine installs an
+//! [`Accel2d`] backend; anything it does not handle falls back to the CPU.
+
+use spin::Mutex;
+
+use super::framebuffer::{Framebuffer, Rect};
+
+/// A 2D engine
+///
+/// Each operation returns `false` if the engine cannot do it (wrong memory,
+/// unsupported format, ...), in which case the CPU path is used instead.
+pub trait Accel2d: Sync {
+    fn name(&self) -> &'static str;
+
+    fn fill(&self, _dst: &mut Framebuffer, _rect: Rect, _rgb: u32) -> bool {
+        false
+    }
+
+    fn copy(&self, _dst: &mut Framebuffer, _src: &Framebuffer, _src_rect: Rect, _x: i32, _y: i32) -> bool {
+        false
+    }
+
+    fn scale(&self, _dst: &mut Framebuffer, _dst_rect: Rect, _src: &Framebuffer, _src_rect: Rect) -> bool {
+        false
+    }
+
+    fn blend(&self, _dst: &mut Framebuffer, _src: &Framebuffer, _src_rect: Rect, _x: i32, _y: i32, _opacity: u8) -> bool {
+        false
+    }
+}
+
+static BACKEND: Mutex<Option<&'static dyn Accel2d>> = Mutex::new(None);
+
+/// Install a hardware 2D engine
+pub fn set_backend(backend: &'static dyn Accel2d) {
+    *BACKEND.lock() = Some(backend);
+}
+
+fn backend() -> Option<&'static dyn Accel2d> {
+    *BACKEND.lock()
+}
+
+/// Whether a hardware 2D engine is installed
+pub fn has_backend() -> bool {
+    backend().is_some()
+}
+
+/// Clip a copy of `src_rect` to (`x`, `y`) against both buffers
+///
+/// Returns the source rectangle and destination corner actually copied.
+fn clip(dst: &Framebuffer, src: &Framebuffer, src_rect: Rect, x: i32, y: i32) -> Option<(Rect, i32, i32)> {
+    let clipped = src_rect.intersect(&src.bounds());
+    let (x, y) = (x + clipped.x - src_rect.x, y + clipped.y - src_rect.y);
+    let dst_rect = Rect::new(x, y, clipped.width, clipped.height).intersect(&dst.bounds());
+    if dst_rect.is_empty() {
+        return None;
+    }
+    let src_rect = Rect::new(
+        clipped.x + dst_rect.x - x,
+        clipped.y + dst_rect.y - y,
+        dst_rect.width,
+        dst_rect.height,
+    );
+    Some((src_rect, dst_rect.x, dst_rect.y))
+}
+
+/// Fill `rect` with a 0x00RRGGBB colour
+pub fn fill(dst: &mut Framebuffer, rect: Rect, rgb: u32) {
+    if backend().is_some_and(|b| b.fill(dst, rect, rgb)) {
+        return;
+    }
+    dst.fill_rect(rect, rgb);
+}
+
+/// Copy `src_rect` of `src` so its top-left lands at (`x`, `y`)
+///
+/// `src` and `dst` may be the same buffer with overlapping rectangles.
+pub fn copy(dst: &mut Framebuffer, src: &Framebuffer, src_rect: Rect, x: i32, y: i32) {
+    if backend().is_some_and(|b| b.copy(dst, src, src_rect, x, y)) {
+        return;
+    }
+
+    let Some((from, x, y)) = clip(dst, src, src_rect, x, y) else {
+        return;
+    };
+
+    // Walk rows away from the overlap so nothing is read after being overwritten
+    let downwards = y <= from.y;
+    for i in 0..from.height as i32 {
+        let i = if downwards { i } else { from.height as i32 - 1 - i };
+        unsafe {
+            core::ptr::copy(
+                pixel_ptr(src, from.x, from.y + i),
+                pixel_ptr(dst, x, y + i),
+                from.width as usize,
+            );
+        }
+    }
+}
+
+/// Address of pixel (`x`, `y`), which must be inside the buffer
+fn pixel_ptr(fb: &Framebuffer, x: i32, y: i32) -> *mut u32 {
+    (fb.base as *mut u32).wrapping_add(y as usize * fb.stride as usize + x as usize)
+}
+
+/// Scale `src_rect` of `src` to fill `dst_rect` (nearest neighbour)
+pub fn scale(dst: &mut Framebuffer, dst_rect: Rect, src: &Framebuffer, src_rect: Rect) {
+    let src_rect = src_rect.intersect(&src.bounds());
+    if src_rect.is_empty() || dst_rect.is_empty() {
+        return;
+    }
+    if src_rect.width == dst_rect.width && src_rect.height == dst_rect.height {
+        return copy(dst, src, src_rect, dst_rect.x, dst_rect.y);
+    }
+    if backend().is_some_and(|b| b.scale(dst, dst_rect, src, src_rect)) {
+        return;
+    }
+
+    let area = dst_rect.intersect(&dst.bounds());
+    // 16.16 fixed-point source steps per destination pixel
+    let step_x = ((src_rect.width as u64) << 16) / dst_rect.width as u64;
+    let step_y = ((src_rect.height as u64) << 16) / dst_rect.height as u64;
+    for y in area.y..area.bottom() {
+        let sy = src_rect.y as u32 + (((y - dst_rect.y) as u64 * step_y) >> 16) as u32;
+        let src_row = src.row(sy);
+        let dst_row = dst.row_mut(y as u32);
+        for x in area.x..area.right() {
+            let sx = src_rect.x as usize + (((x - dst_rect.x) as u64 * step_x) >> 16) as usize;
+            dst_row[x as usize] = src_row[sx];
+        }
+    }
+}
+
+/// Blend one channel of `src` over `dst` with weight `alpha` (0..=255)
+pub(crate) fn mix(src: u32, dst: u32, alpha: u32, shift: u32) -> u32 {
+    let s = (src >> shift) & 0xFF;
+    let d = (dst >> shift) & 0xFF;
+    ((s * alpha + d * (255 - alpha) + 127) / 255) << shift
+}
+
+/// Draw `src_rect` of `src` at (`x`, `y`) using the source's alpha byte
+///
+/// Source pixels carry straight (not premultiplied) alpha in bits 24-31,
+/// further scaled by `opacity`. Buffers may differ in channel order.
+pub fn blend(dst: &mut Framebuffer, src: &Framebuffer, src_rect: Rect, x: i32, y: i32, opacity: u8) {
+    if opacity == 0 {
+        return;
+    }
+    if backend().is_some_and(|b| b.blend(dst, src, src_rect, x, y, opacity)) {
+        return;
+    }
+
+    let Some((from, x, y)) = clip(dst, src, src_rect, x, y) else {
+        return;
+    };
+    let (src_format, dst_format) = (src.format, dst.format);
+    for row in 0..from.height {
+        let src_row = &src.row(from.y as u32 + row)[from.x as usize..from.right() as usize];
+        let dst_row = &mut dst.row_mut(y as u32 + row)[x as usize..x as usize + from.width as usize];
+        for (d, s) in dst_row.iter_mut().zip(src_row) {
+            let alpha = (s >> 24) * opacity as u32 / 255;
+            if alpha == 0 {
+                continue;
+            }
+            let colour = dst_format.encode(src_format.decode(*s));
+            *d = if alpha == 255 {
+                colour
+            } else {
+                mix(colour, *d, alpha, 16) | mix(colour, *d, alpha, 8) | mix(colour, *d, alpha, 0)
+            };
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Shared GPU buffer objects
+//!
+//! A buffer object is a pixel buffer a client allocates, maps, draws into and
+//! then passes to the compositor or another process by handle, without the
+//! pixels ever being copied. The handle is the capability: it carries an
+//! unguessable tag from the kernel CSPRNG, so only processes that were sent
+//! it can use the buffer.
+//! Backing memory is an IPC grant owned by the allocating process.
+
+use spin::Mutex;
+
+use super::framebuffer::{Framebuffer, PixelFormat};
+use super::GpuError;
+use crate::capability::{self, Permission};
+use crate::ipc::grant::{self, GrantMapping};
+
+/// Maximum number of live buffer objects
+pub const MAX_BUFFER_OBJECTS: usize = 128;
+
+/// Largest width or height of a buffer object
+pub const MAX_BO_DIMENSION: u32 = 8192;
+
+/// Bits of a handle holding the slot index
+const SLOT_BITS: u32 = 16;
+
+/// Capability handle naming a buffer object
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BoHandle(pub u64);
+
+impl BoHandle {
+    fn slot(self) -> usize {
+        (self.0 & ((1 << SLOT_BITS) - 1)) as usize
+    }
+}
+
+/// Layout of a buffer object
+#[derive(Clone, Copy, Debug)]
+pub struct BoInfo {
+    pub width: u32,
+    pub height: u32,
+    /// Pixels per row
+    pub stride: u32,
+    pub format: PixelFormat,
+    /// Size in bytes
+    pub size: usize,
+}
+
+struct BufferObject {
+    handle: BoHandle,
+    owner: u32,
+    grant: u32,
+    fb: Framebuffer,
+}
+
+struct Table {
+    objects: [Option<BufferObject>; MAX_BUFFER_OBJECTS],
+}
+
+static OBJECTS: Mutex<Table> = Mutex::new(Table {
+    objects: [const { None }; MAX_BUFFER_OBJECTS],
+});
+
+impl Table {
+    fn get(&self, handle: BoHandle) -> Result<&BufferObject, GpuError> {
+        match self.objects.get(handle.slot()) {
+            Some(Some(bo)) if bo.handle == handle => Ok(bo),
+            _ => Err(GpuError::InvalidBuffer),
+        }
+    }
+}
+
+/// Handle for `slot` with a fresh random tag
+fn new_handle(slot: usize) -> BoHandle {
+    BoHandle(crate::kernel::random::next_u64() << SLOT_BITS | slot as u64)
+}
+
+/// Allocate a zeroed `width` x `height` buffer for `owner`
+///
+/// The caller needs the GPU access permission.
+pub fn bo_create(owner: u32, width: u32, height: u32, format: PixelFormat) -> Result<BoHandle, GpuError> {
+    capability::check_permission(owner, Permission::GpuAccess).map_err(|_| GpuError::PermissionDenied)?;
+    if width == 0 || height == 0 || width > MAX_BO_DIMENSION || height > MAX_BO_DIMENSION {
+        return Err(GpuError::UnsupportedMode);
+    }
+
+    let mut table = OBJECTS.lock();
+    let slot = table
+        .objects
+        .iter()
+        .position(|o| o.is_none())
+        .ok_or(GpuError::TooManyBuffers)?;
+
+    let size = width as usize * height as usize * 4;
+    let grant = grant::grant_create(owner, size).map_err(|_| GpuError::OutOfMemory)?;
+    let mapping = match grant::grant_map(grant, owner) {
+        Ok(mapping) => mapping,
+        Err(_) => {
+            let _ = grant::grant_revoke(grant, owner);
+            return Err(GpuError::OutOfMemory);
+        }
+    };
+
+    let handle = new_handle(slot);
+    table.objects[slot] = Some(BufferObject {
+        handle,
+        owner,
+        grant,
+        fb: Framebuffer {
+            base: mapping.base,
+            width,
+            height,
+            stride: width,
+            format,
+        },
+    });
+    Ok(handle)
+}
+
+/// Map a buffer object into `process`, which must hold its handle
+pub fn bo_map(handle: BoHandle, process: u32) -> Result<GrantMapping, GpuError> {
+    let table = OBJECTS.lock();
+    let bo = table.get(handle)?;
+    if process != bo.owner {
+        grant::grant_share(bo.grant, bo.owner, process).map_err(|_| GpuError::TooManyBuffers)?;
+    }
+    grant::grant_map(bo.grant, process).map_err(|_| GpuError::InvalidBuffer)
+}
+
+/// Layout of a buffer object
+pub fn bo_info(handle: BoHandle) -> Result<BoInfo, GpuError> {
+    let table = OBJECTS.lock();
+    let fb = table.get(handle)?.fb;
+    Ok(BoInfo {
+        width: fb.width,
+        height: fb.height,
+        stride: fb.stride,
+        format: fb.format,
+        size: fb.size(),
+    })
+}
+
+/// Pixels of a buffer object, for the compositor and 2D operations
+pub fn bo_framebuffer(handle: BoHandle) -> Result<Framebuffer, GpuError> {
+    Ok(OBJECTS.lock().get(handle)?.fb)
+}
+
+/// Destroy a buffer object; only its owner may do so
+///
+/// The handle stops working everywhere. Surfaces already showing the buffer
+/// keep their last contents until a new buffer is attached.
+pub fn bo_destroy(handle: BoHandle, owner: u32) -> Result<(), GpuError> {
+    let mut table = OBJECTS.lock();
+    let bo = table.get(handle)?;
+    if bo.owner != owner {
+        return Err(GpuError::PermissionDenied);
+    }
+    let _ = grant::grant_revoke(bo.grant, owner);
+    table.objects[handle.slot()] = None;
+    Ok(())
+}
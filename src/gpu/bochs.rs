@@ -0,0 +1,290 @@
+//! Bochs/QEMU standard VGA display (VBE DISPI interface)
+//!
+//! The linear framebuffer is set up with twice the visible height so the two
+//! halves can be flipped by moving the scanout Y offset. QEMU exposes the
+//! monitor's EDID at the start of the MMIO BAR. Video memory past the
+//! scanout buffers is handed to the VRAM allocator.
+
+use arrayvec::ArrayVec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Once;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+use crate::kernel::pci::{self, DeviceId, PciError};
+
+use super::display::{Display, Mode};
+use super::edid::{Edid, EDID_BLOCK_SIZE, MAX_EDID_MODES};
+use super::framebuffer::{Framebuffer, PixelFormat};
+use super::{vram, GpuError};
+
+/// Modes offered even without EDID
+const MODES: [Mode; 7] = [
+    Mode { width: 640, height: 480, refresh_mhz: 60_000 },
+    Mode { width: 800, height: 600, refresh_mhz: 60_000 },
+    Mode { width: 1024, height: 768, refresh_mhz: 60_000 },
+    Mode { width: 1280, height: 720, refresh_mhz: 60_000 },
+    Mode { width: 1280, height: 1024, refresh_mhz: 60_000 },
+    Mode { width: 1920, height: 1080, refresh_mhz: 60_000 },
+    Mode { width: 2560, height: 1440, refresh_mhz: 60_000 },
+];
+
+const PCI_VENDOR: u16 = 0x1234;
+const PCI_DEVICE: u16 = 0x1111;
+
+static PCI_IDS: [DeviceId; 1] = [DeviceId::new(PCI_VENDOR, PCI_DEVICE)];
+
+const DISPI_INDEX_PORT: u16 = 0x01CE;
+const DISPI_DATA_PORT: u16 = 0x01CF;
+
+/// VGA input status register 1 (bit 3: vertical retrace)
+const VGA_INPUT_STATUS: u16 = 0x03DA;
+const VGA_RETRACE: u8 = 1 << 3;
+
+const DISPI_ID: u16 = 0;
+const DISPI_XRES: u16 = 1;
+const DISPI_YRES: u16 = 2;
+const DISPI_BPP: u16 = 3;
+const DISPI_ENABLE: u16 = 4;
+const DISPI_VIRT_WIDTH: u16 = 6;
+const DISPI_VIRT_HEIGHT: u16 = 7;
+const DISPI_Y_OFFSET: u16 = 9;
+const DISPI_VIDEO_MEMORY_64K: u16 = 0xA;
+
+const DISPI_ID_MIN: u16 = 0xB0C0;
+const DISPI_ENABLED: u16 = 0x01;
+const DISPI_LFB_ENABLED: u16 = 0x40;
+
+/// BAR holding the EDID and register MMIO window
+const MMIO_BAR: usize = 2;
+const MMIO_SIZE: usize = 4096;
+
+/// Mode set at probe time, before an output mode is picked
+const DEFAULT_MODE: Mode = Mode {
+    width: 1024,
+    height: 768,
+    refresh_mhz: 60_000,
+};
+
+pub struct BochsDisplay {
+    /// Kernel virtual address of the linear framebuffer
+    lfb: u64,
+    width: AtomicU32,
+    height: AtomicU32,
+    refresh_mhz: AtomicU32,
+    /// Supported modes that fit in video memory, smallest first
+    modes: ArrayVec<Mode, { MODES.len() + MAX_EDID_MODES }>,
+    edid: Option<Edid>,
+}
+
+/// Bytes needed for two buffers of `width` x `height`
+fn flip_size(width: u32, height: u32) -> usize {
+    width as usize * height as usize * 4 * 2
+}
+
+/// Program the DISPI registers for a double-height `width` x `height` mode
+fn program_mode(width: u32, height: u32) {
+    dispi_write(DISPI_ENABLE, 0);
+    dispi_write(DISPI_XRES, width as u16);
+    dispi_write(DISPI_YRES, height as u16);
+    dispi_write(DISPI_BPP, 32);
+    dispi_write(DISPI_VIRT_WIDTH, width as u16);
+    dispi_write(DISPI_VIRT_HEIGHT, (height * 2) as u16);
+    dispi_write(DISPI_Y_OFFSET, 0);
+    dispi_write(DISPI_ENABLE, DISPI_ENABLED | DISPI_LFB_ENABLED);
+}
+
+fn dispi_read(index: u16) -> u16 {
+    unsafe {
+        Port::<u16>::new(DISPI_INDEX_PORT).write(index);
+        Port::<u16>::new(DISPI_DATA_PORT).read()
+    }
+}
+
+fn dispi_write(index: u16, value: u16) {
+    unsafe {
+        Port::<u16>::new(DISPI_INDEX_PORT).write(index);
+        Port::<u16>::new(DISPI_DATA_PORT).write(value);
+    }
+}
+
+/// Read the EDID block QEMU places at the start of the MMIO BAR
+fn read_edid(device: &pci::Device) -> Option<Edid> {
+    let (base, _) = device.memory_bar(MMIO_BAR)?;
+    let mmio = crate::kernel::memory::map_mmio(PhysAddr::new(base), MMIO_SIZE).ok()?;
+    let mut block = [0u8; EDID_BLOCK_SIZE];
+    for (i, byte) in block.iter_mut().enumerate() {
+        *byte = unsafe { core::ptr::read_volatile(mmio.as_ptr::<u8>().add(i)) };
+    }
+    Edid::parse(&block).ok()
+}
+
+impl BochsDisplay {
+    fn probe(device: &pci::Device) -> Result<Self, GpuError> {
+        if dispi_read(DISPI_ID) < DISPI_ID_MIN {
+            return Err(GpuError::DeviceNotFound);
+        }
+
+        let (lfb_phys, _) = device.memory_bar(0).ok_or(GpuError::DeviceNotFound)?;
+        let vram_size = dispi_read(DISPI_VIDEO_MEMORY_64K) as usize * 64 * 1024;
+
+        if flip_size(DEFAULT_MODE.width, DEFAULT_MODE.height) > vram_size {
+            return Err(GpuError::UnsupportedMode);
+        }
+
+        device.address.enable(pci::COMMAND_MEMORY);
+        let edid = read_edid(device);
+        let mut modes: ArrayVec<Mode, { MODES.len() + MAX_EDID_MODES }> = ArrayVec::new();
+        for mode in MODES.iter().chain(edid.iter().flat_map(|e| e.modes.iter())) {
+            let fits = flip_size(mode.width, mode.height) <= vram_size && mode.height * 2 <= u16::MAX as u32;
+            if fits && !modes.contains(mode) {
+                modes.push(*mode);
+            }
+        }
+        modes.sort_unstable_by_key(|m| (m.width * m.height, m.width, m.refresh_mhz));
+
+        // Map all of video memory so later mode switches need no remapping
+        let lfb = crate::kernel::memory::map_mmio(PhysAddr::new(lfb_phys), vram_size)
+            .map_err(|_| GpuError::MappingFailed)?;
+
+        vram::init(lfb.as_u64(), lfb_phys, vram_size as u64);
+        vram::reserve_scanout(flip_size(DEFAULT_MODE.width, DEFAULT_MODE.height) as u64)?;
+        program_mode(DEFAULT_MODE.width, DEFAULT_MODE.height);
+
+        Ok(Self {
+            lfb: lfb.as_u64(),
+            width: AtomicU32::new(DEFAULT_MODE.width),
+            height: AtomicU32::new(DEFAULT_MODE.height),
+            refresh_mhz: AtomicU32::new(DEFAULT_MODE.refresh_mhz),
+            modes,
+            edid,
+        })
+    }
+
+    fn size(&self) -> (u32, u32) {
+        (self.width.load(Ordering::Acquire), self.height.load(Ordering::Acquire))
+    }
+}
+
+impl Display for BochsDisplay {
+    fn name(&self) -> &'static str {
+        "bochs-vbe"
+    }
+
+    fn mode(&self) -> Mode {
+        let (width, height) = self.size();
+        Mode {
+            width,
+            height,
+            refresh_mhz: self.refresh_mhz.load(Ordering::Relaxed),
+        }
+    }
+
+    fn modes(&self) -> &[Mode] {
+        &self.modes
+    }
+
+    fn edid(&self) -> Option<&Edid> {
+        self.edid.as_ref()
+    }
+
+    fn set_mode(&self, mode: Mode) -> Result<(), GpuError> {
+        if !self.modes().contains(&mode) {
+            return Err(GpuError::UnsupportedMode);
+        }
+        vram::reserve_scanout(flip_size(mode.width, mode.height) as u64)?;
+        program_mode(mode.width, mode.height);
+        self.width.store(mode.width, Ordering::Release);
+        self.height.store(mode.height, Ordering::Release);
+        self.refresh_mhz.store(mode.refresh_mhz, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn buffer_count(&self) -> usize {
+        2
+    }
+
+    fn framebuffer(&self, index: usize) -> Framebuffer {
+        let (width, height) = self.size();
+        let buffer_size = width as u64 * height as u64 * 4;
+        Framebuffer {
+            base: self.lfb + index as u64 * buffer_size,
+            width,
+            height,
+            stride: width,
+            format: PixelFormat::Xrgb8888,
+        }
+    }
+
+    fn flip(&self, index: usize) {
+        dispi_write(DISPI_Y_OFFSET, (index as u32 * self.size().1) as u16);
+    }
+
+    fn wait_vblank(&self) -> bool {
+        let mut status = Port::<u8>::new(VGA_INPUT_STATUS);
+        unsafe {
+            // Let any retrace in progress finish, then wait for the next one
+            while status.read() & VGA_RETRACE != 0 {
+                core::hint::spin_loop();
+            }
+            while status.read() & VGA_RETRACE == 0 {
+                core::hint::spin_loop();
+            }
+        }
+        true
+    }
+}
+
+static BOCHS: Once<BochsDisplay> = Once::new();
+
+struct BochsDriver;
+
+static DRIVER: BochsDriver = BochsDriver;
+
+impl pci::Driver for BochsDriver {
+    fn name(&self) -> &'static str {
+        "bochs-display"
+    }
+
+    fn ids(&self) -> &'static [DeviceId] {
+        &PCI_IDS
+    }
+
+    fn probe(&self, device: &pci::Device) -> Result<(), PciError> {
+        // The DISPI ports are global, so only one adapter can be driven
+        if BOCHS.is_completed() {
+            return Err(PciError::Unsupported);
+        }
+        match BochsDisplay::probe(device) {
+            Ok(display) => {
+                BOCHS.call_once(|| display);
+                Ok(())
+            }
+            Err(GpuError::DeviceNotFound) => Err(PciError::Unsupported),
+            Err(e) => {
+                crate::klog!(Warn, "Bochs display not set up: {:?}", e);
+                Err(PciError::ProbeFailed)
+            }
+        }
+    }
+
+    fn remove(&self, _device: &pci::Device) {
+        // Outputs are never taken away, so the adapter's is switched off
+        let Some(bochs) = BOCHS.get() else {
+            return;
+        };
+        for info in super::output::outputs() {
+            if super::output::display(info.id).is_some_and(|display| core::ptr::addr_eq(display, bochs)) {
+                let _ = super::output::set_enabled(info.id, false);
+            }
+        }
+    }
+}
+
+/// Register the driver, returning the adapter if one was found
+pub fn init() -> Result<&'static BochsDisplay, GpuError> {
+    if let Err(e) = pci::register_driver(&DRIVER) {
+        crate::klog!(Warn, "Bochs display driver not registered: {:?}", e);
+    }
+    BOCHS.get().ok_or(GpuError::DeviceNotFound)
+}
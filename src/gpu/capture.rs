@@ -0,0 +1,114 @@
+//! Screen capture
+//!
+//! Snapshots what an output currently shows, or the contents of a single
+//! surface, into a TagFS object as a binary PPM image. Capturing exposes
+//! other processes' pixels, so callers need the screen capture permission
+//! and every capture is written to the audit log.
+
+use core::fmt::Write;
+
+use arrayvec::ArrayString;
+use spin::Mutex;
+
+use super::framebuffer::{Framebuffer, Rect};
+use super::{compositor, present, GpuError};
+use crate::capability::{self, AuditEntry, Permission, AUDIT_ALLOWED, AUDIT_DENIED};
+use crate::tagfs::{self, Tag};
+
+/// Tag added to every capture
+pub const CAPTURE_TAG: &str = "screenshot";
+
+/// Audit action recorded for a capture
+pub const AUDIT_SCREEN_CAPTURE: u32 = 0x4743_0001;
+
+/// Extra tags a caller can attach
+pub const MAX_CAPTURE_TAGS: usize = 4;
+
+/// What to capture
+#[derive(Clone, Copy, Debug)]
+pub enum CaptureSource {
+    /// The composited frame on screen
+    Output(u32),
+    /// One surface, without anything stacked above it
+    Surface(u32),
+}
+
+/// Encoding buffer, reused between captures and grown when too small
+struct Scratch {
+    base: u64,
+    size: usize,
+}
+
+static SCRATCH: Mutex<Scratch> = Mutex::new(Scratch { base: 0, size: 0 });
+
+impl Scratch {
+    fn get(&mut self, size: usize) -> Result<&mut [u8], GpuError> {
+        if size > self.size {
+            // The old region cannot be freed; captures rarely grow
+            let base = crate::kernel::memory::allocate_region(size).map_err(|_| GpuError::OutOfMemory)?;
+            self.base = base.as_u64();
+            self.size = size;
+        }
+        Ok(unsafe { core::slice::from_raw_parts_mut(self.base as *mut u8, size) })
+    }
+}
+
+fn audit(process: u32, result: u32) {
+    capability::audit_log(AuditEntry {
+        timestamp: crate::scheduler::ticks(),
+        process_id: process,
+        action: AUDIT_SCREEN_CAPTURE,
+        result,
+        signature: [0; 16],
+    });
+}
+
+/// Encode `rect` of `fb` as a binary PPM into `out`, returning its length
+fn encode_ppm(fb: &Framebuffer, rect: Rect, out: &mut [u8]) -> usize {
+    let mut header = ArrayString::<32>::new();
+    let _ = write!(header, "P6\n{} {}\n255\n", rect.width, rect.height);
+    out[..header.len()].copy_from_slice(header.as_bytes());
+
+    let mut pos = header.len();
+    for y in rect.y..rect.bottom() {
+        for pixel in &fb.row(y as u32)[rect.x as usize..rect.right() as usize] {
+            let rgb = fb.format.decode(*pixel);
+            out[pos..pos + 3].copy_from_slice(&[(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8]);
+            pos += 3;
+        }
+    }
+    pos
+}
+
+/// Capture `source` into a new TagFS object on behalf of `process`
+///
+/// The object is tagged with [`CAPTURE_TAG`] and `tags`. Returns its ID.
+pub fn capture(process: u32, source: CaptureSource, tags: &[Tag]) -> Result<u64, GpuError> {
+    if capability::check_permission(process, Permission::ScreenCapture).is_err() {
+        audit(process, AUDIT_DENIED);
+        return Err(GpuError::PermissionDenied);
+    }
+    if tags.len() > MAX_CAPTURE_TAGS {
+        return Err(GpuError::CaptureFailed);
+    }
+
+    let fb = match source {
+        CaptureSource::Output(output) => present::front_buffer(output).ok_or(GpuError::InvalidOutput)?,
+        CaptureSource::Surface(id) => {
+            let comp = compositor::COMPOSITOR.lock();
+            comp.surface(id).ok_or(GpuError::InvalidSurface)?.buffer
+        }
+    };
+    let rect = fb.bounds();
+
+    let mut scratch = SCRATCH.lock();
+    let out = scratch.get(32 + rect.width as usize * rect.height as usize * 3)?;
+    let len = encode_ppm(&fb, rect, out);
+
+    let mut all_tags = [Tag::new(CAPTURE_TAG); MAX_CAPTURE_TAGS + 1];
+    all_tags[1..=tags.len()].copy_from_slice(tags);
+    let object = tagfs::tagfs_create(&all_tags[..=tags.len()], &out[..len]).map_err(|_| GpuError::CaptureFailed)?;
+
+    audit(process, AUDIT_ALLOWED);
+    Ok(object)
+}
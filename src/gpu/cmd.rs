@@ -0,0 +1,250 @@
+//! GPU command submission
+//!
+//! Clients, the compositor and the AI engine each get a command context
+//! with its own ring. Submitting a batch returns a [`Fence`] that signals
+//! when the batch has executed. The engine drains rings round-robin with a
+//! per-context budget, so one busy context cannot starve the others.
+//! Commands name buffers by buffer-object or video memory handle, which is
+//! checked at submission and again at execution in case the buffer was
+//! destroyed or evicted.
+
+use spin::Mutex;
+
+use super::bo::{self, BoHandle};
+use super::compute::MatVec;
+use super::fence::{self, Fence, MAX_TIMELINES};
+use super::framebuffer::Rect;
+use super::{accel, GpuError};
+use crate::capability::{self, Permission};
+
+/// Maximum number of command contexts
+pub const MAX_CONTEXTS: usize = MAX_TIMELINES;
+
+/// Commands a ring holds
+pub const RING_SIZE: usize = 64;
+
+/// Commands one context may run per engine pass
+pub const CONTEXT_BUDGET: usize = 8;
+
+/// An engine command
+#[derive(Clone, Copy, Debug)]
+pub enum Command {
+    Fill {
+        dst: BoHandle,
+        rect: Rect,
+        rgb: u32,
+    },
+    Copy {
+        dst: BoHandle,
+        src: BoHandle,
+        src_rect: Rect,
+        x: i32,
+        y: i32,
+    },
+    Scale {
+        dst: BoHandle,
+        dst_rect: Rect,
+        src: BoHandle,
+        src_rect: Rect,
+    },
+    Blend {
+        dst: BoHandle,
+        src: BoHandle,
+        src_rect: Rect,
+        x: i32,
+        y: i32,
+        opacity: u8,
+    },
+    MatVec(MatVec),
+}
+
+impl Command {
+    fn buffers(&self) -> [Option<BoHandle>; 2] {
+        match *self {
+            Command::Fill { dst, .. } => [Some(dst), None],
+            Command::Copy { dst, src, .. } | Command::Scale { dst, src, .. } | Command::Blend { dst, src, .. } => {
+                [Some(dst), Some(src)]
+            }
+            Command::MatVec(_) => [None, None],
+        }
+    }
+
+    /// Check that every buffer the command names exists
+    fn validate(&self) -> Result<(), GpuError> {
+        for handle in self.buffers().into_iter().flatten() {
+            bo::bo_info(handle)?;
+        }
+        if let Command::MatVec(op) = self {
+            op.buffers()?;
+        }
+        Ok(())
+    }
+
+    fn execute(&self) -> Result<(), GpuError> {
+        match *self {
+            Command::Fill { dst, rect, rgb } => accel::fill(&mut bo::bo_framebuffer(dst)?, rect, rgb),
+            Command::Copy { dst, src, src_rect, x, y } => {
+                accel::copy(&mut bo::bo_framebuffer(dst)?, &bo::bo_framebuffer(src)?, src_rect, x, y)
+            }
+            Command::Scale { dst, dst_rect, src, src_rect } => {
+                accel::scale(&mut bo::bo_framebuffer(dst)?, dst_rect, &bo::bo_framebuffer(src)?, src_rect)
+            }
+            Command::Blend { dst, src, src_rect, x, y, opacity } => {
+                accel::blend(&mut bo::bo_framebuffer(dst)?, &bo::bo_framebuffer(src)?, src_rect, x, y, opacity)
+            }
+            Command::MatVec(op) => op.execute()?,
+        }
+        Ok(())
+    }
+}
+
+struct Context {
+    owner: u32,
+    ring: [Option<(Command, u64)>; RING_SIZE],
+    /// Next slot to execute
+    head: usize,
+    /// Commands queued
+    len: usize,
+    /// Sequence number of the next command
+    next_seqno: u64,
+    /// Commands that failed at execution
+    errors: u64,
+}
+
+struct Engine {
+    contexts: [Option<Context>; MAX_CONTEXTS],
+    /// Context the next pass starts with
+    next: usize,
+}
+
+static ENGINE: Mutex<Engine> = Mutex::new(Engine {
+    contexts: [const { None }; MAX_CONTEXTS],
+    next: 0,
+});
+
+impl Engine {
+    fn context_mut(&mut self, context: u32, owner: u32) -> Result<&mut Context, GpuError> {
+        match self.contexts.get_mut(context as usize) {
+            Some(Some(ctx)) if ctx.owner == owner => Ok(ctx),
+            Some(Some(_)) => Err(GpuError::PermissionDenied),
+            _ => Err(GpuError::InvalidContext),
+        }
+    }
+}
+
+/// Create a command context for `owner`, which needs the GPU access permission
+pub fn context_create(owner: u32) -> Result<u32, GpuError> {
+    capability::check_permission(owner, Permission::GpuAccess).map_err(|_| GpuError::PermissionDenied)?;
+    new_context(owner)
+}
+
+/// Create a command context owned by the kernel, for in-kernel users such
+/// as the AI engine
+pub(crate) fn kernel_context_create() -> Result<u32, GpuError> {
+    new_context(0)
+}
+
+fn new_context(owner: u32) -> Result<u32, GpuError> {
+    let mut engine = ENGINE.lock();
+    let index = engine
+        .contexts
+        .iter()
+        .position(|c| c.is_none())
+        .ok_or(GpuError::TooManyContexts)?;
+
+    // Timelines never go backwards, so earlier owners' fences stay signaled
+    engine.contexts[index] = Some(Context {
+        owner,
+        ring: [None; RING_SIZE],
+        head: 0,
+        len: 0,
+        next_seqno: fence::completed(index as u32) + 1,
+        errors: 0,
+    });
+    Ok(index as u32)
+}
+
+/// Destroy a context, dropping its queued commands
+///
+/// Fences on the context signal immediately so nobody waits forever.
+pub fn context_destroy(context: u32, owner: u32) -> Result<(), GpuError> {
+    let mut engine = ENGINE.lock();
+    let last = engine.context_mut(context, owner)?.next_seqno - 1;
+    engine.contexts[context as usize] = None;
+    drop(engine);
+
+    fence::signal(context, last);
+    Ok(())
+}
+
+/// Queue `commands` on a context, returning the fence of the last one
+///
+/// The whole batch is rejected if it does not fit in the ring or names a
+/// buffer that does not exist.
+pub fn submit(context: u32, owner: u32, commands: &[Command]) -> Result<Fence, GpuError> {
+    for command in commands {
+        command.validate()?;
+    }
+
+    let mut engine = ENGINE.lock();
+    let ctx = engine.context_mut(context, owner)?;
+    if commands.is_empty() {
+        return Ok(Fence {
+            context,
+            seqno: ctx.next_seqno - 1,
+        });
+    }
+    if ctx.len + commands.len() > RING_SIZE {
+        return Err(GpuError::RingFull);
+    }
+
+    for command in commands {
+        let slot = (ctx.head + ctx.len) % RING_SIZE;
+        ctx.ring[slot] = Some((*command, ctx.next_seqno));
+        ctx.len += 1;
+        ctx.next_seqno += 1;
+    }
+    Ok(Fence {
+        context,
+        seqno: ctx.next_seqno - 1,
+    })
+}
+
+/// Commands of a context that failed at execution
+pub fn context_errors(context: u32, owner: u32) -> Result<u64, GpuError> {
+    Ok(ENGINE.lock().context_mut(context, owner)?.errors)
+}
+
+/// Run queued commands, at most [`CONTEXT_BUDGET`] per context
+///
+/// Returns the number of commands executed. Called from the GPU poll loop.
+pub fn process() -> usize {
+    let mut executed = 0;
+    let mut engine = ENGINE.lock();
+    let start = engine.next;
+    engine.next = (start + 1) % MAX_CONTEXTS;
+
+    for i in 0..MAX_CONTEXTS {
+        let index = (start + i) % MAX_CONTEXTS;
+        let Some(ctx) = engine.contexts[index].as_mut() else {
+            continue;
+        };
+
+        let mut retired = None;
+        for _ in 0..CONTEXT_BUDGET.min(ctx.len) {
+            let (command, seqno) = ctx.ring[ctx.head].take().expect("queued command");
+            ctx.head = (ctx.head + 1) % RING_SIZE;
+            ctx.len -= 1;
+            if command.execute().is_err() {
+                ctx.errors += 1;
+            }
+            retired = Some(seqno);
+            executed += 1;
+        }
+
+        if let Some(seqno) = retired {
+            fence::signal(index as u32, seqno);
+        }
+    }
+    executed
+}
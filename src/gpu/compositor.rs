@@ -0,0 +1,470 @@
+//! Damage-tracked compositor
+//!
+//! Clients report which parts of their surface changed. Each frame only the
+//! screen regions covered by that damage (plus whatever moved) are redrawn
+//! into the back buffer and presented; an unchanged screen costs nothing.
+//! Every surface belongs to one output, and outputs are composed separately.
+//! A rotated or scaled output is composed in its logical space into a staging
+//! buffer, which the output's transform then maps onto the back buffer.
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use super::framebuffer::{Framebuffer, Rect};
+use super::output::{self, MAX_OUTPUTS, PRIMARY_OUTPUT};
+use super::effects::{self, Effects};
+use super::{accel, cursor, present, tile, GpuError};
+
+/// Maximum number of surfaces
+pub const MAX_SURFACES: usize = 64;
+
+/// Damage rectangles tracked before they are collapsed into their bounding box
+pub const MAX_DAMAGE_RECTS: usize = 16;
+
+/// Colour behind all surfaces
+pub const BACKGROUND: u32 = 0x0020_2428;
+
+/// A set of damaged rectangles
+#[derive(Clone, Default)]
+pub struct Damage {
+    rects: ArrayVec<Rect, MAX_DAMAGE_RECTS>,
+}
+
+impl Damage {
+    pub const fn new() -> Self {
+        Self { rects: ArrayVec::new_const() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rects.is_empty()
+    }
+
+    /// Add a rectangle, merging into an overlapping one where possible
+    pub fn add(&mut self, rect: Rect) {
+        if rect.is_empty() {
+            return;
+        }
+        if let Some(r) = self.rects.iter_mut().find(|r| !r.intersect(&rect).is_empty()) {
+            *r = r.union(&rect);
+            return;
+        }
+        if self.rects.try_push(rect).is_err() {
+            // Too fragmented: fall back to one bounding box
+            let bounds = self.bounds().union(&rect);
+            self.rects.clear();
+            self.rects.push(bounds);
+        }
+    }
+
+    pub fn add_all(&mut self, other: &Damage) {
+        for rect in other.rects.iter() {
+            self.add(*rect);
+        }
+    }
+
+    /// Bounding box of all damage
+    pub fn bounds(&self) -> Rect {
+        self.rects.iter().fold(Rect::default(), |acc, r| acc.union(r))
+    }
+
+    pub fn rects(&self) -> &[Rect] {
+        &self.rects
+    }
+
+    pub fn clear(&mut self) {
+        self.rects.clear();
+    }
+}
+
+/// A client surface
+pub struct Surface {
+    pub id: u32,
+    /// Owning process
+    pub owner: u32,
+    /// Client pixels
+    pub buffer: Framebuffer,
+    /// Output showing the surface
+    pub output: u32,
+    /// Position of the top-left corner on that output
+    pub x: i32,
+    pub y: i32,
+    pub visible: bool,
+    pub effects: Effects,
+    /// Changed area in surface coordinates since the last frame
+    damage: Damage,
+}
+
+impl Surface {
+    /// Area covered on screen
+    pub fn screen_rect(&self) -> Rect {
+        Rect::new(self.x, self.y, self.buffer.width, self.buffer.height)
+    }
+
+    /// Area drawn on screen, shadow included
+    pub fn painted_rect(&self) -> Rect {
+        self.effects.painted_rect(self.screen_rect())
+    }
+}
+
+pub(crate) struct Compositor {
+    pub(crate) surfaces: [Option<Surface>; MAX_SURFACES],
+    /// Surface IDs, bottom to top
+    pub(crate) stack: ArrayVec<u32, MAX_SURFACES>,
+    next_id: u32,
+    /// Per-output screen damage not tied to a surface (moves, unmaps)
+    screen_damage: [Damage; MAX_OUTPUTS],
+    /// Damage of each output's previous frame, needed when the back buffer is two frames old
+    previous: [Damage; MAX_OUTPUTS],
+    /// Staging buffer of each transformed output: base address and capacity
+    staging: [(u64, usize); MAX_OUTPUTS],
+}
+
+impl Compositor {
+    const fn new() -> Self {
+        Self {
+            surfaces: [const { None }; MAX_SURFACES],
+            stack: ArrayVec::new_const(),
+            next_id: 1,
+            screen_damage: [const { Damage::new() }; MAX_OUTPUTS],
+            previous: [const { Damage::new() }; MAX_OUTPUTS],
+            staging: [(0, 0); MAX_OUTPUTS],
+        }
+    }
+
+    pub(crate) fn surface_mut(&mut self, id: u32) -> Result<&mut Surface, GpuError> {
+        self.surfaces
+            .iter_mut()
+            .flatten()
+            .find(|s| s.id == id)
+            .ok_or(GpuError::InvalidSurface)
+    }
+
+    pub(crate) fn surface(&self, id: u32) -> Option<&Surface> {
+        self.surfaces.iter().flatten().find(|s| s.id == id)
+    }
+
+    /// Damage the screen area a surface covers
+    pub(crate) fn damage_surface_area(&mut self, id: u32) {
+        let area = self.surface(id).filter(|s| s.visible).map(|s| (s.output, s.painted_rect()));
+        if let Some((output, rect)) = area {
+            self.screen_damage[output as usize].add(rect);
+        }
+    }
+
+    /// Collect this frame's damage on `output`
+    fn take_damage(&mut self, output: u32) -> Damage {
+        let mut damage = core::mem::take(&mut self.screen_damage[output as usize]);
+        for surface in self.surfaces.iter_mut().flatten().filter(|s| s.output == output) {
+            if surface.visible {
+                for r in surface.damage.rects() {
+                    damage.add(Rect::new(surface.x + r.x, surface.y + r.y, r.width, r.height));
+                }
+            }
+            surface.damage.clear();
+        }
+        damage
+    }
+
+    /// Redraw `damage` of `output` into `target`
+    fn render(&self, output: u32, target: &mut Framebuffer, damage: &Damage) {
+        if accel::has_backend() {
+            for rect in damage.rects() {
+                self.repaint(output, target, *rect);
+            }
+        } else {
+            // CPU rendering: spread the work over all CPUs
+            let context = TileContext { comp: self, output };
+            tile::render(target, damage.rects(), repaint_tile, &context as *const TileContext as *const ());
+        }
+    }
+
+    /// Logical-space buffer for a transformed output, like `like` but `width` x `height`
+    fn staging_buffer(&mut self, output: u32, like: &Framebuffer, width: u32, height: u32) -> Result<Framebuffer, GpuError> {
+        let size = width as usize * height as usize * 4;
+        let (base, capacity) = &mut self.staging[output as usize];
+        if size > *capacity {
+            // The old region cannot be freed; transforms rarely change
+            let region = crate::kernel::memory::allocate_region(size).map_err(|_| GpuError::OutOfMemory)?;
+            *base = region.as_u64();
+            *capacity = size;
+        }
+        Ok(Framebuffer {
+            base: *base,
+            width,
+            height,
+            stride: width,
+            format: like.format,
+        })
+    }
+
+    /// Redraw `region` of `output` into `target`
+    fn repaint(&self, output: u32, target: &mut Framebuffer, region: Rect) {
+        let region = region.intersect(&target.bounds());
+        if region.is_empty() {
+            return;
+        }
+
+        accel::fill(target, region, BACKGROUND);
+        for id in self.stack.iter() {
+            let surface = match self.surface(*id) {
+                Some(s) if s.visible && s.output == output => s,
+                _ => continue,
+            };
+            let visible = surface.painted_rect().intersect(&region);
+            if visible.is_empty() {
+                continue;
+            }
+            effects::draw_surface(target, &surface.buffer, surface.x, surface.y, &surface.effects, visible);
+        }
+        if output == PRIMARY_OUTPUT {
+            cursor::draw_software(target, region);
+        }
+    }
+}
+
+pub(crate) static COMPOSITOR: Mutex<Compositor> = Mutex::new(Compositor::new());
+
+/// Create a surface showing `buffer` on the primary output, placed on top of the stack
+pub fn surface_create(owner: u32, buffer: Framebuffer) -> Result<u32, GpuError> {
+    let mut comp = COMPOSITOR.lock();
+    let id = comp.next_id;
+    let slot = comp
+        .surfaces
+        .iter_mut()
+        .find(|s| s.is_none())
+        .ok_or(GpuError::TooManySurfaces)?;
+
+    let mut damage = Damage::new();
+    damage.add(buffer.bounds());
+    *slot = Some(Surface {
+        id,
+        owner,
+        buffer,
+        output: PRIMARY_OUTPUT,
+        x: 0,
+        y: 0,
+        visible: true,
+        effects: Effects::NONE,
+        damage,
+    });
+
+    comp.stack.push(id);
+    comp.next_id += 1;
+    Ok(id)
+}
+
+/// Remove a surface, exposing what was below it
+pub fn surface_destroy(id: u32) -> Result<(), GpuError> {
+    let mut comp = COMPOSITOR.lock();
+    comp.damage_surface_area(id);
+
+    let slot = comp
+        .surfaces
+        .iter_mut()
+        .find(|s| matches!(s, Some(s) if s.id == id))
+        .ok_or(GpuError::InvalidSurface)?;
+    *slot = None;
+    comp.stack.retain(|s| *s != id);
+    Ok(())
+}
+
+/// Replace a surface's buffer (the whole surface is damaged)
+pub fn surface_attach(id: u32, buffer: Framebuffer) -> Result<(), GpuError> {
+    let mut comp = COMPOSITOR.lock();
+    // Old area too, in case the new buffer is smaller
+    comp.damage_surface_area(id);
+    let surface = comp.surface_mut(id)?;
+    surface.buffer = buffer;
+    surface.damage.add(buffer.bounds());
+    Ok(())
+}
+
+/// Mark part of a surface (in surface coordinates) as changed
+pub fn surface_damage(id: u32, rect: Rect) -> Result<(), GpuError> {
+    let mut comp = COMPOSITOR.lock();
+    let surface = comp.surface_mut(id)?;
+    let rect = rect.intersect(&surface.buffer.bounds());
+    surface.damage.add(rect);
+    Ok(())
+}
+
+/// Show a surface on another output, keeping its position
+pub fn surface_assign(id: u32, output: u32) -> Result<(), GpuError> {
+    if output as usize >= MAX_OUTPUTS || output::display(output).is_none() {
+        return Err(GpuError::InvalidOutput);
+    }
+    let mut comp = COMPOSITOR.lock();
+    comp.damage_surface_area(id);
+    comp.surface_mut(id)?.output = output;
+    comp.damage_surface_area(id);
+    Ok(())
+}
+
+/// Move a surface on its output
+pub fn surface_move(id: u32, x: i32, y: i32) -> Result<(), GpuError> {
+    let mut comp = COMPOSITOR.lock();
+    comp.damage_surface_area(id);
+    let surface = comp.surface_mut(id)?;
+    surface.x = x;
+    surface.y = y;
+    comp.damage_surface_area(id);
+    Ok(())
+}
+
+/// Put a surface on top of the stack
+pub fn surface_raise(id: u32) -> Result<(), GpuError> {
+    let mut comp = COMPOSITOR.lock();
+    comp.surface_mut(id)?;
+    if comp.stack.last() != Some(&id) {
+        comp.stack.retain(|s| *s != id);
+        comp.stack.push(id);
+        comp.damage_surface_area(id);
+    }
+    Ok(())
+}
+
+/// Put a surface at the bottom of the stack
+pub fn surface_lower(id: u32) -> Result<(), GpuError> {
+    let mut comp = COMPOSITOR.lock();
+    comp.surface_mut(id)?;
+    comp.stack.retain(|s| *s != id);
+    comp.stack.insert(0, id);
+    comp.damage_surface_area(id);
+    Ok(())
+}
+
+/// Topmost visible surface under (`x`, `y`) on `output`, with the point in
+/// surface coordinates
+pub fn surface_at(output: u32, x: i32, y: i32) -> Option<(u32, i32, i32)> {
+    let comp = COMPOSITOR.lock();
+    comp.stack.iter().rev().find_map(|id| {
+        let surface = comp.surface(*id)?;
+        let hit = surface.visible && surface.output == output && surface.screen_rect().contains(x, y);
+        hit.then_some((surface.id, x - surface.x, y - surface.y))
+    })
+}
+
+/// Owner and screen area of a surface
+pub fn surface_geometry(id: u32) -> Option<(u32, Rect)> {
+    COMPOSITOR.lock().surface(id).map(|s| (s.owner, s.screen_rect()))
+}
+
+/// Surface IDs, bottom to top
+pub fn stacking_order() -> ArrayVec<u32, MAX_SURFACES> {
+    COMPOSITOR.lock().stack.clone()
+}
+
+/// Change how a surface is drawn
+pub fn surface_set_effects(id: u32, effects: Effects) -> Result<(), GpuError> {
+    let effects = effects.clamped();
+    let mut comp = COMPOSITOR.lock();
+    if comp.surface_mut(id)?.effects == effects {
+        return Ok(());
+    }
+    comp.damage_surface_area(id);
+    comp.surface_mut(id)?.effects = effects;
+    comp.damage_surface_area(id);
+    Ok(())
+}
+
+/// Show or hide a surface
+pub fn surface_set_visible(id: u32, visible: bool) -> Result<(), GpuError> {
+    let mut comp = COMPOSITOR.lock();
+    comp.damage_surface_area(id);
+    comp.surface_mut(id)?.visible = visible;
+    comp.damage_surface_area(id);
+    Ok(())
+}
+
+/// Mark an area of `output` as needing a repaint
+pub fn damage_screen(output: u32, rect: Rect) {
+    if let Some(damage) = COMPOSITOR.lock().screen_damage.get_mut(output as usize) {
+        damage.add(rect);
+    }
+}
+
+/// Damage all of `output` (e.g. after a mode change)
+pub fn damage_output(output: u32) {
+    if let Some(back) = present::back_buffer(output) {
+        damage_screen(output, back.bounds());
+    }
+}
+
+/// Damage every output
+pub fn damage_all() {
+    for output in 0..MAX_OUTPUTS as u32 {
+        damage_output(output);
+    }
+}
+
+/// What tile workers draw from
+struct TileContext<'a> {
+    comp: &'a Compositor,
+    output: u32,
+}
+
+fn repaint_tile(context: *const (), target: &mut Framebuffer, tile: Rect) {
+    // `tile::render` keeps the context alive until every tile is drawn
+    let context = unsafe { &*(context as *const TileContext) };
+    context.comp.repaint(context.output, target, tile);
+}
+
+/// Compose and present `output` if anything on it changed
+fn compose_output(output: u32) -> Result<Option<u64>, GpuError> {
+    let mut back = present::back_buffer(output).ok_or(GpuError::DeviceNotFound)?;
+    let transform = output::transform(output);
+
+    let damage = {
+        let mut comp = COMPOSITOR.lock();
+        let damage = comp.take_damage(output);
+        if damage.is_empty() {
+            return Ok(None);
+        }
+
+        // A flipped back buffer still shows the frame before last, so it
+        // also needs last frame's changes
+        let mut repaint = damage.clone();
+        if present::buffer_age(output) > 1 {
+            repaint.add_all(&comp.previous[output as usize]);
+        }
+        if transform.is_identity() {
+            comp.render(output, &mut back, &repaint);
+        } else {
+            let (width, height) = transform.logical_size(back.width, back.height);
+            let mut staging = comp.staging_buffer(output, &back, width, height)?;
+            comp.render(output, &mut staging, &repaint);
+            for rect in repaint.rects() {
+                transform.apply(&staging, &mut back, *rect);
+            }
+        }
+
+        comp.previous[output as usize] = damage.clone();
+        damage
+    };
+
+    let bounds = transform.to_physical(damage.bounds(), back.width, back.height);
+    present::present(output, bounds).map(Some)
+}
+
+/// Compose and present every enabled output that changed
+///
+/// Returns the highest frame number presented, or `None` when all outputs
+/// were already up to date.
+pub fn compose() -> Result<Option<u64>, GpuError> {
+    let mut presented = None;
+    let mut any_output = false;
+    for output in 0..MAX_OUTPUTS as u32 {
+        if !output::is_enabled(output) || present::back_buffer(output).is_none() {
+            continue;
+        }
+        any_output = true;
+        if let Some(frame) = compose_output(output)? {
+            presented = presented.max(Some(frame));
+        }
+    }
+
+    if !any_output {
+        return Err(GpuError::DeviceNotFound);
+    }
+    Ok(presented)
+}
@@ -0,0 +1,144 @@
+//! Compute dispatch
+//!
+//! Matrix-vector products over buffers in video memory, queued on command
+//! contexts like 2D operations. A driver with shader cores installs a
+//! [`ComputeEngine`] backend; without one, or for a format it does not
+//! handle, the product runs on the CPU through the aperture mapping.
+
+use spin::Mutex;
+
+use super::vram::{self, Placement, VramHandle};
+use super::GpuError;
+use crate::ai::{kernels, quant, simd};
+
+/// Layout of a weight matrix
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WeightFormat {
+    F32,
+    F16,
+    Q8_0,
+    Q4_0,
+}
+
+impl WeightFormat {
+    /// Bytes of a `rows` x `cols` matrix
+    pub fn matrix_size(self, rows: u32, cols: u32) -> u64 {
+        let elements = rows as u64 * cols as u64;
+        match self {
+            WeightFormat::F32 => elements * 4,
+            WeightFormat::F16 => elements * 2,
+            WeightFormat::Q8_0 => elements / quant::QK as u64 * quant::Q8_0_BLOCK as u64,
+            WeightFormat::Q4_0 => elements / quant::QK as u64 * quant::Q4_0_BLOCK as u64,
+        }
+    }
+}
+
+/// `output = weights * input (+ bias)` with f32 vectors
+#[derive(Clone, Copy, Debug)]
+pub struct MatVec {
+    pub weights: VramHandle,
+    pub format: WeightFormat,
+    /// Outputs
+    pub rows: u32,
+    /// Inputs
+    pub cols: u32,
+    pub input: VramHandle,
+    pub bias: Option<VramHandle>,
+    pub output: VramHandle,
+}
+
+/// Resolved buffers of a [`MatVec`]
+#[derive(Clone, Copy, Debug)]
+pub struct MatVecBuffers {
+    pub weights: Placement,
+    pub input: Placement,
+    pub bias: Option<Placement>,
+    pub output: Placement,
+}
+
+/// A compute engine
+///
+/// Returns `false` if the engine cannot run the operation, in which case
+/// the CPU path is used instead.
+pub trait ComputeEngine: Sync {
+    fn name(&self) -> &'static str;
+
+    fn matvec(&self, _op: &MatVec, _buffers: &MatVecBuffers) -> bool {
+        false
+    }
+}
+
+static BACKEND: Mutex<Option<&'static dyn ComputeEngine>> = Mutex::new(None);
+
+/// Install a hardware compute engine
+pub fn set_backend(backend: &'static dyn ComputeEngine) {
+    *BACKEND.lock() = Some(backend);
+}
+
+/// Whether a hardware compute engine is installed
+pub fn has_backend() -> bool {
+    BACKEND.lock().is_some()
+}
+
+impl MatVec {
+    /// Resolve every buffer, checking each is large enough
+    ///
+    /// Fails with `InvalidBuffer` if one was freed or evicted.
+    pub fn buffers(&self) -> Result<MatVecBuffers, GpuError> {
+        let fits = |handle: VramHandle, bytes: u64| -> Result<Placement, GpuError> {
+            let placement = vram::vram_placement(handle)?;
+            if placement.size < bytes {
+                return Err(GpuError::InvalidBuffer);
+            }
+            Ok(placement)
+        };
+        if self.rows == 0 || self.cols == 0 {
+            return Err(GpuError::InvalidBuffer);
+        }
+        let quantized = matches!(self.format, WeightFormat::Q8_0 | WeightFormat::Q4_0);
+        if quantized && !(self.cols as usize).is_multiple_of(quant::QK) {
+            return Err(GpuError::InvalidBuffer);
+        }
+        Ok(MatVecBuffers {
+            weights: fits(self.weights, self.format.matrix_size(self.rows, self.cols))?,
+            input: fits(self.input, self.cols as u64 * 4)?,
+            bias: self.bias.map(|b| fits(b, self.rows as u64 * 4)).transpose()?,
+            output: fits(self.output, self.rows as u64 * 4)?,
+        })
+    }
+
+    /// Run the product, on the compute engine if it takes it
+    pub fn execute(&self) -> Result<(), GpuError> {
+        let buffers = self.buffers()?;
+        let backend = *BACKEND.lock();
+        if backend.is_some_and(|b| b.matvec(self, &buffers)) {
+            return Ok(());
+        }
+
+        let (rows, cols) = (self.rows as usize, self.cols as usize);
+        let weights = unsafe {
+            core::slice::from_raw_parts(
+                buffers.weights.cpu_addr as *const u8,
+                self.format.matrix_size(self.rows, self.cols) as usize,
+            )
+        };
+        let input = unsafe { core::slice::from_raw_parts(buffers.input.cpu_addr as *const f32, cols) };
+        let bias = buffers
+            .bias
+            .map(|b| unsafe { core::slice::from_raw_parts(b.cpu_addr as *const f32, rows) });
+        let output = unsafe { core::slice::from_raw_parts_mut(buffers.output.cpu_addr as *mut f32, rows) };
+
+        let guard = simd::begin();
+        let v = guard.vector();
+        match self.format {
+            WeightFormat::F32 => {
+                let weights = unsafe { core::slice::from_raw_parts(weights.as_ptr() as *const f32, rows * cols) };
+                kernels::matvec_f32(v, weights, input, bias, output);
+            }
+            WeightFormat::F16 => quant::matvec_f16(weights, input, bias, output),
+            WeightFormat::Q8_0 => quant::matvec_q8_0(v, weights, input, bias, output),
+            WeightFormat::Q4_0 => quant::matvec_q4_0(v, weights, input, bias, output),
+        }
+        Ok(())
+    }
+}
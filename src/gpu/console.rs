@@ -0,0 +1,158 @@
+//! Framebuffer text console
+//!
+//! Text is drawn into a RAM surface with the default font and shown by the
+//! compositor like any other surface. Writers only touch the console's own
+//! buffer; changed cells are handed to the compositor from [`flush`].
+
+use core::fmt;
+use spin::Mutex;
+
+use super::accel;
+use super::compositor::{self, Damage};
+use super::font::{self, Font};
+use super::framebuffer::{Framebuffer, Rect};
+use super::GpuError;
+
+/// Default colours (0x00RRGGBB)
+pub const CONSOLE_FG: u32 = 0x00D0_D0D0;
+pub const CONSOLE_BG: u32 = 0x0000_0000;
+
+/// Columns per tab stop
+const TAB_WIDTH: u32 = 8;
+
+struct Console {
+    fb: Framebuffer,
+    font: Font,
+    surface: u32,
+    cols: u32,
+    rows: u32,
+    col: u32,
+    row: u32,
+    fg: u32,
+    bg: u32,
+    damage: Damage,
+}
+
+impl Console {
+    fn cell(&self, col: u32, row: u32) -> Rect {
+        Rect::new(
+            (col * self.font.width()) as i32,
+            (row * self.font.height()) as i32,
+            self.font.width(),
+            self.font.height(),
+        )
+    }
+
+    fn newline(&mut self) {
+        self.col = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return;
+        }
+
+        // Scroll up one text row
+        let line = self.font.height();
+        let used = self.rows * line;
+        let src = self.fb;
+        accel::copy(&mut self.fb, &src, Rect::new(0, line as i32, src.width, used - line), 0, 0);
+        accel::fill(&mut self.fb, Rect::new(0, (used - line) as i32, src.width, line), self.bg);
+        self.damage.add(self.fb.bounds());
+    }
+
+    fn put(&mut self, c: char) {
+        match c {
+            '\n' => self.newline(),
+            '\r' => self.col = 0,
+            '\t' => {
+                self.col = (self.col / TAB_WIDTH + 1) * TAB_WIDTH;
+                if self.col >= self.cols {
+                    self.newline();
+                }
+            }
+            '\u{8}' => self.col = self.col.saturating_sub(1),
+            c => {
+                if self.col >= self.cols {
+                    self.newline();
+                }
+                let cell = self.cell(self.col, self.row);
+                font::draw_char(&mut self.fb, &self.font, cell.x, cell.y, c, self.fg, self.bg);
+                self.damage.add(cell);
+                self.col += 1;
+            }
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.chars().for_each(|c| self.put(c));
+        Ok(())
+    }
+}
+
+static CONSOLE: Mutex<Option<Console>> = Mutex::new(None);
+
+/// Bring up the console on the primary display with the default font
+pub fn init() -> Result<(), GpuError> {
+    let font = font::default_font().ok_or(GpuError::NoFont)?;
+    let screen = super::present::back_buffer(super::output::PRIMARY_OUTPUT).ok_or(GpuError::DeviceNotFound)?;
+    let (width, height) = super::output::logical_size(super::output::PRIMARY_OUTPUT).ok_or(GpuError::DeviceNotFound)?;
+
+    let base = crate::kernel::memory::allocate_region(width as usize * height as usize * 4)
+        .map_err(|_| GpuError::OutOfMemory)?;
+    let mut fb = Framebuffer {
+        base: base.as_u64(),
+        width,
+        height,
+        stride: width,
+        format: screen.format,
+    };
+    let bounds = fb.bounds();
+    accel::fill(&mut fb, bounds, CONSOLE_BG);
+
+    let surface = compositor::surface_create(0, fb)?;
+    *CONSOLE.lock() = Some(Console {
+        fb,
+        font,
+        surface,
+        cols: fb.width / font.width(),
+        rows: fb.height / font.height(),
+        col: 0,
+        row: 0,
+        fg: CONSOLE_FG,
+        bg: CONSOLE_BG,
+        damage: Damage::new(),
+    });
+    Ok(())
+}
+
+/// Write formatted text; silently dropped if the console is busy or absent
+pub fn write_fmt(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    // Never spin here: this runs from the print path, possibly with the lock held
+    if let Some(mut console) = CONSOLE.try_lock() {
+        if let Some(console) = console.as_mut() {
+            let _ = console.write_fmt(args);
+        }
+    }
+}
+
+/// Set the colours used for subsequent text
+pub fn set_colors(fg: u32, bg: u32) {
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        console.fg = fg;
+        console.bg = bg;
+    }
+}
+
+/// Pass changed cells to the compositor
+pub fn flush() {
+    let (surface, damage) = match CONSOLE.lock().as_mut() {
+        Some(console) => (console.surface, core::mem::take(&mut console.damage)),
+        None => return,
+    };
+    for rect in damage.rects() {
+        let _ = compositor::surface_damage(surface, *rect);
+    }
+}
@@ -0,0 +1,169 @@
+//! Pointer cursor
+//!
+//! Displays with a cursor plane get the image once and are then only told
+//! where to put it, so moving the pointer never recomposes the screen.
+//! Otherwise the cursor is drawn on top of the surfaces by the compositor
+//! and a move damages its old and new positions. The cursor lives on the
+//! primary output.
+
+use spin::Mutex;
+
+use super::display::Display;
+use super::output::{self, PRIMARY_OUTPUT};
+use super::accel;
+use super::framebuffer::{Framebuffer, PixelFormat, Rect};
+use super::{compositor, GpuError};
+
+/// Largest cursor image in either dimension
+pub const MAX_CURSOR_SIZE: u32 = 64;
+
+/// A cursor image, 0xAARRGGBB pixels packed `width` per row
+#[derive(Clone, Copy)]
+pub struct CursorImage {
+    pub pixels: [u32; (MAX_CURSOR_SIZE * MAX_CURSOR_SIZE) as usize],
+    pub width: u32,
+    pub height: u32,
+    /// Point of the image that sits at the pointer position
+    pub hot_x: i32,
+    pub hot_y: i32,
+}
+
+struct Cursor {
+    image: Option<CursorImage>,
+    /// Pointer position in screen coordinates
+    x: i32,
+    y: i32,
+    visible: bool,
+    /// Display whose cursor plane shows the image, if any
+    plane: Option<&'static dyn Display>,
+}
+
+impl Cursor {
+    /// Screen area covered by the image
+    fn rect(&self) -> Rect {
+        match &self.image {
+            Some(image) if self.visible => {
+                Rect::new(self.x - image.hot_x, self.y - image.hot_y, image.width, image.height)
+            }
+            _ => Rect::default(),
+        }
+    }
+
+    /// Tell the cursor plane where the image goes, or return the screen
+    /// area to recompose when drawing in software
+    fn update(&self, old: Rect) -> Option<Rect> {
+        match self.plane {
+            Some(display) => {
+                let rect = self.rect();
+                display.move_cursor((!rect.is_empty()).then_some((rect.x, rect.y)));
+                None
+            }
+            None => Some(old.union(&self.rect())),
+        }
+    }
+}
+
+static CURSOR: Mutex<Cursor> = Mutex::new(Cursor {
+    image: None,
+    x: 0,
+    y: 0,
+    visible: false,
+    plane: None,
+});
+
+/// Apply the result of [`Cursor::update`] once the cursor lock is dropped
+fn redraw(damage: Option<Rect>) {
+    if let Some(rect) = damage {
+        compositor::damage_screen(PRIMARY_OUTPUT, rect);
+    }
+}
+
+/// Use `image` as the pointer, on the cursor plane if the display has one
+pub fn set_image(image: &CursorImage) -> Result<(), GpuError> {
+    if image.width == 0 || image.height == 0 || image.width > MAX_CURSOR_SIZE || image.height > MAX_CURSOR_SIZE {
+        return Err(GpuError::UnsupportedMode);
+    }
+
+    let damage = {
+        let mut cursor = CURSOR.lock();
+        let old = cursor.rect();
+        let plane = output::display(PRIMARY_OUTPUT)
+            .filter(|_| output::transform(PRIMARY_OUTPUT).is_identity())
+            .filter(|d| d.set_cursor_image(image));
+        if let (Some(old_plane), None) = (cursor.plane, plane) {
+            old_plane.move_cursor(None);
+        }
+        cursor.plane = plane;
+        cursor.image = Some(*image);
+        cursor.visible = true;
+        // Switching from software to a plane must still erase the drawn copy
+        cursor.update(old).or(Some(old)).filter(|r| !r.is_empty())
+    };
+    redraw(damage);
+    Ok(())
+}
+
+/// Re-pick hardware or software drawing after the primary output changed
+pub(crate) fn output_changed() {
+    let image = CURSOR.lock().image;
+    if let Some(image) = image {
+        let _ = set_image(&image);
+    }
+}
+
+/// Move the pointer to (`x`, `y`)
+pub fn move_to(x: i32, y: i32) {
+    let damage = {
+        let mut cursor = CURSOR.lock();
+        let old = cursor.rect();
+        cursor.x = x;
+        cursor.y = y;
+        cursor.update(old)
+    };
+    redraw(damage);
+}
+
+/// Show or hide the pointer
+pub fn set_visible(visible: bool) {
+    let damage = {
+        let mut cursor = CURSOR.lock();
+        let old = cursor.rect();
+        cursor.visible = visible;
+        cursor.update(old)
+    };
+    redraw(damage);
+}
+
+/// Current pointer position
+pub fn position() -> (i32, i32) {
+    let cursor = CURSOR.lock();
+    (cursor.x, cursor.y)
+}
+
+/// Whether the cursor is on a hardware plane
+pub fn is_hardware() -> bool {
+    CURSOR.lock().plane.is_some()
+}
+
+/// Draw the software cursor over `region` of `target`
+///
+/// Called by the compositor after the surfaces have been painted.
+pub(crate) fn draw_software(target: &mut Framebuffer, region: Rect) {
+    let cursor = CURSOR.lock();
+    let image = match &cursor.image {
+        Some(image) if cursor.plane.is_none() => image,
+        _ => return,
+    };
+
+    let rect = cursor.rect();
+    let area = rect.intersect(&region);
+    let pixels = Framebuffer {
+        base: image.pixels.as_ptr() as u64,
+        width: image.width,
+        height: image.height,
+        stride: image.width,
+        format: PixelFormat::Xrgb8888,
+    };
+    let src = Rect::new(area.x - rect.x, area.y - rect.y, area.width, area.height);
+    accel::blend(target, &pixels, src, area.x, area.y, u8::MAX);
+}
@@ -0,0 +1,70 @@
+//! Display driver interface
+
+use super::cursor::CursorImage;
+use super::edid::Edid;
+use super::framebuffer::Framebuffer;
+use super::GpuError;
+
+/// A display timing as seen by the compositor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mode {
+    pub width: u32,
+    pub height: u32,
+    /// Refresh rate in millihertz
+    pub refresh_mhz: u32,
+}
+
+/// A scanout engine that can show one of its framebuffers
+pub trait Display: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Current mode
+    fn mode(&self) -> Mode;
+
+    /// Modes the output can be switched to
+    fn modes(&self) -> &[Mode] {
+        &[]
+    }
+
+    /// Program a new mode; scanout buffers change size
+    fn set_mode(&self, _mode: Mode) -> Result<(), GpuError> {
+        Err(GpuError::UnsupportedMode)
+    }
+
+    /// Whether a monitor is attached to the connector
+    fn connected(&self) -> bool {
+        true
+    }
+
+    /// EDID of the attached monitor, if it could be read
+    fn edid(&self) -> Option<&Edid> {
+        None
+    }
+
+    /// Number of scanout buffers; two or more allow page flipping
+    fn buffer_count(&self) -> usize;
+
+    /// Scanout buffer `index`
+    fn framebuffer(&self, index: usize) -> Framebuffer;
+
+    /// Start scanning out buffer `index`
+    fn flip(&self, index: usize);
+
+    /// Block until the start of the next vertical blank
+    ///
+    /// Returns `false` if the hardware has no vblank indication.
+    fn wait_vblank(&self) -> bool {
+        false
+    }
+
+    /// Load `image` into the hardware cursor plane
+    ///
+    /// Returns `false` if there is no cursor plane; the cursor is then drawn
+    /// by the compositor.
+    fn set_cursor_image(&self, _image: &CursorImage) -> bool {
+        false
+    }
+
+    /// Place the cursor plane's top-left corner, or hide it with `None`
+    fn move_cursor(&self, _position: Option<(i32, i32)>) {}
+}
@@ -0,0 +1,176 @@
+//! EDID parsing
+//!
+//! Decodes the 128-byte base block monitors report over DDC: identity,
+//! the native (preferred) timing and every mode advertised in the detailed,
+//! standard and established timing sections.
+
+use arrayvec::{ArrayString, ArrayVec};
+
+use super::display::Mode;
+
+/// Size of the EDID base block
+pub const EDID_BLOCK_SIZE: usize = 128;
+
+/// Modes kept from one EDID
+pub const MAX_EDID_MODES: usize = 32;
+
+const HEADER: [u8; 8] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+
+const ESTABLISHED_TIMINGS: usize = 35;
+const STANDARD_TIMINGS: usize = 38;
+const DESCRIPTORS: usize = 54;
+const DESCRIPTOR_SIZE: usize = 18;
+
+/// Display descriptor tag holding the monitor name
+const TAG_MONITOR_NAME: u8 = 0xFC;
+
+/// Established timings, bit 7 of byte 35 first
+const ESTABLISHED: [(u32, u32, u32); 17] = [
+    (720, 400, 70),
+    (720, 400, 88),
+    (640, 480, 60),
+    (640, 480, 67),
+    (640, 480, 72),
+    (640, 480, 75),
+    (800, 600, 56),
+    (800, 600, 60),
+    (800, 600, 72),
+    (800, 600, 75),
+    (832, 624, 75),
+    (1024, 768, 87),
+    (1024, 768, 60),
+    (1024, 768, 70),
+    (1024, 768, 75),
+    (1280, 1024, 75),
+    (1152, 870, 75),
+];
+
+/// EDID errors
+#[derive(Debug)]
+pub enum EdidError {
+    BadHeader,
+    BadChecksum,
+}
+
+/// Decoded monitor information
+#[derive(Clone, Debug)]
+pub struct Edid {
+    /// Three-letter PNP manufacturer ID
+    pub manufacturer: [u8; 3],
+    pub product: u16,
+    pub serial: u32,
+    /// Monitor name descriptor, if present
+    pub name: ArrayString<13>,
+    /// Native mode (first detailed timing)
+    pub preferred: Option<Mode>,
+    /// All advertised modes, without duplicates
+    pub modes: ArrayVec<Mode, MAX_EDID_MODES>,
+}
+
+impl Edid {
+    /// Parse an EDID base block
+    pub fn parse(data: &[u8; EDID_BLOCK_SIZE]) -> Result<Self, EdidError> {
+        if data[..8] != HEADER {
+            return Err(EdidError::BadHeader);
+        }
+        if data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(EdidError::BadChecksum);
+        }
+
+        let id = u16::from_be_bytes([data[8], data[9]]);
+        let letter = |shift: u16| b'@' + ((id >> shift) & 0x1F) as u8;
+        let mut edid = Edid {
+            manufacturer: [letter(10), letter(5), letter(0)],
+            product: u16::from_le_bytes([data[10], data[11]]),
+            serial: u32::from_le_bytes([data[12], data[13], data[14], data[15]]),
+            name: ArrayString::new(),
+            preferred: None,
+            modes: ArrayVec::new(),
+        };
+
+        for descriptor in data[DESCRIPTORS..DESCRIPTORS + 4 * DESCRIPTOR_SIZE].chunks_exact(DESCRIPTOR_SIZE) {
+            match detailed_timing(descriptor) {
+                Some(mode) => {
+                    edid.preferred.get_or_insert(mode);
+                    edid.add(mode);
+                }
+                None if descriptor[3] == TAG_MONITOR_NAME => {
+                    let text = descriptor[5..].split(|b| *b == b'\n').next().unwrap_or(&[]);
+                    for c in text.iter().filter(|c| c.is_ascii_graphic() || **c == b' ') {
+                        let _ = edid.name.try_push(*c as char);
+                    }
+                    let trimmed = edid.name.trim_end().len();
+                    edid.name.truncate(trimmed);
+                }
+                None => {}
+            }
+        }
+
+        for pair in data[STANDARD_TIMINGS..STANDARD_TIMINGS + 16].chunks_exact(2) {
+            if let Some(mode) = standard_timing(pair[0], pair[1]) {
+                edid.add(mode);
+            }
+        }
+
+        let bits = &data[ESTABLISHED_TIMINGS..ESTABLISHED_TIMINGS + 3];
+        let established = u32::from_be_bytes([0, bits[0], bits[1], bits[2]]);
+        for (bit, (width, height, hz)) in ESTABLISHED.iter().enumerate() {
+            if established & (1 << (23 - bit)) != 0 {
+                edid.add(Mode {
+                    width: *width,
+                    height: *height,
+                    refresh_mhz: hz * 1000,
+                });
+            }
+        }
+
+        Ok(edid)
+    }
+
+    fn add(&mut self, mode: Mode) {
+        if !self.modes.contains(&mode) {
+            let _ = self.modes.try_push(mode);
+        }
+    }
+}
+
+/// Decode a detailed timing descriptor (`None` for display descriptors)
+fn detailed_timing(d: &[u8]) -> Option<Mode> {
+    let clock_10khz = u16::from_le_bytes([d[0], d[1]]) as u64;
+    if clock_10khz == 0 {
+        return None;
+    }
+    let h_active = d[2] as u32 | ((d[4] as u32 & 0xF0) << 4);
+    let h_blank = d[3] as u32 | ((d[4] as u32 & 0x0F) << 8);
+    let v_active = d[5] as u32 | ((d[7] as u32 & 0xF0) << 4);
+    let v_blank = d[6] as u32 | ((d[7] as u32 & 0x0F) << 8);
+
+    let total = (h_active + h_blank) as u64 * (v_active + v_blank) as u64;
+    if h_active == 0 || v_active == 0 || total == 0 {
+        return None;
+    }
+    Some(Mode {
+        width: h_active,
+        height: v_active,
+        refresh_mhz: ((clock_10khz * 10_000 * 1000 + total / 2) / total) as u32,
+    })
+}
+
+/// Decode a two-byte standard timing (`None` for unused entries)
+fn standard_timing(b0: u8, b1: u8) -> Option<Mode> {
+    if (b0, b1) == (0x01, 0x01) || b0 == 0 {
+        return None;
+    }
+    let width = (b0 as u32 + 31) * 8;
+    let height = match b1 >> 6 {
+        0 => width * 10 / 16,
+        1 => width * 3 / 4,
+        2 => width * 4 / 5,
+        _ => width * 9 / 16,
+    };
+    Some(Mode {
+        width,
+        height,
+        refresh_mhz: ((b1 & 0x3F) as u32 + 60) * 1000,
+    })
+}
@@ -0,0 +1,208 @@
+//! Surface effects
+//!
+//! Per-surface opacity, alpha blending, rounded corners and drop shadows,
+//! applied while a surface is composed. Surfaces without effects take the
+//! plain copy path; opacity and blending alone go through the 2D engine's
+//! blend. Corners and shadows are drawn by the CPU with anti-aliased edges.
+
+use super::accel;
+use super::framebuffer::{Framebuffer, Rect};
+
+/// Largest shadow blur radius in pixels
+pub const MAX_SHADOW_RADIUS: u32 = 64;
+
+/// Farthest a shadow is offset from its surface, in pixels on either axis,
+/// beyond any screen
+pub const MAX_SHADOW_OFFSET: i32 = 8192;
+
+/// Largest corner radius in pixels
+pub const MAX_CORNER_RADIUS: u32 = 256;
+
+/// Sub-pixel steps per pixel in edge distances
+const SUBPIXEL: i64 = 16;
+
+/// A drop shadow behind a surface
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Shadow {
+    /// Blur distance over which the shadow fades out
+    pub radius: u32,
+    pub offset_x: i32,
+    pub offset_y: i32,
+    /// 0xAARRGGBB
+    pub argb: u32,
+}
+
+/// How a surface is drawn
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Effects {
+    /// Opacity of the whole surface, 255 being opaque
+    pub opacity: u8,
+    /// Honour the alpha byte of the surface's pixels
+    pub blending: bool,
+    /// Corners are clipped to circles of this radius
+    pub corner_radius: u32,
+    pub shadow: Option<Shadow>,
+}
+
+impl Effects {
+    /// An opaque rectangle
+    pub const NONE: Effects = Effects {
+        opacity: 255,
+        blending: false,
+        corner_radius: 0,
+        shadow: None,
+    };
+
+    /// Clamp values to what the compositor supports
+    pub fn clamped(mut self) -> Self {
+        self.corner_radius = self.corner_radius.min(MAX_CORNER_RADIUS);
+        if let Some(shadow) = self.shadow.as_mut() {
+            shadow.radius = shadow.radius.min(MAX_SHADOW_RADIUS);
+            shadow.offset_x = shadow.offset_x.clamp(-MAX_SHADOW_OFFSET, MAX_SHADOW_OFFSET);
+            shadow.offset_y = shadow.offset_y.clamp(-MAX_SHADOW_OFFSET, MAX_SHADOW_OFFSET);
+        }
+        self.shadow = self.shadow.filter(|s| s.argb >> 24 != 0);
+        self
+    }
+
+    /// Area drawn for a surface covering `rect`, shadow included
+    pub fn painted_rect(&self, rect: Rect) -> Rect {
+        match self.shadow {
+            Some(shadow) => rect.union(&shadow_rect(rect, shadow)),
+            None => rect,
+        }
+    }
+}
+
+impl Default for Effects {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+fn shadow_rect(rect: Rect, shadow: Shadow) -> Rect {
+    let r = shadow.radius as i32;
+    Rect::new(
+        rect.x.saturating_add(shadow.offset_x).saturating_sub(r),
+        rect.y.saturating_add(shadow.offset_y).saturating_sub(r),
+        rect.width + 2 * shadow.radius,
+        rect.height + 2 * shadow.radius,
+    )
+}
+
+/// Integer square root
+fn isqrt(n: u64) -> u64 {
+    if n < 2 {
+        return n;
+    }
+    let mut x = n;
+    let mut y = x.div_ceil(2);
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
+
+/// Distance, in sub-pixels, from the centre of pixel (`x`, `y`) to a
+/// `width` x `height` rectangle at the origin with corners of `radius`;
+/// 0 inside
+fn distance(x: i32, y: i32, width: u32, height: u32, radius: u32) -> i64 {
+    let radius = radius.min(width / 2).min(height / 2) as i64 * SUBPIXEL;
+    let (x, y) = (x as i64 * SUBPIXEL + SUBPIXEL / 2, y as i64 * SUBPIXEL + SUBPIXEL / 2);
+    let (w, h) = (width as i64 * SUBPIXEL, height as i64 * SUBPIXEL);
+    // How far past the rectangle shrunk by the radius, per axis
+    let dx = (radius - x).max(x - (w - radius)).max(0);
+    let dy = (radius - y).max(y - (h - radius)).max(0);
+    (isqrt((dx * dx + dy * dy) as u64) as i64 - radius).max(0)
+}
+
+/// Weight 0..=255 of pixel (`x`, `y`) inside a rounded rectangle
+fn coverage(x: i32, y: i32, width: u32, height: u32, radius: u32) -> u32 {
+    let d = distance(x, y, width, height, radius).min(SUBPIXEL);
+    (255 * (SUBPIXEL - d) / SUBPIXEL) as u32
+}
+
+/// Blend 0x00RRGGBB `rgb` into a target pixel with weight `alpha`
+fn blend_pixel(target: &mut Framebuffer, x: i32, y: i32, rgb: u32, alpha: u32) {
+    let format = target.format;
+    let pixel = &mut target.row_mut(y as u32)[x as usize];
+    let colour = format.encode(rgb);
+    *pixel = if alpha >= 255 {
+        colour
+    } else {
+        accel::mix(colour, *pixel, alpha, 16) | accel::mix(colour, *pixel, alpha, 8) | accel::mix(colour, *pixel, alpha, 0)
+    };
+}
+
+fn draw_shadow(target: &mut Framebuffer, rect: Rect, effects: &Effects, shadow: Shadow, clip: Rect) {
+    let corner_radius = effects.corner_radius;
+    let opaque = corner_radius == 0 && effects.opacity == 255 && !effects.blending;
+    let shape = Rect::new(rect.x + shadow.offset_x, rect.y + shadow.offset_y, rect.width, rect.height);
+    let area = shadow_rect(rect, shadow).intersect(&clip);
+    let alpha = shadow.argb >> 24;
+    let fade = shadow.radius as i64 * SUBPIXEL;
+
+    for y in area.y..area.bottom() {
+        for x in area.x..area.right() {
+            // An opaque surface hides the shadow behind it
+            if opaque && rect.contains(x, y) {
+                continue;
+            }
+            let d = distance(x - shape.x, y - shape.y, shape.width, shape.height, corner_radius);
+            let weight = if fade == 0 {
+                (d == 0) as i64 * 255
+            } else {
+                255 * (fade - d).max(0) / fade
+            };
+            let a = alpha * weight as u32 / 255;
+            if a > 0 {
+                blend_pixel(target, x, y, shadow.argb & 0x00FF_FFFF, a);
+            }
+        }
+    }
+}
+
+/// Draw the part of a surface (and its shadow) inside `clip`
+///
+/// `buffer` is shown with its top-left corner at (`x`, `y`).
+pub fn draw_surface(target: &mut Framebuffer, buffer: &Framebuffer, x: i32, y: i32, effects: &Effects, clip: Rect) {
+    let rect = Rect::new(x, y, buffer.width, buffer.height);
+    if let Some(shadow) = effects.shadow {
+        draw_shadow(target, rect, effects, shadow, clip);
+    }
+
+    let visible = rect.intersect(&clip);
+    if visible.is_empty() || effects.opacity == 0 {
+        return;
+    }
+    let src = Rect::new(visible.x - x, visible.y - y, visible.width, visible.height);
+
+    if effects.corner_radius == 0 {
+        if effects.opacity == 255 && !effects.blending {
+            return accel::copy(target, buffer, src, visible.x, visible.y);
+        }
+        if effects.blending {
+            return accel::blend(target, buffer, src, visible.x, visible.y, effects.opacity);
+        }
+    }
+
+    let format = buffer.format;
+    for row in visible.y..visible.bottom() {
+        let sy = (row - y) as u32;
+        for col in visible.x..visible.right() {
+            let sx = col - x;
+            let pixel = buffer.row(sy)[sx as usize];
+            let mut alpha = effects.opacity as u32;
+            if effects.blending {
+                alpha = alpha * (pixel >> 24) / 255;
+            }
+            if effects.corner_radius > 0 {
+                alpha = alpha * coverage(sx, sy as i32, buffer.width, buffer.height, effects.corner_radius) / 255;
+            }
+            if alpha > 0 {
+                blend_pixel(target, col, row, format.decode(pixel), alpha);
+            }
+        }
+    }
+}
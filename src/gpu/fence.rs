@@ -0,0 +1,90 @@
+//! Fences
+//!
+//! Each command context has a timeline: a sequence number that the engine
+//! advances as it retires the context's commands. A fence names a point on
+//! one timeline and is signaled once the timeline reaches it. Waiting on a
+//! fence sleeps on the timeline's wait queue.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::GpuError;
+use crate::scheduler::wait::WaitQueue;
+
+/// Number of timelines, one per command context
+pub const MAX_TIMELINES: usize = 16;
+
+/// Bits of a raw fence holding the sequence number
+const SEQNO_BITS: u32 = 48;
+
+/// A point on a context's timeline
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fence {
+    pub context: u32,
+    pub seqno: u64,
+}
+
+impl Fence {
+    /// Packed form for IPC messages
+    pub fn to_raw(self) -> u64 {
+        (self.context as u64) << SEQNO_BITS | self.seqno
+    }
+
+    pub fn from_raw(raw: u64) -> Self {
+        Self {
+            context: (raw >> SEQNO_BITS) as u32,
+            seqno: raw & ((1 << SEQNO_BITS) - 1),
+        }
+    }
+}
+
+struct Timeline {
+    /// Last retired sequence number
+    completed: AtomicU64,
+    waiters: WaitQueue,
+}
+
+static TIMELINES: [Timeline; MAX_TIMELINES] = [const {
+    Timeline {
+        completed: AtomicU64::new(0),
+        waiters: WaitQueue::new(),
+    }
+}; MAX_TIMELINES];
+
+fn timeline(context: u32) -> Result<&'static Timeline, GpuError> {
+    TIMELINES.get(context as usize).ok_or(GpuError::InvalidContext)
+}
+
+/// Whether the work before `fence` has finished
+pub fn fence_signaled(fence: Fence) -> bool {
+    timeline(fence.context).is_ok_and(|t| t.completed.load(Ordering::Acquire) >= fence.seqno)
+}
+
+/// Sleep until `fence` signals or `timeout_ticks` timer ticks pass
+pub fn fence_wait(fence: Fence, timeout_ticks: Option<u64>) -> Result<(), GpuError> {
+    let timeline = timeline(fence.context)?;
+    let deadline = timeout_ticks.map(|t| crate::scheduler::ticks() + t);
+    let expired = || deadline.is_some_and(|d| crate::scheduler::ticks() >= d);
+
+    timeline
+        .waiters
+        .wait_until(|| timeline.completed.load(Ordering::Acquire) >= fence.seqno || expired());
+
+    if fence_signaled(fence) {
+        Ok(())
+    } else {
+        Err(GpuError::Timeout)
+    }
+}
+
+/// Retire a context's work up to `seqno` and wake its waiters
+pub(crate) fn signal(context: u32, seqno: u64) {
+    if let Ok(timeline) = timeline(context) {
+        timeline.completed.fetch_max(seqno, Ordering::AcqRel);
+        timeline.waiters.wake_all();
+    }
+}
+
+/// Last retired sequence number of a context
+pub(crate) fn completed(context: u32) -> u64 {
+    timeline(context).map_or(0, |t| t.completed.load(Ordering::Acquire))
+}
@@ -0,0 +1,339 @@
+//! Bitmap font rendering
+//!
+//! Loads PSF1/PSF2 console fonts (TrueType rasterization is future work) and
+//! draws text through a cache of pre-coloured glyph images, so drawing a
+//! character is a row-by-row copy instead of a per-pixel bit test.
+
+use spin::Mutex;
+
+use super::accel;
+use super::framebuffer::Framebuffer;
+
+/// Largest supported glyph
+pub const MAX_GLYPH_WIDTH: usize = 16;
+pub const MAX_GLYPH_HEIGHT: usize = 32;
+
+/// Glyph cache entries
+pub const GLYPH_CACHE_SIZE: usize = 64;
+
+/// TagFS tag of the default console font
+pub const CONSOLE_FONT_TAG: &str = "font:console";
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_MODE_HAS_TABLE: u8 = 0x06;
+const PSF1_SEPARATOR: u16 = 0xFFFF;
+const PSF1_SEQUENCE: u16 = 0xFFFE;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xB5, 0x4A, 0x86];
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xFF;
+const PSF2_SEQUENCE: u8 = 0xFE;
+
+/// Font errors
+#[derive(Debug)]
+pub enum FontError {
+    NotFound,
+    BadMagic,
+    Truncated,
+    TooLarge,
+    OutOfMemory,
+}
+
+#[derive(Clone, Copy)]
+enum UnicodeTable {
+    None,
+    Psf1(usize),
+    Psf2(usize),
+}
+
+/// A parsed bitmap font
+#[derive(Clone, Copy)]
+pub struct Font {
+    data: &'static [u8],
+    glyphs: usize,
+    glyph_count: usize,
+    bytes_per_glyph: usize,
+    width: usize,
+    height: usize,
+    table: UnicodeTable,
+    /// Glyph index of every Latin-1 code point
+    latin1: [u16; 256],
+}
+
+fn le_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+impl Font {
+    /// Parse a PSF1 or PSF2 font image
+    pub fn parse(data: &'static [u8]) -> Result<Self, FontError> {
+        let mut font = if data.starts_with(&PSF2_MAGIC) {
+            if data.len() < 32 {
+                return Err(FontError::Truncated);
+            }
+            let header_size = le_u32(data, 8) as usize;
+            let flags = le_u32(data, 12);
+            let glyph_count = le_u32(data, 16) as usize;
+            let bytes_per_glyph = le_u32(data, 20) as usize;
+            let height = le_u32(data, 24) as usize;
+            let width = le_u32(data, 28) as usize;
+            let table_start = glyph_count
+                .checked_mul(bytes_per_glyph)
+                .and_then(|size| size.checked_add(header_size))
+                .ok_or(FontError::Truncated)?;
+            Font {
+                data,
+                glyphs: header_size,
+                glyph_count,
+                bytes_per_glyph,
+                width,
+                height,
+                table: if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+                    UnicodeTable::Psf2(table_start)
+                } else {
+                    UnicodeTable::None
+                },
+                latin1: [0; 256],
+            }
+        } else if data.starts_with(&PSF1_MAGIC) {
+            if data.len() < 4 {
+                return Err(FontError::Truncated);
+            }
+            let mode = data[2];
+            let height = data[3] as usize;
+            let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+            Font {
+                data,
+                glyphs: 4,
+                glyph_count,
+                bytes_per_glyph: height,
+                width: 8,
+                height,
+                table: if mode & PSF1_MODE_HAS_TABLE != 0 {
+                    UnicodeTable::Psf1(4 + glyph_count * height)
+                } else {
+                    UnicodeTable::None
+                },
+                latin1: [0; 256],
+            }
+        } else {
+            return Err(FontError::BadMagic);
+        };
+
+        if font.width > MAX_GLYPH_WIDTH || font.height > MAX_GLYPH_HEIGHT {
+            return Err(FontError::TooLarge);
+        }
+        let glyphs_end = font
+            .glyph_count
+            .checked_mul(font.bytes_per_glyph)
+            .and_then(|size| size.checked_add(font.glyphs));
+        if font.glyph_count == 0
+            || font.bytes_per_glyph < font.height * font.width.div_ceil(8)
+            || glyphs_end.is_none_or(|end| data.len() < end)
+        {
+            return Err(FontError::Truncated);
+        }
+
+        for c in 0..256u32 {
+            let index = font.lookup(char::from_u32(c).unwrap_or('?')).unwrap_or(c as usize);
+            font.latin1[c as usize] = index.min(font.glyph_count - 1) as u16;
+        }
+        Ok(font)
+    }
+
+    /// Find the glyph for a character in the Unicode table
+    fn lookup(&self, c: char) -> Option<usize> {
+        match self.table {
+            UnicodeTable::None => Some(c as usize).filter(|i| *i < self.glyph_count),
+            UnicodeTable::Psf1(start) => {
+                let mut glyph = 0;
+                let mut in_sequence = false;
+                for entry in self.data.get(start..)?.chunks_exact(2) {
+                    match u16::from_le_bytes([entry[0], entry[1]]) {
+                        PSF1_SEPARATOR => {
+                            glyph += 1;
+                            in_sequence = false;
+                        }
+                        PSF1_SEQUENCE => in_sequence = true,
+                        value if !in_sequence && value as u32 == c as u32 => return Some(glyph),
+                        _ => {}
+                    }
+                }
+                None
+            }
+            UnicodeTable::Psf2(start) => {
+                let mut encoded = [0u8; 4];
+                let needle = c.encode_utf8(&mut encoded).as_bytes();
+                let table = self.data.get(start..)?;
+                let mut glyph = 0;
+                let mut pos = 0;
+                while pos < table.len() {
+                    match table[pos] {
+                        PSF2_SEPARATOR => {
+                            glyph += 1;
+                            pos += 1;
+                        }
+                        // Combining sequences are not used for lookup: skip to the separator
+                        PSF2_SEQUENCE => {
+                            while pos < table.len() && table[pos] != PSF2_SEPARATOR {
+                                pos += 1;
+                            }
+                        }
+                        _ => {
+                            if table[pos..].starts_with(needle) {
+                                return Some(glyph);
+                            }
+                            pos += 1;
+                        }
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width as u32
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height as u32
+    }
+
+    /// Glyph index for a character, falling back to `?`
+    pub fn glyph_index(&self, c: char) -> usize {
+        match c as u32 {
+            code @ 0..=255 => self.latin1[code as usize] as usize,
+            _ => self.lookup(c).unwrap_or(self.latin1[b'?' as usize] as usize),
+        }
+    }
+
+    /// Whether pixel (`x`, `y`) of a glyph is set
+    fn bit(&self, glyph: usize, x: usize, y: usize) -> bool {
+        let row_bytes = self.width.div_ceil(8);
+        let byte = self.data[self.glyphs + glyph * self.bytes_per_glyph + y * row_bytes + x / 8];
+        byte & (0x80 >> (x % 8)) != 0
+    }
+}
+
+/// A glyph rendered in one colour pair
+struct CachedGlyph {
+    glyph: usize,
+    fg: u32,
+    bg: u32,
+    /// Identity of the font the glyph came from
+    font: usize,
+    pixels: [u32; MAX_GLYPH_WIDTH * MAX_GLYPH_HEIGHT],
+}
+
+struct GlyphCache {
+    entries: [Option<CachedGlyph>; GLYPH_CACHE_SIZE],
+    hits: u64,
+    misses: u64,
+}
+
+static GLYPH_CACHE: Mutex<GlyphCache> = Mutex::new(GlyphCache {
+    entries: [const { None }; GLYPH_CACHE_SIZE],
+    hits: 0,
+    misses: 0,
+});
+
+static DEFAULT_FONT: Mutex<Option<Font>> = Mutex::new(None);
+
+/// Load a font image stored in TagFS under `tag`
+pub fn load_from_tagfs(tag: &str) -> Result<Font, FontError> {
+    let object = crate::tagfs::tagfs_query(&crate::tagfs::Tag::new(tag)).ok_or(FontError::NotFound)?;
+    let size = crate::tagfs::tagfs_meta(object).ok_or(FontError::NotFound)?.size as usize;
+
+    let base = crate::kernel::memory::allocate_region(size).map_err(|_| FontError::OutOfMemory)?;
+    let data = unsafe { core::slice::from_raw_parts_mut(base.as_mut_ptr::<u8>(), size) };
+    let read = crate::tagfs::tagfs_read(object, 0, data).map_err(|_| FontError::NotFound)?;
+    if read != size {
+        return Err(FontError::Truncated);
+    }
+
+    Font::parse(data)
+}
+
+/// Font used by the console and UI elements
+pub fn default_font() -> Option<Font> {
+    *DEFAULT_FONT.lock()
+}
+
+pub fn set_default_font(font: Font) {
+    *DEFAULT_FONT.lock() = Some(font);
+}
+
+/// Draw one character with its top-left corner at (`x`, `y`); colours are 0x00RRGGBB
+pub fn draw_char(fb: &mut Framebuffer, font: &Font, x: i32, y: i32, c: char, fg: u32, bg: u32) {
+    let glyph = font.glyph_index(c);
+    let (fg, bg) = (fb.format.encode(fg), fb.format.encode(bg));
+    let font_id = font.data.as_ptr() as usize;
+
+    // Never spin: the console draws from the print path, which may have
+    // interrupted the lock holder; draw uncached instead
+    let Some(mut cache) = GLYPH_CACHE.try_lock() else {
+        let mut pixels = [0; MAX_GLYPH_WIDTH * MAX_GLYPH_HEIGHT];
+        render(font, glyph, fg, bg, &mut pixels);
+        blit(fb, font, &pixels, x, y);
+        return;
+    };
+    let slot = (glyph ^ fg as usize ^ (bg as usize).rotate_left(7)) % GLYPH_CACHE_SIZE;
+    let hit = matches!(&cache.entries[slot],
+        Some(e) if e.glyph == glyph && e.fg == fg && e.bg == bg && e.font == font_id);
+
+    if hit {
+        cache.hits += 1;
+    } else {
+        cache.misses += 1;
+        let mut entry = CachedGlyph {
+            glyph,
+            fg,
+            bg,
+            font: font_id,
+            pixels: [0; MAX_GLYPH_WIDTH * MAX_GLYPH_HEIGHT],
+        };
+        render(font, glyph, fg, bg, &mut entry.pixels);
+        cache.entries[slot] = Some(entry);
+    }
+
+    let entry = cache.entries[slot].as_ref().expect("filled above");
+    blit(fb, font, &entry.pixels, x, y);
+}
+
+/// Colour a glyph's pixels, `font.width` to a row
+fn render(font: &Font, glyph: usize, fg: u32, bg: u32, pixels: &mut [u32; MAX_GLYPH_WIDTH * MAX_GLYPH_HEIGHT]) {
+    for gy in 0..font.height {
+        for gx in 0..font.width {
+            pixels[gy * font.width + gx] = if font.bit(glyph, gx, gy) { fg } else { bg };
+        }
+    }
+}
+
+fn blit(fb: &mut Framebuffer, font: &Font, pixels: &[u32; MAX_GLYPH_WIDTH * MAX_GLYPH_HEIGHT], x: i32, y: i32) {
+    let image = Framebuffer {
+        base: pixels.as_ptr() as u64,
+        width: font.width as u32,
+        height: font.height as u32,
+        stride: font.width as u32,
+        format: fb.format,
+    };
+    accel::copy(fb, &image, image.bounds(), x, y);
+}
+
+/// Draw a string on one line, returning the width drawn in pixels
+pub fn draw_text(fb: &mut Framebuffer, font: &Font, x: i32, y: i32, text: &str, fg: u32, bg: u32) -> u32 {
+    let mut pen = x;
+    for c in text.chars() {
+        draw_char(fb, font, pen, y, c, fg, bg);
+        pen += font.width as i32;
+    }
+    (pen - x) as u32
+}
+
+/// Glyph cache hit and miss counts
+pub fn cache_stats() -> (u64, u64) {
+    let cache = GLYPH_CACHE.lock();
+    (cache.hits, cache.misses)
+}
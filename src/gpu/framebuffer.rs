@@ -0,0 +1,167 @@
+//! Linear framebuffers and pixel rectangles
+
+/// Pixel layout of a framebuffer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 32-bit 0x00RRGGBB
+    Xrgb8888,
+    /// 32-bit 0x00BBGGRR
+    Xbgr8888,
+}
+
+impl PixelFormat {
+    /// Convert a 0x00RRGGBB colour to this format
+    pub fn encode(self, rgb: u32) -> u32 {
+        match self {
+            PixelFormat::Xrgb8888 => rgb,
+            PixelFormat::Xbgr8888 => {
+                (rgb & 0x00FF_0000) >> 16 | (rgb & 0x0000_FF00) | (rgb & 0x0000_00FF) << 16
+            }
+        }
+    }
+
+    /// Convert a pixel in this format back to 0x00RRGGBB
+    pub fn decode(self, pixel: u32) -> u32 {
+        // Both layouts are their own inverse
+        self.encode(pixel & 0x00FF_FFFF)
+    }
+}
+
+/// Axis-aligned rectangle in pixels
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub const fn new(x: i32, y: i32, width: u32, height: u32) -> Self {
+        Self { x, y, width, height }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn right(&self) -> i32 {
+        self.x + self.width as i32
+    }
+
+    pub fn bottom(&self) -> i32 {
+        self.y + self.height as i32
+    }
+
+    /// Overlapping area of two rectangles (empty if disjoint)
+    pub fn intersect(&self, other: &Rect) -> Rect {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+        if right <= x || bottom <= y {
+            return Rect::default();
+        }
+        Rect::new(x, y, (right - x) as u32, (bottom - y) as u32)
+    }
+
+    /// Smallest rectangle containing both
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = self.right().max(other.right());
+        let bottom = self.bottom().max(other.bottom());
+        Rect::new(x, y, (right - x) as u32, (bottom - y) as u32)
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x && x < self.right() && y >= self.y && y < self.bottom()
+    }
+}
+
+/// A linear 32-bit framebuffer in kernel memory or VRAM
+#[derive(Clone, Copy, Debug)]
+pub struct Framebuffer {
+    /// Kernel virtual address of the first pixel
+    pub base: u64,
+    pub width: u32,
+    pub height: u32,
+    /// Pixels per row (at least `width`)
+    pub stride: u32,
+    pub format: PixelFormat,
+}
+
+impl Framebuffer {
+    /// Whole-buffer rectangle
+    pub fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    /// Size in bytes
+    pub fn size(&self) -> usize {
+        self.stride as usize * self.height as usize * 4
+    }
+
+    fn row_ptr(&self, y: u32) -> *mut u32 {
+        assert!(y < self.height);
+        unsafe { (self.base as *mut u32).add(y as usize * self.stride as usize) }
+    }
+
+    /// One row of pixels
+    pub fn row(&self, y: u32) -> &[u32] {
+        unsafe { core::slice::from_raw_parts(self.row_ptr(y), self.width as usize) }
+    }
+
+    /// One row of pixels, writable
+    pub fn row_mut(&mut self, y: u32) -> &mut [u32] {
+        unsafe { core::slice::from_raw_parts_mut(self.row_ptr(y), self.width as usize) }
+    }
+
+    pub fn put_pixel(&mut self, x: u32, y: u32, rgb: u32) {
+        if x < self.width && y < self.height {
+            let pixel = self.format.encode(rgb);
+            self.row_mut(y)[x as usize] = pixel;
+        }
+    }
+
+    /// Fill a rectangle with a solid colour
+    pub fn fill_rect(&mut self, rect: Rect, rgb: u32) {
+        let r = rect.intersect(&self.bounds());
+        let pixel = self.format.encode(rgb);
+        for y in r.y..r.bottom() {
+            self.row_mut(y as u32)[r.x as usize..r.right() as usize].fill(pixel);
+        }
+    }
+
+    /// Copy a rectangle from another buffer of the same format
+    pub fn copy_rect(&mut self, src: &Framebuffer, rect: Rect) {
+        let r = rect.intersect(&self.bounds()).intersect(&src.bounds());
+        for y in r.y..r.bottom() {
+            let span = r.x as usize..r.right() as usize;
+            self.row_mut(y as u32)[span.clone()].copy_from_slice(&src.row(y as u32)[span]);
+        }
+    }
+
+    /// Copy `src_rect` of `src` so that its top-left lands at (`x`, `y`)
+    pub fn blit_from(&mut self, src: &Framebuffer, src_rect: Rect, x: i32, y: i32) {
+        let clipped = src_rect.intersect(&src.bounds());
+        let (x, y) = (x + clipped.x - src_rect.x, y + clipped.y - src_rect.y);
+        let dst = Rect::new(x, y, clipped.width, clipped.height).intersect(&self.bounds());
+        if dst.is_empty() {
+            return;
+        }
+
+        let sx = (clipped.x + dst.x - x) as usize;
+        let sy = clipped.y + dst.y - y;
+        for row in 0..dst.height as i32 {
+            let src_row = &src.row((sy + row) as u32)[sx..sx + dst.width as usize];
+            self.row_mut((dst.y + row) as u32)[dst.x as usize..dst.right() as usize].copy_from_slice(src_row);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Firmware framebuffer display
+//!
+//! Scans out from the framebuffer a UEFI loader left set up through the
+//! Graphics Output Protocol (see [`crate::boot::framebuffer`]), for when
+//! there is no driver for the adapter. The firmware's mode is the only
+//! one, there is a single buffer, which the presenter shadows, and nothing
+//! reports vertical blank or the refresh rate.
+
+use spin::Once;
+
+use super::display::{Display, Mode};
+use super::framebuffer::{Framebuffer, PixelFormat};
+use super::GpuError;
+use crate::boot::framebuffer::{self as boot, Framebuffer as GopFramebuffer};
+
+/// Refresh rate assumed for the firmware's mode
+const REFRESH_MHZ: u32 = 60_000;
+
+pub struct GopDisplay {
+    /// Kernel virtual address of the framebuffer
+    base: u64,
+    modes: [Mode; 1],
+    stride: u32,
+    format: PixelFormat,
+}
+
+/// The layout of `format` as the compositor names it, if it has one
+fn pixel_format(format: boot::PixelFormat) -> Option<PixelFormat> {
+    const RED: u32 = 0x00FF_0000;
+    const GREEN: u32 = 0x0000_FF00;
+    const BLUE: u32 = 0x0000_00FF;
+    match format {
+        boot::PixelFormat::Bgr => Some(PixelFormat::Xrgb8888),
+        boot::PixelFormat::Rgb => Some(PixelFormat::Xbgr8888),
+        boot::PixelFormat::Bitmask { red: RED, green: GREEN, blue: BLUE } => Some(PixelFormat::Xrgb8888),
+        boot::PixelFormat::Bitmask { red: BLUE, green: GREEN, blue: RED } => Some(PixelFormat::Xbgr8888),
+        boot::PixelFormat::Bitmask { .. } | boot::PixelFormat::BltOnly => None,
+    }
+}
+
+impl GopDisplay {
+    fn probe(framebuffer: &GopFramebuffer) -> Result<Self, GpuError> {
+        let format = pixel_format(framebuffer.format).ok_or(GpuError::UnsupportedMode)?;
+        let base = crate::kernel::memory::map_mmio(framebuffer.addr, framebuffer.size())
+            .map_err(|_| GpuError::MappingFailed)?;
+        Ok(Self {
+            base: base.as_u64(),
+            modes: [Mode {
+                width: framebuffer.width,
+                height: framebuffer.height,
+                refresh_mhz: REFRESH_MHZ,
+            }],
+            stride: framebuffer.stride,
+            format,
+        })
+    }
+}
+
+impl Display for GopDisplay {
+    fn name(&self) -> &'static str {
+        "uefi-gop"
+    }
+
+    fn mode(&self) -> Mode {
+        self.modes[0]
+    }
+
+    fn modes(&self) -> &[Mode] {
+        &self.modes
+    }
+
+    fn buffer_count(&self) -> usize {
+        1
+    }
+
+    fn framebuffer(&self, _index: usize) -> Framebuffer {
+        Framebuffer {
+            base: self.base,
+            width: self.modes[0].width,
+            height: self.modes[0].height,
+            stride: self.stride,
+            format: self.format,
+        }
+    }
+
+    fn flip(&self, _index: usize) {}
+}
+
+static GOP: Once<GopDisplay> = Once::new();
+
+/// Set up scanout from the firmware framebuffer
+pub fn init(framebuffer: &GopFramebuffer) -> Result<&'static GopDisplay, GpuError> {
+    let display = GopDisplay::probe(framebuffer)?;
+    Ok(GOP.call_once(|| display))
+}
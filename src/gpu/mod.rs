@@ -1,16 +1,121 @@
 //! GPU subsystem - Wayland compositor and GPU-accelerated rendering
 
+pub mod accel;
+pub mod bo;
+pub mod bochs;
+pub mod capture;
+pub mod cmd;
+pub mod compositor;
+pub mod compute;
+pub mod console;
+pub mod cursor;
+pub mod display;
+pub mod edid;
+pub mod effects;
+pub mod fence;
+pub mod font;
+pub mod gop;
+pub mod framebuffer;
+pub mod output;
+pub mod present;
+pub mod settings;
+pub mod tile;
+pub mod transform;
+pub mod vram;
+pub mod wayland;
+pub mod wm;
+
+use display::Display;
+
 /// Initialize GPU subsystem
-pub fn init() {
-    // TODO: Initialize GPU device
-    // TODO: Set up Wayland-compatible compositor
-    // TODO: Initialize tile-based rendering
+///
+/// `firmware` is the framebuffer the loader left set up, scanned out from
+/// if no adapter has a driver.
+pub fn init(firmware: Option<crate::boot::framebuffer::Framebuffer>) {
+    let adapter: Result<&'static dyn Display, GpuError> = match (bochs::init(), firmware) {
+        (Ok(adapter), _) => Ok(adapter),
+        (Err(_), Some(framebuffer)) => gop::init(&framebuffer).map(|adapter| adapter as &'static dyn Display),
+        (Err(e), None) => Err(e),
+    };
+    match adapter {
+        Ok(adapter) => {
+            if let Err(e) = output::add(adapter) {
+                crate::klog!(Warn, "Cannot add output {}: {:?}", adapter.name(), e);
+            }
+        }
+        Err(e) => crate::klog!(Warn, "No supported display: {:?}", e),
+    }
+
+    for info in output::outputs() {
+        crate::klog!(
+            Info,
+            "Output {}: {} {}x{}",
+            info.id,
+            info.name,
+            info.mode.width,
+            info.mode.height
+        );
+        if let Err(e) = output::start(info.id) {
+            crate::klog!(Warn, "Presentation setup failed on output {}: {:?}", info.id, e);
+        }
+    }
+
+    match font::load_from_tagfs(font::CONSOLE_FONT_TAG) {
+        Ok(font) => {
+            font::set_default_font(font);
+            if let Err(e) = console::init() {
+                crate::klog!(Warn, "Framebuffer console unavailable: {:?}", e);
+            }
+        }
+        Err(e) => crate::klog!(Warn, "No console font ({:?}), console stays on serial", e),
+    }
+
+    match wayland::init() {
+        Ok(channel) => crate::klog!(Info, "Wayland server listening on channel {}", channel),
+        Err(e) => crate::klog!(Error, "Wayland server setup failed: {:?}", e),
+    }
+
+    match settings::init() {
+        Ok(channel) => crate::klog!(Info, "Display settings on channel {}", channel),
+        Err(e) => crate::klog!(Error, "Display settings service setup failed: {:?}", e),
+    }
+}
+
+/// Compose a frame if anything changed, run from the idle loop
+pub fn poll() {
+    wayland::dispatch();
+    wm::poll();
+    settings::dispatch();
+    cmd::process();
+    console::flush();
+
+    match compositor::compose() {
+        Ok(Some(_)) => {
+            let ms = crate::scheduler::ticks() * 1000 / crate::scheduler::TICKS_PER_SECOND;
+            wayland::frame_done(ms as u32);
+        }
+        Ok(None) | Err(GpuError::DeviceNotFound) => {}
+        Err(e) => crate::klog!(Error, "Composition failed: {:?}", e),
+    }
+}
+
+/// Copy a buffer into video memory, returning its GPU address
+///
+/// The copy stays pinned until released with [`unmap_from_gpu`].
+pub fn map_to_gpu(buffer: &[u8]) -> Result<u64, GpuError> {
+    if buffer.is_empty() {
+        return Err(GpuError::InvalidBuffer);
+    }
+    let (_, placement) = vram::vram_alloc(buffer.len() as u64, None)?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(buffer.as_ptr(), placement.cpu_addr as *mut u8, buffer.len());
+    }
+    Ok(placement.gpu_addr)
 }
 
-/// Map buffer to GPU
-pub fn map_to_gpu(_buffer: &[u8]) -> Result<u64, GpuError> {
-    // TODO: Implement GPU-direct buffer mapping
-    Ok(0)
+/// Release a buffer placed by [`map_to_gpu`]
+pub fn unmap_from_gpu(gpu_addr: u64) -> Result<(), GpuError> {
+    vram::vram_free(vram::vram_lookup(gpu_addr).ok_or(GpuError::InvalidBuffer)?)
 }
 
 /// GPU errors
@@ -19,4 +124,19 @@ pub enum GpuError {
     DeviceNotFound,
     MappingFailed,
     RenderingFailed,
+    UnsupportedMode,
+    OutOfMemory,
+    InvalidSurface,
+    TooManySurfaces,
+    NoFont,
+    InvalidOutput,
+    TooManyOutputs,
+    InvalidBuffer,
+    TooManyBuffers,
+    PermissionDenied,
+    CaptureFailed,
+    InvalidContext,
+    TooManyContexts,
+    RingFull,
+    Timeout,
 }
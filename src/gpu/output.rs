@@ -0,0 +1,212 @@
+//! Display outputs
+//!
+//! Every connector the drivers find is registered here and gets an output
+//! ID. Outputs have independent modes and presentation state; compositor
+//! surfaces are assigned to one output and positioned within it, in the
+//! output's logical space: its mode after rotation and scaling.
+//!
+//! A new output is switched to its monitor's native mode, taken from EDID,
+//! unless `video=<width>x<height>` on the command line asks for another.
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use super::display::{Display, Mode};
+use super::edid::Edid;
+use super::transform::{Transform, MAX_SCALE, MIN_SCALE};
+use super::{compositor, cursor, present, GpuError};
+
+/// Maximum number of outputs
+pub const MAX_OUTPUTS: usize = 4;
+
+/// Output that new surfaces, the console and the cursor go to
+pub const PRIMARY_OUTPUT: u32 = 0;
+
+/// Description of one output
+#[derive(Clone, Copy, Debug)]
+pub struct OutputInfo {
+    pub id: u32,
+    pub name: &'static str,
+    pub mode: Mode,
+    pub connected: bool,
+    pub enabled: bool,
+    pub transform: Transform,
+}
+
+struct Output {
+    display: &'static dyn Display,
+    enabled: bool,
+    transform: Transform,
+}
+
+static OUTPUTS: Mutex<[Option<Output>; MAX_OUTPUTS]> = Mutex::new([const { None }; MAX_OUTPUTS]);
+
+/// Size asked for with `video=<width>x<height>`
+fn requested_size() -> Option<(u32, u32)> {
+    crate::boot::cmdline::get("video")
+        .and_then(|v| v.split_once('x'))
+        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+}
+
+/// Mode a new output should start in
+fn initial_mode(display: &dyn Display) -> Option<Mode> {
+    let modes = display.modes();
+    if let Some((width, height)) = requested_size() {
+        // Highest refresh rate at the requested size
+        let requested = modes
+            .iter()
+            .filter(|m| m.width == width && m.height == height)
+            .max_by_key(|m| m.refresh_mhz);
+        if let Some(mode) = requested {
+            return Some(*mode);
+        }
+    }
+    display
+        .edid()
+        .and_then(|edid| edid.preferred)
+        .filter(|native| modes.contains(native))
+}
+
+/// Register a connector, returning its output ID
+///
+/// The first output registered becomes [`PRIMARY_OUTPUT`].
+pub fn add(display: &'static dyn Display) -> Result<u32, GpuError> {
+    let id = {
+        let mut outputs = OUTPUTS.lock();
+        let (id, slot) = outputs
+            .iter_mut()
+            .enumerate()
+            .find(|(_, slot)| slot.is_none())
+            .ok_or(GpuError::TooManyOutputs)?;
+        *slot = Some(Output {
+            display,
+            enabled: true,
+            transform: Transform::IDENTITY,
+        });
+        id as u32
+    };
+
+    if let Some(mode) = initial_mode(display).filter(|m| *m != display.mode()) {
+        if let Err(e) = display.set_mode(mode) {
+            crate::klog!(Warn, "Output {}: cannot set {}x{}: {:?}", id, mode.width, mode.height, e);
+        }
+    }
+    Ok(id)
+}
+
+/// Display driving output `id`
+pub fn display(id: u32) -> Option<&'static dyn Display> {
+    OUTPUTS.lock().get(id as usize)?.as_ref().map(|o| o.display)
+}
+
+/// Whether output `id` should be composed and presented
+pub fn is_enabled(id: u32) -> bool {
+    matches!(OUTPUTS.lock().get(id as usize), Some(Some(o)) if o.enabled)
+}
+
+/// Modes output `id` supports
+pub fn modes(id: u32) -> Result<&'static [Mode], GpuError> {
+    Ok(display(id).ok_or(GpuError::InvalidOutput)?.modes())
+}
+
+/// Monitor information of output `id`
+pub fn edid(id: u32) -> Option<&'static Edid> {
+    display(id)?.edid()
+}
+
+/// All registered outputs
+pub fn outputs() -> ArrayVec<OutputInfo, MAX_OUTPUTS> {
+    OUTPUTS
+        .lock()
+        .iter()
+        .enumerate()
+        .filter_map(|(id, slot)| {
+            let output = slot.as_ref()?;
+            Some(OutputInfo {
+                id: id as u32,
+                name: output.display.name(),
+                mode: output.display.mode(),
+                connected: output.display.connected(),
+                enabled: output.enabled,
+                transform: output.transform,
+            })
+        })
+        .collect()
+}
+
+/// Rotation and scale of output `id`
+pub fn transform(id: u32) -> Transform {
+    match OUTPUTS.lock().get(id as usize) {
+        Some(Some(output)) => output.transform,
+        _ => Transform::IDENTITY,
+    }
+}
+
+/// Size of output `id`'s logical space, where surfaces are placed
+pub fn logical_size(id: u32) -> Option<(u32, u32)> {
+    let mode = display(id)?.mode();
+    Some(transform(id).logical_size(mode.width, mode.height))
+}
+
+/// Rotate and scale output `id`
+pub fn set_transform(id: u32, transform: Transform) -> Result<(), GpuError> {
+    if !(MIN_SCALE..=MAX_SCALE).contains(&transform.scale) {
+        return Err(GpuError::UnsupportedMode);
+    }
+    {
+        let mut outputs = OUTPUTS.lock();
+        let output = outputs
+            .get_mut(id as usize)
+            .and_then(|slot| slot.as_mut())
+            .ok_or(GpuError::InvalidOutput)?;
+        if output.transform == transform {
+            return Ok(());
+        }
+        output.transform = transform;
+    }
+
+    if id == PRIMARY_OUTPUT {
+        // A cursor plane is positioned in untransformed pixels
+        cursor::output_changed();
+    }
+    compositor::damage_output(id);
+    Ok(())
+}
+
+/// Start presenting on output `id` and schedule a full repaint
+pub fn start(id: u32) -> Result<(), GpuError> {
+    let display = display(id).ok_or(GpuError::InvalidOutput)?;
+    present::init(id, display)?;
+    // Scanout buffers start out with garbage
+    compositor::damage_output(id);
+    Ok(())
+}
+
+/// Switch output `id` to `mode`
+pub fn set_mode(id: u32, mode: Mode) -> Result<(), GpuError> {
+    let display = display(id).ok_or(GpuError::InvalidOutput)?;
+    if display.mode() == mode {
+        return Ok(());
+    }
+    if !display.modes().contains(&mode) {
+        return Err(GpuError::UnsupportedMode);
+    }
+    display.set_mode(mode)?;
+    start(id)
+}
+
+/// Turn composition on or off for output `id`
+pub fn set_enabled(id: u32, enabled: bool) -> Result<(), GpuError> {
+    let mut outputs = OUTPUTS.lock();
+    let output = outputs
+        .get_mut(id as usize)
+        .and_then(|slot| slot.as_mut())
+        .ok_or(GpuError::InvalidOutput)?;
+    let was_enabled = core::mem::replace(&mut output.enabled, enabled);
+    drop(outputs);
+
+    if enabled && !was_enabled {
+        compositor::damage_output(id);
+    }
+    Ok(())
+}
@@ -0,0 +1,160 @@
+//! Double-buffered, vsync-paced presentation
+//!
+//! Drawing goes to [`back_buffer`]; [`present`] waits for vertical blank and
+//! makes it visible. Displays with two scanout buffers are page-flipped, so
+//! after a present the back buffer holds the frame from two presents ago
+//! (see [`buffer_age`]). Single-buffer displays get a RAM back buffer that is
+//! copied out during vblank. Without a hardware vblank signal frames are
+//! paced by the timer tick. Each output is presented independently.
+
+use spin::Mutex;
+
+use super::accel;
+use super::display::Display;
+use super::framebuffer::{Framebuffer, Rect};
+use super::output::MAX_OUTPUTS;
+use super::GpuError;
+
+struct Presenter {
+    display: &'static dyn Display,
+    /// Scanout buffer currently visible
+    front: usize,
+    /// RAM back buffer for displays that cannot flip
+    shadow: Option<Framebuffer>,
+    vsync: bool,
+    frames: u64,
+    /// Tick of the last timer-paced present
+    last_tick: u64,
+}
+
+impl Presenter {
+    fn back(&self) -> Framebuffer {
+        match self.shadow {
+            Some(shadow) => shadow,
+            None => self.display.framebuffer(self.front ^ 1),
+        }
+    }
+}
+
+static PRESENTERS: [Mutex<Option<Presenter>>; MAX_OUTPUTS] = [const { Mutex::new(None) }; MAX_OUTPUTS];
+
+fn presenter(output: u32) -> Result<&'static Mutex<Option<Presenter>>, GpuError> {
+    PRESENTERS.get(output as usize).ok_or(GpuError::InvalidOutput)
+}
+
+/// Start presenting `display` on `output`, e.g. after a mode change
+pub fn init(output: u32, display: &'static dyn Display) -> Result<(), GpuError> {
+    let slot = presenter(output)?;
+    let shadow = if display.buffer_count() >= 2 {
+        None
+    } else {
+        let front = display.framebuffer(0);
+        let base = crate::kernel::memory::allocate_region(front.size()).map_err(|_| GpuError::OutOfMemory)?;
+        Some(Framebuffer {
+            base: base.as_u64(),
+            ..front
+        })
+    };
+
+    display.flip(0);
+    *slot.lock() = Some(Presenter {
+        display,
+        front: 0,
+        shadow,
+        vsync: true,
+        frames: 0,
+        last_tick: crate::scheduler::ticks(),
+    });
+    Ok(())
+}
+
+/// Buffer to draw the next frame of `output` into
+pub fn back_buffer(output: u32) -> Option<Framebuffer> {
+    presenter(output).ok()?.lock().as_ref().map(Presenter::back)
+}
+
+/// Buffer holding what `output` currently shows
+pub fn front_buffer(output: u32) -> Option<Framebuffer> {
+    let slot = presenter(output).ok()?.lock();
+    let p = slot.as_ref()?;
+    // A shadow buffer is copied out on every present, so it matches the screen
+    Some(p.shadow.unwrap_or_else(|| p.display.framebuffer(p.front)))
+}
+
+/// Number of presents since the back buffer of `output` last held the current frame
+pub fn buffer_age(output: u32) -> u32 {
+    let slot = match presenter(output) {
+        Ok(slot) => slot.lock(),
+        Err(_) => return 1,
+    };
+    match slot.as_ref() {
+        Some(p) if p.shadow.is_none() => 2,
+        _ => 1,
+    }
+}
+
+/// Wait for the start of the next frame
+fn wait_frame(display: &'static dyn Display, last_tick: u64) -> u64 {
+    if display.wait_vblank() {
+        return crate::scheduler::ticks();
+    }
+
+    // Timer-simulated vsync: one frame per tick
+    loop {
+        let now = crate::scheduler::ticks();
+        if now != last_tick {
+            return now;
+        }
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Show the back buffer of `output`, returning its frame number
+///
+/// Only `damage` is copied out on single-buffer displays; page-flipped
+/// displays always show the whole back buffer.
+pub fn present(output: u32, damage: Rect) -> Result<u64, GpuError> {
+    let slot = presenter(output)?;
+    let (display, vsync, last_tick) = {
+        let presenter = slot.lock();
+        let p = presenter.as_ref().ok_or(GpuError::DeviceNotFound)?;
+        (p.display, p.vsync, p.last_tick)
+    };
+
+    let tick = if vsync {
+        wait_frame(display, last_tick)
+    } else {
+        crate::scheduler::ticks()
+    };
+
+    let mut presenter = slot.lock();
+    let p = presenter.as_mut().ok_or(GpuError::DeviceNotFound)?;
+    match p.shadow {
+        Some(shadow) => {
+            let damage = damage.intersect(&shadow.bounds());
+            accel::copy(&mut display.framebuffer(0), &shadow, damage, damage.x, damage.y);
+        }
+        None => {
+            p.front ^= 1;
+            display.flip(p.front);
+        }
+    }
+
+    p.last_tick = tick;
+    p.frames += 1;
+    Ok(p.frames)
+}
+
+/// Enable or disable vsync pacing on every output
+pub fn set_vsync(enabled: bool) {
+    for slot in PRESENTERS.iter() {
+        if let Some(p) = slot.lock().as_mut() {
+            p.vsync = enabled;
+        }
+    }
+}
+
+/// Frames presented on `output` so far
+pub fn frame_count(output: u32) -> u64 {
+    presenter(output).map_or(0, |slot| slot.lock().as_ref().map_or(0, |p| p.frames))
+}
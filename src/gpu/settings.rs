@@ -0,0 +1,234 @@
+//! Display settings service
+//!
+//! Settings tools list outputs, their monitors and modes, and change modes
+//! through IPC on [`settings_channel`]. Every request carries the channel
+//! the reply should go to; all integers are little-endian.
+//!
+//! A mode record is width, height and refresh rate (mHz) as three u32s. An
+//! output reply is: output ID, flags (bit 0 connected, bit 1 enabled), the
+//! current mode, the preferred mode (zeros if unknown), the 3-byte
+//! manufacturer ID plus padding, a 16-byte NUL-padded monitor name, the
+//! number of modes, the mode records, then the rotation in degrees and the
+//! scale factor in 1/120ths.
+
+use spin::Mutex;
+
+use super::display::Mode;
+use super::transform::{Rotation, Transform};
+use super::{output, GpuError};
+use crate::capability::{self, Permission};
+use crate::ipc::{self, MessageHeader, MAX_MESSAGE_SIZE};
+
+/// List outputs; payload: reply channel (u64). One output reply per output
+/// follows, then a status reply.
+pub const SETTINGS_LIST_MSG: u32 = 0x4453_0001;
+
+/// Description of one output (reply)
+pub const SETTINGS_OUTPUT_MSG: u32 = 0x4453_0002;
+
+/// Change a mode; payload: reply channel (u64), output ID, mode record.
+/// Needs the GPU access permission.
+pub const SETTINGS_SET_MODE_MSG: u32 = 0x4453_0003;
+
+/// Result of a request (reply); payload: status (u32)
+pub const SETTINGS_STATUS_MSG: u32 = 0x4453_0004;
+
+/// Rotate and scale an output; payload: reply channel (u64), output ID,
+/// rotation in degrees, scale in 1/120ths. Needs the GPU access permission.
+pub const SETTINGS_SET_TRANSFORM_MSG: u32 = 0x4453_0005;
+
+/// Status codes
+pub const STATUS_OK: u32 = 0;
+pub const STATUS_INVALID_REQUEST: u32 = 1;
+pub const STATUS_INVALID_OUTPUT: u32 = 2;
+pub const STATUS_UNSUPPORTED_MODE: u32 = 3;
+pub const STATUS_PERMISSION_DENIED: u32 = 4;
+pub const STATUS_FAILED: u32 = 5;
+
+const FLAG_CONNECTED: u32 = 1 << 0;
+const FLAG_ENABLED: u32 = 1 << 1;
+
+static CHANNEL: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Create the service channel
+pub fn init() -> Result<u64, ipc::IpcError> {
+    let channel = ipc::create_channel()?;
+    *CHANNEL.lock() = Some(channel);
+    Ok(channel)
+}
+
+/// Channel settings tools send requests to
+pub fn settings_channel() -> Option<u64> {
+    *CHANNEL.lock()
+}
+
+/// Little-endian encoder for replies
+struct Encoder {
+    buf: [u8; MAX_MESSAGE_SIZE],
+    len: usize,
+}
+
+impl Encoder {
+    fn new() -> Self {
+        Self {
+            buf: [0; MAX_MESSAGE_SIZE],
+            len: 0,
+        }
+    }
+
+    fn bytes(&mut self, data: &[u8]) -> bool {
+        match self.buf.get_mut(self.len..self.len + data.len()) {
+            Some(dst) => {
+                dst.copy_from_slice(data);
+                self.len += data.len();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn u32(&mut self, value: u32) -> bool {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn mode(&mut self, mode: Mode) -> bool {
+        self.u32(mode.width) && self.u32(mode.height) && self.u32(mode.refresh_mhz)
+    }
+}
+
+fn reply(channel: u64, receiver: u32, msg_type: u32, data: &[u8]) {
+    let header = MessageHeader {
+        id: 0,
+        sender: 0, // kernel
+        receiver,
+        length: data.len() as u32,
+        msg_type,
+    };
+    let _ = ipc::msg_send(channel, header, data);
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+fn list_outputs(channel: u64, receiver: u32) {
+    for info in output::outputs() {
+        let edid = output::edid(info.id);
+        let mut name = [0u8; 16];
+        let mut manufacturer = [0u8; 4];
+        if let Some(edid) = edid {
+            name[..edid.name.len()].copy_from_slice(edid.name.as_bytes());
+            manufacturer[..3].copy_from_slice(&edid.manufacturer);
+        }
+        let modes = output::modes(info.id).unwrap_or(&[]);
+        let flags = if info.connected { FLAG_CONNECTED } else { 0 } | if info.enabled { FLAG_ENABLED } else { 0 };
+
+        let mut msg = Encoder::new();
+        msg.u32(info.id);
+        msg.u32(flags);
+        msg.mode(info.mode);
+        msg.mode(edid.and_then(|e| e.preferred).unwrap_or(Mode {
+            width: 0,
+            height: 0,
+            refresh_mhz: 0,
+        }));
+        msg.bytes(&manufacturer);
+        msg.bytes(&name);
+
+        let count_at = msg.len;
+        msg.u32(0);
+        let mut count = 0u32;
+        // Leave room for the transform after the mode records
+        for mode in modes {
+            if msg.len + 12 + 8 > msg.buf.len() || !msg.mode(*mode) {
+                break;
+            }
+            count += 1;
+        }
+        msg.buf[count_at..count_at + 4].copy_from_slice(&count.to_le_bytes());
+        msg.u32(info.transform.rotation.degrees());
+        msg.u32(info.transform.scale);
+
+        reply(channel, receiver, SETTINGS_OUTPUT_MSG, &msg.buf[..msg.len]);
+    }
+}
+
+fn set_mode(sender: u32, data: &[u8]) -> u32 {
+    let request = (|| {
+        let output = read_u32(data, 8)?;
+        let mode = Mode {
+            width: read_u32(data, 12)?,
+            height: read_u32(data, 16)?,
+            refresh_mhz: read_u32(data, 20)?,
+        };
+        Some((output, mode))
+    })();
+    let Some((id, mode)) = request else {
+        return STATUS_INVALID_REQUEST;
+    };
+
+    if capability::check_permission(sender, Permission::GpuAccess).is_err() {
+        return STATUS_PERMISSION_DENIED;
+    }
+    match output::set_mode(id, mode) {
+        Ok(()) => {
+            crate::klog!(Info, "Output {}: mode {}x{} set by process {}", id, mode.width, mode.height, sender);
+            STATUS_OK
+        }
+        Err(GpuError::InvalidOutput) => STATUS_INVALID_OUTPUT,
+        Err(GpuError::UnsupportedMode) => STATUS_UNSUPPORTED_MODE,
+        Err(_) => STATUS_FAILED,
+    }
+}
+
+fn set_transform(sender: u32, data: &[u8]) -> u32 {
+    let request = (|| Some((read_u32(data, 8)?, read_u32(data, 12)?, read_u32(data, 16)?)))();
+    let Some((id, degrees, scale)) = request else {
+        return STATUS_INVALID_REQUEST;
+    };
+    let Some(rotation) = Rotation::from_degrees(degrees) else {
+        return STATUS_UNSUPPORTED_MODE;
+    };
+
+    if capability::check_permission(sender, Permission::GpuAccess).is_err() {
+        return STATUS_PERMISSION_DENIED;
+    }
+    match output::set_transform(id, Transform { rotation, scale }) {
+        Ok(()) => {
+            crate::klog!(Info, "Output {}: rotation {} scale {}/120 set by process {}", id, degrees, scale, sender);
+            STATUS_OK
+        }
+        Err(GpuError::InvalidOutput) => STATUS_INVALID_OUTPUT,
+        Err(GpuError::UnsupportedMode) => STATUS_UNSUPPORTED_MODE,
+        Err(_) => STATUS_FAILED,
+    }
+}
+
+/// Answer pending requests
+pub fn dispatch() {
+    let Some(channel) = settings_channel() else {
+        return;
+    };
+
+    let mut buffer = [0; MAX_MESSAGE_SIZE];
+    while let Ok((header, len)) = ipc::msg_recv(channel, &mut buffer) {
+        let data = &buffer[..len];
+        let Some(reply_channel) = read_u64(data, 0) else {
+            continue;
+        };
+        let status = match header.msg_type {
+            SETTINGS_LIST_MSG => {
+                list_outputs(reply_channel, header.sender);
+                STATUS_OK
+            }
+            SETTINGS_SET_MODE_MSG => set_mode(header.sender, data),
+            SETTINGS_SET_TRANSFORM_MSG => set_transform(header.sender, data),
+            _ => STATUS_INVALID_REQUEST,
+        };
+        reply(reply_channel, header.sender, SETTINGS_STATUS_MSG, &status.to_le_bytes());
+    }
+}
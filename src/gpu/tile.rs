@@ -0,0 +1,142 @@
+//! Tile-based software renderer
+//!
+//! Without a 2D engine every pixel is drawn by the CPU, so damage is cut into
+//! `TILE_SIZE` squares that all CPUs render in parallel. The composing CPU
+//! publishes a job and renders tiles itself; every other CPU picks up tiles
+//! from its idle loop through [`work`]. A tile is small enough that its
+//! target rows and the surface pixels behind it stay in cache.
+
+use arrayvec::ArrayVec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use super::framebuffer::{Framebuffer, Rect};
+use crate::kernel::percpu::{current_cpu_id, MAX_CPUS};
+
+/// Width and height of a tile in pixels
+pub const TILE_SIZE: u32 = 64;
+
+/// Tiles one job can hold (a 4K screen)
+pub const MAX_TILES: usize = 4096;
+
+/// Draws one tile of a job into the target
+pub(crate) type RenderFn = fn(context: *const (), target: &mut Framebuffer, tile: Rect);
+
+struct Job {
+    /// What to draw and where, while a job is published
+    work: Option<(RenderFn, *const (), Framebuffer)>,
+    tiles: ArrayVec<Rect, MAX_TILES>,
+    /// Next tile to hand out
+    next: usize,
+}
+
+// The context pointer is only dereferenced while `render` waits for the job,
+// which keeps whatever it points to alive and borrowed.
+unsafe impl Send for Job {}
+
+static JOB: Mutex<Job> = Mutex::new(Job {
+    work: None,
+    tiles: ArrayVec::new_const(),
+    next: 0,
+});
+
+/// Tiles of the current job that have been drawn
+static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+/// Tiles drawn by each CPU since boot
+static TILES_RENDERED: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Serializes jobs so only one is published at a time
+static RENDERING: Mutex<()> = Mutex::new(());
+
+/// Cut `damage`, clipped to `bounds`, into tiles
+///
+/// Each tile covers the damaged part of one grid cell, so no two tiles
+/// overlap. Returns `false` if there were more tiles than fit.
+fn split(damage: &[Rect], bounds: Rect, tiles: &mut ArrayVec<Rect, MAX_TILES>) -> bool {
+    let area = damage.iter().fold(Rect::default(), |acc, r| acc.union(r)).intersect(&bounds);
+    if area.is_empty() {
+        return true;
+    }
+
+    let size = TILE_SIZE as i32;
+    let (first_col, last_col) = (area.x.div_euclid(size), (area.right() - 1).div_euclid(size));
+    let (first_row, last_row) = (area.y.div_euclid(size), (area.bottom() - 1).div_euclid(size));
+    for row in first_row..=last_row {
+        for col in first_col..=last_col {
+            let cell = Rect::new(col * size, row * size, TILE_SIZE, TILE_SIZE).intersect(&area);
+            let tile = damage
+                .iter()
+                .fold(Rect::default(), |acc, r| acc.union(&r.intersect(&cell)));
+            if !tile.is_empty() && tiles.try_push(tile).is_err() {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Take the next tile of the published job
+fn claim() -> Option<(RenderFn, *const (), Framebuffer, Rect)> {
+    let mut job = JOB.lock();
+    let (render, context, target) = job.work?;
+    let tile = *job.tiles.get(job.next)?;
+    job.next += 1;
+    Some((render, context, target, tile))
+}
+
+/// Render tiles of the current job until none are left to claim
+///
+/// Returns the number of tiles this CPU drew. Run from every CPU's idle
+/// loop; a no-op when nothing is being composed.
+pub fn work() -> usize {
+    let mut drawn = 0;
+    while let Some((render, context, mut target, tile)) = claim() {
+        render(context, &mut target, tile);
+        FINISHED.fetch_add(1, Ordering::Release);
+        drawn += 1;
+    }
+    if drawn > 0 {
+        TILES_RENDERED[current_cpu_id() as usize].fetch_add(drawn as u64, Ordering::Relaxed);
+    }
+    drawn
+}
+
+/// Draw `damage` into `target` tile by tile, spread over all CPUs
+///
+/// `render` is called with `context` for every tile, possibly on several
+/// CPUs at once, and must only draw inside the tile it is given. Returns
+/// once every tile is drawn.
+pub(crate) fn render(target: &Framebuffer, damage: &[Rect], render: RenderFn, context: *const ()) {
+    let _rendering = RENDERING.lock();
+
+    let count = {
+        let mut job = JOB.lock();
+        job.tiles.clear();
+        job.next = 0;
+        if !split(damage, target.bounds(), &mut job.tiles) {
+            drop(job);
+            // More tiles than a job holds: draw the damage whole on this CPU
+            let (mut target, bounds) = (*target, target.bounds());
+            for rect in damage {
+                render(context, &mut target, rect.intersect(&bounds));
+            }
+            return;
+        }
+        FINISHED.store(0, Ordering::Release);
+        job.work = Some((render, context, *target));
+        job.tiles.len()
+    };
+
+    work();
+    // Other CPUs may still be drawing tiles they claimed
+    while FINISHED.load(Ordering::Acquire) < count {
+        core::hint::spin_loop();
+    }
+    JOB.lock().work = None;
+}
+
+/// Tiles drawn by `cpu` since boot
+pub fn tiles_rendered(cpu: u32) -> u64 {
+    TILES_RENDERED.get(cpu as usize).map_or(0, |c| c.load(Ordering::Relaxed))
+}
@@ -0,0 +1,141 @@
+//! Output transforms
+//!
+//! An output can be rotated in 90-degree steps and scaled by a fractional
+//! factor. Surfaces are laid out in the output's logical space; composition
+//! draws that space into a staging buffer and the transform maps it onto
+//! the scanout buffer. Scale factors are in 1/120ths, like Wayland's
+//! fractional-scale protocol, so 180 is 1.5x.
+
+use super::framebuffer::{Framebuffer, Rect};
+
+/// Scale factor of 1.0
+pub const SCALE_UNIT: u32 = 120;
+
+/// Supported scale factors (0.5x to 4x)
+pub const MIN_SCALE: u32 = 60;
+pub const MAX_SCALE: u32 = 480;
+
+/// Clockwise rotation of what an output shows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    Normal,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Rotation {
+    pub fn from_degrees(degrees: u32) -> Option<Self> {
+        match degrees {
+            0 => Some(Rotation::Normal),
+            90 => Some(Rotation::Rotate90),
+            180 => Some(Rotation::Rotate180),
+            270 => Some(Rotation::Rotate270),
+            _ => None,
+        }
+    }
+
+    pub fn degrees(self) -> u32 {
+        match self {
+            Rotation::Normal => 0,
+            Rotation::Rotate90 => 90,
+            Rotation::Rotate180 => 180,
+            Rotation::Rotate270 => 270,
+        }
+    }
+
+    /// Whether width and height trade places
+    fn swaps_axes(self) -> bool {
+        matches!(self, Rotation::Rotate90 | Rotation::Rotate270)
+    }
+}
+
+/// Mapping from an output's logical space to its pixels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Transform {
+    pub rotation: Rotation,
+    /// Physical pixels per logical pixel, in 1/120ths
+    pub scale: u32,
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform {
+        rotation: Rotation::Normal,
+        scale: SCALE_UNIT,
+    };
+
+    pub fn is_identity(&self) -> bool {
+        *self == Self::IDENTITY
+    }
+
+    /// Size before rotation of a `width` x `height` physical buffer
+    fn unrotated(&self, width: u32, height: u32) -> (u32, u32) {
+        if self.rotation.swaps_axes() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    /// Logical size of a `width` x `height` physical buffer
+    pub fn logical_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let (w, h) = self.unrotated(width, height);
+        let logical = |v: u32| (v as u64 * SCALE_UNIT as u64 / self.scale as u64).max(1) as u32;
+        (logical(w), logical(h))
+    }
+
+    /// Physical pixels showing logical `rect` on a `width` x `height` buffer
+    pub fn to_physical(self, rect: Rect, width: u32, height: u32) -> Rect {
+        if rect.is_empty() {
+            return rect;
+        }
+        // Physical pixel u shows logical pixel floor(u / scale), so logical
+        // [a, b) covers physical [ceil(a * scale), ceil(b * scale))
+        let scaled = |v: i32| (v as i64 * self.scale as i64 + SCALE_UNIT as i64 - 1).div_euclid(SCALE_UNIT as i64) as i32;
+        let (x0, x1) = (scaled(rect.x), scaled(rect.right()));
+        let (y0, y1) = (scaled(rect.y), scaled(rect.bottom()));
+        let (pw, ph) = (width as i32, height as i32);
+
+        let (left, top, right, bottom) = match self.rotation {
+            Rotation::Normal => (x0, y0, x1, y1),
+            Rotation::Rotate90 => (pw - y1, x0, pw - y0, x1),
+            Rotation::Rotate180 => (pw - x1, ph - y1, pw - x0, ph - y0),
+            Rotation::Rotate270 => (y0, ph - x1, y1, ph - x0),
+        };
+        Rect::new(left, top, (right - left).max(0) as u32, (bottom - top).max(0) as u32)
+    }
+
+    /// Logical pixel shown at physical pixel (`x`, `y`)
+    pub fn to_logical(self, x: i32, y: i32, width: u32, height: u32) -> (i32, i32) {
+        let (pw, ph) = (width as i32, height as i32);
+        let (ux, uy) = match self.rotation {
+            Rotation::Normal => (x, y),
+            Rotation::Rotate90 => (y, pw - 1 - x),
+            Rotation::Rotate180 => (pw - 1 - x, ph - 1 - y),
+            Rotation::Rotate270 => (ph - 1 - y, x),
+        };
+        let logical = |v: i32| (v as i64 * SCALE_UNIT as i64).div_euclid(self.scale as i64) as i32;
+        (logical(ux), logical(uy))
+    }
+
+    /// Draw logical `rect` of `src` onto `dst` (nearest neighbour)
+    pub fn apply(&self, src: &Framebuffer, dst: &mut Framebuffer, rect: Rect) {
+        let area = self.to_physical(rect, dst.width, dst.height).intersect(&dst.bounds());
+        let (width, height) = (dst.width, dst.height);
+        for y in area.y..area.bottom() {
+            let row = dst.row_mut(y as u32);
+            for x in area.x..area.right() {
+                let (lx, ly) = self.to_logical(x, y, width, height);
+                let lx = lx.clamp(0, src.width as i32 - 1) as u32;
+                let ly = ly.clamp(0, src.height as i32 - 1) as u32;
+                row[x as usize] = src.row(ly)[lx as usize];
+            }
+        }
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
@@ -0,0 +1,382 @@
+//! Video memory manager
+//!
+//! A buddy allocator over the display adapter's aperture. The start of the
+//! aperture is reserved for scanout buffers and grows or shrinks with the
+//! mode. Allocations are either pinned or evictable: when space runs out,
+//! the least recently used evictable allocation is reclaimed after its
+//! eviction hook has had the chance to copy the contents elsewhere.
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use super::GpuError;
+
+/// Smallest unit of allocation
+pub const VRAM_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Largest aperture managed, in blocks (256 MiB)
+const MAX_BLOCKS: usize = 4096;
+
+/// Order of a block covering all of `MAX_BLOCKS`
+const MAX_ORDER: u8 = 12;
+
+/// Maximum number of live allocations
+pub const MAX_VRAM_ALLOCATIONS: usize = 128;
+
+/// Bits of a handle holding the slot index
+const SLOT_BITS: u32 = 16;
+
+/// Handle naming a video memory allocation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VramHandle(pub u32);
+
+impl VramHandle {
+    fn slot(self) -> usize {
+        (self.0 & ((1 << SLOT_BITS) - 1)) as usize
+    }
+}
+
+/// Where an allocation lives
+#[derive(Clone, Copy, Debug)]
+pub struct Placement {
+    /// Offset into the aperture
+    pub offset: u64,
+    /// Address the device uses (physical)
+    pub gpu_addr: u64,
+    /// Kernel virtual address
+    pub cpu_addr: u64,
+    /// Usable size in bytes
+    pub size: u64,
+}
+
+/// Called before an evictable allocation is reclaimed, while the contents
+/// are still intact. Runs with the allocator locked, so it must not call
+/// back into this module.
+pub type EvictFn = fn(VramHandle, Placement);
+
+/// Allocator statistics, in bytes
+#[derive(Clone, Copy, Debug)]
+pub struct VramStats {
+    pub total: u64,
+    pub free: u64,
+    pub scanout: u64,
+    pub allocations: usize,
+    pub evictions: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Block {
+    /// Inside a larger block
+    Interior,
+    /// Head of a free block of the given order
+    Free(u8),
+    /// Head of an allocated block of the given order
+    Used(u8),
+}
+
+struct Allocation {
+    handle: VramHandle,
+    block: usize,
+    order: u8,
+    size: u64,
+    evict: Option<EvictFn>,
+    last_used: u64,
+}
+
+struct Vram {
+    cpu_base: u64,
+    gpu_base: u64,
+    /// Blocks in the aperture
+    count: usize,
+    blocks: [Block; MAX_BLOCKS],
+    allocations: [Option<Allocation>; MAX_VRAM_ALLOCATIONS],
+    /// Blocks reserved for scanout, as (head, order)
+    scanout: ArrayVec<(usize, u8), { MAX_ORDER as usize + 1 }>,
+    next_generation: u32,
+    evictions: u64,
+}
+
+static VRAM: Mutex<Vram> = Mutex::new(Vram {
+    cpu_base: 0,
+    gpu_base: 0,
+    count: 0,
+    blocks: [Block::Interior; MAX_BLOCKS],
+    allocations: [const { None }; MAX_VRAM_ALLOCATIONS],
+    scanout: ArrayVec::new_const(),
+    next_generation: 0,
+    evictions: 0,
+});
+
+/// Split `[start, end)` into the largest aligned power-of-two blocks
+fn aligned_blocks(start: usize, end: usize) -> impl Iterator<Item = (usize, u8)> {
+    let mut index = start;
+    core::iter::from_fn(move || {
+        if index >= end {
+            return None;
+        }
+        let mut order = 0;
+        while order < MAX_ORDER && index.is_multiple_of(2 << order) && index + (2 << order) <= end {
+            order += 1;
+        }
+        let block = (index, order);
+        index += 1 << order;
+        Some(block)
+    })
+}
+
+/// Order of the smallest block holding `size` bytes
+fn order_for(size: u64) -> Option<u8> {
+    let blocks = size.div_ceil(VRAM_BLOCK_SIZE).max(1).next_power_of_two();
+    let order = blocks.trailing_zeros() as u8;
+    (order <= MAX_ORDER).then_some(order)
+}
+
+impl Vram {
+    fn placement(&self, block: usize, size: u64) -> Placement {
+        let offset = block as u64 * VRAM_BLOCK_SIZE;
+        Placement {
+            offset,
+            gpu_addr: self.gpu_base + offset,
+            cpu_addr: self.cpu_base + offset,
+            size,
+        }
+    }
+
+    /// Make `index` the head of a free block of `order`, splitting the free
+    /// block that contains it
+    fn split_to(&mut self, index: usize, order: u8) -> bool {
+        for mut o in order..=MAX_ORDER {
+            let mut head = index & !((1 << o) - 1);
+            if self.blocks.get(head) != Some(&Block::Free(o)) {
+                continue;
+            }
+            while o > order {
+                o -= 1;
+                let buddy = head + (1 << o);
+                self.blocks[head] = Block::Free(o);
+                self.blocks[buddy] = Block::Free(o);
+                if index >= buddy {
+                    head = buddy;
+                }
+            }
+            return true;
+        }
+        false
+    }
+
+    /// Take a free block of `order`, best fit
+    fn take(&mut self, order: u8) -> Option<usize> {
+        let mut best: Option<(usize, u8)> = None;
+        let mut index = 0;
+        while index < self.count {
+            let step = match self.blocks[index] {
+                Block::Free(o) => {
+                    if o >= order && best.is_none_or(|(_, b)| o < b) {
+                        best = Some((index, o));
+                    }
+                    o
+                }
+                Block::Used(o) => o,
+                Block::Interior => 0,
+            };
+            index += 1 << step;
+        }
+
+        let (head, _) = best?;
+        self.split_to(head, order);
+        self.blocks[head] = Block::Used(order);
+        Some(head)
+    }
+
+    /// Return a block, merging it with free buddies
+    fn release(&mut self, mut index: usize, mut order: u8) {
+        self.blocks[index] = Block::Free(order);
+        while order < MAX_ORDER {
+            let buddy = index ^ (1 << order);
+            if buddy + (1 << order) > self.count || self.blocks[buddy] != Block::Free(order) {
+                break;
+            }
+            self.blocks[index.max(buddy)] = Block::Interior;
+            index = index.min(buddy);
+            order += 1;
+            self.blocks[index] = Block::Free(order);
+        }
+    }
+
+    fn get(&self, handle: VramHandle) -> Result<&Allocation, GpuError> {
+        match self.allocations.get(handle.slot()) {
+            Some(Some(a)) if a.handle == handle => Ok(a),
+            _ => Err(GpuError::InvalidBuffer),
+        }
+    }
+
+    /// Reclaim an evictable allocation, oldest first, or those overlapping
+    /// `range` (in blocks) if given
+    fn evict(&mut self, range: Option<(usize, usize)>) -> bool {
+        let victim = self
+            .allocations
+            .iter()
+            .flatten()
+            .filter(|a| a.evict.is_some())
+            .filter(|a| range.is_none_or(|(start, end)| a.block < end && a.block + (1 << a.order) > start))
+            .min_by_key(|a| a.last_used)
+            .map(|a| a.handle.slot());
+        let Some(slot) = victim else {
+            return false;
+        };
+
+        let allocation = self.allocations[slot].take().expect("victim exists");
+        if let Some(hook) = allocation.evict {
+            hook(allocation.handle, self.placement(allocation.block, allocation.size));
+        }
+        self.release(allocation.block, allocation.order);
+        self.evictions += 1;
+        true
+    }
+}
+
+/// Manage the aperture at physical `gpu_base`, mapped at `cpu_base`
+///
+/// Anything beyond 256 MiB is left unused.
+pub fn init(cpu_base: u64, gpu_base: u64, size: u64) {
+    let mut vram = VRAM.lock();
+    vram.cpu_base = cpu_base;
+    vram.gpu_base = gpu_base;
+    vram.count = ((size / VRAM_BLOCK_SIZE) as usize).min(MAX_BLOCKS);
+    vram.blocks = [Block::Interior; MAX_BLOCKS];
+    vram.allocations = [const { None }; MAX_VRAM_ALLOCATIONS];
+    vram.scanout.clear();
+
+    let count = vram.count;
+    for (head, order) in aligned_blocks(0, count) {
+        vram.blocks[head] = Block::Free(order);
+    }
+}
+
+/// Reserve the first `bytes` of the aperture for scanout, evicting whatever
+/// is in the way
+///
+/// Fails with `OutOfMemory`, keeping the old reservation, if pinned
+/// allocations occupy the range.
+pub fn reserve_scanout(bytes: u64) -> Result<(), GpuError> {
+    let mut vram = VRAM.lock();
+    let blocks = bytes.div_ceil(VRAM_BLOCK_SIZE) as usize;
+    if blocks > vram.count {
+        return Err(GpuError::OutOfMemory);
+    }
+
+    let pinned = vram
+        .allocations
+        .iter()
+        .flatten()
+        .any(|a| a.evict.is_none() && a.block < blocks);
+    if pinned {
+        return Err(GpuError::OutOfMemory);
+    }
+    while vram.evict(Some((0, blocks))) {}
+
+    let old = core::mem::take(&mut vram.scanout);
+    for (head, order) in old {
+        vram.release(head, order);
+    }
+    for (head, order) in aligned_blocks(0, blocks) {
+        let free = vram.split_to(head, order);
+        debug_assert!(free, "scanout range is clear");
+        vram.blocks[head] = Block::Used(order);
+        vram.scanout.push((head, order));
+    }
+    Ok(())
+}
+
+/// Allocate `size` bytes of video memory
+///
+/// With an eviction hook the allocation may be reclaimed under memory
+/// pressure; without one it stays pinned until freed.
+pub fn vram_alloc(size: u64, evict: Option<EvictFn>) -> Result<(VramHandle, Placement), GpuError> {
+    let order = order_for(size).ok_or(GpuError::OutOfMemory)?;
+    let mut vram = VRAM.lock();
+    if vram.count == 0 {
+        return Err(GpuError::DeviceNotFound);
+    }
+    let slot = vram
+        .allocations
+        .iter()
+        .position(|a| a.is_none())
+        .ok_or(GpuError::TooManyBuffers)?;
+
+    let block = loop {
+        if let Some(block) = vram.take(order) {
+            break block;
+        }
+        if !vram.evict(None) {
+            return Err(GpuError::OutOfMemory);
+        }
+    };
+
+    vram.next_generation = vram.next_generation.wrapping_add(1);
+    let handle = VramHandle(vram.next_generation << SLOT_BITS | slot as u32);
+    vram.allocations[slot] = Some(Allocation {
+        handle,
+        block,
+        order,
+        size,
+        evict,
+        last_used: crate::scheduler::ticks(),
+    });
+    Ok((handle, vram.placement(block, size)))
+}
+
+/// Free an allocation
+pub fn vram_free(handle: VramHandle) -> Result<(), GpuError> {
+    let mut vram = VRAM.lock();
+    let (block, order) = vram.get(handle).map(|a| (a.block, a.order))?;
+    vram.allocations[handle.slot()] = None;
+    vram.release(block, order);
+    Ok(())
+}
+
+/// Where an allocation lives; counts as a use for eviction
+pub fn vram_placement(handle: VramHandle) -> Result<Placement, GpuError> {
+    let mut vram = VRAM.lock();
+    let (block, size) = vram.get(handle).map(|a| (a.block, a.size))?;
+    if let Some(allocation) = vram.allocations[handle.slot()].as_mut() {
+        allocation.last_used = crate::scheduler::ticks();
+    }
+    Ok(vram.placement(block, size))
+}
+
+/// Allocation starting at GPU address `gpu_addr`
+pub fn vram_lookup(gpu_addr: u64) -> Option<VramHandle> {
+    let vram = VRAM.lock();
+    let offset = gpu_addr.checked_sub(vram.gpu_base)?;
+    vram.allocations
+        .iter()
+        .flatten()
+        .find(|a| a.block as u64 * VRAM_BLOCK_SIZE == offset)
+        .map(|a| a.handle)
+}
+
+/// Usage statistics
+pub fn vram_stats() -> VramStats {
+    let vram = VRAM.lock();
+    let mut free = 0;
+    let mut index = 0;
+    while index < vram.count {
+        let order = match vram.blocks[index] {
+            Block::Free(o) => {
+                free += (1u64 << o) * VRAM_BLOCK_SIZE;
+                o
+            }
+            Block::Used(o) => o,
+            Block::Interior => 0,
+        };
+        index += 1 << order;
+    }
+    VramStats {
+        total: vram.count as u64 * VRAM_BLOCK_SIZE,
+        free,
+        scanout: vram.scanout.iter().map(|(_, o)| (1u64 << o) * VRAM_BLOCK_SIZE).sum(),
+        allocations: vram.allocations.iter().flatten().count(),
+        evictions: vram.evictions,
+    }
+}
@@ -0,0 +1,768 @@
+//! Wayland protocol server over Zen IPC
+//!
+//! Clients send `WAYLAND_CONNECT_MSG` with their reply channel to the server
+//! channel, then exchange wire-format requests and events in
+//! `WAYLAND_MSG` messages. Only core objects are implemented: wl_display,
+//! wl_registry, wl_compositor, wl_surface, wl_shm, wl_seat and the
+//! xdg_shell basics.
+//! wl_shm pools are backed by IPC grants; the fd argument of `create_pool`
+//! carries a grant ID owned by (or shared with) the client. GPU buffer
+//! objects are turned into wl_buffers through the `zen_buffer_v1` global,
+//! whose `create_buffer(new_id, handle_hi, handle_lo)` takes a buffer
+//! object handle. `zen_effects_v1` lets clients set opacity, rounded
+//! corners and a drop shadow per surface, applied on the next commit. Input reaches clients through the window manager, which
+//! decides which surface has keyboard and pointer focus.
+
+pub mod wire;
+
+use alloc::boxed::Box;
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use self::wire::{Header, Reader, WireError, Writer};
+use super::bo::{self, BoHandle};
+use super::compositor::{self, Damage};
+use super::effects::{Effects, Shadow};
+use super::framebuffer::{Framebuffer, PixelFormat, Rect};
+use super::wm;
+use crate::ipc::{self, MessageHeader};
+
+/// Connection request; payload is the client's reply channel (u64)
+pub const WAYLAND_CONNECT_MSG: u32 = 0x574C_0001;
+
+/// Wire-format requests (to the server) or events (to a client)
+pub const WAYLAND_MSG: u32 = 0x574C_0002;
+
+/// Maximum number of connected clients
+pub const MAX_CLIENTS: usize = 8;
+
+/// Maximum number of live objects per client
+pub const MAX_OBJECTS: usize = 128;
+
+/// Frame callbacks a surface can have pending
+const MAX_FRAME_CALLBACKS: usize = 4;
+
+/// Object IDs at and above this are allocated by the server
+const SERVER_ID_BASE: u32 = 0xFF00_0000;
+
+/// wl_shm formats
+const SHM_FORMAT_ARGB8888: u32 = 0;
+const SHM_FORMAT_XRGB8888: u32 = 1;
+
+/// wl_display error codes
+const ERROR_INVALID_OBJECT: u32 = 0;
+const ERROR_INVALID_METHOD: u32 = 1;
+const ERROR_NO_MEMORY: u32 = 2;
+const ERROR_IMPLEMENTATION: u32 = 3;
+
+/// Globals advertised through wl_registry: (name, interface, version)
+const GLOBALS: [(u32, &str, u32); 6] = [
+    (1, "wl_compositor", 4),
+    (2, "wl_shm", 1),
+    (3, "xdg_wm_base", 1),
+    (4, "zen_buffer_v1", 1),
+    (5, "wl_seat", 1),
+    (6, "zen_effects_v1", 1),
+];
+
+/// wl_seat capabilities
+const SEAT_POINTER: u32 = 1;
+const SEAT_KEYBOARD: u32 = 2;
+
+/// wl_keyboard keymap format: none, keys are evdev codes
+const KEYMAP_NO_KEYMAP: u32 = 0;
+
+/// Per-surface state double-buffered until `commit`
+#[derive(Clone, Default)]
+struct SurfaceState {
+    /// Compositor surface, created on first buffer commit
+    surface: Option<u32>,
+    /// Attached buffer object (`Some(0)` detaches)
+    pending_buffer: Option<u32>,
+    pending_damage: Damage,
+    pending_frames: ArrayVec<u32, MAX_FRAME_CALLBACKS>,
+    /// Buffer currently shown
+    current_buffer: u32,
+    /// Effects to apply on commit
+    pending_effects: Option<Effects>,
+    /// Effects last committed
+    effects: Effects,
+}
+
+#[derive(Clone)]
+enum Object {
+    Display,
+    Registry,
+    Callback,
+    Compositor,
+    Region,
+    /// Boxed, as it dwarfs the other variants
+    Surface(Box<SurfaceState>),
+    Shm,
+    ShmPool { base: u64, size: usize, limit: usize },
+    Buffer(Framebuffer),
+    XdgWmBase,
+    XdgPositioner,
+    XdgSurface { surface: u32 },
+    XdgToplevel { xdg_surface: u32 },
+    ZenBuffer,
+    Seat,
+    Pointer,
+    Keyboard,
+    EffectsManager,
+    SurfaceEffects { surface: u32 },
+}
+
+/// Fatal protocol error, reported with wl_display.error before disconnecting
+struct ProtocolError {
+    object: u32,
+    code: u32,
+    message: &'static str,
+}
+
+impl ProtocolError {
+    fn new(object: u32, code: u32, message: &'static str) -> Self {
+        Self { object, code, message }
+    }
+}
+
+impl From<WireError> for ProtocolError {
+    fn from(_: WireError) -> Self {
+        ProtocolError::new(1, ERROR_INVALID_METHOD, "malformed message")
+    }
+}
+
+struct Client {
+    pid: u32,
+    channel: u64,
+    objects: ArrayVec<(u32, Object), MAX_OBJECTS>,
+    events: Writer,
+    /// Frame callbacks to fire after the next presented frame
+    frame_callbacks: ArrayVec<u32, 32>,
+    next_serial: u32,
+}
+
+impl Client {
+    fn new(pid: u32, channel: u64) -> Self {
+        let mut objects = ArrayVec::new();
+        objects.push((1, Object::Display));
+        Self {
+            pid,
+            channel,
+            objects,
+            events: Writer::new(),
+            frame_callbacks: ArrayVec::new(),
+            next_serial: 1,
+        }
+    }
+
+    fn serial(&mut self) -> u32 {
+        self.next_serial = self.next_serial.wrapping_add(1);
+        self.next_serial
+    }
+
+    fn get(&self, id: u32) -> Option<&Object> {
+        self.objects.iter().find(|(oid, _)| *oid == id).map(|(_, o)| o)
+    }
+
+    fn get_mut(&mut self, id: u32) -> Option<&mut Object> {
+        self.objects.iter_mut().find(|(oid, _)| *oid == id).map(|(_, o)| o)
+    }
+
+    fn insert(&mut self, id: u32, object: Object) -> Result<(), ProtocolError> {
+        if id == 0 || id >= SERVER_ID_BASE || self.get(id).is_some() {
+            return Err(ProtocolError::new(1, ERROR_INVALID_OBJECT, "invalid new_id"));
+        }
+        self.objects
+            .try_push((id, object))
+            .map_err(|_| ProtocolError::new(1, ERROR_NO_MEMORY, "too many objects"))
+    }
+
+    /// Destroy a client object and confirm the ID can be reused
+    fn remove(&mut self, id: u32) {
+        self.objects.retain(|(oid, _)| *oid != id);
+        self.delete_id(id);
+    }
+
+    fn delete_id(&mut self, id: u32) {
+        self.event(|w| w.begin(1, 1)?.uint(id)?.end());
+    }
+
+    /// Queue an event, flushing first if the batch is full
+    fn event(&mut self, build: impl Fn(&mut Writer) -> Result<(), WireError>) {
+        if build(&mut self.events).is_ok() {
+            return;
+        }
+        self.events.abort();
+        self.flush();
+        if build(&mut self.events).is_err() {
+            self.events.abort();
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.events.is_empty() {
+            return;
+        }
+        let header = MessageHeader {
+            id: 0,
+            sender: 0, // kernel
+            receiver: self.pid,
+            length: self.events.data().len() as u32,
+            msg_type: WAYLAND_MSG,
+        };
+        let _ = ipc::msg_send(self.channel, header, self.events.data());
+        self.events.clear();
+    }
+
+    fn callback_done(&mut self, callback: u32, data: u32) {
+        self.event(|w| w.begin(callback, 0)?.uint(data)?.end());
+        self.remove(callback);
+    }
+}
+
+struct Server {
+    channel: Option<u64>,
+    clients: [Option<Client>; MAX_CLIENTS],
+}
+
+static SERVER: Mutex<Server> = Mutex::new(Server {
+    channel: None,
+    clients: [const { None }; MAX_CLIENTS],
+});
+
+/// Create the server's listening channel
+pub fn init() -> Result<u64, ipc::IpcError> {
+    let channel = ipc::create_channel()?;
+    SERVER.lock().channel = Some(channel);
+    Ok(channel)
+}
+
+/// Channel clients connect to
+pub fn server_channel() -> Option<u64> {
+    SERVER.lock().channel
+}
+
+fn handle_request(client: &mut Client, header: Header, mut args: Reader) -> Result<(), ProtocolError> {
+    let object = client
+        .get(header.object)
+        .cloned()
+        .ok_or(ProtocolError::new(header.object, ERROR_INVALID_OBJECT, "unknown object"))?;
+    let invalid_method = ProtocolError::new(header.object, ERROR_INVALID_METHOD, "invalid method");
+
+    match (object, header.opcode) {
+        // wl_display.sync
+        (Object::Display, 0) => {
+            let callback = args.new_id()?;
+            client.insert(callback, Object::Callback)?;
+            let serial = client.serial();
+            client.callback_done(callback, serial);
+        }
+        // wl_display.get_registry
+        (Object::Display, 1) => {
+            let registry = args.new_id()?;
+            client.insert(registry, Object::Registry)?;
+            for (name, interface, version) in GLOBALS {
+                client.event(|w| w.begin(registry, 0)?.uint(name)?.string(interface)?.uint(version)?.end());
+            }
+        }
+        // wl_registry.bind
+        (Object::Registry, 0) => {
+            let name = args.uint()?;
+            let interface = args.string()?;
+            let _version = args.uint()?;
+            let id = args.new_id()?;
+
+            let bound = match GLOBALS.iter().find(|(n, i, _)| *n == name && *i == interface) {
+                Some((1, _, _)) => Object::Compositor,
+                Some((2, _, _)) => Object::Shm,
+                Some((3, _, _)) => Object::XdgWmBase,
+                Some((4, _, _)) => Object::ZenBuffer,
+                Some((5, _, _)) => Object::Seat,
+                Some((6, _, _)) => Object::EffectsManager,
+                _ => return Err(ProtocolError::new(header.object, ERROR_INVALID_OBJECT, "unknown global")),
+            };
+            let is_shm = matches!(bound, Object::Shm);
+            let is_seat = matches!(bound, Object::Seat);
+            client.insert(id, bound)?;
+            if is_shm {
+                for format in [SHM_FORMAT_ARGB8888, SHM_FORMAT_XRGB8888] {
+                    client.event(|w| w.begin(id, 0)?.uint(format)?.end());
+                }
+            }
+            if is_seat {
+                client.event(|w| w.begin(id, 0)?.uint(SEAT_POINTER | SEAT_KEYBOARD)?.end());
+            }
+        }
+        // wl_compositor.create_surface / create_region
+        (Object::Compositor, 0) => client.insert(args.new_id()?, Object::Surface(Box::default()))?,
+        (Object::Compositor, 1) => client.insert(args.new_id()?, Object::Region)?,
+        // wl_region.destroy; add/subtract only matter for input and opaque hints
+        (Object::Region, 0) => client.remove(header.object),
+        (Object::Region, 1 | 2) => {}
+        (Object::Surface(state), op) => surface_request(client, header.object, *state, op, &mut args)?,
+        // wl_shm.create_pool
+        (Object::Shm, 0) => {
+            let id = args.new_id()?;
+            let grant = args.uint()?;
+            let size = args.int()?;
+            let mapping = ipc::grant::grant_map(grant, client.pid)
+                .map_err(|_| ProtocolError::new(header.object, ERROR_INVALID_METHOD, "invalid pool grant"))?;
+            if size <= 0 || size as usize > mapping.size {
+                return Err(ProtocolError::new(header.object, ERROR_INVALID_METHOD, "invalid pool size"));
+            }
+            client.insert(id, Object::ShmPool {
+                base: mapping.base,
+                size: size as usize,
+                limit: mapping.size,
+            })?;
+        }
+        // wl_shm_pool.create_buffer
+        (Object::ShmPool { base, size, .. }, 0) => {
+            let id = args.new_id()?;
+            let offset = args.int()?;
+            let width = args.int()?;
+            let height = args.int()?;
+            let stride = args.int()?;
+            let format = args.uint()?;
+
+            let valid = offset >= 0
+                && width > 0
+                && height > 0
+                && width.checked_mul(4).is_some_and(|min_stride| stride >= min_stride)
+                && stride % 4 == 0
+                && (offset as usize).checked_add(stride as usize * height as usize).is_some_and(|end| end <= size)
+                && matches!(format, SHM_FORMAT_ARGB8888 | SHM_FORMAT_XRGB8888);
+            if !valid {
+                return Err(ProtocolError::new(header.object, ERROR_INVALID_METHOD, "invalid buffer"));
+            }
+
+            client.insert(id, Object::Buffer(Framebuffer {
+                base: base + offset as u64,
+                width: width as u32,
+                height: height as u32,
+                stride: stride as u32 / 4,
+                format: PixelFormat::Xrgb8888,
+            }))?;
+        }
+        (Object::ShmPool { .. }, 1) => client.remove(header.object),
+        // wl_shm_pool.resize (pools can only grow, within the grant)
+        (Object::ShmPool { size, limit, .. }, 2) => {
+            let new_size = args.int()?;
+            if new_size < size as i32 || new_size as usize > limit {
+                return Err(ProtocolError::new(header.object, ERROR_INVALID_METHOD, "invalid pool size"));
+            }
+            if let Some(Object::ShmPool { size, .. }) = client.get_mut(header.object) {
+                *size = new_size as usize;
+            }
+        }
+        (Object::Buffer(_), 0) => client.remove(header.object),
+        // zen_buffer_v1.destroy
+        (Object::ZenBuffer, 0) => client.remove(header.object),
+        // zen_buffer_v1.create_buffer
+        (Object::ZenBuffer, 1) => {
+            let id = args.new_id()?;
+            let handle = BoHandle((args.uint()? as u64) << 32 | args.uint()? as u64);
+            let fb = bo::bo_framebuffer(handle)
+                .map_err(|_| ProtocolError::new(header.object, ERROR_INVALID_METHOD, "invalid buffer handle"))?;
+            client.insert(id, Object::Buffer(fb))?;
+        }
+        // xdg_wm_base
+        (Object::XdgWmBase, 0) => client.remove(header.object),
+        (Object::XdgWmBase, 1) => client.insert(args.new_id()?, Object::XdgPositioner)?,
+        (Object::XdgWmBase, 2) => {
+            let id = args.new_id()?;
+            let surface = args.object()?;
+            if !matches!(client.get(surface), Some(Object::Surface(_))) {
+                return Err(ProtocolError::new(header.object, ERROR_INVALID_OBJECT, "not a surface"));
+            }
+            client.insert(id, Object::XdgSurface { surface })?;
+        }
+        (Object::XdgWmBase, 3) => {}
+        (Object::XdgPositioner, 0) => client.remove(header.object),
+        (Object::XdgPositioner, _) => {}
+        // xdg_surface
+        (Object::XdgSurface { .. }, 0) => client.remove(header.object),
+        (Object::XdgSurface { .. }, 1) => {
+            let id = args.new_id()?;
+            client.insert(id, Object::XdgToplevel { xdg_surface: header.object })?;
+            // Let the client pick its size, then ask it to draw
+            client.event(|w| w.begin(id, 0)?.int(0)?.int(0)?.array(&[])?.end());
+            let serial = client.serial();
+            let xdg_surface = header.object;
+            client.event(|w| w.begin(xdg_surface, 0)?.uint(serial)?.end());
+        }
+        (Object::XdgSurface { .. }, 2) => {
+            return Err(ProtocolError::new(header.object, ERROR_IMPLEMENTATION, "popups are not supported"));
+        }
+        (Object::XdgSurface { .. }, 3 | 4) => {}
+        (Object::XdgToplevel { .. }, 0) => client.remove(header.object),
+        // xdg_toplevel.move / resize: the window manager takes over the pointer
+        (Object::XdgToplevel { xdg_surface }, op @ (5 | 6)) => {
+            let _seat = args.object()?;
+            let _serial = args.uint()?;
+            let edges = if op == 6 { args.uint()? } else { 0 };
+            if let Some(surface) = toplevel_surface(client, xdg_surface) {
+                match op {
+                    5 => wm::begin_move(surface),
+                    _ => wm::begin_resize(surface, edges),
+                }
+            }
+        }
+        // xdg_toplevel: titles and other hints are accepted and ignored
+        (Object::XdgToplevel { .. }, 1..=14) => {}
+        // wl_seat.get_pointer / get_keyboard / get_touch
+        (Object::Seat, 0) => client.insert(args.new_id()?, Object::Pointer)?,
+        (Object::Seat, 1) => {
+            let id = args.new_id()?;
+            client.insert(id, Object::Keyboard)?;
+            client.event(|w| w.begin(id, 0)?.uint(KEYMAP_NO_KEYMAP)?.uint(0)?.uint(0)?.end());
+        }
+        (Object::Seat, 2) => {
+            return Err(ProtocolError::new(header.object, ERROR_IMPLEMENTATION, "no touch devices"));
+        }
+        // wl_pointer.set_cursor: all clients share the system cursor for now
+        (Object::Pointer, 0) => {}
+        // wl_pointer.release / wl_keyboard.release
+        (Object::Pointer, 1) | (Object::Keyboard, 0) => client.remove(header.object),
+        // zen_effects_v1.destroy / get_surface_effects
+        (Object::EffectsManager, 0) => client.remove(header.object),
+        (Object::EffectsManager, 1) => {
+            let id = args.new_id()?;
+            let surface = args.object()?;
+            if !matches!(client.get(surface), Some(Object::Surface(_))) {
+                return Err(ProtocolError::new(header.object, ERROR_INVALID_OBJECT, "not a surface"));
+            }
+            client.insert(id, Object::SurfaceEffects { surface })?;
+        }
+        // zen_surface_effects_v1.destroy: the surface goes back to plain drawing
+        (Object::SurfaceEffects { surface }, 0) => {
+            if let Some(Object::Surface(state)) = client.get_mut(surface) {
+                state.pending_effects = Some(Effects::NONE);
+            }
+            client.remove(header.object);
+        }
+        (Object::SurfaceEffects { surface }, op @ 1..=4) => {
+            let Some(Object::Surface(state)) = client.get_mut(surface) else {
+                // The surface is gone; nothing left to apply the effects to
+                return Ok(());
+            };
+            let mut effects = state.pending_effects.unwrap_or(state.effects);
+            match op {
+                // set_opacity(0..=255)
+                1 => effects.opacity = args.uint()?.min(255) as u8,
+                // set_blending(enabled)
+                2 => effects.blending = args.uint()? != 0,
+                // set_corner_radius(pixels)
+                3 => effects.corner_radius = args.uint()?,
+                // set_shadow(radius, offset_x, offset_y, argb); zero alpha removes it
+                _ => {
+                    effects.shadow = Some(Shadow {
+                        radius: args.uint()?,
+                        offset_x: args.int()?,
+                        offset_y: args.int()?,
+                        argb: args.uint()?,
+                    })
+                }
+            }
+            state.pending_effects = Some(effects.clamped());
+        }
+        _ => return Err(invalid_method),
+    }
+    Ok(())
+}
+
+fn surface_request(
+    client: &mut Client,
+    id: u32,
+    mut state: SurfaceState,
+    opcode: u16,
+    args: &mut Reader,
+) -> Result<(), ProtocolError> {
+    match opcode {
+        // destroy
+        0 => {
+            if let Some(surface) = state.surface {
+                let _ = compositor::surface_destroy(surface);
+            }
+            client.remove(id);
+            return Ok(());
+        }
+        // attach
+        1 => {
+            let buffer = args.object()?;
+            let _x = args.int()?;
+            let _y = args.int()?;
+            state.pending_buffer = Some(buffer);
+        }
+        // damage / damage_buffer (buffer scale is always 1)
+        2 | 9 => {
+            let x = args.int()?;
+            let y = args.int()?;
+            let width = args.int()?;
+            let height = args.int()?;
+            state
+                .pending_damage
+                .add(Rect::new(x, y, width.max(0) as u32, height.max(0) as u32));
+        }
+        // frame
+        3 => {
+            let callback = args.new_id()?;
+            client.insert(callback, Object::Callback)?;
+            if state.pending_frames.try_push(callback).is_err() {
+                return Err(ProtocolError::new(id, ERROR_NO_MEMORY, "too many frame callbacks"));
+            }
+        }
+        // set_opaque_region / set_input_region / set_buffer_transform / set_buffer_scale
+        4 | 5 | 7 | 8 => {}
+        // commit
+        6 => commit(client, id, &mut state)?,
+        _ => return Err(ProtocolError::new(id, ERROR_INVALID_METHOD, "invalid method")),
+    }
+
+    if let Some(Object::Surface(s)) = client.get_mut(id) {
+        **s = state;
+    }
+    Ok(())
+}
+
+/// Apply double-buffered surface state
+fn commit(client: &mut Client, id: u32, state: &mut SurfaceState) -> Result<(), ProtocolError> {
+    let no_memory = |_| ProtocolError::new(id, ERROR_NO_MEMORY, "compositor surface limit");
+
+    if let Some(buffer) = state.pending_buffer.take() {
+        if buffer == 0 {
+            if let Some(surface) = state.surface {
+                let _ = compositor::surface_set_visible(surface, false);
+            }
+        } else {
+            let fb = match client.get(buffer) {
+                Some(Object::Buffer(fb)) => *fb,
+                _ => return Err(ProtocolError::new(id, ERROR_INVALID_OBJECT, "not a buffer")),
+            };
+            match state.surface {
+                Some(surface) => {
+                    compositor::surface_attach(surface, fb).map_err(no_memory)?;
+                    let _ = compositor::surface_set_visible(surface, true);
+                }
+                None => state.surface = Some(compositor::surface_create(client.pid, fb).map_err(no_memory)?),
+            }
+        }
+
+        // The compositor reads client memory directly, so the old buffer is
+        // free as soon as it is replaced
+        let previous = core::mem::replace(&mut state.current_buffer, buffer);
+        if previous != 0 && previous != buffer && client.get(previous).is_some() {
+            client.event(|w| w.begin(previous, 0)?.end());
+        }
+    }
+
+    if let Some(effects) = state.pending_effects.take() {
+        state.effects = effects;
+    }
+    if let Some(surface) = state.surface {
+        let _ = compositor::surface_set_effects(surface, state.effects);
+        for rect in state.pending_damage.rects() {
+            let _ = compositor::surface_damage(surface, *rect);
+        }
+    }
+    state.pending_damage.clear();
+
+    for callback in state.pending_frames.drain(..) {
+        if client.frame_callbacks.try_push(callback).is_err() {
+            // Too many outstanding: complete it right away rather than leak it
+            client.callback_done(callback, 0);
+        }
+    }
+    Ok(())
+}
+
+/// Compositor surface behind an xdg_surface
+fn toplevel_surface(client: &Client, xdg_surface: u32) -> Option<u32> {
+    match client.get(xdg_surface)? {
+        Object::XdgSurface { surface } => match client.get(*surface)? {
+            Object::Surface(state) => state.surface,
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Input and configuration the window manager sends to a surface's client
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum SeatEvent {
+    /// Pointer entered at surface-local coordinates
+    PointerEnter { x: i32, y: i32 },
+    PointerLeave,
+    PointerMotion { x: i32, y: i32 },
+    PointerButton { button: u16, pressed: bool },
+    KeyboardEnter,
+    KeyboardLeave,
+    Key { code: u16, pressed: bool },
+    /// Ask the client to redraw its toplevel at a new size
+    Configure { width: u32, height: u32 },
+}
+
+impl SeatEvent {
+    fn is_pointer(self) -> bool {
+        matches!(
+            self,
+            SeatEvent::PointerEnter { .. }
+                | SeatEvent::PointerLeave
+                | SeatEvent::PointerMotion { .. }
+                | SeatEvent::PointerButton { .. }
+        )
+    }
+}
+
+/// Deliver `event` to the client showing compositor surface `surface`
+///
+/// Must not be called with the window manager's lock held.
+pub(crate) fn send_input(surface: u32, event: SeatEvent) {
+    let mut server = SERVER.lock();
+    let found = server.clients.iter_mut().flatten().find_map(|client| {
+        let id = client.objects.iter().find_map(|(id, object)| match object {
+            Object::Surface(state) if state.surface == Some(surface) => Some(*id),
+            _ => None,
+        })?;
+        Some((client, id))
+    });
+    let Some((client, wl_surface)) = found else {
+        return;
+    };
+
+    let time = (crate::scheduler::ticks() * 1000 / crate::scheduler::TICKS_PER_SECOND) as u32;
+    let serial = client.serial();
+
+    if let SeatEvent::Configure { width, height } = event {
+        let toplevels: ArrayVec<(u32, u32), 4> = client
+            .objects
+            .iter()
+            .filter_map(|(id, object)| match object {
+                Object::XdgToplevel { xdg_surface } if toplevel_surface(client, *xdg_surface) == Some(surface) => {
+                    Some((*id, *xdg_surface))
+                }
+                _ => None,
+            })
+            .collect();
+        for (toplevel, xdg_surface) in toplevels {
+            client.event(|w| w.begin(toplevel, 0)?.int(width as i32)?.int(height as i32)?.array(&[])?.end());
+            client.event(|w| w.begin(xdg_surface, 0)?.uint(serial)?.end());
+        }
+        client.flush();
+        return;
+    }
+
+    let targets: ArrayVec<u32, 8> = client
+        .objects
+        .iter()
+        .filter(|(_, object)| match object {
+            Object::Pointer => event.is_pointer(),
+            Object::Keyboard => !event.is_pointer(),
+            _ => false,
+        })
+        .map(|(id, _)| *id)
+        .collect();
+
+    // Pointer coordinates are 24.8 fixed point
+    let fixed = |v: i32| v.saturating_mul(256);
+    for target in targets {
+        client.event(|w| {
+            match event {
+                SeatEvent::PointerEnter { x, y } => {
+                    w.begin(target, 0)?.uint(serial)?.uint(wl_surface)?.int(fixed(x))?.int(fixed(y))?
+                }
+                SeatEvent::PointerLeave => w.begin(target, 1)?.uint(serial)?.uint(wl_surface)?,
+                SeatEvent::PointerMotion { x, y } => w.begin(target, 2)?.uint(time)?.int(fixed(x))?.int(fixed(y))?,
+                SeatEvent::PointerButton { button, pressed } => {
+                    w.begin(target, 3)?.uint(serial)?.uint(time)?.uint(button as u32)?.uint(pressed as u32)?
+                }
+                SeatEvent::KeyboardEnter => w.begin(target, 1)?.uint(serial)?.uint(wl_surface)?.array(&[])?,
+                SeatEvent::KeyboardLeave => w.begin(target, 2)?.uint(serial)?.uint(wl_surface)?,
+                SeatEvent::Key { code, pressed } => {
+                    w.begin(target, 3)?.uint(serial)?.uint(time)?.uint(code as u32)?.uint(pressed as u32)?
+                }
+                SeatEvent::Configure { .. } => return Ok(()),
+            };
+            w.end()
+        });
+    }
+    client.flush();
+}
+
+/// Drop a client and everything it showed on screen
+fn disconnect(client: Client) {
+    for (_, object) in client.objects.iter() {
+        if let Object::Surface(state) = object {
+            if let Some(surface) = state.surface {
+                let _ = compositor::surface_destroy(surface);
+            }
+        }
+    }
+}
+
+/// Process pending connections and requests, then send queued events
+pub fn dispatch() {
+    let mut server = SERVER.lock();
+    let channel = match server.channel {
+        Some(channel) => channel,
+        None => return,
+    };
+
+    let mut buffer = [0; ipc::MAX_MESSAGE_SIZE];
+    while let Ok((header, len)) = ipc::msg_recv(channel, &mut buffer) {
+        let data = &buffer[..len];
+        match header.msg_type {
+            WAYLAND_CONNECT_MSG => {
+                let reply = match data.get(..8) {
+                    Some(bytes) => u64::from_le_bytes(bytes.try_into().unwrap_or([0; 8])),
+                    None => continue,
+                };
+                if let Some(slot) = server.clients.iter_mut().find(|c| c.is_none()) {
+                    *slot = Some(Client::new(header.sender, reply));
+                }
+            }
+            WAYLAND_MSG => {
+                let slot = match server
+                    .clients
+                    .iter_mut()
+                    .find(|c| matches!(c, Some(c) if c.pid == header.sender))
+                {
+                    Some(slot) => slot,
+                    None => continue,
+                };
+                let client = slot.as_mut().expect("matched above");
+
+                let result = wire::messages(data).try_for_each(|message| {
+                    let (header, args) = message?;
+                    handle_request(client, header, args)
+                });
+
+                if let Err(e) = result {
+                    client.event(|w| w.begin(1, 0)?.uint(e.object)?.uint(e.code)?.string(e.message)?.end());
+                    client.flush();
+                    if let Some(client) = slot.take() {
+                        disconnect(client);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for client in server.clients.iter_mut().flatten() {
+        client.flush();
+    }
+}
+
+/// Fire frame callbacks after a frame was presented
+pub fn frame_done(time_ms: u32) {
+    let mut server = SERVER.lock();
+    for client in server.clients.iter_mut().flatten() {
+        let callbacks = core::mem::take(&mut client.frame_callbacks);
+        for callback in callbacks {
+            client.callback_done(callback, time_ms);
+        }
+        client.flush();
+    }
+}
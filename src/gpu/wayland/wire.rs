@@ -0,0 +1,211 @@
+//! Wayland wire format
+//!
+//! Messages are a two-word header (object ID, then size << 16 | opcode)
+//! followed by 32-bit aligned arguments in host byte order. File
+//! descriptors are not carried; shared memory is referenced by grant ID.
+
+use arrayvec::ArrayVec;
+
+use crate::ipc::MAX_MESSAGE_SIZE;
+
+/// Header size in bytes
+pub const HEADER_SIZE: usize = 8;
+
+/// Wire decoding errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireError {
+    Truncated,
+    BadString,
+    Overflow,
+}
+
+/// Header of one message
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
+    pub object: u32,
+    pub opcode: u16,
+    /// Total size including the header
+    pub size: u16,
+}
+
+/// Cursor over the arguments of one message
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn word(&mut self) -> Result<u32, WireError> {
+        let bytes = self.data.get(self.pos..self.pos + 4).ok_or(WireError::Truncated)?;
+        self.pos += 4;
+        Ok(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn uint(&mut self) -> Result<u32, WireError> {
+        self.word()
+    }
+
+    pub fn int(&mut self) -> Result<i32, WireError> {
+        self.word().map(|w| w as i32)
+    }
+
+    /// Object ID (0 = null)
+    pub fn object(&mut self) -> Result<u32, WireError> {
+        self.word()
+    }
+
+    pub fn new_id(&mut self) -> Result<u32, WireError> {
+        self.word()
+    }
+
+    /// Length-prefixed, NUL-terminated, padded string
+    pub fn string(&mut self) -> Result<&'a str, WireError> {
+        let len = self.word()? as usize;
+        if len == 0 {
+            return Ok("");
+        }
+        let padded = (len + 3) & !3;
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or(WireError::Truncated)?;
+        self.pos += padded;
+        // Drop the terminating NUL
+        core::str::from_utf8(&bytes[..len - 1]).map_err(|_| WireError::BadString)
+    }
+
+    pub fn array(&mut self) -> Result<&'a [u8], WireError> {
+        let len = self.word()? as usize;
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or(WireError::Truncated)?;
+        self.pos += (len + 3) & !3;
+        Ok(bytes)
+    }
+}
+
+/// Split a buffer of back-to-back messages
+pub fn messages(data: &[u8]) -> impl Iterator<Item = Result<(Header, Reader<'_>), WireError>> {
+    let mut pos = 0;
+    core::iter::from_fn(move || {
+        if pos >= data.len() {
+            return None;
+        }
+        let header = match data.get(pos..pos + HEADER_SIZE) {
+            Some(h) => h,
+            None => {
+                pos = data.len();
+                return Some(Err(WireError::Truncated));
+            }
+        };
+
+        let object = u32::from_ne_bytes([header[0], header[1], header[2], header[3]]);
+        let word = u32::from_ne_bytes([header[4], header[5], header[6], header[7]]);
+        let size = (word >> 16) as usize;
+        if size < HEADER_SIZE || pos + size > data.len() {
+            pos = data.len();
+            return Some(Err(WireError::Truncated));
+        }
+
+        let body = &data[pos + HEADER_SIZE..pos + size];
+        pos += size;
+        Some(Ok((
+            Header {
+                object,
+                opcode: word as u16,
+                size: size as u16,
+            },
+            Reader { data: body, pos: 0 },
+        )))
+    })
+}
+
+/// Accumulates outgoing events for one client
+pub struct Writer {
+    buf: ArrayVec<u8, MAX_MESSAGE_SIZE>,
+    /// Start of the message being built
+    start: usize,
+}
+
+impl Writer {
+    pub const fn new() -> Self {
+        Self {
+            buf: ArrayVec::new_const(),
+            start: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Encoded events
+    pub fn data(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.start = 0;
+    }
+
+    /// Space left
+    pub fn remaining(&self) -> usize {
+        self.buf.capacity() - self.buf.len()
+    }
+
+    fn word(&mut self, value: u32) -> Result<(), WireError> {
+        self.buf
+            .try_extend_from_slice(&value.to_ne_bytes())
+            .map_err(|_| WireError::Overflow)
+    }
+
+    /// Start an event; finish it with [`Writer::end`]
+    pub fn begin(&mut self, object: u32, opcode: u16) -> Result<&mut Self, WireError> {
+        self.start = self.buf.len();
+        self.word(object)?;
+        self.word(opcode as u32)?;
+        Ok(self)
+    }
+
+    pub fn uint(&mut self, value: u32) -> Result<&mut Self, WireError> {
+        self.word(value)?;
+        Ok(self)
+    }
+
+    pub fn int(&mut self, value: i32) -> Result<&mut Self, WireError> {
+        self.word(value as u32)?;
+        Ok(self)
+    }
+
+    pub fn string(&mut self, value: &str) -> Result<&mut Self, WireError> {
+        self.array_padded(value.as_bytes(), true)
+    }
+
+    pub fn array(&mut self, value: &[u8]) -> Result<&mut Self, WireError> {
+        self.array_padded(value, false)
+    }
+
+    fn array_padded(&mut self, value: &[u8], nul: bool) -> Result<&mut Self, WireError> {
+        let len = value.len() + nul as usize;
+        self.word(len as u32)?;
+        self.buf.try_extend_from_slice(value).map_err(|_| WireError::Overflow)?;
+        for _ in value.len()..(len + 3) & !3 {
+            self.buf.try_push(0).map_err(|_| WireError::Overflow)?;
+        }
+        Ok(self)
+    }
+
+    /// Patch the size into the header of the event being built
+    pub fn end(&mut self) -> Result<(), WireError> {
+        let size = self.buf.len() - self.start;
+        let word = (size as u32) << 16 | u32::from_ne_bytes([
+            self.buf[self.start + 4],
+            self.buf[self.start + 5],
+            self.buf[self.start + 6],
+            self.buf[self.start + 7],
+        ]);
+        self.buf[self.start + 4..self.start + 8].copy_from_slice(&word.to_ne_bytes());
+        Ok(())
+    }
+
+    /// Drop a partially built event
+    pub fn abort(&mut self) {
+        self.buf.truncate(self.start);
+    }
+}
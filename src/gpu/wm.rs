@@ -0,0 +1,299 @@
+//! Window management
+//!
+//! Tracks which surface has keyboard focus and which one the pointer is
+//! over, routes kernel input events to them, and handles interactive moves
+//! and resizes. Clicking a window focuses and raises it; holding Super
+//! while dragging with the left button moves a window, with the right
+//! button resizes it. Kernel surfaces such as the console are never focused.
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use super::compositor;
+use super::framebuffer::Rect;
+use super::output::{self, PRIMARY_OUTPUT};
+use super::wayland::{self, SeatEvent};
+use super::{cursor, GpuError};
+use crate::kernel::input::{self, InputEvent, BUTTON_LEFT, BUTTON_RIGHT};
+
+/// Smallest size an interactive resize asks for
+pub const MIN_WINDOW_SIZE: u32 = 64;
+
+/// xdg_toplevel resize edges
+pub const EDGE_TOP: u32 = 1;
+pub const EDGE_BOTTOM: u32 = 2;
+pub const EDGE_LEFT: u32 = 4;
+pub const EDGE_RIGHT: u32 = 8;
+
+/// evdev code of the left Super key
+const KEY_LEFTMETA: u16 = 125;
+
+/// Input events handled per poll, so a flood cannot stall composition
+const EVENTS_PER_POLL: usize = 64;
+
+#[derive(Clone, Copy)]
+enum Grab {
+    None,
+    /// Dragging a window; the pointer stays at a fixed offset from its corner
+    Move { surface: u32, dx: i32, dy: i32 },
+    /// Resizing a window from the geometry it had when the grab started
+    Resize { surface: u32, edges: u32, start: Rect, px: i32, py: i32 },
+}
+
+struct WindowManager {
+    /// Surface with keyboard focus
+    focused: Option<u32>,
+    /// Surface under the pointer
+    pointer_focus: Option<u32>,
+    pointer_x: i32,
+    pointer_y: i32,
+    grab: Grab,
+    super_down: bool,
+}
+
+static WM: Mutex<WindowManager> = Mutex::new(WindowManager {
+    focused: None,
+    pointer_focus: None,
+    pointer_x: 0,
+    pointer_y: 0,
+    grab: Grab::None,
+    super_down: false,
+});
+
+/// Events to send once the window manager's lock is released
+type Outbox = ArrayVec<(u32, SeatEvent), 8>;
+
+/// Surfaces the window manager may focus and move: those owned by clients
+fn is_client_surface(id: u32) -> bool {
+    compositor::surface_geometry(id).is_some_and(|(owner, _)| owner != 0)
+}
+
+fn deliver(outbox: Outbox) {
+    for (surface, event) in outbox {
+        wayland::send_input(surface, event);
+    }
+}
+
+impl WindowManager {
+    fn set_focus(&mut self, id: Option<u32>, out: &mut Outbox) {
+        if self.focused == id {
+            return;
+        }
+        if let Some(old) = self.focused {
+            let _ = out.try_push((old, SeatEvent::KeyboardLeave));
+        }
+        if let Some(new) = id {
+            let _ = compositor::surface_raise(new);
+            let _ = out.try_push((new, SeatEvent::KeyboardEnter));
+        }
+        self.focused = id;
+    }
+
+    /// Send enter/leave/motion for the surface under the pointer
+    fn update_pointer_focus(&mut self, out: &mut Outbox) {
+        let hit = compositor::surface_at(PRIMARY_OUTPUT, self.pointer_x, self.pointer_y)
+            .filter(|(id, _, _)| is_client_surface(*id));
+        let under = hit.map(|(id, _, _)| id);
+
+        if under != self.pointer_focus {
+            if let Some(old) = self.pointer_focus {
+                let _ = out.try_push((old, SeatEvent::PointerLeave));
+            }
+            if let Some((id, x, y)) = hit {
+                let _ = out.try_push((id, SeatEvent::PointerEnter { x, y }));
+            }
+            self.pointer_focus = under;
+        } else if let Some((id, x, y)) = hit {
+            let _ = out.try_push((id, SeatEvent::PointerMotion { x, y }));
+        }
+    }
+
+    fn move_pointer(&mut self, dx: i32, dy: i32, out: &mut Outbox) {
+        let (width, height) = output::logical_size(PRIMARY_OUTPUT)
+            .map(|(w, h)| (w as i32, h as i32))
+            .unwrap_or((1, 1));
+        self.pointer_x = (self.pointer_x + dx).clamp(0, width - 1);
+        self.pointer_y = (self.pointer_y + dy).clamp(0, height - 1);
+        cursor::move_to(self.pointer_x, self.pointer_y);
+
+        match self.grab {
+            Grab::None => self.update_pointer_focus(out),
+            Grab::Move { surface, dx, dy } => {
+                let _ = compositor::surface_move(surface, self.pointer_x - dx, self.pointer_y - dy);
+            }
+            Grab::Resize { surface, edges, start, px, py } => {
+                let (width, height) = resized(start, edges, self.pointer_x - px, self.pointer_y - py);
+                let _ = out.try_push((surface, SeatEvent::Configure { width, height }));
+            }
+        }
+    }
+
+    fn button(&mut self, button: u16, pressed: bool, out: &mut Outbox) {
+        if !pressed {
+            if !matches!(self.grab, Grab::None) {
+                self.grab = Grab::None;
+                self.update_pointer_focus(out);
+                return;
+            }
+        } else if let Some((id, _, _)) = compositor::surface_at(PRIMARY_OUTPUT, self.pointer_x, self.pointer_y) {
+            if is_client_surface(id) {
+                self.set_focus(Some(id), out);
+                if self.super_down && matches!(button, BUTTON_LEFT | BUTTON_RIGHT) {
+                    self.start_grab(id, if button == BUTTON_LEFT { None } else { Some(EDGE_BOTTOM | EDGE_RIGHT) });
+                    return;
+                }
+            }
+        }
+
+        if let Some(id) = self.pointer_focus {
+            let _ = out.try_push((id, SeatEvent::PointerButton { button, pressed }));
+        }
+    }
+
+    /// Start moving (`edges` None) or resizing a surface under the pointer
+    fn start_grab(&mut self, surface: u32, edges: Option<u32>) {
+        let Some((_, rect)) = compositor::surface_geometry(surface) else {
+            return;
+        };
+        self.grab = match edges {
+            None => Grab::Move {
+                surface,
+                dx: self.pointer_x - rect.x,
+                dy: self.pointer_y - rect.y,
+            },
+            Some(edges) => Grab::Resize {
+                surface,
+                edges,
+                start: rect,
+                px: self.pointer_x,
+                py: self.pointer_y,
+            },
+        };
+    }
+
+    fn key(&mut self, code: u16, pressed: bool, out: &mut Outbox) {
+        if code == KEY_LEFTMETA {
+            self.super_down = pressed;
+            return;
+        }
+        match self.focused {
+            Some(id) => {
+                let _ = out.try_push((id, SeatEvent::Key { code, pressed }));
+            }
+            // Typing with no window focused goes to the kernel shell
+            None => crate::shell::key(code, pressed),
+        }
+    }
+
+    /// Forget surfaces that went away; focus falls back to the topmost window
+    fn prune(&mut self, out: &mut Outbox) {
+        if self.focused.is_some_and(|id| !is_client_surface(id)) {
+            self.focused = None;
+            let top = compositor::stacking_order().into_iter().rev().find(|id| is_client_surface(*id));
+            self.set_focus(top, out);
+        }
+        if self.pointer_focus.is_some_and(|id| !is_client_surface(id)) {
+            self.pointer_focus = None;
+        }
+        let grabbed = match self.grab {
+            Grab::Move { surface, .. } | Grab::Resize { surface, .. } => Some(surface),
+            Grab::None => None,
+        };
+        if grabbed.is_some_and(|id| !is_client_surface(id)) {
+            self.grab = Grab::None;
+        }
+    }
+}
+
+/// Size of `start` after dragging `edges` by (`dx`, `dy`)
+fn resized(start: Rect, edges: u32, dx: i32, dy: i32) -> (u32, u32) {
+    let mut width = start.width as i32;
+    let mut height = start.height as i32;
+    if edges & EDGE_LEFT != 0 {
+        width -= dx;
+    } else if edges & EDGE_RIGHT != 0 {
+        width += dx;
+    }
+    if edges & EDGE_TOP != 0 {
+        height -= dy;
+    } else if edges & EDGE_BOTTOM != 0 {
+        height += dy;
+    }
+    (width.max(MIN_WINDOW_SIZE as i32) as u32, height.max(MIN_WINDOW_SIZE as i32) as u32)
+}
+
+/// Give keyboard focus to a surface and raise it
+pub fn focus(id: u32) -> Result<(), GpuError> {
+    if !is_client_surface(id) {
+        return Err(GpuError::InvalidSurface);
+    }
+    let mut out = Outbox::new();
+    WM.lock().set_focus(Some(id), &mut out);
+    deliver(out);
+    Ok(())
+}
+
+/// Surface with keyboard focus
+pub fn focused() -> Option<u32> {
+    WM.lock().focused
+}
+
+/// Put a window on top
+pub fn raise(id: u32) -> Result<(), GpuError> {
+    compositor::surface_raise(id)
+}
+
+/// Put a window at the bottom
+pub fn lower(id: u32) -> Result<(), GpuError> {
+    compositor::surface_lower(id)
+}
+
+/// Start an interactive move of `surface`, following the pointer until the
+/// button is released
+pub fn begin_move(surface: u32) {
+    if is_client_surface(surface) {
+        WM.lock().start_grab(surface, None);
+    }
+}
+
+/// Start an interactive resize of `surface` from `edges`
+pub fn begin_resize(surface: u32, edges: u32) {
+    if is_client_surface(surface) && edges != 0 {
+        WM.lock().start_grab(surface, Some(edges));
+    }
+}
+
+/// Ask a window's client to redraw at a new size
+pub fn request_resize(id: u32, width: u32, height: u32) -> Result<(), GpuError> {
+    if !is_client_surface(id) {
+        return Err(GpuError::InvalidSurface);
+    }
+    let configure = SeatEvent::Configure {
+        width: width.max(MIN_WINDOW_SIZE),
+        height: height.max(MIN_WINDOW_SIZE),
+    };
+    wayland::send_input(id, configure);
+    Ok(())
+}
+
+/// Route queued input events, run from the GPU poll loop
+pub fn poll() {
+    for _ in 0..EVENTS_PER_POLL {
+        let mut out = Outbox::new();
+        {
+            let mut wm = WM.lock();
+            wm.prune(&mut out);
+            match input::pop() {
+                Some(InputEvent::Key { code, pressed }) => wm.key(code, pressed, &mut out),
+                Some(InputEvent::PointerMotion { dx, dy }) => wm.move_pointer(dx, dy, &mut out),
+                Some(InputEvent::PointerButton { button, pressed }) => wm.button(button, pressed, &mut out),
+                None => {
+                    drop(wm);
+                    deliver(out);
+                    return;
+                }
+            }
+        }
+        deliver(out);
+    }
+}
@@ -0,0 +1,117 @@
+//! Shared-memory grants
+//!
+//! A grant is a page-aligned memory region owned by one process that can be
+//! shared with others by ID. Grant IDs travel inside IPC messages; a peer
+//! can only look up a grant the owner has explicitly shared with it.
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use super::IpcError;
+
+/// Maximum number of live grants
+pub const MAX_GRANTS: usize = 256;
+
+/// Maximum number of processes a grant can be shared with
+pub const MAX_GRANT_PEERS: usize = 8;
+
+struct Grant {
+    owner: u32,
+    /// Kernel virtual address of the region
+    base: u64,
+    size: usize,
+    peers: ArrayVec<u32, MAX_GRANT_PEERS>,
+}
+
+/// Location of a granted region
+#[derive(Clone, Copy, Debug)]
+pub struct GrantMapping {
+    pub base: u64,
+    pub size: usize,
+}
+
+static GRANTS: Mutex<[Option<Grant>; MAX_GRANTS]> = Mutex::new([const { None }; MAX_GRANTS]);
+
+/// Allocate a zeroed region owned by `owner`, returning its grant ID
+pub fn grant_create(owner: u32, size: usize) -> Result<u32, IpcError> {
+    if size == 0 {
+        return Err(IpcError::InvalidGrant);
+    }
+
+    let mut grants = GRANTS.lock();
+    let (index, slot) = grants
+        .iter_mut()
+        .enumerate()
+        .find(|(_, slot)| slot.is_none())
+        .ok_or(IpcError::TooManyGrants)?;
+
+    let base = crate::kernel::memory::allocate_region(size).map_err(|_| IpcError::OutOfMemory)?;
+    *slot = Some(Grant {
+        owner,
+        base: base.as_u64(),
+        size,
+        peers: ArrayVec::new(),
+    });
+
+    // ID 0 is never valid so it can mean "no grant" in messages
+    Ok(index as u32 + 1)
+}
+
+fn slot(grants: &mut [Option<Grant>; MAX_GRANTS], id: u32) -> Result<&mut Grant, IpcError> {
+    id.checked_sub(1)
+        .and_then(|index| grants.get_mut(index as usize))
+        .and_then(|slot| slot.as_mut())
+        .ok_or(IpcError::InvalidGrant)
+}
+
+/// Let `peer` access a grant
+pub fn grant_share(id: u32, owner: u32, peer: u32) -> Result<(), IpcError> {
+    let mut grants = GRANTS.lock();
+    let grant = slot(&mut grants, id)?;
+    if grant.owner != owner {
+        return Err(IpcError::PermissionDenied);
+    }
+    if !grant.peers.contains(&peer) {
+        grant.peers.try_push(peer).map_err(|_| IpcError::TooManyGrants)?;
+    }
+    Ok(())
+}
+
+/// Look up a grant on behalf of `process` (its owner or a peer)
+pub fn grant_map(id: u32, process: u32) -> Result<GrantMapping, IpcError> {
+    let mut grants = GRANTS.lock();
+    let grant = slot(&mut grants, id)?;
+    if grant.owner != process && !grant.peers.contains(&process) {
+        return Err(IpcError::PermissionDenied);
+    }
+    Ok(GrantMapping {
+        base: grant.base,
+        size: grant.size,
+    })
+}
+
+/// Withdraw every grant `process` owns and its access to others' grants,
+/// once it has exited
+pub fn grant_release_process(process: u32) {
+    let mut grants = GRANTS.lock();
+    for slot in grants.iter_mut() {
+        match slot {
+            Some(grant) if grant.owner == process => *slot = None,
+            Some(grant) => grant.peers.retain(|p| *p != process),
+            None => {}
+        }
+    }
+}
+
+/// Withdraw a grant from all peers
+///
+/// The backing pages stay reserved: the frame allocator cannot take memory
+/// back yet.
+pub fn grant_revoke(id: u32, owner: u32) -> Result<(), IpcError> {
+    let mut grants = GRANTS.lock();
+    if slot(&mut grants, id)?.owner != owner {
+        return Err(IpcError::PermissionDenied);
+    }
+    grants[id as usize - 1] = None;
+    Ok(())
+}
@@ -1,7 +1,20 @@
 //! Zero-copy IPC with lock-free ring buffers
+//!
+//! Channels are looked up without a lock, in a read-side section (see
+//! [`crate::kernel::rcu`]); destroying one closes it, waits for a grace
+//! period and only then frees its slot. Only the process a channel was
+//! created for may receive from it. Receivers copy a message out into
+//! their own buffer inside that section, before its ring slot is given
+//! back to senders.
 
-use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+pub mod grant;
+pub mod names;
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use heapless::Vec;
+use spin::Mutex;
+
+use crate::kernel::rcu;
 
 /// Maximum message size
 pub const MAX_MESSAGE_SIZE: usize = 4096;
@@ -76,8 +89,9 @@ impl RingBuffer {
         Ok(())
     }
 
-    /// Receive a message (zero-copy)
-    pub fn recv(&mut self) -> Result<(MessageHeader, &[u8]), IpcError> {
+    /// Look at the next message without receiving it, copying as much of
+    /// its data as fits into `buffer`; returns the header and the bytes copied
+    pub fn peek(&self, buffer: &mut [u8]) -> Result<(MessageHeader, usize), IpcError> {
         let read_idx = self.read_idx.load(Ordering::Acquire);
         let write_idx = self.write_idx.load(Ordering::Acquire);
 
@@ -86,90 +100,148 @@ impl RingBuffer {
             return Err(IpcError::BufferEmpty);
         }
 
-        // Read message
         let header = self.messages[read_idx].ok_or(IpcError::InvalidMessage)?;
-        let data = &self.data[read_idx][..header.length as usize];
+        let len = (header.length as usize).min(buffer.len());
+        buffer[..len].copy_from_slice(&self.data[read_idx][..len]);
+        Ok((header, len))
+    }
+
+    /// Receive a message, copying as much of its data as fits into
+    /// `buffer` before its slot is freed; returns the header and the bytes
+    /// copied
+    pub fn recv(&mut self, buffer: &mut [u8]) -> Result<(MessageHeader, usize), IpcError> {
+        let read_idx = self.read_idx.load(Ordering::Acquire);
+
+        // Read message
+        let received = self.peek(buffer)?;
 
         // Update read index
         self.read_idx.store((read_idx + 1) % RING_BUFFER_SIZE, Ordering::Release);
 
-        Ok((header, data))
+        Ok(received)
     }
 }
 
 /// Global IPC channel table
 static mut IPC_CHANNELS: [Option<RingBuffer>; MAX_IPC_CHANNELS] = [const { None }; MAX_IPC_CHANNELS];
-static NEXT_CHANNEL_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Channels open for use; a channel is closed here first and only torn
+/// down once no reader that saw it open can still be using it
+static OPEN: [AtomicBool; MAX_IPC_CHANNELS] = [const { AtomicBool::new(false) }; MAX_IPC_CHANNELS];
+
+/// Process that may receive from each channel; 0 for the kernel
+static RECEIVERS: [AtomicU32; MAX_IPC_CHANNELS] = [const { AtomicU32::new(0) }; MAX_IPC_CHANNELS];
+
+/// Held while a channel is created or destroyed
+static CHANNEL_SLOTS: Mutex<()> = Mutex::new(());
+
+/// Look up an open channel inside the read-side section `_read`
+fn channel(_read: &rcu::ReadGuard, channel_id: u64) -> Result<&'static mut RingBuffer, IpcError> {
+    if channel_id >= MAX_IPC_CHANNELS as u64 || !OPEN[channel_id as usize].load(Ordering::Acquire) {
+        return Err(IpcError::InvalidChannel);
+    }
+    unsafe {
+        IPC_CHANNELS[channel_id as usize]
+            .as_mut()
+            .ok_or(IpcError::InvalidChannel)
+    }
+}
 
 /// Initialize IPC subsystem
 pub fn init() {
     // IPC channels are created on demand
 }
 
-/// Create a new IPC channel
+/// Create a new IPC channel for the kernel to receive from, in the lowest
+/// free slot
 pub fn create_channel() -> Result<u64, IpcError> {
-    let channel_id = NEXT_CHANNEL_ID.fetch_add(1, Ordering::Relaxed);
-    
-    if channel_id >= MAX_IPC_CHANNELS as u64 {
-        return Err(IpcError::TooManyChannels);
-    }
+    create_channel_for(0)
+}
+
+/// Create a new IPC channel that process `receiver` receives from, in the
+/// lowest free slot
+pub fn create_channel_for(receiver: u32) -> Result<u64, IpcError> {
+    let _slots = CHANNEL_SLOTS.lock();
+    let channel_id = (0..MAX_IPC_CHANNELS)
+        .find(|&id| unsafe { IPC_CHANNELS[id].is_none() })
+        .ok_or(IpcError::TooManyChannels)?;
 
     unsafe {
-        IPC_CHANNELS[channel_id as usize] = Some(RingBuffer::new());
+        IPC_CHANNELS[channel_id] = Some(RingBuffer::new());
     }
+    RECEIVERS[channel_id].store(receiver, Ordering::Relaxed);
+    OPEN[channel_id].store(true, Ordering::Release);
+    crate::trace!(ChannelCreate, channel_id);
 
-    Ok(channel_id)
+    Ok(channel_id as u64)
 }
 
-/// Send message via IPC
-pub fn msg_send(channel_id: u64, header: MessageHeader, data: &[u8]) -> Result<(), IpcError> {
+/// Destroy a channel and the messages left in it; its ID may be given to
+/// the next channel created
+pub fn destroy_channel(channel_id: u64) -> Result<(), IpcError> {
     if channel_id >= MAX_IPC_CHANNELS as u64 {
         return Err(IpcError::InvalidChannel);
     }
 
+    let _slots = CHANNEL_SLOTS.lock();
+    if !OPEN[channel_id as usize].swap(false, Ordering::AcqRel) {
+        return Err(IpcError::InvalidChannel);
+    }
+    // Senders and receivers already inside the channel finish first
+    rcu::synchronize();
     unsafe {
-        let channel = IPC_CHANNELS[channel_id as usize]
-            .as_mut()
-            .ok_or(IpcError::InvalidChannel)?;
-        
-        // Check capability token
-        crate::capability::check_ipc_permission(header.sender, channel_id)?;
-        
-        channel.send(header, data)
+        IPC_CHANNELS[channel_id as usize] = None;
     }
+    crate::trace!(ChannelDestroy, channel_id);
+
+    Ok(())
 }
 
-/// Receive message via IPC
-pub fn msg_recv(channel_id: u64) -> Result<(MessageHeader, &'static [u8]), IpcError> {
-    if channel_id >= MAX_IPC_CHANNELS as u64 {
-        return Err(IpcError::InvalidChannel);
-    }
+/// Send message via IPC
+pub fn msg_send(channel_id: u64, header: MessageHeader, data: &[u8]) -> Result<(), IpcError> {
+    let read = rcu::read_lock();
+    let channel = channel(&read, channel_id)?;
 
-    unsafe {
-        let channel = IPC_CHANNELS[channel_id as usize]
-            .as_mut()
-            .ok_or(IpcError::InvalidChannel)?;
-        
-        channel.recv()
-    }
+    // Check capability token
+    crate::capability::check_ipc_permission(header.sender, channel_id)?;
+
+    channel.send(header, data)?;
+    crate::trace!(MessageSend, channel_id, header.sender, header.msg_type, data.len());
+    Ok(())
+}
+
+/// Receive message via IPC, copying its data into `buffer` (cut to fit);
+/// returns the header and the bytes copied
+pub fn msg_recv(channel_id: u64, buffer: &mut [u8]) -> Result<(MessageHeader, usize), IpcError> {
+    let read = rcu::read_lock();
+    let (header, len) = channel(&read, channel_id)?.recv(buffer)?;
+    crate::trace!(MessageReceive, channel_id, header.receiver, header.msg_type, header.length);
+    Ok((header, len))
+}
+
+/// Look at the next message on a channel without receiving it, copying
+/// its data into `buffer` (cut to fit)
+pub fn msg_peek(channel_id: u64, buffer: &mut [u8]) -> Result<(MessageHeader, usize), IpcError> {
+    let read = rcu::read_lock();
+    channel(&read, channel_id)?.peek(buffer)
+}
+
+/// Process that may receive from an open channel
+pub fn receiver(channel_id: u64) -> Result<u32, IpcError> {
+    let read = rcu::read_lock();
+    channel(&read, channel_id)?;
+    Ok(RECEIVERS[channel_id as usize].load(Ordering::Relaxed))
 }
 
 /// Poll for messages
 pub fn msg_poll(channel_id: u64) -> Result<bool, IpcError> {
-    if channel_id >= MAX_IPC_CHANNELS as u64 {
-        return Err(IpcError::InvalidChannel);
-    }
+    let read = rcu::read_lock();
+    let channel = channel(&read, channel_id)?;
 
-    unsafe {
-        let channel = IPC_CHANNELS[channel_id as usize]
-            .as_ref()
-            .ok_or(IpcError::InvalidChannel)?;
-        
-        let read_idx = channel.read_idx.load(Ordering::Acquire);
-        let write_idx = channel.write_idx.load(Ordering::Acquire);
-        
-        Ok(read_idx != write_idx)
-    }
+    let read_idx = channel.read_idx.load(Ordering::Acquire);
+    let write_idx = channel.write_idx.load(Ordering::Acquire);
+
+    Ok(read_idx != write_idx)
 }
 
 /// IPC errors
@@ -182,6 +254,10 @@ pub enum IpcError {
     InvalidChannel,
     TooManyChannels,
     PermissionDenied,
+    InvalidGrant,
+    TooManyGrants,
+    OutOfMemory,
+    NameInUse,
 }
 
 impl From<crate::capability::CapabilityError> for IpcError {
@@ -189,3 +265,85 @@ impl From<crate::capability::CapabilityError> for IpcError {
         IpcError::PermissionDenied
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spin::Mutex;
+
+    /// A ring of its own, too big for the stack
+    static RING: Mutex<RingBuffer> = Mutex::new(RingBuffer::new());
+
+    fn header(id: u64, length: u32) -> MessageHeader {
+        MessageHeader { id, sender: 1, receiver: 2, length, msg_type: 7 }
+    }
+
+    /// The shared ring, emptied
+    fn ring() -> spin::MutexGuard<'static, RingBuffer> {
+        let mut ring = RING.lock();
+        while ring.recv(&mut []).is_ok() {}
+        ring
+    }
+
+    #[test_case]
+    fn empty_ring() {
+        let mut ring = ring();
+        assert!(matches!(ring.peek(&mut []), Err(IpcError::BufferEmpty)));
+        assert!(matches!(ring.recv(&mut []), Err(IpcError::BufferEmpty)));
+    }
+
+    #[test_case]
+    fn message_round_trip() {
+        let mut ring = ring();
+        let mut data = [0; 8];
+        ring.send(header(5, 4), b"ping").unwrap();
+        let (peeked, len) = ring.peek(&mut data).unwrap();
+        assert_eq!((peeked.id, &data[..len]), (5, &b"ping"[..]));
+        data = [0; 8];
+        let (received, len) = ring.recv(&mut data).unwrap();
+        assert_eq!((received.id, received.sender, received.receiver, received.msg_type), (5, 1, 2, 7));
+        assert_eq!(&data[..len], b"ping");
+        assert!(matches!(ring.recv(&mut data), Err(IpcError::BufferEmpty)));
+    }
+
+    #[test_case]
+    fn short_buffer_cuts_data() {
+        let mut ring = ring();
+        ring.send(header(6, 4), b"pong").unwrap();
+        let mut data = [0; 2];
+        let (received, len) = ring.recv(&mut data).unwrap();
+        assert_eq!((received.length, len, &data), (4, 2, b"po"));
+    }
+
+    #[test_case]
+    fn full_ring_refuses() {
+        let mut ring = ring();
+        // One slot always stays empty
+        for id in 0..RING_BUFFER_SIZE as u64 - 1 {
+            ring.send(header(id, 0), &[]).unwrap();
+        }
+        assert!(matches!(ring.send(header(99, 0), &[]), Err(IpcError::BufferFull)));
+        assert_eq!(ring.recv(&mut []).unwrap().0.id, 0);
+        ring.send(header(99, 0), &[]).unwrap();
+    }
+
+    #[test_case]
+    fn order_kept_across_wrap() {
+        let mut ring = ring();
+        for id in 0..3 * RING_BUFFER_SIZE as u64 {
+            ring.send(header(id, 8), &id.to_le_bytes()).unwrap();
+            let mut data = [0; 8];
+            let (received, len) = ring.recv(&mut data).unwrap();
+            assert_eq!(received.id, id);
+            assert_eq!((len, data), (8, id.to_le_bytes()));
+        }
+    }
+
+    #[test_case]
+    fn oversized_message() {
+        let mut ring = ring();
+        static BIG: [u8; MAX_MESSAGE_SIZE + 1] = [0; MAX_MESSAGE_SIZE + 1];
+        assert!(matches!(ring.send(header(0, 0), &BIG), Err(IpcError::MessageTooLarge)));
+        assert!(matches!(ring.peek(&mut []), Err(IpcError::BufferEmpty)));
+    }
+}
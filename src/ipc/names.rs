@@ -0,0 +1,47 @@
+//! Service names
+//!
+//! Kernel services register their request channel under a well-known name
+//! so clients can find it without the channel number being fixed.
+
+use arrayvec::ArrayString;
+
+use super::IpcError;
+use crate::kernel::rcu::Rcu;
+
+/// Maximum number of registered names
+pub const MAX_NAMES: usize = 32;
+
+/// Longest service name, in bytes
+pub const MAX_NAME_LEN: usize = 32;
+
+/// Looked up far more often than changed, so read without a lock
+static NAMES: Rcu<[Option<(ArrayString<MAX_NAME_LEN>, u64)>; MAX_NAMES]> = Rcu::new([None; MAX_NAMES]);
+
+/// Publish `channel` under `name`
+pub fn register(name: &str, channel: u64) -> Result<(), IpcError> {
+    let key = ArrayString::from(name).map_err(|_| IpcError::InvalidMessage)?;
+    NAMES.update(|names| {
+        if names.iter().flatten().any(|(n, _)| *n == key) {
+            return Err(IpcError::NameInUse);
+        }
+        let slot = names.iter_mut().find(|slot| slot.is_none()).ok_or(IpcError::TooManyChannels)?;
+        *slot = Some((key, channel));
+        Ok(())
+    })
+}
+
+/// Remove a name
+pub fn unregister(name: &str) {
+    NAMES.update(|names| {
+        for slot in names.iter_mut() {
+            if matches!(slot, Some((n, _)) if n.as_str() == name) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+/// Channel registered under `name`
+pub fn lookup(name: &str) -> Option<u64> {
+    NAMES.read().iter().flatten().find(|(n, _)| n.as_str() == name).map(|(_, c)| *c)
+}
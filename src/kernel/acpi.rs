@@ -0,0 +1,200 @@
+//! ACPI tables
+//!
+//! The RSDP is found where the BIOS leaves it, in the first kilobyte of
+//! the EBDA or the read-only area below 1 MiB, and its RSDT or XSDT lists
+//! the other tables. The MADT is read for the local APIC of each
+//! processor and for the I/O APICs, and it, the HPET table and the MCFG
+//! for where device registers are, which memory must not be handed out as
+//! RAM. The FADT and the DSDT it points at say how to turn the machine off
+//! (see [`crate::kernel::power`]), and the MCFG where PCI configuration
+//! space is mapped (see [`crate::kernel::pci`]).
+
+use arrayvec::ArrayVec;
+use x86_64::PhysAddr;
+
+use crate::kernel::memory::phys_to_virt;
+use crate::kernel::percpu::MAX_CPUS;
+
+/// Size of the header every system description table starts with
+const HEADER_SIZE: usize = 36;
+
+/// Device register windows the tables can name
+pub const MAX_WINDOWS: usize = 16;
+
+/// MADT entry: a processor's local APIC
+const MADT_LOCAL_APIC: u8 = 0;
+/// MADT entry: an I/O APIC
+const MADT_IO_APIC: u8 = 1;
+/// MADT entry: the local APIC at a 64-bit address
+const MADT_LOCAL_APIC_OVERRIDE: u8 = 5;
+/// Local APIC flags: the processor is enabled
+const LOCAL_APIC_ENABLED: u32 = 1;
+
+/// `len` bytes of physical memory at `addr`
+fn physical(addr: u64, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(phys_to_virt(PhysAddr::new(addr)).as_ptr(), len) }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap_or_default())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap_or_default())
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Look for the RSDP in `len` bytes at `start`, on 16-byte boundaries
+fn scan(start: u64, len: usize) -> Option<u64> {
+    (start..start + len as u64).step_by(16).find(|addr| {
+        let rsdp = physical(*addr, 20);
+        rsdp.starts_with(b"RSD PTR ") && checksum_ok(rsdp)
+    })
+}
+
+/// Whole table at `addr`, if its checksum holds
+fn table(addr: u64) -> Option<&'static [u8]> {
+    let len = u32_at(physical(addr, HEADER_SIZE), 4) as usize;
+    let table = physical(addr, len.max(HEADER_SIZE));
+    checksum_ok(table).then_some(table)
+}
+
+/// The RSDP's address, its size, and the root table's address and entry
+/// size
+fn root() -> Option<(u64, usize, u64, usize)> {
+    // The BIOS data area holds the EBDA's segment
+    let segment = physical(0x40E, 2);
+    let ebda = u16::from_le_bytes([segment[0], segment[1]]) as u64 * 16;
+    let rsdp = scan(ebda, 1024).or_else(|| scan(0xE_0000, 0x2_0000))?;
+    // Revision 2 adds the XSDT, with 64-bit entries
+    match physical(rsdp, 20)[15] {
+        0 => Some((rsdp, 20, u32_at(physical(rsdp, 20), 16) as u64, 4)),
+        _ => Some((rsdp, 36, u64_at(physical(rsdp, 36), 24), 8)),
+    }
+}
+
+/// Addresses of the tables the root table lists
+fn table_addresses() -> impl Iterator<Item = u64> {
+    let root = root().and_then(|(_, _, root, entry_size)| Some((table(root)?, entry_size)));
+    root.into_iter().flat_map(|(root, entry_size)| {
+        root[HEADER_SIZE..].chunks_exact(entry_size).map(move |entry| match entry_size {
+            4 => u32_at(entry, 0) as u64,
+            _ => u64_at(entry, 0),
+        })
+    })
+}
+
+/// The table with `signature`
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    table_addresses().filter_map(table).find(|table| table.starts_with(signature))
+}
+
+/// A FADT pointer, at `short` in 32 bits and at `long` in 64; the 64-bit
+/// one, where the FADT is long enough to have it, takes over
+fn fadt_pointer(fadt: &[u8], short: usize, long: usize) -> u64 {
+    if fadt.len() >= long + 8 && u64_at(fadt, long) != 0 {
+        u64_at(fadt, long)
+    } else {
+        u32_at(fadt, short) as u64
+    }
+}
+
+/// The DSDT, whose AML describes the devices the tables do not
+pub fn dsdt() -> Option<&'static [u8]> {
+    let fadt = find_table(b"FACP")?;
+    let addr = fadt_pointer(fadt, 40, 140);
+    (addr != 0).then(|| table(addr)).flatten().filter(|dsdt| dsdt.starts_with(b"DSDT"))
+}
+
+/// Physical address and size of the RSDP, the root table, every table it
+/// lists, and the DSDT and FACS the FADT points at
+pub fn table_ranges() -> impl Iterator<Item = (u64, u64)> {
+    let root = root().map(|(rsdp, rsdp_len, root, _)| {
+        let len = table(root).map_or(HEADER_SIZE, <[u8]>::len);
+        [(rsdp, rsdp_len as u64), (root, len as u64)]
+    });
+    let fadt = find_table(b"FACP").map(|fadt| [fadt_pointer(fadt, 36, 132), fadt_pointer(fadt, 40, 140)]);
+    let referenced = fadt.into_iter().flatten().filter(|addr| *addr != 0);
+    root.into_iter()
+        .flatten()
+        .chain(table_addresses().chain(referenced).map(|addr| {
+            // The FACS has a length but no checksum
+            (addr, u32_at(physical(addr, HEADER_SIZE), 4).max(HEADER_SIZE as u32) as u64)
+        }))
+}
+
+/// MADT entries, after the local APIC address and flags
+fn madt_entries(madt: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut entries = madt.get(HEADER_SIZE + 8..).unwrap_or_default();
+    core::iter::from_fn(move || {
+        let [_, len, ..] = *entries else {
+            return None;
+        };
+        let len = (len as usize).clamp(2, entries.len());
+        let (entry, rest) = entries.split_at(len);
+        entries = rest;
+        Some(entry)
+    })
+}
+
+/// Entries of the MADT, none without one
+pub fn madt() -> impl Iterator<Item = &'static [u8]> {
+    find_table(b"APIC").into_iter().flat_map(madt_entries)
+}
+
+/// Local APIC IDs of the enabled processors, as the MADT lists them
+pub fn processors() -> ArrayVec<u32, MAX_CPUS> {
+    let mut ids = ArrayVec::new();
+    for entry in madt() {
+        if entry[0] == MADT_LOCAL_APIC && entry.len() >= 8 && u32_at(entry, 4) & LOCAL_APIC_ENABLED != 0 {
+            let _ = ids.try_push(entry[3] as u32);
+        }
+    }
+    ids
+}
+
+/// Physical address and size of the register windows of the local APIC,
+/// the I/O APICs, the HPET and PCI Express configuration space
+pub fn device_windows() -> ArrayVec<(u64, u64), MAX_WINDOWS> {
+    let mut windows = ArrayVec::new();
+    if let Some(madt) = find_table(b"APIC") {
+        let mut local_apic = u32_at(madt, HEADER_SIZE) as u64;
+        for entry in madt_entries(madt) {
+            match entry[0] {
+                MADT_IO_APIC if entry.len() >= 12 => {
+                    let _ = windows.try_push((u32_at(entry, 4) as u64, 0x1000));
+                }
+                MADT_LOCAL_APIC_OVERRIDE if entry.len() >= 12 => local_apic = u64_at(entry, 4),
+                _ => {}
+            }
+        }
+        let _ = windows.try_push((local_apic, 0x1000));
+    }
+    // The register block's address is in a generic address structure
+    if let Some(hpet) = find_table(b"HPET").filter(|hpet| hpet.len() >= 52) {
+        let _ = windows.try_push((u64_at(hpet, 44), 0x400));
+    }
+    // One entry per segment group, after 8 reserved bytes; a megabyte of
+    // configuration space per bus
+    if let Some(mcfg) = find_table(b"MCFG") {
+        for entry in mcfg.get(HEADER_SIZE + 8..).unwrap_or_default().chunks_exact(16) {
+            // The base is where bus 0 would be, even if the first bus is not
+            let buses = (entry[11] as u64).saturating_sub(entry[10] as u64) + 1;
+            let _ = windows.try_push((u64_at(entry, 0) + ((entry[10] as u64) << 20), buses << 20));
+        }
+    }
+    windows
+}
+
+/// PCI segment group 0's enhanced configuration space from the MCFG: the
+/// address bus 0 would be at, and the first and last bus it covers
+pub fn ecam() -> Option<(u64, u8, u8)> {
+    let mcfg = find_table(b"MCFG")?;
+    mcfg.get(HEADER_SIZE + 8..)?
+        .chunks_exact(16)
+        .find(|entry| entry[8] == 0 && entry[9] == 0)
+        .map(|entry| (u64_at(entry, 0), entry[10], entry[11]))
+}
@@ -2,7 +2,7 @@
 
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
-use spin::Mutex;
+use super::sync::{SpinLockIrq, SpinLockIrqGuard};
 
 /// Heap start address
 pub const HEAP_START: usize = 0x_4444_4444_0000;
@@ -70,19 +70,20 @@ fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
 
-/// Wrapper for spin::Mutex to implement GlobalAlloc
+/// Wrapper for a lock to implement GlobalAlloc; interrupts are off while
+/// it is held, so a handler that allocates cannot spin on its own CPU
 pub struct Locked<A> {
-    inner: Mutex<A>,
+    inner: SpinLockIrq<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: Mutex::new(inner),
+            inner: SpinLockIrq::new("heap", inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> SpinLockIrqGuard<'_, A> {
         self.inner.lock()
     }
 }
@@ -90,9 +91,102 @@ impl<A> Locked<A> {
 #[global_allocator]
 static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
 
-/// Initialize the heap
+/// Bytes of the heap in use and its size
+pub fn usage() -> (usize, usize) {
+    let allocator = ALLOCATOR.lock();
+    (allocator.next - allocator.heap_start, allocator.heap_end - allocator.heap_start)
+}
+
+/// Map the heap and hand it to the allocator
+///
+/// Without frames behind it the heap stays empty, and allocations fail
+/// instead of faulting.
 pub fn init_heap() {
+    let start = x86_64::VirtAddr::new(HEAP_START as u64);
+    if let Err(e) = super::memory::map_range(start, HEAP_SIZE) {
+        crate::klog!(Error, "Heap not mapped: {:?}", e);
+        return;
+    }
     unsafe {
         ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    /// Backing memory for an allocator of its own
+    #[repr(align(4096))]
+    struct Arena([u8; 4096]);
+
+    static mut ARENA: Arena = Arena([0; 4096]);
+
+    /// An allocator over [`ARENA`], so the kernel heap is left alone
+    fn arena_allocator() -> Locked<BumpAllocator> {
+        let allocator = Locked::new(BumpAllocator::new());
+        unsafe { allocator.lock().init(core::ptr::addr_of_mut!(ARENA) as usize, 4096) };
+        allocator
+    }
+
+    // The next three use the kernel heap, which boot has mapped by the
+    // time tests run
+
+    #[test_case]
+    fn boxed_value() {
+        let value = Box::new(41);
+        assert_eq!(*value + 1, 42);
+    }
+
+    #[test_case]
+    fn growing_vec() {
+        let mut vec = Vec::new();
+        for i in 0..1000u64 {
+            vec.push(i);
+        }
+        assert_eq!(vec.iter().sum::<u64>(), 999 * 1000 / 2);
+    }
+
+    #[test_case]
+    fn usage_counts_allocations() {
+        let (used, size) = usage();
+        let block = Box::new([0u8; 256]);
+        assert!(usage().0 >= used + 256);
+        assert_eq!(usage().1, size);
+        drop(block);
+    }
+
+    #[test_case]
+    fn aligned_and_distinct() {
+        let allocator = arena_allocator();
+        let small = Layout::from_size_align(3, 1).unwrap();
+        let aligned = Layout::from_size_align(64, 64).unwrap();
+        unsafe {
+            let first = allocator.alloc(small);
+            let second = allocator.alloc(aligned);
+            assert!(!first.is_null() && !second.is_null());
+            assert!((second as usize).is_multiple_of(64));
+            assert!(second as usize >= first as usize + 3);
+            allocator.dealloc(second, aligned);
+            allocator.dealloc(first, small);
+        }
+    }
+
+    #[test_case]
+    fn exhausted_then_reset() {
+        let allocator = arena_allocator();
+        let half = Layout::from_size_align(2048, 8).unwrap();
+        unsafe {
+            let first = allocator.alloc(half);
+            let second = allocator.alloc(half);
+            assert!(!first.is_null() && !second.is_null());
+            assert!(allocator.alloc(Layout::from_size_align(1, 1).unwrap()).is_null());
+            allocator.dealloc(first, half);
+            allocator.dealloc(second, half);
+            // Once everything is freed the arena starts over
+            assert_eq!(allocator.alloc(half), first);
+        }
+    }
+}
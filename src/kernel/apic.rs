@@ -0,0 +1,149 @@
+//! Local APIC access
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+/// IA32_APIC_BASE model-specific register
+const IA32_APIC_BASE: u32 = 0x1B;
+
+/// Register offsets
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xB0;
+const REG_SVR: usize = 0xF0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_LVT_PERF: usize = 0x340;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3E0;
+
+/// IA32_TSC_DEADLINE model-specific register
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// Timer divide configuration for a divisor of 16
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+/// Interrupt command: delivery still pending
+const ICR_PENDING: u32 = 1 << 12;
+
+/// Interrupt commands that start an application processor: INIT resets
+/// it, and a startup IPI runs it from the page its vector names
+pub const IPI_INIT: u32 = 0x4500;
+pub const IPI_STARTUP: u32 = 0x4600;
+
+/// Local vector table timer modes, or'd with the vector: count down once
+/// from the initial count, or fire when the TSC reaches the deadline MSR;
+/// masked, it counts without interrupting
+pub const TIMER_ONE_SHOT: u32 = 0;
+pub const TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+pub const TIMER_MASKED: u32 = 1 << 16;
+
+/// Local vector table delivery mode for a non-maskable interrupt
+pub const DELIVERY_NMI: u32 = 0b100 << 8;
+
+/// Spurious interrupt vector
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Physical address MSI messages are written to
+pub const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+
+/// Virtual address of the local APIC register window (0 until mapped)
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Map the local APIC and software-enable the current CPU's
+pub fn init() {
+    let base = unsafe { Msr::new(IA32_APIC_BASE).read() } & 0x000F_FFFF_FFFF_F000;
+
+    match crate::kernel::memory::map_mmio(PhysAddr::new(base), 4096) {
+        Ok(virt) => LAPIC_BASE.store(virt.as_u64(), Ordering::Release),
+        Err(e) => {
+            crate::klog!(Error, "Local APIC mapping failed: {:?}", e);
+            return;
+        }
+    }
+    enable();
+}
+
+/// Software-enable the current CPU's local APIC, which every CPU sees at
+/// the same address
+pub fn enable() {
+    // Software enable with the spurious vector
+    write(REG_SVR, read(REG_SVR) | 0x100 | SPURIOUS_VECTOR as u32);
+}
+
+fn read(reg: usize) -> u32 {
+    let base = LAPIC_BASE.load(Ordering::Acquire);
+    if base == 0 {
+        return 0;
+    }
+    unsafe { core::ptr::read_volatile((base as usize + reg) as *const u32) }
+}
+
+fn write(reg: usize, value: u32) {
+    let base = LAPIC_BASE.load(Ordering::Acquire);
+    if base == 0 {
+        return;
+    }
+    unsafe { core::ptr::write_volatile((base as usize + reg) as *mut u32, value) }
+}
+
+/// APIC ID of the current CPU
+pub fn id() -> u32 {
+    read(REG_ID) >> 24
+}
+
+/// Send interrupt command `command` to the CPU with local APIC ID `id`
+pub fn send_ipi(id: u32, command: u32) {
+    write(REG_ICR_HIGH, id << 24);
+    write(REG_ICR_LOW, command);
+    while read(REG_ICR_LOW) & ICR_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Whether the local APIC is mapped
+pub fn present() -> bool {
+    LAPIC_BASE.load(Ordering::Acquire) != 0
+}
+
+/// Start the timer counting down from `count` at the bus clock over 16,
+/// in mode `lvt`
+pub fn set_timer(lvt: u32, count: u32) {
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(REG_LVT_TIMER, lvt);
+    write(REG_TIMER_INITIAL, count);
+}
+
+/// Where the timer's count stands
+pub fn timer_count() -> u32 {
+    read(REG_TIMER_CURRENT)
+}
+
+/// Fire the timer when the TSC reaches `deadline`, in mode `lvt`
+pub fn set_tsc_deadline(lvt: u32, deadline: u64) {
+    write(REG_LVT_TIMER, lvt);
+    // The mode has to be in place before the deadline is written
+    unsafe {
+        core::arch::x86_64::_mm_mfence();
+        Msr::new(IA32_TSC_DEADLINE).write(deadline);
+    }
+}
+
+/// Set what a performance counter overflow raises, as a local vector
+/// table entry
+pub fn set_perf_counter(lvt: u32) {
+    write(REG_LVT_PERF, lvt);
+}
+
+/// Stop the timer in either mode
+pub fn stop_timer() {
+    write(REG_LVT_TIMER, TIMER_MASKED);
+    write(REG_TIMER_INITIAL, 0);
+}
+
+/// Signal end of interrupt for APIC-delivered (MSI/MSI-X) interrupts
+pub fn eoi() {
+    write(REG_EOI, 0);
+}
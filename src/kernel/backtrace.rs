@@ -0,0 +1,171 @@
+//! Panic backtraces
+//!
+//! The kernel is built with frame pointers, so each frame starts with the
+//! caller's frame pointer and its return address, and the chain is walked
+//! from the panicking function up. Return addresses are named from a
+//! symbol table in the `.zen_symbols` section, which the linked kernel
+//! gets from `tools/embed-symbols.sh` before the boot image is made: a
+//! `ZSYM` line, then `<address> <size> <name>` lines in address order, in
+//! hex as `nm` prints them. A kernel that skipped that step prints bare
+//! addresses.
+//!
+//! Everything here runs in the panic handler or the watchdog, so it takes
+//! no lock it would wait for and reads nothing it has not checked is
+//! mapped.
+
+use core::fmt;
+
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::rflags;
+use x86_64::VirtAddr;
+
+/// Bytes reserved for the symbol table
+const SYMBOLS_SIZE: usize = 256 * 1024;
+
+/// First line of a filled-in symbol table
+const MAGIC: &[u8] = b"ZSYM\n";
+
+/// Frames printed at most
+const MAX_FRAMES: usize = 32;
+
+/// How far above the stack pointer frames are trusted when the page
+/// tables cannot be checked
+const UNCHECKED_STACK: u64 = 1024 * 1024;
+
+/// The symbol table, filled in after linking; only the magic is set here,
+/// so an unfilled table reads as empty
+#[used]
+#[link_section = ".zen_symbols"]
+static SYMBOLS: [u8; SYMBOLS_SIZE] = {
+    let mut table = [0; SYMBOLS_SIZE];
+    table[0] = b'Z';
+    table[1] = b'S';
+    table[2] = b'Y';
+    table[3] = b'M';
+    table
+};
+
+/// The function an address falls in, and how far into it
+pub struct Symbol {
+    pub name: &'static str,
+    pub offset: u64,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)
+    }
+}
+
+/// Lines of the symbol table, empty if it was never filled in
+fn table() -> impl Iterator<Item = &'static [u8]> {
+    // The contents change after linking, so they must not be folded in
+    let table: &'static [u8] = core::hint::black_box(&SYMBOLS[..]);
+    let lines = match table.strip_prefix(MAGIC) {
+        Some(rest) => rest,
+        None => &[],
+    };
+    lines.split(|&b| b == b'\n').take_while(|line| !line.is_empty() && line[0] != 0)
+}
+
+fn hex(field: &[u8]) -> Option<u64> {
+    u64::from_str_radix(core::str::from_utf8(field).ok()?, 16).ok()
+}
+
+/// Start, size and name of each function in the symbol table, in
+/// address order
+fn symbols() -> impl Iterator<Item = (u64, u64, &'static str)> {
+    table().filter_map(|line| {
+        let mut fields = line.splitn(3, |&b| b == b' ');
+        let (start, size, name) = (fields.next()?, fields.next()?, fields.next()?);
+        Some((hex(start)?, hex(size)?, core::str::from_utf8(name).unwrap_or("?")))
+    })
+}
+
+/// The symbol `address` falls in
+pub fn resolve(address: u64) -> Option<Symbol> {
+    symbols()
+        .take_while(|(start, ..)| *start <= address)
+        .find(|(start, size, _)| address < start + (*size).max(1))
+        .map(|(start, _, name)| Symbol { name, offset: address - start })
+}
+
+/// Address of the function named `name`, in full or after its last `::`
+pub fn lookup(name: &str) -> Option<u64> {
+    symbols()
+        .find(|(_, _, symbol)| {
+            *symbol == name || symbol.strip_suffix(name).is_some_and(|path| path.ends_with("::"))
+        })
+        .map(|(start, ..)| start)
+}
+
+/// Whether the frame at `frame` can be read
+fn readable(frame: u64, stack_pointer: u64) -> bool {
+    match super::memory::MAPPER.try_lock() {
+        Some(mapper) => mapper.as_ref().is_some_and(|mapper| {
+            use x86_64::structures::paging::Translate;
+            // Both words of the frame, which may straddle a page
+            [frame, frame + 15].iter().all(|&a| mapper.translate_addr(VirtAddr::new(a)).is_some())
+        }),
+        None => frame > stack_pointer && frame - stack_pointer < UNCHECKED_STACK,
+    }
+}
+
+fn print_address(depth: usize, address: u64) {
+    match resolve(address) {
+        Some(symbol) => crate::serial_println!("  {:2}: {:#018x} {}", depth, address, symbol),
+        None => crate::serial_println!("  {:2}: {:#018x}", depth, address),
+    }
+}
+
+/// Print the return addresses from the caller up, named where the symbol
+/// table has them
+#[inline(never)]
+pub fn print() {
+    let (frame, stack_pointer): (u64, u64);
+    unsafe {
+        core::arch::asm!("mov {}, rbp", "mov {}, rsp", out(reg) frame, out(reg) stack_pointer);
+    }
+    crate::serial_println!("Backtrace:");
+    walk(frame, stack_pointer, 0);
+}
+
+/// Print the backtrace of interrupted code, from its instruction pointer
+/// and frame pointer
+pub fn print_from(rip: u64, frame: u64) {
+    crate::serial_println!("Backtrace:");
+    print_address(0, rip);
+    walk(frame, frame.saturating_sub(8), 1);
+}
+
+fn walk(mut frame: u64, stack_pointer: u64, first: usize) {
+    for depth in first..MAX_FRAMES {
+        let valid = frame != 0 && frame.is_multiple_of(8) && VirtAddr::try_new(frame).is_ok();
+        if !valid || !readable(frame, stack_pointer) {
+            break;
+        }
+        let (caller_frame, return_address) = unsafe { (*(frame as *const u64), *((frame + 8) as *const u64)) };
+        if return_address == 0 {
+            break;
+        }
+        print_address(depth, return_address);
+        // Callers' frames lie higher up the stack
+        if caller_frame <= frame {
+            break;
+        }
+        frame = caller_frame;
+    }
+}
+
+/// Print the stack pointer, flags and control registers
+pub fn print_registers() {
+    let (rsp, rbp): (u64, u64);
+    unsafe {
+        core::arch::asm!("mov {}, rsp", "mov {}, rbp", out(reg) rsp, out(reg) rbp);
+    }
+    let (l4, cr3_flags) = Cr3::read();
+    crate::serial_println!("RSP {:#018x} RBP {:#018x} RFLAGS {:?}", rsp, rbp, rflags::read());
+    crate::serial_println!("CR0 {:?}", Cr0::read());
+    crate::serial_println!("CR2 {:#x} CR3 {:?} {:?}", Cr2::read_raw(), l4.start_address(), cr3_flags);
+    crate::serial_println!("CR4 {:?}", Cr4::read());
+}
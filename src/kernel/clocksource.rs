@@ -0,0 +1,274 @@
+//! Clock sources
+//!
+//! Time since boot is read from a counter: the TSC where it is invariant,
+//! the HPET, or, failing both, the PIT tick. Each source has a rating, and
+//! the best one registered is used unless `clocksource=<name>` on the
+//! command line or [`select`] picks another. The time is kept as the
+//! nanoseconds up to the last switch plus the counter's progress since,
+//! taken under a lock whenever it is read and at least once a tick, so it
+//! never goes backwards, across a switch or a counter that wraps.
+//!
+//! The TSC's frequency is measured at boot against the HPET, or against a
+//! one-shot of PIT channel 2 without one.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+/// Clock sources that can be registered
+pub const MAX_SOURCES: usize = 8;
+
+/// PIT input clock
+const PIT_HZ: u64 = 1_193_182;
+
+/// PIT counts per tick at its default divisor
+const PIT_COUNTS_PER_TICK: u64 = 65_536;
+
+/// Time the TSC is measured over, in microseconds
+const CALIBRATION_US: u64 = 10_000;
+
+/// A free-running counter
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Higher is better: finer, cheaper to read and steadier
+    fn rating(&self) -> u32;
+
+    /// Counter value, in the bits of [`ClockSource::mask`]
+    fn read(&self) -> u64;
+
+    /// Counts per second
+    fn frequency(&self) -> u64;
+
+    /// Bits the counter has before it wraps
+    fn mask(&self) -> u64 {
+        u64::MAX
+    }
+}
+
+/// The source in use and where its time stands
+struct Current {
+    source: &'static dyn ClockSource,
+    /// Nanoseconds when it was selected
+    base: u64,
+    /// Counts since then, and the counter value last read
+    counts: u64,
+    last: u64,
+}
+
+impl Current {
+    fn nanoseconds(&mut self) -> u64 {
+        let value = self.source.read();
+        self.counts += value.wrapping_sub(self.last) & self.source.mask();
+        self.last = value;
+        let elapsed = self.counts as u128 * 1_000_000_000 / self.source.frequency().max(1) as u128;
+        self.base + elapsed as u64
+    }
+}
+
+struct Registry {
+    sources: ArrayVec<&'static dyn ClockSource, MAX_SOURCES>,
+    current: Option<Current>,
+    /// Picked on the command line or with `select`, so ratings no longer
+    /// decide
+    pinned: bool,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    sources: ArrayVec::new_const(),
+    current: None,
+    pinned: false,
+});
+
+/// Clock source errors
+#[derive(Debug)]
+pub enum ClockError {
+    TooManySources,
+    /// No source has the name
+    NotFound,
+}
+
+/// The PIT tick, counted in PIT input clocks
+struct Pit;
+
+impl ClockSource for Pit {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn rating(&self) -> u32 {
+        10
+    }
+
+    fn read(&self) -> u64 {
+        crate::scheduler::ticks() * PIT_COUNTS_PER_TICK
+    }
+
+    fn frequency(&self) -> u64 {
+        PIT_HZ
+    }
+}
+
+/// The time stamp counter
+struct Tsc {
+    frequency: AtomicU64,
+    rating: AtomicU64,
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn rating(&self) -> u32 {
+        self.rating.load(Ordering::Relaxed) as u32
+    }
+
+    fn read(&self) -> u64 {
+        tsc()
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency.load(Ordering::Relaxed)
+    }
+}
+
+static PIT: Pit = Pit;
+static TSC: Tsc = Tsc {
+    frequency: AtomicU64::new(0),
+    rating: AtomicU64::new(0),
+};
+
+fn tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Whether the TSC runs at a constant rate through frequency and power
+/// state changes
+fn tsc_invariant() -> bool {
+    crate::kernel::cpu::has(crate::kernel::cpu::Feature::InvariantTsc)
+}
+
+/// TSC counts per second, measured over a one-shot of PIT channel 2
+pub fn pit_calibrate_tsc() -> u64 {
+    let count = (PIT_HZ * CALIBRATION_US / 1_000_000) as u16;
+    let mut gate = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel = Port::<u8>::new(0x42);
+    unsafe {
+        // Gate channel 2 on with the speaker off
+        let value = gate.read();
+        gate.write((value & !0x02) | 0x01);
+        // Channel 2, low byte then high byte, interrupt on terminal count
+        command.write(0b1011_0000);
+        channel.write(count as u8);
+        channel.write((count >> 8) as u8);
+        let start = tsc();
+        // The output, bit 5, goes high at terminal count; give up on a PIT
+        // that never gets there
+        while gate.read() & 0x20 == 0 && tsc() - start < 1 << 36 {}
+        (tsc() - start) * (1_000_000 / CALIBRATION_US)
+    }
+}
+
+/// TSC counts per second, measured against `source`
+fn calibrate_tsc(source: &dyn ClockSource) -> u64 {
+    let window = source.frequency() * CALIBRATION_US / 1_000_000;
+    let (start, start_tsc) = (source.read(), tsc());
+    let mut elapsed = 0;
+    while elapsed < window {
+        elapsed = source.read().wrapping_sub(start) & source.mask();
+    }
+    let cycles = (tsc() - start_tsc) as u128;
+    (cycles * source.frequency() as u128 / elapsed.max(1) as u128) as u64
+}
+
+/// Switch to `source`, carrying the time over
+fn switch(registry: &mut Registry, source: &'static dyn ClockSource) {
+    let base = registry.current.as_mut().map_or(0, Current::nanoseconds);
+    registry.current = Some(Current { source, base, counts: 0, last: source.read() });
+}
+
+/// Add a clock source, switching to it if it is wanted
+pub fn register(source: &'static dyn ClockSource) -> Result<(), ClockError> {
+    interrupts::without_interrupts(|| {
+        let mut registry = REGISTRY.lock();
+        registry.sources.try_push(source).map_err(|_| ClockError::TooManySources)?;
+        let wanted = crate::boot::cmdline::get("clocksource");
+        let better = registry.current.as_ref().is_none_or(|c| source.rating() > c.source.rating());
+        if wanted == Some(source.name()) {
+            registry.pinned = true;
+            switch(&mut registry, source);
+        } else if better && !registry.pinned {
+            switch(&mut registry, source);
+        }
+        Ok(())
+    })
+}
+
+/// Switch to the source called `name`
+pub fn select(name: &str) -> Result<(), ClockError> {
+    interrupts::without_interrupts(|| {
+        let mut registry = REGISTRY.lock();
+        let source = *registry.sources.iter().find(|s| s.name() == name).ok_or(ClockError::NotFound)?;
+        registry.pinned = true;
+        switch(&mut registry, source);
+        Ok(())
+    })
+}
+
+/// Name of the source in use
+pub fn current() -> Option<&'static str> {
+    REGISTRY.lock().current.as_ref().map(|c| c.source.name())
+}
+
+/// Every source registered, with its name and rating
+pub fn sources() -> ArrayVec<(&'static str, u32), MAX_SOURCES> {
+    REGISTRY.lock().sources.iter().map(|s| (s.name(), s.rating())).collect()
+}
+
+/// Nanoseconds since the first source was registered
+pub fn nanoseconds() -> u64 {
+    interrupts::without_interrupts(|| REGISTRY.lock().current.as_mut().map_or(0, Current::nanoseconds))
+}
+
+/// Keep up with a counter that would wrap between reads; called every tick
+pub fn tick() {
+    nanoseconds();
+}
+
+/// TSC counts per second as measured at boot, if the TSC keeps a constant
+/// rate
+pub fn tsc_frequency() -> Option<u64> {
+    let frequency = TSC.frequency.load(Ordering::Relaxed);
+    (frequency != 0 && tsc_invariant()).then_some(frequency)
+}
+
+/// Register the PIT, the HPET and the TSC
+pub fn init() {
+    if let Err(e) = register(&PIT) {
+        crate::klog!(Warn, "PIT clock source not registered: {:?}", e);
+    }
+    let hpet = match super::hpet::init() {
+        Ok(hpet) => {
+            let _ = register(hpet);
+            Some(hpet)
+        }
+        Err(e) => {
+            crate::klog!(Info, "No HPET: {:?}", e);
+            None
+        }
+    };
+    let frequency = match hpet {
+        Some(hpet) => calibrate_tsc(hpet),
+        None => pit_calibrate_tsc(),
+    };
+    TSC.frequency.store(frequency, Ordering::Relaxed);
+    // A TSC that changes speed with the CPU is worse than the PIT
+    TSC.rating.store(if tsc_invariant() { 300 } else { 5 }, Ordering::Relaxed);
+    let _ = register(&TSC);
+    crate::klog!(Info, "Clock source: {} (TSC at {} MHz)", current().unwrap_or("none"), frequency / 1_000_000);
+}
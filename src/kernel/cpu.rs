@@ -0,0 +1,281 @@
+//! CPU features
+//!
+//! CPUID is read once, the first time a feature is asked about, into a
+//! set the rest of the kernel checks instead of decoding leaves itself:
+//! the vector code picks its kernels by it, security and timer features
+//! are only turned on where it has them, and page table flags the
+//! processor would fault on are left out. `cpu.disable=` on the command
+//! line takes features out of the set, so fallbacks can be tried on
+//! hardware that would not otherwise need them. Every processor is assumed
+//! to have what the bootstrap processor has.
+//!
+//! SMEP, SMAP and UMIP are turned on where the set has them, so the kernel
+//! can neither run code from user pages nor touch user memory outside
+//! [`crate::userspace::uaccess`], and user mode cannot read descriptor
+//! table addresses. Application processors start with the bootstrap
+//! processor's CR4, so they are protected from their first instruction.
+
+use core::arch::x86_64::{CpuidResult, __cpuid, __cpuid_count, __get_cpuid_max};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Once;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+
+/// Something the processor can do
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Feature {
+    Sse,
+    Sse2,
+    Sse3,
+    Ssse3,
+    Sse41,
+    Sse42,
+    Fma,
+    Xsave,
+    Avx,
+    Avx2,
+    /// AVX-512 Foundation
+    Avx512,
+    X2Apic,
+    /// The local APIC timer can fire at a TSC value
+    TscDeadline,
+    Rdrand,
+    Rdseed,
+    /// Supervisor mode execution and access prevention
+    Smep,
+    Smap,
+    /// User mode instruction prevention
+    Umip,
+    /// The TSC runs at a constant rate through power state changes
+    InvariantTsc,
+    /// 1 GiB pages
+    Gigapages,
+    NoExecute,
+    /// Process context identifiers
+    Pcid,
+    /// Architectural performance monitoring (CPUID leaf 0xA)
+    ArchPerfmon,
+    /// Machine check exception
+    Mce,
+    /// Machine check architecture: the banks
+    Mca,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 25] = [
+        Feature::Sse,
+        Feature::Sse2,
+        Feature::Sse3,
+        Feature::Ssse3,
+        Feature::Sse41,
+        Feature::Sse42,
+        Feature::Fma,
+        Feature::Xsave,
+        Feature::Avx,
+        Feature::Avx2,
+        Feature::Avx512,
+        Feature::X2Apic,
+        Feature::TscDeadline,
+        Feature::Rdrand,
+        Feature::Rdseed,
+        Feature::Smep,
+        Feature::Smap,
+        Feature::Umip,
+        Feature::InvariantTsc,
+        Feature::Gigapages,
+        Feature::NoExecute,
+        Feature::Pcid,
+        Feature::ArchPerfmon,
+        Feature::Mce,
+        Feature::Mca,
+    ];
+
+    /// Name as `/proc/cpuinfo` spells it
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Sse => "sse",
+            Feature::Sse2 => "sse2",
+            Feature::Sse3 => "pni",
+            Feature::Ssse3 => "ssse3",
+            Feature::Sse41 => "sse4_1",
+            Feature::Sse42 => "sse4_2",
+            Feature::Fma => "fma",
+            Feature::Xsave => "xsave",
+            Feature::Avx => "avx",
+            Feature::Avx2 => "avx2",
+            Feature::Avx512 => "avx512f",
+            Feature::X2Apic => "x2apic",
+            Feature::TscDeadline => "tsc_deadline_timer",
+            Feature::Rdrand => "rdrand",
+            Feature::Rdseed => "rdseed",
+            Feature::Smep => "smep",
+            Feature::Smap => "smap",
+            Feature::Umip => "umip",
+            Feature::InvariantTsc => "constant_tsc",
+            Feature::Gigapages => "pdpe1gb",
+            Feature::NoExecute => "nx",
+            Feature::Pcid => "pcid",
+            Feature::ArchPerfmon => "arch_perfmon",
+            Feature::Mce => "mce",
+            Feature::Mca => "mca",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Feature> {
+        Feature::ALL.into_iter().find(|feature| feature.name() == name)
+    }
+}
+
+/// A set of features
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Features(u64);
+
+impl Features {
+    pub fn has(&self, feature: Feature) -> bool {
+        self.0 & 1 << feature as u8 != 0
+    }
+
+    fn set(&mut self, feature: Feature, present: bool) {
+        if present {
+            self.0 |= 1 << feature as u8;
+        } else {
+            self.0 &= !(1 << feature as u8);
+        }
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names = Feature::ALL.into_iter().filter(|feature| self.has(*feature)).map(Feature::name);
+        if let Some(first) = names.next() {
+            f.write_str(first)?;
+        }
+        names.try_for_each(|name| write!(f, " {}", name))
+    }
+}
+
+static FEATURES: Once<Features> = Once::new();
+
+/// Whether SMAP is on, and user memory has to be opened to be touched
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+/// Protections turned on
+#[derive(Clone, Copy, Debug)]
+pub struct Protections {
+    pub smep: bool,
+    pub smap: bool,
+    pub umip: bool,
+}
+
+/// A leaf, or zeros if the processor does not have it
+fn leaf(leaf: u32, max: u32) -> CpuidResult {
+    if leaf <= max {
+        __cpuid_count(leaf, 0)
+    } else {
+        CpuidResult { eax: 0, ebx: 0, ecx: 0, edx: 0 }
+    }
+}
+
+fn detect() -> Features {
+    let max = __get_cpuid_max(0).0;
+    let extended = __get_cpuid_max(0x8000_0000).0;
+    let basic = leaf(1, max);
+    let structured = leaf(7, max);
+    let extended_info = leaf(0x8000_0001, extended);
+    let power = leaf(0x8000_0007, extended);
+    let perfmon = leaf(0xA, max);
+
+    let bits = [
+        (Feature::Mce, basic.edx, 7),
+        (Feature::Mca, basic.edx, 14),
+        (Feature::Sse, basic.edx, 25),
+        (Feature::Sse2, basic.edx, 26),
+        (Feature::Sse3, basic.ecx, 0),
+        (Feature::Ssse3, basic.ecx, 9),
+        (Feature::Fma, basic.ecx, 12),
+        (Feature::Pcid, basic.ecx, 17),
+        (Feature::Sse41, basic.ecx, 19),
+        (Feature::Sse42, basic.ecx, 20),
+        (Feature::X2Apic, basic.ecx, 21),
+        (Feature::TscDeadline, basic.ecx, 24),
+        (Feature::Xsave, basic.ecx, 26),
+        (Feature::Avx, basic.ecx, 28),
+        (Feature::Rdrand, basic.ecx, 30),
+        (Feature::Avx2, structured.ebx, 5),
+        (Feature::Smep, structured.ebx, 7),
+        (Feature::Avx512, structured.ebx, 16),
+        (Feature::Rdseed, structured.ebx, 18),
+        (Feature::Smap, structured.ebx, 20),
+        (Feature::Umip, structured.ecx, 2),
+        (Feature::NoExecute, extended_info.edx, 20),
+        (Feature::Gigapages, extended_info.edx, 26),
+        (Feature::InvariantTsc, power.edx, 8),
+    ];
+    let mut features = Features::default();
+    for (feature, register, bit) in bits {
+        features.set(feature, register & 1 << bit != 0);
+    }
+    // A version rather than a bit
+    features.set(Feature::ArchPerfmon, perfmon.eax & 0xFF != 0);
+
+    // Features turned off on the command line
+    for name in crate::boot::cmdline::get("cpu.disable").unwrap_or_default().split(',') {
+        match Feature::parse(name) {
+            Some(feature) => features.set(feature, false),
+            None if name.is_empty() => {}
+            None => crate::klog!(Warn, "cpu.disable: no feature {}", name),
+        }
+    }
+    features
+}
+
+/// Every feature the processor has, less those disabled
+pub fn features() -> Features {
+    *FEATURES.call_once(detect)
+}
+
+pub fn has(feature: Feature) -> bool {
+    features().has(feature)
+}
+
+/// The vendor string, "GenuineIntel" or "AuthenticAMD" on most machines
+pub fn vendor() -> [u8; 12] {
+    let leaf = __cpuid(0);
+    let mut vendor = [0; 12];
+    vendor[..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&leaf.edx.to_le_bytes());
+    vendor[8..].copy_from_slice(&leaf.ecx.to_le_bytes());
+    vendor
+}
+
+/// Whether the kernel must open user memory before touching it
+pub fn smap_enabled() -> bool {
+    SMAP.load(Ordering::Relaxed)
+}
+
+/// Turn on SMEP, SMAP and UMIP where the processor has them
+fn protect() -> Protections {
+    let protections = Protections {
+        smep: has(Feature::Smep),
+        smap: has(Feature::Smap),
+        umip: has(Feature::Umip),
+    };
+    let mut flags = Cr4Flags::empty();
+    flags.set(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION, protections.smep);
+    flags.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, protections.smap);
+    flags.set(Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION, protections.umip);
+    unsafe { Cr4::update(|cr4| cr4.insert(flags)) };
+    SMAP.store(protections.smap, Ordering::Relaxed);
+    protections
+}
+
+/// Read the features, logging them, and turn on the protections they allow
+pub fn init() -> Features {
+    let features = features();
+    let vendor = vendor();
+    crate::klog!(Info, "CPU {}: {}", core::str::from_utf8(&vendor).unwrap_or("?"), features);
+    crate::klog!(Info, "Protections: {:?}", protect());
+    features
+}
@@ -0,0 +1,245 @@
+//! DMA mapping layer
+//!
+//! Drivers get bus addresses from [`map`] rather than translating buffers
+//! themselves. The direct backend hands out physical addresses and asks for
+//! a bounce buffer when a buffer is not physically contiguous or lies above
+//! the device's address limit. An IOMMU (VT-d) backend can be installed with
+//! [`set_backend`] and remap such buffers instead, without driver changes.
+
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::kernel::memory;
+use crate::kernel::regions::{self, RegionKind};
+
+/// Size of one bounce slot
+pub const BOUNCE_SLOT_SIZE: usize = 4096;
+
+/// Number of bounce slots (one bit each in the pool bitmap)
+pub const BOUNCE_SLOTS: usize = 64;
+
+/// Bounce buffers must be reachable by 32-bit devices
+const BOUNCE_POOL_LIMIT: u64 = 1 << 32;
+
+/// Devices limited to 32-bit bus addresses
+pub const DMA_32BIT: DmaConstraints = DmaConstraints { addr_limit: 0xFFFF_FFFF };
+
+/// Devices that can reach all of memory
+pub const DMA_64BIT: DmaConstraints = DmaConstraints { addr_limit: u64::MAX };
+
+/// Direction of a transfer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DmaDirection {
+    ToDevice,
+    FromDevice,
+    Bidirectional,
+}
+
+/// Addressing capabilities of a device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DmaConstraints {
+    /// Highest bus address the device can generate
+    pub addr_limit: u64,
+}
+
+/// Translates kernel buffers into device-visible bus addresses
+pub trait DmaBackend: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Map a buffer, returning its bus address
+    ///
+    /// Returns [`DmaError::NeedsBounce`] if the buffer cannot be made
+    /// visible to the device as one contiguous range.
+    fn map(&self, buffer: VirtAddr, len: usize, constraints: DmaConstraints) -> Result<u64, DmaError>;
+
+    /// Release a bus address range returned by `map`
+    fn unmap(&self, bus_addr: u64, len: usize);
+}
+
+/// Identity backend used when no IOMMU is present: bus address = physical address
+pub struct DirectBackend;
+
+impl DmaBackend for DirectBackend {
+    fn name(&self) -> &'static str {
+        "direct"
+    }
+
+    fn map(&self, buffer: VirtAddr, len: usize, constraints: DmaConstraints) -> Result<u64, DmaError> {
+        let start = memory::virt_to_phys(buffer).ok_or(DmaError::NotMapped)?.as_u64();
+
+        // Every page after the first must follow its predecessor physically
+        let mut page = buffer.align_down(4096u64) + 4096u64;
+        while page < buffer + len as u64 {
+            let phys = memory::virt_to_phys(page).ok_or(DmaError::NotMapped)?.as_u64();
+            if phys != start + (page - buffer) {
+                return Err(DmaError::NeedsBounce);
+            }
+            page += 4096u64;
+        }
+        if regions::kind(start, len as u64).is_some_and(RegionKind::firmware_owned) {
+            return Err(DmaError::FirmwareMemory);
+        }
+
+        if start + len as u64 - 1 > constraints.addr_limit {
+            return Err(DmaError::NeedsBounce);
+        }
+        Ok(start)
+    }
+
+    fn unmap(&self, _bus_addr: u64, _len: usize) {}
+}
+
+/// A buffer made visible to a device
+#[derive(Debug)]
+pub struct DmaMapping {
+    /// Address to program into the device
+    pub bus_addr: u64,
+    pub len: usize,
+    direction: DmaDirection,
+    /// Caller's buffer
+    buffer: VirtAddr,
+    /// First bounce slot and slot count, if the transfer is bounced
+    bounce: Option<(usize, usize)>,
+}
+
+impl DmaMapping {
+    /// Whether the transfer goes through a bounce buffer
+    pub fn is_bounced(&self) -> bool {
+        self.bounce.is_some()
+    }
+}
+
+/// Bounce buffer pool
+struct BouncePool {
+    phys: PhysAddr,
+    /// Allocated slots, one bit per slot
+    used: u64,
+}
+
+impl BouncePool {
+    fn alloc(&mut self, slots: usize) -> Option<usize> {
+        let mask = if slots == BOUNCE_SLOTS { u64::MAX } else { (1u64 << slots) - 1 };
+        (0..=BOUNCE_SLOTS - slots).find(|first| self.used & (mask << first) == 0).inspect(|first| {
+            self.used |= mask << first;
+        })
+    }
+
+    fn free(&mut self, first: usize, slots: usize) {
+        let mask = if slots == BOUNCE_SLOTS { u64::MAX } else { (1u64 << slots) - 1 };
+        self.used &= !(mask << first);
+    }
+
+    fn slot_virt(&self, first: usize) -> VirtAddr {
+        memory::phys_to_virt(self.phys + (first * BOUNCE_SLOT_SIZE) as u64)
+    }
+}
+
+static DIRECT: DirectBackend = DirectBackend;
+
+static BACKEND: Mutex<&'static dyn DmaBackend> = Mutex::new(&DIRECT);
+
+static BOUNCE_POOL: Mutex<Option<BouncePool>> = Mutex::new(None);
+
+/// Reserve the bounce pool in low memory
+pub fn init() {
+    let pages = (BOUNCE_SLOTS * BOUNCE_SLOT_SIZE / 4096) as u64;
+    match memory::allocate_contiguous(pages, BOUNCE_POOL_LIMIT) {
+        Ok(phys) => *BOUNCE_POOL.lock() = Some(BouncePool { phys, used: 0 }),
+        Err(e) => crate::klog!(Error, "DMA bounce pool allocation failed: {:?}", e),
+    }
+}
+
+/// Install a different translation backend (e.g. an IOMMU driver)
+pub fn set_backend(backend: &'static dyn DmaBackend) {
+    crate::klog!(Info, "DMA backend: {}", backend.name());
+    *BACKEND.lock() = backend;
+}
+
+/// Make a buffer visible to a device
+///
+/// # Safety
+/// `buffer..buffer + len` must stay valid, and must not be accessed by the
+/// CPU, until the mapping is passed to [`unmap`].
+pub unsafe fn map(
+    buffer: VirtAddr,
+    len: usize,
+    direction: DmaDirection,
+    constraints: DmaConstraints,
+) -> Result<DmaMapping, DmaError> {
+    if len == 0 {
+        return Err(DmaError::InvalidLength);
+    }
+
+    let backend = *BACKEND.lock();
+    match backend.map(buffer, len, constraints) {
+        Ok(bus_addr) => {
+            return Ok(DmaMapping { bus_addr, len, direction, buffer, bounce: None });
+        }
+        Err(DmaError::NeedsBounce) => {}
+        Err(e) => return Err(e),
+    }
+
+    let slots = len.div_ceil(BOUNCE_SLOT_SIZE);
+    if slots > BOUNCE_SLOTS {
+        return Err(DmaError::TooLarge);
+    }
+
+    let mut pool = BOUNCE_POOL.lock();
+    let pool = pool.as_mut().ok_or(DmaError::NoBouncePool)?;
+    let first = pool.alloc(slots).ok_or(DmaError::BounceExhausted)?;
+    let bounce = pool.slot_virt(first);
+
+    if direction != DmaDirection::FromDevice {
+        core::ptr::copy_nonoverlapping(buffer.as_ptr::<u8>(), bounce.as_mut_ptr::<u8>(), len);
+    }
+
+    match backend.map(bounce, len, constraints) {
+        Ok(bus_addr) => Ok(DmaMapping {
+            bus_addr,
+            len,
+            direction,
+            buffer,
+            bounce: Some((first, slots)),
+        }),
+        Err(e) => {
+            pool.free(first, slots);
+            Err(e)
+        }
+    }
+}
+
+/// Tear down a mapping once the device is done with it
+///
+/// Bounced device-to-memory transfers are copied back into the caller's buffer.
+pub fn unmap(mapping: DmaMapping) {
+    BACKEND.lock().unmap(mapping.bus_addr, mapping.len);
+
+    if let Some((first, slots)) = mapping.bounce {
+        let mut pool = BOUNCE_POOL.lock();
+        if let Some(pool) = pool.as_mut() {
+            if mapping.direction != DmaDirection::ToDevice {
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        pool.slot_virt(first).as_ptr::<u8>(),
+                        mapping.buffer.as_mut_ptr::<u8>(),
+                        mapping.len,
+                    );
+                }
+            }
+            pool.free(first, slots);
+        }
+    }
+}
+
+/// DMA mapping errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    NotMapped,
+    NeedsBounce,
+    NoBouncePool,
+    BounceExhausted,
+    TooLarge,
+    InvalidLength,
+    /// The buffer is in memory firmware or a device owns
+    FirmwareMemory,
+}
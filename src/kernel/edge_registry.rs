@@ -1,8 +1,23 @@
 //! Global edge-case registry (12-byte packed structs)
+//!
+//! Registering takes no lock, so it works from any context, NMIs
+//! included; an entry being written as the registry is read may show as
+//! zero.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 /// Maximum number of edge cases that can be registered
 pub const MAX_EDGE_CASES: usize = 1024;
 
+/// Source files that register edge cases
+pub const FILE_NMI: u16 = 1;
+
+/// Edge case codes
+pub const NMI_SERR: u32 = 0x100;
+pub const NMI_IOCHK: u32 = 0x101;
+pub const NMI_UNKNOWN: u32 = 0x102;
+
 /// Edge case entry (12 bytes packed)
 #[repr(C, packed)]
 #[derive(Clone, Copy)]
@@ -31,44 +46,48 @@ impl EdgeCase {
 
 /// Global edge case registry
 pub struct EdgeRegistry {
-    entries: [EdgeCase; MAX_EDGE_CASES],
-    count: usize,
+    entries: UnsafeCell<[EdgeCase; MAX_EDGE_CASES]>,
+    /// Entries claimed, which may run past the end
+    count: AtomicUsize,
 }
 
 impl EdgeRegistry {
     /// Create a new empty registry
     pub const fn new() -> Self {
         Self {
-            entries: [EdgeCase::new(0, 0, 0, 0); MAX_EDGE_CASES],
-            count: 0,
+            entries: UnsafeCell::new([EdgeCase::new(0, 0, 0, 0); MAX_EDGE_CASES]),
+            count: AtomicUsize::new(0),
         }
     }
 
     /// Register a new edge case
-    pub fn register(&mut self, code: u32, file_id: u16, line: u16, timestamp: u32) {
-        if self.count < MAX_EDGE_CASES {
-            self.entries[self.count] = EdgeCase::new(code, file_id, line, timestamp);
-            self.count += 1;
+    pub fn register(&self, code: u32, file_id: u16, line: u16, timestamp: u32) {
+        let index = self.count.fetch_add(1, Ordering::AcqRel);
+        if index < MAX_EDGE_CASES {
+            let entry = unsafe { (self.entries.get() as *mut EdgeCase).add(index) };
+            unsafe { entry.write_volatile(EdgeCase::new(code, file_id, line, timestamp)) };
         }
     }
 
     /// Get all registered edge cases
     pub fn entries(&self) -> &[EdgeCase] {
-        &self.entries[..self.count]
+        let count = self.count.load(Ordering::Acquire).min(MAX_EDGE_CASES);
+        unsafe { core::slice::from_raw_parts(self.entries.get() as *const EdgeCase, count) }
     }
 }
 
+/// Each entry is written once, by whoever claimed it
+unsafe impl Sync for EdgeRegistry {}
+
 /// Global edge case registry instance
-static mut EDGE_REGISTRY: EdgeRegistry = EdgeRegistry::new();
+static EDGE_REGISTRY: EdgeRegistry = EdgeRegistry::new();
 
 /// Register an edge case globally
 pub fn register_edge_case(code: u32, file_id: u16, line: u16, timestamp: u32) {
-    unsafe {
-        EDGE_REGISTRY.register(code, file_id, line, timestamp);
-    }
+    EDGE_REGISTRY.register(code, file_id, line, timestamp);
 }
 
 /// Get all registered edge cases
 pub fn get_edge_cases() -> &'static [EdgeCase] {
-    unsafe { EDGE_REGISTRY.entries() }
+    EDGE_REGISTRY.entries()
 }
@@ -0,0 +1,121 @@
+//! Segmentation: GDT and TSS
+//!
+//! Long mode barely uses segments, but privilege levels live in them: the
+//! GDT holds kernel and user code and data segments, and the TSS names
+//! the kernel stack the CPU switches to when an interrupt or exception
+//! arrives while user code runs, and the stack double faults are always
+//! handled on. A double fault is most often the kernel running out of
+//! stack, and the faulting stack is no place to report that from. The
+//! stack for ring 3 entries has a guard page below it (see
+//! [`memory::allocate_stack`]).
+//!
+//! User data comes right before user code so the layout also suits
+//! `sysret`.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+use super::memory;
+
+/// Size of the stack used on entry from user mode
+pub const PRIVILEGE_STACK_SIZE: usize = 64 * 1024;
+
+/// Interrupt stack table entry double faults switch to
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+/// Size of the double fault stack, which the panic handler runs on too
+const DOUBLE_FAULT_STACK_SIZE: usize = 32 * 1024;
+
+#[repr(C, align(16))]
+struct Stack<const N: usize>([u8; N]);
+
+static mut DOUBLE_FAULT_STACK: Stack<DOUBLE_FAULT_STACK_SIZE> = Stack([0; DOUBLE_FAULT_STACK_SIZE]);
+
+/// Stack the CPU switches to on entry from ring 3, should the guarded one
+/// not be allocated
+static mut FALLBACK_STACK: Stack<PRIVILEGE_STACK_SIZE> = Stack([0; PRIVILEGE_STACK_SIZE]);
+
+/// Top of the stack the CPU switches to on entry from ring 3
+static PRIVILEGE_STACK_TOP: AtomicU64 = AtomicU64::new(0);
+
+/// Segment selectors, with the RPL of their ring
+#[derive(Clone, Copy, Debug)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+lazy_static! {
+    static ref TSS: TaskStateSegment = {
+        let mut tss = TaskStateSegment::new();
+        tss.privilege_stack_table[0] = privilege_stack_top();
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            VirtAddr::from_ptr(&raw const DOUBLE_FAULT_STACK) + DOUBLE_FAULT_STACK_SIZE as u64;
+        tss
+    };
+
+    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        let mut gdt = GlobalDescriptorTable::new();
+        let selectors = Selectors {
+            kernel_code: gdt.append(Descriptor::kernel_code_segment()),
+            kernel_data: gdt.append(Descriptor::kernel_data_segment()),
+            user_data: gdt.append(Descriptor::user_data_segment()),
+            user_code: gdt.append(Descriptor::user_code_segment()),
+            tss: gdt.append(Descriptor::tss_segment(&TSS)),
+        };
+        (gdt, selectors)
+    };
+}
+
+/// Load the GDT and TSS and reload the segment registers
+pub fn init() {
+    match memory::allocate_stack(PRIVILEGE_STACK_SIZE, "ring 3 entry") {
+        Ok(top) => PRIVILEGE_STACK_TOP.store(top.as_u64(), Ordering::Relaxed),
+        Err(e) => crate::klog!(Warn, "Ring 3 entry stack has no guard page: {:?}", e),
+    }
+    let (gdt, selectors) = &*GDT;
+    gdt.load();
+    unsafe {
+        CS::set_reg(selectors.kernel_code);
+        SS::set_reg(selectors.kernel_data);
+        DS::set_reg(selectors.kernel_data);
+        ES::set_reg(selectors.kernel_data);
+        load_tss(selectors.tss);
+    }
+}
+
+/// Load the GDT and reload the segment registers on an application
+/// processor, which never enters user mode and so has no TSS, nor a
+/// stack of its own for double faults
+pub fn init_ap() {
+    let (gdt, selectors) = &*GDT;
+    gdt.load();
+    unsafe {
+        CS::set_reg(selectors.kernel_code);
+        SS::set_reg(selectors.kernel_data);
+        DS::set_reg(selectors.kernel_data);
+        ES::set_reg(selectors.kernel_data);
+    }
+}
+
+/// Top of the ring 0 stack used on entry from user mode, 16-byte aligned
+pub fn privilege_stack_top() -> VirtAddr {
+    match PRIVILEGE_STACK_TOP.load(Ordering::Relaxed) {
+        0 => VirtAddr::from_ptr(&raw const FALLBACK_STACK) + PRIVILEGE_STACK_SIZE as u64,
+        top => VirtAddr::new(top),
+    }
+}
+
+/// Segment selectors of the loaded GDT
+pub fn selectors() -> Selectors {
+    GDT.1
+}
@@ -0,0 +1,104 @@
+//! High Precision Event Timer
+//!
+//! The HPET table gives the register block's address. The main counter
+//! runs at the period the capabilities register gives in femtoseconds,
+//! usually around 14.3 MHz, and is 64 bits wide on most chipsets; on the
+//! rest it is 32 bits and wraps every few minutes, which the clocksource
+//! framework allows for. Only the main counter is used, as a clock source;
+//! the comparators are left alone.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::PhysAddr;
+
+use super::clocksource::ClockSource;
+
+/// Registers
+const REG_CAPABILITIES: u64 = 0x000;
+const REG_CONFIG: u64 = 0x010;
+const REG_COUNTER: u64 = 0x0F0;
+
+/// Capabilities: the main counter is 64 bits wide
+const CAP_64BIT: u64 = 1 << 13;
+/// Configuration: the main counter runs
+const CONFIG_ENABLE: u64 = 1;
+
+/// Femtoseconds in a second
+const FS_PER_SECOND: u128 = 1_000_000_000_000_000;
+
+/// The HPET as a clock source
+pub struct Hpet {
+    /// Virtual address of the register block
+    base: AtomicU64,
+    frequency: AtomicU64,
+    mask: AtomicU64,
+}
+
+static HPET: Hpet = Hpet {
+    base: AtomicU64::new(0),
+    frequency: AtomicU64::new(0),
+    mask: AtomicU64::new(0),
+};
+
+/// HPET errors
+#[derive(Debug)]
+pub enum HpetError {
+    /// No HPET table
+    NotFound,
+    MapFailed,
+    /// A counter period of 0, or one longer than the specification allows
+    BadPeriod,
+}
+
+impl Hpet {
+    fn read_reg(&self, reg: u64) -> u64 {
+        unsafe { core::ptr::read_volatile((self.base.load(Ordering::Relaxed) + reg) as *const u64) }
+    }
+
+    fn write_reg(&self, reg: u64, value: u64) {
+        unsafe { core::ptr::write_volatile((self.base.load(Ordering::Relaxed) + reg) as *mut u64, value) }
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        250
+    }
+
+    fn read(&self) -> u64 {
+        self.read_reg(REG_COUNTER) & self.mask()
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency.load(Ordering::Relaxed)
+    }
+
+    fn mask(&self) -> u64 {
+        self.mask.load(Ordering::Relaxed)
+    }
+}
+
+/// Map the HPET and start its main counter
+pub fn init() -> Result<&'static Hpet, HpetError> {
+    let table = super::acpi::find_table(b"HPET").filter(|table| table.len() >= 52).ok_or(HpetError::NotFound)?;
+    // The register block's address is in a generic address structure
+    let addr = u64::from_le_bytes(table[44..52].try_into().unwrap_or_default());
+    let base = super::memory::map_mmio(PhysAddr::new(addr), 0x400).map_err(|_| HpetError::MapFailed)?;
+    HPET.base.store(base.as_u64(), Ordering::Relaxed);
+
+    let capabilities = HPET.read_reg(REG_CAPABILITIES);
+    // At most 100 ns
+    let period = capabilities >> 32;
+    if period == 0 || period > 100_000_000 {
+        return Err(HpetError::BadPeriod);
+    }
+    HPET.frequency.store((FS_PER_SECOND / period as u128) as u64, Ordering::Relaxed);
+    let mask = if capabilities & CAP_64BIT != 0 { u64::MAX } else { u32::MAX as u64 };
+    HPET.mask.store(mask, Ordering::Relaxed);
+    HPET.write_reg(REG_CONFIG, HPET.read_reg(REG_CONFIG) | CONFIG_ENABLE);
+    Ok(&HPET)
+}
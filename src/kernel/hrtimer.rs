@@ -0,0 +1,219 @@
+//! High-resolution timers
+//!
+//! One-shot timers with deadlines in nanoseconds of the clock source's
+//! time. Armed timers are kept in a fixed array sorted by deadline; with a
+//! few dozen at most, shifting entries on insertion costs less than a
+//! tree's bookkeeping, and the earliest is always the first. It is
+//! programmed into the boot CPU's local APIC timer: in TSC-deadline mode
+//! where the CPU has it and the TSC keeps a constant rate, otherwise as a
+//! one-shot count at the rate measured at boot. Without a local APIC the
+//! scheduler tick stands in, at its coarser resolution.
+//!
+//! The interrupt only ends the scheduler loop's halt. Callbacks run from
+//! [`run`] in that loop, outside interrupt context and with no lock held,
+//! so they may start timers and wake threads; they must not block.
+
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use super::cpu::{self, Feature};
+use super::{apic, clocksource, interrupts};
+
+/// Timers that can be armed at once
+pub const MAX_TIMERS: usize = 64;
+
+/// Time the local APIC timer is measured over, in nanoseconds
+const CALIBRATION_NS: u64 = 10_000_000;
+
+/// Called with the data it was started with when its timer expires
+pub type Callback = fn(u64);
+
+/// An armed timer, to cancel it by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+/// How expiries are signalled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Mode {
+    /// Only noticed on the scheduler tick
+    Tick,
+    /// Local APIC timer counting down
+    OneShot,
+    /// Local APIC timer firing at a TSC value
+    TscDeadline,
+}
+
+#[derive(Debug)]
+pub enum HrTimerError {
+    /// Every timer slot is armed
+    Full,
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    id: u64,
+    deadline: u64,
+    callback: Callback,
+    data: u64,
+}
+
+/// Armed timers, earliest first
+static TIMERS: Mutex<ArrayVec<Timer, MAX_TIMERS>> = Mutex::new(ArrayVec::new_const());
+
+/// Deadline of the earliest timer, `u64::MAX` with none armed
+static NEXT: AtomicU64 = AtomicU64::new(u64::MAX);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+static MODE: AtomicU8 = AtomicU8::new(Mode::Tick as u8);
+static VECTOR: AtomicU8 = AtomicU8::new(0);
+/// Local APIC timer counts per second, in one-shot mode
+static APIC_HZ: AtomicU64 = AtomicU64::new(0);
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+/// Local APIC ID of the CPU the timer belongs to
+static OWNER: AtomicU64 = AtomicU64::new(0);
+
+/// How expiries are signalled
+pub fn mode() -> Mode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => Mode::OneShot,
+        2 => Mode::TscDeadline,
+        _ => Mode::Tick,
+    }
+}
+
+/// Arm a timer calling `callback` with `data` once the time reaches
+/// `deadline`; one already past fires on the next [`run`]
+pub fn start(deadline: u64, callback: Callback, data: u64) -> Result<TimerId, HrTimerError> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let earliest = {
+        let mut timers = TIMERS.lock();
+        if timers.is_full() {
+            return Err(HrTimerError::Full);
+        }
+        let index = timers.partition_point(|t| t.deadline <= deadline);
+        timers.insert(index, Timer { id, deadline, callback, data });
+        index == 0
+    };
+    if earliest {
+        program(deadline);
+    }
+    Ok(TimerId(id))
+}
+
+/// Arm a timer `delay` nanoseconds from now
+pub fn start_after(delay: u64, callback: Callback, data: u64) -> Result<TimerId, HrTimerError> {
+    start(clocksource::nanoseconds().saturating_add(delay), callback, data)
+}
+
+/// Disarm a timer; false if it already fired or was cancelled
+pub fn cancel(id: TimerId) -> bool {
+    let mut timers = TIMERS.lock();
+    let Some(index) = timers.iter().position(|t| t.id == id.0) else {
+        return false;
+    };
+    timers.remove(index);
+    // An early interrupt for the old first timer finds nothing due and
+    // programs the next, so nothing is reprogrammed here
+    NEXT.store(timers.first().map_or(u64::MAX, |t| t.deadline), Ordering::Relaxed);
+    true
+}
+
+/// Call the callbacks of every timer that is due and program the next;
+/// returns how many fired
+pub fn run() -> usize {
+    if NEXT.load(Ordering::Relaxed) == u64::MAX {
+        return 0;
+    }
+    let mut fired = 0;
+    loop {
+        let now = clocksource::nanoseconds();
+        let mut timers = TIMERS.lock();
+        if !timers.first().is_some_and(|t| t.deadline <= now) {
+            let next = timers.first().map_or(u64::MAX, |t| t.deadline);
+            drop(timers);
+            program(next);
+            return fired;
+        }
+        let timer = timers.remove(0);
+        drop(timers);
+        (timer.callback)(timer.data);
+        fired += 1;
+    }
+}
+
+/// Make the timer interrupt arrive by `deadline`
+fn program(deadline: u64) {
+    NEXT.store(deadline, Ordering::Relaxed);
+    let mode = mode();
+    if mode == Mode::Tick || apic::id() as u64 != OWNER.load(Ordering::Relaxed) {
+        return;
+    }
+    if deadline == u64::MAX {
+        apic::stop_timer();
+        return;
+    }
+    let vector = VECTOR.load(Ordering::Relaxed) as u32;
+    let delay = deadline.saturating_sub(clocksource::nanoseconds()) as u128;
+    let counts = |hz: &AtomicU64| (delay * hz.load(Ordering::Relaxed) as u128 / 1_000_000_000) as u64;
+    match mode {
+        Mode::TscDeadline => {
+            let now = unsafe { core::arch::x86_64::_rdtsc() };
+            apic::set_tsc_deadline(apic::TIMER_TSC_DEADLINE | vector, now + counts(&TSC_HZ).max(1));
+        }
+        // A deadline further off than the counter reaches fires early,
+        // finds nothing due, and programs the rest
+        _ => apic::set_timer(apic::TIMER_ONE_SHOT | vector, counts(&APIC_HZ).clamp(1, u32::MAX as u64) as u32),
+    }
+}
+
+/// The timer interrupt; [`run`] finds what is due
+fn expired(_vector: u8) {}
+
+/// Local APIC timer counts per second, measured against the clock source
+fn calibrate_apic() -> u64 {
+    // Start on a change of the time, which only moves a tick at a time
+    // on the PIT
+    let origin = clocksource::nanoseconds();
+    let mut start = origin;
+    while start == origin {
+        start = clocksource::nanoseconds();
+    }
+    apic::set_timer(apic::TIMER_MASKED, u32::MAX);
+    let mut elapsed = 0;
+    while elapsed < CALIBRATION_NS {
+        elapsed = clocksource::nanoseconds() - start;
+    }
+    let counts = (u32::MAX - apic::timer_count()) as u128;
+    apic::stop_timer();
+    (counts * 1_000_000_000 / elapsed as u128) as u64
+}
+
+/// Pick how expiries are signalled and give the local APIC timer a vector;
+/// needs the clock source and the local APIC
+pub fn init() -> Mode {
+    if !apic::present() {
+        return Mode::Tick;
+    }
+    let Ok(vector) = interrupts::allocate_vector(expired) else {
+        return Mode::Tick;
+    };
+    VECTOR.store(vector, Ordering::Relaxed);
+    OWNER.store(apic::id() as u64, Ordering::Relaxed);
+
+    let deadline = cpu::has(Feature::TscDeadline);
+    let mode = match clocksource::tsc_frequency() {
+        Some(hz) if deadline => {
+            TSC_HZ.store(hz, Ordering::Relaxed);
+            Mode::TscDeadline
+        }
+        _ => {
+            APIC_HZ.store(calibrate_apic(), Ordering::Relaxed);
+            Mode::OneShot
+        }
+    };
+    MODE.store(mode as u8, Ordering::Relaxed);
+    mode
+}
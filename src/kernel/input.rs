@@ -0,0 +1,138 @@
+//! Input event queue
+//!
+//! Input drivers push events from their interrupt handlers; consumers drain
+//! them later from normal context. Keys use Linux evdev key codes and
+//! buttons evdev button codes, matching what Wayland clients expect. The
+//! PS/2 keyboard's scan code set 1 is decoded here.
+
+use x86_64::instructions::port::Port;
+
+use super::interrupts::{self, InterruptError, IrqFlags, IrqReturn};
+use super::sync::SpinLockIrq;
+
+/// Events buffered before new ones are dropped
+pub const INPUT_QUEUE_SIZE: usize = 128;
+
+/// evdev button codes
+pub const BUTTON_LEFT: u16 = 0x110;
+pub const BUTTON_RIGHT: u16 = 0x111;
+pub const BUTTON_MIDDLE: u16 = 0x112;
+
+/// One input event
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputEvent {
+    Key { code: u16, pressed: bool },
+    /// Relative pointer movement in pixels
+    PointerMotion { dx: i32, dy: i32 },
+    PointerButton { button: u16, pressed: bool },
+}
+
+struct Queue {
+    events: [Option<InputEvent>; INPUT_QUEUE_SIZE],
+    head: usize,
+    len: usize,
+    dropped: u64,
+    /// The previous scan code byte was the 0xE0 prefix
+    extended: bool,
+}
+
+static QUEUE: SpinLockIrq<Queue> = SpinLockIrq::new("input queue", Queue {
+    events: [None; INPUT_QUEUE_SIZE],
+    head: 0,
+    len: 0,
+    dropped: 0,
+    extended: false,
+});
+
+/// Queue an event; safe to call from interrupt handlers
+pub fn push(event: InputEvent) {
+    let mut queue = QUEUE.lock();
+    if queue.len == INPUT_QUEUE_SIZE {
+        queue.dropped += 1;
+        return;
+    }
+    let slot = (queue.head + queue.len) % INPUT_QUEUE_SIZE;
+    queue.events[slot] = Some(event);
+    queue.len += 1;
+}
+
+/// Take the oldest queued event
+pub fn pop() -> Option<InputEvent> {
+    let mut queue = QUEUE.lock();
+    if queue.len == 0 {
+        return None;
+    }
+    let head = queue.head;
+    let event = queue.events[head].take();
+    queue.head = (head + 1) % INPUT_QUEUE_SIZE;
+    queue.len -= 1;
+    event
+}
+
+/// Events lost because the queue was full
+pub fn dropped() -> u64 {
+    QUEUE.lock().dropped
+}
+
+/// evdev codes of 0xE0-prefixed set 1 scan codes
+fn extended_key(code: u8) -> Option<u16> {
+    Some(match code {
+        0x1C => 96,  // keypad enter
+        0x1D => 97,  // right ctrl
+        0x35 => 98,  // keypad slash
+        0x38 => 100, // right alt
+        0x47 => 102, // home
+        0x48 => 103, // up
+        0x49 => 104, // page up
+        0x4B => 105, // left
+        0x4D => 106, // right
+        0x4F => 107, // end
+        0x50 => 108, // down
+        0x51 => 109, // page down
+        0x52 => 110, // insert
+        0x53 => 111, // delete
+        0x5B => 125, // left meta
+        0x5C => 126, // right meta
+        _ => return None,
+    })
+}
+
+/// Take the PS/2 keyboard's interrupt line
+pub fn init() -> Result<(), InterruptError> {
+    interrupts::request_irq(interrupts::KEYBOARD_IRQ, IrqFlags::THREADED, keyboard_interrupt, 0)
+}
+
+/// Read and decode a byte from the PS/2 keyboard, which holds it while the
+/// line is masked
+fn keyboard_interrupt(_context: usize) -> IrqReturn {
+    let scancode: u8 = unsafe { Port::new(0x60).read() };
+    ps2_scancode(scancode);
+    IrqReturn::Handled
+}
+
+/// Decode one byte from the PS/2 keyboard (scan code set 1)
+pub fn ps2_scancode(byte: u8) {
+    let extended = {
+        let mut queue = QUEUE.lock();
+        if byte == 0xE0 {
+            queue.extended = true;
+            return;
+        }
+        core::mem::replace(&mut queue.extended, false)
+    };
+    let pressed = byte & 0x80 == 0;
+    let code = byte & 0x7F;
+
+    // Plain set 1 make codes are the evdev key codes
+    let code = if extended {
+        match extended_key(code) {
+            Some(code) => code,
+            None => return,
+        }
+    } else {
+        code as u16
+    };
+    if code != 0 {
+        push(InputEvent::Key { code, pressed });
+    }
+}
@@ -1,34 +1,186 @@
 //! Interrupt handling subsystem
+//!
+//! Drivers take a device's interrupt line with [`request_irq`]. A line is
+//! a GSI the I/O APIC routes to a dynamic vector, or the keyboard's, which
+//! the PICs keep. Handlers that say so share a line, each called in turn
+//! to check its own device; a threaded handler runs after the interrupt,
+//! as deferred work with interrupts enabled, and its line stays masked
+//! until it returns. Every line counts its interrupts, and those no
+//! handler claimed.
 
+use arrayvec::ArrayVec;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin::Mutex;
+
+use super::softirq;
+use super::sync::SpinLockIrq;
 
 /// PIC offset for hardware interrupts
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-/// Global PIC controller
-pub static PICS: Mutex<ChainedPics> =
-    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+/// First vector available for dynamic (MSI/MSI-X) allocation
+pub const DYNAMIC_VECTOR_BASE: u8 = 48;
+/// Number of dynamically allocatable vectors
+pub const DYNAMIC_VECTOR_COUNT: usize = 32;
+
+/// Handler for a dynamically allocated vector, called with the vector number
+pub type VectorHandler = fn(u8);
+
+/// Interrupt lines that can be requested at once
+pub const MAX_IRQ_LINES: usize = 16;
+/// Handlers that can share a line
+pub const MAX_SHARED_HANDLERS: usize = 4;
+
+/// The keyboard's line, which the PICs deliver; the timer's is the kernel's
+pub const KEYBOARD_IRQ: u32 = 1;
+
+/// How a handler takes its line
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct IrqFlags(u8);
+
+impl IrqFlags {
+    pub const NONE: Self = Self(0);
+    /// Other handlers may share the line, if they all say so
+    pub const SHARED: Self = Self(1 << 0);
+    /// Run after the interrupt with interrupts enabled, the line masked
+    pub const THREADED: Self = Self(1 << 1);
+
+    /// Whether all flags in `other` are set
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for IrqFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Whether a handler's device was the one that interrupted
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IrqReturn {
+    None,
+    Handled,
+}
+
+/// Handler for an interrupt line, called with the context it was
+/// requested with
+pub type IrqHandler = fn(usize) -> IrqReturn;
+
+#[derive(Clone, Copy)]
+struct Action {
+    handler: IrqHandler,
+    context: usize,
+    flags: IrqFlags,
+}
+
+struct Line {
+    line: u32,
+    /// Vector the I/O APIC delivers the line on, `None` for the PICs'
+    vector: Option<u8>,
+    actions: ArrayVec<Action, MAX_SHARED_HANDLERS>,
+    /// Runs of threaded handlers queued and not finished, the line masked
+    /// while there are any
+    pending: u32,
+    stats: IrqStats,
+}
+
+/// Counts for a requested line
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IrqStats {
+    pub line: u32,
+    pub vector: Option<u8>,
+    pub handlers: usize,
+    pub interrupts: u64,
+    /// Interrupts no handler claimed
+    pub unhandled: u64,
+    /// Interrupts that ran threaded handlers
+    pub threaded: u64,
+}
+
+static LINES: SpinLockIrq<ArrayVec<Line, MAX_IRQ_LINES>> = SpinLockIrq::new("irq lines", ArrayVec::new_const());
+
+/// Handlers registered for dynamic vectors
+static DYNAMIC_HANDLERS: SpinLockIrq<[Option<VectorHandler>; DYNAMIC_VECTOR_COUNT]> =
+    SpinLockIrq::new("interrupt vectors", [None; DYNAMIC_VECTOR_COUNT]);
+
+/// Generate one IDT stub per dynamic vector
+macro_rules! dynamic_stubs {
+    ($($n:literal),*) => {
+        [$({
+            extern "x86-interrupt" fn stub(stack_frame: InterruptStackFrame) {
+                entered(&stack_frame);
+                dispatch_dynamic(DYNAMIC_VECTOR_BASE + $n);
+            }
+            stub as extern "x86-interrupt" fn(InterruptStackFrame)
+        }),*]
+    };
+}
+
+static DYNAMIC_STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); DYNAMIC_VECTOR_COUNT] = dynamic_stubs!(
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+    16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+);
+
+/// Global PIC controller, locked from the timer and keyboard handlers too
+pub static PICS: SpinLockIrq<ChainedPics> =
+    SpinLockIrq::new("pics", unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 lazy_static! {
     /// Global interrupt descriptor table
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault_handler)
+                .set_stack_index(super::gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
+
+        // Exceptions a program can raise, through stubs that save every
+        // register so they can be handed to the program's handler; probes
+        // on kernel code change the registers of breakpoints and steps
+        unsafe {
+            let stub = |f: unsafe extern "C" fn()| x86_64::VirtAddr::new(f as *const () as u64);
+            idt.divide_error.set_handler_addr(stub(exception_0));
+            idt.debug.set_handler_addr(stub(exception_1));
+            idt.breakpoint.set_handler_addr(stub(exception_3));
+            idt.invalid_opcode.set_handler_addr(stub(exception_6));
+            idt.stack_segment_fault.set_handler_addr(stub(exception_12));
+            idt.general_protection_fault.set_handler_addr(stub(exception_13));
+            idt.page_fault.set_handler_addr(stub(exception_14));
+            idt.machine_check.set_handler_addr(stub(exception_18));
+            idt.simd_floating_point.set_handler_addr(stub(exception_19));
+        }
         
-        // Timer interrupt
-        idt[InterruptIndex::Timer.as_u8()]
-            .set_handler_fn(timer_interrupt_handler);
+        // Timer interrupt, through a stub that saves every register so the
+        // running program can be preempted
+        unsafe {
+            idt[InterruptIndex::Timer.as_u8()]
+                .set_handler_addr(x86_64::VirtAddr::new(timer_entry as *const () as u64));
+        }
         
-        // Keyboard interrupt
+        // Keyboard interrupt, for whichever driver requests its line
         idt[InterruptIndex::Keyboard.as_u8()]
             .set_handler_fn(keyboard_interrupt_handler);
+
+        // MSI/MSI-X vectors
+        for (i, stub) in DYNAMIC_STUBS.iter().enumerate() {
+            idt[DYNAMIC_VECTOR_BASE + i as u8].set_handler_fn(*stub);
+        }
+        idt[crate::kernel::apic::SPURIOUS_VECTOR].set_handler_fn(spurious_interrupt_handler);
+
+        // System calls from ring 3 without `syscall`
+        unsafe {
+            idt[crate::userspace::syscall::SYSCALL_VECTOR]
+                .set_handler_addr(crate::userspace::syscall::int_entry())
+                .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
+        }
         
         idt
     };
@@ -41,6 +193,31 @@ pub fn init() {
     x86_64::instructions::interrupts::enable();
 }
 
+/// Mask ISA IRQ `irq` on the PICs, for one the I/O APIC delivers instead
+pub fn mask_pic_irq(irq: u8) {
+    set_pic_masked(irq, true);
+}
+
+fn set_pic_masked(irq: u8, masked: bool) {
+    let mut pics = PICS.lock();
+    unsafe {
+        let mut masks = pics.read_masks();
+        let bit = 1 << (irq % 8);
+        match masked {
+            true => masks[(irq / 8) as usize % 2] |= bit,
+            false => masks[(irq / 8) as usize % 2] &= !bit,
+        }
+        pics.write_masks(masks[0], masks[1]);
+    }
+}
+
+/// Load the IDT on an application processor; the PICs deliver only to the
+/// bootstrap processor
+pub fn init_ap() {
+    IDT.load();
+    x86_64::instructions::interrupts::enable();
+}
+
 /// Hardware interrupt indices
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -59,9 +236,12 @@ impl InterruptIndex {
     }
 }
 
-/// Breakpoint exception handler
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    crate::serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+/// Put the per-CPU GS base back if an interrupt came from user mode,
+/// where a program may have moved it
+fn entered(stack_frame: &InterruptStackFrame) {
+    if stack_frame.code_segment.rpl() == x86_64::PrivilegeLevel::Ring3 {
+        super::percpu::restore();
+    }
 }
 
 /// Double fault exception handler
@@ -69,59 +249,462 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    super::percpu::restore();
+    // A page fault on a stack's guard page cannot push its frame there
+    // either, so it arrives here with the address it touched still in CR2
+    let address = x86_64::registers::control::Cr2::read_raw();
+    if let Some(stack) = super::memory::stack_guard(address) {
+        panic!("KERNEL STACK OVERFLOW on the {} stack at {:#x}\n{:#?}", stack, address, stack_frame);
+    }
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
-/// Page fault exception handler
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: x86_64::structures::idt::PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    crate::serial_println!("EXCEPTION: PAGE FAULT");
-    crate::serial_println!("Accessed Address: {:?}", Cr2::read());
-    crate::serial_println!("Error Code: {:?}", error_code);
-    crate::serial_println!("{:#?}", stack_frame);
-    
-    loop {
-        x86_64::instructions::hlt();
+/// Names of the exceptions that go through [`exception_handler`]
+fn exception_name(vector: u8) -> &'static str {
+    match vector {
+        0 => "DIVIDE ERROR",
+        1 => "DEBUG",
+        3 => "BREAKPOINT",
+        6 => "INVALID OPCODE",
+        12 => "STACK SEGMENT FAULT",
+        13 => "GENERAL PROTECTION FAULT",
+        14 => "PAGE FAULT",
+        18 => "MACHINE CHECK",
+        19 => "SIMD FLOATING POINT",
+        _ => "UNKNOWN",
     }
 }
 
-/// General protection fault handler
-extern "x86-interrupt" fn general_protection_fault_handler(
-    stack_frame: InterruptStackFrame,
+/// Exception handler, with the interrupted registers; a program's
+/// exception goes to the program, a kernel one is fatal unless it is a
+/// breakpoint, a step or a machine check the kernel survives
+extern "C" fn exception_handler(
+    context: &mut crate::userspace::usermode::UserContext,
+    vector: u64,
     error_code: u64,
 ) {
+    use x86_64::registers::control::Cr2;
+
+    if context.is_user() {
+        super::percpu::restore();
+    }
+    let vector = vector as u8;
+    if matches!(vector, 1 | 3) && !context.is_user() {
+        let probe = match vector {
+            1 => super::kprobe::debug(context),
+            _ => super::kprobe::breakpoint(context),
+        };
+        if !probe {
+            crate::serial_println!("EXCEPTION: {}\n{:#?}", exception_name(vector), context);
+        }
+        return;
+    }
+    if vector == 18 {
+        return super::mce::exception(context);
+    }
+    let error_code = matches!(vector, 12..=14).then_some(error_code);
+    let address = (vector == 14).then(Cr2::read_raw);
+    crate::userspace::usermode::check_fault(vector, context, error_code, address);
+
+    if vector == 14 {
+        if let Some(stack) = address.and_then(super::memory::stack_guard) {
+            crate::serial_println!("KERNEL STACK OVERFLOW on the {} stack", stack);
+        }
+        crate::serial_println!("EXCEPTION: PAGE FAULT");
+        crate::serial_println!("Accessed Address: {:?}", Cr2::read());
+        crate::serial_println!("Error Code: {:?}", error_code);
+        crate::serial_println!("{:#?}", context);
+
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
     panic!(
-        "EXCEPTION: GENERAL PROTECTION FAULT (error code: {})\n{:#?}",
-        error_code, stack_frame
+        "EXCEPTION: {} (error code: {:?})\n{:#?}",
+        exception_name(vector),
+        error_code,
+        context
     );
 }
 
-/// Timer interrupt handler
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+// Each stub leaves the error code (0 if the CPU pushed none) in rax and
+// the vector in rbx, with the registers they held saved in their slots
+core::arch::global_asm!(
+    r#"
+.macro exception_stub vector, error
+.global exception_\vector
+exception_\vector:
+.if \error
+    xchg rax, [rsp]
+.else
+    push rax
+    xor eax, eax
+.endif
+    push rbx
+    mov ebx, \vector
+    jmp exception_common
+.endm
+
+exception_stub 0, 0
+exception_stub 1, 0
+exception_stub 3, 0
+exception_stub 6, 0
+exception_stub 12, 1
+exception_stub 13, 1
+exception_stub 14, 1
+exception_stub 18, 0
+exception_stub 19, 0
+
+exception_common:
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    mov rsi, rbx
+    mov rdx, rax
+    cld
+    call {handler}
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    iretq
+"#,
+    handler = sym exception_handler,
+);
+
+extern "C" {
+    fn exception_0();
+    fn exception_1();
+    fn exception_3();
+    fn exception_6();
+    fn exception_12();
+    fn exception_13();
+    fn exception_14();
+    fn exception_18();
+    fn exception_19();
+}
+
+core::arch::global_asm!(
+    r#"
+.global timer_entry
+timer_entry:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    call {handler}
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    iretq
+"#,
+    handler = sym timer_interrupt_handler,
+);
+
+extern "C" {
+    fn timer_entry();
+}
+
+/// Timer interrupt handler, with the interrupted registers
+extern "C" fn timer_interrupt_handler(context: &mut crate::userspace::usermode::UserContext) {
+    if context.is_user() {
+        super::percpu::restore();
+    }
     // Notify scheduler of timer tick
     crate::scheduler::tick();
+    crate::kernel::clocksource::tick();
+    crate::kernel::watchdog::tick(context);
+    crate::kernel::random::add_interrupt(InterruptIndex::Timer.as_u8());
     
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    super::softirq::run();
+
+    crate::userspace::usermode::tick(context);
 }
 
 /// Keyboard interrupt handler
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
+    entered(&stack_frame);
+    handle_line(KEYBOARD_IRQ);
+    crate::kernel::random::add_interrupt(InterruptIndex::Keyboard.as_u8());
 
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    
-    // TODO: Process keyboard input
-    
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
     }
+    super::softirq::run();
+}
+
+/// Non-maskable interrupt handler, for the watchdog's counter, hardware
+/// errors and anything else that raises one
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    // Even from the kernel: it may have landed before an entry put GS back
+    super::percpu::restore();
+    super::nmi::handle(&stack_frame);
+}
+
+/// Spurious APIC interrupt handler (no EOI required)
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+/// Dispatch a dynamic vector to its registered handler
+fn dispatch_dynamic(vector: u8) {
+    let handler = DYNAMIC_HANDLERS.lock()[(vector - DYNAMIC_VECTOR_BASE) as usize];
+    if let Some(handler) = handler {
+        handler(vector);
+    }
+    crate::kernel::random::add_interrupt(vector);
+    crate::kernel::apic::eoi();
+    super::softirq::run();
+}
+
+fn set_line_masked(line: &Line, masked: bool) {
+    match line.vector {
+        Some(_) => {
+            let _ = super::ioapic::set_masked(line.line, masked);
+        }
+        None => set_pic_masked(line.line as u8, masked),
+    }
+}
+
+/// Run the handlers of `line`, before its interrupt is acknowledged; the
+/// threaded ones are queued, the line masked until they have run
+fn handle_line(line: u32) {
+    let actions = {
+        let mut lines = LINES.lock();
+        let Some(entry) = lines.iter_mut().find(|l| l.line == line) else {
+            return;
+        };
+        entry.stats.interrupts += 1;
+        entry.actions.clone()
+    };
+
+    let mut handled = false;
+    let mut threaded = false;
+    for action in &actions {
+        if action.flags.contains(IrqFlags::THREADED) {
+            threaded = true;
+        } else {
+            handled |= (action.handler)(action.context) == IrqReturn::Handled;
+        }
+    }
+    // Run here after all if there is no room to queue them
+    if threaded && softirq::defer(run_threaded, (line as u64) << 1 | handled as u64).is_err() {
+        for action in actions.iter().filter(|a| a.flags.contains(IrqFlags::THREADED)) {
+            handled |= (action.handler)(action.context) == IrqReturn::Handled;
+        }
+        threaded = false;
+    }
+
+    let mut lines = LINES.lock();
+    let Some(entry) = lines.iter_mut().find(|l| l.line == line) else {
+        return;
+    };
+    if threaded {
+        if entry.pending == 0 {
+            set_line_masked(entry, true);
+        }
+        entry.pending += 1;
+        entry.stats.threaded += 1;
+    } else if !handled {
+        entry.stats.unhandled += 1;
+    }
+}
+
+/// Run the threaded handlers of a line, then unmask it; `data` is the line
+/// and whether a handler claimed the interrupt already
+fn run_threaded(data: u64) {
+    let line = (data >> 1) as u32;
+    let mut handled = data & 1 != 0;
+    let actions = match LINES.lock().iter().find(|l| l.line == line) {
+        Some(entry) => entry.actions.clone(),
+        None => return,
+    };
+    for action in actions.iter().filter(|a| a.flags.contains(IrqFlags::THREADED)) {
+        handled |= (action.handler)(action.context) == IrqReturn::Handled;
+    }
+
+    let mut lines = LINES.lock();
+    let Some(entry) = lines.iter_mut().find(|l| l.line == line) else {
+        return;
+    };
+    if !handled {
+        entry.stats.unhandled += 1;
+    }
+    if entry.pending > 0 {
+        entry.pending -= 1;
+        if entry.pending == 0 {
+            set_line_masked(entry, false);
+        }
+    }
+}
+
+/// A vector the I/O APIC delivers a requested line on
+fn line_interrupt(vector: u8) {
+    let line = LINES.lock().iter().find(|l| l.vector == Some(vector)).map(|l| l.line);
+    if let Some(line) = line {
+        handle_line(line);
+    }
+}
+
+/// Have `handler(context)` called on each interrupt on `line`, routing
+/// the line if it is the first handler there
+pub fn request_irq(line: u32, flags: IrqFlags, handler: IrqHandler, context: usize) -> Result<(), InterruptError> {
+    use super::ioapic::IoApicError;
+
+    let action = Action { handler, context, flags };
+    let mut lines = LINES.lock();
+    if let Some(entry) = lines.iter_mut().find(|l| l.line == line) {
+        if !flags.contains(IrqFlags::SHARED) || entry.actions.iter().any(|a| !a.flags.contains(IrqFlags::SHARED)) {
+            return Err(InterruptError::Busy);
+        }
+        return entry.actions.try_push(action).map_err(|_| InterruptError::TooManyHandlers);
+    }
+    if lines.is_full() {
+        return Err(InterruptError::TooManyLines);
+    }
+
+    let vector = match line {
+        KEYBOARD_IRQ => None,
+        _ => Some(super::ioapic::register_irq(line, line_interrupt).map_err(|e| match e {
+            IoApicError::NoIoApic | IoApicError::NoSuchGsi => InterruptError::NoSuchLine,
+            IoApicError::Busy => InterruptError::Busy,
+            IoApicError::NoFreeVector => InterruptError::NoFreeVector,
+        })?),
+    };
+    let mut actions = ArrayVec::new();
+    actions.push(action);
+    let entry = Line {
+        line,
+        vector,
+        actions,
+        pending: 0,
+        stats: IrqStats { line, vector, ..IrqStats::default() },
+    };
+    if vector.is_none() {
+        set_line_masked(&entry, false);
+    }
+    lines.push(entry);
+    Ok(())
+}
+
+/// Remove the handler [`request_irq`] put on `line` with `context`,
+/// masking the line if it was the last
+pub fn free_irq(line: u32, handler: IrqHandler, context: usize) -> Result<(), InterruptError> {
+    let mut lines = LINES.lock();
+    let index = lines.iter().position(|l| l.line == line).ok_or(InterruptError::NotRequested)?;
+    let entry = &mut lines[index];
+    let action = entry
+        .actions
+        .iter()
+        .position(|a| a.handler as usize == handler as usize && a.context == context)
+        .ok_or(InterruptError::NotRequested)?;
+    entry.actions.remove(action);
+    if !entry.actions.is_empty() {
+        return Ok(());
+    }
+
+    let entry = lines.swap_remove(index);
+    match entry.vector {
+        Some(_) => {
+            let _ = super::ioapic::unregister_irq(line);
+        }
+        None => set_line_masked(&entry, true),
+    }
+    Ok(())
+}
+
+/// Counts for every requested line
+pub fn irq_stats() -> ArrayVec<IrqStats, MAX_IRQ_LINES> {
+    LINES
+        .lock()
+        .iter()
+        .map(|l| IrqStats { handlers: l.actions.len(), ..l.stats })
+        .collect()
+}
+
+/// Allocate a free dynamic vector and attach a handler to it
+pub fn allocate_vector(handler: VectorHandler) -> Result<u8, InterruptError> {
+    let mut handlers = DYNAMIC_HANDLERS.lock();
+    let (index, slot) = handlers
+        .iter_mut()
+        .enumerate()
+        .find(|(_, slot)| slot.is_none())
+        .ok_or(InterruptError::NoFreeVector)?;
+
+    *slot = Some(handler);
+    Ok(DYNAMIC_VECTOR_BASE + index as u8)
+}
+
+/// Release a dynamic vector
+pub fn free_vector(vector: u8) -> Result<(), InterruptError> {
+    let index = vector
+        .checked_sub(DYNAMIC_VECTOR_BASE)
+        .filter(|&i| (i as usize) < DYNAMIC_VECTOR_COUNT)
+        .ok_or(InterruptError::InvalidVector)?;
+
+    DYNAMIC_HANDLERS.lock()[index as usize] = None;
+    Ok(())
+}
+
+/// Interrupt management errors
+#[derive(Debug)]
+pub enum InterruptError {
+    NoFreeVector,
+    InvalidVector,
+    /// No I/O APIC has a pin for the line
+    NoSuchLine,
+    /// The line is taken and not shared
+    Busy,
+    TooManyHandlers,
+    TooManyLines,
+    NotRequested,
 }
@@ -0,0 +1,281 @@
+//! I/O APIC interrupt routing
+//!
+//! The MADT lists each I/O APIC with the first global system interrupt
+//! (GSI) its pins serve, and the overrides that say which GSI an ISA IRQ
+//! arrives on and with what polarity and trigger mode, where they differ
+//! from the ISA default of active high and edge triggered. A driver asks
+//! for a GSI with [`interrupts::request_irq`], which routes it here with
+//! [`register_irq`]; it gets a dynamic vector, and the redirection entry
+//! for the GSI is programmed to deliver it to the bootstrap processor. A GSI no override names and no ISA IRQ maps to
+//! is taken to be a PCI interrupt, active low and level triggered.
+//!
+//! The PICs keep the timer and the keyboard. An ISA IRQ routed here is
+//! masked on the PIC, so it does not arrive twice.
+
+use arrayvec::ArrayVec;
+use x86_64::PhysAddr;
+
+use super::interrupts::{self, VectorHandler};
+use super::sync::SpinLockIrq;
+
+/// I/O APICs the kernel drives
+pub const MAX_IO_APICS: usize = 8;
+
+/// Interrupt source overrides kept
+const MAX_OVERRIDES: usize = 16;
+
+/// ISA IRQs, which are identity-mapped to GSIs unless overridden
+const ISA_IRQS: u8 = 16;
+
+/// ISA IRQs the PICs keep: the timer and the keyboard
+const PIC_IRQS: [u8; 2] = [0, 1];
+
+/// MADT entries
+const MADT_IO_APIC: u8 = 1;
+const MADT_SOURCE_OVERRIDE: u8 = 2;
+
+/// Registers: the version, with the last redirection entry in bits 16-23,
+/// and the redirection table, two registers per entry
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+/// Redirection entry bits
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_LEVEL: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+
+/// Signal polarity
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    High,
+    Low,
+}
+
+/// Trigger mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+struct IoApic {
+    /// Virtual address of the register window
+    base: u64,
+    gsi_base: u32,
+    /// Redirection entries
+    pins: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            core::ptr::write_volatile(self.base as *mut u32, reg);
+            core::ptr::read_volatile((self.base + 0x10) as *const u32)
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            core::ptr::write_volatile(self.base as *mut u32, reg);
+            core::ptr::write_volatile((self.base + 0x10) as *mut u32, value);
+        }
+    }
+
+    fn set_masked(&self, pin: u32, masked: bool) {
+        let low = self.read(REG_REDIRECTION + pin * 2);
+        let low = match masked {
+            true => low | ENTRY_MASKED as u32,
+            false => low & !(ENTRY_MASKED as u32),
+        };
+        self.write(REG_REDIRECTION + pin * 2, low);
+    }
+
+    fn set_entry(&self, pin: u32, entry: u64) {
+        // Masked while it is half written
+        self.write(REG_REDIRECTION + pin * 2, ENTRY_MASKED as u32);
+        self.write(REG_REDIRECTION + pin * 2 + 1, (entry >> 32) as u32);
+        self.write(REG_REDIRECTION + pin * 2, entry as u32);
+    }
+}
+
+/// Where an ISA IRQ arrives, and how
+#[derive(Clone, Copy, Debug)]
+struct Override {
+    irq: u8,
+    gsi: u32,
+    polarity: Polarity,
+    trigger: Trigger,
+}
+
+struct Routing {
+    io_apics: ArrayVec<IoApic, MAX_IO_APICS>,
+    overrides: ArrayVec<Override, MAX_OVERRIDES>,
+    /// Vector of each GSI routed so far
+    routed: ArrayVec<(u32, u8), { interrupts::DYNAMIC_VECTOR_COUNT }>,
+}
+
+/// Taken from interrupt handlers too, to mask a line
+static ROUTING: SpinLockIrq<Routing> = SpinLockIrq::new(
+    "ioapic routing",
+    Routing {
+        io_apics: ArrayVec::new_const(),
+        overrides: ArrayVec::new_const(),
+        routed: ArrayVec::new_const(),
+    },
+);
+
+/// I/O APIC errors
+#[derive(Debug)]
+pub enum IoApicError {
+    /// The MADT lists no I/O APIC
+    NoIoApic,
+    /// No I/O APIC has a pin for the GSI
+    NoSuchGsi,
+    /// The GSI is routed already, or the PICs keep it
+    Busy,
+    NoFreeVector,
+}
+
+/// Polarity and trigger mode from an override's MPS flags, where 0 means
+/// the bus default
+fn mps_flags(flags: u16) -> (Polarity, Trigger) {
+    let polarity = match flags & 0b11 {
+        0b11 => Polarity::Low,
+        _ => Polarity::High,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b11 => Trigger::Level,
+        _ => Trigger::Edge,
+    };
+    (polarity, trigger)
+}
+
+/// Map the I/O APICs the MADT lists, with every pin masked, and read the
+/// ISA overrides; returns how many I/O APICs there are
+pub fn init() -> Result<usize, IoApicError> {
+    let mut routing = ROUTING.lock();
+    for entry in super::acpi::madt() {
+        match entry[0] {
+            MADT_IO_APIC if entry.len() >= 12 => {
+                let addr = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+                let gsi_base = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]);
+                let base = match super::memory::map_mmio(PhysAddr::new(addr as u64), 0x20) {
+                    Ok(base) => base.as_u64(),
+                    Err(e) => {
+                        crate::klog!(Warn, "I/O APIC at {:#x} not mapped: {:?}", addr, e);
+                        continue;
+                    }
+                };
+                let mut io_apic = IoApic { base, gsi_base, pins: 0 };
+                io_apic.pins = ((io_apic.read(REG_VERSION) >> 16) & 0xff) + 1;
+                for pin in 0..io_apic.pins {
+                    io_apic.set_entry(pin, ENTRY_MASKED);
+                }
+                let _ = routing.io_apics.try_push(io_apic);
+            }
+            MADT_SOURCE_OVERRIDE if entry.len() >= 10 => {
+                let (polarity, trigger) = mps_flags(u16::from_le_bytes([entry[8], entry[9]]));
+                let _ = routing.overrides.try_push(Override {
+                    irq: entry[3],
+                    gsi: u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]),
+                    polarity,
+                    trigger,
+                });
+            }
+            _ => {}
+        }
+    }
+    match routing.io_apics.len() {
+        0 => Err(IoApicError::NoIoApic),
+        count => Ok(count),
+    }
+}
+
+/// The GSI ISA IRQ `irq` arrives on
+pub fn isa_gsi(irq: u8) -> u32 {
+    let routing = ROUTING.lock();
+    routing.overrides.iter().find(|o| o.irq == irq).map_or(irq as u32, |o| o.gsi)
+}
+
+/// The ISA IRQ that arrives on `gsi`, if any, with its polarity and
+/// trigger mode
+fn isa_source(routing: &Routing, gsi: u32) -> Option<(u8, Polarity, Trigger)> {
+    if let Some(o) = routing.overrides.iter().find(|o| o.gsi == gsi) {
+        return Some((o.irq, o.polarity, o.trigger));
+    }
+    // An identity-mapped ISA IRQ, unless an override took it elsewhere
+    let irq = u8::try_from(gsi).ok().filter(|irq| *irq < ISA_IRQS)?;
+    let moved = routing.overrides.iter().any(|o| o.irq == irq);
+    (!moved).then_some((irq, Polarity::High, Trigger::Edge))
+}
+
+/// Route `gsi` to a fresh vector that calls `handler`, returning the
+/// vector
+pub fn register_irq(gsi: u32, handler: VectorHandler) -> Result<u8, IoApicError> {
+    let mut routing = ROUTING.lock();
+    if routing.routed.iter().any(|(routed, _)| *routed == gsi) {
+        return Err(IoApicError::Busy);
+    }
+    let (pin, io_apic) = routing
+        .io_apics
+        .iter()
+        .enumerate()
+        .find(|(_, a)| gsi >= a.gsi_base && gsi < a.gsi_base + a.pins)
+        .map(|(index, a)| (gsi - a.gsi_base, index))
+        .ok_or(IoApicError::NoSuchGsi)?;
+    let source = isa_source(&routing, gsi);
+    let (polarity, trigger) = match source {
+        Some((irq, _, _)) if PIC_IRQS.contains(&irq) => return Err(IoApicError::Busy),
+        Some((_, polarity, trigger)) => (polarity, trigger),
+        None => (Polarity::Low, Trigger::Level),
+    };
+
+    let vector = interrupts::allocate_vector(handler).map_err(|_| IoApicError::NoFreeVector)?;
+    if routing.routed.try_push((gsi, vector)).is_err() {
+        let _ = interrupts::free_vector(vector);
+        return Err(IoApicError::NoFreeVector);
+    }
+    if let Some((irq, _, _)) = source {
+        interrupts::mask_pic_irq(irq);
+    }
+    let mut entry = vector as u64 | (super::apic::id() as u64) << 56;
+    if polarity == Polarity::Low {
+        entry |= ENTRY_ACTIVE_LOW;
+    }
+    if trigger == Trigger::Level {
+        entry |= ENTRY_LEVEL;
+    }
+    routing.io_apics[io_apic].set_entry(pin, entry);
+    Ok(vector)
+}
+
+/// Mask or unmask a routed `gsi`
+pub fn set_masked(gsi: u32, masked: bool) -> Result<(), IoApicError> {
+    let routing = ROUTING.lock();
+    if !routing.routed.iter().any(|(routed, _)| *routed == gsi) {
+        return Err(IoApicError::NoSuchGsi);
+    }
+    let io_apic = routing
+        .io_apics
+        .iter()
+        .find(|a| gsi >= a.gsi_base && gsi < a.gsi_base + a.pins)
+        .ok_or(IoApicError::NoSuchGsi)?;
+    io_apic.set_masked(gsi - io_apic.gsi_base, masked);
+    Ok(())
+}
+
+/// Mask `gsi` and free its vector
+pub fn unregister_irq(gsi: u32) -> Result<(), IoApicError> {
+    let mut routing = ROUTING.lock();
+    let index = routing
+        .routed
+        .iter()
+        .position(|(routed, _)| *routed == gsi)
+        .ok_or(IoApicError::NoSuchGsi)?;
+    let (_, vector) = routing.routed.swap_remove(index);
+    if let Some(io_apic) = routing.io_apics.iter().find(|a| gsi >= a.gsi_base && gsi < a.gsi_base + a.pins) {
+        io_apic.set_entry(gsi - io_apic.gsi_base, ENTRY_MASKED);
+    }
+    let _ = interrupts::free_vector(vector);
+    Ok(())
+}
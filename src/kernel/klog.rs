@@ -0,0 +1,323 @@
+//! Kernel log
+//!
+//! [`klog!`](crate::klog) records a message with a level and the module
+//! that logged it in a ring of the last [`RING_SIZE`] records, which the
+//! `dmesg` shell command and the kernel-log system call read back, and
+//! passes it on to the serial port and the framebuffer console. Each sink
+//! has a level of its own (`klog.serial=` and `klog.console=` on the
+//! command line). Whether a message is kept at all is decided by the
+//! longest module filter that covers its module, set with
+//! `klog=<module>:<level>,...`, or else by `loglevel=`; debug messages are
+//! dropped by default.
+//!
+//! Messages longer than [`TEXT_SIZE`] bytes are cut short. Crash paths
+//! print straight to the serial port instead, since the log's locks may be
+//! held by whatever crashed.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use arrayvec::{ArrayString, ArrayVec};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Records kept
+pub const RING_SIZE: usize = 256;
+
+/// Bytes of a message kept
+pub const TEXT_SIZE: usize = 160;
+
+/// Module filters that can be set
+pub const MAX_FILTERS: usize = 16;
+
+/// Longest module path a filter matches
+const MODULE_SIZE: usize = 48;
+
+/// Log a message at a level, as `klog!(Warn, "format", args...)`
+#[macro_export]
+macro_rules! klog {
+    ($level:ident, $($arg:tt)*) => {
+        $crate::kernel::klog::log($crate::kernel::klog::Level::$level, module_path!(), format_args!($($arg)*))
+    };
+}
+
+/// How severe a message is, most severe first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn from_u8(value: u8) -> Level {
+        match value {
+            0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Level> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+
+    /// Syslog priority, for the system call's records
+    fn priority(self) -> u8 {
+        match self {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug => 7,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum KlogError {
+    /// The module path is longer than a filter holds
+    ModuleTooLong,
+    /// Every filter slot is in use
+    TooManyFilters,
+    NoSuchSink,
+}
+
+/// A logged message
+#[derive(Debug, Clone, Copy)]
+pub struct Record {
+    /// Counts every record ever logged
+    pub sequence: u64,
+    /// Nanoseconds since boot
+    pub time: u64,
+    pub level: Level,
+    /// Module path without the crate name
+    pub module: &'static str,
+    pub text: ArrayString<TEXT_SIZE>,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let micros = self.time / 1000;
+        write!(f, "[{:>5}.{:06}] ", micros / 1_000_000, micros % 1_000_000)?;
+        if self.level < Level::Info {
+            write!(f, "{}: ", self.level.name())?;
+        }
+        write!(f, "{}: {}", self.module, self.text)
+    }
+}
+
+struct Ring {
+    records: [Option<Record>; RING_SIZE],
+    /// Sequence number of the next record
+    next: u64,
+}
+
+impl Ring {
+    /// The oldest record kept from `sequence` on
+    fn from(&self, sequence: u64) -> Option<Record> {
+        let sequence = sequence.max(self.next.saturating_sub(RING_SIZE as u64));
+        (sequence < self.next).then(|| self.records[sequence as usize % RING_SIZE]).flatten()
+    }
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring {
+    records: [None; RING_SIZE],
+    next: 0,
+});
+
+struct Filter {
+    module: ArrayString<MODULE_SIZE>,
+    level: Level,
+}
+
+static FILTERS: Mutex<ArrayVec<Filter, MAX_FILTERS>> = Mutex::new(ArrayVec::new_const());
+
+/// Level kept for modules no filter covers
+static DEFAULT_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Where records are written as they are logged
+struct Sink {
+    name: &'static str,
+    level: AtomicU8,
+    write: fn(&Record),
+}
+
+static SINKS: [Sink; 2] = [
+    Sink { name: "serial", level: AtomicU8::new(Level::Debug as u8), write: to_serial },
+    Sink { name: "console", level: AtomicU8::new(Level::Info as u8), write: to_console },
+];
+
+fn to_serial(record: &Record) {
+    let _ = writeln!(crate::boot::serial::SERIAL1.lock(), "{}", record);
+}
+
+fn to_console(record: &Record) {
+    crate::gpu::console::write_fmt(format_args!("{}\n", record));
+}
+
+/// Writes what fits and drops the rest, at a character boundary
+struct Truncating<'a>(&'a mut ArrayString<TEXT_SIZE>);
+
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(self.0.remaining_capacity());
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.0.push_str(&s[..end]);
+        Ok(())
+    }
+}
+
+/// Whether messages at `level` from `module` are kept
+fn enabled(level: Level, module: &str) -> bool {
+    let covers = |filter: &&Filter| {
+        let prefix = filter.module.as_str();
+        module.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+    };
+    let threshold = interrupts::without_interrupts(|| {
+        let filters = FILTERS.lock();
+        let filter = filters.iter().filter(covers).max_by_key(|f| f.module.len());
+        filter.map_or(Level::from_u8(DEFAULT_LEVEL.load(Ordering::Relaxed)), |f| f.level)
+    });
+    level <= threshold
+}
+
+/// Record a message; use [`klog!`](crate::klog) rather than calling this
+pub fn log(level: Level, path: &'static str, args: fmt::Arguments) {
+    let module = path.split_once("::").map_or(path, |(_, module)| module);
+    if !enabled(level, module) {
+        return;
+    }
+    let mut text = ArrayString::new();
+    let _ = Truncating(&mut text).write_fmt(args);
+    let time = super::clocksource::nanoseconds();
+    let record = interrupts::without_interrupts(|| {
+        let mut ring = RING.lock();
+        let record = Record { sequence: ring.next, time, level, module, text };
+        ring.records[record.sequence as usize % RING_SIZE] = Some(record);
+        ring.next += 1;
+        record
+    });
+    for sink in &SINKS {
+        if level as u8 <= sink.level.load(Ordering::Relaxed) {
+            (sink.write)(&record);
+        }
+    }
+}
+
+/// The oldest record still kept with a sequence number of at least
+/// `sequence`
+pub fn record(sequence: u64) -> Option<Record> {
+    interrupts::without_interrupts(|| RING.lock().from(sequence))
+}
+
+/// Print the last `count` records straight to the serial port, for the
+/// panic handler; nothing if the log is locked
+pub fn dump(count: usize) {
+    let Some(ring) = RING.try_lock() else {
+        return;
+    };
+    let mut sequence = ring.next.saturating_sub(count as u64);
+    crate::serial_println!("Last kernel log records:");
+    while let Some(record) = ring.from(sequence) {
+        crate::serial_println!("  {}", record);
+        sequence = record.sequence + 1;
+    }
+}
+
+/// Keep messages from `module` and the modules under it up to `level`
+pub fn set_filter(module: &str, level: Level) -> Result<(), KlogError> {
+    let module = ArrayString::from(module).map_err(|_| KlogError::ModuleTooLong)?;
+    interrupts::without_interrupts(|| {
+        let mut filters = FILTERS.lock();
+        match filters.iter_mut().find(|f| f.module == module) {
+            Some(filter) => filter.level = level,
+            None => filters.try_push(Filter { module, level }).map_err(|_| KlogError::TooManyFilters)?,
+        }
+        Ok(())
+    })
+}
+
+/// Set the level kept for modules no filter covers
+pub fn set_default_level(level: Level) {
+    DEFAULT_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Write records to sink `name` up to `level`
+pub fn set_sink_level(name: &str, level: Level) -> Result<(), KlogError> {
+    let sink = SINKS.iter().find(|s| s.name == name).ok_or(KlogError::NoSuchSink)?;
+    sink.level.store(level as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Format records from `sequence` on into `out`, a line each, as
+/// `<priority>,<sequence>,<microseconds>,-;<module>: <text>`; returns how
+/// many bytes were written, whole lines only
+pub fn read(mut sequence: u64, out: &mut [u8]) -> usize {
+    let mut written = 0;
+    while let Some(record) = record(sequence) {
+        let mut line = ArrayString::<{ TEXT_SIZE + MODULE_SIZE + 48 }>::new();
+        let _ = writeln!(
+            line,
+            "{},{},{},-;{}: {}",
+            record.level.priority(),
+            record.sequence,
+            record.time / 1000,
+            record.module,
+            record.text
+        );
+        let Some(space) = out.get_mut(written..written + line.len()) else {
+            break;
+        };
+        space.copy_from_slice(line.as_bytes());
+        written += line.len();
+        sequence = record.sequence + 1;
+    }
+    written
+}
+
+/// Apply the levels and filters on the command line
+pub fn init() {
+    use crate::boot::cmdline;
+
+    if let Some(level) = cmdline::get("loglevel").and_then(Level::parse) {
+        set_default_level(level);
+    }
+    for sink in &SINKS {
+        let mut key = ArrayString::<16>::new();
+        let _ = write!(key, "klog.{}", sink.name);
+        if let Some(level) = cmdline::get(&key).and_then(Level::parse) {
+            sink.level.store(level as u8, Ordering::Relaxed);
+        }
+    }
+    for filter in cmdline::get("klog").into_iter().flat_map(|value| value.split(',')) {
+        let applied = filter
+            .split_once(':')
+            .and_then(|(module, level)| Some((module, Level::parse(level)?)))
+            .map(|(module, level)| set_filter(module, level));
+        match applied {
+            Some(Ok(())) => {}
+            Some(Err(e)) => crate::klog!(Warn, "Log filter {} not set: {:?}", filter, e),
+            None => crate::klog!(Warn, "Log filter {} is not <module>:<level>", filter),
+        }
+    }
+}
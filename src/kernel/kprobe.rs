@@ -0,0 +1,271 @@
+//! Dynamic probes on kernel functions
+//!
+//! A probe replaces the first byte of an instruction with `int3`. When a
+//! CPU reaches it, the breakpoint exception runs the probe's handler with
+//! the registers as they were, which at a function's entry hold its
+//! arguments, then puts the original byte back and single-steps it in
+//! place with interrupts off; the debug exception that follows puts the
+//! `int3` back. Another CPU that runs the instruction during that step
+//! goes past the probe without a hit.
+//!
+//! Probes go on functions named in the symbol table (see
+//! [`super::backtrace`]), at their start or an offset that must start an
+//! instruction. Code the probes themselves run through cannot be probed,
+//! nor instructions that single-stepping would upset. The default
+//! handler, [`log_arguments`], records a [`ProbeHit`](super::trace::Event)
+//! when the `probe` trace subsystem is on. Probes can be put in from the
+//! shell, or at boot with `kprobe=function,function+0x10,...`.
+
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, Ordering};
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::rflags::RFlags;
+
+use super::percpu::{self, MAX_CPUS};
+use crate::userspace::usermode::UserContext;
+
+/// Probes that can be in place at once
+pub const MAX_PROBES: usize = 32;
+
+const INT3: u8 = 0xCC;
+
+/// Called on each hit with the probed address and the registers, `rip`
+/// pointing at the probed instruction
+pub type Handler = fn(u64, &UserContext);
+
+/// Code a probe in would be reached again while handling the probe
+const FORBIDDEN: [&str; 5] = [
+    "::kernel::kprobe::",
+    "::kernel::interrupts::",
+    "::kernel::trace::",
+    "::kernel::percpu::",
+    "::userspace::process::current_thread",
+];
+
+/// Crates probes run through, and the exception entry stubs
+const FORBIDDEN_PREFIXES: [&str; 4] = ["core::", "x86_64::", "compiler_builtins::", "exception_"];
+
+#[derive(Debug)]
+pub enum ProbeError {
+    /// The address is not in a function the symbol table names
+    NoSymbol,
+    /// The function is on the path probes are handled on
+    Forbidden,
+    /// The instruction cannot be single-stepped
+    Unprobeable,
+    AlreadyProbed,
+    TooManyProbes,
+    NotProbed,
+}
+
+struct Slot {
+    /// Probed address, 0 while the slot is free
+    address: AtomicU64,
+    /// Byte the `int3` replaced
+    original: AtomicU8,
+    handler: AtomicPtr<()>,
+    hits: AtomicU64,
+    /// Set while the probe is being taken out
+    removing: AtomicBool,
+}
+
+static SLOTS: [Slot; MAX_PROBES] = [const {
+    Slot {
+        address: AtomicU64::new(0),
+        original: AtomicU8::new(0),
+        handler: AtomicPtr::new(core::ptr::null_mut()),
+        hits: AtomicU64::new(0),
+        removing: AtomicBool::new(false),
+    }
+}; MAX_PROBES];
+
+/// Held while probes are put in or taken out
+static WRITER: Mutex<()> = Mutex::new(());
+
+/// Probed address each CPU is handling, 0 if none
+static STEPPING: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Whether interrupts were enabled where each CPU hit its probe
+static STEP_INTERRUPTS: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// A probe in place
+#[derive(Clone, Copy, Debug)]
+pub struct ProbeInfo {
+    pub address: u64,
+    pub hits: u64,
+}
+
+/// Record the probed address and the first three arguments
+pub fn log_arguments(address: u64, context: &UserContext) {
+    crate::trace!(ProbeHit, address, context.rdi, context.rsi, context.rdx);
+}
+
+/// Write a byte of kernel code, which is mapped read-only
+fn poke(address: u64, byte: u8) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let cr0 = Cr0::read();
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+        core::ptr::write_volatile(address as *mut u8, byte);
+        Cr0::write(cr0);
+    });
+}
+
+fn peek(address: u64) -> u8 {
+    unsafe { core::ptr::read_volatile(address as *const u8) }
+}
+
+/// Whether single-stepping the instruction at `address` would go wrong:
+/// it changes or saves the flags the step relies on, or halts with
+/// interrupts off
+fn unsteppable(address: u64) -> bool {
+    let (first, second) = (peek(address), peek(address + 1));
+    let rex_w = first & 0xF8 == 0x48;
+    matches!(first, 0x9C | 0x9D | 0xCF | 0xFA | 0xFB | 0xF4 | INT3)
+        || (rex_w && matches!(second, 0x9C | 0x9D | 0xCF))
+        || (first == 0x0F && second == 0x07)
+        || (rex_w && second == 0x0F && peek(address + 2) == 0x07)
+}
+
+fn find(address: u64) -> Option<&'static Slot> {
+    SLOTS.iter().find(|slot| slot.address.load(Ordering::Acquire) == address)
+}
+
+/// The address `spec` names: a function, a function plus an offset as
+/// `name+0x10`, or a number
+pub fn parse(spec: &str) -> Option<u64> {
+    if let Some(hex) = spec.strip_prefix("0x") {
+        return u64::from_str_radix(hex, 16).ok();
+    }
+    let (name, offset) = match spec.rsplit_once('+') {
+        Some((name, offset)) => {
+            let offset = match offset.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+                None => offset.parse().ok()?,
+            };
+            (name, offset)
+        }
+        None => (spec, 0),
+    };
+    Some(super::backtrace::lookup(name)? + offset)
+}
+
+/// Put a probe at `address` that calls `handler` on each hit
+pub fn register(address: u64, handler: Handler) -> Result<(), ProbeError> {
+    let symbol = super::backtrace::resolve(address).ok_or(ProbeError::NoSymbol)?;
+    if FORBIDDEN.iter().any(|path| symbol.name.contains(path))
+        || FORBIDDEN_PREFIXES.iter().any(|prefix| symbol.name.starts_with(prefix))
+    {
+        return Err(ProbeError::Forbidden);
+    }
+
+    let _writer = WRITER.lock();
+    if find(address).is_some() {
+        return Err(ProbeError::AlreadyProbed);
+    }
+    if unsteppable(address) {
+        return Err(ProbeError::Unprobeable);
+    }
+    let slot = SLOTS
+        .iter()
+        .find(|slot| slot.address.load(Ordering::Relaxed) == 0)
+        .ok_or(ProbeError::TooManyProbes)?;
+    slot.original.store(peek(address), Ordering::Relaxed);
+    slot.handler.store(handler as *mut (), Ordering::Relaxed);
+    slot.hits.store(0, Ordering::Relaxed);
+    slot.removing.store(false, Ordering::Relaxed);
+    slot.address.store(address, Ordering::Release);
+    poke(address, INT3);
+    Ok(())
+}
+
+/// Take out the probe at `address`, once no CPU is handling it
+pub fn unregister(address: u64) -> Result<(), ProbeError> {
+    let _writer = WRITER.lock();
+    let slot = find(address).ok_or(ProbeError::NotProbed)?;
+    slot.removing.store(true, Ordering::SeqCst);
+    while STEPPING.iter().any(|stepping| stepping.load(Ordering::SeqCst) == address) {
+        core::hint::spin_loop();
+    }
+    poke(address, slot.original.load(Ordering::Relaxed));
+    slot.address.store(0, Ordering::Release);
+    Ok(())
+}
+
+/// Every probe in place
+pub fn list() -> ArrayVec<ProbeInfo, MAX_PROBES> {
+    SLOTS
+        .iter()
+        .filter_map(|slot| {
+            let address = slot.address.load(Ordering::Acquire);
+            (address != 0).then(|| ProbeInfo { address, hits: slot.hits.load(Ordering::Relaxed) })
+        })
+        .collect()
+}
+
+/// Handle a breakpoint in the kernel, returning whether it was a probe's
+pub fn breakpoint(context: &mut UserContext) -> bool {
+    let address = context.rip - 1;
+    let cpu = percpu::current_cpu_id() as usize;
+    let Some(slot) = find(address) else {
+        // Taken out after this CPU reached it: run what was put back
+        if peek(address) != INT3 {
+            context.rip = address;
+            return true;
+        }
+        return false;
+    };
+    let stepping = STEPPING[cpu].load(Ordering::Relaxed);
+    if stepping != 0 {
+        panic!("kprobe: probe at {:#x} hit while handling the one at {:#x}", address, stepping);
+    }
+    context.rip = address;
+    STEPPING[cpu].store(address, Ordering::SeqCst);
+    if slot.removing.load(Ordering::SeqCst) {
+        // Try again once the original byte is back
+        STEPPING[cpu].store(0, Ordering::SeqCst);
+        return true;
+    }
+
+    slot.hits.fetch_add(1, Ordering::Relaxed);
+    let handler: Handler = unsafe { core::mem::transmute(slot.handler.load(Ordering::Relaxed)) };
+    handler(address, context);
+
+    poke(address, slot.original.load(Ordering::Relaxed));
+    let flags = RFlags::from_bits_truncate(context.rflags);
+    STEP_INTERRUPTS[cpu].store(flags.contains(RFlags::INTERRUPT_FLAG), Ordering::Relaxed);
+    context.rflags = ((flags | RFlags::TRAP_FLAG) - RFlags::INTERRUPT_FLAG).bits();
+    true
+}
+
+/// Handle a debug exception in the kernel, returning whether it ended
+/// the step over a probed instruction
+pub fn debug(context: &mut UserContext) -> bool {
+    let cpu = percpu::current_cpu_id() as usize;
+    let address = STEPPING[cpu].load(Ordering::SeqCst);
+    if address == 0 {
+        return false;
+    }
+    if find(address).is_some_and(|slot| !slot.removing.load(Ordering::SeqCst)) {
+        poke(address, INT3);
+    }
+    let mut flags = RFlags::from_bits_truncate(context.rflags) - RFlags::TRAP_FLAG;
+    flags.set(RFlags::INTERRUPT_FLAG, STEP_INTERRUPTS[cpu].load(Ordering::Relaxed));
+    context.rflags = flags.bits();
+    STEPPING[cpu].store(0, Ordering::SeqCst);
+    true
+}
+
+/// Put in the probes named on the command line
+pub fn init() {
+    let Some(list) = crate::boot::cmdline::get("kprobe") else {
+        return;
+    };
+    for spec in list.split(',').filter(|spec| !spec.is_empty()) {
+        match parse(spec).ok_or(ProbeError::NoSymbol).and_then(|address| register(address, log_arguments)) {
+            Ok(()) => crate::klog!(Info, "kprobe: probing {}", spec),
+            Err(e) => crate::klog!(Warn, "kprobe: cannot probe {}: {:?}", spec, e),
+        }
+    }
+}
@@ -0,0 +1,350 @@
+//! Machine checks
+//!
+//! The processor reports the hardware errors it detects in its
+//! machine-check banks, each a status, an address and more for one unit.
+//! Errors the hardware corrected raise nothing: a timer polls the boot
+//! CPU's banks every [`POLL_SECONDS`] and logs what it finds to the kernel
+//! log and the audit log. An uncorrected error raises a machine-check
+//! exception on the CPU that met it.
+//!
+//! The exception is fatal when the processor's state is corrupt, or it
+//! cannot resume where it was, or the error was consumed by the kernel.
+//! Consumed by a program, it stops the program. A memory error that was
+//! not consumed, such as one found by a scrubber, costs nothing but its
+//! frame. Either way the frame with the error is retired from the frame
+//! allocator, so its contents are not handed to anyone again.
+//!
+//! Like an NMI, the exception can land with any lock held. It records its
+//! errors without locks; the next poll logs them, and retires the frames
+//! the exception found the allocator too busy for.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+use super::cpu::{self, Feature};
+use super::{hrtimer, memory, percpu};
+use crate::capability::{self, AuditEntry, AUDIT_CORRECTED, AUDIT_MACHINE_CHECK, AUDIT_UNCORRECTED};
+use crate::userspace::usermode::UserContext;
+
+/// How often the banks are polled for corrected errors
+pub const POLL_SECONDS: u64 = 10;
+
+/// Banks looked at, at most
+const MAX_BANKS: u32 = 32;
+
+/// Errors the exception can keep until the next poll
+const PENDING_ERRORS: usize = 16;
+
+const MACHINE_CHECK: u8 = 18;
+
+/// Global MSRs
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17A;
+const IA32_MCG_CTL: u32 = 0x17B;
+
+/// Bank MSRs, four to a bank from bank 0's control
+const IA32_MC0_CTL: u32 = 0x400;
+const BANK_CTL: u32 = 0;
+const BANK_STATUS: u32 = 1;
+const BANK_ADDR: u32 = 2;
+const BANK_MISC: u32 = 3;
+
+/// IA32_MCG_CAP bits
+const MCG_BANKS: u64 = 0xFF;
+const MCG_CTL_P: u64 = 1 << 8;
+/// Software error recovery: uncorrected errors say whether they need action
+const MCG_SER_P: u64 = 1 << 24;
+
+/// IA32_MCG_STATUS bits
+const MCG_RIPV: u64 = 1 << 0;
+const MCG_EIPV: u64 = 1 << 1;
+
+/// Bank status bits
+const STATUS_VAL: u64 = 1 << 63;
+const STATUS_OVER: u64 = 1 << 62;
+const STATUS_UC: u64 = 1 << 61;
+const STATUS_MISCV: u64 = 1 << 59;
+const STATUS_ADDRV: u64 = 1 << 58;
+const STATUS_PCC: u64 = 1 << 57;
+/// Action required: the error was consumed
+const STATUS_AR: u64 = 1 << 55;
+
+/// The address mode in a bank's MISC register that says ADDR is physical
+const MISC_ADDRESS_PHYSICAL: u64 = 2;
+
+#[derive(Debug)]
+pub enum MceError {
+    /// The processor has no machine-check architecture
+    Unsupported,
+}
+
+/// One error out of a bank
+#[derive(Clone, Copy, Debug)]
+struct BankError {
+    cpu: u32,
+    bank: u32,
+    status: u64,
+    /// Physical address of the error, if the bank gave one
+    address: Option<u64>,
+}
+
+impl BankError {
+    fn read(bank: u32) -> Option<BankError> {
+        let status = read(bank, BANK_STATUS);
+        if status & STATUS_VAL == 0 {
+            return None;
+        }
+        let physical = status & STATUS_MISCV == 0 || (read(bank, BANK_MISC) >> 6) & 0b111 == MISC_ADDRESS_PHYSICAL;
+        let address = (status & STATUS_ADDRV != 0 && physical).then(|| read(bank, BANK_ADDR));
+        Some(BankError { cpu: percpu::current_cpu_id(), bank, status, address })
+    }
+
+    fn corrected(&self) -> bool {
+        self.status & STATUS_UC == 0
+    }
+
+    /// The MCA error code
+    fn code(&self) -> u16 {
+        self.status as u16
+    }
+
+    /// A memory controller error, or a cache error, with the address of
+    /// the memory
+    fn memory(&self) -> Option<u64> {
+        let code = self.code();
+        let memory = code & 0xEF80 == 0x0080 || code & 0xEF00 == 0x0100;
+        self.address.filter(|_| memory)
+    }
+
+    fn log(&self) {
+        let (what, result) = if self.corrected() {
+            ("Corrected", AUDIT_CORRECTED)
+        } else {
+            ("Uncorrected", AUDIT_UNCORRECTED)
+        };
+        crate::klog!(
+            Warn,
+            "{} machine check on CPU {} bank {}: status {:#018x} (code {:#06x}{}), address {:x?}",
+            what,
+            self.cpu,
+            self.bank,
+            self.status,
+            self.code(),
+            if self.status & STATUS_OVER != 0 { ", more lost" } else { "" },
+            self.address
+        );
+        capability::audit_log(AuditEntry {
+            timestamp: crate::scheduler::ticks(),
+            process_id: 0,
+            action: AUDIT_MACHINE_CHECK | self.bank,
+            result,
+            signature: [0; 16],
+        });
+    }
+}
+
+/// An error the exception recorded, for the next poll
+struct Pending {
+    /// 0 free, 1 being written, 2 ready
+    state: AtomicU8,
+    cpu_bank: AtomicU64,
+    status: AtomicU64,
+    address: AtomicU64,
+    /// Whether its frame is still to be retired
+    retire: AtomicBool,
+}
+
+static PENDING: [Pending; PENDING_ERRORS] = [const {
+    Pending {
+        state: AtomicU8::new(0),
+        cpu_bank: AtomicU64::new(0),
+        status: AtomicU64::new(0),
+        address: AtomicU64::new(0),
+        retire: AtomicBool::new(false),
+    }
+}; PENDING_ERRORS];
+
+/// Errors the exception could not keep
+static LOST: AtomicU64 = AtomicU64::new(0);
+
+static BANKS: AtomicU64 = AtomicU64::new(0);
+static SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+fn read(bank: u32, register: u32) -> u64 {
+    unsafe { Msr::new(IA32_MC0_CTL + bank * 4 + register).read() }
+}
+
+fn write(bank: u32, register: u32, value: u64) {
+    unsafe { Msr::new(IA32_MC0_CTL + bank * 4 + register).write(value) }
+}
+
+fn banks() -> u32 {
+    BANKS.load(Ordering::Relaxed) as u32
+}
+
+/// Turn on every bank's error reporting and the exception on this CPU
+fn enable() {
+    let cap = unsafe { Msr::new(IA32_MCG_CAP).read() };
+    if cap & MCG_CTL_P != 0 {
+        unsafe { Msr::new(IA32_MCG_CTL).write(u64::MAX) };
+    }
+    for bank in 0..banks() {
+        write(bank, BANK_CTL, u64::MAX);
+    }
+    unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION)) };
+}
+
+/// Keep an error for the next poll to log, and retire its frame
+fn keep(error: &BankError, retire: bool) {
+    let Some(slot) = PENDING
+        .iter()
+        .find(|slot| slot.state.compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed).is_ok())
+    else {
+        LOST.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+    slot.cpu_bank.store((error.cpu as u64) << 32 | error.bank as u64, Ordering::Relaxed);
+    slot.status.store(error.status, Ordering::Relaxed);
+    slot.address.store(error.address.unwrap_or(u64::MAX), Ordering::Relaxed);
+    slot.retire.store(retire, Ordering::Relaxed);
+    slot.state.store(2, Ordering::Release);
+}
+
+fn retire(address: u64) -> bool {
+    match memory::retire_frame(PhysAddr::new(address)) {
+        Ok(true) => {
+            crate::klog!(Warn, "Machine check: retired the frame at {:#x}", address & !0xFFF);
+            true
+        }
+        Ok(false) => true,
+        Err(memory::MapError::AllocatorBusy) => false,
+        Err(e) => {
+            crate::klog!(Error, "Machine check: frame at {:#x} not retired: {:?}", address & !0xFFF, e);
+            true
+        }
+    }
+}
+
+/// Handle a machine-check exception, with the interrupted registers;
+/// returns if the interrupted code can go on
+pub fn exception(context: &UserContext) {
+    let mcg_status = unsafe { Msr::new(IA32_MCG_STATUS).read() };
+    let ser = unsafe { Msr::new(IA32_MCG_CAP).read() } & MCG_SER_P != 0;
+    let mut fatal = (mcg_status & MCG_RIPV == 0).then_some("cannot resume");
+    let mut culprit = None;
+    let mut stop_program = false;
+
+    for bank in 0..banks() {
+        let Some(error) = BankError::read(bank) else {
+            continue;
+        };
+        write(bank, BANK_STATUS, 0);
+        let mut retire_later = false;
+        if !error.corrected() {
+            // Without recovery support an uncorrected error counts as
+            // consumed wherever the exception points at an instruction
+            let consumed = error.status & STATUS_AR != 0 || (!ser && mcg_status & MCG_EIPV != 0);
+            if error.status & STATUS_PCC != 0 {
+                fatal = Some("processor context corrupt");
+                culprit = Some(error);
+            } else if consumed && !context.is_user() {
+                fatal = Some("error consumed by the kernel");
+                culprit = Some(error);
+            } else if consumed {
+                stop_program = true;
+            }
+            if let Some(address) = error.memory() {
+                retire_later = memory::retire_frame(PhysAddr::new(address)).is_err();
+            }
+        }
+        keep(&error, retire_later);
+    }
+
+    if let Some(reason) = fatal {
+        let serial = &crate::boot::serial::SERIAL1;
+        if serial.is_locked() {
+            unsafe { serial.force_unlock() };
+        }
+        match culprit {
+            Some(error) => panic!(
+                "MACHINE CHECK: {} on CPU {} bank {}: status {:#018x}, address {:x?}\n{:#?}",
+                reason, error.cpu, error.bank, error.status, error.address, context
+            ),
+            None => panic!("MACHINE CHECK: {}\n{:#?}", reason, context),
+        }
+    }
+    unsafe { Msr::new(IA32_MCG_STATUS).write(0) };
+
+    if stop_program {
+        crate::userspace::usermode::check_fault(MACHINE_CHECK, context, None, None);
+    }
+}
+
+/// Log what the exception recorded and the corrected errors in this
+/// CPU's banks, and retire the frames left to retire
+pub fn poll() {
+    for slot in PENDING.iter().filter(|slot| slot.state.load(Ordering::Acquire) == 2) {
+        let cpu_bank = slot.cpu_bank.load(Ordering::Relaxed);
+        let address = slot.address.load(Ordering::Relaxed);
+        let error = BankError {
+            cpu: (cpu_bank >> 32) as u32,
+            bank: cpu_bank as u32,
+            status: slot.status.load(Ordering::Relaxed),
+            address: (address != u64::MAX).then_some(address),
+        };
+        if slot.retire.load(Ordering::Relaxed) && !error.address.is_some_and(retire) {
+            // Logged once it is retired
+            continue;
+        }
+        error.log();
+        slot.state.store(0, Ordering::Release);
+    }
+    let lost = LOST.swap(0, Ordering::Relaxed);
+    if lost > 0 {
+        crate::klog!(Warn, "{} machine check errors lost", lost);
+    }
+
+    for bank in 0..banks() {
+        let Some(error) = BankError::read(bank) else {
+            continue;
+        };
+        write(bank, BANK_STATUS, 0);
+        // Uncorrected, but left for software to find
+        if let Some(address) = error.memory().filter(|_| !error.corrected()) {
+            if !retire(address) {
+                keep(&error, true);
+                continue;
+            }
+        }
+        error.log();
+    }
+}
+
+fn poll_timer(_data: u64) {
+    poll();
+    let _ = hrtimer::start_after(POLL_SECONDS * 1_000_000_000, poll_timer, 0);
+}
+
+/// Turn machine checks on for the boot CPU and start polling its banks,
+/// which logs any errors left from before boot; returns the banks
+pub fn init() -> Result<u32, MceError> {
+    if !cpu::has(Feature::Mce) || !cpu::has(Feature::Mca) {
+        return Err(MceError::Unsupported);
+    }
+    let cap = unsafe { Msr::new(IA32_MCG_CAP).read() };
+    BANKS.store((cap & MCG_BANKS).min(MAX_BANKS as u64), Ordering::Relaxed);
+    SUPPORTED.store(true, Ordering::Relaxed);
+    enable();
+    poll_timer(0);
+    Ok(banks())
+}
+
+/// Turn machine checks on for an application processor
+pub fn init_ap() {
+    if SUPPORTED.load(Ordering::Relaxed) {
+        enable();
+    }
+}
@@ -1,7 +1,7 @@
 //! Memory management subsystem
 
+use arrayvec::ArrayVec;
 use bootloader::BootInfo;
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 use x86_64::{
     structures::paging::{
         FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
@@ -10,6 +10,30 @@ use x86_64::{
 };
 use spin::Mutex;
 use lazy_static::lazy_static;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::regions::{self, Region, MAX_REGIONS};
+
+/// Start of the kernel virtual range used for on-demand mappings
+pub const KERNEL_REGION_START: u64 = 0x_5555_0000_0000;
+/// Size of the on-demand mapping range (64 GiB)
+pub const KERNEL_REGION_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
+/// Next free address in the on-demand mapping range
+static NEXT_REGION_ADDR: AtomicU64 = AtomicU64::new(KERNEL_REGION_START);
+
+/// Kernel stacks with a guard page: one per processor and a few more
+pub const MAX_GUARDED_STACKS: usize = super::percpu::MAX_CPUS + 8;
+
+/// The guard page under each stack from [`allocate_stack`], with what the
+/// stack is for
+static STACK_GUARDS: Mutex<ArrayVec<(u64, &'static str), MAX_GUARDED_STACKS>> = Mutex::new(ArrayVec::new_const());
+
+/// Frames that can be taken out of use for memory errors
+pub const MAX_RETIRED_FRAMES: usize = 64;
+
+/// Virtual address at which all physical memory is mapped
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
 
 /// Global frame allocator
 pub static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
@@ -20,12 +44,15 @@ pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
 /// Initialize memory management
 pub fn init(boot_info: &'static BootInfo) {
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
     let level_4_table = unsafe { active_level_4_table(phys_mem_offset) };
     let mapper = unsafe { OffsetPageTable::new(level_4_table, phys_mem_offset) };
     
     *MAPPER.lock() = Some(mapper);
-    
-    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    // The ACPI tables are read through the physical memory mapping
+    regions::init(&boot_info.memory_map);
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init() };
     *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
 }
 
@@ -42,26 +69,36 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr
 }
 
-/// Frame allocator that uses bootloader's memory map
+/// Frame allocator over the usable memory of the bootloader's memory map,
+/// as the region registry leaves it
+///
+/// Freed frames are kept on a stack linked through the frames themselves
+/// and handed out again before any new frame. Retired frames, with memory
+/// errors, are never handed out again.
 pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
+    usable: ArrayVec<Region, MAX_REGIONS>,
     next: usize,
+    free: Option<PhysFrame>,
+    /// Frames on the free list
+    freed: usize,
+    retired: ArrayVec<PhysFrame, MAX_RETIRED_FRAMES>,
 }
 
 impl BootInfoFrameAllocator {
-    /// Create a new frame allocator from the bootloader memory map
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+    /// Create a new frame allocator from the region registry
+    pub unsafe fn init() -> Self {
         BootInfoFrameAllocator {
-            memory_map,
+            usable: regions::usable().collect(),
             next: 0,
+            free: None,
+            freed: 0,
+            retired: ArrayVec::new_const(),
         }
     }
 
     /// Returns an iterator over the usable frames
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
+        let addr_ranges = self.usable.iter().map(|r| r.start..r.end);
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
@@ -69,21 +106,130 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        if let Some(frame) = self.free {
+            let next = unsafe { *phys_to_virt(frame.start_address()).as_ptr::<u64>() };
+            self.free = (next != u64::MAX).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+            self.freed -= 1;
+            return Some(frame);
+        }
+        loop {
+            let frame = self.usable_frames().nth(self.next);
+            self.next += 1;
+            match frame {
+                Some(frame) if self.retired.contains(&frame) => {}
+                frame => return frame,
+            }
+        }
     }
 }
 
 /// Allocate a physical frame
 pub fn allocate_frame() -> Option<PhysFrame> {
-    FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()
+    let frame = FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()?;
+    crate::trace!(FrameAllocate, frame.start_address().as_u64());
+    Some(frame)
+}
+
+/// Give a frame back to the allocator
+///
+/// The frame must no longer be mapped anywhere.
+pub fn free_frame(frame: PhysFrame) {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let Some(allocator) = allocator.as_mut() else {
+        return;
+    };
+    if allocator.retired.contains(&frame) {
+        return;
+    }
+    let next = allocator.free.map_or(u64::MAX, |f| f.start_address().as_u64());
+    unsafe {
+        *phys_to_virt(frame.start_address()).as_mut_ptr::<u64>() = next;
+    }
+    allocator.free = Some(frame);
+    allocator.freed += 1;
+    crate::trace!(FrameFree, frame.start_address().as_u64());
+}
+
+/// Take the frame holding `address` out of use after a memory error
+///
+/// A frame on the free list is unlinked from it, which reads its link
+/// unless the error is in the cache line that holds it; then the frames
+/// after it on the list are given up instead. One in use is dropped when
+/// it is freed. Returns whether the frame was newly retired. This does
+/// not wait for the allocator, which may be held by whatever a machine
+/// check interrupted.
+pub fn retire_frame(address: PhysAddr) -> Result<bool, MapError> {
+    let frame = PhysFrame::containing_address(address);
+    let mut allocator = FRAME_ALLOCATOR.try_lock().ok_or(MapError::AllocatorBusy)?;
+    let allocator = allocator.as_mut().ok_or(MapError::AllocatorNotInitialized)?;
+    if allocator.retired.contains(&frame) {
+        return Ok(false);
+    }
+    allocator.retired.try_push(frame).map_err(|_| MapError::TooManyRetired)?;
+
+    let link = |frame: PhysFrame| phys_to_virt(frame.start_address()).as_mut_ptr::<u64>();
+    let mut previous: Option<PhysFrame> = None;
+    let mut current = allocator.free;
+    let mut position = 0;
+    while let Some(free) = current {
+        if free == frame {
+            let next = if address.as_u64() & 0xFFF >= 64 {
+                unsafe { *link(free) }
+            } else {
+                allocator.freed = position + 1;
+                u64::MAX
+            };
+            match previous {
+                Some(previous) => unsafe { *link(previous) = next },
+                None => allocator.free = (next != u64::MAX).then(|| PhysFrame::containing_address(PhysAddr::new(next))),
+            }
+            allocator.freed -= 1;
+            break;
+        }
+        let next = unsafe { *link(free) };
+        previous = current;
+        current = (next != u64::MAX).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+        position += 1;
+    }
+    Ok(true)
+}
+
+/// Physical frame usage
+#[derive(Clone, Copy, Debug)]
+pub struct FrameStats {
+    /// Frames the memory map marks usable
+    pub usable: usize,
+    /// Frames handed out and not given back, retired ones among them
+    pub allocated: usize,
+    /// Frames retired after memory errors
+    pub retired: usize,
+}
+
+/// Current frame usage, once the allocator is up
+pub fn frame_stats() -> Option<FrameStats> {
+    let allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_ref()?;
+    let usable = allocator.usable_frames().count();
+    Some(FrameStats {
+        usable,
+        allocated: allocator.next.min(usable) - allocator.freed,
+        retired: allocator.retired.len(),
+    })
 }
 
 /// Map a virtual page to a physical frame
 pub fn map_page(page: Page, frame: PhysFrame) -> Result<(), MapError> {
     use x86_64::structures::paging::PageTableFlags as Flags;
 
+    map_page_with_flags(page, frame, Flags::PRESENT | Flags::WRITABLE)
+}
+
+/// Map a virtual page to a physical frame with explicit flags
+pub fn map_page_with_flags(
+    page: Page,
+    frame: PhysFrame,
+    flags: x86_64::structures::paging::PageTableFlags,
+) -> Result<(), MapError> {
     let mut mapper = MAPPER.lock();
     let mapper = mapper.as_mut().ok_or(MapError::MapperNotInitialized)?;
     
@@ -92,18 +238,161 @@ pub fn map_page(page: Page, frame: PhysFrame) -> Result<(), MapError> {
 
     unsafe {
         mapper
-            .map_to(page, frame, Flags::PRESENT | Flags::WRITABLE, frame_allocator)
+            .map_to(page, frame, flags, frame_allocator)
             .map_err(|_| MapError::MapFailed)?
             .flush();
     }
+    crate::trace!(PageMap, page.start_address().as_u64(), frame.start_address().as_u64(), flags.bits());
 
     Ok(())
 }
 
+/// Translate a physical address through the physical memory mapping
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+/// Translate a kernel virtual address to the physical address it maps
+pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
+    use x86_64::structures::paging::Translate;
+
+    MAPPER.lock().as_ref()?.translate_addr(addr)
+}
+
+/// Allocate `pages` physically contiguous frames ending below `limit`
+///
+/// Frames skipped while looking for a contiguous run are not returned to
+/// the allocator, so this is meant for boot-time pools.
+pub fn allocate_contiguous(pages: u64, limit: u64) -> Result<PhysAddr, MapError> {
+    let mut start = 0;
+    let mut run = 0;
+
+    while run < pages {
+        let frame = allocate_frame().ok_or(MapError::OutOfMemory)?.start_address().as_u64();
+        if frame + 4096 > limit {
+            return Err(MapError::OutOfMemory);
+        }
+        if run > 0 && frame == start + run * 4096 {
+            run += 1;
+        } else {
+            start = frame;
+            run = 1;
+        }
+    }
+
+    Ok(PhysAddr::new(start))
+}
+
+/// Reserve `pages` pages of kernel virtual address space
+fn reserve_region(pages: u64) -> Result<u64, MapError> {
+    let start = NEXT_REGION_ADDR.fetch_add(pages * 4096, Ordering::Relaxed);
+    if start + pages * 4096 > KERNEL_REGION_START + KERNEL_REGION_SIZE {
+        return Err(MapError::RegionExhausted);
+    }
+    Ok(start)
+}
+
+/// Back `pages` pages from `start` with fresh frames
+///
+/// On failure the frames already mapped are unmapped and freed again; the
+/// address range itself stays reserved.
+fn map_fresh(start: u64, pages: u64) -> Result<(), MapError> {
+    for i in 0..pages {
+        let page = Page::containing_address(VirtAddr::new(start + i * 4096));
+        let mapped = allocate_frame()
+            .ok_or(MapError::OutOfMemory)
+            .and_then(|frame| map_page(page, frame).inspect_err(|_| free_frame(frame)));
+        if let Err(e) = mapped {
+            unmap_fresh(start, i);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Undo [`map_fresh`] for the first `pages` pages from `start`
+fn unmap_fresh(start: u64, pages: u64) {
+    for i in 0..pages {
+        let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(start + i * 4096));
+        let unmapped = MAPPER.lock().as_mut().and_then(|mapper| mapper.unmap(page).ok());
+        if let Some((frame, flush)) = unmapped {
+            flush.flush();
+            free_frame(frame);
+        }
+    }
+}
+
+/// Map `size` bytes of fresh, zeroed frames into kernel space
+pub fn allocate_region(size: usize) -> Result<VirtAddr, MapError> {
+    let pages = (size as u64).div_ceil(4096);
+    let start = reserve_region(pages)?;
+    map_fresh(start, pages)?;
+
+    let base = VirtAddr::new(start);
+    unsafe {
+        core::ptr::write_bytes(base.as_mut_ptr::<u8>(), 0, (pages * 4096) as usize);
+    }
+    Ok(base)
+}
+
+/// Back `size` bytes at a fixed kernel address with fresh frames
+pub fn map_range(start: VirtAddr, size: usize) -> Result<(), MapError> {
+    map_fresh(start.as_u64(), (size as u64).div_ceil(4096))
+}
+
+/// Map a kernel stack of `size` bytes with an unmapped guard page below
+/// it, returning its top
+///
+/// Running off the bottom of the stack faults on the guard page instead
+/// of overwriting whatever lies below, and [`stack_guard`] tells the fault
+/// handlers which stack it was.
+pub fn allocate_stack(size: usize, name: &'static str) -> Result<VirtAddr, MapError> {
+    let pages = (size as u64).div_ceil(4096);
+    let guard = reserve_region(pages + 1)?;
+    map_fresh(guard + 4096, pages)?;
+    if STACK_GUARDS.lock().try_push((guard, name)).is_err() {
+        crate::klog!(Warn, "Guard page of the {} stack not recorded", name);
+    }
+    Ok(VirtAddr::new(guard + (pages + 1) * 4096))
+}
+
+/// The stack whose guard page holds `addr`
+///
+/// Called from fault handlers, so it gives up rather than wait for the
+/// lock.
+pub fn stack_guard(addr: u64) -> Option<&'static str> {
+    let guards = STACK_GUARDS.try_lock()?;
+    guards.iter().find(|(guard, _)| (*guard..*guard + 4096).contains(&addr)).map(|(_, name)| *name)
+}
+
+/// Map a device register window uncached into kernel space
+pub fn map_mmio(phys: PhysAddr, size: usize) -> Result<VirtAddr, MapError> {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let offset = phys.as_u64() % 4096;
+    let pages = (offset + size as u64).div_ceil(4096);
+    let start = reserve_region(pages)?;
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_CACHE | Flags::WRITE_THROUGH;
+
+    for i in 0..pages {
+        let page = Page::containing_address(VirtAddr::new(start + i * 4096));
+        let frame = PhysFrame::containing_address(PhysAddr::new(phys.as_u64() - offset + i * 4096));
+        map_page_with_flags(page, frame, flags)?;
+    }
+
+    Ok(VirtAddr::new(start + offset))
+}
+
 /// Memory mapping errors
 #[derive(Debug)]
 pub enum MapError {
     MapperNotInitialized,
     AllocatorNotInitialized,
     MapFailed,
+    OutOfMemory,
+    RegionExhausted,
+    /// No room to record another retired frame
+    TooManyRetired,
+    /// The frame allocator is locked
+    AllocatorBusy,
 }
@@ -1,11 +1,39 @@
 //! Core kernel subsystem - Microkernel implementation
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod backtrace;
+pub mod clocksource;
+pub mod cpu;
+pub mod dma;
 pub mod edge_registry;
+pub mod gdt;
+pub mod hpet;
+pub mod hrtimer;
+pub mod input;
+pub mod ioapic;
 pub mod interrupts;
+pub mod klog;
+pub mod kprobe;
 pub mod lazy_pool;
+pub mod mce;
 pub mod memory;
+pub mod msi;
+pub mod nmi;
+pub mod pci;
 pub mod percpu;
+pub mod pmu;
+pub mod power;
+pub mod random;
+pub mod rcu;
+pub mod regions;
+pub mod smp;
+pub mod softirq;
+pub mod sync;
+pub mod trace;
+pub mod virtio;
+pub mod watchdog;
 
 use bootloader::BootInfo;
 
@@ -13,22 +41,83 @@ use bootloader::BootInfo;
 pub fn init(boot_info: &'static BootInfo) {
     // Detect firmware type
     let firmware = crate::boot::detect_firmware();
-    crate::serial_println!("Firmware: {:?}", firmware);
+    crate::klog!(Info, "Firmware: {:?}", firmware);
 
     // Verify secure boot if enabled
     if let Err(e) = crate::boot::verify_secure_boot() {
-        crate::serial_println!("Secure boot verification: {:?}", e);
+        crate::klog!(Info, "Secure boot verification: {:?}", e);
     }
 
     // Initialize per-CPU first (needed by other subsystems)
     percpu::init();
 
+    // What the processor can do, which much of what follows depends on
+    cpu::init();
+
+    // Seed random numbers before anything that needs them
+    let sources = random::init();
+    crate::klog!(Info, "Random numbers seeded from {:?}", sources);
+
     // Then memory (needs per-CPU for statistics)
     memory::init(boot_info);
+    let usable: u64 = regions::usable().map(|r| r.end - r.start).sum();
+    crate::klog!(Info, "{} memory regions, {} MiB usable", regions::regions().len(), usable >> 20);
+
+    // Bounce buffers have to come from low memory, so reserve them early
+    dma::init();
+
+    // Local APIC (MSI/MSI-X delivery needs its EOI register mapped)
+    apic::init();
+
+    // Lock order checking and tracing, if asked for
+    sync::init();
+    trace::init();
+
+    // Segments and the TSS, so exceptions in user mode find a kernel stack
+    gdt::init();
 
     // Then interrupts
     interrupts::init();
+    nmi::init();
+
+    // Probes asked for at boot, now that their breakpoints are handled
+    kprobe::init();
+
+    // The keyboard, on the line the PICs keep for it
+    if let Err(e) = input::init() {
+        crate::klog!(Warn, "No keyboard interrupts: {:?}", e);
+    }
+
+    // I/O APICs, for devices without MSI
+    match ioapic::init() {
+        Ok(count) => crate::klog!(Info, "{} I/O APICs", count),
+        Err(e) => crate::klog!(Warn, "No I/O APIC routing: {:?}", e),
+    }
+
+    // Clock sources, the best of them picked
+    clocksource::init();
+
+    // One-shot timers on the local APIC timer
+    crate::klog!(Info, "High-resolution timers: {:?}", hrtimer::init());
+
+    // Lockup checks, once the timers they measure by are running
+    crate::klog!(Info, "Watchdog: {:?}", watchdog::init());
+
+    // Performance counters, around the one the watchdog may have taken
+    match pmu::init() {
+        Ok(pmu) => crate::klog!(Info, "Performance counters: {}", pmu),
+        Err(e) => crate::klog!(Info, "No performance counters: {:?}", e),
+    }
+
+    // Machine checks, polled on a timer for the errors corrected quietly
+    match mce::init() {
+        Ok(banks) => crate::klog!(Info, "Machine checks: {} banks", banks),
+        Err(e) => crate::klog!(Info, "No machine checks: {:?}", e),
+    }
 
     // Initialize heap allocator
     allocator::init_heap();
+
+    // Devices on the PCI bus, before any driver registers for them
+    pci::init();
 }
@@ -0,0 +1,262 @@
+//! MSI and MSI-X interrupts
+//!
+//! A PCI driver asks for message-signalled interrupts with [`enable_msix`]
+//! or, for a device with only MSI, [`enable_msi`]. Each interrupt gets a
+//! dynamic vector and is aimed at a CPU: the one asked for, or with
+//! [`Affinity::Spread`] the next online CPU in turn, so that the queues of
+//! an NVMe or virtio device are served by different CPUs. The vector is
+//! the same on every CPU, as they share the IDT; [`set_affinity`] moves an
+//! interrupt to another CPU by rewriting its message. Legacy INTx is turned
+//! off once a device signals by message. MSI is set up for a single message.
+
+use arrayvec::ArrayVec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use super::interrupts::{self, VectorHandler, DYNAMIC_VECTOR_COUNT};
+use super::pci::{self, PciAddress};
+use super::percpu;
+use crate::kernel::apic::MSI_ADDRESS_BASE;
+
+/// Size of one MSI-X table entry
+const MSIX_ENTRY_SIZE: u64 = 16;
+
+/// Vector control: entry masked
+const MSIX_CTRL_MASKED: u32 = 1;
+
+/// MSI-X message control: table size less one, function mask and enable
+const MSIX_TABLE_SIZE: u16 = 0x7FF;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+
+/// MSI message control: enable, messages enabled, and 64-bit addresses
+const MSI_ENABLE: u16 = 1;
+const MSI_MULTIPLE_ENABLE: u16 = 0b111 << 4;
+const MSI_64BIT: u16 = 1 << 7;
+
+/// Interrupts a device can have through this service
+pub const MAX_DEVICE_VECTORS: usize = 16;
+
+/// Which CPU an interrupt is delivered to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Affinity {
+    /// The next online CPU in turn
+    Spread,
+    /// This CPU
+    Cpu(u32),
+}
+
+/// Message address for the CPU with local APIC ID `apic_id`
+fn message_address(apic_id: u32) -> u32 {
+    MSI_ADDRESS_BASE | (apic_id & 0xFF) << 12
+}
+
+/// One entry of a device's memory-mapped MSI-X table
+#[derive(Clone, Copy, Debug)]
+pub struct MsixEntry {
+    /// Kernel virtual address of the MSI-X table
+    pub table: u64,
+    /// Entry index within the table
+    pub index: u16,
+}
+
+impl MsixEntry {
+    fn reg(&self, offset: u64) -> *mut u32 {
+        (self.table + self.index as u64 * MSIX_ENTRY_SIZE + offset) as *mut u32
+    }
+
+    /// Route this entry to `vector` on the CPU with the given APIC ID and unmask it
+    pub fn program(&self, vector: u8, apic_id: u32) {
+        unsafe {
+            self.set_masked(true);
+            core::ptr::write_volatile(self.reg(0), message_address(apic_id));
+            core::ptr::write_volatile(self.reg(4), 0);
+            // Fixed delivery, edge triggered
+            core::ptr::write_volatile(self.reg(8), vector as u32);
+            self.set_masked(false);
+        }
+    }
+
+    /// Mask or unmask the entry
+    ///
+    /// # Safety
+    /// `table` must point at a mapped MSI-X table.
+    pub unsafe fn set_masked(&self, masked: bool) {
+        let ctrl = core::ptr::read_volatile(self.reg(12));
+        let ctrl = if masked {
+            ctrl | MSIX_CTRL_MASKED
+        } else {
+            ctrl & !MSIX_CTRL_MASKED
+        };
+        core::ptr::write_volatile(self.reg(12), ctrl);
+    }
+}
+
+/// Where an interrupt's message is programmed
+#[derive(Clone, Copy, Debug)]
+enum Source {
+    /// The MSI capability at this offset
+    Msi(u8),
+    Msix(MsixEntry),
+}
+
+/// An interrupt handed out
+#[derive(Clone, Copy, Debug)]
+struct Allocation {
+    device: PciAddress,
+    source: Source,
+    vector: u8,
+    cpu: u32,
+}
+
+static ALLOCATIONS: Mutex<ArrayVec<Allocation, DYNAMIC_VECTOR_COUNT>> = Mutex::new(ArrayVec::new_const());
+
+/// CPU [`Affinity::Spread`] picks next
+static NEXT_CPU: AtomicU32 = AtomicU32::new(0);
+
+/// MSI errors
+#[derive(Debug)]
+pub enum MsiError {
+    /// The device has no capability for it
+    Unsupported,
+    /// The MSI-X table is in a BAR that is not memory
+    BadTable,
+    TooManyVectors,
+    NoFreeVector,
+    /// The CPU is not online
+    NoSuchCpu,
+    /// No interrupt has the vector
+    NotAllocated,
+    MapFailed,
+}
+
+/// The CPU and its local APIC ID for `affinity`
+fn target(affinity: Affinity) -> Result<(u32, u32), MsiError> {
+    let cpu = match affinity {
+        Affinity::Spread => NEXT_CPU.fetch_add(1, Ordering::Relaxed) % percpu::online(),
+        Affinity::Cpu(cpu) => cpu,
+    };
+    Ok((cpu, percpu::apic_id(cpu).ok_or(MsiError::NoSuchCpu)?))
+}
+
+/// Aim the MSI capability at `cap` at `vector` on `apic_id`
+fn program_msi(device: PciAddress, cap: u8, vector: u8, apic_id: u32) {
+    let control = device.read16(cap + 2);
+    device.write(cap + 4, message_address(apic_id));
+    let data = if control & MSI_64BIT != 0 {
+        device.write(cap + 8, 0);
+        cap + 12
+    } else {
+        cap + 8
+    };
+    // Fixed delivery, edge triggered
+    device.write16(data, vector as u16);
+    device.write16(cap + 2, (control & !MSI_MULTIPLE_ENABLE) | MSI_ENABLE);
+}
+
+fn record(allocation: Allocation) -> Result<(), MsiError> {
+    ALLOCATIONS.lock().try_push(allocation).map_err(|_| {
+        let _ = interrupts::free_vector(allocation.vector);
+        MsiError::NoFreeVector
+    })
+}
+
+/// Give the device's single MSI message a vector that calls `handler`,
+/// returning the vector
+pub fn enable_msi(device: PciAddress, handler: VectorHandler, affinity: Affinity) -> Result<u8, MsiError> {
+    let cap = device.capability(pci::CAP_MSI).ok_or(MsiError::Unsupported)?;
+    let (cpu, apic_id) = target(affinity)?;
+    let vector = interrupts::allocate_vector(handler).map_err(|_| MsiError::NoFreeVector)?;
+    record(Allocation { device, source: Source::Msi(cap), vector, cpu })?;
+    program_msi(device, cap, vector, apic_id);
+    device.enable(pci::COMMAND_INTX_DISABLE);
+    Ok(vector)
+}
+
+/// Give the first `count` entries of the device's MSI-X table vectors
+/// that call `handler`, returning each entry with its vector
+pub fn enable_msix(
+    device: PciAddress,
+    count: usize,
+    handler: VectorHandler,
+    affinity: Affinity,
+) -> Result<ArrayVec<(MsixEntry, u8), MAX_DEVICE_VECTORS>, MsiError> {
+    let cap = device.capability(pci::CAP_MSIX).ok_or(MsiError::Unsupported)?;
+    let control = device.read16(cap + 2);
+    let size = (control & MSIX_TABLE_SIZE) as usize + 1;
+    if count > size || count > MAX_DEVICE_VECTORS {
+        return Err(MsiError::TooManyVectors);
+    }
+    // The low three bits name the BAR the table is in
+    let table = device.read(cap + 4);
+    let bar = device.bar((table & 0b111) as u8).ok_or(MsiError::BadTable)?;
+    let phys = PhysAddr::new(bar + (table & !0b111) as u64);
+    let base = super::memory::map_mmio(phys, size * MSIX_ENTRY_SIZE as usize).map_err(|_| MsiError::MapFailed)?;
+    device.enable(pci::COMMAND_MEMORY);
+
+    // Masked as a whole while the entries are written
+    device.write16(cap + 2, control | MSIX_ENABLE | MSIX_FUNCTION_MASK);
+    let mut entries = ArrayVec::new();
+    for index in 0..count as u16 {
+        let entry = MsixEntry { table: base.as_u64(), index };
+        let programmed = target(affinity).and_then(|(cpu, apic_id)| {
+            let vector = interrupts::allocate_vector(handler).map_err(|_| MsiError::NoFreeVector)?;
+            record(Allocation { device, source: Source::Msix(entry), vector, cpu })?;
+            entry.program(vector, apic_id);
+            Ok(vector)
+        });
+        match programmed {
+            Ok(vector) => entries.push((entry, vector)),
+            Err(e) => {
+                disable(device);
+                return Err(e);
+            }
+        }
+    }
+    device.write16(cap + 2, (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK);
+    device.enable(pci::COMMAND_INTX_DISABLE);
+    Ok(entries)
+}
+
+/// Deliver the interrupt on `vector` to `cpu` from now on
+pub fn set_affinity(vector: u8, cpu: u32) -> Result<(), MsiError> {
+    let apic_id = percpu::apic_id(cpu).ok_or(MsiError::NoSuchCpu)?;
+    let mut allocations = ALLOCATIONS.lock();
+    let allocation = allocations
+        .iter_mut()
+        .find(|a| a.vector == vector)
+        .ok_or(MsiError::NotAllocated)?;
+    match allocation.source {
+        Source::Msi(cap) => program_msi(allocation.device, cap, vector, apic_id),
+        Source::Msix(entry) => entry.program(vector, apic_id),
+    }
+    allocation.cpu = cpu;
+    Ok(())
+}
+
+/// CPU the interrupt on `vector` is delivered to
+pub fn affinity(vector: u8) -> Option<u32> {
+    ALLOCATIONS.lock().iter().find(|a| a.vector == vector).map(|a| a.cpu)
+}
+
+/// Turn off the device's message-signalled interrupts and free their
+/// vectors
+pub fn disable(device: PciAddress) {
+    if let Some(cap) = device.capability(pci::CAP_MSI) {
+        device.write16(cap + 2, device.read16(cap + 2) & !MSI_ENABLE);
+    }
+    if let Some(cap) = device.capability(pci::CAP_MSIX) {
+        device.write16(cap + 2, device.read16(cap + 2) & !MSIX_ENABLE);
+    }
+    ALLOCATIONS.lock().retain(|a| {
+        if a.device != device {
+            return true;
+        }
+        if let Source::Msix(entry) = a.source {
+            unsafe { entry.set_masked(true) };
+        }
+        let _ = interrupts::free_vector(a.vector);
+        false
+    });
+}
@@ -0,0 +1,191 @@
+//! Non-maskable interrupts
+//!
+//! An NMI comes from the watchdog's performance counter, from the chipset
+//! for a hardware error, or from somewhere this cannot tell, such as
+//! QEMU's monitor. The watchdog's are its own. On the boot CPU, system
+//! control port B says whether the chipset raised one: SERR for a PCI
+//! system error or a memory parity error, IOCHK for an I/O channel check.
+//! Anything else is unknown.
+//!
+//! An NMI can land while its CPU holds any lock, so the handler only
+//! counts what it found and records it in the edge-case registry; the
+//! kernel log hears of it from the NMI softirq. An unknown NMI dumps the
+//! registers on the serial port too, if the port is free. `nmi_panic` on
+//! the command line panics on hardware errors and unknown NMIs instead.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::edge_registry::{self, FILE_NMI};
+use super::softirq::{self, Softirq};
+use super::{backtrace, percpu};
+
+/// System control port B
+const PORT_B: u16 = 0x61;
+
+/// Port B bits: the error lines, and the enables that clear them
+const PORT_B_SERR: u8 = 1 << 7;
+const PORT_B_IOCHK: u8 = 1 << 6;
+const PORT_B_SERR_DISABLE: u8 = 1 << 2;
+const PORT_B_IOCHK_DISABLE: u8 = 1 << 3;
+/// Bits that are written back; the rest read as status
+const PORT_B_WRITABLE: u8 = 0x0F;
+
+/// Where an NMI came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    Watchdog,
+    /// PCI system error or memory parity error
+    Serr,
+    /// I/O channel check
+    Iochk,
+    Unknown,
+}
+
+const SOURCES: usize = 4;
+
+impl Source {
+    pub const ALL: [Source; SOURCES] = [Source::Watchdog, Source::Serr, Source::Iochk, Source::Unknown];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Source::Watchdog => "watchdog",
+            Source::Serr => "serr",
+            Source::Iochk => "iochk",
+            Source::Unknown => "unknown",
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Source::Watchdog => "watchdog",
+            Source::Serr => "PCI system error or memory parity error",
+            Source::Iochk => "I/O channel check",
+            Source::Unknown => "unknown source",
+        }
+    }
+
+    fn edge_case(self) -> Option<u32> {
+        match self {
+            Source::Watchdog => None,
+            Source::Serr => Some(edge_registry::NMI_SERR),
+            Source::Iochk => Some(edge_registry::NMI_IOCHK),
+            Source::Unknown => Some(edge_registry::NMI_UNKNOWN),
+        }
+    }
+}
+
+/// NMIs taken from each source
+static COUNTS: [AtomicU64; SOURCES] = [const { AtomicU64::new(0) }; SOURCES];
+
+/// NMIs from each source the log has not heard of, and where the last
+/// one landed
+static UNLOGGED: [AtomicU64; SOURCES] = [const { AtomicU64::new(0) }; SOURCES];
+static LAST_RIP: [AtomicU64; SOURCES] = [const { AtomicU64::new(0) }; SOURCES];
+
+/// Set from `nmi_panic` on the command line
+static PANIC: AtomicBool = AtomicBool::new(false);
+
+/// NMIs taken from `source` so far
+pub fn count(source: Source) -> u64 {
+    COUNTS[source as usize].load(Ordering::Relaxed)
+}
+
+/// Read port B and clear the error lines it shows, returning the sources
+fn chipset_sources() -> (bool, bool) {
+    let mut port = Port::<u8>::new(PORT_B);
+    let status = unsafe { port.read() };
+    let (serr, iochk) = (status & PORT_B_SERR != 0, status & PORT_B_IOCHK != 0);
+    let mut clear = status & PORT_B_WRITABLE;
+    if serr {
+        clear |= PORT_B_SERR_DISABLE;
+    }
+    if iochk {
+        clear |= PORT_B_IOCHK_DISABLE;
+    }
+    if serr || iochk {
+        unsafe {
+            port.write(clear);
+            port.write(clear & !(PORT_B_SERR_DISABLE | PORT_B_IOCHK_DISABLE));
+        }
+    }
+    (serr, iochk)
+}
+
+fn record(source: Source, frame: &InterruptStackFrame) {
+    let rip = frame.instruction_pointer.as_u64();
+    COUNTS[source as usize].fetch_add(1, Ordering::Relaxed);
+    if let Some(code) = source.edge_case() {
+        let ticks = crate::scheduler::ticks() as u32;
+        edge_registry::register_edge_case(code, FILE_NMI, line!() as u16, ticks);
+        LAST_RIP[source as usize].store(rip, Ordering::Relaxed);
+        UNLOGGED[source as usize].fetch_add(1, Ordering::Release);
+        softirq::raise(Softirq::Nmi);
+    }
+    if source != Source::Watchdog && PANIC.load(Ordering::Relaxed) {
+        let serial = &crate::boot::serial::SERIAL1;
+        if serial.is_locked() {
+            unsafe { serial.force_unlock() };
+        }
+        panic!("NMI: {} at {:#x}", source.describe(), rip);
+    }
+}
+
+/// Handle a non-maskable interrupt
+pub fn handle(frame: &InterruptStackFrame) {
+    if super::watchdog::nmi(frame) {
+        record(Source::Watchdog, frame);
+        return;
+    }
+    let (serr, iochk) = match percpu::current_cpu_id() {
+        0 => chipset_sources(),
+        _ => (false, false),
+    };
+    if serr {
+        record(Source::Serr, frame);
+    }
+    if iochk {
+        record(Source::Iochk, frame);
+    }
+    if serr || iochk {
+        return;
+    }
+
+    record(Source::Unknown, frame);
+    let serial = &crate::boot::serial::SERIAL1;
+    if !serial.is_locked() {
+        crate::serial_println!(
+            "NMI from an unknown source on CPU {}: RIP {:#018x} RSP {:#018x} RFLAGS {:#x}",
+            percpu::current_cpu_id(),
+            frame.instruction_pointer.as_u64(),
+            frame.stack_pointer.as_u64(),
+            frame.cpu_flags.bits()
+        );
+        backtrace::print();
+    }
+}
+
+/// NMI softirq: log the NMIs taken since it last ran
+fn log_unlogged() {
+    for source in Source::ALL {
+        let count = UNLOGGED[source as usize].swap(0, Ordering::Acquire);
+        if count == 0 {
+            continue;
+        }
+        let rip = LAST_RIP[source as usize].load(Ordering::Relaxed);
+        match source {
+            Source::Unknown => {
+                crate::klog!(Warn, "NMI from an unknown source ({} times), the last at {:#x}", count, rip)
+            }
+            _ => crate::klog!(Error, "NMI: {} ({} times), the last at {:#x}", source.describe(), count, rip),
+        }
+    }
+}
+
+/// Log NMIs from the NMI softirq, and read `nmi_panic`
+pub fn init() {
+    PANIC.store(crate::boot::cmdline::flag("nmi_panic"), Ordering::Relaxed);
+    softirq::open(Softirq::Nmi, log_unlogged);
+}
@@ -0,0 +1,559 @@
+//! PCI configuration space and the devices on the bus
+//!
+//! Configuration space is reached through the enhanced mechanism when the
+//! MCFG describes one, a megabyte of memory-mapped registers per bus, and
+//! through the legacy ports otherwise. At boot the bus is walked from the
+//! root through every bridge into a table of functions with their IDs,
+//! class and sized BARs. Drivers register with the IDs or classes they
+//! handle and are probed for each function that matches and has no driver
+//! yet; they are told to let go when the function is removed or the driver
+//! unregistered.
+
+use arrayvec::ArrayVec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, Once};
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+/// Register offsets in the common configuration header
+pub const REG_VENDOR_DEVICE: u8 = 0x00;
+pub const REG_COMMAND: u8 = 0x04;
+pub const REG_CLASS: u8 = 0x08;
+pub const REG_HEADER_TYPE: u8 = 0x0C;
+pub const REG_BAR0: u8 = 0x10;
+/// Bridges: primary, secondary and subordinate bus numbers
+pub const REG_BUS_NUMBERS: u8 = 0x18;
+pub const REG_CAPABILITIES: u8 = 0x34;
+
+/// Header type: a PCI-to-PCI bridge
+const HEADER_BRIDGE: u8 = 0x01;
+/// Header type flag: the device has more than one function
+const HEADER_MULTI_FUNCTION: u8 = 0x80;
+
+/// Command register: respond to I/O accesses
+pub const COMMAND_IO: u16 = 1 << 0;
+/// Command register: respond to memory accesses
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+/// Command register: allow the device to master the bus (DMA)
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+/// Command register: no legacy INTx interrupts
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+/// Status register, the high half of the command register's dword: the
+/// function has a capability list
+const STATUS_CAPABILITIES: u32 = 1 << 20;
+
+/// Capability IDs
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR: u8 = 0x09;
+pub const CAP_MSIX: u8 = 0x11;
+
+/// Functions kept in the device table
+pub const MAX_DEVICES: usize = 64;
+/// Registered drivers
+pub const MAX_DRIVERS: usize = 16;
+
+/// Matches any vendor or device ID in a [`DeviceId`]
+pub const ANY: u16 = 0xFFFF;
+
+/// Bytes of enhanced configuration space per bus
+const ECAM_BUS_SIZE: usize = 1 << 20;
+
+/// Segment group 0's enhanced configuration space from the MCFG: its base
+/// and its first and last bus
+static ECAM: Once<Option<(u64, u8, u8)>> = Once::new();
+/// Where each bus's enhanced configuration space is mapped, 0 if it is not
+static ECAM_BUSES: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// Enumerated functions, in the order they were found
+static DEVICES: Mutex<ArrayVec<Slot, MAX_DEVICES>> = Mutex::new(ArrayVec::new_const());
+static DRIVERS: Mutex<ArrayVec<&'static dyn Driver, MAX_DRIVERS>> = Mutex::new(ArrayVec::new_const());
+
+#[derive(Debug)]
+pub enum PciError {
+    NoSuchDevice,
+    NoSuchDriver,
+    AlreadyRegistered,
+    TooManyDrivers,
+    /// The driver does not handle this particular device
+    Unsupported,
+    ProbeFailed,
+}
+
+/// Location of a function on the PCI bus
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    fn config_address(&self, offset: u8) -> u32 {
+        0x8000_0000
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset as u32 & 0xFC)
+    }
+
+    /// The register in enhanced configuration space, if the bus has it mapped
+    fn ecam_register(&self, offset: u8) -> Option<*mut u32> {
+        let bus = ECAM_BUSES[self.bus as usize].load(Ordering::Acquire);
+        let offset = (self.device as u64) << 15 | (self.function as u64) << 12 | (offset as u64 & 0xFC);
+        (bus != 0).then_some((bus + offset) as *mut u32)
+    }
+
+    /// Read a 32-bit configuration register
+    pub fn read(&self, offset: u8) -> u32 {
+        if let Some(register) = self.ecam_register(offset) {
+            return unsafe { core::ptr::read_volatile(register) };
+        }
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).read()
+        }
+    }
+
+    /// Write a 32-bit configuration register
+    pub fn write(&self, offset: u8, value: u32) {
+        if let Some(register) = self.ecam_register(offset) {
+            return unsafe { core::ptr::write_volatile(register, value) };
+        }
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).write(value);
+        }
+    }
+
+    /// Vendor and device ID, or `None` if no function is present
+    pub fn id(&self) -> Option<(u16, u16)> {
+        let id = self.read(REG_VENDOR_DEVICE);
+        let vendor = id as u16;
+        (vendor != 0xFFFF).then_some((vendor, (id >> 16) as u16))
+    }
+
+    /// Physical base address of a memory BAR
+    pub fn bar(&self, index: u8) -> Option<u64> {
+        let offset = REG_BAR0 + index * 4;
+        let low = self.read(offset);
+        if low & 1 != 0 {
+            // I/O space BAR
+            return None;
+        }
+
+        let base = (low & !0xF) as u64;
+        if (low >> 1) & 0b11 == 0b10 {
+            // 64-bit BAR spans the next register too
+            Some(base | (self.read(offset + 4) as u64) << 32)
+        } else {
+            Some(base)
+        }
+    }
+
+    /// Set bits in the command register
+    ///
+    /// Only the command register is written: the status register beside it
+    /// has bits that a written one clears.
+    pub fn enable(&self, bits: u16) {
+        let value = self.read16(REG_COMMAND);
+        self.write16(REG_COMMAND, value | bits);
+    }
+
+    /// Read a 16-bit configuration register
+    pub fn read16(&self, offset: u8) -> u16 {
+        (self.read(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    /// Write a 16-bit configuration register with a 16-bit access, leaving
+    /// its neighbour alone
+    pub fn write16(&self, offset: u8, value: u16) {
+        let half = (offset & 2) as usize;
+        if let Some(register) = self.ecam_register(offset) {
+            return unsafe { core::ptr::write_volatile(register.byte_add(half) as *mut u16, value) };
+        }
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::<u16>::new(CONFIG_DATA + half as u16).write(value);
+        }
+    }
+
+    /// Offset of the capability with ID `id`
+    pub fn capability(&self, id: u8) -> Option<u8> {
+        self.capabilities(id).next()
+    }
+
+    /// Offsets of every capability with ID `id`, as vendor-specific ones
+    /// can be several
+    pub fn capabilities(&self, id: u8) -> impl Iterator<Item = u8> + '_ {
+        let mut offset = match self.read(REG_COMMAND) & STATUS_CAPABILITIES {
+            0 => 0,
+            _ => self.read(REG_CAPABILITIES) as u8 & 0xFC,
+        };
+        // The list cannot hold more than fits in configuration space
+        core::iter::from_fn(move || {
+            if offset == 0 {
+                return None;
+            }
+            let current = offset;
+            let header = self.read(current);
+            offset = (header >> 8) as u8 & 0xFC;
+            Some((current, header as u8))
+        })
+        .take(48)
+        .filter(move |(_, found)| *found == id)
+        .map(|(offset, _)| offset)
+    }
+}
+
+impl core::fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A base address register, sized
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bar {
+    /// Not implemented, or the upper half of a 64-bit BAR
+    Unused,
+    Memory { base: u64, size: u64, prefetchable: bool },
+    Io { port: u16, size: u16 },
+}
+
+/// A function found on the bus
+#[derive(Clone, Copy, Debug)]
+pub struct Device {
+    pub address: PciAddress,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    /// Bridges have two BARs, the rest of the array is unused
+    pub bars: [Bar; 6],
+    /// Position in [`devices`] of the bridge the function sits behind
+    pub parent: Option<u8>,
+}
+
+impl Device {
+    /// Physical base of a memory BAR
+    pub fn memory_bar(&self, index: usize) -> Option<(u64, u64)> {
+        match self.bars.get(index)? {
+            Bar::Memory { base, size, .. } => Some((*base, *size)),
+            _ => None,
+        }
+    }
+
+    fn class_code(&self) -> u32 {
+        (self.class as u32) << 16 | (self.subclass as u32) << 8 | self.prog_if as u32
+    }
+}
+
+struct Slot {
+    device: Device,
+    driver: Option<&'static dyn Driver>,
+    present: bool,
+}
+
+/// Which devices a driver handles: a vendor and device ID, a class, or
+/// both
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceId {
+    pub vendor: u16,
+    pub device: u16,
+    /// Class, subclass and programming interface, compared under the mask
+    pub class: u32,
+    pub class_mask: u32,
+}
+
+impl DeviceId {
+    /// One vendor's device
+    pub const fn new(vendor: u16, device: u16) -> Self {
+        DeviceId { vendor, device, class: 0, class_mask: 0 }
+    }
+
+    /// Any device of a class and subclass
+    pub const fn class(class: u8, subclass: u8) -> Self {
+        let class = (class as u32) << 16 | (subclass as u32) << 8;
+        DeviceId { vendor: ANY, device: ANY, class, class_mask: 0xFF_FF00 }
+    }
+
+    /// Any device of a class and subclass with a programming interface
+    pub const fn interface(class: u8, subclass: u8, prog_if: u8) -> Self {
+        let class = (class as u32) << 16 | (subclass as u32) << 8 | prog_if as u32;
+        DeviceId { vendor: ANY, device: ANY, class, class_mask: 0xFF_FFFF }
+    }
+
+    pub fn matches(&self, device: &Device) -> bool {
+        (self.vendor == ANY || self.vendor == device.vendor)
+            && (self.device == ANY || self.device == device.device)
+            && device.class_code() & self.class_mask == self.class
+    }
+}
+
+/// A driver for PCI functions
+pub trait Driver: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Devices the driver is probed for
+    fn ids(&self) -> &'static [DeviceId];
+
+    /// Take the device over. [`PciError::Unsupported`] leaves it for
+    /// another driver.
+    fn probe(&self, device: &Device) -> Result<(), PciError>;
+
+    /// Let go of the device, which is being removed or the driver is
+    fn remove(&self, device: &Device);
+}
+
+/// Map a bus's enhanced configuration space, if the MCFG covers it
+fn map_bus(bus: u8) {
+    let Some((base, first, last)) = ECAM.get().copied().flatten() else {
+        return;
+    };
+    if !(first..=last).contains(&bus) || ECAM_BUSES[bus as usize].load(Ordering::Relaxed) != 0 {
+        return;
+    }
+    let window = PhysAddr::new(base + ((bus as u64) << 20));
+    match crate::kernel::memory::map_mmio(window, ECAM_BUS_SIZE) {
+        Ok(virt) => ECAM_BUSES[bus as usize].store(virt.as_u64(), Ordering::Release),
+        Err(e) => crate::klog!(Warn, "Bus {:02x} left on port I/O: {:?}", bus, e),
+    }
+}
+
+/// Size the BARs by writing all ones to each and reading back which bits
+/// stuck, with decoding off so the device does not answer at the probe
+/// addresses meanwhile
+fn size_bars(address: PciAddress, count: u8) -> [Bar; 6] {
+    let command = address.read16(REG_COMMAND);
+    address.write16(REG_COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+
+    let mut bars = [Bar::Unused; 6];
+    let mut index = 0;
+    while index < count {
+        let offset = REG_BAR0 + index * 4;
+        let low = address.read(offset);
+        address.write(offset, !0);
+        let mask = address.read(offset);
+        address.write(offset, low);
+
+        if low & 1 != 0 {
+            let mask = mask & !0b11;
+            if mask != 0 {
+                let size = (!mask as u16).wrapping_add(1);
+                bars[index as usize] = Bar::Io { port: (low & !0b11) as u16, size };
+            }
+            index += 1;
+            continue;
+        }
+
+        // A 64-bit BAR spans the next register too
+        let wide = (low >> 1) & 0b11 == 0b10 && index + 1 < count;
+        let mut base = (low & !0xF) as u64;
+        let mut mask = (mask & !0xF) as u64;
+        if wide {
+            let high = address.read(offset + 4);
+            address.write(offset + 4, !0);
+            mask |= (address.read(offset + 4) as u64) << 32;
+            address.write(offset + 4, high);
+            base |= (high as u64) << 32;
+        } else if mask != 0 {
+            mask |= 0xFFFF_FFFF_0000_0000;
+        }
+        if mask != 0 {
+            let size = (!mask).wrapping_add(1);
+            bars[index as usize] = Bar::Memory { base, size, prefetchable: low & 0b1000 != 0 };
+        }
+        index += if wide { 2 } else { 1 };
+    }
+
+    address.write16(REG_COMMAND, command);
+    bars
+}
+
+/// Add every function on a bus to the table, and the buses behind its
+/// bridges after each bridge
+fn scan_bus(bus: u8, parent: Option<u8>, devices: &mut ArrayVec<Slot, MAX_DEVICES>) {
+    map_bus(bus);
+    for slot in 0..32u8 {
+        for function in 0..8u8 {
+            let address = PciAddress { bus, device: slot, function };
+            let Some((vendor, device)) = address.id() else {
+                // Function 0 absent: no device in this slot
+                if function == 0 {
+                    break;
+                }
+                continue;
+            };
+
+            let class = address.read(REG_CLASS);
+            let header = (address.read(REG_HEADER_TYPE) >> 16) as u8;
+            let bridge = header & !HEADER_MULTI_FUNCTION == HEADER_BRIDGE;
+            let entry = Device {
+                address,
+                vendor,
+                device,
+                class: (class >> 24) as u8,
+                subclass: (class >> 16) as u8,
+                prog_if: (class >> 8) as u8,
+                revision: class as u8,
+                bars: size_bars(address, if bridge { 2 } else { 6 }),
+                parent,
+            };
+            let index = devices.len() as u8;
+            if devices.try_push(Slot { device: entry, driver: None, present: true }).is_err() {
+                crate::klog!(Warn, "Device table full, {} and later functions left out", address);
+                return;
+            }
+
+            // Bus numbers only grow away from the root, which bounds the
+            // recursion
+            let secondary = (address.read(REG_BUS_NUMBERS) >> 8) as u8;
+            if bridge && secondary > bus {
+                scan_bus(secondary, Some(index), devices);
+            }
+
+            // Single-function devices only decode function 0
+            if function == 0 && header & HEADER_MULTI_FUNCTION == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// Probe `driver` for the device at `index` if it matches and is free
+fn attach(index: usize, driver: &'static dyn Driver) -> bool {
+    let device = {
+        let devices = DEVICES.lock();
+        match devices.get(index) {
+            Some(slot) if slot.present && slot.driver.is_none() => slot.device,
+            _ => return false,
+        }
+    };
+    if !driver.ids().iter().any(|id| id.matches(&device)) {
+        return false;
+    }
+
+    match driver.probe(&device) {
+        Ok(()) => {
+            if let Some(slot) = DEVICES.lock().get_mut(index) {
+                slot.driver = Some(driver);
+            }
+            let (vendor, id) = (device.vendor, device.device);
+            crate::klog!(Info, "{} {:04x}:{:04x} bound to {}", device.address, vendor, id, driver.name());
+            true
+        }
+        Err(PciError::Unsupported) => false,
+        Err(e) => {
+            crate::klog!(Warn, "{} rejected {}: {:?}", driver.name(), device.address, e);
+            false
+        }
+    }
+}
+
+/// Register a driver and probe it for every matching device without one,
+/// returning how many it took
+pub fn register_driver(driver: &'static dyn Driver) -> Result<usize, PciError> {
+    {
+        let mut drivers = DRIVERS.lock();
+        if drivers.iter().any(|d| d.name() == driver.name()) {
+            return Err(PciError::AlreadyRegistered);
+        }
+        drivers.try_push(driver).map_err(|_| PciError::TooManyDrivers)?;
+    }
+    let count = DEVICES.lock().len();
+    Ok((0..count).filter(|&index| attach(index, driver)).count())
+}
+
+/// Unregister a driver, taking it off every device it had
+pub fn unregister_driver(name: &str) -> Result<(), PciError> {
+    let driver = {
+        let mut drivers = DRIVERS.lock();
+        let position = drivers.iter().position(|d| d.name() == name).ok_or(PciError::NoSuchDriver)?;
+        drivers.remove(position)
+    };
+    let count = DEVICES.lock().len();
+    for index in 0..count {
+        let device = {
+            let mut devices = DEVICES.lock();
+            let slot = &mut devices[index];
+            match slot.driver {
+                Some(d) if d.name() == name => {
+                    slot.driver = None;
+                    slot.device
+                }
+                _ => continue,
+            }
+        };
+        driver.remove(&device);
+    }
+    Ok(())
+}
+
+/// Take a function that has gone off the bus out of the table, after its
+/// driver has let go of it
+pub fn remove_device(address: PciAddress) -> Result<(), PciError> {
+    let (device, driver) = {
+        let mut devices = DEVICES.lock();
+        let slot = devices
+            .iter_mut()
+            .find(|slot| slot.present && slot.device.address == address)
+            .ok_or(PciError::NoSuchDevice)?;
+        slot.present = false;
+        (slot.device, slot.driver.take())
+    };
+    if let Some(driver) = driver {
+        driver.remove(&device);
+    }
+    crate::klog!(Info, "{} removed", address);
+    Ok(())
+}
+
+/// Every function present, with the name of its driver if it has one
+pub fn devices() -> ArrayVec<(Device, Option<&'static str>), MAX_DEVICES> {
+    DEVICES
+        .lock()
+        .iter()
+        .filter(|slot| slot.present)
+        .map(|slot| (slot.device, slot.driver.map(|d| d.name())))
+        .collect()
+}
+
+/// Find the first function with the given vendor and device ID
+pub fn find_device(vendor: u16, device: u16) -> Option<PciAddress> {
+    let id = DeviceId::new(vendor, device);
+    DEVICES
+        .lock()
+        .iter()
+        .find(|slot| slot.present && id.matches(&slot.device))
+        .map(|slot| slot.device.address)
+}
+
+/// Walk the bus and probe the drivers registered so far, returning how
+/// many functions were found
+pub fn init() -> usize {
+    let ecam = *ECAM.call_once(crate::kernel::acpi::ecam);
+    {
+        let mut devices = DEVICES.lock();
+        devices.clear();
+        scan_bus(ecam.map_or(0, |(_, first, _)| first), None, &mut devices);
+    }
+
+    let drivers = DRIVERS.lock().clone();
+    let count = DEVICES.lock().len();
+    for index in 0..count {
+        for &driver in &drivers {
+            if attach(index, driver) {
+                break;
+            }
+        }
+    }
+    let access = if ecam.is_some() { "ECAM" } else { "port I/O" };
+    crate::klog!(Info, "{} PCI functions, configuration space through {}", count, access);
+    count
+}
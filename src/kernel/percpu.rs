@@ -1,6 +1,24 @@
 //! Per-CPU data structures (1 KB scratch buffers)
+//!
+//! CPUs are numbered from 0, the bootstrap processor, in the order they
+//! come up. Each CPU's GS base points at its own [`PerCpuData`], whose
+//! first field points back at it, so finding this CPU's data is one load.
+//! The same pointer is kept in the kernel GS base MSR, which ring 3
+//! cannot change: a program that loads GS moves the GS base, and the
+//! kernel puts it back from there whenever it is entered from user mode.
+//!
+//! Data is only ever handed out shared; what changes is atomic, or, for
+//! the scratch buffer, lent out by [`with_scratch`] with interrupts off.
 
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::VirtAddr;
+
+use super::apic;
 
 /// Maximum number of CPUs supported
 pub const MAX_CPUS: usize = 256;
@@ -11,58 +29,132 @@ pub const SCRATCH_BUFFER_SIZE: usize = 1024;
 /// Per-CPU data structure
 #[repr(C, align(64))] // Cache-line aligned
 pub struct PerCpuData {
+    /// This structure's own address, read through GS; must stay first
+    this: AtomicU64,
     /// CPU ID
-    pub cpu_id: u32,
-    /// Scratch buffer for temporary allocations
-    pub scratch_buffer: [u8; SCRATCH_BUFFER_SIZE],
+    pub cpu_id: AtomicU32,
     /// Current task ID
     pub current_task: AtomicU32,
     /// Idle time counter
     pub idle_ticks: AtomicU32,
+    /// The scratch buffer is lent out
+    scratch_busy: AtomicBool,
+    /// Scratch buffer for temporary allocations
+    scratch_buffer: UnsafeCell<[u8; SCRATCH_BUFFER_SIZE]>,
 }
 
+// Only the owning CPU touches the scratch buffer, with interrupts off
+unsafe impl Sync for PerCpuData {}
+
 impl PerCpuData {
     /// Create a new per-CPU data structure
     pub const fn new(cpu_id: u32) -> Self {
         Self {
-            cpu_id,
-            scratch_buffer: [0; SCRATCH_BUFFER_SIZE],
+            this: AtomicU64::new(0),
+            cpu_id: AtomicU32::new(cpu_id),
             current_task: AtomicU32::new(0),
             idle_ticks: AtomicU32::new(0),
+            scratch_busy: AtomicBool::new(false),
+            scratch_buffer: UnsafeCell::new([0; SCRATCH_BUFFER_SIZE]),
         }
     }
 }
 
 /// Global per-CPU data array
-static mut PER_CPU_DATA: [PerCpuData; MAX_CPUS] = {
-    const INIT: PerCpuData = PerCpuData::new(0);
-    [INIT; MAX_CPUS]
-};
+static PER_CPU_DATA: [PerCpuData; MAX_CPUS] = [const { PerCpuData::new(0) }; MAX_CPUS];
+
+/// Local APIC ID by CPU number
+static APIC_ID_BY_CPU: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+
+/// CPUs that have come up
+static ONLINE: AtomicU32 = AtomicU32::new(1);
+
+/// Point this CPU's GS base at the data of CPU `cpu_id`
+fn install(cpu_id: u32) {
+    let data = &PER_CPU_DATA[cpu_id as usize];
+    let address = data as *const PerCpuData as u64;
+    data.cpu_id.store(cpu_id, Ordering::Relaxed);
+    data.this.store(address, Ordering::Relaxed);
+    GsBase::write(VirtAddr::new(address));
+    KernelGsBase::write(VirtAddr::new(address));
+}
 
 /// Initialize per-CPU structures
 pub fn init() {
     // Initialize CPU 0 (BSP)
-    unsafe {
-        PER_CPU_DATA[0] = PerCpuData::new(0);
-    }
-    
-    // TODO: Initialize additional CPUs (APs) when SMP is implemented
+    install(0);
+    APIC_ID_BY_CPU[0].store(apic::id(), Ordering::Release);
 }
 
-/// Get current CPU ID
-pub fn current_cpu_id() -> u32 {
-    // TODO: Read from APIC or similar
-    0
+/// Initialize the structures of application processor `cpu_id`, run by
+/// that processor first thing as it comes up
+pub fn init_ap(cpu_id: u32) {
+    install(cpu_id);
+    APIC_ID_BY_CPU[cpu_id as usize].store(apic::id(), Ordering::Release);
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Put the GS base back after entering the kernel from user mode
+pub fn restore() {
+    GsBase::write(KernelGsBase::read());
+}
+
+/// Number of CPUs that have come up
+pub fn online() -> u32 {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Local APIC ID of CPU `cpu`, if it has come up
+pub fn apic_id(cpu: u32) -> Option<u32> {
+    (cpu < online()).then(|| APIC_ID_BY_CPU[cpu as usize].load(Ordering::Acquire))
 }
 
 /// Get per-CPU data for current CPU
 pub fn current() -> &'static PerCpuData {
-    let cpu_id = current_cpu_id() as usize;
-    unsafe { &PER_CPU_DATA[cpu_id] }
+    let this: u64;
+    unsafe { asm!("mov {}, gs:[0]", out(reg) this, options(nostack, preserves_flags, readonly)) };
+    unsafe { &*(this as *const PerCpuData) }
+}
+
+/// Get current CPU ID
+pub fn current_cpu_id() -> u32 {
+    current().cpu_id.load(Ordering::Relaxed)
+}
+
+/// Lend `f` this CPU's scratch buffer, with interrupts off; `None` if it
+/// is already lent out further up the stack
+pub fn with_scratch<R>(f: impl FnOnce(&mut [u8; SCRATCH_BUFFER_SIZE]) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        let data = current();
+        if data.scratch_busy.swap(true, Ordering::Acquire) {
+            return None;
+        }
+        let result = f(unsafe { &mut *data.scratch_buffer.get() });
+        data.scratch_busy.store(false, Ordering::Release);
+        Some(result)
+    })
+}
+
+/// A field of this CPU's [`PerCpuData`]
+#[macro_export]
+macro_rules! this_cpu {
+    ($field:ident) => {
+        &$crate::kernel::percpu::current().$field
+    };
+}
+
+/// Load an atomic field of this CPU's [`PerCpuData`]
+#[macro_export]
+macro_rules! this_cpu_read {
+    ($field:ident) => {
+        $crate::this_cpu!($field).load(core::sync::atomic::Ordering::Relaxed)
+    };
 }
 
-/// Get mutable per-CPU data for current CPU
-pub fn current_mut() -> &'static mut PerCpuData {
-    let cpu_id = current_cpu_id() as usize;
-    unsafe { &mut PER_CPU_DATA[cpu_id] }
+/// Store to an atomic field of this CPU's [`PerCpuData`]
+#[macro_export]
+macro_rules! this_cpu_write {
+    ($field:ident, $value:expr) => {
+        $crate::this_cpu!($field).store($value, core::sync::atomic::Ordering::Relaxed)
+    };
 }
@@ -0,0 +1,189 @@
+//! Performance counters
+//!
+//! Uses the architectural performance monitoring of CPUID leaf 0xA:
+//! fixed counters 0 and 1 count instructions retired and core cycles, and
+//! the second programmable counter last-level cache misses where the
+//! processor lists that event; the first is the watchdog's, and its enable
+//! bit is left alone. Counting is per thread: the counters are
+//! zeroed and started when a counted thread is switched in, and read and
+//! stopped when it is switched out, so what a thread is charged includes
+//! the kernel work done on its behalf but nothing of anyone else's.
+
+use core::fmt;
+
+use spin::Once;
+use x86_64::registers::model_specific::Msr;
+
+use super::cpu::{self, Feature};
+
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_FIXED_CTR0: u32 = 0x309;
+const IA32_FIXED_CTR_CTRL: u32 = 0x38D;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+
+/// Programmable counter used for cache misses, the watchdog having the first
+const LLC_COUNTER: u32 = 1;
+
+/// Event select: count in user mode and in the kernel, enabled
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_EN: u64 = 1 << 22;
+
+/// Last-level cache misses, an architectural event
+const LLC_MISSES_EVENT: u64 = 0x2E;
+const LLC_MISSES_UMASK: u64 = 0x41;
+/// Bit of CPUID.0xA EBX that is set when the event is missing
+const LLC_MISSES_UNAVAILABLE: u32 = 1 << 4;
+
+/// Fixed counter control: both rings, for each of the first two counters
+const FIXED_CTRL_BOTH_RINGS: u64 = 0b11 | 0b11 << 4;
+
+/// Global control bits of the counters used here
+const GLOBAL_FIXED: u64 = 1 << 32 | 1 << 33;
+const GLOBAL_LLC: u64 = 1 << LLC_COUNTER;
+
+/// Something counted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Counter {
+    Cycles,
+    Instructions,
+    LlcMisses,
+}
+
+/// Number of counters a thread has
+pub const COUNTERS: usize = 3;
+
+impl Counter {
+    pub const ALL: [Counter; COUNTERS] = [Counter::Cycles, Counter::Instructions, Counter::LlcMisses];
+
+    pub fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// Counts of each [`Counter`], indexed by it
+pub type Counts = [u64; COUNTERS];
+
+/// The performance monitoring the processor has
+#[derive(Clone, Copy, Debug)]
+pub struct Pmu {
+    pub version: u8,
+    /// Programmable counters
+    pub general: u8,
+    pub fixed: u8,
+    /// Bits in a programmable counter
+    pub width: u8,
+    /// The [`Counter`]s it can count, a bit each
+    pub supported: u32,
+}
+
+impl fmt::Display for Pmu {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "version {}, {} programmable {}-bit and {} fixed counters",
+            self.version, self.general, self.width, self.fixed
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum PmuError {
+    /// No architectural performance monitoring, or too old a version
+    Unsupported,
+}
+
+static PMU: Once<Option<Pmu>> = Once::new();
+
+fn detect() -> Option<Pmu> {
+    if !cpu::has(Feature::ArchPerfmon) {
+        return None;
+    }
+    let leaf = core::arch::x86_64::__cpuid(0xA);
+    let pmu = Pmu {
+        version: leaf.eax as u8,
+        general: (leaf.eax >> 8) as u8,
+        width: (leaf.eax >> 16) as u8,
+        fixed: (leaf.edx & 0x1F) as u8,
+        supported: 0,
+    };
+    // The global control register, which starts and stops everything at
+    // once, came with version 2
+    if pmu.version < 2 || pmu.fixed < 2 {
+        return None;
+    }
+    let mut supported = Counter::Cycles.bit() | Counter::Instructions.bit();
+    if pmu.general > LLC_COUNTER as u8 && leaf.ebx & LLC_MISSES_UNAVAILABLE == 0 {
+        supported |= Counter::LlcMisses.bit();
+    }
+    Some(Pmu { supported, ..pmu })
+}
+
+/// The processor's performance monitoring, if it has what is used here
+pub fn pmu() -> Option<Pmu> {
+    *PMU.call_once(detect)
+}
+
+/// Look for performance counters and leave them all stopped
+pub fn init() -> Result<Pmu, PmuError> {
+    let pmu = pmu().ok_or(PmuError::Unsupported)?;
+    let mut global = Msr::new(IA32_PERF_GLOBAL_CTRL);
+    unsafe { global.write(global.read() & !(GLOBAL_FIXED | GLOBAL_LLC)) };
+    Ok(pmu)
+}
+
+/// Zero this CPU's counters and start them, as a counted thread is
+/// switched in
+pub fn start() {
+    let Some(pmu) = pmu() else {
+        return;
+    };
+    let mut global = Msr::new(IA32_PERF_GLOBAL_CTRL);
+    unsafe {
+        let mut enable = global.read() & !(GLOBAL_FIXED | GLOBAL_LLC);
+        global.write(enable);
+        Msr::new(IA32_FIXED_CTR0).write(0);
+        Msr::new(IA32_FIXED_CTR0 + 1).write(0);
+        Msr::new(IA32_FIXED_CTR_CTRL).write(FIXED_CTRL_BOTH_RINGS);
+        enable |= GLOBAL_FIXED;
+        if pmu.supported & Counter::LlcMisses.bit() != 0 {
+            Msr::new(IA32_PMC0 + LLC_COUNTER).write(0);
+            Msr::new(IA32_PERFEVTSEL0 + LLC_COUNTER).write(
+                LLC_MISSES_EVENT | LLC_MISSES_UMASK << 8 | EVTSEL_USR | EVTSEL_OS | EVTSEL_EN,
+            );
+            enable |= GLOBAL_LLC;
+        }
+        global.write(enable);
+    }
+}
+
+/// What this CPU's counters have counted since [`start`], leaving them
+/// running
+pub fn read() -> Counts {
+    let mut counts = [0; COUNTERS];
+    let Some(pmu) = pmu() else {
+        return counts;
+    };
+    unsafe {
+        counts[Counter::Instructions as usize] = Msr::new(IA32_FIXED_CTR0).read();
+        counts[Counter::Cycles as usize] = Msr::new(IA32_FIXED_CTR0 + 1).read();
+        if pmu.supported & Counter::LlcMisses.bit() != 0 {
+            counts[Counter::LlcMisses as usize] = Msr::new(IA32_PMC0 + LLC_COUNTER).read();
+        }
+    }
+    counts
+}
+
+/// Stop this CPU's counters and return what they counted since
+/// [`start`], as a counted thread is switched out
+pub fn stop() -> Counts {
+    if pmu().is_none() {
+        return [0; COUNTERS];
+    }
+    let mut global = Msr::new(IA32_PERF_GLOBAL_CTRL);
+    unsafe { global.write(global.read() & !(GLOBAL_FIXED | GLOBAL_LLC)) };
+    let counts = read();
+    unsafe { Msr::new(IA32_PERFEVTSEL0 + LLC_COUNTER).write(0) };
+    counts
+}
@@ -0,0 +1,232 @@
+//! Shutdown and reboot
+//!
+//! Turning the machine off enters ACPI sleep state S5: the `\_S5_` object
+//! in the DSDT gives the sleep type values, which are written with the
+//! sleep-enable bit to the PM1 control registers the FADT names. Only the
+//! plain package firmware writes for `\_S5_` is understood, not AML in
+//! general. Rebooting tries the FADT's reset register, then the keyboard
+//! controller's reset line, then a triple fault, which always works.
+//! Storage write caches are flushed first either way.
+
+use x86_64::instructions::port::Port;
+use x86_64::instructions::{hlt, interrupts};
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{acpi, clocksource, memory};
+
+/// FADT fields
+const FADT_SMI_CMD: usize = 48;
+const FADT_ACPI_ENABLE: usize = 52;
+const FADT_PM1A_CONTROL: usize = 64;
+const FADT_PM1B_CONTROL: usize = 68;
+const FADT_FLAGS: usize = 112;
+const FADT_RESET_REGISTER: usize = 116;
+const FADT_RESET_VALUE: usize = 128;
+const FADT_X_PM1A_CONTROL: usize = 172;
+const FADT_X_PM1B_CONTROL: usize = 184;
+
+/// FADT flags: the reset register is there
+const RESET_REGISTER_SUPPORTED: u32 = 1 << 10;
+
+/// Generic address structure spaces
+const SPACE_MEMORY: u8 = 0;
+const SPACE_IO: u8 = 1;
+
+/// PM1 control: interrupts go to the OS as SCIs, i.e. ACPI mode is on,
+/// and the sleep type is entered
+const SCI_ENABLE: u16 = 1;
+const SLEEP_ENABLE: u16 = 1 << 13;
+const SLEEP_TYPE_SHIFT: u16 = 10;
+
+/// AML opcodes in `\_S5_`
+const AML_NAME: u8 = 0x08;
+const AML_PACKAGE: u8 = 0x12;
+const AML_ZERO: u8 = 0x00;
+const AML_ONE: u8 = 0x01;
+const AML_BYTE: u8 = 0x0A;
+
+/// Keyboard controller status and command port, its input-full bit and
+/// the command that pulses the reset line
+const KBC_PORT: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_RESET: u8 = 0xFE;
+
+/// How long each way of turning off or resetting gets to work
+const ATTEMPT_NS: u64 = 100_000_000;
+
+#[derive(Debug)]
+pub enum PowerError {
+    NoFadt,
+    /// The DSDT has no `\_S5_` this understands
+    NoSleepState,
+    /// The machine was still on afterwards
+    StillOn,
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+/// A PM1 control port, from the 32-bit field at `short` or the I/O space
+/// generic address at `long`
+fn pm1_control(fadt: &[u8], short: usize, long: usize) -> Option<u16> {
+    let port = u32_at(fadt, short).filter(|&port| port != 0).or_else(|| {
+        let space = *fadt.get(long)?;
+        (space == SPACE_IO).then(|| u32_at(fadt, long + 4)).flatten()
+    })?;
+    u16::try_from(port).ok().filter(|&port| port != 0)
+}
+
+/// An integer element of an AML package, and what follows it
+fn element(aml: &[u8]) -> Option<(u16, &[u8])> {
+    match *aml.first()? {
+        AML_ZERO => Some((0, &aml[1..])),
+        AML_ONE => Some((1, &aml[1..])),
+        AML_BYTE => Some((*aml.get(1)? as u16, aml.get(2..)?)),
+        _ => None,
+    }
+}
+
+/// The sleep type values for PM1a and PM1b control in the DSDT's `\_S5_`
+fn s5_sleep_types(dsdt: &[u8]) -> Option<(u16, u16)> {
+    let at = dsdt.windows(4).position(|name| name == b"_S5_")?;
+    // Named with or without a root prefix
+    let before = &dsdt[..at];
+    if !before.ends_with(&[AML_NAME]) && !before.ends_with(&[AML_NAME, b'\\']) {
+        return None;
+    }
+    let package = dsdt.get(at + 4..)?;
+    if *package.first()? != AML_PACKAGE {
+        return None;
+    }
+    // The length's lead byte gives how many bytes follow it, then comes
+    // the element count
+    let extra = (*package.get(1)? >> 6) as usize;
+    let elements = package.get(2 + extra + 1..)?;
+    let (a, rest) = element(elements)?;
+    let (b, _) = element(rest)?;
+    Some((a, b))
+}
+
+/// Wait a while, for the machine to go off or reset
+fn pause() {
+    let until = clocksource::nanoseconds() + ATTEMPT_NS;
+    while clocksource::nanoseconds() < until {
+        core::hint::spin_loop();
+    }
+}
+
+/// Flush what storage has cached
+fn prepare(what: &str) {
+    crate::klog!(Info, "{}", what);
+    for (device, _) in crate::storage::devices() {
+        if let Err(e) = crate::storage::flush(device) {
+            crate::klog!(Warn, "Storage device {} not flushed: {:?}", device, e);
+        }
+    }
+}
+
+/// Switch the chipset to ACPI mode if the firmware left it in legacy mode
+fn enable_acpi(fadt: &[u8], pm1a: u16) {
+    let mut control = Port::<u16>::new(pm1a);
+    if unsafe { control.read() } & SCI_ENABLE != 0 {
+        return;
+    }
+    let (Some(smi_cmd), Some(&enable)) = (u32_at(fadt, FADT_SMI_CMD), fadt.get(FADT_ACPI_ENABLE)) else {
+        return;
+    };
+    if smi_cmd == 0 || enable == 0 {
+        return;
+    }
+    unsafe { Port::<u8>::new(smi_cmd as u16).write(enable) };
+    let until = clocksource::nanoseconds() + ATTEMPT_NS;
+    while unsafe { control.read() } & SCI_ENABLE == 0 && clocksource::nanoseconds() < until {
+        core::hint::spin_loop();
+    }
+}
+
+/// Turn the machine off; returns only if it stayed on, with why
+pub fn shutdown() -> PowerError {
+    prepare("Powering off");
+    let Some(fadt) = acpi::find_table(b"FACP") else {
+        return PowerError::NoFadt;
+    };
+    let Some(pm1a) = pm1_control(fadt, FADT_PM1A_CONTROL, FADT_X_PM1A_CONTROL) else {
+        return PowerError::NoFadt;
+    };
+    let Some((type_a, type_b)) = acpi::dsdt().and_then(s5_sleep_types) else {
+        return PowerError::NoSleepState;
+    };
+    enable_acpi(fadt, pm1a);
+    interrupts::without_interrupts(|| unsafe {
+        let mut control = Port::<u16>::new(pm1a);
+        let value = control.read() & !(0b111 << SLEEP_TYPE_SHIFT);
+        if let Some(pm1b) = pm1_control(fadt, FADT_PM1B_CONTROL, FADT_X_PM1B_CONTROL) {
+            let mut control_b = Port::<u16>::new(pm1b);
+            let value_b = control_b.read() & !(0b111 << SLEEP_TYPE_SHIFT);
+            control_b.write(value_b | type_b << SLEEP_TYPE_SHIFT | SLEEP_ENABLE);
+        }
+        control.write(value | type_a << SLEEP_TYPE_SHIFT | SLEEP_ENABLE);
+    });
+    pause();
+    PowerError::StillOn
+}
+
+/// Write the FADT's reset value to its reset register, if it has one
+fn acpi_reset() {
+    let Some(fadt) = acpi::find_table(b"FACP") else {
+        return;
+    };
+    if u32_at(fadt, FADT_FLAGS).is_none_or(|flags| flags & RESET_REGISTER_SUPPORTED == 0) {
+        return;
+    }
+    // A generic address structure: the space, then the address at 4
+    let (Some(&space), Some(address), Some(&value)) = (
+        fadt.get(FADT_RESET_REGISTER),
+        u64_at(fadt, FADT_RESET_REGISTER + 4),
+        fadt.get(FADT_RESET_VALUE),
+    ) else {
+        return;
+    };
+    match space {
+        SPACE_IO => unsafe { Port::<u8>::new(address as u16).write(value) },
+        SPACE_MEMORY => {
+            if let Ok(virt) = memory::map_mmio(PhysAddr::new(address), 1) {
+                unsafe { core::ptr::write_volatile(virt.as_mut_ptr::<u8>(), value) };
+            }
+        }
+        // PCI configuration space resets are left to the fallbacks
+        _ => return,
+    }
+    pause();
+}
+
+/// Reset the machine
+pub fn reboot() -> ! {
+    prepare("Rebooting");
+    acpi_reset();
+
+    let mut status = Port::<u8>::new(KBC_PORT);
+    let until = clocksource::nanoseconds() + ATTEMPT_NS;
+    while unsafe { status.read() } & KBC_INPUT_FULL != 0 && clocksource::nanoseconds() < until {
+        core::hint::spin_loop();
+    }
+    unsafe { status.write(KBC_RESET) };
+    pause();
+
+    // With no IDT, the breakpoint becomes a triple fault
+    interrupts::disable();
+    let empty = DescriptorTablePointer { limit: 0, base: VirtAddr::zero() };
+    unsafe {
+        x86_64::instructions::tables::lidt(&empty);
+        core::arch::asm!("int3");
+    }
+    loop {
+        hlt();
+    }
+}
@@ -0,0 +1,296 @@
+//! Kernel random numbers
+//!
+//! Entropy goes into a pool, and a ChaCha20 generator keyed from the pool
+//! gives the kernel its random numbers. At boot the pool takes RDSEED and
+//! RDRAND output where the CPU has them, and the jitter of the time stamp
+//! counter over a loop whose run time varies with caches and buses; the
+//! generator is seeded from it there, before memory, so KASLR, capability
+//! signatures and disk encryption have randomness long before userspace.
+//! Afterwards interrupts add their time stamps, and the generator is
+//! reseeded from the pool, with fresh RDSEED or RDRAND words mixed in,
+//! once [`RESEED_EVENTS`] of them have come in.
+//!
+//! The pool is a sponge over the ChaCha permutation: input is added into
+//! half of the state, which is then permuted, and a key is read from it
+//! after permuting again. The generator replaces its key with its own
+//! output after each request, so its state does not give away what it
+//! returned before.
+
+use core::arch::x86_64::{_rdseed64_step, _rdtsc};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::random::RdRand;
+
+use super::cpu::{self, Feature};
+
+/// Interrupts between reseeds
+pub const RESEED_EVENTS: usize = 64;
+
+/// Time stamp counter samples taken at boot
+const JITTER_SAMPLES: usize = 2048;
+
+/// Words RDSEED and RDRAND are each asked for at boot
+const HARDWARE_WORDS: usize = 16;
+
+/// Words RDSEED and RDRAND are each asked for at a reseed
+const RESEED_HARDWARE_WORDS: usize = 2;
+
+/// RDSEED attempts per word, as it fails while its conditioner refills
+const RDSEED_RETRIES: usize = 32;
+
+/// "expand 32-byte k"
+const SIGMA: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// The 20 rounds of ChaCha
+fn permute(s: &mut [u32; 16]) {
+    for _ in 0..10 {
+        quarter_round(s, 0, 4, 8, 12);
+        quarter_round(s, 1, 5, 9, 13);
+        quarter_round(s, 2, 6, 10, 14);
+        quarter_round(s, 3, 7, 11, 15);
+        quarter_round(s, 0, 5, 10, 15);
+        quarter_round(s, 1, 6, 11, 12);
+        quarter_round(s, 2, 7, 8, 13);
+        quarter_round(s, 3, 4, 9, 14);
+    }
+}
+
+/// The ChaCha20 block of `key` at `counter`, with a zero nonce
+fn block(key: &[u32; 8], counter: u64) -> [u32; 16] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&SIGMA);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+    let mut state = input;
+    permute(&mut state);
+    for (word, input) in state.iter_mut().zip(input) {
+        *word = word.wrapping_add(input);
+    }
+    state
+}
+
+struct Pool {
+    state: [u32; 16],
+    /// Words added since the last permutation
+    absorbed: usize,
+}
+
+impl Pool {
+    fn add(&mut self, value: u64) {
+        self.state[self.absorbed] ^= value as u32;
+        self.state[self.absorbed + 1] ^= (value >> 32) as u32;
+        self.absorbed += 2;
+        if self.absorbed == 8 {
+            permute(&mut self.state);
+            self.absorbed = 0;
+        }
+    }
+
+    /// Add bytes, the last word padded with zeros
+    fn absorb(&mut self, data: &[u8]) {
+        for chunk in data.chunks(8) {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.add(u64::from_le_bytes(word));
+        }
+    }
+
+    /// A key drawn from everything added so far
+    fn extract(&mut self) -> [u32; 8] {
+        permute(&mut self.state);
+        let mut key = [0u32; 8];
+        key.copy_from_slice(&self.state[..8]);
+        // Overwrite the key, so the state left does not lead back to it
+        self.state[..8].fill(0);
+        permute(&mut self.state);
+        self.absorbed = 0;
+        key
+    }
+}
+
+struct Generator {
+    key: [u32; 8],
+    counter: u64,
+}
+
+// The constants in the half of the pool input never reaches keep the
+// permutation off the all-zero state
+static POOL: Mutex<Pool> = Mutex::new(Pool {
+    state: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, SIGMA[0], SIGMA[1], SIGMA[2], SIGMA[3]],
+    absorbed: 0,
+});
+static GENERATOR: Mutex<Generator> = Mutex::new(Generator { key: [0; 8], counter: 0 });
+
+/// Interrupt time stamps, folded together until the next reseed takes
+/// them, so that interrupt handlers need no lock
+static TIMINGS: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+static EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// What the generator was seeded from at boot
+#[derive(Clone, Copy, Debug)]
+pub struct Sources {
+    /// Words from RDSEED
+    pub rdseed: usize,
+    /// Words from RDRAND
+    pub rdrand: usize,
+    /// Time stamp counter samples
+    pub jitter: usize,
+}
+
+#[target_feature(enable = "rdseed")]
+unsafe fn rdseed() -> Option<u64> {
+    let mut value = 0;
+    (0..RDSEED_RETRIES).find(|_| _rdseed64_step(&mut value) == 1).map(|_| value)
+}
+
+fn tsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Time a loop that walks `scratch` and stirs it, `JITTER_SAMPLES` times
+fn add_jitter(pool: &mut Pool) {
+    let mut scratch = [0u32; 16];
+    for i in 0..JITTER_SAMPLES {
+        let start = tsc();
+        scratch[i % 16] ^= start as u32;
+        permute(&mut scratch);
+        pool.add(tsc().wrapping_sub(start) ^ (scratch[0] as u64) << 32);
+    }
+}
+
+/// Add up to `words` words each from RDSEED and RDRAND, returning how
+/// many each gave
+fn add_hardware(pool: &mut Pool, words: usize) -> (usize, usize) {
+    let (mut seeds, mut randoms) = (0, 0);
+    if cpu::has(Feature::Rdseed) {
+        for _ in 0..words {
+            if let Some(value) = unsafe { rdseed() } {
+                pool.add(value);
+                seeds += 1;
+            }
+        }
+    }
+    if let Some(rdrand) = RdRand::new().filter(|_| cpu::has(Feature::Rdrand)) {
+        for _ in 0..words {
+            if let Some(value) = rdrand.get_u64() {
+                pool.add(value);
+                randoms += 1;
+            }
+        }
+    }
+    (seeds, randoms)
+}
+
+/// Gather entropy and seed the generator
+pub fn init() -> Sources {
+    let mut pool = POOL.lock();
+    let (rdseed, rdrand) = add_hardware(&mut pool, HARDWARE_WORDS);
+    let sources = Sources { rdseed, rdrand, jitter: JITTER_SAMPLES };
+    add_jitter(&mut pool);
+    let key = pool.extract();
+    drop(pool);
+    GENERATOR.lock().key = key;
+    sources
+}
+
+/// Add the time of an interrupt on `vector`; cheap enough for any handler
+pub fn add_interrupt(vector: u8) {
+    let event = EVENTS.fetch_add(1, Ordering::Relaxed);
+    let timing = &TIMINGS[event % TIMINGS.len()];
+    let value = timing.load(Ordering::Relaxed).rotate_left(19) ^ tsc() ^ (vector as u64) << 56;
+    timing.store(value, Ordering::Relaxed);
+}
+
+/// Add `data`, such as a device's serial number or a key the user typed,
+/// to the pool; it is used at the next reseed
+pub fn add_entropy(data: &[u8]) {
+    let mut pool = POOL.lock();
+    pool.absorb(data);
+    pool.add(tsc());
+}
+
+/// Key the generator from the pool, the interrupt timings, the CPU's
+/// generator and its old key
+fn reseed(generator: &mut Generator) {
+    let mut pool = POOL.lock();
+    add_hardware(&mut pool, RESEED_HARDWARE_WORDS);
+    for timing in &TIMINGS {
+        pool.add(timing.swap(0, Ordering::Relaxed));
+    }
+    for pair in generator.key.chunks(2) {
+        pool.add(pair[0] as u64 | (pair[1] as u64) << 32);
+    }
+    generator.key = pool.extract();
+}
+
+/// Fill `buffer` with random bytes
+pub fn fill(buffer: &mut [u8]) {
+    let mut generator = GENERATOR.lock();
+    if EVENTS.load(Ordering::Relaxed) >= RESEED_EVENTS {
+        EVENTS.store(0, Ordering::Relaxed);
+        reseed(&mut generator);
+    }
+    for chunk in buffer.chunks_mut(64) {
+        let output = block(&generator.key, generator.counter);
+        generator.counter += 1;
+        for (byte, value) in chunk.iter_mut().zip(output.iter().flat_map(|word| word.to_le_bytes())) {
+            *byte = value;
+        }
+    }
+    let next = block(&generator.key, generator.counter);
+    generator.counter += 1;
+    generator.key.copy_from_slice(&next[..8]);
+}
+
+/// A random number
+pub fn next_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    fill(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+/// A random number below `bound`, which must not be 0
+pub fn below(bound: u64) -> u64 {
+    // Values from the last, incomplete multiple of the bound would favour
+    // the small results
+    let limit = u64::MAX - u64::MAX % bound;
+    loop {
+        let value = next_u64();
+        if value < limit {
+            return value % bound;
+        }
+    }
+}
+
+/// A 256-bit tag of `data` under `key`, which only someone with the key
+/// can produce
+///
+/// The key fills the half of a sponge input never reaches and `data`
+/// is absorbed with its length after it, so no two inputs share a tag.
+pub fn keyed_hash(key: &[u8; 32], data: &[u8]) -> [u8; 32] {
+    let mut pool = Pool { state: [0; 16], absorbed: 0 };
+    for (word, bytes) in pool.state[8..].iter_mut().zip(key.chunks_exact(4)) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    pool.absorb(data);
+    pool.add(data.len() as u64);
+
+    let mut tag = [0u8; 32];
+    for (bytes, word) in tag.chunks_exact_mut(4).zip(pool.extract()) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    tag
+}
@@ -0,0 +1,186 @@
+//! Read-copy-update
+//!
+//! Readers of a structure that rarely changes take no lock: they mark
+//! themselves inside a read-side section, which costs no more than a
+//! per-CPU counter, and a writer never changes what they might be
+//! looking at. Instead it publishes a new version and waits a grace
+//! period before touching the old one again, by which time every reader
+//! that could have seen it has finished.
+//!
+//! A grace period ends once every CPU has passed a quiescent state after
+//! it began: a timer tick that lands outside any read-side section, or the
+//! CPU's idle loop. A CPU halted in its idle loop is quiescent for as long
+//! as it stays there and runs no readers, so the application processors,
+//! which spend their time halted, do not hold grace periods up. Read-side
+//! sections must not block or sleep, since the tick that ends a grace
+//! period only reports a CPU that is outside one.
+//!
+//! [`Rcu`] keeps two copies of a value for readers and a writer to take
+//! turns with, for tables that need no allocation.
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use super::percpu::{self, MAX_CPUS};
+
+/// Read-side sections each CPU is inside
+static NESTING: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+
+/// The newest grace period each CPU has been quiescent in
+static QUIESCENT: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// CPUs halted in their idle loop
+static IDLE: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// The newest grace period started
+static STARTED: AtomicU64 = AtomicU64::new(0);
+
+fn cpu() -> usize {
+    percpu::current_cpu_id() as usize % MAX_CPUS
+}
+
+/// An open read-side section; it must not be held across anything that
+/// blocks
+pub struct ReadGuard {
+    cpu: usize,
+    // Ends on the CPU it began on
+    _cpu: PhantomData<*const ()>,
+}
+
+impl Drop for ReadGuard {
+    fn drop(&mut self) {
+        NESTING[self.cpu].fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Begin a read-side section, which lasts until the guard drops
+pub fn read_lock() -> ReadGuard {
+    let cpu = cpu();
+    // Sequentially consistent, so a writer that sees no reader on this CPU
+    // published before whatever the reader loads next
+    NESTING[cpu].fetch_add(1, Ordering::SeqCst);
+    ReadGuard { cpu, _cpu: PhantomData }
+}
+
+/// Report a quiescent state for this CPU if it is outside any read-side
+/// section
+fn report(cpu: usize) {
+    if NESTING[cpu].load(Ordering::SeqCst) == 0 {
+        QUIESCENT[cpu].fetch_max(STARTED.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+}
+
+/// Called from the scheduler tick on the CPU it interrupted
+pub fn tick() {
+    report(cpu());
+}
+
+/// Halt until the next interrupt, quiescent meanwhile; for idle loops
+pub fn idle() {
+    let cpu = cpu();
+    report(cpu);
+    IDLE[cpu].store(true, Ordering::SeqCst);
+    x86_64::instructions::hlt();
+    IDLE[cpu].store(false, Ordering::SeqCst);
+}
+
+/// Begin a grace period, returning its number for [`completed`] and
+/// [`wait`]; changes published before now are what it waits out
+pub fn start() -> u64 {
+    let cpu = cpu();
+    if NESTING[cpu].load(Ordering::Relaxed) != 0 {
+        panic!("rcu: grace period started inside a read-side section");
+    }
+    let period = STARTED.fetch_add(1, Ordering::SeqCst) + 1;
+    report(cpu);
+    period
+}
+
+/// Whether every CPU has been quiescent since grace period `period` began
+pub fn completed(period: u64) -> bool {
+    (0..(percpu::online() as usize).clamp(1, MAX_CPUS)).all(|cpu| {
+        QUIESCENT[cpu].load(Ordering::SeqCst) >= period
+            || (IDLE[cpu].load(Ordering::SeqCst) && NESTING[cpu].load(Ordering::SeqCst) == 0)
+    })
+}
+
+/// Wait for grace period `period` to end
+pub fn wait(period: u64) {
+    while !completed(period) {
+        core::hint::spin_loop();
+    }
+}
+
+/// Wait for every read-side section already begun to end
+pub fn synchronize() {
+    wait(start());
+}
+
+/// A value read without locking and replaced a copy at a time
+///
+/// One copy is current and the other is what the last update replaced.
+/// An update waits until that one is free of readers, copies the current
+/// value over it, changes it and makes it current. Updates are serialized
+/// among themselves.
+pub struct Rcu<T> {
+    copies: [UnsafeCell<T>; 2],
+    current: AtomicUsize,
+    /// Grace period after which no reader is left on the other copy
+    retired: AtomicU64,
+    writer: Mutex<()>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+
+impl<T: Copy> Rcu<T> {
+    pub const fn new(value: T) -> Self {
+        Rcu {
+            copies: [UnsafeCell::new(value), UnsafeCell::new(value)],
+            current: AtomicUsize::new(0),
+            retired: AtomicU64::new(0),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// The current value, for as long as the reference is held
+    pub fn read(&self) -> RcuRef<'_, T> {
+        let guard = read_lock();
+        let value = unsafe { &*self.copies[self.current.load(Ordering::SeqCst)].get() };
+        RcuRef { value, _guard: guard }
+    }
+
+    /// Change the value with `f`; readers see the old value or the new
+    /// one, never a mix
+    pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let _writer = self.writer.lock();
+        wait(self.retired.load(Ordering::Relaxed));
+
+        let current = self.current.load(Ordering::Relaxed);
+        let next = self.copies[1 - current].get();
+        // Copied in place, as tables kept this way may not fit on the stack
+        unsafe { core::ptr::copy_nonoverlapping(self.copies[current].get(), next, 1) };
+        let result = f(unsafe { &mut *next });
+
+        self.current.store(1 - current, Ordering::SeqCst);
+        self.retired.store(start(), Ordering::Relaxed);
+        result
+    }
+}
+
+/// A reference to an [`Rcu`] value, in a read-side section
+pub struct RcuRef<'a, T> {
+    value: &'a T,
+    _guard: ReadGuard,
+}
+
+impl<T> Deref for RcuRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
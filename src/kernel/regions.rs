@@ -0,0 +1,184 @@
+//! Physical memory regions
+//!
+//! The bootloader's memory map is not the last word on what memory is
+//! free: firmware can leave ACPI tables in memory it reports usable, the
+//! map says nothing of most device register windows, and what the loader
+//! hands over on the command line, the initrd and the firmware
+//! framebuffer, may sit in memory it lists as usable. At boot the map is
+//! reconciled with the ACPI tables, the register windows the tables name,
+//! the legacy video and BIOS area, and the handovers into one sorted list
+//! of regions that do not overlap. Where two sources disagree the more
+//! restrictive kind wins, so a reservation always punches a hole in usable
+//! memory. The frame allocator takes frames only from usable regions, and
+//! the DMA layer refuses buffers in memory firmware or devices own.
+
+use arrayvec::ArrayVec;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
+
+/// Regions in the registry
+pub const MAX_REGIONS: usize = 128;
+
+/// Ranges the registry is built from
+const MAX_SOURCES: usize = 160;
+
+/// Real-mode interrupt vectors and the BIOS data area
+const LOW_FIRMWARE: (u64, u64) = (0, 0x1000);
+/// Legacy video memory
+const LEGACY_VIDEO: (u64, u64) = (0xA_0000, 0xC_0000);
+/// Option ROMs and the system BIOS
+const BIOS_AREA: (u64, u64) = (0xC_0000, 0x10_0000);
+
+/// What a region of physical memory is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RegionKind {
+    Usable,
+    /// The kernel image, its stack, the boot page tables and boot information
+    Kernel,
+    /// What the loader handed over: the initrd and the framebuffer
+    Handover,
+    /// ACPI tables, which can be reclaimed once they have been read
+    AcpiReclaimable,
+    AcpiNvs,
+    /// Firmware's, or reserved for reasons the map does not give
+    Firmware,
+    /// Device registers
+    Mmio,
+    BadMemory,
+}
+
+impl RegionKind {
+    /// Whether the memory belongs to firmware or a device rather than the
+    /// kernel
+    pub fn firmware_owned(self) -> bool {
+        self > RegionKind::Handover
+    }
+}
+
+/// A range of physical memory, page-aligned
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub start: u64,
+    /// First byte past the region
+    pub end: u64,
+    pub kind: RegionKind,
+}
+
+static REGIONS: Mutex<ArrayVec<Region, MAX_REGIONS>> = Mutex::new(ArrayVec::new_const());
+
+fn map_kind(region_type: MemoryRegionType) -> Option<RegionKind> {
+    match region_type {
+        MemoryRegionType::Usable => Some(RegionKind::Usable),
+        MemoryRegionType::InUse
+        | MemoryRegionType::Kernel
+        | MemoryRegionType::KernelStack
+        | MemoryRegionType::PageTable
+        | MemoryRegionType::Bootloader
+        | MemoryRegionType::BootInfo
+        | MemoryRegionType::Package => Some(RegionKind::Kernel),
+        MemoryRegionType::AcpiReclaimable => Some(RegionKind::AcpiReclaimable),
+        MemoryRegionType::AcpiNvs => Some(RegionKind::AcpiNvs),
+        MemoryRegionType::BadMemory => Some(RegionKind::BadMemory),
+        MemoryRegionType::Empty => None,
+        _ => Some(RegionKind::Firmware),
+    }
+}
+
+/// Add `size` bytes at `start`, widened to whole pages unless usable, to
+/// which only whole pages count
+fn add(sources: &mut ArrayVec<Region, MAX_SOURCES>, start: u64, size: u64, kind: RegionKind) {
+    let end = start.saturating_add(size);
+    let (start, end) = match kind {
+        RegionKind::Usable => (start.next_multiple_of(4096), end & !0xfff),
+        _ => (start & !0xfff, end.saturating_add(0xfff) & !0xfff),
+    };
+    if start < end && sources.try_push(Region { start, end, kind }).is_err() {
+        crate::klog!(Warn, "Memory region {:#x}..{:#x} dropped: too many", start, end);
+    }
+}
+
+/// Every range the memory map, the ACPI tables and the command line give
+fn gather(memory_map: &MemoryMap) -> ArrayVec<Region, MAX_SOURCES> {
+    let mut sources = ArrayVec::new();
+    for region in memory_map.iter() {
+        if let Some(kind) = map_kind(region.region_type) {
+            let start = region.range.start_addr();
+            add(&mut sources, start, region.range.end_addr() - start, kind);
+        }
+    }
+    for (start, end, kind) in [
+        (LOW_FIRMWARE.0, LOW_FIRMWARE.1, RegionKind::Firmware),
+        (LEGACY_VIDEO.0, LEGACY_VIDEO.1, RegionKind::Mmio),
+        (BIOS_AREA.0, BIOS_AREA.1, RegionKind::Firmware),
+    ] {
+        add(&mut sources, start, end - start, kind);
+    }
+    for (start, size) in super::acpi::table_ranges() {
+        add(&mut sources, start, size, RegionKind::AcpiReclaimable);
+    }
+    for (start, size) in super::acpi::device_windows() {
+        add(&mut sources, start, size, RegionKind::Mmio);
+    }
+    if let Ok(Some((start, size))) = crate::boot::initrd::location() {
+        add(&mut sources, start.as_u64(), size, RegionKind::Handover);
+    }
+    if let Some(framebuffer) = crate::boot::framebuffer::capture() {
+        add(&mut sources, framebuffer.addr.as_u64(), framebuffer.size() as u64, RegionKind::Handover);
+    }
+    sources
+}
+
+/// Build the registry from the bootloader's memory map and what else is
+/// known about physical memory
+pub fn init(memory_map: &MemoryMap) {
+    let sources = gather(memory_map);
+
+    // Split at every boundary; each piece takes the most restrictive kind
+    // of the sources covering it
+    let mut bounds: ArrayVec<u64, { MAX_SOURCES * 2 }> = sources.iter().flat_map(|r| [r.start, r.end]).collect();
+    bounds.sort_unstable();
+    let mut regions = REGIONS.lock();
+    regions.clear();
+    for piece in bounds.windows(2).filter(|piece| piece[0] < piece[1]) {
+        let (start, end) = (piece[0], piece[1]);
+        let Some(kind) = sources
+            .iter()
+            .filter(|r| r.start <= start && end <= r.end)
+            .map(|r| r.kind)
+            .max()
+        else {
+            continue;
+        };
+        match regions.last_mut() {
+            Some(last) if last.end == start && last.kind == kind => last.end = end,
+            _ => {
+                if regions.try_push(Region { start, end, kind }).is_err() {
+                    crate::klog!(Warn, "Memory regions past {:#x} dropped: too many", start);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Every region, in address order
+pub fn regions() -> ArrayVec<Region, MAX_REGIONS> {
+    REGIONS.lock().clone()
+}
+
+/// The usable regions, in address order
+pub fn usable() -> impl Iterator<Item = Region> {
+    regions().into_iter().filter(|r| r.kind == RegionKind::Usable)
+}
+
+/// The most restrictive kind of memory in `len` bytes at `start`, or
+/// `None` where the registry knows of none
+pub fn kind(start: u64, len: u64) -> Option<RegionKind> {
+    let end = start.saturating_add(len);
+    REGIONS
+        .lock()
+        .iter()
+        .filter(|r| r.start < end && start < r.end)
+        .map(|r| r.kind)
+        .max()
+}
@@ -0,0 +1,255 @@
+//! Application processor startup
+//!
+//! The MADT lists every processor's local APIC (see [`super::acpi`]). Each
+//! one other than the bootstrap processor is sent INIT and two startup
+//! IPIs and starts in real mode at a trampoline copied to a page below
+//! 1 MiB. The trampoline loads the bootstrap processor's control
+//! registers, so the kernel's page tables, with a GDT of its own, and jumps
+//! straight to long mode, then onto a fresh stack and into [`ap_main`].
+//! There the processor sets up its segments, IDT, local APIC, per-CPU data
+//! and run queue, reports in, and idles in its scheduler loop. Processors
+//! are started one at a time, since they share the trampoline.
+//!
+//! The trampoline runs with paging on before it leaves its page, so that
+//! page is identity-mapped, and it loads CR3 while still 32-bit, so the
+//! top-level page table has to lie below 4 GiB.
+
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use x86_64::instructions::port::Port;
+use x86_64::registers::control::{Cr0, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{Page, PageTableFlags as Flags, PhysFrame};
+use x86_64::VirtAddr;
+
+use super::{acpi, apic, gdt, interrupts, mce, memory, percpu, rcu, softirq};
+use crate::scheduler;
+
+/// Stack each application processor runs on
+pub const AP_STACK_SIZE: usize = 64 * 1024;
+
+/// How long a processor gets to report in, in microseconds
+const START_TIMEOUT_US: u32 = 100_000;
+
+/// Where the trampoline finds its settings, after the jump over them
+const DATA_OFFSET: usize = 8;
+
+/// The trampoline's settings, as the assembly below lays them out
+#[repr(C)]
+struct Trampoline {
+    /// Null, 64-bit code and data descriptors
+    gdt: [u64; 3],
+    stack: u64,
+    entry: u64,
+    cr0: u32,
+    cr3: u32,
+    cr4: u32,
+    efer: u32,
+    /// Far pointer to the 64-bit code
+    target: u32,
+    code_selector: u16,
+    gdt_limit: u16,
+    gdt_base: u32,
+    _reserved: u32,
+}
+
+global_asm!(
+    r#"
+.code16
+.balign 16
+.global ap_trampoline
+ap_trampoline:
+    jmp 2f
+.balign 8
+    .quad 0
+    .quad 0x00209A0000000000
+    .quad 0x0000920000000000
+    .skip 48
+2:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+    mov eax, [{data} + 48]
+    mov cr4, eax
+    mov eax, [{data} + 44]
+    mov cr3, eax
+    mov ecx, 0xC0000080
+    mov eax, [{data} + 52]
+    xor edx, edx
+    wrmsr
+    lgdt [{data} + 62]
+    mov eax, [{data} + 40]
+    mov cr0, eax
+    // jmp far dword [data + 56]
+    .byte 0x66, 0xFF, 0x2E
+    .word {data} + 56
+.code64
+.global ap_trampoline_long
+ap_trampoline_long:
+    mov ax, 16
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov rsp, [rip + ap_trampoline + {data} + 24]
+    call [rip + ap_trampoline + {data} + 32]
+    ud2
+.global ap_trampoline_end
+ap_trampoline_end:
+"#,
+    data = const DATA_OFFSET,
+);
+
+extern "C" {
+    fn ap_trampoline();
+    fn ap_trampoline_long();
+    fn ap_trampoline_end();
+}
+
+/// Number the processor being started takes
+static STARTING: AtomicU32 = AtomicU32::new(0);
+
+/// Set once the processor being started has come up
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Application processor startup errors
+#[derive(Debug)]
+pub enum SmpError {
+    /// No free page below 1 MiB for the trampoline
+    NoTrampoline,
+    /// The top-level page table lies above 4 GiB
+    PageTableTooHigh,
+    Map(memory::MapError),
+}
+
+impl From<memory::MapError> for SmpError {
+    fn from(e: memory::MapError) -> Self {
+        SmpError::Map(e)
+    }
+}
+
+/// A page below 1 MiB for the trampoline, which the frame allocator
+/// hands out first if it has one left
+fn trampoline_frame() -> Result<PhysFrame, SmpError> {
+    let frame = memory::allocate_frame().ok_or(SmpError::NoTrampoline)?;
+    if frame.start_address().as_u64() >= 0x10_0000 {
+        memory::free_frame(frame);
+        return Err(SmpError::NoTrampoline);
+    }
+    Ok(frame)
+}
+
+/// Wait about `us` microseconds, an I/O port write taking about one
+fn delay(us: u32) {
+    let mut port = Port::<u8>::new(0x80);
+    for _ in 0..us {
+        unsafe { port.write(0) };
+    }
+}
+
+/// Copy the trampoline to its page, identity-mapped, and fill in what is
+/// the same for every processor
+fn install(frame: PhysFrame) -> Result<(), SmpError> {
+    let base = frame.start_address().as_u64();
+    let (table, _) = Cr3::read();
+    let cr3 = u32::try_from(table.start_address().as_u64()).map_err(|_| SmpError::PageTableTooHigh)?;
+    let page = Page::containing_address(VirtAddr::new(base));
+    if memory::virt_to_phys(page.start_address()) != Some(frame.start_address()) {
+        memory::map_page_with_flags(page, frame, Flags::PRESENT | Flags::WRITABLE)?;
+    }
+
+    let start = ap_trampoline as *const () as usize;
+    let len = ap_trampoline_end as *const () as usize - start;
+    let long = ap_trampoline_long as *const () as usize - start;
+    let copy = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+    unsafe {
+        core::ptr::copy_nonoverlapping(start as *const u8, copy, len);
+        copy.add(DATA_OFFSET).cast::<Trampoline>().write(Trampoline {
+            gdt: [0, 0x0020_9A00_0000_0000, 0x0000_9200_0000_0000],
+            stack: 0,
+            entry: ap_main as *const () as u64,
+            cr0: Cr0::read_raw() as u32,
+            cr3,
+            // PCIDs cannot be enabled outside long mode, nor long mode
+            // marked active
+            cr4: (Cr4::read() - Cr4Flags::PCID).bits() as u32,
+            efer: (Efer::read() - EferFlags::LONG_MODE_ACTIVE).bits() as u32,
+            target: (base + long as u64) as u32,
+            code_selector: 8,
+            gdt_limit: 23,
+            gdt_base: (base + DATA_OFFSET as u64) as u32,
+            _reserved: 0,
+        });
+    }
+    Ok(())
+}
+
+/// Start the processor with local APIC ID `apic_id` as CPU `cpu`; false
+/// if it did not report in
+fn start(frame: PhysFrame, apic_id: u32, cpu: u32) -> Result<bool, SmpError> {
+    let stack = memory::allocate_stack(AP_STACK_SIZE, "application processor")?;
+    let data = memory::phys_to_virt(frame.start_address() + DATA_OFFSET as u64).as_mut_ptr::<Trampoline>();
+    unsafe { (*data).stack = stack.as_u64() };
+    STARTING.store(cpu, Ordering::Release);
+    STARTED.store(false, Ordering::Release);
+
+    let vector = (frame.start_address().as_u64() >> 12) as u32;
+    apic::send_ipi(apic_id, apic::IPI_INIT);
+    delay(10_000);
+    for _ in 0..2 {
+        apic::send_ipi(apic_id, apic::IPI_STARTUP | vector);
+        delay(200);
+    }
+    for _ in 0..START_TIMEOUT_US / 100 {
+        if STARTED.load(Ordering::Acquire) {
+            return Ok(true);
+        }
+        delay(100);
+    }
+    Ok(false)
+}
+
+/// Start every other enabled processor the MADT lists, returning how many
+/// CPUs are online
+pub fn init() -> Result<u32, SmpError> {
+    let processors = acpi::processors();
+    let own = apic::id();
+    if processors.iter().all(|id| *id == own) {
+        return Ok(percpu::online());
+    }
+    let frame = trampoline_frame()?;
+    install(frame)?;
+
+    let mut cpu = percpu::online();
+    for apic_id in processors.iter().filter(|id| **id != own) {
+        if cpu as usize >= percpu::MAX_CPUS {
+            break;
+        }
+        if start(frame, *apic_id, cpu)? {
+            cpu += 1;
+        } else {
+            crate::klog!(Warn, "CPU with APIC ID {} did not start", apic_id);
+        }
+    }
+    Ok(percpu::online())
+}
+
+/// Where an application processor enters the kernel
+extern "C" fn ap_main() -> ! {
+    // Per-CPU data first, as anything taking a lock may look for it
+    let cpu = STARTING.load(Ordering::Acquire);
+    percpu::init_ap(cpu);
+    gdt::init_ap();
+    apic::enable();
+    mce::init_ap();
+    crate::ai::simd::init_cpu();
+    scheduler::init_cpu(cpu);
+    interrupts::init_ap();
+    STARTED.store(true, Ordering::Release);
+    loop {
+        softirq::run();
+        scheduler::schedule();
+        rcu::idle();
+    }
+}
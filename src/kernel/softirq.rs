@@ -0,0 +1,156 @@
+//! Deferred interrupt work
+//!
+//! An interrupt handler does only what cannot wait, such as reading the
+//! device's status, and leaves the rest to a softirq: it raises one of a
+//! fixed set, or queues a work item, a function and a word of data, on its
+//! CPU. Pending softirqs run on the way out of the handler, on the CPU that
+//! raised them, with interrupts enabled again; an interrupt that arrives
+//! meanwhile raises more, which the same run picks up. After
+//! [`MAX_ROUNDS`] rounds the rest is left to the CPU's idle loop, which
+//! calls [`run`] too, so a flood of interrupts cannot keep a CPU in them.
+//!
+//! A softirq runs in whatever the interrupt cut into, so it must not block,
+//! and any lock it takes must be one held only with interrupts disabled
+//! elsewhere, as for interrupt handlers.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use arrayvec::ArrayVec;
+use spin::Once;
+use x86_64::instructions::interrupts;
+
+use super::percpu::{self, MAX_CPUS};
+use super::sync::SpinLockIrq;
+
+/// Work items each CPU can have queued
+pub const WORK_ITEMS: usize = 32;
+
+/// Rounds of softirqs run on the way out of an interrupt
+pub const MAX_ROUNDS: usize = 10;
+
+/// Kinds of deferred work, run in this order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Softirq {
+    /// Scheduler bookkeeping for the timer tick
+    Timer,
+    /// Completions of storage queues
+    Storage,
+    /// Frames sent and received by network interfaces
+    Net,
+    /// Logging non-maskable interrupts, which cannot take the log's locks
+    Nmi,
+    /// Queued work items
+    Work,
+}
+
+const SOFTIRQS: usize = 5;
+
+impl Softirq {
+    pub const ALL: [Softirq; SOFTIRQS] =
+        [Softirq::Timer, Softirq::Storage, Softirq::Net, Softirq::Nmi, Softirq::Work];
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// Runs a softirq
+pub type Handler = fn();
+
+/// Runs a work item with its data
+pub type WorkFn = fn(u64);
+
+#[derive(Debug)]
+pub enum SoftirqError {
+    /// This CPU's work queue is full
+    Full,
+}
+
+static HANDLERS: [Once<Handler>; SOFTIRQS] = [const { Once::new() }; SOFTIRQS];
+
+/// Softirqs raised on each CPU, a bit each
+static PENDING: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+
+/// Whether each CPU is running its softirqs
+static ACTIVE: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+static WORK: [SpinLockIrq<ArrayVec<(WorkFn, u64), WORK_ITEMS>>; MAX_CPUS] =
+    [const { SpinLockIrq::new("deferred work", ArrayVec::new_const()) }; MAX_CPUS];
+
+/// Work items dropped because their CPU's queue was full
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+fn cpu() -> usize {
+    percpu::current_cpu_id() as usize % MAX_CPUS
+}
+
+/// Set the handler of `softirq`; only the first one set counts
+pub fn open(softirq: Softirq, handler: Handler) {
+    HANDLERS[softirq as usize].call_once(|| handler);
+}
+
+/// Have `softirq` run on this CPU once the current interrupt is handled
+pub fn raise(softirq: Softirq) {
+    PENDING[cpu()].fetch_or(softirq.bit(), Ordering::Release);
+}
+
+/// Queue `work(data)` to run on this CPU once the current interrupt is
+/// handled
+pub fn defer(work: WorkFn, data: u64) -> Result<(), SoftirqError> {
+    let cpu = cpu();
+    if WORK[cpu].lock().try_push((work, data)).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return Err(SoftirqError::Full);
+    }
+    PENDING[cpu].fetch_or(Softirq::Work.bit(), Ordering::Release);
+    Ok(())
+}
+
+/// Work items dropped so far
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Run this CPU's queued work items, oldest first
+fn run_work() {
+    let work = core::mem::take(&mut *WORK[cpu()].lock());
+    for (work, data) in work {
+        work(data);
+    }
+}
+
+/// Run this CPU's pending softirqs with interrupts enabled, unless it is
+/// already running them; interrupts are as they were when it returns
+pub fn run() {
+    let cpu = cpu();
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    if ACTIVE[cpu].swap(true, Ordering::Acquire) {
+        if enabled {
+            interrupts::enable();
+        }
+        return;
+    }
+    for _ in 0..MAX_ROUNDS {
+        let pending = PENDING[cpu].swap(0, Ordering::Acquire);
+        if pending == 0 {
+            break;
+        }
+        interrupts::enable();
+        for softirq in Softirq::ALL.into_iter().filter(|s| pending & s.bit() != 0) {
+            match softirq {
+                Softirq::Work => run_work(),
+                _ => {
+                    if let Some(handler) = HANDLERS[softirq as usize].get() {
+                        handler();
+                    }
+                }
+            }
+        }
+        interrupts::disable();
+    }
+    ACTIVE[cpu].store(false, Ordering::Release);
+    if enabled {
+        interrupts::enable();
+    }
+}
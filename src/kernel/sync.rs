@@ -0,0 +1,203 @@
+//! Interrupt-safe spinlocks and lock order checking
+//!
+//! A lock an interrupt handler takes must not be held with interrupts on:
+//! the handler would spin on it forever on the CPU that holds it.
+//! [`SpinLockIrq`] turns interrupts off before it spins and puts them back
+//! as they were when its guard drops, so it can be taken anywhere.
+//!
+//! With `lockdep` on the command line every acquisition is checked
+//! against the order locks have been taken in before. Each CPU keeps the
+//! locks it holds, and the first time a lock is taken while another is
+//! held the pair is added to a graph of orders seen; if the graph already
+//! leads from the new lock back to one held, two CPUs taking them in
+//! their two orders would deadlock, and the inversion is logged. Locks
+//! are told apart by name, so every instance of a structure counts as the
+//! same lock, as it would deadlock the same way.
+
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use arrayvec::ArrayVec;
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+use super::percpu::{self, MAX_CPUS};
+
+/// Locks one CPU can be recorded holding at once
+const MAX_HELD: usize = 8;
+
+/// Orders between two locks remembered
+const MAX_ORDERS: usize = 256;
+
+/// A spinlock taken with interrupts off
+pub struct SpinLockIrq<T> {
+    name: &'static str,
+    inner: Mutex<T>,
+}
+
+impl<T> SpinLockIrq<T> {
+    /// A lock called `name` in lock order reports
+    pub const fn new(name: &'static str, value: T) -> Self {
+        SpinLockIrq { name, inner: Mutex::new(value) }
+    }
+
+    pub fn lock(&self) -> SpinLockIrqGuard<'_, T> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        // Checked before spinning, so a deadlock is reported before it hangs
+        lockdep::acquire(self.name, true);
+        SpinLockIrqGuard { guard: ManuallyDrop::new(self.inner.lock()), name: self.name, enabled }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockIrqGuard<'_, T>> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                // A lock that is not waited for cannot deadlock
+                lockdep::acquire(self.name, false);
+                Some(SpinLockIrqGuard { guard: ManuallyDrop::new(guard), name: self.name, enabled })
+            }
+            None => {
+                if enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+}
+
+/// A held [`SpinLockIrq`]; interrupts come back on, if they were on, when
+/// it drops
+pub struct SpinLockIrqGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    name: &'static str,
+    enabled: bool,
+}
+
+impl<T> Deref for SpinLockIrqGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for SpinLockIrqGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for SpinLockIrqGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        lockdep::release(self.name);
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+mod lockdep {
+    use super::*;
+
+    /// Locks each CPU holds, in the order it took them; only ever touched
+    /// by its own CPU with interrupts off
+    static HELD: [Mutex<ArrayVec<&'static str, MAX_HELD>>; MAX_CPUS] =
+        [const { Mutex::new(ArrayVec::new_const()) }; MAX_CPUS];
+
+    /// Pairs of locks seen taken first then second
+    static ORDERS: Mutex<ArrayVec<(&'static str, &'static str), MAX_ORDERS>> = Mutex::new(ArrayVec::new_const());
+
+    /// Inversions logged, so a debug boot is told but not flooded
+    static REPORTED: AtomicUsize = AtomicUsize::new(0);
+    const MAX_REPORTS: usize = 16;
+
+    /// Whether the orders seen lead from `from` to `to`
+    fn reaches(orders: &[(&'static str, &'static str)], from: &'static str, to: &'static str) -> bool {
+        let mut found: ArrayVec<&'static str, MAX_ORDERS> = ArrayVec::new();
+        found.push(from);
+        let mut next = 0;
+        while let Some(&at) = found.get(next) {
+            next += 1;
+            for &(first, second) in orders {
+                if first != at || found.contains(&second) {
+                    continue;
+                }
+                if second == to {
+                    return true;
+                }
+                let _ = found.try_push(second);
+            }
+        }
+        false
+    }
+
+    /// Record that this CPU takes `name`, checking the order against the
+    /// locks it holds when `check` is set
+    pub(super) fn acquire(name: &'static str, check: bool) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut held = HELD[percpu::current_cpu_id() as usize % MAX_CPUS].lock();
+
+        // Gathered first and logged after, as logging may take locks too
+        let mut inversions: ArrayVec<&'static str, MAX_HELD> = ArrayVec::new();
+        if check {
+            let mut orders = ORDERS.lock();
+            for &holding in held.iter() {
+                if holding == name {
+                    inversions.push(holding);
+                    continue;
+                }
+                // Only an order not seen before can be new trouble
+                if orders.contains(&(holding, name)) {
+                    continue;
+                }
+                if reaches(&orders, name, holding) {
+                    inversions.push(holding);
+                }
+                if orders.try_push((holding, name)).is_err() {
+                    break;
+                }
+            }
+        }
+        let _ = held.try_push(name);
+        drop(held);
+
+        for holding in inversions {
+            if REPORTED.fetch_add(1, Ordering::Relaxed) >= MAX_REPORTS {
+                break;
+            }
+            if holding == name {
+                crate::klog!(Error, "lockdep: {} taken again while already held", name);
+            } else {
+                crate::klog!(Error, "lockdep: {} taken while holding {}, against an order seen before", name, holding);
+            }
+        }
+    }
+
+    /// Record that this CPU let go of `name`
+    pub(super) fn release(name: &'static str) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut held = HELD[percpu::current_cpu_id() as usize % MAX_CPUS].lock();
+        if let Some(index) = held.iter().rposition(|&holding| holding == name) {
+            held.remove(index);
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turn on lock order checking if `lockdep` was passed on the command line
+pub fn init() {
+    if crate::boot::cmdline::flag("lockdep") {
+        ENABLED.store(true, Ordering::Relaxed);
+        crate::klog!(Info, "lockdep: checking lock order");
+    }
+}
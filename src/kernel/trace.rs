@@ -0,0 +1,445 @@
+//! Event tracing
+//!
+//! Tracepoints in the scheduler, IPC, memory and storage code, and probes
+//! put on kernel functions at run time (see [`super::kprobe`]), write
+//! fixed-size records into a ring per CPU, stamped with the TSC, the CPU
+//! and the running thread. A tracepoint whose subsystem is off costs one
+//! load and a branch; its arguments are not even evaluated. Subsystems
+//! are switched on with `trace=sched,ipc,...` on the command line, from
+//! the shell, or over IPC.
+//!
+//! A ring is only written by its own CPU, so writers take no lock: each
+//! claims the next slot and marks it with the record's sequence number
+//! once written, and a reader drops a record whose mark changed while it
+//! was being copied. A full ring overwrites its oldest records, which the
+//! next read counts as lost.
+//!
+//! A trace viewer reads the records over the channel registered as
+//! [`SERVICE_NAME`]; all integers are little-endian.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::capability::{self, Permission};
+use crate::ipc::{self, names, MessageHeader};
+
+/// CPUs with a ring; records from the rest are counted as lost
+pub const TRACE_CPUS: usize = 32;
+
+/// Records each ring holds
+pub const RING_RECORDS: usize = 256;
+
+/// Bytes of one record as sent over IPC
+pub const RECORD_SIZE: usize = 48;
+
+/// Name the service channel is registered under
+pub const SERVICE_NAME: &str = "kernel.trace";
+
+/// Switch subsystems on or off; payload: reply channel (u64), mask of
+/// subsystems to trace (u32, bit per [`Subsystem`]). A status reply
+/// follows. Needs the trace permission.
+pub const TRACE_ENABLE_MSG: u32 = 0x5452_0001;
+
+/// Take every record traced since the last read; payload: reply channel
+/// (u64). Records replies follow, then a status reply. Needs the trace
+/// permission.
+pub const TRACE_READ_MSG: u32 = 0x5452_0002;
+
+/// Records (reply); payload: whole records of [`RECORD_SIZE`] bytes, each
+/// TSC (u64), event (u16), CPU (u16), thread (u32) and four arguments
+/// (u64 each)
+pub const TRACE_RECORDS_MSG: u32 = 0x5452_0003;
+
+/// Result of a request (reply); payload: status (u32), records lost since
+/// the last read (u64), TSC ticks per second (u64, 0 if unknown)
+pub const TRACE_STATUS_MSG: u32 = 0x5452_0004;
+
+/// Status codes
+pub const STATUS_OK: u32 = 0;
+pub const STATUS_INVALID_REQUEST: u32 = 1;
+pub const STATUS_PERMISSION_DENIED: u32 = 2;
+
+/// Code that can be traced, switched on and off as a whole
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsystem {
+    Scheduler,
+    Ipc,
+    Memory,
+    Storage,
+    Probe,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 5] = [
+        Subsystem::Scheduler,
+        Subsystem::Ipc,
+        Subsystem::Memory,
+        Subsystem::Storage,
+        Subsystem::Probe,
+    ];
+
+    /// Name on the command line and in the shell
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsystem::Scheduler => "sched",
+            Subsystem::Ipc => "ipc",
+            Subsystem::Memory => "mem",
+            Subsystem::Storage => "storage",
+            Subsystem::Probe => "probe",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| s.name() == name)
+    }
+
+    pub fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// What a record is of, with the meaning of its arguments
+#[repr(u16)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A thread starts running; thread, process
+    ThreadRun = 1,
+    /// A thread stops running; thread, process
+    ThreadStop = 2,
+    /// A kernel task is woken; task
+    TaskWake = 3,
+    /// A kernel task blocks; task
+    TaskBlock = 4,
+    /// Channel created; channel
+    ChannelCreate = 16,
+    /// Channel destroyed; channel
+    ChannelDestroy = 17,
+    /// Message sent; channel, sender, message type, length
+    MessageSend = 18,
+    /// Message received; channel, receiver, message type, length
+    MessageReceive = 19,
+    /// Physical frame allocated; address
+    FrameAllocate = 32,
+    /// Physical frame freed; address
+    FrameFree = 33,
+    /// Kernel page mapped; virtual address, physical address, flags
+    PageMap = 34,
+    /// Storage read; device, offset, length
+    StorageRead = 48,
+    /// Storage write; device, offset, length
+    StorageWrite = 49,
+    /// Storage cache flush; device
+    StorageFlush = 50,
+    /// Storage discard; device, offset, length
+    StorageDiscard = 51,
+    /// Probe hit; address, first three arguments
+    ProbeHit = 64,
+}
+
+impl Event {
+    const ALL: [Event; 16] = [
+        Event::ThreadRun,
+        Event::ThreadStop,
+        Event::TaskWake,
+        Event::TaskBlock,
+        Event::ChannelCreate,
+        Event::ChannelDestroy,
+        Event::MessageSend,
+        Event::MessageReceive,
+        Event::FrameAllocate,
+        Event::FrameFree,
+        Event::PageMap,
+        Event::StorageRead,
+        Event::StorageWrite,
+        Event::StorageFlush,
+        Event::StorageDiscard,
+        Event::ProbeHit,
+    ];
+
+    pub fn subsystem(self) -> Subsystem {
+        match self {
+            Event::ThreadRun | Event::ThreadStop | Event::TaskWake | Event::TaskBlock => Subsystem::Scheduler,
+            Event::ChannelCreate | Event::ChannelDestroy | Event::MessageSend | Event::MessageReceive => {
+                Subsystem::Ipc
+            }
+            Event::FrameAllocate | Event::FrameFree | Event::PageMap => Subsystem::Memory,
+            Event::StorageRead | Event::StorageWrite | Event::StorageFlush | Event::StorageDiscard => {
+                Subsystem::Storage
+            }
+            Event::ProbeHit => Subsystem::Probe,
+        }
+    }
+
+    pub fn from_raw(raw: u16) -> Option<Self> {
+        Self::ALL.into_iter().find(|e| *e as u16 == raw)
+    }
+}
+
+/// One traced event
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Record {
+    pub tsc: u64,
+    /// An [`Event`]
+    pub event: u16,
+    pub cpu: u16,
+    /// Thread running when it was traced, 0 for the kernel
+    pub thread: u32,
+    pub args: [u64; 4],
+}
+
+impl Record {
+    const EMPTY: Record = Record { tsc: 0, event: 0, cpu: 0, thread: 0, args: [0; 4] };
+
+    pub fn to_bytes(self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0u8; RECORD_SIZE];
+        bytes[0..8].copy_from_slice(&self.tsc.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.event.to_le_bytes());
+        bytes[10..12].copy_from_slice(&self.cpu.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.thread.to_le_bytes());
+        for (i, arg) in self.args.iter().enumerate() {
+            bytes[16 + i * 8..24 + i * 8].copy_from_slice(&arg.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>20} cpu{} t{} ", self.tsc, self.cpu, self.thread)?;
+        match Event::from_raw(self.event) {
+            Some(event) => write!(f, "{:?}", event)?,
+            None => write!(f, "event {}", self.event)?,
+        }
+        write!(f, " {:#x} {:#x} {:#x} {:#x}", self.args[0], self.args[1], self.args[2], self.args[3])
+    }
+}
+
+struct Slot {
+    /// Sequence number of the record in it plus one; 0 while being written
+    sequence: AtomicU64,
+    record: UnsafeCell<Record>,
+}
+
+struct Ring {
+    /// Records ever written
+    head: AtomicU64,
+    /// Records ever read or skipped
+    tail: AtomicU64,
+    slots: [Slot; RING_RECORDS],
+}
+
+// A slot is only written by the ring's CPU, and readers check its sequence
+unsafe impl Sync for Ring {}
+
+static RINGS: [Ring; TRACE_CPUS] = [const {
+    Ring {
+        head: AtomicU64::new(0),
+        tail: AtomicU64::new(0),
+        slots: [const { Slot { sequence: AtomicU64::new(0), record: UnsafeCell::new(Record::EMPTY) } }; RING_RECORDS],
+    }
+}; TRACE_CPUS];
+
+/// Subsystems being traced, a bit each
+static ENABLED: AtomicU32 = AtomicU32::new(0);
+
+/// Records from CPUs without a ring
+static UNTRACED: AtomicU64 = AtomicU64::new(0);
+
+/// Held while records are taken out of the rings
+static READER: Mutex<()> = Mutex::new(());
+
+/// Service channel, once created
+static CHANNEL: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Whether events of `subsystem` are being recorded
+pub fn enabled(subsystem: Subsystem) -> bool {
+    ENABLED.load(Ordering::Relaxed) & subsystem.bit() != 0
+}
+
+/// Mask of the subsystems being traced
+pub fn mask() -> u32 {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Trace the subsystems in `mask`, and only those
+pub fn set_mask(mask: u32) {
+    ENABLED.store(mask, Ordering::Relaxed);
+}
+
+/// Record `event` with up to four arguments; use [`crate::trace!`]
+pub fn record(event: Event, args: &[u64]) {
+    let cpu = super::percpu::current_cpu_id();
+    let Some(ring) = RINGS.get(cpu as usize) else {
+        UNTRACED.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+    let mut record = Record {
+        tsc: unsafe { core::arch::x86_64::_rdtsc() },
+        event: event as u16,
+        cpu: cpu as u16,
+        thread: crate::userspace::process::current_thread(),
+        args: [0; 4],
+    };
+    for (to, from) in record.args.iter_mut().zip(args) {
+        *to = *from;
+    }
+
+    // An interrupt tracing meanwhile claims the slot after
+    let sequence = ring.head.fetch_add(1, Ordering::Relaxed);
+    let slot = &ring.slots[sequence as usize % RING_RECORDS];
+    slot.sequence.store(0, Ordering::Relaxed);
+    core::sync::atomic::fence(Ordering::Release);
+    unsafe { core::ptr::write_volatile(slot.record.get(), record) };
+    slot.sequence.store(sequence + 1, Ordering::Release);
+}
+
+/// Record an event if its subsystem is traced, as
+/// `trace!(MessageSend, channel, sender, msg_type, length)`
+#[macro_export]
+macro_rules! trace {
+    ($event:ident $(, $arg:expr)* $(,)?) => {{
+        let event = $crate::kernel::trace::Event::$event;
+        if $crate::kernel::trace::enabled(event.subsystem()) {
+            $crate::kernel::trace::record(event, &[$($arg as u64),*]);
+        }
+    }};
+}
+
+/// Take every record not read yet, a CPU at a time and oldest first
+/// within each, returning how many were lost since the last read
+pub fn drain(mut f: impl FnMut(&Record)) -> u64 {
+    let _reader = READER.lock();
+    let mut lost = UNTRACED.swap(0, Ordering::Relaxed);
+    for ring in RINGS.iter() {
+        let head = ring.head.load(Ordering::Acquire);
+        let mut sequence = ring.tail.load(Ordering::Relaxed);
+        if head - sequence > RING_RECORDS as u64 {
+            lost += head - sequence - RING_RECORDS as u64;
+            sequence = head - RING_RECORDS as u64;
+        }
+        while sequence < head {
+            let slot = &ring.slots[sequence as usize % RING_RECORDS];
+            let mark = slot.sequence.load(Ordering::Acquire);
+            let record = unsafe { core::ptr::read_volatile(slot.record.get()) };
+            core::sync::atomic::fence(Ordering::Acquire);
+            if mark == sequence + 1 && slot.sequence.load(Ordering::Relaxed) == mark {
+                f(&record);
+            } else {
+                lost += 1;
+            }
+            sequence += 1;
+        }
+        ring.tail.store(sequence, Ordering::Relaxed);
+    }
+    lost
+}
+
+/// Parse a comma-separated list of subsystem names into a mask
+pub fn parse_mask(list: &str) -> Option<u32> {
+    list.split(',')
+        .filter(|name| !name.is_empty())
+        .try_fold(0, |mask, name| match name {
+            "all" => Some(mask | Subsystem::ALL.iter().fold(0, |m, s| m | s.bit())),
+            name => Some(mask | Subsystem::parse(name)?.bit()),
+        })
+}
+
+/// Trace the subsystems named on the command line
+pub fn init() {
+    let Some(list) = crate::boot::cmdline::get("trace") else {
+        return;
+    };
+    match parse_mask(list) {
+        Some(mask) => set_mask(mask),
+        None => crate::klog!(Warn, "trace: unknown subsystem in {}", list),
+    }
+}
+
+/// Create the service channel and register its name
+pub fn serve() -> Result<u64, ipc::IpcError> {
+    let channel = ipc::create_channel()?;
+    names::register(SERVICE_NAME, channel)?;
+    *CHANNEL.lock() = Some(channel);
+    Ok(channel)
+}
+
+fn reply(channel: u64, receiver: u32, msg_type: u32, data: &[u8]) {
+    let header = MessageHeader {
+        id: 0,
+        sender: 0, // kernel
+        receiver,
+        length: data.len() as u32,
+        msg_type,
+    };
+    let _ = ipc::msg_send(channel, header, data);
+}
+
+fn status(channel: u64, receiver: u32, status: u32, lost: u64) {
+    let mut msg = [0u8; 20];
+    msg[0..4].copy_from_slice(&status.to_le_bytes());
+    msg[4..12].copy_from_slice(&lost.to_le_bytes());
+    msg[12..20].copy_from_slice(&super::clocksource::tsc_frequency().unwrap_or(0).to_le_bytes());
+    reply(channel, receiver, TRACE_STATUS_MSG, &msg);
+}
+
+/// Send every record not read yet to `channel`
+fn send_records(channel: u64, receiver: u32) -> u64 {
+    const PER_MESSAGE: usize = ipc::MAX_MESSAGE_SIZE / RECORD_SIZE;
+
+    let mut msg = [0u8; PER_MESSAGE * RECORD_SIZE];
+    let mut count = 0;
+    let lost = drain(|record| {
+        msg[count * RECORD_SIZE..(count + 1) * RECORD_SIZE].copy_from_slice(&record.to_bytes());
+        count += 1;
+        if count == PER_MESSAGE {
+            reply(channel, receiver, TRACE_RECORDS_MSG, &msg);
+            count = 0;
+        }
+    });
+    if count > 0 {
+        reply(channel, receiver, TRACE_RECORDS_MSG, &msg[..count * RECORD_SIZE]);
+    }
+    lost
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+/// Answer requests on the service channel
+pub fn poll() {
+    let Some(channel) = *CHANNEL.lock() else {
+        return;
+    };
+
+    let mut buffer = [0; ipc::MAX_MESSAGE_SIZE];
+    while let Ok((header, len)) = ipc::msg_recv(channel, &mut buffer) {
+        let data = &buffer[..len];
+        let Some(reply_channel) = read_u64(data, 0) else {
+            continue;
+        };
+        if capability::check_permission(header.sender, Permission::Trace).is_err() {
+            status(reply_channel, header.sender, STATUS_PERMISSION_DENIED, 0);
+            continue;
+        }
+        match (header.msg_type, read_u32(data, 8)) {
+            (TRACE_ENABLE_MSG, Some(mask)) => {
+                set_mask(mask);
+                status(reply_channel, header.sender, STATUS_OK, 0);
+            }
+            (TRACE_READ_MSG, _) => {
+                let lost = send_records(reply_channel, header.sender);
+                status(reply_channel, header.sender, STATUS_OK, lost);
+            }
+            _ => status(reply_channel, header.sender, STATUS_INVALID_REQUEST, 0),
+        }
+    }
+}
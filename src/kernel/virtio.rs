@@ -0,0 +1,420 @@
+//! Virtio devices over PCI
+//!
+//! The modern (virtio 1.0) PCI transport. Vendor-specific capabilities
+//! point into the BARs at four structures, which [`Transport::probe`]
+//! maps: the common configuration, through which features are negotiated
+//! and queues set up; the notification area; the interrupt status; and the
+//! configuration of the device's own kind. Legacy devices, which have
+//! none of them, are not driven.
+//!
+//! Each queue is a split virtqueue: a table of buffer descriptors, the
+//! ring the driver makes chains of them available on and the ring the
+//! device hands them back on once used, together in one physically
+//! contiguous buffer.
+
+use core::sync::atomic::{fence, Ordering};
+
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::dma::{self, DmaDirection, DmaMapping};
+use super::memory;
+use super::pci::{self, PciAddress};
+
+/// Vendor ID of virtio devices
+pub const VENDOR: u16 = 0x1AF4;
+
+/// Vendor-specific capability types
+const CAP_COMMON: u8 = 1;
+const CAP_NOTIFY: u8 = 2;
+const CAP_ISR: u8 = 3;
+const CAP_DEVICE: u8 = 4;
+
+/// Common configuration registers
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0C;
+const COMMON_MSIX_CONFIG: u64 = 0x10;
+const COMMON_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: u64 = 0x1A;
+const COMMON_QUEUE_ENABLE: u64 = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1E;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+/// Device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// The device follows virtio 1.0, which every modern device offers
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+/// No MSI-X table entry, for a queue or configuration changes
+pub const NO_VECTOR: u16 = 0xFFFF;
+
+/// Descriptor flags: the chain goes on, the device writes the buffer
+const DESC_NEXT: u16 = 1;
+const DESC_WRITE: u16 = 2;
+
+#[derive(Debug)]
+pub enum VirtioError {
+    /// A structure of the modern transport is not there
+    NotModern,
+    MapFailed,
+    OutOfMemory,
+    /// The device did not accept the features negotiated
+    FeaturesRejected,
+    NoSuchQueue,
+    QueueFull,
+    /// Neither MSI-X nor an INTx line
+    NoInterrupt,
+}
+
+/// Physically contiguous memory shared with a device
+pub struct DmaBuffer {
+    pub virt: VirtAddr,
+    /// Address the device reaches it at
+    pub bus: u64,
+    phys: PhysAddr,
+    pages: u64,
+    mapping: DmaMapping,
+}
+
+impl DmaBuffer {
+    /// Zeroed memory of at least `len` bytes, reachable without bouncing
+    pub fn new(len: usize) -> Result<DmaBuffer, VirtioError> {
+        let pages = len.div_ceil(4096) as u64;
+        let phys = memory::allocate_contiguous(pages, u64::MAX).map_err(|_| VirtioError::OutOfMemory)?;
+        let virt = memory::phys_to_virt(phys);
+        let len = pages as usize * 4096;
+        unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, len) };
+        let buffer = match unsafe { dma::map(virt, len, DmaDirection::Bidirectional, dma::DMA_64BIT) } {
+            // Both sides use the memory at once, which a bounce copy cannot give
+            Ok(mapping) if mapping.is_bounced() => {
+                dma::unmap(mapping);
+                None
+            }
+            Ok(mapping) => Some(DmaBuffer { virt, bus: mapping.bus_addr, phys, pages, mapping }),
+            Err(_) => None,
+        };
+        buffer.ok_or_else(|| {
+            free_pages(phys, pages);
+            VirtioError::MapFailed
+        })
+    }
+
+    pub fn free(self) {
+        dma::unmap(self.mapping);
+        free_pages(self.phys, self.pages);
+    }
+}
+
+fn free_pages(phys: PhysAddr, pages: u64) {
+    for page in 0..pages {
+        memory::free_frame(PhysFrame::containing_address(phys + page * 4096));
+    }
+}
+
+fn read<T>(base: u64, offset: u64) -> T {
+    unsafe { core::ptr::read_volatile((base + offset) as *const T) }
+}
+
+fn write<T>(base: u64, offset: u64, value: T) {
+    unsafe { core::ptr::write_volatile((base + offset) as *mut T, value) }
+}
+
+/// A device's structures of the modern transport, mapped
+pub struct Transport {
+    pub address: PciAddress,
+    common: u64,
+    notify: u64,
+    /// Bytes between the notification addresses of consecutive offsets
+    notify_multiplier: u32,
+    isr: u64,
+    device: u64,
+}
+
+impl Transport {
+    /// Map the device's structures; [`VirtioError::NotModern`] if it lacks
+    /// one
+    pub fn probe(device: &pci::Device) -> Result<Transport, VirtioError> {
+        let address = device.address;
+        // Mapped structures by type; the first of a type is the one to use
+        let mut found = [None; CAP_DEVICE as usize + 1];
+        let mut notify_multiplier = 0;
+        for cap in address.capabilities(pci::CAP_VENDOR) {
+            let cfg_type = (address.read(cap) >> 24) as u8;
+            if !(CAP_COMMON..=CAP_DEVICE).contains(&cfg_type) || found[cfg_type as usize].is_some() {
+                continue;
+            }
+            let bar = address.read(cap + 4) as u8;
+            let (offset, length) = (address.read(cap + 8), address.read(cap + 12));
+            let Some((base, _)) = device.memory_bar(bar as usize) else {
+                continue;
+            };
+            let virt = memory::map_mmio(PhysAddr::new(base + offset as u64), length.max(1) as usize)
+                .map_err(|_| VirtioError::MapFailed)?;
+            found[cfg_type as usize] = Some(virt.as_u64());
+            if cfg_type == CAP_NOTIFY {
+                notify_multiplier = address.read(cap + 16);
+            }
+        }
+        let structure = |cfg_type: u8| found[cfg_type as usize];
+        let (Some(common), Some(notify), Some(isr), Some(device)) =
+            (structure(CAP_COMMON), structure(CAP_NOTIFY), structure(CAP_ISR), structure(CAP_DEVICE))
+        else {
+            return Err(VirtioError::NotModern);
+        };
+        address.enable(pci::COMMAND_MEMORY | pci::COMMAND_BUS_MASTER);
+        Ok(Transport { address, common, notify, notify_multiplier, isr, device })
+    }
+
+    pub fn status(&self) -> u8 {
+        read(self.common, COMMON_STATUS)
+    }
+
+    fn add_status(&self, bits: u8) {
+        write(self.common, COMMON_STATUS, self.status() | bits);
+    }
+
+    /// Reset the device, which stops it using its queues, and say a driver
+    /// has found it
+    pub fn reset(&self) {
+        write(self.common, COMMON_STATUS, 0u8);
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+        self.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+    }
+
+    /// Features the device offers
+    pub fn features(&self) -> u64 {
+        write(self.common, COMMON_DEVICE_FEATURE_SELECT, 0u32);
+        let low = read::<u32>(self.common, COMMON_DEVICE_FEATURE);
+        write(self.common, COMMON_DEVICE_FEATURE_SELECT, 1u32);
+        let high = read::<u32>(self.common, COMMON_DEVICE_FEATURE);
+        (high as u64) << 32 | low as u64
+    }
+
+    /// Take up the features of `wanted` the device offers, and
+    /// [`FEATURE_VERSION_1`], returning them; after a [`reset`](Self::reset)
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, VirtioError> {
+        let offered = self.features();
+        if offered & FEATURE_VERSION_1 == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err(VirtioError::NotModern);
+        }
+        let accepted = offered & (wanted | FEATURE_VERSION_1);
+        write(self.common, COMMON_DRIVER_FEATURE_SELECT, 0u32);
+        write(self.common, COMMON_DRIVER_FEATURE, accepted as u32);
+        write(self.common, COMMON_DRIVER_FEATURE_SELECT, 1u32);
+        write(self.common, COMMON_DRIVER_FEATURE, (accepted >> 32) as u32);
+        self.add_status(STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err(VirtioError::FeaturesRejected);
+        }
+        Ok(accepted)
+    }
+
+    /// MSI-X table entry configuration changes are signalled on
+    pub fn set_config_vector(&self, entry: u16) {
+        write(self.common, COMMON_MSIX_CONFIG, entry);
+    }
+
+    /// Set up and enable queue `index` with at most `max_size` entries,
+    /// signalled on MSI-X table entry `entry`
+    pub fn setup_queue(&self, index: u16, max_size: u16, entry: u16) -> Result<Virtqueue, VirtioError> {
+        write(self.common, COMMON_QUEUE_SELECT, index);
+        let offered = read::<u16>(self.common, COMMON_QUEUE_SIZE);
+        if offered == 0 {
+            return Err(VirtioError::NoSuchQueue);
+        }
+        // Split queue sizes are powers of two
+        let size = 1 << offered.min(max_size.max(1)).ilog2();
+        let notify_offset = read::<u16>(self.common, COMMON_QUEUE_NOTIFY_OFF) as u64;
+        let notify = self.notify + notify_offset * self.notify_multiplier as u64;
+        let queue = Virtqueue::new(index, size, notify)?;
+
+        write(self.common, COMMON_QUEUE_SIZE, size);
+        write(self.common, COMMON_QUEUE_MSIX_VECTOR, entry);
+        for (register, address) in [
+            (COMMON_QUEUE_DESC, queue.memory.bus),
+            (COMMON_QUEUE_DRIVER, queue.memory.bus + queue.avail_offset as u64),
+            (COMMON_QUEUE_DEVICE, queue.memory.bus + queue.used_offset as u64),
+        ] {
+            write(self.common, register, address as u32);
+            write(self.common, register + 4, (address >> 32) as u32);
+        }
+        write(self.common, COMMON_QUEUE_ENABLE, 1u16);
+        Ok(queue)
+    }
+
+    /// Let the device start; its queues can be notified from now on
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    /// Read the interrupt status, which clears it and deasserts the INTx
+    /// line
+    pub fn interrupt_status(&self) -> u8 {
+        read(self.isr, 0)
+    }
+
+    /// A byte of the device-specific configuration
+    pub fn config8(&self, offset: u64) -> u8 {
+        read(self.device, offset)
+    }
+
+    pub fn config16(&self, offset: u64) -> u16 {
+        read(self.device, offset)
+    }
+}
+
+/// An entry of the descriptor table
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A split virtqueue
+///
+/// Free descriptors are kept chained through their `next` fields.
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    memory: DmaBuffer,
+    /// Offsets in `memory` of the available and used rings
+    avail_offset: usize,
+    used_offset: usize,
+    notify: u64,
+    free_head: u16,
+    free: u16,
+    /// The available ring's index, as the device will see it next
+    next_avail: u16,
+    /// The used ring's index this far
+    last_used: u16,
+}
+
+impl Virtqueue {
+    fn new(index: u16, size: u16, notify: u64) -> Result<Virtqueue, VirtioError> {
+        let entries = size as usize;
+        let avail_offset = 16 * entries;
+        // Flags, index, ring and the used event; the used ring is 4-aligned
+        let used_offset = (avail_offset + 6 + 2 * entries).next_multiple_of(4);
+        let memory = DmaBuffer::new(used_offset + 6 + 8 * entries)?;
+        let queue = Virtqueue {
+            index,
+            size,
+            memory,
+            avail_offset,
+            used_offset,
+            notify,
+            free_head: 0,
+            free: size,
+            next_avail: 0,
+            last_used: 0,
+        };
+        for i in 0..size {
+            queue.set_descriptor(i, Descriptor { address: 0, length: 0, flags: 0, next: i.wrapping_add(1) });
+        }
+        Ok(queue)
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn base(&self) -> u64 {
+        self.memory.virt.as_u64()
+    }
+
+    fn descriptor(&self, i: u16) -> Descriptor {
+        read(self.base(), i as u64 * 16)
+    }
+
+    fn set_descriptor(&self, i: u16, descriptor: Descriptor) {
+        write(self.base(), i as u64 * 16, descriptor);
+    }
+
+    /// Make a chain of buffers, each a bus address, a length and whether
+    /// the device writes it, available to the device, returning the
+    /// chain's head; device-written buffers go after the rest
+    pub fn add(&mut self, buffers: &[(u64, u32, bool)]) -> Result<u16, VirtioError> {
+        if buffers.is_empty() || buffers.len() > self.free as usize {
+            return Err(VirtioError::QueueFull);
+        }
+        let head = self.free_head;
+        let mut index = head;
+        for (i, &(address, length, writable)) in buffers.iter().enumerate() {
+            let next = self.descriptor(index).next;
+            let mut flags = if writable { DESC_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= DESC_NEXT;
+            }
+            self.set_descriptor(index, Descriptor { address, length, flags, next });
+            index = next;
+        }
+        self.free_head = index;
+        self.free -= buffers.len() as u16;
+
+        let slot = (self.next_avail % self.size) as u64;
+        write(self.base(), self.avail_offset as u64 + 4 + slot * 2, head);
+        self.next_avail = self.next_avail.wrapping_add(1);
+        // The entry must be visible before the index that hands it over
+        fence(Ordering::Release);
+        write(self.base(), self.avail_offset as u64 + 2, self.next_avail);
+        Ok(head)
+    }
+
+    /// Tell the device there are buffers available
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        write(self.notify, 0, self.index);
+    }
+
+    /// Take back the next chain the device has used, returning its head
+    /// and the bytes the device wrote
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used = read::<u16>(self.base(), self.used_offset as u64 + 2);
+        if used == self.last_used {
+            return None;
+        }
+        fence(Ordering::Acquire);
+        let slot = (self.last_used % self.size) as u64;
+        let element = self.used_offset as u64 + 4 + slot * 8;
+        let head = read::<u32>(self.base(), element) as u16;
+        let length = read::<u32>(self.base(), element + 4);
+        self.last_used = self.last_used.wrapping_add(1);
+
+        // Put the chain back on the free list
+        let mut tail = head;
+        let mut count = 1;
+        while self.descriptor(tail).flags & DESC_NEXT != 0 {
+            tail = self.descriptor(tail).next;
+            count += 1;
+        }
+        let mut last = self.descriptor(tail);
+        last.next = self.free_head;
+        self.set_descriptor(tail, last);
+        self.free_head = head;
+        self.free += count;
+        Some((head, length))
+    }
+
+    /// Give the queue's memory back, once the device is reset
+    pub fn free(self) {
+        self.memory.free();
+    }
+}
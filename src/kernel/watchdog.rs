@@ -0,0 +1,260 @@
+//! Lockup watchdog
+//!
+//! Two checks, both on the boot CPU, which runs everything the others do
+//! not. A soft lockup is the scheduler loop not coming round for
+//! [`SOFT_LOCKUP_SECONDS`] while the timer still ticks; the timer
+//! interrupt notices it and reports where it interrupted. A hard lockup is
+//! the timer itself not ticking for [`HARD_LOCKUP_SECONDS`], as when the
+//! CPU is stuck with interrupts disabled. Only a non-maskable interrupt
+//! gets through then, so the first performance counter counts unhalted
+//! cycles and raises one each time it overflows, about every second of
+//! work. CPUs without architectural performance monitoring, AMD's among
+//! them, get only the soft check.
+//!
+//! A lockup is reported once, on the serial port, with the registers, a
+//! backtrace and the last kernel log records. `watchdog=panic` on the
+//! command line panics instead, which prints the same and stops, and
+//! `watchdog=off` turns both checks off. An NMI the counter did not raise
+//! is left to [`super::nmi`].
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::{apic, backtrace, clocksource, klog};
+use crate::scheduler::{self, TICKS_PER_SECOND};
+use crate::userspace::usermode::UserContext;
+
+/// Time without progress before a lockup is reported
+pub const SOFT_LOCKUP_SECONDS: u64 = 10;
+pub const HARD_LOCKUP_SECONDS: u64 = 10;
+
+/// Kernel log records printed with a report
+const REPORT_LOG_RECORDS: usize = 10;
+
+/// Performance monitoring model-specific registers
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+/// Event select: unhalted core cycles, in both rings, interrupting on
+/// overflow, enabled
+const EVENT_CYCLES: u64 = 0x3C | 1 << 16 | 1 << 17 | 1 << 20 | 1 << 22;
+
+/// Longest period a legacy counter write can set, which sign-extends
+/// bit 31
+const MAX_PERIOD: u64 = 0x7FFF_FFFF;
+
+/// What is done about a lockup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Action {
+    Off,
+    Report,
+    Panic,
+}
+
+/// Which checks run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coverage {
+    Off,
+    Soft,
+    SoftAndHard,
+}
+
+static ACTION: AtomicU8 = AtomicU8::new(Action::Off as u8);
+
+/// Times the scheduler loop has come round
+static PROGRESS: AtomicU64 = AtomicU64::new(0);
+/// Progress when the timer last saw it change, and the tick it did
+static SEEN_PROGRESS: AtomicU64 = AtomicU64::new(0);
+static PROGRESS_TICK: AtomicU64 = AtomicU64::new(0);
+static SOFT_REPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Tick count when an NMI last saw it change, and the TSC then
+static SEEN_TICKS: AtomicU64 = AtomicU64::new(0);
+static TICKS_TSC: AtomicU64 = AtomicU64::new(0);
+static HARD_REPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Counter state, set once it is running
+static COUNTING: AtomicBool = AtomicBool::new(false);
+static PERIOD: AtomicU64 = AtomicU64::new(0);
+static COUNTER_BITS: AtomicU8 = AtomicU8::new(0);
+static PERFMON_VERSION: AtomicU8 = AtomicU8::new(0);
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+fn action() -> Action {
+    match ACTION.load(Ordering::Relaxed) {
+        1 => Action::Report,
+        2 => Action::Panic,
+        _ => Action::Off,
+    }
+}
+
+fn tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Note that the scheduler loop came round
+pub fn pet() {
+    PROGRESS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Check for a soft lockup; called every timer tick with what it
+/// interrupted
+pub fn tick(context: &UserContext) {
+    // Nothing to watch until the scheduler loop runs
+    let progress = PROGRESS.load(Ordering::Relaxed);
+    if action() == Action::Off || progress == 0 {
+        return;
+    }
+    let now = scheduler::ticks();
+    if SEEN_PROGRESS.swap(progress, Ordering::Relaxed) != progress {
+        PROGRESS_TICK.store(now, Ordering::Relaxed);
+        SOFT_REPORTED.store(false, Ordering::Relaxed);
+        return;
+    }
+    let stalled = now - PROGRESS_TICK.load(Ordering::Relaxed);
+    if stalled >= SOFT_LOCKUP_SECONDS * TICKS_PER_SECOND && !SOFT_REPORTED.swap(true, Ordering::Relaxed) {
+        let seconds = stalled / TICKS_PER_SECOND;
+        let reported = report("Soft lockup", seconds, || {
+            crate::serial_println!("RIP {:#018x} RSP {:#018x} RFLAGS {:#x}", context.rip, context.rsp, context.rflags);
+            backtrace::print_from(context.rip, context.rbp);
+        });
+        if reported {
+            crate::klog!(Error, "Scheduler loop stuck for {} s at {:#x}", seconds, context.rip);
+        } else {
+            SOFT_REPORTED.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Whether the counter overflowed, which leaves its top bit clear
+fn overflowed() -> bool {
+    if !COUNTING.load(Ordering::Relaxed) {
+        return false;
+    }
+    let top = 1 << (COUNTER_BITS.load(Ordering::Relaxed) - 1);
+    unsafe { Msr::new(IA32_PMC0).read() & top == 0 }
+}
+
+/// Start the counter on a new period and let it raise the next NMI
+fn rearm() {
+    unsafe {
+        Msr::new(IA32_PMC0).write(PERIOD.load(Ordering::Relaxed).wrapping_neg() & 0xFFFF_FFFF);
+        if PERFMON_VERSION.load(Ordering::Relaxed) >= 2 {
+            Msr::new(IA32_PERF_GLOBAL_OVF_CTRL).write(1);
+        }
+    }
+    // Delivering the NMI masked the entry
+    apic::set_perf_counter(apic::DELIVERY_NMI);
+}
+
+/// Handle a non-maskable interrupt if the counter raised it, checking for
+/// a hard lockup; false if something else did
+pub fn nmi(frame: &InterruptStackFrame) -> bool {
+    if !overflowed() {
+        return false;
+    }
+    let registers = || {
+        crate::serial_println!(
+            "RIP {:#018x} RSP {:#018x} RFLAGS {:#x}",
+            frame.instruction_pointer.as_u64(),
+            frame.stack_pointer.as_u64(),
+            frame.cpu_flags.bits()
+        );
+        backtrace::print();
+    };
+    rearm();
+    if action() == Action::Off {
+        return true;
+    }
+    let (ticks, now) = (scheduler::ticks(), tsc());
+    if SEEN_TICKS.swap(ticks, Ordering::Relaxed) != ticks {
+        TICKS_TSC.store(now, Ordering::Relaxed);
+        HARD_REPORTED.store(false, Ordering::Relaxed);
+        return true;
+    }
+    let hz = TSC_HZ.load(Ordering::Relaxed).max(1);
+    let stalled = now.saturating_sub(TICKS_TSC.load(Ordering::Relaxed)) / hz;
+    if stalled >= HARD_LOCKUP_SECONDS
+        && !HARD_REPORTED.swap(true, Ordering::Relaxed)
+        && !report("Hard lockup (no timer ticks)", stalled, registers)
+    {
+        HARD_REPORTED.store(false, Ordering::Relaxed);
+    }
+    true
+}
+
+/// Print a lockup with `registers` printing where it is stuck, or panic;
+/// false if the serial port is held, to try again later
+fn report(what: &str, seconds: u64, registers: impl FnOnce()) -> bool {
+    let serial = &crate::boot::serial::SERIAL1;
+    if action() == Action::Panic {
+        // Whatever held the port is not coming back
+        if serial.is_locked() {
+            unsafe { serial.force_unlock() };
+        }
+        panic!("Watchdog: {} for {} s", what, seconds);
+    }
+    if serial.is_locked() {
+        return false;
+    }
+    crate::serial_println!("Watchdog: {} for {} s", what, seconds);
+    registers();
+    klog::dump(REPORT_LOG_RECORDS);
+    true
+}
+
+/// Architectural performance monitoring: the version and the width of
+/// the general counters, if the unhalted cycles event is there
+fn perfmon() -> Option<(u8, u8)> {
+    use core::arch::x86_64::{__cpuid, __get_cpuid_max};
+
+    if __get_cpuid_max(0).0 < 0xA {
+        return None;
+    }
+    let leaf = __cpuid(0xA);
+    let (version, counters, bits, events) =
+        (leaf.eax as u8, (leaf.eax >> 8) as u8, (leaf.eax >> 16) as u8, (leaf.eax >> 24) as u8);
+    // A set bit 0 in EBX means the cycles event is missing
+    (version >= 1 && counters >= 1 && bits > 32 && events >= 1 && leaf.ebx & 1 == 0).then_some((version, bits))
+}
+
+/// Start the checks the command line and the CPU allow; needs the clock
+/// sources and the local APIC
+pub fn init() -> Coverage {
+    let action = match crate::boot::cmdline::get("watchdog") {
+        Some("off") => Action::Off,
+        Some("panic") => Action::Panic,
+        _ => Action::Report,
+    };
+    ACTION.store(action as u8, Ordering::Relaxed);
+    if action == Action::Off {
+        return Coverage::Off;
+    }
+    let Some((version, bits)) = perfmon().filter(|_| apic::present()) else {
+        return Coverage::Soft;
+    };
+    let hz = clocksource::tsc_frequency().unwrap_or_else(clocksource::pit_calibrate_tsc);
+    TSC_HZ.store(hz, Ordering::Relaxed);
+    PERIOD.store(hz.clamp(1, MAX_PERIOD), Ordering::Relaxed);
+    PERFMON_VERSION.store(version, Ordering::Relaxed);
+    COUNTER_BITS.store(bits, Ordering::Relaxed);
+    TICKS_TSC.store(tsc(), Ordering::Relaxed);
+    unsafe {
+        Msr::new(IA32_PERFEVTSEL0).write(0);
+    }
+    COUNTING.store(true, Ordering::Relaxed);
+    rearm();
+    unsafe {
+        if version >= 2 {
+            let mut global = Msr::new(IA32_PERF_GLOBAL_CTRL);
+            global.write(global.read() | 1);
+        }
+        Msr::new(IA32_PERFEVTSEL0).write(EVENT_CYCLES);
+    }
+    Coverage::SoftAndHard
+}
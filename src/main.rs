@@ -12,6 +12,9 @@
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
 #![feature(const_mut_refs)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::testing::run)]
+#![reexport_test_harness_main = "test_main"]
 #![deny(unsafe_code)]
 #![allow(unsafe_code)] // Only for assembly glue and hardware interaction
 
@@ -19,6 +22,7 @@ extern crate alloc;
 
 use core::panic::PanicInfo;
 use bootloader::{BootInfo, entry_point};
+use boot::report::{run, stage};
 
 mod boot;
 mod kernel;
@@ -27,72 +31,97 @@ mod ipc;
 mod capability;
 mod tagfs;
 mod storage;
+mod net;
 mod gpu;
 mod ai;
 mod userspace;
 mod compat;
+mod shell;
+#[cfg(test)]
+mod testing;
 
 entry_point!(kernel_main);
 
+/// Kernel log records printed on a panic
+const PANIC_LOG_RECORDS: usize = 10;
+
 /// Kernel entry point called by the bootloader
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // Initialize serial output for early debugging
     boot::serial::init();
-    crate::serial_println!("Zen OS v0.1.0 - Booting...");
-
-    // Initialize core kernel components
-    kernel::init(boot_info);
-    crate::serial_println!("[OK] Kernel core initialized");
+    kernel::klog::init();
+    crate::klog!(Info, "Zen OS v0.1.0 - Booting...");
 
-    // Initialize memory management
-    kernel::memory::init(boot_info);
-    crate::serial_println!("[OK] Memory management initialized");
+    // Time every stage from here on
+    boot::report::start();
 
-    // Initialize interrupt handling
-    kernel::interrupts::init();
-    crate::serial_println!("[OK] Interrupt handling initialized");
-
-    // Initialize per-CPU structures
-    kernel::percpu::init();
-    crate::serial_println!("[OK] Per-CPU structures initialized");
+    // Initialize core kernel components
+    run("Kernel core", || kernel::init(boot_info));
 
     // Initialize scheduler
-    scheduler::init();
-    crate::serial_println!("[OK] Scheduler initialized");
+    run("Scheduler", scheduler::init);
+
+    // Start the other processors
+    if let Some(cpus) = stage("Application processors", kernel::smp::init) {
+        crate::klog!(Info, "{} CPUs online", cpus);
+    }
 
     // Initialize IPC subsystem
-    ipc::init();
-    crate::serial_println!("[OK] IPC subsystem initialized");
+    run("IPC subsystem", ipc::init);
 
     // Initialize capability system
-    capability::init();
-    crate::serial_println!("[OK] Capability system initialized");
+    run("Capability system", capability::init);
+
+    // Trace records for viewers, over IPC
+    stage("Trace service", kernel::trace::serve);
 
     // Initialize TagFS
-    tagfs::init();
-    crate::serial_println!("[OK] TagFS initialized");
+    run("TagFS", tagfs::init);
+
+    // Unpack the initrd, for what is needed before there is storage
+    if let Some(files @ 1..) = stage("Initrd", boot::initrd::load) {
+        crate::klog!(Info, "{} files from the initrd", files);
+    }
 
     // Initialize storage subsystem
-    storage::init();
-    crate::serial_println!("[OK] Storage subsystem initialized");
+    run("Storage subsystem", storage::init);
+
+    // Initialize network subsystem
+    run("Network subsystem", net::init);
 
-    // Initialize GPU/compositor
-    gpu::init();
-    crate::serial_println!("[OK] GPU subsystem initialized");
+    // The report can be kept from here on
+    if let Err(e) = boot::report::persist() {
+        crate::klog!(Warn, "Boot report not written: {:?}", e);
+    }
+
+    // Initialize GPU/compositor, with the firmware's framebuffer to fall
+    // back on
+    run("GPU subsystem", || gpu::init(boot::framebuffer::capture()));
 
     // Initialize AI inference engine
-    ai::init();
-    crate::serial_println!("[OK] AI inference engine initialized");
+    run("AI inference engine", ai::init);
 
     // Initialize userspace environment
-    userspace::init();
-    crate::serial_println!("[OK] Userspace environment initialized");
+    run("Userspace environment", userspace::init);
 
     // Initialize compatibility layer
-    compat::init();
-    crate::serial_println!("[OK] Compatibility layer initialized");
+    run("Compatibility layer", compat::init);
+
+    // Self tests, when asked for
+    if boot::post::enabled() {
+        stage("Self tests", boot::post::run);
+    }
+
+    if let Err(e) = boot::report::persist() {
+        crate::klog!(Warn, "Boot report not written: {:?}", e);
+    }
+    crate::klog!(Info, "=== Zen OS Boot Complete ({} ms) ===", boot::report::now() / 1000);
+
+    // In a test build the tests run now, and leave QEMU when done
+    #[cfg(test)]
+    test_main();
 
-    crate::serial_println!("\n=== Zen OS Boot Complete ===\n");
+    shell::init();
 
     // Start the scheduler and enter idle loop
     scheduler::start();
@@ -104,16 +133,28 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 }
 
 /// Panic handler for the kernel
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     crate::serial_println!("\n!!! KERNEL PANIC !!!");
     crate::serial_println!("{}", info);
-    
+    kernel::backtrace::print_registers();
+    kernel::backtrace::print();
+    kernel::klog::dump(PANIC_LOG_RECORDS);
+    boot::report::dump();
+
     loop {
         x86_64::instructions::hlt();
     }
 }
 
+/// Panic handler for a test build: the running test failed
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::panic(info)
+}
+
 /// Allocation error handler
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
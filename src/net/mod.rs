@@ -0,0 +1,382 @@
+//! Network subsystem - NIC drivers and the frames they carry
+//!
+//! A NIC driver implements [`NetDevice`] and registers it, which gives it
+//! an interface number. Its interrupts only raise the network softirq,
+//! which polls every interface: the driver reaps the frames it has sent
+//! and passes those it has received to [`deliver`], where they wait in the
+//! interface's receive queue for [`receive`]. Frames go out with
+//! [`transmit`].
+//!
+//! Checksums can be left to the NIC. A frame sent with
+//! [`Checksum::Partial`] has its transport checksum filled in by the NIC if
+//! it offers that, or here if not; a frame received with one is completed
+//! here before it is queued, so readers always see whole checksums.
+
+pub mod virtio_net;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use arrayvec::ArrayVec;
+
+use crate::kernel::softirq::{self, Softirq};
+use crate::kernel::sync::SpinLockIrq;
+
+/// Maximum number of network interfaces
+pub const MAX_INTERFACES: usize = 4;
+
+/// Largest Ethernet payload
+pub const MTU: usize = 1500;
+
+/// Ethernet header: destination, source and EtherType
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+
+/// Largest frame, without the frame check sequence
+pub const MAX_FRAME_SIZE: usize = ETHERNET_HEADER_SIZE + MTU;
+
+/// Received frames each interface holds before dropping new ones
+pub const RX_QUEUE_FRAMES: usize = 32;
+
+#[derive(Debug)]
+pub enum NetError {
+    NoSuchInterface,
+    TooManyInterfaces,
+    /// Shorter than an Ethernet header or longer than [`MAX_FRAME_SIZE`]
+    BadFrameSize,
+    /// The checksum's position is outside the frame
+    BadChecksumOffset,
+    /// The NIC has no room for another frame until it sends some
+    QueueFull,
+    LinkDown,
+}
+
+/// How a frame's transport checksum stands
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Checksum {
+    /// Whole, or not looked at
+    None,
+    /// Checked by the NIC
+    Valid,
+    /// To be filled in: summed from `start` to the end of the frame and
+    /// stored at `start + offset`, which holds the pseudo-header's sum
+    /// until then
+    Partial { start: u16, offset: u16 },
+}
+
+/// What a NIC does for the frames it handles
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Offloads {
+    /// Fills in the checksums of frames sent with [`Checksum::Partial`]
+    pub tx_checksum: bool,
+    /// Checks the checksums of frames received
+    pub rx_checksum: bool,
+}
+
+/// A NIC driver
+pub trait NetDevice: Sync {
+    fn name(&self) -> &'static str;
+    fn mac(&self) -> [u8; 6];
+    fn link_up(&self) -> bool;
+    fn offloads(&self) -> Offloads;
+
+    /// Queue a frame to send; `checksum` is only [`Checksum::Partial`] if
+    /// the NIC offloads it
+    fn transmit(&self, frame: &[u8], checksum: Checksum) -> Result<(), NetError>;
+
+    /// Reap the frames sent and [`deliver`] those received as interface
+    /// `interface`
+    fn poll(&self, interface: u32);
+}
+
+/// Traffic through an interface
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NetStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    /// Received frames dropped because the receive queue was full
+    pub rx_dropped: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_errors: u64,
+}
+
+/// An interface, as listed by [`interfaces`]
+#[derive(Clone, Copy, Debug)]
+pub struct InterfaceInfo {
+    pub interface: u32,
+    pub name: &'static str,
+    pub mac: [u8; 6],
+    pub link_up: bool,
+    pub offloads: Offloads,
+    pub stats: NetStats,
+}
+
+struct Counters {
+    rx_packets: AtomicU64,
+    rx_bytes: AtomicU64,
+    rx_dropped: AtomicU64,
+    tx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+    tx_errors: AtomicU64,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Counters {
+            rx_packets: AtomicU64::new(0),
+            rx_bytes: AtomicU64::new(0),
+            rx_dropped: AtomicU64::new(0),
+            tx_packets: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
+            tx_errors: AtomicU64::new(0),
+        }
+    }
+
+    fn reset(&self) {
+        for counter in [
+            &self.rx_packets,
+            &self.rx_bytes,
+            &self.rx_dropped,
+            &self.tx_packets,
+            &self.tx_bytes,
+            &self.tx_errors,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> NetStats {
+        NetStats {
+            rx_packets: self.rx_packets.load(Ordering::Relaxed),
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            rx_dropped: self.rx_dropped.load(Ordering::Relaxed),
+            tx_packets: self.tx_packets.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            tx_errors: self.tx_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A received frame waiting to be read
+struct Frame {
+    length: u16,
+    data: [u8; MAX_FRAME_SIZE],
+}
+
+/// Received frames, oldest first
+struct RxQueue {
+    frames: [Frame; RX_QUEUE_FRAMES],
+    head: usize,
+    count: usize,
+}
+
+/// Registered interfaces, by number; the softirq takes the lock too
+static INTERFACES: SpinLockIrq<[Option<&'static dyn NetDevice>; MAX_INTERFACES]> =
+    SpinLockIrq::new("net interfaces", [None; MAX_INTERFACES]);
+
+static RX_QUEUES: [SpinLockIrq<RxQueue>; MAX_INTERFACES] = [const {
+    SpinLockIrq::new(
+        "net rx queue",
+        RxQueue {
+            frames: [const { Frame { length: 0, data: [0; MAX_FRAME_SIZE] } }; RX_QUEUE_FRAMES],
+            head: 0,
+            count: 0,
+        },
+    )
+}; MAX_INTERFACES];
+
+static STATS: [Counters; MAX_INTERFACES] = [const { Counters::new() }; MAX_INTERFACES];
+
+/// Register a NIC, returning its interface number
+pub fn register(device: &'static dyn NetDevice) -> Result<u32, NetError> {
+    let mut interfaces = INTERFACES.lock();
+    let index = interfaces
+        .iter()
+        .position(|slot| slot.is_none())
+        .ok_or(NetError::TooManyInterfaces)?;
+    STATS[index].reset();
+    let mut queue = RX_QUEUES[index].lock();
+    queue.head = 0;
+    queue.count = 0;
+    interfaces[index] = Some(device);
+    Ok(index as u32)
+}
+
+/// Take an interface away, dropping the frames it has queued
+pub fn unregister(interface: u32) -> Result<(), NetError> {
+    let mut interfaces = INTERFACES.lock();
+    let slot = interfaces
+        .get_mut(interface as usize)
+        .filter(|slot| slot.is_some())
+        .ok_or(NetError::NoSuchInterface)?;
+    *slot = None;
+    RX_QUEUES[interface as usize].lock().count = 0;
+    Ok(())
+}
+
+pub fn device(interface: u32) -> Result<&'static dyn NetDevice, NetError> {
+    INTERFACES
+        .lock()
+        .get(interface as usize)
+        .copied()
+        .flatten()
+        .ok_or(NetError::NoSuchInterface)
+}
+
+/// Every registered interface
+pub fn interfaces() -> ArrayVec<InterfaceInfo, MAX_INTERFACES> {
+    let devices = *INTERFACES.lock();
+    devices
+        .iter()
+        .enumerate()
+        .filter_map(|(index, device)| {
+            let device = (*device)?;
+            Some(InterfaceInfo {
+                interface: index as u32,
+                name: device.name(),
+                mac: device.mac(),
+                link_up: device.link_up(),
+                offloads: device.offloads(),
+                stats: STATS[index].snapshot(),
+            })
+        })
+        .collect()
+}
+
+/// Sum `data` as 16-bit big-endian words, in ones' complement
+fn ones_complement_sum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)]) as u32)
+        .sum();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Fill in a [`Checksum::Partial`] checksum
+fn complete_checksum(frame: &mut [u8], start: u16, offset: u16) -> Result<(), NetError> {
+    let (start, at) = (start as usize, start as usize + offset as usize);
+    if at + 2 > frame.len() {
+        return Err(NetError::BadChecksumOffset);
+    }
+    let checksum = !ones_complement_sum(&frame[start..]);
+    frame[at..at + 2].copy_from_slice(&checksum.to_be_bytes());
+    Ok(())
+}
+
+/// Queue a frame the NIC behind `interface` received, completing its
+/// checksum; called by drivers from [`NetDevice::poll`]
+pub fn deliver(interface: u32, frame: &[u8], checksum: Checksum) {
+    let Some(counters) = STATS.get(interface as usize) else {
+        return;
+    };
+    if !(ETHERNET_HEADER_SIZE..=MAX_FRAME_SIZE).contains(&frame.len()) {
+        counters.rx_dropped.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let mut queue = RX_QUEUES[interface as usize].lock();
+    if queue.count == RX_QUEUE_FRAMES {
+        counters.rx_dropped.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let slot = (queue.head + queue.count) % RX_QUEUE_FRAMES;
+    let stored = &mut queue.frames[slot];
+    stored.data[..frame.len()].copy_from_slice(frame);
+    stored.length = frame.len() as u16;
+    if let Checksum::Partial { start, offset } = checksum {
+        if complete_checksum(&mut stored.data[..frame.len()], start, offset).is_err() {
+            counters.rx_dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    }
+    queue.count += 1;
+    counters.rx_packets.fetch_add(1, Ordering::Relaxed);
+    counters.rx_bytes.fetch_add(frame.len() as u64, Ordering::Relaxed);
+}
+
+/// Take the oldest frame `interface` received into `buffer`, returning its
+/// length; a frame longer than `buffer` is cut short
+pub fn receive(interface: u32, buffer: &mut [u8]) -> Result<Option<usize>, NetError> {
+    device(interface)?;
+    let mut queue = RX_QUEUES[interface as usize].lock();
+    if queue.count == 0 {
+        return Ok(None);
+    }
+    let head = queue.head;
+    let frame = &queue.frames[head];
+    let length = (frame.length as usize).min(buffer.len());
+    buffer[..length].copy_from_slice(&frame.data[..length]);
+    queue.head = (head + 1) % RX_QUEUE_FRAMES;
+    queue.count -= 1;
+    Ok(Some(length))
+}
+
+/// Send a frame through `interface`
+pub fn transmit(interface: u32, frame: &[u8], checksum: Checksum) -> Result<(), NetError> {
+    let device = device(interface)?;
+    let counters = &STATS[interface as usize];
+    if !(ETHERNET_HEADER_SIZE..=MAX_FRAME_SIZE).contains(&frame.len()) {
+        return Err(NetError::BadFrameSize);
+    }
+    if !device.link_up() {
+        return Err(NetError::LinkDown);
+    }
+    let sent = match checksum {
+        Checksum::Partial { start, offset } if !device.offloads().tx_checksum => {
+            let mut copy = [0; MAX_FRAME_SIZE];
+            let copy = &mut copy[..frame.len()];
+            copy.copy_from_slice(frame);
+            complete_checksum(copy, start, offset).and_then(|()| device.transmit(copy, Checksum::None))
+        }
+        Checksum::Partial { .. } => device.transmit(frame, checksum),
+        _ => device.transmit(frame, Checksum::None),
+    };
+    match sent {
+        Ok(()) => {
+            counters.tx_packets.fetch_add(1, Ordering::Relaxed);
+            counters.tx_bytes.fetch_add(frame.len() as u64, Ordering::Relaxed);
+        }
+        Err(_) => {
+            counters.tx_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    sent
+}
+
+/// The network softirq: poll every interface
+fn poll_all() {
+    let devices = *INTERFACES.lock();
+    for (index, device) in devices.iter().enumerate() {
+        if let Some(device) = device {
+            device.poll(index as u32);
+        }
+    }
+}
+
+/// Initialize the network subsystem and probe for NICs
+pub fn init() {
+    softirq::open(Softirq::Net, poll_all);
+    virtio_net::init();
+
+    for info in interfaces() {
+        crate::klog!(
+            Info,
+            "Interface {}: {} {} link {}",
+            info.interface,
+            info.name,
+            MacAddress(info.mac),
+            if info.link_up { "up" } else { "down" }
+        );
+    }
+}
+
+/// Formats a MAC address the usual way
+pub struct MacAddress(pub [u8; 6]);
+
+impl core::fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
+}
@@ -0,0 +1,404 @@
+//! virtio-net NICs, as QEMU's `-device virtio-net-pci`
+//!
+//! The receive queue is kept full of buffers big enough for a frame behind
+//! its virtio-net header, each put back as soon as its frame is delivered;
+//! a frame sent is copied into a free buffer of the transmit queue's. Both
+//! queues interrupt through MSI-X, or the device's shared INTx line if it
+//! has no MSI-X, and the network softirq does the rest. Checksum offload is
+//! taken up both ways where the device offers it; merged receive buffers
+//! and segmentation offloads are not.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use spin::Once;
+
+use crate::kernel::interrupts::{self, IrqFlags, IrqReturn};
+use crate::kernel::msi::{self, Affinity};
+use crate::kernel::pci::{self, DeviceId, PciError};
+use crate::kernel::softirq::{self, Softirq};
+use crate::kernel::sync::SpinLockIrq;
+use crate::kernel::virtio::{self, DmaBuffer, Transport, VirtioError, Virtqueue};
+
+use super::{Checksum, MacAddress, NetDevice, NetError, Offloads, MAX_FRAME_SIZE};
+
+/// Transitional and modern device IDs
+static PCI_IDS: [DeviceId; 2] = [DeviceId::new(virtio::VENDOR, 0x1000), DeviceId::new(virtio::VENDOR, 0x1041)];
+
+/// Features: the device fills in checksums, it checks them, it has a MAC
+/// address, it reports the link status
+const FEATURE_CSUM: u64 = 1 << 0;
+const FEATURE_GUEST_CSUM: u64 = 1 << 1;
+const FEATURE_MAC: u64 = 1 << 5;
+const FEATURE_STATUS: u64 = 1 << 16;
+
+/// Device configuration: the MAC address and the link status
+const CONFIG_MAC: u64 = 0;
+const CONFIG_STATUS: u64 = 6;
+const LINK_UP: u16 = 1;
+
+/// Header flags: the checksum is to be filled in, or has been checked
+const HEADER_NEEDS_CSUM: u8 = 1;
+const HEADER_DATA_VALID: u8 = 2;
+
+/// The virtio-net header in front of every frame
+const HEADER_SIZE: usize = 12;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+/// Buffers, and queue entries, each queue has at most
+const BUFFERS: u16 = 64;
+/// Room for a header and a frame
+const BUFFER_SIZE: usize = 2048;
+
+/// NICs driven at once
+pub const MAX_NICS: usize = 2;
+const NAMES: [&str; MAX_NICS] = ["vnet0", "vnet1"];
+
+/// INTx line register in configuration space
+const REG_INTERRUPT_LINE: u8 = 0x3C;
+const NO_LINE: u8 = 0xFF;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Header {
+    flags: u8,
+    gso_type: u8,
+    header_length: u16,
+    gso_size: u16,
+    checksum_start: u16,
+    checksum_offset: u16,
+    buffers: u16,
+}
+
+/// How the NIC interrupts
+#[derive(Clone, Copy, Debug)]
+enum Interrupt {
+    /// An MSI-X entry per queue
+    Msix,
+    /// The shared INTx line
+    Line(u32),
+}
+
+/// A queue and the buffers it hands the device
+struct Queue {
+    queue: Virtqueue,
+    buffers: DmaBuffer,
+    /// Buffer behind each chain head
+    buffer_of: [u8; BUFFERS as usize],
+    /// Transmit buffers the device has, a bit each
+    busy: u64,
+}
+
+impl Queue {
+    fn new(transport: &Transport, index: u16, entry: u16) -> Result<Queue, VirtioError> {
+        let queue = transport.setup_queue(index, BUFFERS, entry)?;
+        match DmaBuffer::new(queue.size() as usize * BUFFER_SIZE) {
+            Ok(buffers) => Ok(Queue { queue, buffers, buffer_of: [0; BUFFERS as usize], busy: 0 }),
+            Err(e) => {
+                queue.free();
+                Err(e)
+            }
+        }
+    }
+
+    fn buffer(&self, index: usize) -> (*mut u8, u64) {
+        let offset = (index * BUFFER_SIZE) as u64;
+        ((self.buffers.virt.as_u64() + offset) as *mut u8, self.buffers.bus + offset)
+    }
+
+    /// Hand receive buffer `index` to the device
+    fn post(&mut self, index: usize) -> Result<(), VirtioError> {
+        let (_, bus) = self.buffer(index);
+        let head = self.queue.add(&[(bus, BUFFER_SIZE as u32, true)])?;
+        self.buffer_of[head as usize] = index as u8;
+        Ok(())
+    }
+
+    fn free(self) {
+        self.queue.free();
+        self.buffers.free();
+    }
+}
+
+pub struct VirtioNet {
+    name: &'static str,
+    transport: Transport,
+    features: u64,
+    mac: [u8; 6],
+    interrupt: Interrupt,
+    rx: SpinLockIrq<Queue>,
+    tx: SpinLockIrq<Queue>,
+    interface: AtomicU32,
+    removed: AtomicBool,
+}
+
+static NICS: [Once<VirtioNet>; MAX_NICS] = [const { Once::new() }; MAX_NICS];
+
+/// MSI-X: raise the softirq, which polls both queues
+fn queue_interrupt(_vector: u8) {
+    softirq::raise(Softirq::Net);
+}
+
+/// INTx: the line is shared, so only claim it if the NIC's status says so
+fn line_interrupt(nic: usize) -> IrqReturn {
+    match NICS[nic].get() {
+        Some(nic) if nic.transport.interrupt_status() != 0 => {
+            softirq::raise(Softirq::Net);
+            IrqReturn::Handled
+        }
+        _ => IrqReturn::None,
+    }
+}
+
+impl VirtioNet {
+    fn probe(device: &pci::Device, index: usize) -> Result<VirtioNet, VirtioError> {
+        let transport = Transport::probe(device)?;
+        transport.reset();
+        let features = transport.negotiate(FEATURE_CSUM | FEATURE_GUEST_CSUM | FEATURE_MAC | FEATURE_STATUS)?;
+
+        let mac = match features & FEATURE_MAC {
+            0 => {
+                // Locally administered, not multicast
+                let mut mac = [0; 6];
+                crate::kernel::random::fill(&mut mac);
+                mac[0] = (mac[0] | 0x02) & !0x01;
+                mac
+            }
+            _ => core::array::from_fn(|i| transport.config8(CONFIG_MAC + i as u64)),
+        };
+
+        // MSI-X must be on before queues are given table entries
+        let interrupt = match msi::enable_msix(device.address, 2, queue_interrupt, Affinity::Spread) {
+            Ok(_) => Interrupt::Msix,
+            Err(_) => match device.address.read(REG_INTERRUPT_LINE) as u8 {
+                NO_LINE => {
+                    transport.reset();
+                    return Err(VirtioError::NoInterrupt);
+                }
+                line => Interrupt::Line(line as u32),
+            },
+        };
+        let entries = match interrupt {
+            Interrupt::Msix => [RX_QUEUE, TX_QUEUE],
+            Interrupt::Line(_) => [virtio::NO_VECTOR; 2],
+        };
+        transport.set_config_vector(virtio::NO_VECTOR);
+
+        let (mut rx, tx) = match (
+            Queue::new(&transport, RX_QUEUE, entries[0]),
+            Queue::new(&transport, TX_QUEUE, entries[1]),
+        ) {
+            (Ok(rx), Ok(tx)) => (rx, tx),
+            (rx, tx) => {
+                transport.reset();
+                if let Interrupt::Msix = interrupt {
+                    msi::disable(device.address);
+                }
+                let mut error = VirtioError::NoSuchQueue;
+                for queue in [rx, tx] {
+                    match queue {
+                        Ok(queue) => queue.free(),
+                        Err(e) => error = e,
+                    }
+                }
+                return Err(error);
+            }
+        };
+        // There are as many buffers as entries, so they all fit
+        for buffer in 0..rx.queue.size() as usize {
+            let _ = rx.post(buffer);
+        }
+
+        Ok(VirtioNet {
+            name: NAMES[index],
+            transport,
+            features,
+            mac,
+            interrupt,
+            rx: SpinLockIrq::new("virtio-net rx", rx),
+            tx: SpinLockIrq::new("virtio-net tx", tx),
+            interface: AtomicU32::new(u32::MAX),
+            removed: AtomicBool::new(false),
+        })
+    }
+
+    /// Take back the transmit buffers the device is done with
+    fn reap_sent(tx: &mut Queue) {
+        while let Some((head, _)) = tx.queue.pop_used() {
+            tx.busy &= !(1 << tx.buffer_of[head as usize]);
+        }
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        // Without the status feature the link is taken to be always up
+        self.features & FEATURE_STATUS == 0 || self.transport.config16(CONFIG_STATUS) & LINK_UP != 0
+    }
+
+    fn offloads(&self) -> Offloads {
+        Offloads {
+            tx_checksum: self.features & FEATURE_CSUM != 0,
+            rx_checksum: self.features & FEATURE_GUEST_CSUM != 0,
+        }
+    }
+
+    fn transmit(&self, frame: &[u8], checksum: Checksum) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(NetError::BadFrameSize);
+        }
+        let header = match checksum {
+            Checksum::Partial { start, offset } => Header {
+                flags: HEADER_NEEDS_CSUM,
+                checksum_start: start,
+                checksum_offset: offset,
+                ..Header::default()
+            },
+            _ => Header::default(),
+        };
+
+        let mut tx = self.tx.lock();
+        Self::reap_sent(&mut tx);
+        let buffer = (0..tx.queue.size() as usize)
+            .find(|buffer| tx.busy & 1 << buffer == 0)
+            .ok_or(NetError::QueueFull)?;
+        let (virt, bus) = tx.buffer(buffer);
+        unsafe {
+            core::ptr::write_unaligned(virt as *mut Header, header);
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), virt.add(HEADER_SIZE), frame.len());
+        }
+        let length = (HEADER_SIZE + frame.len()) as u32;
+        let head = tx.queue.add(&[(bus, length, false)]).map_err(|_| NetError::QueueFull)?;
+        tx.buffer_of[head as usize] = buffer as u8;
+        tx.busy |= 1 << buffer;
+        tx.queue.notify();
+        Ok(())
+    }
+
+    fn poll(&self, interface: u32) {
+        Self::reap_sent(&mut self.tx.lock());
+
+        let mut rx = self.rx.lock();
+        let mut posted = false;
+        while let Some((head, written)) = rx.queue.pop_used() {
+            let buffer = rx.buffer_of[head as usize] as usize;
+            let written = (written as usize).min(BUFFER_SIZE);
+            if written > HEADER_SIZE {
+                let (virt, _) = rx.buffer(buffer);
+                let (header, frame) = unsafe {
+                    (
+                        core::ptr::read_unaligned(virt as *const Header),
+                        core::slice::from_raw_parts(virt.add(HEADER_SIZE), written - HEADER_SIZE),
+                    )
+                };
+                let checksum = if header.flags & HEADER_NEEDS_CSUM != 0 {
+                    Checksum::Partial { start: header.checksum_start, offset: header.checksum_offset }
+                } else if header.flags & HEADER_DATA_VALID != 0 {
+                    Checksum::Valid
+                } else {
+                    Checksum::None
+                };
+                super::deliver(interface, frame, checksum);
+            }
+            // Chains come back one at a time, so there is room for the buffer
+            posted |= rx.post(buffer).is_ok();
+        }
+        if posted {
+            rx.queue.notify();
+        }
+    }
+}
+
+struct VirtioNetDriver;
+
+static DRIVER: VirtioNetDriver = VirtioNetDriver;
+
+impl pci::Driver for VirtioNetDriver {
+    fn name(&self) -> &'static str {
+        "virtio-net"
+    }
+
+    fn ids(&self) -> &'static [DeviceId] {
+        &PCI_IDS
+    }
+
+    fn probe(&self, device: &pci::Device) -> Result<(), PciError> {
+        // Slots are not reused, as the softirq may still be polling a NIC
+        // removed
+        let index = NICS.iter().position(|nic| !nic.is_completed()).ok_or(PciError::ProbeFailed)?;
+        let nic = match VirtioNet::probe(device, index) {
+            Ok(nic) => NICS[index].call_once(|| nic),
+            Err(VirtioError::NotModern) => return Err(PciError::Unsupported),
+            Err(e) => {
+                crate::klog!(Warn, "virtio-net {} not set up: {:?}", device.address, e);
+                return Err(PciError::ProbeFailed);
+            }
+        };
+        if let Interrupt::Line(line) = nic.interrupt {
+            if let Err(e) = interrupts::request_irq(line, IrqFlags::SHARED, line_interrupt, index) {
+                crate::klog!(Warn, "{}: IRQ {} not requested: {:?}", nic.name, line, e);
+                nic.transport.reset();
+                nic.removed.store(true, Ordering::Release);
+                return Err(PciError::ProbeFailed);
+            }
+        }
+        nic.transport.driver_ok();
+        nic.rx.lock().queue.notify();
+
+        match super::register(nic) {
+            Ok(interface) => nic.interface.store(interface, Ordering::Release),
+            Err(e) => crate::klog!(Warn, "{}: no interface: {:?}", nic.name, e),
+        }
+        // Frames may have come in before there was an interface to poll
+        softirq::raise(Softirq::Net);
+        crate::klog!(
+            Info,
+            "{}: virtio-net at {}, {}, {:?}",
+            nic.name,
+            device.address,
+            MacAddress(nic.mac),
+            nic.interrupt
+        );
+        Ok(())
+    }
+
+    fn remove(&self, device: &pci::Device) {
+        let Some((index, nic)) = NICS
+            .iter()
+            .enumerate()
+            .filter_map(|(index, nic)| Some((index, nic.get()?)))
+            .find(|(_, nic)| nic.transport.address == device.address && !nic.removed.load(Ordering::Acquire))
+        else {
+            return;
+        };
+        nic.removed.store(true, Ordering::Release);
+        let interface = nic.interface.swap(u32::MAX, Ordering::AcqRel);
+        if interface != u32::MAX {
+            let _ = super::unregister(interface);
+        }
+        match nic.interrupt {
+            Interrupt::Msix => msi::disable(device.address),
+            Interrupt::Line(line) => {
+                let _ = interrupts::free_irq(line, line_interrupt, index);
+            }
+        }
+        // The queues' memory stays with the slot
+        nic.transport.reset();
+    }
+}
+
+/// Register the driver, which probes the NICs already found
+pub fn init() {
+    if let Err(e) = pci::register_driver(&DRIVER) {
+        crate::klog!(Warn, "virtio-net driver not registered: {:?}", e);
+    }
+}
@@ -1,8 +1,12 @@
 //! Hybrid stride-based scheduler with per-CPU run queues
 
+pub mod wait;
+
 use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use heapless::Vec;
 
+use crate::kernel::softirq::Softirq;
+
 /// Maximum number of tasks per CPU
 pub const MAX_TASKS_PER_CPU: usize = 256;
 
@@ -71,26 +75,34 @@ impl RunQueue {
 
     /// Get next task to run (stride scheduling)
     pub fn next_task(&mut self) -> Option<&mut TaskDesc> {
-        if self.tasks.is_empty() {
-            return None;
+        // The running task goes back into contention
+        for task in self.tasks.iter_mut() {
+            if task.state == TaskState::Running {
+                task.state = TaskState::Ready;
+            }
         }
 
         // Find task with minimum pass value
-        let mut min_idx = 0;
+        let mut min_idx = None;
         let mut min_pass = u64::MAX;
 
         for (i, task) in self.tasks.iter().enumerate() {
             if task.state == TaskState::Ready && task.pass < min_pass {
                 min_pass = task.pass;
-                min_idx = i;
+                min_idx = Some(i);
             }
         }
 
-        let task = &mut self.tasks[min_idx];
+        let task = &mut self.tasks[min_idx?];
         task.pass += task.stride as u64;
         task.state = TaskState::Running;
         Some(task)
     }
+
+    /// Find a task by ID
+    pub fn task_mut(&mut self, id: u32) -> Option<&mut TaskDesc> {
+        self.tasks.iter_mut().find(|task| task.id == id)
+    }
 }
 
 /// Global scheduler state
@@ -101,20 +113,38 @@ static mut RUN_QUEUES: [RunQueue; crate::kernel::percpu::MAX_CPUS] = {
 
 static TICK_COUNTER: AtomicU64 = AtomicU64::new(0);
 
+/// Timer ticks per second (PIT at its default divisor, ~18.2 Hz)
+pub const TICKS_PER_SECOND: u64 = 18;
+
 /// Initialize scheduler
 pub fn init() {
-    // Create idle task for CPU 0
+    init_cpu(0);
+    crate::kernel::softirq::open(Softirq::Timer, tick_work);
+}
+
+/// Give `cpu` its idle task, as it comes up
+pub fn init_cpu(cpu: u32) {
     let idle_task = TaskDesc::new(0, 100);
     unsafe {
-        let _ = RUN_QUEUES[0].enqueue(idle_task);
+        let _ = RUN_QUEUES[cpu as usize].enqueue(idle_task);
     }
 }
 
 /// Start the scheduler
 pub fn start() -> ! {
     loop {
+        crate::kernel::softirq::run();
         schedule();
-        x86_64::instructions::hlt();
+        crate::kernel::watchdog::pet();
+        crate::kernel::hrtimer::run();
+        crate::kernel::trace::poll();
+        crate::storage::poll();
+        crate::gpu::poll();
+        crate::ai::poll();
+        crate::userspace::poll();
+        crate::shell::poll();
+        crate::gpu::tile::work();
+        crate::kernel::rcu::idle();
     }
 }
 
@@ -124,20 +154,67 @@ pub fn schedule() {
     
     unsafe {
         if let Some(task) = RUN_QUEUES[cpu_id].next_task() {
+            crate::this_cpu_write!(current_task, task.id);
+
             // Context switch to task
             switch_to_task(task);
         }
     }
 }
 
+/// ID of the task running on this CPU
+pub fn current_task_id() -> u32 {
+    crate::this_cpu_read!(current_task)
+}
+
+/// Apply `f` to a task on any CPU's run queue
+fn with_task<R>(task_id: u32, f: impl FnOnce(&mut TaskDesc) -> R) -> Result<R, SchedulerError> {
+    unsafe {
+        for queue in RUN_QUEUES.iter_mut() {
+            if let Some(task) = queue.task_mut(task_id) {
+                return Ok(f(task));
+            }
+        }
+    }
+    Err(SchedulerError::InvalidTaskId)
+}
+
+/// Block the current task until it is woken
+pub fn block_current() {
+    crate::trace!(TaskBlock, current_task_id());
+    let _ = with_task(current_task_id(), |task| task.state = TaskState::Blocked);
+    schedule();
+}
+
+/// Make a blocked task runnable again
+pub fn wake(task_id: u32) -> Result<(), SchedulerError> {
+    crate::trace!(TaskWake, task_id);
+    with_task(task_id, |task| {
+        if task.state == TaskState::Blocked {
+            task.state = TaskState::Ready;
+        }
+    })
+}
+
 /// Handle timer tick
 pub fn tick() {
     TICK_COUNTER.fetch_add(1, Ordering::Relaxed);
-    
+    crate::kernel::softirq::raise(Softirq::Timer);
+}
+
+/// The rest of the tick, once the interrupt is acknowledged
+fn tick_work() {
+    crate::kernel::rcu::tick();
+
     // Trigger rescheduling
     schedule();
 }
 
+/// Timer ticks since boot
+pub fn ticks() -> u64 {
+    TICK_COUNTER.load(Ordering::Relaxed)
+}
+
 /// Context switch to a task (assembly stub)
 fn switch_to_task(task: &TaskDesc) {
     // TODO: Implement register-only context switch in assembly
@@ -151,3 +228,67 @@ pub enum SchedulerError {
     NoTasksAvailable,
     InvalidTaskId,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tasks picked in `turns` turns, by ID
+    fn picks(queue: &mut RunQueue, turns: usize) -> [usize; 4] {
+        let mut picks = [0; 4];
+        for _ in 0..turns {
+            let id = queue.next_task().unwrap().id;
+            picks[id as usize] += 1;
+        }
+        picks
+    }
+
+    #[test_case]
+    fn empty_queue() {
+        let mut queue = RunQueue::new();
+        assert!(queue.next_task().is_none());
+    }
+
+    #[test_case]
+    fn turns_follow_strides() {
+        let mut queue = RunQueue::new();
+        queue.enqueue(TaskDesc::new(1, 100)).unwrap();
+        queue.enqueue(TaskDesc::new(2, 200)).unwrap();
+        queue.enqueue(TaskDesc::new(3, 400)).unwrap();
+        // A smaller stride runs more often: 4:2:1
+        assert_eq!(picks(&mut queue, 70), [0, 40, 20, 10]);
+    }
+
+    #[test_case]
+    fn one_task_runs_at_a_time() {
+        let mut queue = RunQueue::new();
+        queue.enqueue(TaskDesc::new(1, 100)).unwrap();
+        queue.enqueue(TaskDesc::new(2, 100)).unwrap();
+        let first = queue.next_task().unwrap().id;
+        let second = queue.next_task().unwrap().id;
+        assert_ne!(first, second);
+        assert_eq!(queue.task_mut(first).unwrap().state, TaskState::Ready);
+        assert_eq!(queue.task_mut(second).unwrap().state, TaskState::Running);
+    }
+
+    #[test_case]
+    fn blocked_tasks_skipped() {
+        let mut queue = RunQueue::new();
+        queue.enqueue(TaskDesc::new(1, 100)).unwrap();
+        queue.enqueue(TaskDesc::new(2, 100)).unwrap();
+        queue.task_mut(1).unwrap().state = TaskState::Blocked;
+        assert_eq!(picks(&mut queue, 5), [0, 0, 5, 0]);
+        queue.task_mut(1).unwrap().state = TaskState::Ready;
+        // The woken task has fallen behind and catches up first
+        assert_eq!(queue.next_task().unwrap().id, 1);
+    }
+
+    #[test_case]
+    fn full_queue() {
+        let mut queue = RunQueue::new();
+        for id in 0..MAX_TASKS_PER_CPU as u32 {
+            queue.enqueue(TaskDesc::new(id, 100)).unwrap();
+        }
+        assert!(matches!(queue.enqueue(TaskDesc::new(0, 100)), Err(SchedulerError::QueueFull)));
+    }
+}
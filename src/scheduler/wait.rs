@@ -0,0 +1,63 @@
+//! Wait queues
+//!
+//! A task waits for a condition by blocking on a queue; whoever makes the
+//! condition true wakes the queue. The condition is re-checked with
+//! interrupts off after registering, so a wake from an interrupt handler
+//! cannot be lost between the check and the sleep.
+
+use arrayvec::ArrayVec;
+use x86_64::instructions::interrupts;
+
+use crate::kernel::sync::SpinLockIrq;
+
+/// Tasks that can wait on one queue at a time
+pub const MAX_WAITERS: usize = 32;
+
+pub struct WaitQueue {
+    waiters: SpinLockIrq<ArrayVec<u32, MAX_WAITERS>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: SpinLockIrq::new("wait queue", ArrayVec::new_const()),
+        }
+    }
+
+    /// Block the current task until `condition` holds
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        loop {
+            interrupts::disable();
+            if condition() {
+                interrupts::enable();
+                return;
+            }
+
+            let task = super::current_task_id();
+            {
+                let mut waiters = self.waiters.lock();
+                if !waiters.contains(&task) {
+                    // A full queue degrades to polling on every tick
+                    let _ = waiters.try_push(task);
+                }
+            }
+
+            super::block_current();
+            // sti; hlt is atomic, so a wake cannot slip in before we sleep
+            interrupts::enable_and_hlt();
+        }
+    }
+
+    /// Wake every waiting task; each re-checks its condition
+    pub fn wake_all(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for task in waiters {
+            let _ = super::wake(task);
+        }
+    }
+
+    /// Whether any task is waiting
+    pub fn has_waiters(&self) -> bool {
+        !self.waiters.lock().is_empty()
+    }
+}
@@ -0,0 +1,640 @@
+//! Shell commands
+//!
+//! Objects are named by ID or by a tag; a word that parses as a number is
+//! taken as an ID.
+
+use crate::capability::{self, Permission};
+use crate::kernel::klog::{self, Level};
+use crate::tagfs::{self, Tag};
+use crate::userspace::args::Strings;
+use crate::userspace::{console, init, process};
+use crate::{serial_print, serial_println};
+
+/// Longest object printed by `cat`
+const MAX_CAT_SIZE: usize = 4096;
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    run: fn(&[&str]),
+}
+
+static COMMANDS: &[Command] = &[
+    Command { name: "help", usage: "help", run: help },
+    Command { name: "tag", usage: "tag <tag>  - objects with a tag", run: tag },
+    Command { name: "ls", usage: "ls  - every object with its size and tags", run: ls },
+    Command { name: "cat", usage: "cat <object>", run: cat },
+    Command { name: "write", usage: "write <tag> <text...>  - new object", run: write },
+    Command { name: "addtag", usage: "addtag <object> <tag>", run: addtag },
+    Command { name: "rm", usage: "rm <object>", run: rm },
+    Command { name: "ps", usage: "ps  - process tree and services", run: ps },
+    Command {
+        name: "run",
+        usage: "run <object> [args...] [&]  - start an executable, in the background with &",
+        run: start,
+    },
+    Command { name: "kill", usage: "kill <pid>", run: kill },
+    Command { name: "caps", usage: "caps <pid>  - permissions of a process", run: caps },
+    Command { name: "mem", usage: "mem  - frame and heap usage", run: mem },
+    Command {
+        name: "import",
+        usage: "import <device>  - copy the files of an ext2 disk into TagFS",
+        run: import,
+    },
+    Command { name: "dmesg", usage: "dmesg  - kernel log", run: dmesg },
+    Command {
+        name: "loglevel",
+        usage: "loglevel <module|*> <error|warn|info|debug>  - what the kernel log keeps",
+        run: loglevel,
+    },
+    Command { name: "lspci", usage: "lspci  - PCI functions and their drivers", run: lspci },
+    Command { name: "irqs", usage: "irqs  - interrupt lines, their handlers and counts, and NMIs", run: irqs },
+    Command { name: "queues", usage: "queues  - block request counts per CPU and hardware queue", run: queues },
+    Command { name: "iostat", usage: "iostat [reset]  - per-device I/O counts and latency", run: iostat },
+    Command { name: "health", usage: "health  - SMART health of each storage device", run: health },
+    Command {
+        name: "cache",
+        usage: "cache [reset | prefetch on|off]  - block cache hits and prefetching",
+        run: cache,
+    },
+    Command {
+        name: "net",
+        usage: "net [recv <interface>]  - network interfaces, or the frames one has received",
+        run: net,
+    },
+    Command {
+        name: "trace",
+        usage: "trace [on|off <subsystem,...|all>]  - event tracing, or print what was traced",
+        run: trace,
+    },
+    Command {
+        name: "probe",
+        usage: "probe [add|del <function[+offset]|address>]  - probes on kernel code, traced as probe",
+        run: probe,
+    },
+    Command { name: "poweroff", usage: "poweroff", run: poweroff },
+    Command { name: "reboot", usage: "reboot", run: reboot },
+];
+
+/// Run one command line
+pub fn run(line: &str) {
+    let mut words = [""; 16];
+    let mut count = 0;
+    for word in line.split_whitespace().take(words.len()) {
+        words[count] = word;
+        count += 1;
+    }
+    let Some((name, args)) = words[..count].split_first() else {
+        return;
+    };
+    match COMMANDS.iter().find(|c| c.name == *name) {
+        Some(command) => (command.run)(args),
+        None => serial_println!("{}: unknown command", name),
+    }
+}
+
+/// The object a word names, printing why if there is none
+fn object(word: &str) -> Option<u64> {
+    let id = word.parse::<u64>().ok().or_else(|| tagfs::tagfs_query(&Tag::new(word)));
+    match id.filter(|id| tagfs::tagfs_meta(*id).is_some()) {
+        Some(id) => Some(id),
+        None => {
+            serial_println!("{}: no such object", word);
+            None
+        }
+    }
+}
+
+fn print_tags(id: u64) {
+    tagfs::tagfs_tags(id, |tag| serial_print!(" {}", tag.as_str()));
+    serial_println!();
+}
+
+fn print_permissions(bits: u64) {
+    for permission in Permission::ALL.iter().filter(|p| bits & p.bit() != 0) {
+        serial_print!(" {}", permission.name());
+    }
+    serial_println!();
+}
+
+fn help(_args: &[&str]) {
+    for command in COMMANDS {
+        serial_println!("  {}", command.usage);
+    }
+}
+
+fn tag(args: &[&str]) {
+    let [tag] = args else {
+        return serial_println!("usage: tag <tag>");
+    };
+    let mut found = false;
+    tagfs::tagfs_query_all(&Tag::new(tag), |id| {
+        if let Some(meta) = tagfs::tagfs_meta(id) {
+            serial_print!("{:>6} {:>8}", { meta.id }, { meta.size });
+            print_tags(meta.id);
+            found = true;
+        }
+    });
+    if !found {
+        serial_println!("{}: no object", tag);
+    }
+}
+
+fn ls(_args: &[&str]) {
+    serial_println!("{:>6} {:>8} tags", "id", "size");
+    tagfs::tagfs_list(|meta| {
+        serial_print!("{:>6} {:>8}", { meta.id }, { meta.size });
+        print_tags(meta.id);
+    });
+}
+
+fn cat(args: &[&str]) {
+    let [word] = args else {
+        return serial_println!("usage: cat <object>");
+    };
+    let Some(id) = object(word) else {
+        return;
+    };
+    let mut buffer = [0u8; MAX_CAT_SIZE];
+    match tagfs::tagfs_read(id, 0, &mut buffer) {
+        Ok(len) => {
+            for chunk in buffer[..len].utf8_chunks() {
+                serial_print!("{}", chunk.valid());
+                if !chunk.invalid().is_empty() {
+                    serial_print!("\u{fffd}");
+                }
+            }
+            serial_println!();
+        }
+        Err(e) => serial_println!("{}: {:?}", word, e),
+    }
+}
+
+fn write(args: &[&str]) {
+    let [tag, words @ ..] = args else {
+        return serial_println!("usage: write <tag> <text...>");
+    };
+    let mut text = arrayvec::ArrayString::<{ super::MAX_LINE }>::new();
+    for (i, word) in words.iter().enumerate() {
+        if i > 0 {
+            let _ = text.try_push(' ');
+        }
+        let _ = text.try_push_str(word);
+    }
+    match tagfs::tagfs_create(&[Tag::new(tag)], text.as_bytes()) {
+        Ok(id) => serial_println!("created object {}", id),
+        Err(e) => serial_println!("write failed: {:?}", e),
+    }
+}
+
+fn addtag(args: &[&str]) {
+    let [word, tag] = args else {
+        return serial_println!("usage: addtag <object> <tag>");
+    };
+    if let Some(id) = object(word) {
+        if let Err(e) = tagfs::tagfs_add_tag(id, Tag::new(tag)) {
+            serial_println!("addtag failed: {:?}", e);
+        }
+    }
+}
+
+fn rm(args: &[&str]) {
+    let [word] = args else {
+        return serial_println!("usage: rm <object>");
+    };
+    if let Some(id) = object(word) {
+        if let Err(e) = tagfs::tagfs_delete(id) {
+            serial_println!("rm failed: {:?}", e);
+        }
+    }
+}
+
+fn ps(_args: &[&str]) {
+    serial_println!("{:>6} {:>7} pid state", "parent", "threads");
+    for (depth, info) in process::tree() {
+        serial_println!(
+            "{:>6} {:>7} {:indent$}{} {:?}",
+            info.parent,
+            info.threads,
+            "",
+            info.pid,
+            info.state,
+            indent = depth * 2
+        );
+    }
+    let services = init::services();
+    if services.is_empty() {
+        return;
+    }
+    serial_println!("services:");
+    for service in services {
+        serial_println!(
+            "  {:<16} {:?} ({:?}, {} failures)",
+            service.name.as_str(),
+            service.state,
+            service.restart,
+            service.failures
+        );
+    }
+}
+
+fn start(args: &[&str]) {
+    let (args, background) = match args {
+        [args @ .., "&"] => (args, true),
+        _ => (args, false),
+    };
+    let [word, ..] = args else {
+        return serial_println!("usage: run <object> [args...] [&]");
+    };
+    let mut argv = Strings::new();
+    if args.iter().try_for_each(|arg| argv.push(arg)).is_err() {
+        return serial_println!("run: arguments too long");
+    }
+    if let Some(id) = object(word) {
+        match crate::userspace::spawn(id, &argv) {
+            Ok(pid) if background => serial_println!("started process {}", pid),
+            Ok(pid) => console::set_foreground(Some(pid)),
+            Err(e) => serial_println!("run failed: {:?}", e),
+        }
+    }
+}
+
+fn kill(args: &[&str]) {
+    let Some(pid) = args.first().and_then(|w| w.parse::<u32>().ok()) else {
+        return serial_println!("usage: kill <pid>");
+    };
+    if process::kill(pid, process::FAULT_STATUS).is_err() {
+        serial_println!("{}: no such process", pid);
+    }
+}
+
+fn caps(args: &[&str]) {
+    let Some(pid) = args.first().and_then(|w| w.parse::<u32>().ok()) else {
+        return serial_println!("usage: caps <pid>");
+    };
+    match capability::permissions(pid) {
+        Some(bits) => {
+            serial_print!("{}:", pid);
+            print_permissions(bits);
+        }
+        None => serial_println!("{}: no tokens", pid),
+    }
+}
+
+fn mem(_args: &[&str]) {
+    if let Some(stats) = crate::kernel::memory::frame_stats() {
+        serial_println!(
+            "frames: {} of {} in use ({} KiB of {} KiB)",
+            stats.allocated,
+            stats.usable,
+            stats.allocated * 4,
+            stats.usable * 4
+        );
+        if stats.retired > 0 {
+            serial_println!("frames retired after memory errors: {}", stats.retired);
+        }
+    }
+    let (used, size) = crate::kernel::allocator::usage();
+    serial_println!("heap: {} of {} bytes in use", used, size);
+}
+
+fn import(args: &[&str]) {
+    let Some(device) = args.first().and_then(|word| word.parse::<u32>().ok()) else {
+        return serial_println!("usage: import <device>");
+    };
+    match crate::compat::ext2::import(device) {
+        Ok(imported) => serial_println!(
+            "imported {} files, {} bytes; {} entries left out",
+            imported.files,
+            imported.bytes,
+            imported.skipped
+        ),
+        Err(e) => serial_println!("import failed: {:?}", e),
+    }
+}
+
+fn dmesg(_args: &[&str]) {
+    let mut sequence = 0;
+    while let Some(record) = klog::record(sequence) {
+        serial_println!("{}", record);
+        sequence = record.sequence + 1;
+    }
+}
+
+fn loglevel(args: &[&str]) {
+    let [module, level] = args else {
+        return serial_println!("usage: loglevel <module|*> <error|warn|info|debug>");
+    };
+    let Some(level) = Level::parse(level) else {
+        return serial_println!("{}: not a level", level);
+    };
+    match *module {
+        "*" => klog::set_default_level(level),
+        module => {
+            if let Err(e) = klog::set_filter(module, level) {
+                serial_println!("filter not set: {:?}", e);
+            }
+        }
+    }
+}
+
+fn lspci(_args: &[&str]) {
+    for (device, driver) in crate::kernel::pci::devices() {
+        serial_println!(
+            "{} {:04x}:{:04x} class {:02x}{:02x}{:02x} rev {:02x}  {}",
+            device.address,
+            device.vendor,
+            device.device,
+            device.class,
+            device.subclass,
+            device.prog_if,
+            device.revision,
+            driver.unwrap_or("-")
+        );
+    }
+}
+
+fn irqs(_args: &[&str]) {
+    use crate::kernel::{interrupts, nmi};
+
+    serial_println!(
+        "{:>4} {:>6} {:>8} {:>10} {:>10} {:>10}",
+        "line",
+        "vector",
+        "handlers",
+        "count",
+        "unhandled",
+        "threaded"
+    );
+    for stats in interrupts::irq_stats() {
+        match stats.vector {
+            Some(vector) => serial_print!("{:>4} {:>6}", stats.line, vector),
+            None => serial_print!("{:>4} {:>6}", stats.line, "pic"),
+        }
+        serial_println!(
+            " {:>8} {:>10} {:>10} {:>10}",
+            stats.handlers,
+            stats.interrupts,
+            stats.unhandled,
+            stats.threaded
+        );
+    }
+    serial_print!("nmi:");
+    for source in nmi::Source::ALL {
+        serial_print!(" {} {}", source.name(), nmi::count(source));
+    }
+    serial_println!();
+}
+
+fn queues(_args: &[&str]) {
+    use crate::kernel::percpu;
+    use crate::storage::{irq, mq};
+
+    let print = |stats: mq::QueueStatsSnapshot| {
+        serial_println!(
+            " {:>10} {:>10} {:>10} {:>8}",
+            stats.submitted,
+            stats.dispatched,
+            stats.completed,
+            stats.errors
+        )
+    };
+    serial_println!(
+        "{:>5} {:>3} {:>6} {:>10} {:>10} {:>10} {:>8}",
+        "queue",
+        "id",
+        "device",
+        "submitted",
+        "dispatched",
+        "completed",
+        "errors"
+    );
+    for cpu in 0..percpu::online() {
+        if let Some(stats) = mq::sw_queue_stats(cpu) {
+            serial_print!("{:>5} {:>3} {:>6}", "cpu", cpu, "-");
+            print(stats);
+        }
+    }
+    for queue in 0..irq::MAX_HW_QUEUES as u16 {
+        if let (Some(device), Some(stats)) = (irq::queue_device(queue), mq::hw_queue_stats(queue)) {
+            serial_print!("{:>5} {:>3} {:>6}", "hw", queue, device);
+            print(stats);
+        }
+    }
+}
+
+fn iostat(args: &[&str]) {
+    use crate::storage::{self, stats};
+
+    if args.first() == Some(&"reset") {
+        for (device, _) in storage::devices() {
+            stats::reset(device);
+        }
+        return;
+    }
+
+    serial_println!(
+        "{:>6} {:<12} {:>10} {:>10} {:>10} {:>10} {:>8} {:>9} {:>10} {:>10}",
+        "device",
+        "name",
+        "reads",
+        "writes",
+        "read KiB",
+        "write KiB",
+        "errors",
+        "in flight",
+        "p50 us",
+        "p99 us"
+    );
+    for (device, info) in storage::devices() {
+        let Some(stats) = stats::device_stats(device) else { continue };
+        serial_println!(
+            "{:>6} {:<12} {:>10} {:>10} {:>10} {:>10} {:>8} {:>4}/{:<4} {:>10} {:>10}",
+            device,
+            info.name,
+            stats.reads,
+            stats.writes,
+            stats.bytes_read / 1024,
+            stats.bytes_written / 1024,
+            stats.errors,
+            stats.in_flight,
+            stats.max_in_flight,
+            stats.p50() / 1000,
+            stats.p99() / 1000
+        );
+    }
+}
+
+fn health(_args: &[&str]) {
+    use crate::storage::{self, smart};
+
+    serial_println!(
+        "{:>6} {:<12} {:>6} {:>5} {:>6} {:>12} {:>10} {:>8}",
+        "device",
+        "name",
+        "temp C",
+        "used%",
+        "spare%",
+        "media errors",
+        "power hrs",
+        "warning"
+    );
+    for (device, info) in storage::devices() {
+        match smart::health(device) {
+            Some(health) => serial_println!(
+                "{:>6} {:<12} {:>6} {:>5} {:>6} {:>12} {:>10} {:>#8x}",
+                device,
+                info.name,
+                health.temperature_c,
+                health.percent_used,
+                health.available_spare,
+                health.media_errors,
+                health.power_on_hours,
+                health.critical_warning
+            ),
+            None => serial_println!("{:>6} {:<12} no health data", device, info.name),
+        }
+    }
+}
+
+fn cache(args: &[&str]) {
+    use crate::storage::cache;
+
+    match args {
+        ["reset"] => return cache::reset_stats(),
+        ["prefetch", "on"] => return cache::set_prefetch(true),
+        ["prefetch", "off"] => return cache::set_prefetch(false),
+        [] => {}
+        _ => return serial_println!("usage: cache [reset | prefetch on|off]"),
+    }
+    let stats = cache::stats();
+    serial_println!(
+        "hits: {} misses: {} ({}% hit rate)",
+        stats.hits,
+        stats.misses,
+        stats.hit_rate()
+    );
+    serial_println!(
+        "prefetch {}: {} loaded, {} read, {} wasted, {} hints dropped",
+        if cache::prefetch_enabled() { "on" } else { "off" },
+        stats.prefetched,
+        stats.prefetch_hits,
+        stats.prefetch_wasted,
+        stats.hints_dropped
+    );
+}
+
+fn net(args: &[&str]) {
+    use crate::net::{self, MacAddress, MAX_FRAME_SIZE};
+
+    match args {
+        [] => {
+            for info in net::interfaces() {
+                serial_println!(
+                    "{} {} {} link {}{}{}",
+                    info.interface,
+                    info.name,
+                    MacAddress(info.mac),
+                    if info.link_up { "up" } else { "down" },
+                    if info.offloads.tx_checksum { " tx-csum" } else { "" },
+                    if info.offloads.rx_checksum { " rx-csum" } else { "" }
+                );
+                let stats = info.stats;
+                serial_println!(
+                    "  rx {} frames {} bytes {} dropped, tx {} frames {} bytes {} errors",
+                    stats.rx_packets,
+                    stats.rx_bytes,
+                    stats.rx_dropped,
+                    stats.tx_packets,
+                    stats.tx_bytes,
+                    stats.tx_errors
+                );
+            }
+        }
+        ["recv", interface] => {
+            let Ok(interface) = interface.parse() else {
+                return serial_println!("{}: not an interface number", interface);
+            };
+            let mut frame = [0; MAX_FRAME_SIZE];
+            loop {
+                match net::receive(interface, &mut frame) {
+                    Ok(Some(length)) => serial_println!(
+                        "{} > {} type {:04x} length {}",
+                        MacAddress(frame[6..12].try_into().unwrap()),
+                        MacAddress(frame[..6].try_into().unwrap()),
+                        u16::from_be_bytes([frame[12], frame[13]]),
+                        length
+                    ),
+                    Ok(None) => break,
+                    Err(e) => return serial_println!("{}: {:?}", interface, e),
+                }
+            }
+        }
+        _ => serial_println!("usage: net [recv <interface>]"),
+    }
+}
+
+fn trace(args: &[&str]) {
+    use crate::kernel::trace::{self, Subsystem};
+
+    match args {
+        [] => {
+            let lost = trace::drain(|record| serial_println!("{}", record));
+            if lost > 0 {
+                serial_println!("{} records lost", lost);
+            }
+        }
+        [switch @ ("on" | "off"), list] => {
+            let Some(mask) = trace::parse_mask(list) else {
+                return serial_println!("{}: not a list of subsystems", list);
+            };
+            match *switch {
+                "on" => trace::set_mask(trace::mask() | mask),
+                _ => trace::set_mask(trace::mask() & !mask),
+            }
+            serial_print!("tracing:");
+            for subsystem in Subsystem::ALL.into_iter().filter(|s| trace::enabled(*s)) {
+                serial_print!(" {}", subsystem.name());
+            }
+            serial_println!();
+        }
+        _ => serial_println!("usage: trace [on|off <subsystem,...|all>]"),
+    }
+}
+
+fn probe(args: &[&str]) {
+    use crate::kernel::{backtrace, kprobe};
+
+    match args {
+        [] => {
+            for probe in kprobe::list() {
+                match backtrace::resolve(probe.address) {
+                    Some(symbol) => serial_println!("{:#018x} {:>8} hits  {}", probe.address, probe.hits, symbol),
+                    None => serial_println!("{:#018x} {:>8} hits", probe.address, probe.hits),
+                }
+            }
+        }
+        [action @ ("add" | "del"), spec] => {
+            let Some(address) = kprobe::parse(spec) else {
+                return serial_println!("{}: no such function", spec);
+            };
+            let result = match *action {
+                "add" => kprobe::register(address, kprobe::log_arguments),
+                _ => kprobe::unregister(address),
+            };
+            if let Err(e) = result {
+                serial_println!("{}: {:?}", spec, e);
+            }
+        }
+        _ => serial_println!("usage: probe [add|del <function[+offset]|address>]"),
+    }
+}
+
+fn poweroff(_args: &[&str]) {
+    let e = crate::kernel::power::shutdown();
+    serial_println!("power off failed: {:?}", e);
+}
+
+fn reboot(_args: &[&str]) {
+    crate::kernel::power::reboot();
+}
@@ -0,0 +1,130 @@
+//! Interactive kernel shell
+//!
+//! A line-oriented shell on the consoles, running from the idle loop until
+//! userspace can host one. Input comes from the serial port and from the
+//! keyboard while no window has focus; output goes through the kernel's
+//! print path, so it shows on both the serial and framebuffer consoles.
+//! A program the shell runs holds the console until it exits, and input
+//! goes to it meanwhile (see [`crate::userspace::console`]).
+//!
+//! The commands themselves are in [`commands`].
+
+pub mod commands;
+
+use arrayvec::{ArrayString, ArrayVec};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::userspace::console;
+
+/// Longest command line
+pub const MAX_LINE: usize = 160;
+
+/// Keyboard characters buffered between polls
+const KEY_BUFFER_SIZE: usize = 64;
+
+const PROMPT: &str = "zen> ";
+
+/// evdev codes of the shift keys
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTSHIFT: u16 = 54;
+
+/// US layout, from evdev code 2: unshifted and shifted characters
+const KEYMAP: &[u8] = b"1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const KEYMAP_SHIFT: &[u8] = b"!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+struct Keyboard {
+    shift: bool,
+    pending: ArrayVec<u8, KEY_BUFFER_SIZE>,
+}
+
+static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard {
+    shift: false,
+    pending: ArrayVec::new_const(),
+});
+
+static LINE: Mutex<ArrayString<MAX_LINE>> = Mutex::new(ArrayString::new_const());
+
+/// Print the banner and first prompt
+pub fn init() {
+    crate::serial_println!("Zen OS shell; type `help` for commands");
+    crate::serial_print!("{}", PROMPT);
+}
+
+/// Take a key the window manager had no window for
+pub fn key(code: u16, pressed: bool) {
+    interrupts::without_interrupts(|| {
+        let mut keyboard = KEYBOARD.lock();
+        if code == KEY_LEFTSHIFT || code == KEY_RIGHTSHIFT {
+            keyboard.shift = pressed;
+            return;
+        }
+        let map = if keyboard.shift { KEYMAP_SHIFT } else { KEYMAP };
+        let byte = (code as usize).checked_sub(2).and_then(|i| map.get(i)).copied();
+        if let Some(byte) = byte.filter(|b| pressed && *b != 0) {
+            let _ = keyboard.pending.try_push(byte);
+        }
+    });
+}
+
+/// Next input byte from either console
+fn next_byte() -> Option<u8> {
+    let key = interrupts::without_interrupts(|| {
+        let mut keyboard = KEYBOARD.lock();
+        (!keyboard.pending.is_empty()).then(|| keyboard.pending.remove(0))
+    });
+    key.or_else(|| interrupts::without_interrupts(|| crate::boot::serial::SERIAL1.lock().try_receive().ok()))
+}
+
+/// Edit the line with `byte`, returning a finished line to run
+fn edit(line: &mut ArrayString<MAX_LINE>, byte: u8) -> Option<ArrayString<MAX_LINE>> {
+    match byte {
+        b'\r' | b'\n' => {
+            crate::serial_println!();
+            Some(core::mem::take(line))
+        }
+        0x08 | 0x7f => {
+            if line.pop().is_some() {
+                crate::serial_print!("\u{8} \u{8}");
+            }
+            None
+        }
+        b' '..=b'~' => {
+            if line.try_push(byte as char).is_ok() {
+                crate::serial_print!("{}", byte as char);
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+/// Whether a program the shell ran holds the console
+static WAITING: Mutex<bool> = Mutex::new(false);
+
+/// Handle pending input, from the idle loop
+pub fn poll() {
+    let foreground = console::foreground();
+    let mut waiting = WAITING.lock();
+    if *waiting && foreground.is_none() {
+        *waiting = false;
+        crate::serial_print!("{}", PROMPT);
+    }
+    drop(waiting);
+
+    while let Some(byte) = next_byte() {
+        if console::foreground().is_some() {
+            console::input(byte);
+            continue;
+        }
+        let finished = edit(&mut LINE.lock(), byte);
+        if let Some(line) = finished {
+            commands::run(&line);
+            if console::foreground().is_some() {
+                *WAITING.lock() = true;
+            } else {
+                crate::serial_print!("{}", PROMPT);
+            }
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! Asynchronous I/O submission/completion rings (io_uring-style)
+//!
+//! A ring is only usable by the process that created it; others are told
+//! it does not exist. Buffers are checked against the owner's address
+//! space when submitted, and copied through it when the request runs.
+//! Programs reach their rings through `SYS_IO`, and a process's rings go
+//! when it exits.
+//!
+//! A ring ID is its table slot with a generation above it, so a request
+//! still in flight when its ring is destroyed does not complete into a
+//! newer ring in the same slot.
+
+use arrayvec::ArrayVec;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use super::iosched::{self, IoPriority};
+use super::{StorageError, WriteFlags};
+use crate::userspace::uaccess;
+
+/// Entries per submission/completion ring (must be power of 2)
+pub const IO_RING_ENTRIES: usize = 64;
+
+/// Maximum number of I/O rings
+pub const MAX_IO_RINGS: usize = 64;
+
+/// IPC message type used for completion notifications
+pub const IO_COMPLETION_MSG: u32 = 0x494F_0001;
+
+/// I/O operation type
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IoOp {
+    Read = 0,
+    Write = 1,
+    /// Barrier: completes once everything submitted before it is durable
+    Flush = 2,
+}
+
+/// Submission queue entry
+///
+/// The buffer at `buffer` must stay valid until the matching completion is reaped.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct IoDescriptor {
+    /// Operation to perform
+    pub op: IoOp,
+    /// Target device
+    pub device: u32,
+    /// Byte offset on the device
+    pub offset: u64,
+    /// Address of the caller's buffer
+    pub buffer: u64,
+    /// Length of the buffer in bytes
+    pub length: u32,
+    /// Ordering flags for writes
+    pub flags: WriteFlags,
+    /// Opaque value returned in the completion
+    pub user_data: u64,
+}
+
+/// Completion queue entry
+#[derive(Clone, Copy)]
+pub struct IoCompletion {
+    /// Value copied from the submitted descriptor
+    pub user_data: u64,
+    /// Bytes transferred or the error that occurred
+    pub result: Result<usize, StorageError>,
+}
+
+/// Single-producer/single-consumer ring of fixed capacity
+struct Ring<T: Copy> {
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    entries: [Option<T>; IO_RING_ENTRIES],
+}
+
+impl<T: Copy> Ring<T> {
+    const fn new() -> Self {
+        Self {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            entries: [None; IO_RING_ENTRIES],
+        }
+    }
+
+    fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+        (tail + IO_RING_ENTRIES - head) % IO_RING_ENTRIES
+    }
+
+    fn free(&self) -> usize {
+        IO_RING_ENTRIES - 1 - self.len()
+    }
+
+    fn is_full(&self) -> bool {
+        self.free() == 0
+    }
+
+    fn push(&mut self, entry: T) -> Result<(), StorageError> {
+        if self.is_full() {
+            return Err(StorageError::QueueFull);
+        }
+
+        let tail = self.tail.load(Ordering::Acquire);
+        self.entries[tail] = Some(entry);
+        self.tail.store((tail + 1) % IO_RING_ENTRIES, Ordering::Release);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+
+        if head == tail {
+            return None;
+        }
+
+        let entry = self.entries[head].take();
+        self.head.store((head + 1) % IO_RING_ENTRIES, Ordering::Release);
+        entry
+    }
+}
+
+/// Submission/completion ring pair owned by one process
+pub struct IoRing {
+    /// ID the ring was created with
+    id: u64,
+    /// Owning process ID
+    owner: u32,
+    /// IPC channel signalled when completions are posted
+    notify_channel: Option<u64>,
+    /// Scheduling class for this ring's requests
+    priority: IoPriority,
+    /// Submissions handed to the I/O scheduler but not yet completed
+    in_flight: usize,
+    /// Completions posted since the last notification
+    unnotified: usize,
+    sq: Ring<IoDescriptor>,
+    cq: Ring<IoCompletion>,
+}
+
+impl IoRing {
+    /// Create a new empty ring pair
+    pub const fn new(id: u64, owner: u32, notify_channel: Option<u64>, priority: IoPriority) -> Self {
+        Self {
+            id,
+            owner,
+            notify_channel,
+            priority,
+            in_flight: 0,
+            unnotified: 0,
+            sq: Ring::new(),
+            cq: Ring::new(),
+        }
+    }
+
+    /// Send a doorbell message to the owner's notification channel
+    fn notify(&mut self) {
+        let completed = core::mem::take(&mut self.unnotified);
+        let channel = match self.notify_channel {
+            Some(channel) if completed > 0 => channel,
+            _ => return,
+        };
+
+        let count = (completed as u32).to_le_bytes();
+        let header = crate::ipc::MessageHeader {
+            id: 0,
+            sender: 0, // kernel
+            receiver: self.owner,
+            length: count.len() as u32,
+            msg_type: IO_COMPLETION_MSG,
+        };
+
+        // A full notification channel is not an error: the owner still reaps the CQ
+        let _ = crate::ipc::msg_send(channel, header, &count);
+    }
+}
+
+/// Global I/O ring table
+static IO_RINGS: Mutex<[Option<IoRing>; MAX_IO_RINGS]> = Mutex::new([const { None }; MAX_IO_RINGS]);
+
+/// Generation of the next ring created
+static NEXT_GENERATION: AtomicU32 = AtomicU32::new(1);
+
+/// Table slot a ring ID names
+fn slot_of(ring_id: u64) -> usize {
+    ring_id as u32 as usize
+}
+
+/// Create a new I/O ring, optionally bound to an IPC notification channel
+pub fn io_ring_create(
+    owner: u32,
+    notify_channel: Option<u64>,
+    priority: IoPriority,
+) -> Result<u64, StorageError> {
+    let mut rings = IO_RINGS.lock();
+
+    let (index, slot) = rings
+        .iter_mut()
+        .enumerate()
+        .find(|(_, slot)| slot.is_none())
+        .ok_or(StorageError::TooManyRings)?;
+    let generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+    let id = (generation as u64) << 32 | index as u64;
+    *slot = Some(IoRing::new(id, owner, notify_channel, priority));
+    Ok(id)
+}
+
+/// The slot of ring `ring_id` if `caller` owns the ring in it
+fn owned_slot(
+    rings: &mut [Option<IoRing>; MAX_IO_RINGS],
+    ring_id: u64,
+    caller: u32,
+) -> Result<&mut Option<IoRing>, StorageError> {
+    rings
+        .get_mut(slot_of(ring_id))
+        .filter(|slot| slot.as_ref().is_some_and(|ring| ring.id == ring_id && ring.owner == caller))
+        .ok_or(StorageError::InvalidRing)
+}
+
+/// Ring `ring_id` if `caller` owns it
+fn owned(rings: &mut [Option<IoRing>; MAX_IO_RINGS], ring_id: u64, caller: u32) -> Result<&mut IoRing, StorageError> {
+    owned_slot(rings, ring_id, caller)?.as_mut().ok_or(StorageError::InvalidRing)
+}
+
+/// Destroy an I/O ring of `caller`'s, dropping any unreaped completions
+pub fn io_ring_destroy(caller: u32, ring_id: u64) -> Result<(), StorageError> {
+    let mut rings = IO_RINGS.lock();
+    owned_slot(&mut rings, ring_id, caller)?.take();
+    Ok(())
+}
+
+/// Destroy every ring of a process that exited
+pub fn io_release_process(owner: u32) {
+    let mut rings = IO_RINGS.lock();
+    for slot in rings.iter_mut() {
+        if slot.as_ref().is_some_and(|ring| ring.owner == owner) {
+            *slot = None;
+        }
+    }
+}
+
+/// Queue a descriptor on the submission queue of a ring of `caller`'s,
+/// whose buffer must be memory of the caller's
+pub fn io_submit(caller: u32, ring_id: u64, desc: IoDescriptor) -> Result<(), StorageError> {
+    if desc.op != IoOp::Flush {
+        // Reads write to the buffer
+        let write = desc.op == IoOp::Read;
+        if desc.length > iosched::MAX_MERGED_BYTES {
+            return Err(StorageError::OutOfRange);
+        }
+        if desc.buffer == 0 || uaccess::check(desc.buffer, desc.length as usize, write).is_err() {
+            return Err(StorageError::InvalidBuffer);
+        }
+    }
+
+    let mut rings = IO_RINGS.lock();
+    owned(&mut rings, ring_id, caller)?.sq.push(desc)
+}
+
+/// Ring the doorbell of a ring of `caller`'s: hand pending submissions to
+/// the I/O scheduler and run it
+pub fn io_doorbell(caller: u32, ring_id: u64) -> Result<usize, StorageError> {
+    let mut batch: ArrayVec<IoDescriptor, IO_RING_ENTRIES> = ArrayVec::new();
+
+    let priority = {
+        let mut rings = IO_RINGS.lock();
+        let ring = owned(&mut rings, ring_id, caller)?;
+
+        // Keep a CQ slot reserved for every request in flight
+        while ring.cq.free() > ring.in_flight {
+            match ring.sq.pop() {
+                Some(desc) => {
+                    batch.push(desc);
+                    ring.in_flight += 1;
+                }
+                None => break,
+            }
+        }
+        ring.priority
+    };
+
+    for desc in &batch {
+        if let Err(e) = iosched::submit(priority, ring_id, caller, desc) {
+            complete(ring_id, desc.user_data, Err(e));
+        }
+    }
+    iosched::run();
+
+    Ok(batch.len())
+}
+
+/// Post a completion for a request previously taken from a ring
+pub fn complete(ring_id: u64, user_data: u64, result: Result<usize, StorageError>) {
+    let mut rings = IO_RINGS.lock();
+    let ring = match rings.get_mut(slot_of(ring_id)).and_then(|slot| slot.as_mut()) {
+        Some(ring) if ring.id == ring_id => ring,
+        // Ring destroyed while the request was in flight, and its slot
+        // perhaps given to another
+        _ => return,
+    };
+
+    ring.in_flight = ring.in_flight.saturating_sub(1);
+    if ring.cq.push(IoCompletion { user_data, result }).is_ok() {
+        ring.unnotified += 1;
+    }
+}
+
+/// Notify owners of every ring with newly posted completions
+pub fn flush_notifications() {
+    let mut rings = IO_RINGS.lock();
+    for ring in rings.iter_mut().flatten() {
+        ring.notify();
+    }
+}
+
+/// Reap the next completion from a ring of `caller`'s, if any
+pub fn io_reap(caller: u32, ring_id: u64) -> Result<Option<IoCompletion>, StorageError> {
+    let mut rings = IO_RINGS.lock();
+    Ok(owned(&mut rings, ring_id, caller)?.cq.pop())
+}
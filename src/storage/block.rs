@@ -0,0 +1,153 @@
+//! Block device driver interface
+//!
+//! NVMe, virtio-blk, AHCI, RAM disks and RAID volumes all implement
+//! [`BlockDevice`] and register with the storage layer, which gives them a
+//! device number and handles byte-granular access on top of whole blocks.
+
+use super::smart::{SmartLogFormat, SMART_LOG_SIZE};
+use super::StorageError;
+
+/// Largest block size the storage layer supports
+pub const MAX_BLOCK_SIZE: usize = 4096;
+
+/// A driver for one block device
+pub trait BlockDevice: Sync {
+    /// Short driver-assigned name (e.g. `nvme0n1`, `ram0`)
+    fn name(&self) -> &'static str;
+
+    /// Logical block size in bytes (a power of two, at most [`MAX_BLOCK_SIZE`])
+    fn block_size(&self) -> u32;
+
+    /// Capacity in blocks
+    fn capacity(&self) -> u64;
+
+    /// Read whole blocks starting at `lba`; `buffer` is a multiple of the block size
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<usize, StorageError>;
+
+    /// Write whole blocks starting at `lba`; `data` is a multiple of the block size
+    fn write_blocks(&self, lba: u64, data: &[u8]) -> Result<usize, StorageError>;
+
+    /// Flush the volatile write cache
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Tell the device a block range no longer holds data
+    fn discard(&self, _lba: u64, _blocks: u64) -> Result<(), StorageError> {
+        Err(StorageError::Unsupported)
+    }
+
+    /// Fetch the SMART/health log page
+    fn smart_log(&self, _log: &mut [u8; SMART_LOG_SIZE]) -> Result<SmartLogFormat, StorageError> {
+        Err(StorageError::Unsupported)
+    }
+
+    /// Whether writes are rejected
+    fn read_only(&self) -> bool {
+        false
+    }
+}
+
+/// Summary of a registered device
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceInfo {
+    pub name: &'static str,
+    pub block_size: u32,
+    /// Capacity in blocks
+    pub capacity: u64,
+    pub read_only: bool,
+}
+
+impl DeviceInfo {
+    pub fn of(device: &dyn BlockDevice) -> Self {
+        Self {
+            name: device.name(),
+            block_size: device.block_size(),
+            capacity: device.capacity(),
+            read_only: device.read_only(),
+        }
+    }
+
+    /// Capacity in bytes
+    pub fn size(&self) -> u64 {
+        self.capacity * self.block_size as u64
+    }
+}
+
+/// Read an arbitrary byte range, bouncing partial blocks through a scratch block
+pub(crate) fn read_bytes(dev: &dyn BlockDevice, offset: u64, buffer: &mut [u8]) -> Result<usize, StorageError> {
+    let bs = dev.block_size() as u64;
+    let mut scratch = [0u8; MAX_BLOCK_SIZE];
+    let mut done = 0;
+
+    while done < buffer.len() {
+        let pos = offset + done as u64;
+        let lba = pos / bs;
+        let skip = (pos % bs) as usize;
+        let remaining = buffer.len() - done;
+
+        if skip == 0 && remaining as u64 >= bs {
+            // Aligned middle: transfer straight into the caller's buffer
+            let len = remaining - remaining % bs as usize;
+            dev.read_blocks(lba, &mut buffer[done..done + len])?;
+            done += len;
+        } else {
+            let block = &mut scratch[..bs as usize];
+            dev.read_blocks(lba, block)?;
+            let len = remaining.min(bs as usize - skip);
+            buffer[done..done + len].copy_from_slice(&block[skip..skip + len]);
+            done += len;
+        }
+    }
+
+    Ok(done)
+}
+
+/// Write an arbitrary byte range, read-modify-writing partial blocks
+pub(crate) fn write_bytes(dev: &dyn BlockDevice, offset: u64, data: &[u8]) -> Result<usize, StorageError> {
+    if dev.read_only() {
+        return Err(StorageError::ReadOnly);
+    }
+
+    let bs = dev.block_size() as u64;
+    let mut scratch = [0u8; MAX_BLOCK_SIZE];
+    let mut done = 0;
+
+    while done < data.len() {
+        let pos = offset + done as u64;
+        let lba = pos / bs;
+        let skip = (pos % bs) as usize;
+        let remaining = data.len() - done;
+
+        if skip == 0 && remaining as u64 >= bs {
+            let len = remaining - remaining % bs as usize;
+            dev.write_blocks(lba, &data[done..done + len])?;
+            done += len;
+        } else {
+            let block = &mut scratch[..bs as usize];
+            dev.read_blocks(lba, block)?;
+            let len = remaining.min(bs as usize - skip);
+            block[skip..skip + len].copy_from_slice(&data[done..done + len]);
+            dev.write_blocks(lba, block)?;
+            done += len;
+        }
+    }
+
+    Ok(done)
+}
+
+/// Discard the whole blocks inside a byte range
+pub(crate) fn discard_bytes(dev: &dyn BlockDevice, offset: u64, length: u64) -> Result<(), StorageError> {
+    let bs = dev.block_size() as u64;
+    let first = offset.div_ceil(bs);
+    let end = (offset + length) / bs;
+    if end <= first {
+        return Ok(());
+    }
+
+    // Discard is advisory; devices without it just keep the stale data
+    match dev.discard(first, end - first) {
+        Err(StorageError::Unsupported) => Ok(()),
+        result => result,
+    }
+}
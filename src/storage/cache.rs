@@ -0,0 +1,233 @@
+//! Unit cache with prefetch hints
+//!
+//! Decompressed compression units are kept after a read so repeated reads
+//! skip the device and LZ4. Other subsystems may hint at ranges they expect
+//! to be read soon; hinted units are loaded from the idle loop. Hints can
+//! be switched off, and the counters separate hits on prefetched units from
+//! ordinary hits so the benefit can be measured either way.
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use super::extent::COMPRESSION_UNIT;
+
+/// Units held in the cache
+pub const CACHE_UNITS: usize = 32;
+
+/// Hinted units waiting to be loaded
+const MAX_HINTS: usize = 32;
+
+struct Line {
+    device: u32,
+    logical: u64,
+    valid: bool,
+    /// Loaded from a hint and not read since
+    prefetched: bool,
+    last_used: u64,
+    data: [u8; COMPRESSION_UNIT],
+}
+
+impl Line {
+    const EMPTY: Self = Self {
+        device: 0,
+        logical: 0,
+        valid: false,
+        prefetched: false,
+        last_used: 0,
+        data: [0; COMPRESSION_UNIT],
+    };
+
+    fn holds(&self, device: u32, logical: u64) -> bool {
+        self.valid && self.device == device && self.logical == logical
+    }
+}
+
+/// Cache counters
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Units loaded from hints
+    pub prefetched: u64,
+    /// Reads served by a prefetched unit
+    pub prefetch_hits: u64,
+    /// Prefetched units evicted without being read
+    pub prefetch_wasted: u64,
+    /// Hints dropped with the queue full
+    pub hints_dropped: u64,
+}
+
+impl CacheStats {
+    /// Reads served from the cache, in percent
+    pub fn hit_rate(&self) -> u64 {
+        (self.hits * 100).checked_div(self.hits + self.misses).unwrap_or(0)
+    }
+}
+
+struct Cache {
+    lines: [Line; CACHE_UNITS],
+    clock: u64,
+    hints: ArrayVec<(u32, u64), MAX_HINTS>,
+    prefetch: bool,
+    stats: CacheStats,
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache {
+    lines: [const { Line::EMPTY }; CACHE_UNITS],
+    clock: 0,
+    hints: ArrayVec::new_const(),
+    prefetch: true,
+    stats: CacheStats {
+        hits: 0,
+        misses: 0,
+        prefetched: 0,
+        prefetch_hits: 0,
+        prefetch_wasted: 0,
+        hints_dropped: 0,
+    },
+});
+
+impl Cache {
+    fn find(&self, device: u32, logical: u64) -> Option<usize> {
+        self.lines.iter().position(|l| l.holds(device, logical))
+    }
+
+    /// Line to reuse: an empty one, else the least recently used
+    fn victim(&mut self) -> usize {
+        let index = (0..CACHE_UNITS)
+            .min_by_key(|&i| {
+                let line = &self.lines[i];
+                if line.valid {
+                    line.last_used + 1
+                } else {
+                    0
+                }
+            })
+            .expect("cache has lines");
+        let line = &self.lines[index];
+        if line.valid && line.prefetched {
+            self.stats.prefetch_wasted += 1;
+        }
+        index
+    }
+}
+
+/// Copy `out.len()` bytes at `within` from a cached unit
+///
+/// Returns `false` on a miss.
+pub(crate) fn read(device: u32, logical: u64, within: usize, out: &mut [u8]) -> bool {
+    let mut cache = CACHE.lock();
+    cache.clock += 1;
+    let clock = cache.clock;
+    let Some(index) = cache.find(device, logical) else {
+        cache.stats.misses += 1;
+        return false;
+    };
+    let line = &mut cache.lines[index];
+    out.copy_from_slice(&line.data[within..within + out.len()]);
+    line.last_used = clock;
+    let prefetched = core::mem::take(&mut line.prefetched);
+    cache.stats.hits += 1;
+    if prefetched {
+        cache.stats.prefetch_hits += 1;
+    }
+    true
+}
+
+/// Keep a copy of a unit just read or hinted
+pub(crate) fn insert(device: u32, logical: u64, data: &[u8; COMPRESSION_UNIT], prefetched: bool) {
+    let mut cache = CACHE.lock();
+    cache.clock += 1;
+    let clock = cache.clock;
+    if prefetched {
+        cache.stats.prefetched += 1;
+    }
+    let index = match cache.find(device, logical) {
+        Some(index) => index,
+        None => cache.victim(),
+    };
+    let line = &mut cache.lines[index];
+    line.device = device;
+    line.logical = logical;
+    line.valid = true;
+    line.prefetched = prefetched;
+    line.last_used = clock;
+    line.data.copy_from_slice(data);
+}
+
+/// Refresh a cached unit after it was rewritten
+pub(crate) fn update(device: u32, logical: u64, data: &[u8; COMPRESSION_UNIT]) {
+    let mut cache = CACHE.lock();
+    if let Some(index) = cache.find(device, logical) {
+        cache.lines[index].data.copy_from_slice(data);
+    }
+}
+
+/// Drop cached units of `device` in `start..end`
+pub(crate) fn invalidate(device: u32, start: u64, end: u64) {
+    let mut cache = CACHE.lock();
+    for line in cache.lines.iter_mut() {
+        if line.valid && line.device == device && line.logical >= start && line.logical < end {
+            line.valid = false;
+        }
+    }
+    cache.hints.retain(|&mut (d, l)| d != device || l < start || l >= end);
+}
+
+/// Whether a unit is already cached
+pub(crate) fn contains(device: u32, logical: u64) -> bool {
+    CACHE.lock().find(device, logical).is_some()
+}
+
+/// Next hinted unit to load
+pub(crate) fn next_hint() -> Option<(u32, u64)> {
+    let mut cache = CACHE.lock();
+    if cache.hints.is_empty() {
+        None
+    } else {
+        Some(cache.hints.remove(0))
+    }
+}
+
+/// Ask for `len` bytes at `offset` on `device` to be loaded ahead of use
+///
+/// Ignored while prefetching is off.
+pub fn hint(device: u32, offset: u64, len: u64) {
+    let unit_size = COMPRESSION_UNIT as u64;
+    let mut cache = CACHE.lock();
+    if !cache.prefetch || len == 0 {
+        return;
+    }
+    let mut unit = offset - offset % unit_size;
+    while unit < offset + len {
+        let known = cache.find(device, unit).is_some() || cache.hints.contains(&(device, unit));
+        if !known && cache.hints.try_push((device, unit)).is_err() {
+            cache.stats.hints_dropped += 1;
+        }
+        unit += unit_size;
+    }
+}
+
+/// Turn prefetching on or off; turning it off drops pending hints
+pub fn set_prefetch(enabled: bool) {
+    let mut cache = CACHE.lock();
+    cache.prefetch = enabled;
+    if !enabled {
+        cache.hints.clear();
+    }
+}
+
+/// Whether hints are acted on
+pub fn prefetch_enabled() -> bool {
+    CACHE.lock().prefetch
+}
+
+/// Cache counters
+pub fn stats() -> CacheStats {
+    CACHE.lock().stats
+}
+
+/// Zero the counters, e.g. before measuring with prefetch on or off
+pub fn reset_stats() {
+    CACHE.lock().stats = CacheStats::default();
+}
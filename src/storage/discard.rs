@@ -0,0 +1,136 @@
+//! Batched TRIM/discard propagation
+//!
+//! Freed physical ranges are coalesced per device and issued as a single
+//! NVMe Deallocate / ATA TRIM command once a batch fills up or ages out.
+//! Batches age out on the next `queue` or, on an idle device, from `poll`.
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use super::{StorageError, MAX_DEVICES};
+
+/// Maximum ranges per discard command
+pub const MAX_DISCARD_RANGES: usize = 64;
+
+/// Flush a pending batch once its oldest range is this many ticks old
+pub const DISCARD_MAX_AGE_TICKS: u64 = 100;
+
+/// Physical byte range to discard
+#[derive(Clone, Copy, Debug)]
+pub struct DiscardRange {
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Pending discards for one device
+struct DiscardBatch {
+    ranges: ArrayVec<DiscardRange, MAX_DISCARD_RANGES>,
+    /// Tick at which the first pending range was queued
+    oldest: u64,
+}
+
+impl DiscardBatch {
+    const fn new() -> Self {
+        Self {
+            ranges: ArrayVec::new_const(),
+            oldest: 0,
+        }
+    }
+
+    /// Merge a range into an adjacent pending one or append it
+    fn add(&mut self, range: DiscardRange) -> bool {
+        for r in self.ranges.iter_mut() {
+            if r.offset + r.length == range.offset {
+                r.length += range.length;
+                return true;
+            }
+            if range.offset + range.length == r.offset {
+                r.offset = range.offset;
+                r.length += range.length;
+                return true;
+            }
+        }
+        self.ranges.try_push(range).is_ok()
+    }
+
+    fn submit(&mut self, device: u32) -> Result<(), StorageError> {
+        if self.ranges.is_empty() {
+            return Ok(());
+        }
+        super::discard_raw(device, &self.ranges)?;
+        self.ranges.clear();
+        Ok(())
+    }
+}
+
+static BATCHES: Mutex<[DiscardBatch; MAX_DEVICES]> =
+    Mutex::new([const { DiscardBatch::new() }; MAX_DEVICES]);
+
+/// Queue a physical range for discard
+pub fn queue(device: u32, offset: u64, length: u64) -> Result<(), StorageError> {
+    if device as usize >= MAX_DEVICES {
+        return Err(StorageError::DeviceNotFound);
+    }
+    if length == 0 {
+        return Ok(());
+    }
+
+    let now = crate::scheduler::ticks();
+    let range = DiscardRange { offset, length };
+    let mut batches = BATCHES.lock();
+    let batch = &mut batches[device as usize];
+
+    if batch.ranges.is_empty() {
+        batch.oldest = now;
+    }
+
+    if !batch.add(range) {
+        // Batch full: issue it and start a new one
+        batch.submit(device)?;
+        batch.oldest = now;
+        batch.add(range);
+    }
+
+    if now.saturating_sub(batch.oldest) >= DISCARD_MAX_AGE_TICKS {
+        batch.submit(device)?;
+    }
+
+    Ok(())
+}
+
+/// Issue any pending discards for a device
+pub fn flush(device: u32) -> Result<(), StorageError> {
+    if device as usize >= MAX_DEVICES {
+        return Err(StorageError::DeviceNotFound);
+    }
+    BATCHES.lock()[device as usize].submit(device)
+}
+
+/// Issue batches that have aged out, run from the storage poll loop
+pub fn poll() {
+    let now = crate::scheduler::ticks();
+    let mut batches = BATCHES.lock();
+    for (device, batch) in batches.iter_mut().enumerate() {
+        if !batch.ranges.is_empty() && now.saturating_sub(batch.oldest) >= DISCARD_MAX_AGE_TICKS {
+            if let Err(e) = batch.submit(device as u32) {
+                crate::klog!(Warn, "Discard on device {} failed: {:?}", device, e);
+            }
+        }
+    }
+}
+
+/// Issue pending discards on all devices
+pub fn flush_all() -> Result<(), StorageError> {
+    let mut batches = BATCHES.lock();
+    for (device, batch) in batches.iter_mut().enumerate() {
+        batch.submit(device as u32)?;
+    }
+    Ok(())
+}
+
+/// Drop pending discards of a removed device
+pub fn forget_device(device: u32) {
+    if let Some(batch) = BATCHES.lock().get_mut(device as usize) {
+        batch.ranges.clear();
+    }
+}
@@ -0,0 +1,361 @@
+//! Compressed-extent mapping layer
+//!
+//! Logical data is stored in fixed-size compression units. Each unit is
+//! LZ4-compressed on write and appended to a per-device log; the extent map
+//! records where the current copy of every unit lives on the device.
+//! Reads go through the unit cache.
+//!
+//! Space a superseded or unmapped copy took is discarded and kept on the
+//! device's free list. The log grows until it reaches the end of the
+//! device; after that units go into free ranges, first fit, once the
+//! discards still pending for them have been issued.
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use super::{cache, discard, lz4, StorageError, MAX_DEVICES};
+
+/// Size of a compression unit in bytes
+pub const COMPRESSION_UNIT: usize = 16 * 1024;
+
+/// Physical allocation granularity
+pub const SECTOR_SIZE: usize = 512;
+
+/// Maximum number of mapped extents
+pub const MAX_EXTENTS: usize = 4096;
+
+/// Free ranges tracked per device; space freed past this is lost until the
+/// device is forgotten
+pub const MAX_FREE_RANGES: usize = 256;
+
+/// Hinted units loaded per poll
+const PREFETCH_PER_POLL: usize = 4;
+
+/// Mapping of one logical compression unit to its physical location
+#[derive(Clone, Copy, Debug)]
+pub struct Extent {
+    /// Device the extent lives on
+    pub device: u32,
+    /// Logical byte offset (unit aligned)
+    pub logical: u64,
+    /// Physical byte offset on the device
+    pub physical: u64,
+    /// Length of the stored payload in bytes
+    pub payload_len: u32,
+    /// Whether the payload is LZ4-compressed
+    pub compressed: bool,
+}
+
+impl Extent {
+    /// Bytes occupied on the device (sector aligned)
+    pub fn stored_len(&self) -> u64 {
+        align_up(self.payload_len as usize, SECTOR_SIZE) as u64
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Physical space no unit uses, below the log head
+#[derive(Clone, Copy, Debug)]
+struct FreeRange {
+    offset: u64,
+    length: u64,
+}
+
+/// Extent map plus the scratch buffers used by the compression path
+struct ExtentMap {
+    extents: [Option<Extent>; MAX_EXTENTS],
+    log_heads: [u64; MAX_DEVICES],
+    /// Free ranges of each device, sorted by offset and coalesced
+    free: [ArrayVec<FreeRange, MAX_FREE_RANGES>; MAX_DEVICES],
+    unit_buf: [u8; COMPRESSION_UNIT],
+    comp_buf: [u8; COMPRESSION_UNIT],
+}
+
+impl ExtentMap {
+    const fn new() -> Self {
+        Self {
+            extents: [None; MAX_EXTENTS],
+            log_heads: [0; MAX_DEVICES],
+            free: [const { ArrayVec::new_const() }; MAX_DEVICES],
+            unit_buf: [0; COMPRESSION_UNIT],
+            comp_buf: [0; COMPRESSION_UNIT],
+        }
+    }
+
+    fn find(&self, device: u32, logical: u64) -> Option<Extent> {
+        self.extents
+            .iter()
+            .flatten()
+            .find(|e| e.device == device && e.logical == logical)
+            .copied()
+    }
+
+    /// Insert or replace the mapping for a unit, returning the superseded extent
+    fn insert(&mut self, extent: Extent) -> Result<Option<Extent>, StorageError> {
+        let mut free = None;
+
+        for (i, slot) in self.extents.iter_mut().enumerate() {
+            match slot {
+                Some(e) if e.device == extent.device && e.logical == extent.logical => {
+                    let old = *e;
+                    *e = extent;
+                    return Ok(Some(old));
+                }
+                None if free.is_none() => free = Some(i),
+                _ => {}
+            }
+        }
+
+        let index = free.ok_or(StorageError::ExtentMapFull)?;
+        self.extents[index] = Some(extent);
+        Ok(None)
+    }
+
+    /// Remove the mapping for a unit
+    fn remove(&mut self, device: u32, logical: u64) -> Option<Extent> {
+        self.extents
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(e) if e.device == device && e.logical == logical))
+            .and_then(|slot| slot.take())
+    }
+
+    /// Physical space for `length` bytes: the log head while the device has
+    /// room past it, then the first free range big enough
+    fn allocate(&mut self, device: u32, length: u64) -> Result<u64, StorageError> {
+        let info = super::info(device)?;
+        let end = info.capacity * info.block_size as u64;
+        let head = &mut self.log_heads[device as usize];
+        if head.checked_add(length).is_some_and(|tail| tail <= end) {
+            let offset = *head;
+            *head += length;
+            return Ok(offset);
+        }
+
+        let free = &mut self.free[device as usize];
+        let index = free.iter().position(|r| r.length >= length).ok_or(StorageError::NoSpace)?;
+        // A discard still pending for the range would wipe what goes there
+        discard::flush(device)?;
+        let range = &mut free[index];
+        let offset = range.offset;
+        range.offset += length;
+        range.length -= length;
+        if range.length == 0 {
+            free.remove(index);
+        }
+        Ok(offset)
+    }
+
+    /// Give back space a unit no longer uses, discarding it
+    fn release(&mut self, device: u32, offset: u64, length: u64) -> Result<(), StorageError> {
+        let free = &mut self.free[device as usize];
+        let index = free.partition_point(|r| r.offset < offset);
+        let joins_before = index > 0 && free[index - 1].offset + free[index - 1].length == offset;
+        let joins_after = free.get(index).is_some_and(|r| offset + length == r.offset);
+        match (joins_before, joins_after) {
+            (true, true) => {
+                let after = free.remove(index);
+                free[index - 1].length += length + after.length;
+            }
+            (true, false) => free[index - 1].length += length,
+            (false, true) => {
+                free[index].offset = offset;
+                free[index].length += length;
+            }
+            // A full list leaks the range rather than losing track of one
+            // already on it
+            (false, false) => {
+                let _ = free.try_insert(index, FreeRange { offset, length });
+            }
+        }
+        discard::queue(device, offset, length)
+    }
+
+    /// Load a logical unit into `unit_buf` (zero-filled if unmapped)
+    fn load_unit(&mut self, device: u32, logical: u64) -> Result<(), StorageError> {
+        let extent = match self.find(device, logical) {
+            Some(extent) => extent,
+            None => {
+                self.unit_buf.fill(0);
+                return Ok(());
+            }
+        };
+
+        let len = extent.payload_len as usize;
+        if extent.compressed {
+            super::read_raw(device, extent.physical, &mut self.comp_buf[..len])?;
+            let n = lz4::decompress(&self.comp_buf[..len], &mut self.unit_buf)?;
+            if n != COMPRESSION_UNIT {
+                return Err(StorageError::CorruptData);
+            }
+        } else {
+            super::read_raw(device, extent.physical, &mut self.unit_buf[..len])?;
+        }
+
+        Ok(())
+    }
+
+    /// Compress `unit_buf` and append it to the device log
+    fn store_unit(&mut self, device: u32, logical: u64) -> Result<Option<Extent>, StorageError> {
+        let (payload_len, compressed) = match lz4::compress(&self.unit_buf, &mut self.comp_buf) {
+            Ok(len) if align_up(len, SECTOR_SIZE) < COMPRESSION_UNIT => (len, true),
+            // CompressionFailed or no space saved: fall back to a raw write
+            _ => (COMPRESSION_UNIT, false),
+        };
+
+        let mut extent = Extent {
+            device,
+            logical,
+            physical: 0,
+            payload_len: payload_len as u32,
+            compressed,
+        };
+        extent.physical = self.allocate(device, extent.stored_len())?;
+
+        let payload = if compressed {
+            &self.comp_buf[..payload_len]
+        } else {
+            &self.unit_buf[..]
+        };
+        let stored = super::write_raw(device, extent.physical, payload).and_then(|_| self.insert(extent));
+        let old = match stored {
+            Ok(old) => old,
+            Err(e) => {
+                let _ = self.release(device, extent.physical, extent.stored_len());
+                return Err(e);
+            }
+        };
+
+        // The superseded copy is garbage now
+        if let Some(old) = old {
+            self.release(device, old.physical, old.stored_len())?;
+        }
+        Ok(old)
+    }
+}
+
+static EXTENT_MAP: Mutex<ExtentMap> = Mutex::new(ExtentMap::new());
+
+/// Split a byte offset into its unit base and offset within the unit
+fn unit_of(pos: u64) -> (u64, usize) {
+    let within = (pos % COMPRESSION_UNIT as u64) as usize;
+    (pos - within as u64, within)
+}
+
+/// Write through the compression layer
+pub fn write(device: u32, offset: u64, data: &[u8]) -> Result<usize, StorageError> {
+    if device as usize >= MAX_DEVICES {
+        return Err(StorageError::DeviceNotFound);
+    }
+
+    let mut map = EXTENT_MAP.lock();
+    let mut done = 0;
+
+    while done < data.len() {
+        let (unit, within) = unit_of(offset + done as u64);
+        let chunk = (COMPRESSION_UNIT - within).min(data.len() - done);
+
+        // Partial unit: read-modify-write
+        if chunk < COMPRESSION_UNIT {
+            map.load_unit(device, unit)?;
+        }
+        map.unit_buf[within..within + chunk].copy_from_slice(&data[done..done + chunk]);
+        map.store_unit(device, unit)?;
+        cache::update(device, unit, &map.unit_buf);
+
+        done += chunk;
+    }
+
+    Ok(done)
+}
+
+/// Read through the compression layer
+pub fn read(device: u32, offset: u64, buffer: &mut [u8]) -> Result<usize, StorageError> {
+    if device as usize >= MAX_DEVICES {
+        return Err(StorageError::DeviceNotFound);
+    }
+
+    let mut map = EXTENT_MAP.lock();
+    let mut done = 0;
+
+    while done < buffer.len() {
+        let (unit, within) = unit_of(offset + done as u64);
+        let chunk = (COMPRESSION_UNIT - within).min(buffer.len() - done);
+
+        if !cache::read(device, unit, within, &mut buffer[done..done + chunk]) {
+            map.load_unit(device, unit)?;
+            cache::insert(device, unit, &map.unit_buf, false);
+            buffer[done..done + chunk].copy_from_slice(&map.unit_buf[within..within + chunk]);
+        }
+
+        done += chunk;
+    }
+
+    Ok(done)
+}
+
+/// Look up the extent currently mapping a logical offset
+pub fn lookup(device: u32, offset: u64) -> Option<Extent> {
+    let (unit, _) = unit_of(offset);
+    EXTENT_MAP.lock().find(device, unit)
+}
+
+/// Drop every mapping of a removed device
+pub fn forget_device(device: u32) {
+    let mut map = EXTENT_MAP.lock();
+    for slot in map.extents.iter_mut() {
+        if matches!(slot, Some(e) if e.device == device) {
+            *slot = None;
+        }
+    }
+    if let Some(head) = map.log_heads.get_mut(device as usize) {
+        *head = 0;
+    }
+    if let Some(free) = map.free.get_mut(device as usize) {
+        free.clear();
+    }
+    cache::invalidate(device, 0, u64::MAX);
+}
+
+/// Unmap every unit fully covered by a logical range and discard its physical space
+pub fn unmap(device: u32, offset: u64, length: u64) -> Result<(), StorageError> {
+    if device as usize >= MAX_DEVICES {
+        return Err(StorageError::DeviceNotFound);
+    }
+
+    let unit_size = COMPRESSION_UNIT as u64;
+    let end = offset + length;
+    let mut unit = offset.div_ceil(unit_size) * unit_size;
+    let mut map = EXTENT_MAP.lock();
+
+    cache::invalidate(device, unit, end - end % unit_size);
+    while unit + unit_size <= end {
+        if let Some(extent) = map.remove(device, unit) {
+            map.release(device, extent.physical, extent.stored_len())?;
+        }
+        unit += unit_size;
+    }
+
+    Ok(())
+}
+
+/// Load units hinted through the cache, run from the idle loop
+///
+/// Unmapped units read as zeros and are not worth a cache line, so their
+/// hints are dropped.
+pub fn prefetch() {
+    for _ in 0..PREFETCH_PER_POLL {
+        let Some((device, unit)) = cache::next_hint() else {
+            return;
+        };
+        let mut map = EXTENT_MAP.lock();
+        if map.find(device, unit).is_none() || cache::contains(device, unit) {
+            continue;
+        }
+        if map.load_unit(device, unit).is_ok() {
+            cache::insert(device, unit, &map.unit_buf, true);
+        }
+    }
+}
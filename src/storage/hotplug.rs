@@ -0,0 +1,149 @@
+//! Device hot-plug and surprise removal
+//!
+//! Drivers report presence changes from their PCIe presence-detect or virtio
+//! config-change interrupt, or register a presence probe that is polled from
+//! the idle loop. A new device has its FAT32 volume mounted, if it holds
+//! one. Removal fails in-flight commands with `DeviceGone`, drops the
+//! device's cached state, unmounts its volume and notifies the subscribed
+//! device manager.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+
+use super::irq::MAX_HW_QUEUES;
+use super::{StorageError, MAX_DEVICES};
+
+/// IPC message type for device events
+pub const DEVICE_EVENT_MSG: u32 = 0x4850_0001;
+
+/// Ticks between presence probes
+pub const HOTPLUG_POLL_INTERVAL_TICKS: u64 = 100;
+
+/// Kind of device event sent to the device manager
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum DeviceEvent {
+    Added = 1,
+    Removed = 2,
+}
+
+/// Driver routine reporting whether a device is still physically present
+pub type PresenceFn = fn(device: u32) -> bool;
+
+struct Hotplug {
+    probes: [Option<PresenceFn>; MAX_DEVICES],
+    event_channel: Option<u64>,
+    last_poll: u64,
+}
+
+static HOTPLUG: Mutex<Hotplug> = Mutex::new(Hotplug {
+    probes: [None; MAX_DEVICES],
+    event_channel: None,
+    last_poll: 0,
+});
+
+/// Bitmask of device numbers that were removed and not yet reused
+static GONE: AtomicU32 = AtomicU32::new(0);
+
+/// Whether a device has been removed
+pub fn is_gone(device: u32) -> bool {
+    (device as usize) < MAX_DEVICES && GONE.load(Ordering::Acquire) & (1 << device) != 0
+}
+
+/// Send a device event to the subscribed channel
+fn send_event(device: u32, event: DeviceEvent) {
+    let channel = match HOTPLUG.lock().event_channel {
+        Some(channel) => channel,
+        None => return,
+    };
+
+    let mut payload = [0u8; 8];
+    payload[..4].copy_from_slice(&device.to_le_bytes());
+    payload[4..].copy_from_slice(&(event as u32).to_le_bytes());
+
+    let header = crate::ipc::MessageHeader {
+        id: 0,
+        sender: 0, // kernel
+        receiver: 0,
+        length: payload.len() as u32,
+        msg_type: DEVICE_EVENT_MSG,
+    };
+    let _ = crate::ipc::msg_send(channel, header, &payload);
+}
+
+/// Register a presence probe for a device
+pub fn watch(device: u32, probe: PresenceFn) -> Result<(), StorageError> {
+    let mut hotplug = HOTPLUG.lock();
+    let slot = hotplug
+        .probes
+        .get_mut(device as usize)
+        .ok_or(StorageError::DeviceNotFound)?;
+    *slot = Some(probe);
+    Ok(())
+}
+
+/// Announce a newly registered device (called by [`super::register`])
+pub(crate) fn device_added(device: u32) {
+    GONE.fetch_and(!(1 << device), Ordering::AcqRel);
+    // A reused device number starts with fresh counters
+    super::stats::reset(device);
+    crate::compat::vfs::device_added(device);
+    send_event(device, DeviceEvent::Added);
+}
+
+/// Tear down a device that has disappeared
+///
+/// New I/O fails with `DeviceGone` from this point on; commands already on
+/// the device's hardware queues complete with the same error and their
+/// waiters are woken.
+pub fn device_removed(device: u32) -> Result<(), StorageError> {
+    if device as usize >= MAX_DEVICES {
+        return Err(StorageError::DeviceNotFound);
+    }
+    if GONE.fetch_or(1 << device, Ordering::AcqRel) & (1 << device) != 0 {
+        return Ok(());
+    }
+
+    for queue in 0..MAX_HW_QUEUES as u16 {
+        if super::irq::queue_device(queue) == Some(device) {
+            let _ = super::mq::remove_hw_queue(queue);
+        }
+    }
+
+    super::discard::forget_device(device);
+    super::extent::forget_device(device);
+    super::smart::forget_device(device);
+    crate::compat::vfs::device_removed(device);
+    super::unregister(device);
+    HOTPLUG.lock().probes[device as usize] = None;
+
+    crate::klog!(Info, "Storage device {} removed", device);
+    send_event(device, DeviceEvent::Removed);
+    Ok(())
+}
+
+/// Probe watched devices if the poll interval has elapsed
+pub fn poll() {
+    let now = crate::scheduler::ticks();
+    let probes = {
+        let mut hotplug = HOTPLUG.lock();
+        if now.saturating_sub(hotplug.last_poll) < HOTPLUG_POLL_INTERVAL_TICKS {
+            return;
+        }
+        hotplug.last_poll = now;
+        hotplug.probes
+    };
+
+    for (device, probe) in probes.iter().enumerate() {
+        if let Some(present) = probe {
+            if !present(device as u32) {
+                let _ = device_removed(device as u32);
+            }
+        }
+    }
+}
+
+/// Subscribe an IPC channel to device events
+pub fn subscribe(channel: u64) {
+    HOTPLUG.lock().event_channel = Some(channel);
+}
@@ -0,0 +1,260 @@
+//! I/O scheduler - merging elevator with priority classes
+//!
+//! Requests are kept sorted per class and dispatched in C-SCAN order.
+//! Realtime always goes first, best-effort next, and idle only when the
+//! other classes are empty (or after it has waited too long).
+
+use arrayvec::ArrayVec;
+use heapless::Vec;
+use spin::Mutex;
+
+use super::aio::{self, IoDescriptor, IoOp};
+use super::{StorageError, WriteFlags};
+use crate::userspace::uaccess;
+
+/// Maximum queued requests per priority class
+pub const MAX_QUEUED_PER_CLASS: usize = 128;
+
+/// Maximum submissions merged into one request
+pub const MAX_MERGE_PARTS: usize = 16;
+
+/// Maximum size of a merged request
+pub const MAX_MERGED_BYTES: u32 = 128 * 1024;
+
+/// Dispatches after which a waiting idle request is served anyway
+pub const IDLE_STARVATION_LIMIT: u32 = 64;
+
+/// I/O priority class
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IoPriority {
+    /// Latency-critical I/O (e.g. compositor swap)
+    Realtime = 0,
+    /// Regular interactive and filesystem I/O
+    BestEffort = 1,
+    /// Background work such as scrubbing and dedup scans
+    Idle = 2,
+}
+
+/// One submission folded into a request
+#[derive(Clone, Copy)]
+struct RequestPart {
+    ring_id: u64,
+    user_data: u64,
+    length: u32,
+}
+
+/// A (possibly merged) request waiting for dispatch
+#[derive(Clone)]
+pub struct IoRequest {
+    /// Process whose memory the buffer is in
+    pub owner: u32,
+    pub op: IoOp,
+    pub device: u32,
+    pub offset: u64,
+    pub buffer: u64,
+    pub length: u32,
+    pub flags: WriteFlags,
+    parts: ArrayVec<RequestPart, MAX_MERGE_PARTS>,
+}
+
+impl IoRequest {
+    fn key(&self) -> (u32, u64) {
+        (self.device, self.offset)
+    }
+
+    /// Try to append a contiguous submission from the same process to this
+    /// request
+    fn try_merge(&mut self, ring_id: u64, owner: u32, desc: &IoDescriptor) -> bool {
+        let contiguous = owner == self.owner
+            && desc.op == self.op
+            && desc.flags == self.flags
+            && desc.device == self.device
+            && self.offset.checked_add(self.length as u64) == Some(desc.offset)
+            && self.buffer.checked_add(self.length as u64) == Some(desc.buffer);
+        if !contiguous || self.parts.is_full() {
+            return false;
+        }
+        let Some(length) = self.length.checked_add(desc.length).filter(|&length| length <= MAX_MERGED_BYTES) else {
+            return false;
+        };
+
+        self.length = length;
+        self.parts.push(RequestPart {
+            ring_id,
+            user_data: desc.user_data,
+            length: desc.length,
+        });
+        true
+    }
+}
+
+/// Sorted queue for one priority class
+struct ClassQueue {
+    requests: Vec<IoRequest, MAX_QUEUED_PER_CLASS>,
+}
+
+impl ClassQueue {
+    const fn new() -> Self {
+        Self { requests: Vec::new() }
+    }
+
+    fn insert(&mut self, ring_id: u64, owner: u32, desc: &IoDescriptor) -> Result<(), StorageError> {
+        for request in self.requests.iter_mut() {
+            if request.try_merge(ring_id, owner, desc) {
+                return Ok(());
+            }
+        }
+
+        let mut parts = ArrayVec::new();
+        parts.push(RequestPart {
+            ring_id,
+            user_data: desc.user_data,
+            length: desc.length,
+        });
+        let request = IoRequest {
+            owner,
+            op: desc.op,
+            device: desc.device,
+            offset: desc.offset,
+            buffer: desc.buffer,
+            length: desc.length,
+            flags: desc.flags,
+            parts,
+        };
+
+        let pos = self
+            .requests
+            .iter()
+            .position(|r| r.key() > request.key())
+            .unwrap_or(self.requests.len());
+        self.requests
+            .insert(pos, request)
+            .map_err(|_| StorageError::QueueFull)
+    }
+
+    /// Take the next request at or after the head position, wrapping around
+    fn take_next(&mut self, head: (u32, u64)) -> Option<IoRequest> {
+        if self.requests.is_empty() {
+            return None;
+        }
+        let pos = self
+            .requests
+            .iter()
+            .position(|r| r.key() >= head)
+            .unwrap_or(0);
+        Some(self.requests.remove(pos))
+    }
+}
+
+/// Elevator state
+struct Elevator {
+    queues: [ClassQueue; 3],
+    /// Position of the last dispatched request
+    head: (u32, u64),
+    /// Dispatches since an idle request was last served
+    idle_wait: u32,
+}
+
+impl Elevator {
+    const fn new() -> Self {
+        Self {
+            queues: [ClassQueue::new(), ClassQueue::new(), ClassQueue::new()],
+            head: (0, 0),
+            idle_wait: 0,
+        }
+    }
+
+    fn next(&mut self) -> Option<IoRequest> {
+        let idle = IoPriority::Idle as usize;
+        let idle_waiting = !self.queues[idle].requests.is_empty();
+
+        let class = if idle_waiting && self.idle_wait >= IDLE_STARVATION_LIMIT {
+            idle
+        } else {
+            self.queues.iter().position(|q| !q.requests.is_empty())?
+        };
+        let request = self.queues[class].take_next(self.head)?;
+
+        self.idle_wait = if class == idle || !idle_waiting {
+            0
+        } else {
+            self.idle_wait + 1
+        };
+        self.head = (request.device, request.offset + request.length as u64);
+        Some(request)
+    }
+}
+
+static ELEVATOR: Mutex<Elevator> = Mutex::new(Elevator::new());
+
+/// Kernel copy of the buffer of the request being executed
+static BOUNCE: Mutex<[u8; MAX_MERGED_BYTES as usize]> = Mutex::new([0; MAX_MERGED_BYTES as usize]);
+
+/// Queue a submission from a ring of `owner`'s in the given priority class
+pub fn submit(priority: IoPriority, ring_id: u64, owner: u32, desc: &IoDescriptor) -> Result<(), StorageError> {
+    if desc.op == IoOp::Flush {
+        // Barrier: nothing queued before the flush may be reordered past it
+        while dispatch_one() {}
+        aio::complete(ring_id, desc.user_data, super::flush(desc.device).map(|_| 0));
+        return Ok(());
+    }
+
+    ELEVATOR.lock().queues[priority as usize].insert(ring_id, owner, desc)
+}
+
+/// Perform a request against the block layer
+fn execute(request: &IoRequest) -> Result<usize, StorageError> {
+    if request.buffer == 0 {
+        return Err(StorageError::InvalidBuffer);
+    }
+
+    // Whichever process rang the doorbell, the buffer is in the owner's
+    // address space, so it is copied through a kernel one
+    let mut bounce = BOUNCE.lock();
+    let bounce = bounce.get_mut(..request.length as usize).ok_or(StorageError::OutOfRange)?;
+    match request.op {
+        IoOp::Flush => super::flush(request.device).map(|_| 0),
+        IoOp::Read => {
+            let read = super::read(request.device, request.offset, bounce)?;
+            uaccess::copy_to_process(request.owner, request.buffer, &bounce[..read])
+                .map_err(|_| StorageError::InvalidBuffer)?;
+            Ok(read)
+        }
+        IoOp::Write => {
+            uaccess::copy_from_process(request.owner, bounce, request.buffer)
+                .map_err(|_| StorageError::InvalidBuffer)?;
+            super::write_ordered(request.device, request.offset, bounce, request.flags)
+        }
+    }
+}
+
+/// Dispatch one request; returns false when all queues are empty
+pub fn dispatch_one() -> bool {
+    // Release the elevator before doing I/O so submitters are not blocked
+    let request = match ELEVATOR.lock().next() {
+        Some(request) => request,
+        None => return false,
+    };
+
+    // Parts are contiguous, so a short transfer ends part-way through one
+    // and leaves the parts after it with nothing
+    let result = execute(&request);
+    let mut remaining = result.unwrap_or(0);
+    for part in &request.parts {
+        let done = remaining.min(part.length as usize);
+        remaining -= done;
+        aio::complete(part.ring_id, part.user_data, result.map(|_| done));
+    }
+    true
+}
+
+/// Dispatch until every queue is empty, then notify ring owners
+pub fn run() -> usize {
+    let mut dispatched = 0;
+    while dispatch_one() {
+        dispatched += 1;
+    }
+    aio::flush_notifications();
+    dispatched
+}
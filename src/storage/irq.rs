@@ -0,0 +1,260 @@
+//! Interrupt-driven completion for hardware I/O queues
+//!
+//! Each hardware queue gets its own MSI-X vector. The vector only notes
+//! which queue fired; the driver's reap routine runs in the storage
+//! softirq that follows, with interrupts enabled, posts completions by
+//! command ID, and the waiting task is woken through the scheduler instead
+//! of polling the device. Each queue has its own lock, so CPUs submitting
+//! on different queues do not contend.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::StorageError;
+use crate::kernel::interrupts::{DYNAMIC_VECTOR_BASE, DYNAMIC_VECTOR_COUNT};
+use crate::kernel::msi::MsixEntry;
+use crate::kernel::softirq::{self, Softirq};
+
+/// Maximum number of hardware queues
+pub const MAX_HW_QUEUES: usize = 32;
+
+/// Outstanding commands per hardware queue
+pub const QUEUE_DEPTH: usize = 64;
+
+/// Driver routine that drains a queue's completion ring, calling [`complete`]
+pub type ReapFn = fn(queue: u16);
+
+/// State of one command slot
+#[derive(Clone, Copy)]
+enum CommandSlot {
+    Free,
+    /// Submitted; `task` sleeps until completion
+    Waiting { task: u32 },
+    Done(Result<usize, StorageError>),
+}
+
+/// Hardware submission/completion queue pair
+struct HwQueue {
+    device: u32,
+    vector: u8,
+    msix: MsixEntry,
+    reap: ReapFn,
+    /// Torn down; the slot is released once every waiter has its result
+    gone: bool,
+    slots: [CommandSlot; QUEUE_DEPTH],
+}
+
+impl HwQueue {
+    fn idle(&self) -> bool {
+        self.slots.iter().all(|slot| matches!(slot, CommandSlot::Free))
+    }
+}
+
+/// Hardware queues by ID, each behind its own lock
+static QUEUES: [Mutex<Option<HwQueue>>; MAX_HW_QUEUES] = [const { Mutex::new(None) }; MAX_HW_QUEUES];
+
+/// Run `f` on queue `queue`'s slot with interrupts disabled, as the
+/// softirq takes the same lock
+fn with_queue<R>(queue: u16, f: impl FnOnce(&mut Option<HwQueue>) -> R) -> Option<R> {
+    let entry = QUEUES.get(queue as usize)?;
+    Some(interrupts::without_interrupts(|| f(&mut entry.lock())))
+}
+
+/// Vectors that fired since the softirq last ran, a bit each from
+/// [`DYNAMIC_VECTOR_BASE`]
+static FIRED: AtomicU32 = AtomicU32::new(0);
+
+const _: () = assert!(DYNAMIC_VECTOR_COUNT <= 32);
+
+/// Completion interrupt: note the vector for the softirq
+fn queue_interrupt(vector: u8) {
+    FIRED.fetch_or(1 << (vector - DYNAMIC_VECTOR_BASE), Ordering::Release);
+    softirq::raise(Softirq::Storage);
+}
+
+/// Storage softirq: let the drivers of the queues that fired reap them
+fn reap_fired() {
+    let fired = FIRED.swap(0, Ordering::Acquire);
+    for index in (0..DYNAMIC_VECTOR_COUNT).filter(|i| fired & 1 << i != 0) {
+        let vector = DYNAMIC_VECTOR_BASE + index as u8;
+        let found = (0..MAX_HW_QUEUES as u16).find_map(|id| {
+            with_queue(id, |q| q.as_ref().filter(|q| q.vector == vector && !q.gone).map(|q| (id, q.reap))).flatten()
+        });
+        if let Some((queue, reap)) = found {
+            reap(queue);
+        }
+    }
+}
+
+/// Reap completions from the storage softirq
+pub fn init() {
+    softirq::open(Softirq::Storage, reap_fired);
+}
+
+/// Register a hardware queue and route its MSI-X entry to a fresh vector
+pub fn register_queue(device: u32, msix: MsixEntry, reap: ReapFn) -> Result<u16, StorageError> {
+    let vector = crate::kernel::interrupts::allocate_vector(queue_interrupt)
+        .map_err(|_| StorageError::NoInterruptVector)?;
+
+    let queue = (0..MAX_HW_QUEUES as u16)
+        .find(|&id| {
+            with_queue(id, |slot| {
+                if slot.is_some() {
+                    return false;
+                }
+                *slot = Some(HwQueue {
+                    device,
+                    vector,
+                    msix,
+                    reap,
+                    gone: false,
+                    slots: [CommandSlot::Free; QUEUE_DEPTH],
+                });
+                true
+            })
+            .unwrap_or(false)
+        })
+        .ok_or(StorageError::TooManyQueues);
+
+    match queue {
+        Ok(queue) => {
+            msix.program(vector, crate::kernel::apic::id());
+            Ok(queue)
+        }
+        Err(e) => {
+            let _ = crate::kernel::interrupts::free_vector(vector);
+            Err(e)
+        }
+    }
+}
+
+/// Tear down a hardware queue, failing any outstanding commands with `DeviceGone`
+pub fn unregister_queue(queue: u16) -> Result<(), StorageError> {
+    let (msix, vector) = with_queue(queue, |entry| {
+        let q = entry
+            .as_mut()
+            .filter(|q| !q.gone)
+            .ok_or(StorageError::InvalidQueue)?;
+
+        q.gone = true;
+        for slot in q.slots.iter_mut() {
+            if let CommandSlot::Waiting { task } = *slot {
+                *slot = CommandSlot::Done(Err(StorageError::DeviceGone));
+                let _ = crate::scheduler::wake(task);
+            }
+        }
+
+        let hw = (q.msix, q.vector);
+        if q.idle() {
+            *entry = None;
+        }
+        Ok::<_, StorageError>(hw)
+    })
+    .ok_or(StorageError::InvalidQueue)??;
+
+    unsafe { msix.set_masked(true) };
+    let _ = crate::kernel::interrupts::free_vector(vector);
+    Ok(())
+}
+
+/// Device a queue belongs to
+pub fn queue_device(queue: u16) -> Option<u32> {
+    with_queue(queue, |q| q.as_ref().map(|q| q.device)).flatten()
+}
+
+/// Reserve a command ID on behalf of the current task before submitting
+pub fn begin_command(queue: u16) -> Result<u16, StorageError> {
+    let task = crate::scheduler::current_task_id();
+
+    with_queue(queue, |entry| {
+        let q = entry.as_mut().ok_or(StorageError::InvalidQueue)?;
+        if q.gone {
+            return Err(StorageError::DeviceGone);
+        }
+
+        let (cid, slot) = q
+            .slots
+            .iter_mut()
+            .enumerate()
+            .find(|(_, slot)| matches!(slot, CommandSlot::Free))
+            .ok_or(StorageError::QueueFull)?;
+
+        *slot = CommandSlot::Waiting { task };
+        Ok(cid as u16)
+    })
+    .unwrap_or(Err(StorageError::InvalidQueue))
+}
+
+/// Release a command ID that never reached the device
+pub fn cancel(queue: u16, cid: u16) {
+    with_queue(queue, |entry| {
+        if let Some(q) = entry.as_mut() {
+            if let Some(slot) = q.slots.get_mut(cid as usize) {
+                *slot = CommandSlot::Free;
+            }
+            if q.gone && q.idle() {
+                *entry = None;
+            }
+        }
+    });
+}
+
+/// Post a command completion (called by the driver's reap routine)
+pub fn complete(queue: u16, cid: u16, result: Result<usize, StorageError>) {
+    with_queue(queue, |entry| {
+        let slot = match entry.as_mut().and_then(|q| q.slots.get_mut(cid as usize)) {
+            Some(slot) => slot,
+            None => return,
+        };
+
+        if let CommandSlot::Waiting { task } = *slot {
+            *slot = CommandSlot::Done(result);
+            let _ = crate::scheduler::wake(task);
+        }
+    });
+}
+
+/// Take the result of a finished command, freeing its slot
+fn take_result(queue: u16, cid: u16) -> Result<Option<Result<usize, StorageError>>, StorageError> {
+    let mut entry = QUEUES.get(queue as usize).ok_or(StorageError::InvalidQueue)?.lock();
+    let q = entry.as_mut().ok_or(StorageError::InvalidQueue)?;
+    let slot = q.slots.get_mut(cid as usize).ok_or(StorageError::InvalidQueue)?;
+
+    match *slot {
+        CommandSlot::Done(result) => {
+            *slot = CommandSlot::Free;
+            // Last waiter on a removed queue releases it
+            if q.gone && q.idle() {
+                *entry = None;
+            }
+            Ok(Some(result))
+        }
+        CommandSlot::Free => Err(StorageError::InvalidQueue),
+        CommandSlot::Waiting { .. } => Ok(None),
+    }
+}
+
+/// Sleep until a command completes and return its result
+pub fn wait(queue: u16, cid: u16) -> Result<usize, StorageError> {
+    loop {
+        interrupts::disable();
+        let done = take_result(queue, cid);
+        match done {
+            Ok(Some(result)) => {
+                interrupts::enable();
+                return result;
+            }
+            Err(e) => {
+                interrupts::enable();
+                return Err(e);
+            }
+            Ok(None) => {}
+        }
+
+        crate::scheduler::block_current();
+        // sti; hlt is atomic, so the completion interrupt cannot slip in before we sleep
+        interrupts::enable_and_hlt();
+    }
+}
@@ -0,0 +1,178 @@
+//! LZ4 block-format codec
+
+use super::StorageError;
+
+/// Minimum match length encoded by a sequence
+const MIN_MATCH: usize = 4;
+/// Last match must start at least this many bytes before the end of input
+const MFLIMIT: usize = 12;
+/// The final bytes of a block are always emitted as literals
+const LAST_LITERALS: usize = 5;
+/// Maximum back-reference distance
+const MAX_DISTANCE: usize = 65535;
+/// log2 of the match-finder hash table size
+const HASH_LOG: u32 = 10;
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+/// Bounds-checked output cursor
+struct Writer<'a> {
+    out: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn push(&mut self, byte: u8) -> Result<(), StorageError> {
+        let slot = self.out.get_mut(self.pos).ok_or(StorageError::CompressionFailed)?;
+        *slot = byte;
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn extend(&mut self, bytes: &[u8]) -> Result<(), StorageError> {
+        let end = self.pos + bytes.len();
+        if end > self.out.len() {
+            return Err(StorageError::CompressionFailed);
+        }
+        self.out[self.pos..end].copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+
+    /// Emit the 255-run extension of a length field
+    fn length(&mut self, mut n: usize) -> Result<(), StorageError> {
+        while n >= 255 {
+            self.push(255)?;
+            n -= 255;
+        }
+        self.push(n as u8)
+    }
+
+    fn sequence(&mut self, literals: &[u8], match_info: Option<(u16, usize)>) -> Result<(), StorageError> {
+        let lit_len = literals.len();
+        let match_code = match_info.map_or(0, |(_, len)| len - MIN_MATCH);
+
+        let token = ((lit_len.min(15) as u8) << 4) | match_code.min(15) as u8;
+        self.push(token)?;
+        if lit_len >= 15 {
+            self.length(lit_len - 15)?;
+        }
+        self.extend(literals)?;
+
+        if let Some((offset, _)) = match_info {
+            self.extend(&offset.to_le_bytes())?;
+            if match_code >= 15 {
+                self.length(match_code - 15)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compress `input` into `output`, returning the compressed length
+///
+/// Fails with `CompressionFailed` if the result does not fit in `output`.
+pub fn compress(input: &[u8], output: &mut [u8]) -> Result<usize, StorageError> {
+    let mut table = [0u32; 1 << HASH_LOG];
+    let mut writer = Writer { out: output, pos: 0 };
+    let mut anchor = 0;
+    let mut pos = 0;
+
+    if input.len() > MFLIMIT {
+        let match_limit = input.len() - MFLIMIT;
+        let extend_limit = input.len() - LAST_LITERALS;
+
+        while pos < match_limit {
+            let sequence = read_u32(input, pos);
+            let h = hash(sequence);
+            let candidate = table[h] as usize;
+            table[h] = pos as u32;
+
+            if candidate < pos && pos - candidate <= MAX_DISTANCE && read_u32(input, candidate) == sequence {
+                let mut len = MIN_MATCH;
+                while pos + len < extend_limit && input[candidate + len] == input[pos + len] {
+                    len += 1;
+                }
+
+                writer.sequence(&input[anchor..pos], Some(((pos - candidate) as u16, len)))?;
+                pos += len;
+                anchor = pos;
+            } else {
+                pos += 1;
+            }
+        }
+    }
+
+    writer.sequence(&input[anchor..], None)?;
+    Ok(writer.pos)
+}
+
+/// Read a 255-run length extension
+fn read_length(input: &[u8], pos: &mut usize) -> Result<usize, StorageError> {
+    let mut total = 0;
+    loop {
+        let byte = *input.get(*pos).ok_or(StorageError::CorruptData)?;
+        *pos += 1;
+        total += byte as usize;
+        if byte != 255 {
+            return Ok(total);
+        }
+    }
+}
+
+/// Decompress a complete LZ4 block into `output`, returning the decompressed length
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Result<usize, StorageError> {
+    let mut i = 0;
+    let mut o = 0;
+
+    loop {
+        let token = *input.get(i).ok_or(StorageError::CorruptData)?;
+        i += 1;
+
+        let mut lit_len = (token >> 4) as usize;
+        if lit_len == 15 {
+            lit_len += read_length(input, &mut i)?;
+        }
+        if i + lit_len > input.len() || o + lit_len > output.len() {
+            return Err(StorageError::CorruptData);
+        }
+        output[o..o + lit_len].copy_from_slice(&input[i..i + lit_len]);
+        i += lit_len;
+        o += lit_len;
+
+        // The last sequence carries literals only
+        if i == input.len() {
+            return Ok(o);
+        }
+
+        if i + 2 > input.len() {
+            return Err(StorageError::CorruptData);
+        }
+        let offset = u16::from_le_bytes([input[i], input[i + 1]]) as usize;
+        i += 2;
+        if offset == 0 || offset > o {
+            return Err(StorageError::CorruptData);
+        }
+
+        let mut match_len = (token & 0x0F) as usize;
+        if match_len == 15 {
+            match_len += read_length(input, &mut i)?;
+        }
+        match_len += MIN_MATCH;
+        if o + match_len > output.len() {
+            return Err(StorageError::CorruptData);
+        }
+
+        // Byte-wise copy: source and destination may overlap
+        for k in 0..match_len {
+            output[o + k] = output[o + k - offset];
+        }
+        o += match_len;
+    }
+}
@@ -1,28 +1,282 @@
 //! Storage subsystem - NVMe, DMA, RAID, compression
 
+pub mod aio;
+pub mod block;
+pub mod cache;
+pub mod discard;
+pub mod extent;
+pub mod hotplug;
+pub mod iosched;
+pub mod irq;
+pub mod lz4;
+pub mod mq;
+pub mod ramdisk;
+pub mod smart;
+pub mod stats;
+
+use spin::Mutex;
+
+use aio::IoOp;
+pub use block::{BlockDevice, DeviceInfo};
+
+/// Maximum number of storage devices
+pub const MAX_DEVICES: usize = 16;
+
+/// Registered devices, indexed by device number
+static DEVICES: Mutex<[Option<&'static dyn BlockDevice>; MAX_DEVICES]> = Mutex::new([None; MAX_DEVICES]);
+
+/// Register a block device driver, returning its device number
+pub fn register(device: &'static dyn BlockDevice) -> Result<u32, StorageError> {
+    if device.block_size() as usize > block::MAX_BLOCK_SIZE || !device.block_size().is_power_of_two() {
+        return Err(StorageError::Unsupported);
+    }
+
+    let mut devices = DEVICES.lock();
+    let (index, slot) = devices
+        .iter_mut()
+        .enumerate()
+        .find(|(_, slot)| slot.is_none())
+        .ok_or(StorageError::TooManyDevices)?;
+
+    *slot = Some(device);
+    drop(devices);
+
+    hotplug::device_added(index as u32);
+    Ok(index as u32)
+}
+
+/// Release a device number after hot-removal
+pub(crate) fn unregister(device: u32) {
+    if let Some(slot) = DEVICES.lock().get_mut(device as usize) {
+        *slot = None;
+    }
+}
+
+/// Driver registered under a device number
+pub fn device(device: u32) -> Result<&'static dyn BlockDevice, StorageError> {
+    if hotplug::is_gone(device) {
+        return Err(StorageError::DeviceGone);
+    }
+    DEVICES
+        .lock()
+        .get(device as usize)
+        .copied()
+        .flatten()
+        .ok_or(StorageError::DeviceNotFound)
+}
+
+/// Describe a registered device
+pub fn info(device: u32) -> Result<DeviceInfo, StorageError> {
+    self::device(device).map(DeviceInfo::of)
+}
+
+/// Iterate over registered devices as `(device number, info)`
+pub fn devices() -> impl Iterator<Item = (u32, DeviceInfo)> {
+    (0..MAX_DEVICES as u32).filter_map(|id| info(id).ok().map(|info| (id, info)))
+}
+
 /// Initialize storage subsystem
 pub fn init() {
+    irq::init();
+
+    match ramdisk::init() {
+        Ok(Some(device)) => crate::klog!(Info, "RAM disk registered as device {}", device),
+        Ok(None) => {}
+        Err(e) => crate::klog!(Error, "RAM disk creation failed: {:?}", e),
+    }
+
     // TODO: Detect and initialize NVMe devices
     // TODO: Set up DMA descriptors
-    // TODO: Initialize per-CPU submission/completion queues
 }
 
 /// Read from storage
-pub fn read(_device: u32, _offset: u64, _buffer: &mut [u8]) -> Result<usize, StorageError> {
-    // TODO: Implement DMA-driven read
-    Ok(0)
+pub fn read(device: u32, offset: u64, buffer: &mut [u8]) -> Result<usize, StorageError> {
+    crate::trace!(StorageRead, device, offset, buffer.len());
+    extent::read(device, offset, buffer)
+}
+
+/// Write to storage (LZ4-compressed, raw fallback)
+pub fn write(device: u32, offset: u64, data: &[u8]) -> Result<usize, StorageError> {
+    crate::trace!(StorageWrite, device, offset, data.len());
+    extent::write(device, offset, data)
+}
+
+/// Ordering flags for a write
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct WriteFlags(u8);
+
+impl WriteFlags {
+    /// No ordering requirements
+    pub const NONE: Self = Self(0);
+    /// Flush the device's volatile write cache before the write
+    pub const PREFLUSH: Self = Self(1 << 0);
+    /// Force unit access: data is on stable media when the write completes
+    pub const FUA: Self = Self(1 << 1);
+    /// Pre-flush plus FUA, as used for journal commit records
+    pub const BARRIER: Self = Self(Self::PREFLUSH.0 | Self::FUA.0);
+
+    /// Whether all flags in `other` are set
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
 }
 
-/// Write to storage
-pub fn write(_device: u32, _offset: u64, _data: &[u8]) -> Result<usize, StorageError> {
-    // TODO: Implement DMA-driven write with compression
-    Ok(0)
+impl core::ops::BitOr for WriteFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Write with explicit ordering guarantees
+///
+/// A `PREFLUSH` write is not started until everything written before it is
+/// durable; a `FUA` write is durable when this returns. Devices without
+/// native FUA get it emulated with a post-write cache flush.
+pub fn write_ordered(device: u32, offset: u64, data: &[u8], flags: WriteFlags) -> Result<usize, StorageError> {
+    if flags.contains(WriteFlags::PREFLUSH) {
+        flush(device)?;
+    }
+
+    crate::trace!(StorageWrite, device, offset, data.len());
+    let written = extent::write(device, offset, data)?;
+
+    // The compression layer may split the data over several raw writes,
+    // so FUA is always completed with a flush
+    if flags.contains(WriteFlags::FUA) {
+        flush(device)?;
+    }
+
+    Ok(written)
+}
+
+/// Flush a device's volatile write cache
+pub fn flush(device: u32) -> Result<(), StorageError> {
+    crate::trace!(StorageFlush, device);
+    let dev = self::device(device)?;
+    accounted(device, stats::IoKind::Flush, || {
+        if mq::has_hw_queues(device) {
+            return queued(device, IoOp::Flush, 0, 0, 0);
+        }
+        dev.flush().map(|_| 0)
+    })?;
+    Ok(())
+}
+
+/// Discard a logical range so the device can reclaim its space
+pub fn discard(device: u32, offset: u64, length: u64) -> Result<(), StorageError> {
+    crate::trace!(StorageDiscard, device, offset, length);
+    extent::unmap(device, offset, length)
+}
+
+/// Run a driver command, recording it in the device statistics
+fn accounted(
+    device: u32,
+    kind: stats::IoKind,
+    op: impl FnOnce() -> Result<usize, StorageError>,
+) -> Result<usize, StorageError> {
+    let started = stats::start(device);
+    let result = op();
+    let bytes = *result.as_ref().unwrap_or(&0);
+    stats::finish(device, kind, bytes, started, result.is_ok());
+    result
+}
+
+/// Send a request to a device with hardware queues, sleeping until the
+/// queue's completion interrupt reports it done
+fn queued(device: u32, op: IoOp, offset: u64, buffer: u64, length: usize) -> Result<usize, StorageError> {
+    let length = u32::try_from(length).map_err(|_| StorageError::OutOfRange)?;
+    mq::execute(mq::BlockRequest {
+        op,
+        device,
+        offset,
+        buffer,
+        length,
+        flags: WriteFlags::NONE,
+    })
+}
+
+/// Read raw bytes from a device, bypassing the compression layer
+pub(crate) fn read_raw(device: u32, offset: u64, buffer: &mut [u8]) -> Result<usize, StorageError> {
+    let dev = self::device(device)?;
+    accounted(device, stats::IoKind::Read, || {
+        if mq::has_hw_queues(device) {
+            return queued(device, IoOp::Read, offset, buffer.as_mut_ptr() as u64, buffer.len());
+        }
+        block::read_bytes(dev, offset, buffer)
+    })
+}
+
+/// Write raw bytes to a device, bypassing the compression layer
+pub(crate) fn write_raw(device: u32, offset: u64, data: &[u8]) -> Result<usize, StorageError> {
+    let dev = self::device(device)?;
+    accounted(device, stats::IoKind::Write, || {
+        if mq::has_hw_queues(device) {
+            if dev.read_only() {
+                return Err(StorageError::ReadOnly);
+            }
+            return queued(device, IoOp::Write, offset, data.as_ptr() as u64, data.len());
+        }
+        block::write_bytes(dev, offset, data)
+    })
+}
+
+/// Issue a discard command for a batch of physical ranges
+pub(crate) fn discard_raw(device: u32, ranges: &[discard::DiscardRange]) -> Result<(), StorageError> {
+    let dev = self::device(device)?;
+    ranges.iter().try_for_each(|r| {
+        accounted(device, stats::IoKind::Discard, || {
+            block::discard_bytes(dev, r.offset, r.length).map(|_| 0)
+        })?;
+        Ok(())
+    })
+}
+
+/// Fetch the SMART/health log page from a device
+pub(crate) fn read_smart_log(
+    device: u32,
+    log: &mut [u8; smart::SMART_LOG_SIZE],
+) -> Result<smart::SmartLogFormat, StorageError> {
+    self::device(device)?.smart_log(log)
+}
+
+/// Periodic storage housekeeping, run from the idle loop
+pub fn poll() {
+    hotplug::poll();
+    smart::poll();
+    extent::prefetch();
+    discard::poll();
 }
 
 /// Storage errors
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StorageError {
     DeviceNotFound,
     IoError,
     CompressionFailed,
+    QueueFull,
+    InvalidRing,
+    TooManyRings,
+    InvalidBuffer,
+    CorruptData,
+    ExtentMapFull,
+    /// No physical space left for the data
+    NoSpace,
+    TooManyDevices,
+    OutOfMemory,
+    OutOfRange,
+    ReadOnly,
+    Unsupported,
+    NoInterruptVector,
+    TooManyQueues,
+    InvalidQueue,
+    DeviceGone,
+    DmaMappingFailed,
+}
+
+impl From<crate::kernel::dma::DmaError> for StorageError {
+    fn from(_: crate::kernel::dma::DmaError) -> Self {
+        StorageError::DmaMappingFailed
+    }
 }
@@ -0,0 +1,290 @@
+//! Multi-queue block submission
+//!
+//! Every CPU owns a software queue that only it touches, so staging requests
+//! takes no locks. Each software queue is mapped onto one hardware queue per
+//! device; dispatch locks only that hardware context, which is uncontended
+//! when the device has a queue per CPU (NVMe). Raw I/O to a device with
+//! hardware queues goes through [`execute`], which stages the request on
+//! its CPU's software queue and dispatches it from there.
+
+use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use heapless::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::aio::IoOp;
+use super::irq::{ReapFn, MAX_HW_QUEUES};
+use super::{StorageError, WriteFlags, MAX_DEVICES};
+use crate::kernel::msi::MsixEntry;
+use crate::kernel::percpu::{current_cpu_id, MAX_CPUS};
+
+/// Requests a software queue can stage before it must be dispatched
+pub const SW_QUEUE_DEPTH: usize = 32;
+
+/// CPU map entry for a device without hardware queues
+const NO_HW_QUEUE: u16 = u16::MAX;
+
+/// A request as handed to a hardware queue
+#[derive(Clone, Copy, Debug)]
+pub struct BlockRequest {
+    pub op: IoOp,
+    pub device: u32,
+    /// Byte offset on the device
+    pub offset: u64,
+    /// Kernel virtual address of the data buffer
+    pub buffer: u64,
+    pub length: u32,
+    pub flags: WriteFlags,
+}
+
+/// Handle for a dispatched request, passed to [`wait`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tag {
+    /// Hardware queue the request went to
+    pub queue: u16,
+    /// Command ID within that queue
+    pub cid: u16,
+}
+
+/// Driver routine that places a request on a hardware submission ring
+pub type QueueRqFn = fn(queue: u16, cid: u16, request: &BlockRequest) -> Result<(), StorageError>;
+
+/// Per-queue counters
+pub struct QueueStats {
+    submitted: AtomicU64,
+    dispatched: AtomicU64,
+    completed: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// Point-in-time copy of a queue's counters
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueueStatsSnapshot {
+    pub submitted: u64,
+    pub dispatched: u64,
+    pub completed: u64,
+    pub errors: u64,
+}
+
+impl QueueStats {
+    const fn new() -> Self {
+        Self {
+            submitted: AtomicU64::new(0),
+            dispatched: AtomicU64::new(0),
+            completed: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    fn snapshot(&self) -> QueueStatsSnapshot {
+        QueueStatsSnapshot {
+            submitted: self.submitted.load(Ordering::Relaxed),
+            dispatched: self.dispatched.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record_result(&self, result: &Result<usize, StorageError>) {
+        match result {
+            Ok(_) => self.completed.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.errors.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+}
+
+/// Software staging queue owned by one CPU
+struct SwQueue {
+    pending: Vec<BlockRequest, SW_QUEUE_DEPTH>,
+    stats: QueueStats,
+}
+
+impl SwQueue {
+    const fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+            stats: QueueStats::new(),
+        }
+    }
+}
+
+/// Hardware dispatch context, one per hardware queue
+struct HwContext {
+    device: u32,
+    queue_rq: QueueRqFn,
+}
+
+/// Software queues, indexed by CPU; each is only accessed by its own CPU
+static mut SW_QUEUES: [SwQueue; MAX_CPUS] = [const { SwQueue::new() }; MAX_CPUS];
+
+/// Hardware contexts, indexed by `irq` queue ID, each behind its own lock
+static HW_CONTEXTS: [Mutex<Option<HwContext>>; MAX_HW_QUEUES] = [const { Mutex::new(None) }; MAX_HW_QUEUES];
+
+/// Hardware queue statistics, indexed like `HW_CONTEXTS`
+static HW_STATS: [QueueStats; MAX_HW_QUEUES] = [const { QueueStats::new() }; MAX_HW_QUEUES];
+
+/// Hardware queue serving each (CPU, device) pair
+static CPU_MAP: [[AtomicU16; MAX_DEVICES]; MAX_CPUS] =
+    [const { [const { AtomicU16::new(NO_HW_QUEUE) }; MAX_DEVICES] }; MAX_CPUS];
+
+/// Run `f` on this CPU's software queue with interrupts off
+fn with_local<R>(f: impl FnOnce(&mut SwQueue) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let cpu = current_cpu_id() as usize;
+        unsafe { f(&mut *core::ptr::addr_of_mut!(SW_QUEUES[cpu])) }
+    })
+}
+
+/// Set up a hardware queue of a device, returning its ID: its MSI-X entry
+/// is routed to a vector of its own, whose completions `reap` drains, and
+/// `queue_rq` places requests on it
+pub fn add_hw_queue(device: u32, msix: MsixEntry, reap: ReapFn, queue_rq: QueueRqFn) -> Result<u16, StorageError> {
+    if device as usize >= MAX_DEVICES {
+        return Err(StorageError::DeviceNotFound);
+    }
+
+    let queue = super::irq::register_queue(device, msix, reap)?;
+    *HW_CONTEXTS[queue as usize].lock() = Some(HwContext { device, queue_rq });
+    map_queues(device);
+    Ok(queue)
+}
+
+/// Tear down a hardware queue, spreading its CPUs over the remaining ones
+/// and failing the commands still on it
+pub fn remove_hw_queue(queue: u16) -> Result<(), StorageError> {
+    let ctx = HW_CONTEXTS
+        .get(queue as usize)
+        .and_then(|ctx| ctx.lock().take())
+        .ok_or(StorageError::InvalidQueue)?;
+
+    map_queues(ctx.device);
+    super::irq::unregister_queue(queue)
+}
+
+/// Assign a device's hardware queues to CPUs round-robin
+fn map_queues(device: u32) {
+    let mut queues: Vec<u16, MAX_HW_QUEUES> = Vec::new();
+    for (id, ctx) in HW_CONTEXTS.iter().enumerate() {
+        if ctx.lock().as_ref().is_some_and(|ctx| ctx.device == device) {
+            let _ = queues.push(id as u16);
+        }
+    }
+
+    for (cpu, map) in CPU_MAP.iter().enumerate() {
+        let queue = if queues.is_empty() {
+            NO_HW_QUEUE
+        } else {
+            queues[cpu % queues.len()]
+        };
+        map[device as usize].store(queue, Ordering::Release);
+    }
+}
+
+/// Hardware queue this CPU dispatches to for `device`
+pub fn hw_queue_for(cpu: u32, device: u32) -> Option<u16> {
+    let queue = CPU_MAP
+        .get(cpu as usize)?
+        .get(device as usize)?
+        .load(Ordering::Acquire);
+    (queue != NO_HW_QUEUE).then_some(queue)
+}
+
+/// Whether a device is driven through hardware queues
+pub fn has_hw_queues(device: u32) -> bool {
+    hw_queue_for(current_cpu_id(), device).is_some()
+}
+
+/// Stage a request on this CPU's software queue
+fn submit(request: BlockRequest) -> Result<(), StorageError> {
+    hw_queue_for(current_cpu_id(), request.device).ok_or(StorageError::DeviceNotFound)?;
+
+    with_local(|sw| {
+        sw.pending.push(request).map_err(|_| StorageError::QueueFull)?;
+        sw.stats.submitted.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    })
+}
+
+/// Hand one request to its hardware queue
+fn dispatch(request: &BlockRequest) -> Result<Tag, StorageError> {
+    let queue = hw_queue_for(current_cpu_id(), request.device).ok_or(StorageError::DeviceNotFound)?;
+
+    let ctx = HW_CONTEXTS[queue as usize].lock();
+    let queue_rq = ctx.as_ref().ok_or(StorageError::InvalidQueue)?.queue_rq;
+
+    let cid = super::irq::begin_command(queue)?;
+    if let Err(e) = queue_rq(queue, cid, request) {
+        super::irq::cancel(queue, cid);
+        return Err(e);
+    }
+
+    HW_STATS[queue as usize].dispatched.fetch_add(1, Ordering::Relaxed);
+    Ok(Tag { queue, cid })
+}
+
+/// Dispatch everything staged on this CPU, in submission order
+///
+/// `tags[i]` receives the tag of the i-th staged request. Returns the number
+/// of requests dispatched; dispatch stops at the first driver error and the
+/// rest stay staged.
+fn commit(tags: &mut [Tag]) -> Result<usize, StorageError> {
+    with_local(|sw| {
+        let count = sw.pending.len().min(tags.len());
+        let mut dispatched = 0;
+        let mut result = Ok(());
+
+        for (request, tag) in sw.pending[..count].iter().zip(tags.iter_mut()) {
+            match dispatch(request) {
+                Ok(t) => {
+                    *tag = t;
+                    dispatched += 1;
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        sw.pending.rotate_left(dispatched);
+        sw.pending.truncate(sw.pending.len() - dispatched);
+        sw.stats.dispatched.fetch_add(dispatched as u64, Ordering::Relaxed);
+
+        result.map(|_| dispatched)
+    })
+}
+
+/// Sleep until a dispatched request completes
+pub fn wait(tag: Tag) -> Result<usize, StorageError> {
+    let result = super::irq::wait(tag.queue, tag.cid);
+    HW_STATS[tag.queue as usize].record_result(&result);
+    with_local(|sw| sw.stats.record_result(&result));
+    result
+}
+
+/// Stage a request on this CPU's software queue, dispatch it and wait
+/// for it
+pub fn execute(request: BlockRequest) -> Result<usize, StorageError> {
+    let mut tag = [Tag { queue: NO_HW_QUEUE, cid: 0 }];
+    // With interrupts off nothing else stages on this CPU in between, so
+    // the request is the only one staged and the one dispatched
+    interrupts::without_interrupts(|| {
+        submit(request)?;
+        commit(&mut tag).inspect_err(|_| with_local(|sw| sw.pending.clear()))
+    })?;
+    wait(tag[0])
+}
+
+/// Counters of a CPU's software queue
+pub fn sw_queue_stats(cpu: u32) -> Option<QueueStatsSnapshot> {
+    if cpu as usize >= MAX_CPUS {
+        return None;
+    }
+    let sw = unsafe { &*core::ptr::addr_of!(SW_QUEUES[cpu as usize]) };
+    Some(sw.stats.snapshot())
+}
+
+/// Counters of a hardware queue
+pub fn hw_queue_stats(queue: u16) -> Option<QueueStatsSnapshot> {
+    HW_STATS.get(queue as usize).map(QueueStats::snapshot)
+}
@@ -0,0 +1,157 @@
+//! RAM-backed block device
+
+use spin::Once;
+
+use super::{BlockDevice, StorageError};
+
+/// Default RAM disk size when `ramdisk=` is not given on the command line
+pub const DEFAULT_RAMDISK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// RAM disk block size
+pub const RAMDISK_BLOCK_SIZE: u32 = 512;
+
+/// Maximum number of RAM disks
+pub const MAX_RAM_DISKS: usize = 4;
+
+const NAMES: [&str; MAX_RAM_DISKS] = ["ram0", "ram1", "ram2", "ram3"];
+
+/// A block device backed by kernel memory
+#[derive(Clone, Copy)]
+pub struct RamDisk {
+    /// Kernel virtual address of the backing memory
+    base: u64,
+    /// Size in bytes
+    size: u64,
+    /// Reject writes (e.g. an exposed initrd)
+    read_only: bool,
+}
+
+impl RamDisk {
+    /// Allocate a new zeroed RAM disk
+    pub fn create(size: u64) -> Result<Self, StorageError> {
+        let base = crate::kernel::memory::allocate_region(size as usize)
+            .map_err(|_| StorageError::OutOfMemory)?;
+
+        Ok(Self {
+            base: base.as_u64(),
+            size,
+            read_only: false,
+        })
+    }
+
+    /// Wrap an existing memory range, such as the initrd image
+    ///
+    /// # Safety
+    /// `base..base + size` must be mapped and stay valid for the kernel's lifetime.
+    pub unsafe fn attach(base: u64, size: u64, read_only: bool) -> Self {
+        Self { base, size, read_only }
+    }
+
+    /// Capacity in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn check_range(&self, offset: u64, len: usize) -> Result<(), StorageError> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(StorageError::OutOfRange),
+        }
+    }
+
+    pub fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, StorageError> {
+        self.check_range(offset, buffer.len())?;
+        unsafe {
+            let src = (self.base + offset) as *const u8;
+            core::ptr::copy_nonoverlapping(src, buffer.as_mut_ptr(), buffer.len());
+        }
+        Ok(buffer.len())
+    }
+
+    pub fn write(&self, offset: u64, data: &[u8]) -> Result<usize, StorageError> {
+        if self.read_only {
+            return Err(StorageError::ReadOnly);
+        }
+        self.check_range(offset, data.len())?;
+        unsafe {
+            let dst = (self.base + offset) as *mut u8;
+            core::ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len());
+        }
+        Ok(data.len())
+    }
+
+    /// Zero a range so discarded blocks read back as zeroes
+    pub fn discard(&self, offset: u64, length: u64) -> Result<(), StorageError> {
+        if self.read_only {
+            return Err(StorageError::ReadOnly);
+        }
+        self.check_range(offset, length as usize)?;
+        unsafe {
+            core::ptr::write_bytes((self.base + offset) as *mut u8, 0, length as usize);
+        }
+        Ok(())
+    }
+}
+
+/// A RAM disk with its name, as registered with the storage layer
+struct RamBlockDevice {
+    name: &'static str,
+    disk: RamDisk,
+}
+
+impl BlockDevice for RamBlockDevice {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn block_size(&self) -> u32 {
+        RAMDISK_BLOCK_SIZE
+    }
+
+    fn capacity(&self) -> u64 {
+        self.disk.size / RAMDISK_BLOCK_SIZE as u64
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<usize, StorageError> {
+        self.disk.read(lba * RAMDISK_BLOCK_SIZE as u64, buffer)
+    }
+
+    fn write_blocks(&self, lba: u64, data: &[u8]) -> Result<usize, StorageError> {
+        self.disk.write(lba * RAMDISK_BLOCK_SIZE as u64, data)
+    }
+
+    fn discard(&self, lba: u64, blocks: u64) -> Result<(), StorageError> {
+        let bs = RAMDISK_BLOCK_SIZE as u64;
+        self.disk.discard(lba * bs, blocks * bs)
+    }
+
+    fn read_only(&self) -> bool {
+        self.disk.read_only
+    }
+}
+
+/// Backing storage for registered RAM disks
+static RAM_DISKS: [Once<RamBlockDevice>; MAX_RAM_DISKS] = [const { Once::new() }; MAX_RAM_DISKS];
+
+/// Register a RAM disk with the storage layer, returning its device number
+pub fn register(disk: RamDisk) -> Result<u32, StorageError> {
+    let (index, slot) = RAM_DISKS
+        .iter()
+        .enumerate()
+        .find(|(_, slot)| !slot.is_completed())
+        .ok_or(StorageError::TooManyDevices)?;
+
+    let device = slot.call_once(|| RamBlockDevice { name: NAMES[index], disk });
+    super::register(device)
+}
+
+/// Create the boot RAM disk sized by the `ramdisk=` option (`ramdisk=0` disables it)
+pub fn init() -> Result<Option<u32>, StorageError> {
+    let size = crate::boot::cmdline::size("ramdisk").unwrap_or(DEFAULT_RAMDISK_SIZE);
+    if size == 0 {
+        return Ok(None);
+    }
+
+    let disk = RamDisk::create(size)?;
+    register(disk).map(Some)
+}
@@ -0,0 +1,257 @@
+//! SMART health monitoring (NVMe SMART/Health log, ATA SMART attributes)
+
+use spin::Mutex;
+
+use super::{StorageError, MAX_DEVICES};
+
+/// Size of a SMART log page
+pub const SMART_LOG_SIZE: usize = 512;
+
+/// Ticks between health polls
+pub const SMART_POLL_INTERVAL_TICKS: u64 = 6000;
+
+/// IPC message type for threshold events
+pub const HEALTH_EVENT_MSG: u32 = 0x534D_0001;
+
+/// Threshold flags carried in a health event
+pub const HEALTH_TEMPERATURE: u32 = 1 << 0;
+pub const HEALTH_WEAR: u32 = 1 << 1;
+pub const HEALTH_MEDIA_ERRORS: u32 = 1 << 2;
+pub const HEALTH_CRITICAL_WARNING: u32 = 1 << 3;
+
+/// Format of a raw SMART log returned by a driver
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SmartLogFormat {
+    /// NVMe Get Log Page 02h
+    Nvme,
+    /// ATA SMART READ DATA
+    Ata,
+}
+
+/// Decoded device health counters
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HealthInfo {
+    /// Composite temperature in degrees Celsius
+    pub temperature_c: i16,
+    /// Estimated life used (percent, may exceed 100)
+    pub percent_used: u8,
+    /// Remaining spare capacity (percent)
+    pub available_spare: u8,
+    /// Media and data integrity errors
+    pub media_errors: u64,
+    pub power_on_hours: u64,
+    pub unsafe_shutdowns: u64,
+    /// NVMe critical warning bits (0 for ATA)
+    pub critical_warning: u8,
+}
+
+/// Limits that trigger a health event
+#[derive(Clone, Copy, Debug)]
+pub struct HealthThresholds {
+    pub max_temperature_c: i16,
+    pub max_percent_used: u8,
+    pub max_media_errors: u64,
+}
+
+impl HealthThresholds {
+    pub const fn new() -> Self {
+        Self {
+            max_temperature_c: 70,
+            max_percent_used: 90,
+            max_media_errors: 0,
+        }
+    }
+
+    /// Flags for every threshold the reading exceeds
+    fn exceeded(&self, info: &HealthInfo) -> u32 {
+        let mut flags = 0;
+        if info.temperature_c > self.max_temperature_c {
+            flags |= HEALTH_TEMPERATURE;
+        }
+        if info.percent_used > self.max_percent_used {
+            flags |= HEALTH_WEAR;
+        }
+        if info.media_errors > self.max_media_errors {
+            flags |= HEALTH_MEDIA_ERRORS;
+        }
+        if info.critical_warning != 0 {
+            flags |= HEALTH_CRITICAL_WARNING;
+        }
+        flags
+    }
+}
+
+fn le_u64(log: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&log[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Decode an NVMe SMART/Health Information log page
+pub fn parse_nvme_log(log: &[u8; SMART_LOG_SIZE]) -> HealthInfo {
+    let kelvin = u16::from_le_bytes([log[1], log[2]]);
+
+    // 128-bit counters: the low 64 bits are plenty
+    HealthInfo {
+        temperature_c: kelvin as i16 - 273,
+        percent_used: log[5],
+        available_spare: log[3],
+        media_errors: le_u64(log, 160),
+        power_on_hours: le_u64(log, 128),
+        unsafe_shutdowns: le_u64(log, 144),
+        critical_warning: log[0],
+    }
+}
+
+/// ATA SMART attribute IDs
+const ATA_REALLOCATED_SECTORS: u8 = 5;
+const ATA_POWER_ON_HOURS: u8 = 9;
+const ATA_WEAR_LEVELING: u8 = 177;
+const ATA_UNSAFE_SHUTDOWNS: u8 = 192;
+const ATA_TEMPERATURE: u8 = 194;
+const ATA_UNCORRECTABLE: u8 = 198;
+const ATA_MEDIA_WEAROUT: u8 = 233;
+
+/// Attribute table layout within SMART READ DATA
+const ATA_ATTR_OFFSET: usize = 2;
+const ATA_ATTR_SIZE: usize = 12;
+const ATA_ATTR_COUNT: usize = 30;
+
+/// Decode ATA SMART READ DATA attributes
+pub fn parse_ata_log(log: &[u8; SMART_LOG_SIZE]) -> HealthInfo {
+    let mut info = HealthInfo {
+        available_spare: 100,
+        ..HealthInfo::default()
+    };
+
+    for i in 0..ATA_ATTR_COUNT {
+        let attr = &log[ATA_ATTR_OFFSET + i * ATA_ATTR_SIZE..][..ATA_ATTR_SIZE];
+        let id = attr[0];
+        let normalized = attr[3];
+        let mut raw_bytes = [0u8; 8];
+        raw_bytes[..6].copy_from_slice(&attr[5..11]);
+        let raw = u64::from_le_bytes(raw_bytes);
+
+        match id {
+            0 => continue,
+            ATA_TEMPERATURE => info.temperature_c = (raw & 0xFF) as i16,
+            ATA_POWER_ON_HOURS => info.power_on_hours = raw & 0xFFFF_FFFF,
+            ATA_UNSAFE_SHUTDOWNS => info.unsafe_shutdowns = raw,
+            ATA_REALLOCATED_SECTORS | ATA_UNCORRECTABLE => info.media_errors += raw,
+            // Normalized value counts down from 100 as the flash wears
+            ATA_WEAR_LEVELING | ATA_MEDIA_WEAROUT => {
+                info.percent_used = 100u8.saturating_sub(normalized);
+            }
+            _ => {}
+        }
+    }
+
+    info
+}
+
+/// Per-device monitoring state
+#[derive(Clone, Copy)]
+struct DeviceHealth {
+    latest: Option<HealthInfo>,
+    /// Flags reported in the last event (events fire on new flags only)
+    reported: u32,
+}
+
+struct Monitor {
+    devices: [DeviceHealth; MAX_DEVICES],
+    thresholds: HealthThresholds,
+    event_channel: Option<u64>,
+    last_poll: u64,
+}
+
+static MONITOR: Mutex<Monitor> = Mutex::new(Monitor {
+    devices: [DeviceHealth { latest: None, reported: 0 }; MAX_DEVICES],
+    thresholds: HealthThresholds::new(),
+    event_channel: None,
+    last_poll: 0,
+});
+
+/// Send a threshold event to the subscribed channel
+fn send_event(channel: u64, device: u32, flags: u32) {
+    let mut payload = [0u8; 8];
+    payload[..4].copy_from_slice(&device.to_le_bytes());
+    payload[4..].copy_from_slice(&flags.to_le_bytes());
+
+    let header = crate::ipc::MessageHeader {
+        id: 0,
+        sender: 0, // kernel
+        receiver: 0,
+        length: payload.len() as u32,
+        msg_type: HEALTH_EVENT_MSG,
+    };
+    let _ = crate::ipc::msg_send(channel, header, &payload);
+}
+
+/// Query one device and record its health
+pub fn refresh(device: u32) -> Result<HealthInfo, StorageError> {
+    if device as usize >= MAX_DEVICES {
+        return Err(StorageError::DeviceNotFound);
+    }
+
+    let mut log = [0u8; SMART_LOG_SIZE];
+    let info = match super::read_smart_log(device, &mut log)? {
+        SmartLogFormat::Nvme => parse_nvme_log(&log),
+        SmartLogFormat::Ata => parse_ata_log(&log),
+    };
+
+    let mut monitor = MONITOR.lock();
+    let flags = monitor.thresholds.exceeded(&info);
+    let channel = monitor.event_channel;
+    let state = &mut monitor.devices[device as usize];
+
+    state.latest = Some(info);
+    let new_flags = flags & !state.reported;
+    state.reported = flags;
+
+    if let Some(channel) = channel {
+        if new_flags != 0 {
+            send_event(channel, device, flags);
+        }
+    }
+
+    Ok(info)
+}
+
+/// Poll every device if the poll interval has elapsed
+pub fn poll() {
+    let now = crate::scheduler::ticks();
+    {
+        let mut monitor = MONITOR.lock();
+        if now.saturating_sub(monitor.last_poll) < SMART_POLL_INTERVAL_TICKS {
+            return;
+        }
+        monitor.last_poll = now;
+    }
+
+    for device in 0..MAX_DEVICES as u32 {
+        // Devices without SMART support simply report an error
+        let _ = refresh(device);
+    }
+}
+
+/// Latest health reading for a device
+pub fn health(device: u32) -> Option<HealthInfo> {
+    MONITOR.lock().devices.get(device as usize)?.latest
+}
+
+/// Replace the alert thresholds
+pub fn set_thresholds(thresholds: HealthThresholds) {
+    MONITOR.lock().thresholds = thresholds;
+}
+
+/// Subscribe an IPC channel to threshold events
+pub fn subscribe(channel: u64) {
+    MONITOR.lock().event_channel = Some(channel);
+}
+
+/// Clear the recorded health of a removed device
+pub fn forget_device(device: u32) {
+    if let Some(state) = MONITOR.lock().devices.get_mut(device as usize) {
+        *state = DeviceHealth { latest: None, reported: 0 };
+    }
+}
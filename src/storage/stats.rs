@@ -0,0 +1,234 @@
+//! Per-device I/O statistics
+//!
+//! Every command that reaches a driver is counted here: operations, bytes,
+//! errors, queue depth and a log2 latency histogram in nanoseconds. Take two
+//! [`DeviceStats`] snapshots to get IOPS and throughput over an interval.
+//!
+//! Latency is timed with the TSC and converted with the frequency measured
+//! at boot; without an invariant TSC there is no such frequency and latency
+//! is not recorded.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::MAX_DEVICES;
+
+/// Latency histogram buckets; bucket `i` holds latencies below `2^i` nanoseconds
+pub const LATENCY_BUCKETS: usize = 40;
+
+/// Kind of device command being accounted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoKind {
+    Read,
+    Write,
+    Flush,
+    Discard,
+}
+
+struct DeviceCounters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    flushes: AtomicU64,
+    discards: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    errors: AtomicU64,
+    in_flight: AtomicU64,
+    max_in_flight: AtomicU64,
+    latency: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl DeviceCounters {
+    const fn new() -> Self {
+        Self {
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            flushes: AtomicU64::new(0),
+            discards: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            in_flight: AtomicU64::new(0),
+            max_in_flight: AtomicU64::new(0),
+            latency: [const { AtomicU64::new(0) }; LATENCY_BUCKETS],
+        }
+    }
+}
+
+static COUNTERS: [DeviceCounters; MAX_DEVICES] = [const { DeviceCounters::new() }; MAX_DEVICES];
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Mark a command as issued, returning its start timestamp
+pub(crate) fn start(device: u32) -> u64 {
+    if let Some(c) = COUNTERS.get(device as usize) {
+        let depth = c.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        c.max_in_flight.fetch_max(depth, Ordering::Relaxed);
+    }
+    rdtsc()
+}
+
+/// Account a finished command started at `started`
+pub(crate) fn finish(device: u32, kind: IoKind, bytes: usize, started: u64, ok: bool) {
+    let c = match COUNTERS.get(device as usize) {
+        Some(c) => c,
+        None => return,
+    };
+
+    c.in_flight.fetch_sub(1, Ordering::Relaxed);
+    if !ok {
+        c.errors.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    match kind {
+        IoKind::Read => {
+            c.reads.fetch_add(1, Ordering::Relaxed);
+            c.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+        IoKind::Write => {
+            c.writes.fetch_add(1, Ordering::Relaxed);
+            c.bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+        IoKind::Flush => {
+            c.flushes.fetch_add(1, Ordering::Relaxed);
+        }
+        IoKind::Discard => {
+            c.discards.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let Some(frequency) = crate::kernel::clocksource::tsc_frequency() else {
+        return;
+    };
+    let cycles = rdtsc().saturating_sub(started);
+    let nanoseconds = (cycles as u128 * 1_000_000_000 / frequency as u128) as u64;
+    let bucket = ((u64::BITS - nanoseconds.leading_zeros()) as usize).min(LATENCY_BUCKETS - 1);
+    c.latency[bucket].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Point-in-time copy of a device's counters
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceStats {
+    /// Nanoseconds since boot at which the snapshot was taken
+    pub nanoseconds: u64,
+    pub reads: u64,
+    pub writes: u64,
+    pub flushes: u64,
+    pub discards: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub errors: u64,
+    /// Commands currently issued to the driver
+    pub in_flight: u64,
+    /// Highest queue depth seen
+    pub max_in_flight: u64,
+    pub latency: [u64; LATENCY_BUCKETS],
+}
+
+impl DeviceStats {
+    /// Completed operations of any kind
+    pub fn ops(&self) -> u64 {
+        self.reads + self.writes + self.flushes + self.discards
+    }
+
+    /// Upper bound, in nanoseconds, of the latency below which `percent`% of commands finished
+    pub fn latency_percentile(&self, percent: u64) -> u64 {
+        let total: u64 = self.latency.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+
+        let target = (total * percent).div_ceil(100);
+        let mut seen = 0;
+        for (bucket, count) in self.latency.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return 1 << bucket;
+            }
+        }
+        1 << (LATENCY_BUCKETS - 1)
+    }
+
+    /// Median latency in nanoseconds
+    pub fn p50(&self) -> u64 {
+        self.latency_percentile(50)
+    }
+
+    /// 99th percentile latency in nanoseconds
+    pub fn p99(&self) -> u64 {
+        self.latency_percentile(99)
+    }
+
+    /// Operations per second since an earlier snapshot; zero if the
+    /// counters were reset in between
+    pub fn iops_since(&self, earlier: &DeviceStats) -> u64 {
+        per_second(self.ops().saturating_sub(earlier.ops()), self.elapsed_since(earlier))
+    }
+
+    /// Bytes transferred per second since an earlier snapshot; zero if the
+    /// counters were reset in between
+    pub fn throughput_since(&self, earlier: &DeviceStats) -> u64 {
+        let bytes = (self.bytes_read + self.bytes_written).saturating_sub(earlier.bytes_read + earlier.bytes_written);
+        per_second(bytes, self.elapsed_since(earlier))
+    }
+
+    fn elapsed_since(&self, earlier: &DeviceStats) -> u64 {
+        self.nanoseconds.saturating_sub(earlier.nanoseconds)
+    }
+}
+
+fn per_second(count: u64, nanoseconds: u64) -> u64 {
+    if nanoseconds == 0 {
+        return 0;
+    }
+    (count as u128 * 1_000_000_000 / nanoseconds as u128) as u64
+}
+
+/// Snapshot a device's counters
+pub fn device_stats(device: u32) -> Option<DeviceStats> {
+    let c = COUNTERS.get(device as usize)?;
+    let mut latency = [0; LATENCY_BUCKETS];
+    for (dst, src) in latency.iter_mut().zip(c.latency.iter()) {
+        *dst = src.load(Ordering::Relaxed);
+    }
+
+    Some(DeviceStats {
+        nanoseconds: crate::kernel::clocksource::nanoseconds(),
+        reads: c.reads.load(Ordering::Relaxed),
+        writes: c.writes.load(Ordering::Relaxed),
+        flushes: c.flushes.load(Ordering::Relaxed),
+        discards: c.discards.load(Ordering::Relaxed),
+        bytes_read: c.bytes_read.load(Ordering::Relaxed),
+        bytes_written: c.bytes_written.load(Ordering::Relaxed),
+        errors: c.errors.load(Ordering::Relaxed),
+        in_flight: c.in_flight.load(Ordering::Relaxed),
+        max_in_flight: c.max_in_flight.load(Ordering::Relaxed),
+        latency,
+    })
+}
+
+/// Clear a device's counters (queue depth in flight is kept)
+pub fn reset(device: u32) {
+    let c = match COUNTERS.get(device as usize) {
+        Some(c) => c,
+        None => return,
+    };
+
+    for counter in [
+        &c.reads,
+        &c.writes,
+        &c.flushes,
+        &c.discards,
+        &c.bytes_read,
+        &c.bytes_written,
+        &c.errors,
+    ] {
+        counter.store(0, Ordering::Relaxed);
+    }
+    c.max_in_flight.store(c.in_flight.load(Ordering::Relaxed), Ordering::Relaxed);
+    for bucket in c.latency.iter() {
+        bucket.store(0, Ordering::Relaxed);
+    }
+}
@@ -1,7 +1,21 @@
 //! Tag-based file system (TagFS)
+//!
+//! Object data lives in a region of the storage device, except for objects
+//! made from memory the kernel keeps, such as the initrd's files, which can
+//! be read before there is any storage.
+//!
+//! Other subsystems can watch for changes: a watcher is called after an
+//! object is created, tagged or deleted. A single access hook also sees
+//! every read, for subsystems that learn access patterns.
+//!
+//! The tag index is read without a lock (see [`crate::kernel::rcu`]), so
+//! path lookups never wait on a change being made.
 
 use arrayvec::ArrayVec;
 use core::hash::{Hash, Hasher};
+use spin::Mutex;
+
+use crate::kernel::rcu::Rcu;
 
 /// Object metadata (12 bytes packed)
 #[repr(C, packed)]
@@ -42,16 +56,40 @@ impl Tag {
 /// Cuckoo hash table for tag index
 const HASH_TABLE_SIZE: usize = 4096;
 
+/// Most tag-object pairs the index holds
+const MAX_TAG_ENTRIES: usize = 8192;
+
+/// End of a chain of entries
+const NO_ENTRY: u16 = u16::MAX;
+
+/// A tag and the first entry for the objects it points at
+#[derive(Clone, Copy)]
+struct TagSlot {
+    tag: Tag,
+    head: u16,
+}
+
+/// An object a tag points at, and the next entry for the same tag
+#[derive(Clone, Copy)]
+struct Entry {
+    object_id: u64,
+    next: u16,
+}
+
+/// Maps each tag to the set of objects it points at: the tag sits in one
+/// of two cuckoo tables, and its objects are chained through a pool of
+/// entries shared by every tag
+#[derive(Clone, Copy)]
 pub struct TagIndex {
-    table1: [Option<(Tag, u64)>; HASH_TABLE_SIZE],
-    table2: [Option<(Tag, u64)>; HASH_TABLE_SIZE],
+    tables: [[Option<TagSlot>; HASH_TABLE_SIZE]; 2],
+    entries: [Option<Entry>; MAX_TAG_ENTRIES],
 }
 
 impl TagIndex {
     pub const fn new() -> Self {
         Self {
-            table1: [None; HASH_TABLE_SIZE],
-            table2: [None; HASH_TABLE_SIZE],
+            tables: [[None; HASH_TABLE_SIZE]; 2],
+            entries: [None; MAX_TAG_ENTRIES],
         }
     }
 
@@ -71,45 +109,240 @@ impl TagIndex {
         (h as usize) % HASH_TABLE_SIZE
     }
 
-    pub fn insert(&mut self, tag: Tag, object_id: u64) -> Result<(), TagFsError> {
-        let idx1 = self.hash1(&tag);
-        if self.table1[idx1].is_none() {
-            self.table1[idx1] = Some((tag, object_id));
-            return Ok(());
-        }
+    /// The two places, as table and slot, a tag can sit
+    fn homes(&self, tag: &Tag) -> [(usize, usize); 2] {
+        [(0, self.hash1(tag)), (1, self.hash2(tag))]
+    }
+
+    /// Where a tag sits, if anywhere
+    fn find(&self, tag: &Tag) -> Option<(usize, usize)> {
+        self.homes(tag)
+            .into_iter()
+            .find(|&(table, index)| matches!(&self.tables[table][index], Some(slot) if slot.tag == *tag))
+    }
+
+    fn slots(&self) -> impl Iterator<Item = &TagSlot> + '_ {
+        self.tables.iter().flatten().flatten()
+    }
+
+    /// Objects on the chain of entries starting at `head`
+    fn chain(&self, head: u16) -> impl Iterator<Item = u64> + '_ {
+        let first = self.entries.get(head as usize).copied().flatten();
+        core::iter::successors(first, |entry| self.entries.get(entry.next as usize).copied().flatten())
+            .map(|entry| entry.object_id)
+    }
 
-        let idx2 = self.hash2(&tag);
-        if self.table2[idx2].is_none() {
-            self.table2[idx2] = Some((tag, object_id));
+    /// Point a tag at an object as well as at those it already points at
+    pub fn insert(&mut self, tag: Tag, object_id: u64) -> Result<(), TagFsError> {
+        if self.objects(&tag).any(|id| id == object_id) {
             return Ok(());
         }
+        let (table, index) = self
+            .find(&tag)
+            .or_else(|| self.homes(&tag).into_iter().find(|&(table, index)| self.tables[table][index].is_none()))
+            // Cuckoo eviction would go here
+            .ok_or(TagFsError::HashTableFull)?;
+        let free = self.entries.iter().position(Option::is_none).ok_or(TagFsError::HashTableFull)?;
 
-        // Cuckoo eviction would go here
-        Err(TagFsError::HashTableFull)
+        let slot = &mut self.tables[table][index];
+        let next = slot.map_or(NO_ENTRY, |slot| slot.head);
+        self.entries[free] = Some(Entry { object_id, next });
+        *slot = Some(TagSlot { tag, head: free as u16 });
+        Ok(())
     }
 
-    pub fn lookup(&self, tag: &Tag) -> Option<u64> {
-        let idx1 = self.hash1(tag);
-        if let Some((t, oid)) = &self.table1[idx1] {
-            if t == tag {
-                return Some(*oid);
+    /// Drop an object from the slot at `home`, emptying the slot along with
+    /// its last object
+    fn unlink(&mut self, (table, index): (usize, usize), object_id: u64) -> bool {
+        let Some(slot) = self.tables[table][index] else {
+            return false;
+        };
+        let (mut prev, mut next): (Option<u16>, u16) = (None, slot.head);
+        while let Some(entry) = self.entries.get(next as usize).copied().flatten() {
+            if entry.object_id == object_id {
+                self.entries[next as usize] = None;
+                match prev.and_then(|prev| self.entries[prev as usize].as_mut()) {
+                    Some(prev) => prev.next = entry.next,
+                    None if entry.next == NO_ENTRY => self.tables[table][index] = None,
+                    None => self.tables[table][index] = Some(TagSlot { head: entry.next, ..slot }),
+                }
+                return true;
             }
+            (prev, next) = (Some(next), entry.next);
         }
+        false
+    }
 
-        let idx2 = self.hash2(tag);
-        if let Some((t, oid)) = &self.table2[idx2] {
-            if t == tag {
-                return Some(*oid);
+    /// Remove every index entry pointing at an object
+    pub fn remove_object(&mut self, object_id: u64) {
+        for table in 0..2 {
+            for index in 0..HASH_TABLE_SIZE {
+                self.unlink((table, index), object_id);
             }
         }
+    }
+
+    /// Stop a tag pointing at an object, returning whether it did
+    pub fn remove(&mut self, tag: &Tag, object_id: u64) -> bool {
+        self.find(tag).is_some_and(|home| self.unlink(home, object_id))
+    }
+
+    /// Every tag with each object it points at
+    pub fn entries(&self) -> impl Iterator<Item = (&Tag, u64)> + '_ {
+        self.slots().flat_map(move |slot| self.chain(slot.head).map(move |id| (&slot.tag, id)))
+    }
+
+    /// Every tag pointing at an object
+    pub fn tags_of(&self, object_id: u64) -> impl Iterator<Item = &Tag> + '_ {
+        self.slots()
+            .filter(move |slot| self.chain(slot.head).any(|id| id == object_id))
+            .map(|slot| &slot.tag)
+    }
+
+    /// Every object a tag points at, newest first
+    pub fn objects(&self, tag: &Tag) -> impl Iterator<Item = u64> + '_ {
+        let head = self.find(tag).and_then(|(table, index)| self.tables[table][index]);
+        self.chain(head.map_or(NO_ENTRY, |slot| slot.head))
+    }
+
+    /// The object a tag was last put on
+    pub fn lookup(&self, tag: &Tag) -> Option<u64> {
+        self.objects(tag).next()
+    }
+}
+
+/// Maximum number of objects
+const MAX_OBJECTS: usize = 4096;
+
+/// Device holding the object storage region
+const TAGFS_DEVICE: u32 = 0;
+
+/// Object data is allocated in whole compression units so freed space can be discarded
+const OBJECT_ALIGN: u64 = crate::storage::extent::COMPRESSION_UNIT as u64;
+
+/// Bytes written at a time when an object is created
+const CREATE_PIECE: usize = 4096;
 
-        None
+/// Where an object's data is
+#[derive(Clone, Copy)]
+enum Backing {
+    /// At this byte offset in the storage region
+    Storage(u64),
+    Memory(&'static [u8]),
+}
+
+/// Object table entry
+#[derive(Clone, Copy)]
+struct ObjectRecord {
+    meta: ObjectMeta,
+    backing: Backing,
+}
+
+impl ObjectRecord {
+    /// Bytes reserved for the object data
+    fn extent_len(&self) -> u64 {
+        extent_len(self.meta.size)
     }
 }
 
+/// Bytes reserved for `size` bytes of object data
+fn extent_len(size: u32) -> u64 {
+    (size as u64).div_ceil(OBJECT_ALIGN) * OBJECT_ALIGN
+}
+
 /// Global TagFS state
-static mut TAG_INDEX: TagIndex = TagIndex::new();
+static TAG_INDEX: Rcu<TagIndex> = Rcu::new(TagIndex::new());
 static mut NEXT_OBJECT_ID: u64 = 1;
+static mut OBJECTS: [Option<ObjectRecord>; MAX_OBJECTS] = [None; MAX_OBJECTS];
+static mut NEXT_DATA_OFFSET: u64 = 0;
+
+/// Freed ranges of the storage region tracked for reuse; beyond this,
+/// freed space is lost until the next boot
+const MAX_FREE_RANGES: usize = 256;
+
+/// Ranges of the storage region freed by deleted objects, as `(offset,
+/// length)` sorted by offset
+static FREE_RANGES: Mutex<ArrayVec<(u64, u64), MAX_FREE_RANGES>> = Mutex::new(ArrayVec::new_const());
+
+/// Take `len` bytes of the storage region, reusing freed space first
+unsafe fn allocate_data(len: u64) -> u64 {
+    let mut free = FREE_RANGES.lock();
+    if let Some(i) = free.iter().position(|&(_, length)| length >= len) {
+        let (offset, length) = free[i];
+        if length == len {
+            free.remove(i);
+        } else {
+            free[i] = (offset + len, length - len);
+        }
+        return offset;
+    }
+    let offset = NEXT_DATA_OFFSET;
+    NEXT_DATA_OFFSET += len;
+    offset
+}
+
+/// Give `len` bytes at `offset` back for reuse, merging them with adjacent
+/// free ranges
+fn release_data(offset: u64, len: u64) {
+    if len == 0 {
+        return;
+    }
+    let mut free = FREE_RANGES.lock();
+    let i = free.iter().position(|&(start, _)| start > offset).unwrap_or(free.len());
+    let joins_previous = i > 0 && free[i - 1].0 + free[i - 1].1 == offset;
+    let joins_next = i < free.len() && offset + len == free[i].0;
+    match (joins_previous, joins_next) {
+        (true, true) => {
+            free[i - 1].1 += len + free[i].1;
+            free.remove(i);
+        }
+        (true, false) => free[i - 1].1 += len,
+        (false, true) => free[i] = (offset, len + free[i].1),
+        (false, false) => {
+            let _ = free.try_insert(i, (offset, len));
+        }
+    }
+}
+
+/// Maximum number of watchers
+const MAX_WATCHERS: usize = 8;
+
+/// Change reported to watchers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchEvent {
+    Created,
+    Tagged,
+    Deleted,
+}
+
+/// Called with the object and what happened to it
+///
+/// Runs in the context of whoever changed the object, possibly with their
+/// locks held, so it should only record the event and return.
+pub type WatchFn = fn(u64, WatchEvent);
+
+static WATCHERS: Mutex<[Option<WatchFn>; MAX_WATCHERS]> = Mutex::new([None; MAX_WATCHERS]);
+
+/// Called after a read with the object, offset and bytes read; same
+/// constraints as [`WatchFn`]
+pub type AccessFn = fn(u64, u64, usize);
+
+static ACCESS_HOOK: Mutex<Option<AccessFn>> = Mutex::new(None);
+
+/// Report a change to every watcher
+fn notify(object_id: u64, event: WatchEvent) {
+    let watchers = *WATCHERS.lock();
+    for watcher in watchers.iter().flatten() {
+        watcher(object_id, event);
+    }
+}
+
+/// Find the table slot holding an object
+unsafe fn find_object(object_id: u64) -> Option<&'static mut Option<ObjectRecord>> {
+    OBJECTS
+        .iter_mut()
+        .find(|slot| matches!(slot, Some(rec) if { rec.meta.id } == object_id))
+}
 
 /// Initialize TagFS
 pub fn init() {
@@ -118,27 +351,226 @@ pub fn init() {
 
 /// Create a new object with tags
 pub fn tagfs_create(tags: &[Tag], data: &[u8]) -> Result<u64, TagFsError> {
+    tagfs_create_with(tags, data.len() as u64, |offset, buffer| {
+        let start = offset as usize;
+        buffer.copy_from_slice(&data[start..start + buffer.len()]);
+        Ok(())
+    })
+}
+
+/// Create a new object with tags, `size` bytes long, whose data `fill`
+/// supplies a piece at a time: it fills the buffer with the bytes from
+/// the offset it is given
+pub fn tagfs_create_with(
+    tags: &[Tag],
+    size: u64,
+    mut fill: impl FnMut(u64, &mut [u8]) -> Result<(), TagFsError>,
+) -> Result<u64, TagFsError> {
     unsafe {
+        let size = u32::try_from(size).map_err(|_| TagFsError::StorageFull)?;
+        let slot = OBJECTS
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(TagFsError::StorageFull)?;
+
         let object_id = NEXT_OBJECT_ID;
         NEXT_OBJECT_ID += 1;
 
-        for tag in tags {
-            TAG_INDEX.insert(*tag, object_id)?;
+        let extent_len = extent_len(size);
+        let start = allocate_data(extent_len);
+        let mut piece = [0u8; CREATE_PIECE];
+        for offset in (0..size as u64).step_by(CREATE_PIECE) {
+            let piece = &mut piece[..(size as u64 - offset).min(CREATE_PIECE as u64) as usize];
+            let written = fill(offset, piece)
+                .and_then(|()| crate::storage::write(TAGFS_DEVICE, start + offset, piece).map_err(TagFsError::from));
+            if let Err(e) = written {
+                let _ = crate::storage::discard(TAGFS_DEVICE, start, extent_len);
+                release_data(start, extent_len);
+                return Err(e);
+            }
         }
+        *slot = Some(ObjectRecord {
+            meta: ObjectMeta::new(object_id, size),
+            backing: Backing::Storage(start),
+        });
+        tag_new(object_id, tags)
+    }
+}
+
+/// Create an object with tags whose data stays in `data`, needing no
+/// storage
+pub fn tagfs_create_in_memory(tags: &[Tag], data: &'static [u8]) -> Result<u64, TagFsError> {
+    unsafe {
+        let size = u32::try_from(data.len()).map_err(|_| TagFsError::StorageFull)?;
+        let slot = OBJECTS
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(TagFsError::StorageFull)?;
 
-        // TODO: Store actual data
-        Ok(object_id)
+        let object_id = NEXT_OBJECT_ID;
+        NEXT_OBJECT_ID += 1;
+        *slot = Some(ObjectRecord {
+            meta: ObjectMeta::new(object_id, size),
+            backing: Backing::Memory(data),
+        });
+        tag_new(object_id, tags)
+    }
+}
+
+/// Give a new object its tags and announce it, deleting it again if a
+/// tag does not fit
+unsafe fn tag_new(object_id: u64, tags: &[Tag]) -> Result<u64, TagFsError> {
+    if let Err(e) = TAG_INDEX.update(|index| tags.iter().try_for_each(|tag| index.insert(*tag, object_id))) {
+        let _ = tagfs_delete(object_id);
+        return Err(e);
+    }
+
+    notify(object_id, WatchEvent::Created);
+    Ok(object_id)
+}
+
+/// Read object data starting at `offset`
+pub fn tagfs_read(object_id: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, TagFsError> {
+    unsafe {
+        let record = find_object(object_id)
+            .and_then(|slot| *slot)
+            .ok_or(TagFsError::ObjectNotFound)?;
+
+        let size = record.meta.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let len = buffer.len().min((size - offset) as usize);
+        match record.backing {
+            Backing::Storage(start) => {
+                crate::storage::read(TAGFS_DEVICE, start + offset, &mut buffer[..len])?;
+            }
+            Backing::Memory(data) => buffer[..len].copy_from_slice(&data[offset as usize..offset as usize + len]),
+        }
+        let hook = *ACCESS_HOOK.lock();
+        if let Some(hook) = hook {
+            hook(object_id, offset, len);
+        }
+        Ok(len)
+    }
+}
+
+/// Hint that `len` bytes at `offset` in an object will be read soon
+pub fn tagfs_prefetch(object_id: u64, offset: u64, len: u64) -> Result<(), TagFsError> {
+    unsafe {
+        let record = find_object(object_id)
+            .and_then(|slot| *slot)
+            .ok_or(TagFsError::ObjectNotFound)?;
+
+        let size = record.meta.size as u64;
+        if let (Backing::Storage(start), true) = (record.backing, offset < size) {
+            crate::storage::cache::hint(TAGFS_DEVICE, start + offset, len.min(size - offset));
+        }
+        Ok(())
+    }
+}
+
+/// Get object metadata
+pub fn tagfs_meta(object_id: u64) -> Option<ObjectMeta> {
+    unsafe { find_object(object_id).and_then(|slot| *slot).map(|rec| rec.meta) }
+}
+
+/// Delete an object, dropping its tags and discarding its data extents
+pub fn tagfs_delete(object_id: u64) -> Result<(), TagFsError> {
+    unsafe {
+        let record = find_object(object_id)
+            .and_then(|slot| slot.take())
+            .ok_or(TagFsError::ObjectNotFound)?;
+
+        TAG_INDEX.update(|index| index.remove_object(object_id));
+        notify(object_id, WatchEvent::Deleted);
+        if let Backing::Storage(start) = record.backing {
+            let discarded = crate::storage::discard(TAGFS_DEVICE, start, record.extent_len());
+            release_data(start, record.extent_len());
+            discarded?;
+        }
+        Ok(())
     }
 }
 
-/// Query objects by tag
+/// The object a tag was last put on
 pub fn tagfs_query(tag: &Tag) -> Option<u64> {
-    unsafe { TAG_INDEX.lookup(tag) }
+    TAG_INDEX.read().lookup(tag)
+}
+
+/// Call `f` with every object a tag is on, newest first, under the same
+/// constraints as [`tagfs_tags`]
+pub fn tagfs_query_all(tag: &Tag, mut f: impl FnMut(u64)) {
+    for object_id in TAG_INDEX.read().objects(tag) {
+        f(object_id);
+    }
+}
+
+/// Call `f` with the metadata of every object
+pub fn tagfs_list(mut f: impl FnMut(ObjectMeta)) {
+    unsafe {
+        for record in OBJECTS.iter().flatten() {
+            f(record.meta);
+        }
+    }
+}
+
+/// Call `f` with every tag of an object; `f` runs in a read-side section,
+/// so it must not change tags or block
+pub fn tagfs_tags(object_id: u64, mut f: impl FnMut(&Tag)) {
+    for tag in TAG_INDEX.read().tags_of(object_id) {
+        f(tag);
+    }
+}
+
+/// Call `f` with every tag and the object it points at, under the same
+/// constraints as [`tagfs_tags`]
+pub fn tagfs_each_tag(mut f: impl FnMut(&Tag, u64)) {
+    for (tag, object_id) in TAG_INDEX.read().entries() {
+        f(tag, object_id);
+    }
+}
+
+/// Remove a tag from an object, deleting the object if that was its last
+/// tag
+pub fn tagfs_remove_tag(object_id: u64, tag: &Tag) -> Result<(), TagFsError> {
+    let last = TAG_INDEX
+        .update(|index| index.remove(tag, object_id).then(|| index.tags_of(object_id).next().is_none()))
+        .ok_or(TagFsError::ObjectNotFound)?;
+    if last {
+        return tagfs_delete(object_id);
+    }
+    notify(object_id, WatchEvent::Tagged);
+    Ok(())
 }
 
 /// Add tag to object
 pub fn tagfs_add_tag(object_id: u64, tag: Tag) -> Result<(), TagFsError> {
-    unsafe { TAG_INDEX.insert(tag, object_id) }
+    TAG_INDEX.update(|index| index.insert(tag, object_id))?;
+    notify(object_id, WatchEvent::Tagged);
+    Ok(())
+}
+
+/// Start calling `watcher` on object changes, returning an ID for
+/// [`tagfs_unwatch`]
+pub fn tagfs_watch(watcher: WatchFn) -> Result<usize, TagFsError> {
+    let mut watchers = WATCHERS.lock();
+    let id = watchers.iter().position(|w| w.is_none()).ok_or(TagFsError::TooManyWatchers)?;
+    watchers[id] = Some(watcher);
+    Ok(id)
+}
+
+/// Stop a watcher
+pub fn tagfs_unwatch(id: usize) {
+    if let Some(slot) = WATCHERS.lock().get_mut(id) {
+        *slot = None;
+    }
+}
+
+/// Install or remove the read hook
+pub fn tagfs_set_access_hook(hook: Option<AccessFn>) {
+    *ACCESS_HOOK.lock() = hook;
 }
 
 /// TagFS errors
@@ -148,4 +580,120 @@ pub enum TagFsError {
     ObjectNotFound,
     InvalidTag,
     StorageFull,
+    IoError,
+    DeviceGone,
+    TooManyWatchers,
+}
+
+impl From<crate::storage::StorageError> for TagFsError {
+    fn from(e: crate::storage::StorageError) -> Self {
+        match e {
+            crate::storage::StorageError::DeviceGone => TagFsError::DeviceGone,
+            _ => TagFsError::IoError,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spin::Mutex;
+
+    /// An index of its own, too big for the stack
+    static INDEX: Mutex<TagIndex> = Mutex::new(TagIndex::new());
+
+    /// The shared index, emptied in place
+    fn index() -> spin::MutexGuard<'static, TagIndex> {
+        let mut index = INDEX.lock();
+        for table in index.tables.iter_mut() {
+            table.fill(None);
+        }
+        index.entries.fill(None);
+        index
+    }
+
+    #[test_case]
+    fn lookup_finds_inserted() {
+        let mut index = index();
+        index.insert(Tag::new("kind:photo"), 1).unwrap();
+        index.insert(Tag::new("year:2024"), 1).unwrap();
+        index.insert(Tag::new("kind:music"), 2).unwrap();
+        assert_eq!(index.lookup(&Tag::new("kind:photo")), Some(1));
+        assert_eq!(index.lookup(&Tag::new("year:2024")), Some(1));
+        assert_eq!(index.lookup(&Tag::new("kind:music")), Some(2));
+        assert_eq!(index.lookup(&Tag::new("kind:video")), None);
+    }
+
+    #[test_case]
+    fn remove_tag() {
+        let mut index = index();
+        let tag = Tag::new("draft");
+        index.insert(tag, 3).unwrap();
+        assert!(index.remove(&tag, 3));
+        assert!(!index.remove(&tag, 3));
+        assert_eq!(index.lookup(&tag), None);
+    }
+
+    #[test_case]
+    fn remove_object_drops_its_tags() {
+        let mut index = index();
+        index.insert(Tag::new("a"), 4).unwrap();
+        index.insert(Tag::new("b"), 4).unwrap();
+        index.insert(Tag::new("c"), 5).unwrap();
+        assert_eq!(index.tags_of(4).count(), 2);
+        index.remove_object(4);
+        assert_eq!(index.tags_of(4).count(), 0);
+        assert_eq!(index.lookup(&Tag::new("c")), Some(5));
+        assert_eq!(index.entries().count(), 1);
+    }
+
+    #[test_case]
+    fn shared_tag_points_at_every_object() {
+        let mut index = index();
+        let tag = Tag::new("type=image");
+        for id in 1..=3 {
+            index.insert(tag, id).unwrap();
+        }
+        index.insert(tag, 2).unwrap();
+        assert!(index.objects(&tag).eq([3, 2, 1]));
+        assert!(index.remove(&tag, 2));
+        assert!(index.objects(&tag).eq([3, 1]));
+        index.remove_object(3);
+        assert_eq!(index.lookup(&tag), Some(1));
+        assert!(index.remove(&tag, 1));
+        assert_eq!(index.lookup(&tag), None);
+        assert_eq!(index.entries().count(), 0);
+    }
+
+    #[test_case]
+    fn long_tags_truncated() {
+        let tag = Tag::new("0123456789abcdef0123456789abcdef-and-more");
+        assert_eq!(tag.as_str(), "0123456789abcdef0123456789abcdef");
+    }
+
+    #[test_case]
+    fn second_table_takes_collisions() {
+        let mut index = index();
+        // "Ab" and "BC" share a slot of the first table
+        let (first, second) = (Tag::new("Ab"), Tag::new("BC"));
+        assert_eq!(index.hash1(&first), index.hash1(&second));
+        index.insert(first, 1).unwrap();
+        index.insert(second, 2).unwrap();
+        assert_eq!(index.lookup(&first), Some(1));
+        assert_eq!(index.lookup(&second), Some(2));
+        assert!(index.remove(&second, 2));
+        assert_eq!(index.lookup(&first), Some(1));
+    }
+
+    #[test_case]
+    fn full_slots_refuse() {
+        let mut index = index();
+        // "tsn0" wants the slots of "Ab" in the first table and "BC" in
+        // the second, and nothing moves out of the way
+        let third = Tag::new("tsn0");
+        index.insert(Tag::new("Ab"), 1).unwrap();
+        index.insert(Tag::new("BC"), 2).unwrap();
+        assert!(matches!(index.insert(third, 3), Err(TagFsError::HashTableFull)));
+        assert_eq!(index.lookup(&third), None);
+    }
 }
@@ -0,0 +1,66 @@
+//! Kernel tests under QEMU
+//!
+//! `cargo test` builds the kernel with every `#[test_case]` function and
+//! boots it in QEMU through `bootimage runner`. The kernel comes up as
+//! usual, so tests can use the heap, IPC, TagFS and the scheduler, and
+//! then runs the tests in turn instead of starting the shell, printing
+//! each result on the serial port, which QEMU copies to its standard
+//! output. A failed assertion panics, which ends the run. Either way the
+//! kernel leaves QEMU through its `isa-debug-exit` device, whose exit
+//! status `bootimage` turns back into a pass or a failure.
+
+use core::panic::PanicInfo;
+
+use x86_64::instructions::port::Port;
+
+use crate::{serial_print, serial_println};
+
+/// I/O port of the `isa-debug-exit` device, as `Cargo.toml` configures it
+const DEBUG_EXIT_PORT: u16 = 0xF4;
+
+/// Codes written to the exit device; QEMU exits with `(code << 1) | 1`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Leave QEMU with `code`
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    unsafe { Port::new(DEBUG_EXIT_PORT).write(code as u32) };
+    // Not under QEMU, or without the device
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// A test the runner can name
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        serial_print!("{}...\t", core::any::type_name::<T>());
+        self();
+        serial_println!("[ok]");
+    }
+}
+
+/// Run every test, then leave QEMU; the harness's entry point
+pub fn run(tests: &[&dyn Testable]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// Report the failed test and leave QEMU
+pub fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("{}", info);
+    crate::kernel::backtrace::print();
+    exit_qemu(QemuExitCode::Failed);
+}
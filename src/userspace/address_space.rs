@@ -0,0 +1,313 @@
+//! User address spaces
+//!
+//! Each address space has its own level 4 table. The lowest 512 GiB (the
+//! first level 4 entry) belong to the process; every other entry is shared
+//! with the kernel's table, so kernel code, the heap, kernel regions and
+//! the physical memory mapping stay reachable after a switch. The
+//! bootloader's low identity mappings are not carried over.
+//!
+//! Tables are edited through the physical memory mapping and never need
+//! to be active while a program is loaded. A forked address space shares
+//! its parent's pages copy-on-write (see [`super::cow`]).
+
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageTable, PageTableFlags as Flags, PhysFrame, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::cow::{self, COW};
+use crate::kernel::cpu::{self, Feature};
+use crate::kernel::memory::{self, MapError};
+
+/// Lowest address a program may map; the pages below catch null pointers
+pub const USER_START: u64 = 0x1_0000;
+
+/// End of the user half, exclusive
+pub const USER_END: u64 = 0x0000_0080_0000_0000;
+
+/// `flags` less what the processor would fault on as a reserved bit
+fn supported(mut flags: Flags) -> Flags {
+    if !cpu::has(Feature::NoExecute) {
+        flags.remove(Flags::NO_EXECUTE);
+    }
+    flags
+}
+
+/// A process's page tables
+pub struct AddressSpace {
+    l4: PhysFrame,
+}
+
+fn table(frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *memory::phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>() }
+}
+
+/// Free a table of the user half at `level` (3 for the level 3 table)
+/// with everything below it
+fn free_table(frame: PhysFrame, level: u8) {
+    for entry in table(frame).iter() {
+        if !entry.flags().contains(Flags::PRESENT) {
+            continue;
+        }
+        let child = PhysFrame::containing_address(entry.addr());
+        if level > 1 {
+            free_table(child, level - 1);
+        } else if cow::release(child) {
+            memory::free_frame(child);
+        }
+    }
+    memory::free_frame(frame);
+}
+
+fn zeroed_frame() -> Result<PhysFrame, MapError> {
+    let frame = memory::allocate_frame().ok_or(MapError::OutOfMemory)?;
+    unsafe {
+        core::ptr::write_bytes(memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, 4096);
+    }
+    Ok(frame)
+}
+
+impl AddressSpace {
+    /// An empty user half over the kernel's mappings
+    pub fn new() -> Result<Self, MapError> {
+        let l4 = zeroed_frame()?;
+        let (active, _) = Cr3::read();
+        let kernel = table(active);
+        let user = table(l4);
+        for i in 1..512 {
+            user[i] = kernel[i].clone();
+        }
+        Ok(Self { l4 })
+    }
+
+    /// Free the user half's pages and tables and the level 4 table
+    ///
+    /// The address space must not be active.
+    pub fn destroy(self) {
+        let entry = &table(self.l4)[0];
+        if entry.flags().contains(Flags::PRESENT) {
+            free_table(PhysFrame::containing_address(entry.addr()), 3);
+        }
+        memory::free_frame(self.l4);
+    }
+
+    /// The address space the CPU is using
+    pub fn active() -> Self {
+        Self { l4: Cr3::read().0 }
+    }
+
+    /// Frame to load into CR3 to switch to this address space
+    pub fn l4_frame(&self) -> PhysFrame {
+        self.l4
+    }
+
+    fn mapper(&self) -> OffsetPageTable<'static> {
+        unsafe { OffsetPageTable::new(table(self.l4), memory::phys_to_virt(PhysAddr::new(0))) }
+    }
+
+    /// Map a zeroed page for the process
+    ///
+    /// A page that is already mapped keeps its contents and gains the new
+    /// permissions, so segments sharing a page get the union of theirs.
+    pub fn map(&mut self, page: Page, flags: Flags) -> Result<(), MapError> {
+        let addr = page.start_address().as_u64();
+        if !(USER_START..USER_END).contains(&addr) {
+            return Err(MapError::MapFailed);
+        }
+        let flags = supported(flags) | Flags::PRESENT | Flags::USER_ACCESSIBLE;
+        let mut mapper = self.mapper();
+
+        if let TranslateResult::Mapped { flags: old, .. } = mapper.translate(page.start_address()) {
+            let mut merged = old | flags;
+            merged.set(Flags::NO_EXECUTE, old.contains(Flags::NO_EXECUTE) && flags.contains(Flags::NO_EXECUTE));
+            // A shared page becomes writable when it is copied
+            if old.contains(COW) {
+                merged.remove(Flags::WRITABLE);
+            }
+            unsafe {
+                mapper.update_flags(page, merged).map_err(|_| MapError::MapFailed)?.ignore();
+            }
+            return Ok(());
+        }
+
+        self.map_frame(page, zeroed_frame()?, flags)
+    }
+
+    fn map_frame(&mut self, page: Page, frame: PhysFrame, flags: Flags) -> Result<(), MapError> {
+        let mut mapper = self.mapper();
+        let parent = Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+        let mut allocator = memory::FRAME_ALLOCATOR.lock();
+        let allocator = allocator.as_mut().ok_or(MapError::AllocatorNotInitialized)?;
+        unsafe {
+            mapper
+                .map_to_with_table_flags(page, frame, flags, parent, allocator)
+                .map_err(|_| MapError::MapFailed)?
+                .ignore();
+        }
+        Ok(())
+    }
+
+    /// A copy of the user half for a forked process, sharing every page
+    /// copy-on-write
+    ///
+    /// This address space's writable pages lose write access too, so the
+    /// TLB must be flushed if it is active.
+    pub fn fork(&mut self) -> Result<AddressSpace, MapError> {
+        let mut child = AddressSpace::new()?;
+        if let Err(e) = self.share_with(&mut child) {
+            child.destroy();
+            return Err(e);
+        }
+        Ok(child)
+    }
+
+    fn share_with(&mut self, child: &mut AddressSpace) -> Result<(), MapError> {
+        let l4 = &table(self.l4)[0];
+        if !l4.flags().contains(Flags::PRESENT) {
+            return Ok(());
+        }
+        for (i3, l3) in table(PhysFrame::containing_address(l4.addr())).iter().enumerate() {
+            if !l3.flags().contains(Flags::PRESENT) {
+                continue;
+            }
+            for (i2, l2) in table(PhysFrame::containing_address(l3.addr())).iter().enumerate() {
+                if !l2.flags().contains(Flags::PRESENT) {
+                    continue;
+                }
+                for (i1, entry) in table(PhysFrame::containing_address(l2.addr())).iter_mut().enumerate() {
+                    let mut flags = entry.flags();
+                    if !flags.contains(Flags::PRESENT) {
+                        continue;
+                    }
+                    if flags.contains(Flags::WRITABLE) {
+                        flags.remove(Flags::WRITABLE);
+                        flags.insert(COW);
+                        entry.set_flags(flags);
+                    }
+                    let frame = PhysFrame::containing_address(entry.addr());
+                    let addr = (i3 as u64) << 30 | (i2 as u64) << 21 | (i1 as u64) << 12;
+                    cow::share(frame)?;
+                    if let Err(e) = child.map_frame(Page::containing_address(VirtAddr::new(addr)), frame, flags) {
+                        cow::release(frame);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Give this address space its own writable copy of the copy-on-write
+    /// page mapping `addr`, returning whether there was one
+    ///
+    /// The address space must be active.
+    pub fn resolve_cow(&mut self, addr: VirtAddr) -> Result<bool, MapError> {
+        let page = Page::containing_address(addr);
+        let mut mapper = self.mapper();
+        let TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } = mapper.translate(addr) else {
+            return Ok(false);
+        };
+        if !flags.contains(COW) {
+            return Ok(false);
+        }
+        let flags = (flags - COW) | Flags::WRITABLE;
+
+        if !cow::is_shared(frame) {
+            unsafe {
+                mapper.update_flags(page, flags).map_err(|_| MapError::MapFailed)?.flush();
+            }
+            return Ok(true);
+        }
+
+        let copy = memory::allocate_frame().ok_or(MapError::OutOfMemory)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                memory::phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                memory::phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                4096,
+            );
+        }
+        let (_, flush) = mapper.unmap(page).map_err(|_| MapError::MapFailed)?;
+        flush.flush();
+        cow::release(frame);
+        self.map_frame(page, copy, flags)?;
+        Ok(true)
+    }
+
+    /// Unmap a page, freeing its frame unless another address space
+    /// still shares it
+    pub fn unmap(&mut self, page: Page) -> Result<(), MapError> {
+        let (frame, flush) = self.mapper().unmap(page).map_err(|_| MapError::MapFailed)?;
+        flush.flush();
+        if cow::release(frame) {
+            memory::free_frame(frame);
+        }
+        Ok(())
+    }
+
+    /// Change the permissions of a mapped page to `flags`
+    ///
+    /// A page whose frame is shared stays copy-on-write rather than
+    /// becoming writable, so it is copied the first time it is written.
+    pub fn protect(&mut self, page: Page, flags: Flags) -> Result<(), MapError> {
+        let mut mapper = self.mapper();
+        let TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags: old, .. } =
+            mapper.translate(page.start_address())
+        else {
+            return Err(MapError::MapFailed);
+        };
+        let mut flags = supported(flags) | Flags::PRESENT;
+        if flags.contains(Flags::WRITABLE) && (old.contains(COW) || cow::is_shared(frame)) {
+            flags.remove(Flags::WRITABLE);
+            flags.insert(COW);
+        }
+        unsafe {
+            mapper.update_flags(page, flags).map_err(|_| MapError::MapFailed)?.flush();
+        }
+        Ok(())
+    }
+
+    /// Flags of the page mapping `addr`, if any
+    pub fn flags(&self, addr: VirtAddr) -> Option<Flags> {
+        match self.mapper().translate(addr) {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None,
+        }
+    }
+
+    /// Copy `data` into mapped memory at `addr`, whatever its permissions
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), MapError> {
+        let mut done = 0;
+        while done < data.len() {
+            let bytes = self.bytes_at(addr + done as u64).ok_or(MapError::MapFailed)?;
+            let len = bytes.len().min(data.len() - done);
+            bytes[..len].copy_from_slice(&data[done..done + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Copy mapped memory at `addr` into `data`
+    pub fn read(&mut self, addr: VirtAddr, data: &mut [u8]) -> Result<(), MapError> {
+        let mut done = 0;
+        while done < data.len() {
+            let bytes = self.bytes_at(addr + done as u64).ok_or(MapError::MapFailed)?;
+            let len = bytes.len().min(data.len() - done);
+            data[done..done + len].copy_from_slice(&bytes[..len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Kernel view of the mapped bytes from `addr` to the end of its page
+    pub fn bytes_at(&mut self, addr: VirtAddr) -> Option<&mut [u8]> {
+        let TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), .. } = self.mapper().translate(addr) else {
+            return None;
+        };
+        let offset = addr.as_u64() as usize % 4096;
+        let base = memory::phys_to_virt(frame.start_address()).as_u64() as usize;
+        Some(unsafe { core::slice::from_raw_parts_mut((base + offset) as *mut u8, 4096 - offset) })
+    }
+}
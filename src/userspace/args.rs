@@ -0,0 +1,93 @@
+//! Argument and environment vectors
+//!
+//! System calls take each vector as one packed block: the strings back to
+//! back, each ending in a NUL byte. The kernel copies the block before it
+//! touches the caller's image, so `exec` can pass strings from the image
+//! it replaces.
+//!
+//! A new program finds them on its stack, laid out as in the System V
+//! ABI. The stack pointer is 16-byte aligned and points at `argc`,
+//! followed by the `argv` pointers, a null pointer, the `envp` pointers
+//! and another null pointer; the strings themselves sit above that, at
+//! the top of the stack. `rdi`, `rsi` and `rdx` also hold `argc`, `argv`
+//! and `envp`, so a program can take them as the arguments of its entry
+//! function.
+
+use super::UserError;
+
+/// Bytes of strings one vector may hold
+pub const MAX_STRINGS_SIZE: usize = 4096;
+
+/// Strings one vector may hold
+pub const MAX_STRINGS: usize = 64;
+
+/// A vector of NUL-terminated strings
+#[derive(Clone)]
+pub struct Strings {
+    data: [u8; MAX_STRINGS_SIZE],
+    len: usize,
+    count: usize,
+}
+
+impl Strings {
+    /// An empty vector
+    pub const fn new() -> Self {
+        Self {
+            data: [0; MAX_STRINGS_SIZE],
+            len: 0,
+            count: 0,
+        }
+    }
+
+    /// Copy a packed block, which must end in a NUL byte unless it is
+    /// empty
+    pub fn from_packed(block: &[u8]) -> Result<Self, UserError> {
+        if block.len() > MAX_STRINGS_SIZE || block.last().is_some_and(|b| *b != 0) {
+            return Err(UserError::BadArguments);
+        }
+        let count = block.iter().filter(|b| **b == 0).count();
+        if count > MAX_STRINGS {
+            return Err(UserError::BadArguments);
+        }
+        let mut strings = Self::new();
+        strings.data[..block.len()].copy_from_slice(block);
+        strings.len = block.len();
+        strings.count = count;
+        Ok(strings)
+    }
+
+    /// Add a string, which must not contain NUL
+    pub fn push(&mut self, s: &str) -> Result<(), UserError> {
+        let end = self.len + s.len() + 1;
+        if s.contains('\0') || end > MAX_STRINGS_SIZE || self.count == MAX_STRINGS {
+            return Err(UserError::BadArguments);
+        }
+        self.data[self.len..end - 1].copy_from_slice(s.as_bytes());
+        self.data[end - 1] = 0;
+        self.len = end;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Number of strings
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The packed block
+    pub fn packed(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    /// Offset of each string in the packed block
+    pub fn offsets(&self) -> impl Iterator<Item = usize> + '_ {
+        let starts = self.packed().iter().enumerate().filter(|(_, b)| **b == 0).map(|(i, _)| i + 1);
+        core::iter::once(0).chain(starts).take(self.count)
+    }
+}
+
+impl Default for Strings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,81 @@
+//! The program break
+//!
+//! Each process has a data area that starts at the first page after its
+//! executable and ends at the program break. Moving the break up maps
+//! zeroed pages, and moving it down unmaps the pages wholly above it, so
+//! an allocator can grow and give back memory with one number rather
+//! than managing mappings. The area can grow to [`MAX_SIZE`], and never
+//! into the shared objects or the heap zen-libc maps itself.
+
+use x86_64::structures::paging::{Page, PageTableFlags as Flags};
+use x86_64::VirtAddr;
+
+use super::address_space::AddressSpace;
+use super::dynamic::LIBRARY_START;
+use super::UserError;
+
+/// Largest the data area grows
+pub const MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// A process's data area
+#[derive(Clone, Copy, Debug)]
+pub struct Break {
+    /// Where the area starts, page aligned
+    start: u64,
+    /// The break itself, exclusive
+    end: u64,
+}
+
+fn page_up(addr: u64) -> u64 {
+    (addr + 0xfff) & !0xfff
+}
+
+fn pages(from: u64, to: u64) -> impl Iterator<Item = Page> {
+    (from..to).step_by(4096).map(|addr| Page::containing_address(VirtAddr::new(addr)))
+}
+
+impl Break {
+    /// An empty area after an image ending at `image_end`
+    pub fn new(image_end: u64) -> Self {
+        let start = page_up(image_end);
+        Self { start, end: start }
+    }
+
+    /// The current break
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Move the break to `end` in `space`, returning it
+    pub fn set(&mut self, space: &mut AddressSpace, end: u64) -> Result<u64, UserError> {
+        let limit = (self.start + MAX_SIZE).min(LIBRARY_START);
+        if end < self.start || end > limit {
+            return Err(UserError::BadAddress);
+        }
+        let (old, new) = (page_up(self.end), page_up(end));
+        if new > old {
+            for (i, page) in pages(old, new).enumerate() {
+                if let Err(e) = space.map(page, Flags::WRITABLE | Flags::NO_EXECUTE) {
+                    for page in pages(old, old + i as u64 * 4096) {
+                        let _ = space.unmap(page);
+                    }
+                    return Err(e.into());
+                }
+            }
+        } else {
+            for page in pages(new, old) {
+                space.unmap(page)?;
+            }
+        }
+        self.end = end;
+        Ok(end)
+    }
+
+    /// Move the break by `increment` bytes, returning where it was
+    pub fn adjust(&mut self, space: &mut AddressSpace, increment: i64) -> Result<u64, UserError> {
+        let old = self.end;
+        let end = old.checked_add_signed(increment).ok_or(UserError::BadAddress)?;
+        self.set(space, end)?;
+        Ok(old)
+    }
+}
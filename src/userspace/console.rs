@@ -0,0 +1,152 @@
+//! The console as processes see it
+//!
+//! The console is three streams a process holds handles to (see
+//! [`super::handle`]): standard input reads it and standard output and
+//! error write to it. Output goes through the kernel's print path, so it
+//! shows on the serial port and on the framebuffer console's surface.
+//!
+//! Input reaches processes only while one of them holds the foreground,
+//! which the shell gives to the programs it runs; until then the shell
+//! reads the console itself. Input is edited a line at a time, with echo
+//! and backspace, and a line becomes readable once Enter finishes it.
+//! Ctrl-D ends input for the next read, which returns 0, and Ctrl-C stops
+//! the foreground process.
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::process;
+
+/// Standard streams, by the handle numbers processes hold them at
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// Longest line being edited
+pub const MAX_LINE: usize = 160;
+
+/// Finished input waiting to be read
+const INPUT_SIZE: usize = 1024;
+
+/// Exit status of a process stopped with Ctrl-C
+pub const INTERRUPT_STATUS: i32 = process::FAULT_STATUS + 2;
+
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+
+struct Console {
+    /// Process input goes to
+    foreground: Option<u32>,
+    line: ArrayVec<u8, MAX_LINE>,
+    ready: ArrayVec<u8, INPUT_SIZE>,
+    /// Ctrl-D was typed on an empty line
+    end_of_input: bool,
+    /// Processes blocked reading
+    readers: ArrayVec<u32, { process::MAX_PROCESSES }>,
+}
+
+static CONSOLE: Mutex<Console> = Mutex::new(Console {
+    foreground: None,
+    line: ArrayVec::new_const(),
+    ready: ArrayVec::new_const(),
+    end_of_input: false,
+    readers: ArrayVec::new_const(),
+});
+
+/// Write bytes from a process to the console
+pub fn write(data: &[u8]) {
+    for chunk in data.utf8_chunks() {
+        crate::serial_print!("{}", chunk.valid());
+        if !chunk.invalid().is_empty() {
+            crate::serial_print!("\u{fffd}");
+        }
+    }
+}
+
+/// Give console input to `pid`, or back to the shell
+pub fn set_foreground(pid: Option<u32>) {
+    interrupts::without_interrupts(|| {
+        let mut console = CONSOLE.lock();
+        console.foreground = pid;
+        console.line.clear();
+        console.ready.clear();
+        console.end_of_input = false;
+    });
+}
+
+/// The process console input goes to, if it is still running
+pub fn foreground() -> Option<u32> {
+    let pid = interrupts::without_interrupts(|| CONSOLE.lock().foreground)?;
+    if process::is_running(pid) {
+        Some(pid)
+    } else {
+        set_foreground(None);
+        None
+    }
+}
+
+/// Edit the foreground process's input with a byte typed on the console
+pub fn input(byte: u8) {
+    let (line_done, interrupt) = interrupts::without_interrupts(|| {
+        let mut console = CONSOLE.lock();
+        match byte {
+            CTRL_C => return (false, console.foreground),
+            CTRL_D if console.line.is_empty() => {
+                console.end_of_input = true;
+                return (true, None);
+            }
+            b'\r' | b'\n' => {
+                crate::serial_println!();
+                let mut line = core::mem::take(&mut console.line);
+                let _ = line.try_push(b'\n');
+                let room = console.ready.remaining_capacity().min(line.len());
+                let _ = console.ready.try_extend_from_slice(&line[..room]);
+                return (true, None);
+            }
+            0x08 | 0x7f => {
+                if console.line.pop().is_some() {
+                    crate::serial_print!("\u{8} \u{8}");
+                }
+            }
+            b' '..=b'~' | b'\t' if console.line.try_push(byte).is_ok() => {
+                crate::serial_print!("{}", byte as char);
+            }
+            _ => {}
+        }
+        (false, None)
+    });
+
+    if let Some(pid) = interrupt {
+        crate::serial_println!("^C");
+        let _ = process::kill(pid, INTERRUPT_STATUS);
+    }
+    if line_done {
+        let readers = interrupts::without_interrupts(|| core::mem::take(&mut CONSOLE.lock().readers));
+        for pid in readers {
+            process::wake(pid);
+        }
+    }
+}
+
+/// Read finished input into `buffer` for `pid`, returning how many bytes
+/// were read (0 at the end of input), or `None` if `pid` has to wait
+pub fn read(pid: u32, buffer: &mut [u8]) -> Option<usize> {
+    interrupts::without_interrupts(|| {
+        let mut console = CONSOLE.lock();
+        if !console.ready.is_empty() {
+            let len = buffer.len().min(console.ready.len());
+            buffer[..len].copy_from_slice(&console.ready[..len]);
+            console.ready.drain(..len);
+            return Some(len);
+        }
+        if console.end_of_input {
+            console.end_of_input = false;
+            return Some(0);
+        }
+        if !console.readers.contains(&pid) {
+            let _ = console.readers.try_push(pid);
+        }
+        None
+    })
+}
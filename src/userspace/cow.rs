@@ -0,0 +1,106 @@
+//! Copy-on-write sharing of user pages
+//!
+//! `fork` gives the child its parent's frames rather than copies. Both
+//! mappings lose write access and carry the [`COW`] mark, and the first
+//! write to such a page faults; the writer then gets a private copy, or
+//! takes the frame over if no one else maps it any more.
+//!
+//! A frame mapped by more than one address space has an entry here
+//! counting its extra mappings; a frame without one has a single mapping
+//! and is freed with it.
+
+use spin::Mutex;
+use x86_64::structures::paging::{PageTableFlags as Flags, PhysFrame};
+
+use crate::kernel::memory::MapError;
+
+/// Page table bit marking a page that is writable once copied
+pub const COW: Flags = Flags::BIT_9;
+
+/// Frames that can be shared at once (a power of two)
+pub const MAX_SHARED_FRAMES: usize = 16384;
+
+#[derive(Clone, Copy)]
+struct Shared {
+    frame: PhysFrame,
+    /// Mappings besides the first
+    extra: u32,
+}
+
+/// Open-addressed table of shared frames, probed linearly
+static SHARED: Mutex<[Option<Shared>; MAX_SHARED_FRAMES]> = Mutex::new([None; MAX_SHARED_FRAMES]);
+
+fn home(frame: PhysFrame) -> usize {
+    let number = frame.start_address().as_u64() >> 12;
+    (number.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - MAX_SHARED_FRAMES.trailing_zeros())) as usize
+}
+
+/// Slot holding `frame`, or the empty slot it would go in
+fn find(table: &[Option<Shared>; MAX_SHARED_FRAMES], frame: PhysFrame) -> Result<usize, Option<usize>> {
+    let start = home(frame);
+    for i in 0..MAX_SHARED_FRAMES {
+        let index = (start + i) % MAX_SHARED_FRAMES;
+        match table[index] {
+            Some(shared) if shared.frame == frame => return Ok(index),
+            Some(_) => {}
+            None => return Err(Some(index)),
+        }
+    }
+    Err(None)
+}
+
+/// Empty `hole`, moving later entries of its probe run back into it
+fn remove(table: &mut [Option<Shared>; MAX_SHARED_FRAMES], mut hole: usize) {
+    table[hole] = None;
+    let mut index = hole;
+    loop {
+        index = (index + 1) % MAX_SHARED_FRAMES;
+        let Some(shared) = table[index] else {
+            return;
+        };
+        let home = home(shared.frame);
+        let in_place = if hole <= index {
+            hole < home && home <= index
+        } else {
+            hole < home || home <= index
+        };
+        if !in_place {
+            table[hole] = table[index].take();
+            hole = index;
+        }
+    }
+}
+
+/// Count one more mapping of `frame`
+pub fn share(frame: PhysFrame) -> Result<(), MapError> {
+    let mut table = SHARED.lock();
+    match find(&table, frame) {
+        Ok(index) => {
+            if let Some(shared) = table[index].as_mut() {
+                shared.extra += 1;
+            }
+        }
+        Err(Some(index)) => table[index] = Some(Shared { frame, extra: 1 }),
+        Err(None) => return Err(MapError::OutOfMemory),
+    }
+    Ok(())
+}
+
+/// Count one mapping of `frame` less, returning whether it was the last
+/// one and the frame can be freed
+pub fn release(frame: PhysFrame) -> bool {
+    let mut table = SHARED.lock();
+    let Ok(index) = find(&table, frame) else {
+        return true;
+    };
+    match table[index].as_mut() {
+        Some(shared) if shared.extra > 1 => shared.extra -= 1,
+        _ => remove(&mut table, index),
+    }
+    false
+}
+
+/// Whether more than one mapping uses `frame`
+pub fn is_shared(frame: PhysFrame) -> bool {
+    find(&SHARED.lock(), frame).is_ok()
+}
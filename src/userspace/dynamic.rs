@@ -0,0 +1,386 @@
+//! Dynamic linking
+//!
+//! A program with a dynamic section is linked by the kernel as it loads,
+//! all at once rather than lazily. Each shared object the program needs
+//! (`DT_NEEDED`), and each those need in turn, is found in TagFS by its
+//! name as a tag and loaded at the next free place from a random page
+//! above [`LIBRARY_START`]. Then the relocations of every object are applied,
+//! the libraries' before the program's, resolving symbols in load order
+//! with the program first. A static position-independent program only
+//! has its own relative relocations to apply.
+//!
+//! What x86-64 code needs outside thread-local storage is handled: the
+//! `R_X86_64_64`, `GLOB_DAT`, `JUMP_SLOT`, `RELATIVE` and `COPY`
+//! relocations, with symbols looked up through the SysV or GNU hash
+//! table. Shared objects may not have TLS of their own, and their
+//! initializers are not run; zen-libc needs none.
+
+use arrayvec::{ArrayString, ArrayVec};
+use x86_64::VirtAddr;
+
+use super::address_space::AddressSpace;
+use super::elf::ElfError;
+use super::loader::{self, Image, IMAGE_END};
+use super::UserError;
+use crate::tagfs::{self, Tag};
+
+/// Where shared objects start to be loaded, before the random offset
+pub const LIBRARY_START: u64 = 0x20_0000_0000;
+
+/// Pages the first shared object may move up by, 16 GiB
+const LIBRARY_RANDOM_PAGES: u64 = 1 << 22;
+
+/// Objects in one program, the program included
+pub const MAX_OBJECTS: usize = 16;
+
+/// Shared objects one object may need
+const MAX_NEEDED: usize = 16;
+
+/// Dynamic section entries read from one object
+const MAX_DYNAMIC: usize = 256;
+
+/// Longest symbol or library name
+const MAX_NAME: usize = 128;
+
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_PLTRELSZ: u64 = 2;
+const DT_HASH: u64 = 4;
+const DT_STRTAB: u64 = 5;
+const DT_SYMTAB: u64 = 6;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_STRSZ: u64 = 10;
+const DT_JMPREL: u64 = 23;
+const DT_GNU_HASH: u64 = 0x6fff_fef5;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_COPY: u32 = 5;
+const R_X86_64_GLOB_DAT: u32 = 6;
+const R_X86_64_JUMP_SLOT: u32 = 7;
+const R_X86_64_RELATIVE: u32 = 8;
+
+const STB_LOCAL: u8 = 0;
+const STB_WEAK: u8 = 2;
+
+const SYM_SIZE: u64 = 24;
+const RELA_SIZE: u64 = 24;
+
+/// Alignment of each shared object's load address
+const LIBRARY_ALIGN: u64 = 0x20_0000;
+
+/// Symbol hash table of an object
+#[derive(Clone, Copy, Debug)]
+enum Hash {
+    Sysv(u64),
+    Gnu(u64),
+}
+
+/// What linking needs from one loaded object's dynamic section
+#[derive(Debug)]
+struct Object {
+    base: u64,
+    strtab: u64,
+    strsz: u64,
+    symtab: u64,
+    hash: Option<Hash>,
+    /// Address and size of the `DT_RELA` and `DT_JMPREL` tables
+    rela: (u64, u64),
+    jmprel: (u64, u64),
+    /// Names of the objects it needs, as string table offsets
+    needed: ArrayVec<u64, MAX_NEEDED>,
+}
+
+/// A symbol table entry
+#[derive(Clone, Copy, Debug)]
+struct Symbol {
+    name: u32,
+    bind: u8,
+    defined: bool,
+    value: u64,
+    size: u64,
+}
+
+fn dynamic_error() -> UserError {
+    UserError::InvalidProgram(ElfError::Dynamic)
+}
+
+fn read_u32(space: &mut AddressSpace, addr: u64) -> Result<u32, UserError> {
+    let mut bytes = [0u8; 4];
+    space.read(VirtAddr::try_new(addr).map_err(|_| dynamic_error())?, &mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(space: &mut AddressSpace, addr: u64) -> Result<u64, UserError> {
+    let mut bytes = [0u8; 8];
+    space.read(VirtAddr::try_new(addr).map_err(|_| dynamic_error())?, &mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// ELF's SysV symbol hash
+fn sysv_hash(name: &[u8]) -> u32 {
+    let mut h: u32 = 0;
+    for &c in name {
+        h = (h << 4).wrapping_add(c as u32);
+        let g = h & 0xf000_0000;
+        if g != 0 {
+            h ^= g >> 24;
+        }
+        h &= !g;
+    }
+    h
+}
+
+/// The GNU symbol hash
+fn gnu_hash(name: &[u8]) -> u32 {
+    name.iter().fold(5381u32, |h, &c| h.wrapping_mul(33).wrapping_add(c as u32))
+}
+
+impl Object {
+    /// Read the dynamic section at `dynamic` of an object loaded at `base`
+    fn read(space: &mut AddressSpace, base: u64, dynamic: u64) -> Result<Self, UserError> {
+        let mut object = Object {
+            base,
+            strtab: 0,
+            strsz: 0,
+            symtab: 0,
+            hash: None,
+            rela: (0, 0),
+            jmprel: (0, 0),
+            needed: ArrayVec::new(),
+        };
+        for i in 0..MAX_DYNAMIC as u64 {
+            let tag = read_u64(space, dynamic + i * 16)?;
+            let value = read_u64(space, dynamic + i * 16 + 8)?;
+            match tag {
+                DT_NULL => return Ok(object),
+                DT_NEEDED => object.needed.try_push(value).map_err(|_| dynamic_error())?,
+                DT_STRTAB => object.strtab = base + value,
+                DT_STRSZ => object.strsz = value,
+                DT_SYMTAB => object.symtab = base + value,
+                DT_HASH => object.hash = object.hash.or(Some(Hash::Sysv(base + value))),
+                DT_GNU_HASH => object.hash = Some(Hash::Gnu(base + value)),
+                DT_RELA => object.rela.0 = base + value,
+                DT_RELASZ => object.rela.1 = value,
+                DT_JMPREL => object.jmprel.0 = base + value,
+                DT_PLTRELSZ => object.jmprel.1 = value,
+                _ => {}
+            }
+        }
+        Err(dynamic_error())
+    }
+
+    /// The string at `offset` in the string table
+    fn string(&self, space: &mut AddressSpace, offset: u64) -> Result<ArrayString<MAX_NAME>, UserError> {
+        let len = self.strsz.checked_sub(offset).ok_or_else(dynamic_error)?.min(MAX_NAME as u64) as usize;
+        let mut bytes = [0u8; MAX_NAME];
+        space.read(VirtAddr::new(self.strtab + offset), &mut bytes[..len])?;
+        let end = bytes[..len].iter().position(|b| *b == 0).ok_or_else(dynamic_error)?;
+        let name = core::str::from_utf8(&bytes[..end]).map_err(|_| dynamic_error())?;
+        ArrayString::from(name).map_err(|_| dynamic_error())
+    }
+
+    fn symbol(&self, space: &mut AddressSpace, index: u64) -> Result<Symbol, UserError> {
+        let mut bytes = [0u8; SYM_SIZE as usize];
+        space.read(VirtAddr::new(self.symtab + index * SYM_SIZE), &mut bytes)?;
+        Ok(Symbol {
+            name: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            bind: bytes[4] >> 4,
+            defined: u16::from_le_bytes([bytes[6], bytes[7]]) != 0,
+            value: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            size: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+        })
+    }
+
+    /// Whether symbol `index` is a global definition of `name`
+    fn defines(&self, space: &mut AddressSpace, index: u64, name: &str) -> Result<Option<Symbol>, UserError> {
+        let symbol = self.symbol(space, index)?;
+        let found = symbol.defined && symbol.bind != STB_LOCAL && self.string(space, symbol.name as u64)?.as_str() == name;
+        Ok(found.then_some(symbol))
+    }
+
+    /// The global definition of `name` in this object, through its hash
+    /// table
+    fn lookup(&self, space: &mut AddressSpace, name: &str) -> Result<Option<Symbol>, UserError> {
+        match self.hash {
+            None => Ok(None),
+            Some(Hash::Sysv(table)) => {
+                let nbucket = read_u32(space, table)? as u64;
+                let nchain = read_u32(space, table + 4)? as u64;
+                if nbucket == 0 {
+                    return Ok(None);
+                }
+                let bucket = sysv_hash(name.as_bytes()) as u64 % nbucket;
+                let mut index = read_u32(space, table + 8 + bucket * 4)? as u64;
+                for _ in 0..nchain {
+                    if index == 0 {
+                        break;
+                    }
+                    if let Some(symbol) = self.defines(space, index, name)? {
+                        return Ok(Some(symbol));
+                    }
+                    index = read_u32(space, table + 8 + (nbucket + index) * 4)? as u64;
+                }
+                Ok(None)
+            }
+            Some(Hash::Gnu(table)) => {
+                let nbuckets = read_u32(space, table)? as u64;
+                let symoffset = read_u32(space, table + 4)? as u64;
+                let bloom_size = read_u32(space, table + 8)? as u64;
+                if nbuckets == 0 {
+                    return Ok(None);
+                }
+                let buckets = table + 16 + bloom_size * 8;
+                let chains = buckets + nbuckets * 4;
+                let hash = gnu_hash(name.as_bytes());
+                let mut index = read_u32(space, buckets + (hash as u64 % nbuckets) * 4)? as u64;
+                if index < symoffset {
+                    return Ok(None);
+                }
+                loop {
+                    let chain = read_u32(space, chains + (index - symoffset) * 4)?;
+                    if chain | 1 == hash | 1 {
+                        if let Some(symbol) = self.defines(space, index, name)? {
+                            return Ok(Some(symbol));
+                        }
+                    }
+                    if chain & 1 != 0 {
+                        return Ok(None);
+                    }
+                    index += 1;
+                }
+            }
+        }
+    }
+}
+
+/// The address `name` resolves to in `objects`, skipping the program
+/// itself when `skip_program` is set, with the definition's size
+fn resolve(
+    space: &mut AddressSpace,
+    objects: &[Object],
+    name: &str,
+    skip_program: bool,
+) -> Result<Option<(u64, u64)>, UserError> {
+    for object in objects.iter().skip(skip_program as usize) {
+        if let Some(symbol) = object.lookup(space, name)? {
+            return Ok(Some((object.base + symbol.value, symbol.size)));
+        }
+    }
+    Ok(None)
+}
+
+/// Apply one table of relocations of `objects[which]`
+fn relocate(
+    space: &mut AddressSpace,
+    objects: &[Object],
+    which: usize,
+    (table, size): (u64, u64),
+) -> Result<(), UserError> {
+    let object = &objects[which];
+    for i in 0..size / RELA_SIZE {
+        let entry = table + i * RELA_SIZE;
+        let offset = read_u64(space, entry)?;
+        let info = read_u64(space, entry + 8)?;
+        let addend = read_u64(space, entry + 16)?;
+        let kind = info as u32;
+        let target = VirtAddr::try_new(object.base + offset).map_err(|_| dynamic_error())?;
+
+        if kind == R_X86_64_NONE {
+            continue;
+        }
+        if kind == R_X86_64_RELATIVE {
+            space.write(target, &object.base.wrapping_add(addend).to_le_bytes())?;
+            continue;
+        }
+
+        let symbol = object.symbol(space, info >> 32)?;
+        let name = object.string(space, symbol.name as u64)?;
+        let resolved = if symbol.bind == STB_LOCAL && symbol.defined {
+            Some((object.base + symbol.value, symbol.size))
+        } else {
+            resolve(space, objects, &name, kind == R_X86_64_COPY)?
+        };
+        let (value, size) = match resolved {
+            Some(found) => found,
+            None if symbol.bind == STB_WEAK => (0, 0),
+            None => {
+                crate::klog!(Warn, "Undefined symbol {}", name.as_str());
+                return Err(dynamic_error());
+            }
+        };
+
+        match kind {
+            R_X86_64_64 => space.write(target, &value.wrapping_add(addend).to_le_bytes())?,
+            R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => space.write(target, &value.to_le_bytes())?,
+            R_X86_64_COPY => {
+                let mut chunk = [0u8; 256];
+                let mut done = 0;
+                while done < size.min(symbol.size) {
+                    let len = (size.min(symbol.size) - done).min(chunk.len() as u64) as usize;
+                    space.read(VirtAddr::new(value + done), &mut chunk[..len])?;
+                    space.write(target + done, &chunk[..len])?;
+                    done += len as u64;
+                }
+            }
+            _ => {
+                crate::klog!(Warn, "Unsupported relocation type {} for {}", kind, name.as_str());
+                return Err(dynamic_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Load the shared objects `program`, already mapped in `space`, needs
+/// and apply every object's relocations
+pub fn link(space: &mut AddressSpace, program: &Image) -> Result<(), UserError> {
+    let dynamic = program.dynamic.ok_or_else(dynamic_error)?;
+    let mut objects = ArrayVec::<Object, MAX_OBJECTS>::new();
+    let mut loaded = ArrayVec::<u64, MAX_OBJECTS>::new();
+    objects.push(Object::read(space, program.base, dynamic)?);
+    loaded.push(program.object_id);
+
+    let mut next = LIBRARY_START + loader::random_pages(LIBRARY_RANDOM_PAGES);
+    let mut i = 0;
+    while i < objects.len() {
+        for n in 0..objects[i].needed.len() {
+            let name = objects[i].string(space, objects[i].needed[n])?;
+            let Some(object_id) = tagfs::tagfs_query(&Tag::new(&name)) else {
+                crate::klog!(Warn, "Shared object {} not found", name.as_str());
+                return Err(dynamic_error());
+            };
+            if loaded.contains(&object_id) {
+                continue;
+            }
+
+            let mut library = Image::read(object_id)?;
+            library.place(next)?;
+            library.check()?;
+            if library.tls.is_some() {
+                crate::klog!(Warn, "Shared object {} has thread-local storage", name.as_str());
+                return Err(dynamic_error());
+            }
+            let Some(dynamic) = library.dynamic else {
+                return Err(dynamic_error());
+            };
+            library.map(space)?;
+            next = (library.extent().1 + LIBRARY_ALIGN - 1) & !(LIBRARY_ALIGN - 1);
+            if next > IMAGE_END {
+                return Err(UserError::BadAddress);
+            }
+
+            let object = Object::read(space, library.base, dynamic)?;
+            objects.try_push(object).map_err(|_| dynamic_error())?;
+            loaded.push(object_id);
+        }
+        i += 1;
+    }
+
+    for which in (0..objects.len()).rev() {
+        relocate(space, &objects, which, objects[which].rela)?;
+        relocate(space, &objects, which, objects[which].jmprel)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,261 @@
+//! ELF64 executables and shared objects
+//!
+//! Only what loading an x86-64 program needs is parsed: the file header
+//! and the program headers. Loadable segments come out with their
+//! protections, along with the thread-local storage template and where
+//! the dynamic section and the program headers themselves lie. A program
+//! may name an interpreter, but the kernel links dynamic programs itself
+//! (see [`super::dynamic`]), so only whether it names one is kept.
+//!
+//! A file is branded for Linux by its OS/ABI byte (`ELFOSABI_GNU`, which
+//! `brandelf -t Linux` sets) or by a GNU ABI tag note naming Linux, as
+//! glibc's startup files add.
+
+use super::tls::{TLS_END, TLS_START};
+
+/// "\x7fELF"
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+
+const CLASS_64: u8 = 2;
+const DATA_LSB: u8 = 1;
+/// Offset of the OS/ABI byte in the identification bytes
+const EI_OSABI: usize = 7;
+const ELFOSABI_GNU: u8 = 3;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_NOTE: u32 = 4;
+const PT_PHDR: u32 = 6;
+const PT_TLS: u32 = 7;
+
+/// Type of the GNU note naming the OS the file is built for
+const NT_GNU_ABI_TAG: u32 = 1;
+/// OS of that note
+const GNU_ABI_LINUX: u32 = 0;
+
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+/// Largest alignment a TLS template may ask for
+pub const MAX_TLS_ALIGN: u64 = 4096;
+
+/// Size of the ELF64 file header
+pub const HEADER_SIZE: usize = 64;
+
+/// Size of one ELF64 program header
+pub const PHDR_SIZE: usize = 56;
+
+/// Program headers accepted in one file
+pub const MAX_PHDRS: usize = 32;
+
+/// File header fields the loader uses
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
+    /// Whether the file can be loaded anywhere: a shared object or a
+    /// position-independent executable
+    pub relocatable: bool,
+    pub entry: u64,
+    /// File offset of the program header table
+    pub phoff: u64,
+    pub phnum: usize,
+    /// Whether the OS/ABI byte brands the file for Linux
+    pub linux: bool,
+}
+
+/// A `PT_LOAD` segment
+#[derive(Clone, Copy, Debug)]
+pub struct Segment {
+    pub vaddr: u64,
+    /// File offset of the bytes backing the segment
+    pub offset: u64,
+    pub file_size: u64,
+    /// Size in memory; the part past `file_size` is zero
+    pub mem_size: u64,
+    pub readable: bool,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+impl Segment {
+    /// Last virtual address the segment covers, exclusive
+    pub fn end(&self) -> u64 {
+        self.vaddr + self.mem_size
+    }
+}
+
+/// The `PT_TLS` segment: the initial contents of each thread's TLS block
+#[derive(Clone, Copy, Debug)]
+pub struct Tls {
+    /// Where the initialized part lies in the image
+    pub vaddr: u64,
+    pub file_size: u64,
+    /// Size of the block; the part past `file_size` is zero
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Check the file header of an x86-64 executable
+pub fn parse_header(data: &[u8]) -> Result<Header, ElfError> {
+    if data.len() < HEADER_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    if data[0..4] != ELF_MAGIC {
+        return Err(ElfError::BadMagic);
+    }
+    if data[4] != CLASS_64 || data[5] != DATA_LSB {
+        return Err(ElfError::Unsupported);
+    }
+    let kind = read_u16(data, 16);
+    if (kind != ET_EXEC && kind != ET_DYN) || read_u16(data, 18) != EM_X86_64 {
+        return Err(ElfError::Unsupported);
+    }
+    if read_u16(data, 54) as usize != PHDR_SIZE {
+        return Err(ElfError::Malformed);
+    }
+    let phnum = read_u16(data, 56) as usize;
+    if phnum == 0 || phnum > MAX_PHDRS {
+        return Err(ElfError::Malformed);
+    }
+    Ok(Header {
+        relocatable: kind == ET_DYN,
+        entry: read_u64(data, 24),
+        phoff: read_u64(data, 32),
+        phnum,
+        linux: data[EI_OSABI] == ELFOSABI_GNU,
+    })
+}
+
+/// Parse one program header, returning the segment if it is loadable
+pub fn parse_phdr(data: &[u8]) -> Result<Option<Segment>, ElfError> {
+    if data.len() < PHDR_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    let flags = read_u32(data, 4);
+    if read_u32(data, 0) != PT_LOAD {
+        return Ok(None);
+    }
+    let segment = Segment {
+        vaddr: read_u64(data, 16),
+        offset: read_u64(data, 8),
+        file_size: read_u64(data, 32),
+        mem_size: read_u64(data, 40),
+        readable: flags & PF_R != 0,
+        writable: flags & PF_W != 0,
+        executable: flags & PF_X != 0,
+    };
+    if segment.file_size > segment.mem_size
+        || segment.vaddr.checked_add(segment.mem_size).is_none()
+        || segment.offset.checked_add(segment.file_size).is_none()
+    {
+        return Err(ElfError::Malformed);
+    }
+    Ok(Some(segment))
+}
+
+/// Parse one program header, returning the address of the dynamic
+/// section if it is the `PT_DYNAMIC` one
+pub fn parse_dynamic(data: &[u8]) -> Result<Option<u64>, ElfError> {
+    if data.len() < PHDR_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    Ok((read_u32(data, 0) == PT_DYNAMIC).then(|| read_u64(data, 16)))
+}
+
+/// Parse one program header, returning the address of the program
+/// header table if it is the `PT_PHDR` one
+pub fn parse_phdr_table(data: &[u8]) -> Result<Option<u64>, ElfError> {
+    if data.len() < PHDR_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    Ok((read_u32(data, 0) == PT_PHDR).then(|| read_u64(data, 16)))
+}
+
+/// Parse one program header, returning whether it names an interpreter
+pub fn parse_interpreter(data: &[u8]) -> Result<bool, ElfError> {
+    if data.len() < PHDR_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    Ok(read_u32(data, 0) == PT_INTERP)
+}
+
+/// Parse one program header, returning the file offset and size of the
+/// notes if it is a `PT_NOTE` one
+pub fn parse_note(data: &[u8]) -> Result<Option<(u64, u64)>, ElfError> {
+    if data.len() < PHDR_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    Ok((read_u32(data, 0) == PT_NOTE).then(|| (read_u64(data, 8), read_u64(data, 32))))
+}
+
+/// Whether `notes`, the contents of a `PT_NOTE` segment, hold a GNU ABI
+/// tag naming Linux
+pub fn is_linux_note(mut notes: &[u8]) -> bool {
+    // Name size, description size and type, then both padded to 4 bytes
+    while notes.len() >= 12 {
+        let name_size = read_u32(notes, 0) as usize;
+        let desc_size = read_u32(notes, 4) as usize;
+        let name_end = 12 + name_size.next_multiple_of(4);
+        let Some(end) = name_end.checked_add(desc_size.next_multiple_of(4)).filter(|end| *end <= notes.len()) else {
+            return false;
+        };
+        let name = &notes[12..12 + name_size];
+        if read_u32(notes, 8) == NT_GNU_ABI_TAG && name == b"GNU\0" && desc_size >= 4 {
+            return read_u32(notes, name_end) == GNU_ABI_LINUX;
+        }
+        notes = &notes[end..];
+    }
+    false
+}
+
+/// Parse one program header, returning the TLS template if it is one
+pub fn parse_tls(data: &[u8]) -> Result<Option<Tls>, ElfError> {
+    if data.len() < PHDR_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    if read_u32(data, 0) != PT_TLS {
+        return Ok(None);
+    }
+    let tls = Tls {
+        vaddr: read_u64(data, 16),
+        file_size: read_u64(data, 32),
+        mem_size: read_u64(data, 40),
+        align: read_u64(data, 48).max(1),
+    };
+    if tls.file_size > tls.mem_size || tls.vaddr.checked_add(tls.file_size).is_none() {
+        return Err(ElfError::Malformed);
+    }
+    // Blocks must fit the region their slots are in
+    if tls.mem_size > TLS_END - TLS_START || !tls.align.is_power_of_two() || tls.align > MAX_TLS_ALIGN {
+        return Err(ElfError::Unsupported);
+    }
+    Ok(Some(tls))
+}
+
+/// ELF parsing errors
+#[derive(Debug)]
+pub enum ElfError {
+    BadMagic,
+    Truncated,
+    /// Not a little-endian 64-bit x86-64 executable
+    Unsupported,
+    /// Dynamic linking information the kernel cannot handle
+    Dynamic,
+    Malformed,
+}
@@ -0,0 +1,112 @@
+//! Exceptions raised in user mode
+//!
+//! A thread can register a handler for the exceptions it raises: divide
+//! errors, invalid opcodes, stack segment and general protection faults,
+//! page faults the kernel does not resolve itself, and SIMD floating-point
+//! errors. The kernel then pushes an [`ExceptionInfo`] onto the handler's
+//! stack, or below the red zone of the thread's own stack when it named
+//! none, and enters the handler as `handler(&info)`. The handler resumes
+//! the thread with `SYS_EXCEPTION_RETURN` from the registers in the info,
+//! which it may change to skip the faulting instruction or to unwind to a
+//! recovery point; it may also end the thread or the process.
+//!
+//! An exception with no handler to take it, raised inside the handler, or
+//! whose info cannot be pushed stops the process. The supervisor is sent a
+//! [`PROCESS_FAULT_MSG`](super::process::PROCESS_FAULT_MSG) describing it,
+//! and a parent waiting for the process sees `FAULT_STATUS` plus the
+//! vector.
+
+use core::mem::size_of;
+
+use x86_64::structures::paging::PageTableFlags as Flags;
+use x86_64::VirtAddr;
+
+use super::address_space::{AddressSpace, USER_END, USER_START};
+use super::cow::COW;
+use super::usermode::UserContext;
+
+/// Bytes below the stack pointer that leaf functions use without moving it
+const RED_ZONE: u64 = 128;
+
+/// RFLAGS bits a handler may change when it resumes a thread: the status
+/// flags and the direction flag
+const USER_FLAGS: u64 = 0xcd5;
+
+/// Where a thread's exceptions go
+#[derive(Clone, Copy, Debug)]
+pub struct Handler {
+    pub entry: u64,
+    /// Top of the stack to run the handler on, 0 for the thread's own
+    pub stack: u64,
+}
+
+/// What a handler is given, on its stack
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ExceptionInfo {
+    pub vector: u64,
+    /// Error code the CPU pushed, 0 for exceptions without one
+    pub error_code: u64,
+    /// Address a page fault was raised for, 0 for other exceptions
+    pub address: u64,
+    /// Registers when the exception was raised
+    pub context: UserContext,
+}
+
+/// Make `len` bytes at `addr` of `space` writable user memory, copying
+/// any copy-on-write pages among them
+fn writable(space: &mut AddressSpace, addr: u64, len: u64) -> bool {
+    let mut page = addr & !0xfff;
+    while page < addr + len {
+        let Some(mut flags) = space.flags(VirtAddr::new(page)) else {
+            return false;
+        };
+        if flags.contains(COW) && space.resolve_cow(VirtAddr::new(page)).is_ok_and(|copied| copied) {
+            flags |= Flags::WRITABLE;
+        }
+        if !flags.contains(Flags::USER_ACCESSIBLE | Flags::WRITABLE) {
+            return false;
+        }
+        page += 4096;
+    }
+    true
+}
+
+/// Push `info` for `handler` in `space`, returning the registers that
+/// enter the handler, or `None` if the stack cannot take it
+pub fn deliver(space: &mut AddressSpace, handler: &Handler, info: &ExceptionInfo) -> Option<UserContext> {
+    let top = match handler.stack {
+        0 => info.context.rsp.checked_sub(RED_ZONE)?,
+        stack => stack,
+    };
+    let addr = top.checked_sub(size_of::<ExceptionInfo>() as u64)? & !0xf;
+    if addr < USER_START + 8 || top > USER_END || !writable(space, addr - 8, top - addr + 8) {
+        return None;
+    }
+    let bytes = unsafe {
+        core::slice::from_raw_parts(info as *const ExceptionInfo as *const u8, size_of::<ExceptionInfo>())
+    };
+    space.write(VirtAddr::new(addr), bytes).ok()?;
+
+    // Entered as if called, with no return address to go back to
+    space.write(VirtAddr::new(addr - 8), &0u64.to_le_bytes()).ok()?;
+    Some(UserContext {
+        rdi: addr,
+        ..UserContext::new(handler.entry, addr - 8)
+    })
+}
+
+/// The registers a handler resumes its thread with, kept to ring 3, or
+/// `None` if they do not point into the user half
+pub fn resume(context: &UserContext) -> Option<UserContext> {
+    if !(USER_START..USER_END).contains(&context.rip) || context.rsp > USER_END {
+        return None;
+    }
+    let entry = UserContext::new(context.rip, context.rsp);
+    Some(UserContext {
+        rflags: entry.rflags | (context.rflags & USER_FLAGS),
+        cs: entry.cs,
+        ss: entry.ss,
+        ..*context
+    })
+}
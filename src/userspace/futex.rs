@@ -0,0 +1,160 @@
+//! Futexes
+//!
+//! A futex is a 32-bit word of a process's memory its threads wait on
+//! while it holds a value, and wake each other through. A waiting thread
+//! is recorded with the address it waits on and blocks; a wake marks
+//! waiters on the same address of the same process as woken and makes them
+//! ready, and each returns from its wait when it runs. A wait with a
+//! deadline polls instead of blocking, so it notices the deadline.
+//! Requeueing moves waiters to another address without waking them, so a
+//! condition variable's waiters do not all rush for its mutex at once.
+//!
+//! Waiters carry a bitset, and a wake only reaches those whose bitset
+//! shares a bit with its own; native waits and wakes use every bit.
+
+use spin::Mutex;
+
+use super::process;
+use super::uaccess;
+use super::usermode::{self, Exit, UserContext};
+use crate::capability::MAX_PROCESSES;
+use crate::scheduler;
+
+/// A bitset every waiter matches
+pub const ANY: u32 = u32::MAX;
+
+/// When a wait gives up
+#[derive(Clone, Copy, Debug)]
+pub enum Timeout {
+    /// This many ticks after the wait starts
+    After(u64),
+    /// At this tick
+    At(u64),
+}
+
+#[derive(Clone, Copy)]
+struct Waiter {
+    pid: u32,
+    /// Address the wait started on, which tells a repeated call apart from
+    /// a new one after a requeue
+    origin: u64,
+    addr: u64,
+    bitset: u32,
+    woken: bool,
+    /// Tick the wait times out at
+    deadline: Option<u64>,
+}
+
+/// Each thread's wait, by thread ID
+static WAITERS: Mutex<[Option<Waiter>; MAX_PROCESSES]> = Mutex::new([None; MAX_PROCESSES]);
+
+/// Futex errors
+#[derive(Debug)]
+pub enum FutexError {
+    /// An address not aligned to 4 bytes, or an empty bitset
+    Invalid,
+    /// The word no longer held the value
+    Changed,
+    TimedOut,
+    /// The word cannot be read
+    BadAddress,
+}
+
+/// Wait on `addr` in the calling thread while it holds `value`, until a
+/// wake matching `bitset` or the timeout; the call in `context` is
+/// repeated until the wait ends
+pub fn wait(
+    context: &UserContext,
+    addr: u64,
+    value: u32,
+    bitset: u32,
+    timeout: Option<Timeout>,
+) -> Result<(), FutexError> {
+    if !addr.is_multiple_of(4) || bitset == 0 {
+        return Err(FutexError::Invalid);
+    }
+    let (pid, tid) = (process::current(), process::current_thread());
+    let now = scheduler::ticks();
+    let mut waiters = WAITERS.lock();
+    let slot = waiters.get_mut(tid as usize).ok_or(FutexError::Invalid)?;
+    let waiter = match *slot {
+        Some(waiter) if waiter.pid == pid && waiter.origin == addr => waiter,
+        // A new wait, rather than this one being repeated
+        _ => Waiter {
+            pid,
+            origin: addr,
+            addr,
+            bitset,
+            woken: false,
+            deadline: timeout.map(|timeout| match timeout {
+                Timeout::After(ticks) => now.saturating_add(ticks),
+                Timeout::At(tick) => tick,
+            }),
+        },
+    };
+    if waiter.woken {
+        *slot = None;
+        return Ok(());
+    }
+    if waiter.deadline.is_some_and(|deadline| now >= deadline) {
+        *slot = None;
+        return Err(FutexError::TimedOut);
+    }
+    // A requeued waiter has already seen its value
+    if waiter.addr == addr {
+        let current: u32 = match uaccess::read_value(addr) {
+            Ok(current) => current,
+            Err(_) => {
+                *slot = None;
+                return Err(FutexError::BadAddress);
+            }
+        };
+        if current != value {
+            *slot = None;
+            return Err(FutexError::Changed);
+        }
+    }
+    *slot = Some(waiter);
+    drop(waiters);
+    match waiter.deadline {
+        Some(_) => usermode::leave(Exit::Yielded(context.restart())),
+        None => usermode::leave(Exit::Blocked(context.restart())),
+    }
+}
+
+/// Wake up to `count` waiters on `addr` in `pid` whose bitset meets
+/// `bitset`, returning how many there were
+pub fn wake(pid: u32, addr: u64, count: u64, bitset: u32) -> u64 {
+    let mut woken = 0;
+    let waiting = |w: &&mut Waiter| w.pid == pid && w.addr == addr && w.bitset & bitset != 0 && !w.woken;
+    let mut waiters = WAITERS.lock();
+    for (tid, slot) in waiters.iter_mut().enumerate() {
+        if woken == count {
+            break;
+        }
+        if let Some(waiter) = slot.as_mut().filter(waiting) {
+            waiter.woken = true;
+            process::wake_thread(tid as u32);
+            woken += 1;
+        }
+    }
+    woken
+}
+
+/// Wake up to `count` waiters on `from` in `pid` and move up to `moved`
+/// more to wait on `to`, returning how many were woken and how many moved
+pub fn requeue(pid: u32, from: u64, to: u64, count: u64, moved: u64) -> (u64, u64) {
+    let woken = wake(pid, from, count, ANY);
+    let mut requeued = 0;
+    let mut waiters = WAITERS.lock();
+    for waiter in waiters.iter_mut().flatten() {
+        if requeued == moved {
+            break;
+        }
+        if waiter.pid == pid && waiter.addr == from && !waiter.woken {
+            waiter.addr = to;
+            requeued += 1;
+        }
+    }
+    (woken, requeued)
+}
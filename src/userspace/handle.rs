@@ -0,0 +1,362 @@
+//! Handle tables
+//!
+//! A process reaches what it has opened through handles, small numbers
+//! indexing a table of its own: TagFS objects, IPC channels, GPU buffer
+//! objects, timers, the console's streams, pipe ends (see
+//! [`crate::compat::pipe`]), pseudo-terminal ends (see
+//! [`crate::compat::pty`]) and what the POSIX file system view opens (see
+//! [`crate::compat::vfs`]): directories, files being written, files on
+//! FAT32 volumes and the null device. Handles 0, 1 and 2 are standard
+//! input, output and error. A process the kernel starts gets the console
+//! for them; every other process starts with copies of all its parent's
+//! handles, whether it was forked or spawned.
+//!
+//! A handle names an open object, which duplicated and inherited handles
+//! share, read position included. The object is released when the last
+//! handle to it is closed, as all of a process's handles are when it
+//! exits: a buffer object the process created through its handle is
+//! destroyed, a timer stops, a file being written replaces its object and
+//! a pipe or pseudo-terminal loses an end. Channels and TagFS objects are
+//! named globally, so they outlive their handles.
+
+use spin::Mutex;
+
+use super::console;
+use crate::capability::MAX_PROCESSES;
+use crate::compat::pipe::{self, End};
+use crate::compat::pty;
+use crate::compat::vfs::{self, Path};
+use crate::gpu::bo::{self, BoHandle};
+
+/// Handles one process may hold
+pub const MAX_HANDLES: usize = 64;
+
+/// Objects open across all processes
+pub const MAX_OPEN: usize = 1024;
+
+/// Kinds of handle, as system calls name them
+pub const KIND_CONSOLE: u64 = 0;
+pub const KIND_OBJECT: u64 = 1;
+pub const KIND_CHANNEL: u64 = 2;
+pub const KIND_BUFFER: u64 = 3;
+pub const KIND_TIMER: u64 = 4;
+pub const KIND_PTY: u64 = 5;
+
+/// Deadline of a timer that will not expire again
+const DISARMED: u64 = u64::MAX;
+
+/// What a handle refers to
+///
+/// Objects are kept in a fixed table, so a directory's two paths set the
+/// size of every entry rather than being boxed.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Object {
+    /// A console stream, by its standard handle number
+    Console(u64),
+    /// A TagFS object, read from `offset` on
+    File { id: u64, offset: u64 },
+    Channel(u64),
+    /// A buffer object, read and written from `offset` on; `creator` is
+    /// the process that made it through this handle, which destroys it
+    Buffer {
+        bo: BoHandle,
+        creator: Option<u32>,
+        offset: u64,
+    },
+    /// Expires at `deadline`, in nanoseconds of the clock source's time,
+    /// then every `interval` nanoseconds unless that is 0
+    Timer { deadline: u64, interval: u64 },
+    /// A directory of the file system view, listed from the first entry
+    /// after `cursor`
+    Directory { path: Path, cursor: Path },
+    /// A file open for writing, by its staging slot
+    Staged { slot: usize, offset: u64 },
+    /// A file on a FAT32 volume, by where its directory entry lies, read
+    /// and written from `offset` on, or at its end with `append`
+    Fat {
+        volume: u32,
+        entry: u64,
+        offset: u64,
+        write: bool,
+        append: bool,
+    },
+    /// Reads as empty and takes any write
+    Null,
+    /// An end of a pipe, or of a socket pair, which reads from one pipe
+    /// and writes to the other
+    Pipe { read: Option<usize>, write: Option<usize> },
+    /// An end of a pseudo-terminal, by its number
+    Pty { pty: usize, end: pty::End },
+    /// The pseudo-terminal multiplexer, which opens as the master of a new
+    /// pseudo-terminal; no handle refers to it
+    Ptmx,
+}
+
+impl Object {
+    /// The kind system calls name it by
+    pub fn kind(&self) -> u64 {
+        match self {
+            Object::Console(_) => KIND_CONSOLE,
+            Object::File { .. } => KIND_OBJECT,
+            Object::Channel(_) | Object::Pipe { .. } => KIND_CHANNEL,
+            Object::Buffer { .. } => KIND_BUFFER,
+            Object::Timer { .. } => KIND_TIMER,
+            Object::Pty { .. } => KIND_PTY,
+            Object::Directory { .. }
+            | Object::Staged { .. }
+            | Object::Fat { .. }
+            | Object::Null
+            | Object::Ptmx => KIND_OBJECT,
+        }
+    }
+
+    /// A timer that first expires `delay` nanoseconds after `now`
+    pub fn timer(now: u64, delay: u64, interval: u64) -> Self {
+        Object::Timer {
+            deadline: now.saturating_add(delay),
+            interval,
+        }
+    }
+
+    /// The position of a file or buffer
+    pub fn offset_mut(&mut self) -> Option<&mut u64> {
+        match self {
+            Object::File { offset, .. }
+            | Object::Buffer { offset, .. }
+            | Object::Staged { offset, .. }
+            | Object::Fat { offset, .. } => Some(offset),
+            _ => None,
+        }
+    }
+
+    /// Move the position of a file or buffer on by `len` bytes
+    pub fn advance(&mut self, len: u64) {
+        if let Some(offset) = self.offset_mut() {
+            *offset += len;
+        }
+    }
+
+    /// Take the expirations of a timer due at `now`: how many, 0 if it is
+    /// not due yet, or `None` if it will not expire again
+    pub fn expire(&mut self, now: u64) -> Option<u64> {
+        let Object::Timer { deadline, interval } = self else {
+            return None;
+        };
+        if *deadline == DISARMED {
+            return None;
+        }
+        if now < *deadline {
+            return Some(0);
+        }
+        if *interval == 0 {
+            *deadline = DISARMED;
+            return Some(1);
+        }
+        let count = (now - *deadline) / *interval + 1;
+        *deadline += count * *interval;
+        Some(count)
+    }
+
+    /// Release what the object holds once no handle refers to it
+    pub fn release(self) {
+        match self {
+            Object::Buffer {
+                bo,
+                creator: Some(creator),
+                ..
+            } => {
+                let _ = bo::bo_destroy(bo, creator);
+            }
+            Object::Staged { slot, .. } => vfs::commit(slot),
+            Object::Pipe { read, write } => {
+                if let Some(pipe) = read {
+                    pipe::close(pipe, End::Read);
+                }
+                if let Some(pipe) = write {
+                    pipe::close(pipe, End::Write);
+                }
+            }
+            Object::Pty { pty, end } => pty::close(pty, end),
+            _ => {}
+        }
+    }
+}
+
+struct Open {
+    object: Object,
+    /// Handles referring to it
+    refs: u32,
+}
+
+type Table = [Option<u16>; MAX_HANDLES];
+
+struct Handles {
+    open: [Option<Open>; MAX_OPEN],
+    /// Each process's handles, as indexes into `open`
+    tables: [Option<Table>; MAX_PROCESSES],
+}
+
+static HANDLES: Mutex<Handles> = Mutex::new(Handles {
+    open: [const { None }; MAX_OPEN],
+    tables: [None; MAX_PROCESSES],
+});
+
+/// Handle table errors
+#[derive(Debug)]
+pub enum HandleError {
+    /// Not a handle the process holds
+    BadHandle,
+    TooManyHandles,
+    TooManyOpen,
+}
+
+impl Handles {
+    fn table(&mut self, pid: u32) -> Result<&mut Table, HandleError> {
+        self.tables
+            .get_mut(pid as usize)
+            .and_then(Option::as_mut)
+            .ok_or(HandleError::BadHandle)
+    }
+
+    fn index(&mut self, pid: u32, handle: u64) -> Result<u16, HandleError> {
+        let table = self.table(pid)?;
+        let slot = table.get(handle as usize).ok_or(HandleError::BadHandle)?;
+        slot.ok_or(HandleError::BadHandle)
+    }
+
+    fn add(&mut self, object: Object) -> Result<u16, HandleError> {
+        let index = self.open.iter().position(Option::is_none).ok_or(HandleError::TooManyOpen)?;
+        self.open[index] = Some(Open { object, refs: 1 });
+        Ok(index as u16)
+    }
+
+    fn hold(&mut self, index: u16) {
+        if let Some(open) = self.open[index as usize].as_mut() {
+            open.refs += 1;
+        }
+    }
+
+    fn drop_ref(&mut self, index: u16) {
+        let slot = &mut self.open[index as usize];
+        if let Some(open) = slot.as_mut() {
+            open.refs -= 1;
+            if open.refs == 0 {
+                open.object.release();
+                *slot = None;
+            }
+        }
+    }
+}
+
+/// Give a new process its handles: copies of its parent's, or the
+/// console's streams if the parent has no table
+pub fn inherit(parent: u32, child: u32) -> Result<(), HandleError> {
+    let mut handles = HANDLES.lock();
+    if child as usize >= MAX_PROCESSES {
+        return Err(HandleError::TooManyHandles);
+    }
+    let table = match handles.table(parent) {
+        Ok(table) => *table,
+        Err(_) => {
+            let mut table = [None; MAX_HANDLES];
+            for stream in [console::STDIN, console::STDOUT, console::STDERR] {
+                match handles.add(Object::Console(stream)) {
+                    Ok(index) => table[stream as usize] = Some(index),
+                    Err(e) => {
+                        for index in table.into_iter().flatten() {
+                            handles.drop_ref(index);
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            handles.tables[child as usize] = Some(table);
+            return Ok(());
+        }
+    };
+    for index in table.into_iter().flatten() {
+        handles.hold(index);
+    }
+    handles.tables[child as usize] = Some(table);
+    Ok(())
+}
+
+/// Close every handle of a process that has exited
+pub fn release_process(pid: u32) {
+    let mut handles = HANDLES.lock();
+    let Some(table) = handles.tables.get_mut(pid as usize).and_then(Option::take) else {
+        return;
+    };
+    for index in table.into_iter().flatten() {
+        handles.drop_ref(index);
+    }
+}
+
+/// Give `pid` the lowest free handle to `object`; the object is released
+/// if it cannot be
+pub fn open(pid: u32, object: Object) -> Result<u64, HandleError> {
+    let mut handles = HANDLES.lock();
+    let free = handles
+        .table(pid)
+        .and_then(|table| table.iter().position(Option::is_none).ok_or(HandleError::TooManyHandles));
+    let (handle, index) = match free.and_then(|handle| Ok((handle, handles.add(object)?))) {
+        Ok(found) => found,
+        Err(e) => {
+            object.release();
+            return Err(e);
+        }
+    };
+    handles.table(pid)?[handle] = Some(index);
+    Ok(handle as u64)
+}
+
+/// Close a handle of `pid`
+pub fn close(pid: u32, handle: u64) -> Result<(), HandleError> {
+    let mut handles = HANDLES.lock();
+    let index = handles.index(pid, handle)?;
+    handles.table(pid)?[handle as usize] = None;
+    handles.drop_ref(index);
+    Ok(())
+}
+
+/// Make another handle of `pid` to what `handle` refers to: `target`,
+/// closing what it held, or the lowest free one
+pub fn dup(pid: u32, handle: u64, target: Option<u64>) -> Result<u64, HandleError> {
+    let mut handles = HANDLES.lock();
+    let index = handles.index(pid, handle)?;
+    let target = match target {
+        Some(target) if target == handle => return Ok(target),
+        Some(target) if target as usize >= MAX_HANDLES => return Err(HandleError::BadHandle),
+        Some(target) => target as usize,
+        None => handles
+            .table(pid)?
+            .iter()
+            .position(Option::is_none)
+            .ok_or(HandleError::TooManyHandles)?,
+    };
+    handles.hold(index);
+    if let Some(old) = handles.table(pid)?[target].replace(index) {
+        handles.drop_ref(old);
+    }
+    Ok(target as u64)
+}
+
+/// What a handle of `pid` refers to
+pub fn get(pid: u32, handle: u64) -> Result<Object, HandleError> {
+    let mut handles = HANDLES.lock();
+    let index = handles.index(pid, handle)?;
+    handles.open[index as usize]
+        .as_ref()
+        .map(|open| open.object)
+        .ok_or(HandleError::BadHandle)
+}
+
+/// Change the object a handle of `pid` refers to, for every handle that
+/// shares it
+pub fn update<R>(pid: u32, handle: u64, f: impl FnOnce(&mut Object) -> R) -> Result<R, HandleError> {
+    let mut handles = HANDLES.lock();
+    let index = handles.index(pid, handle)?;
+    let open = handles.open[index as usize].as_mut().ok_or(HandleError::BadHandle)?;
+    Ok(f(&mut open.object))
+}
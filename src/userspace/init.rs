@@ -0,0 +1,353 @@
+//! Init and service supervision
+//!
+//! The kernel plays init until a userspace one can take over: at boot it
+//! reads the service manifest from TagFS, where the boot image's files
+//! live, and starts every service it lists as a process of its own. A
+//! service gets exactly the permissions the manifest declares, not the
+//! kernel's.
+//!
+//! Services are children of the kernel, so their exits arrive as
+//! process exit notifications on the supervisor's channel. A service that
+//! stops is started again after [`RESTART_DELAY_TICKS`], as its restart
+//! policy says; one that fails [`MAX_RESTARTS`] times without staying up
+//! for [`STABLE_TICKS`] is given up on. The same channel hears about
+//! storage devices crossing a health threshold, which are logged.
+//!
+//! The manifest is the object tagged `services` (or the tag given with the
+//! `services=` option). Each line names a service, the tag of its
+//! executable, its restart policy and its permissions, optionally followed
+//! by `--` and arguments; `#` starts a comment. A service's `argv` is its
+//! name followed by its arguments:
+//!
+//! ```text
+//! compositor  compositor  always      read write ipc-send ipc-recv gpu
+//! net         net         on-failure  read ipc-send ipc-recv network -- dhcp
+//! ai          ai-service  on-failure  read ipc-send ipc-recv ai
+//! ```
+
+use arrayvec::{ArrayString, ArrayVec};
+use spin::Mutex;
+
+use super::args::Strings;
+use super::process;
+use crate::capability::Permission;
+use crate::ipc::{self, IpcError};
+use crate::storage::smart;
+use crate::tagfs::{self, Tag};
+
+/// Services a manifest may list
+pub const MAX_SERVICES: usize = 16;
+
+/// Largest manifest read
+pub const MAX_MANIFEST_SIZE: usize = 4096;
+
+/// Bytes of arguments a service may have
+pub const MAX_SERVICE_ARGS: usize = 128;
+
+/// Manifest tag when `services=` is not given
+pub const DEFAULT_MANIFEST_TAG: &str = "services";
+
+/// Manifest tag when `services=` is not given and there is no
+/// [`DEFAULT_MANIFEST_TAG`], for a manifest in the initrd
+pub const BOOT_MANIFEST_TAG: &str = "boot:services";
+
+/// Ticks between a service stopping and starting again (about a second)
+pub const RESTART_DELAY_TICKS: u64 = crate::scheduler::TICKS_PER_SECOND;
+
+/// Failed starts in a row before a service is given up on
+pub const MAX_RESTARTS: u32 = 5;
+
+/// Ticks a service has to run for its failures to be forgotten
+pub const STABLE_TICKS: u64 = 30 * crate::scheduler::TICKS_PER_SECOND;
+
+/// When a service is started again after it stops
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Restart {
+    Always,
+    /// Only after a non-zero status or a fault
+    OnFailure,
+    Never,
+}
+
+impl Restart {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "always" => Some(Restart::Always),
+            "on-failure" => Some(Restart::OnFailure),
+            "never" => Some(Restart::Never),
+            _ => None,
+        }
+    }
+}
+
+/// Where a service is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServiceState {
+    Running(u32),
+    /// Waiting to be started at a tick
+    Restarting(u64),
+    /// Exited for good with a status
+    Stopped(i32),
+    /// Failed too often, or its executable is missing
+    Failed,
+}
+
+#[derive(Clone, Copy)]
+struct Service {
+    name: Tag,
+    executable: Tag,
+    restart: Restart,
+    permissions: u64,
+    /// Arguments after the name, separated by spaces
+    args: ArrayString<MAX_SERVICE_ARGS>,
+    state: ServiceState,
+    started_at: u64,
+    failures: u32,
+}
+
+/// Details of a service
+#[derive(Clone, Copy)]
+pub struct ServiceInfo {
+    pub name: Tag,
+    pub state: ServiceState,
+    pub restart: Restart,
+    pub permissions: u64,
+    pub failures: u32,
+}
+
+/// Channel exit notifications arrive on
+static CHANNEL: Mutex<Option<u64>> = Mutex::new(None);
+
+static SERVICES: Mutex<ArrayVec<Service, MAX_SERVICES>> = Mutex::new(ArrayVec::new_const());
+
+/// Parse one manifest line, `None` for blank and comment lines
+fn parse_line(line: &str) -> Option<Result<Service, InitError>> {
+    let line = line.split('#').next().unwrap_or("");
+    let mut words = line.split_whitespace();
+    let name = words.next()?;
+    let (Some(executable), Some(restart)) = (words.next(), words.next()) else {
+        return Some(Err(InitError::BadManifest));
+    };
+    let Some(restart) = Restart::from_name(restart) else {
+        return Some(Err(InitError::BadManifest));
+    };
+    let mut permissions = 0;
+    for word in words.by_ref().take_while(|w| *w != "--") {
+        match Permission::from_name(word) {
+            Some(permission) => permissions |= permission.bit(),
+            None => return Some(Err(InitError::BadManifest)),
+        }
+    }
+    let mut args = ArrayString::new();
+    for (i, word) in words.enumerate() {
+        if i > 0 && args.try_push(' ').is_err() || args.try_push_str(word).is_err() {
+            return Some(Err(InitError::BadManifest));
+        }
+    }
+    if name.len() > 32 || executable.len() > 32 {
+        return Some(Err(InitError::BadManifest));
+    }
+    Some(Ok(Service {
+        name: Tag::new(name),
+        executable: Tag::new(executable),
+        restart,
+        permissions,
+        args,
+        state: ServiceState::Restarting(0),
+        started_at: 0,
+        failures: 0,
+    }))
+}
+
+/// Read the manifest and start its services
+pub fn init() -> Result<(), InitError> {
+    let channel = ipc::create_channel()?;
+    *CHANNEL.lock() = Some(channel);
+    process::notify_exits(channel);
+    smart::subscribe(channel);
+
+    let tag = crate::boot::cmdline::get("services").unwrap_or_else(|| {
+        match tagfs::tagfs_query(&Tag::new(DEFAULT_MANIFEST_TAG)) {
+            Some(_) => DEFAULT_MANIFEST_TAG,
+            None => BOOT_MANIFEST_TAG,
+        }
+    });
+    let Some(object) = tagfs::tagfs_query(&Tag::new(tag)) else {
+        crate::klog!(Warn, "No service manifest tagged {}", tag);
+        return Ok(());
+    };
+    let mut buffer = [0u8; MAX_MANIFEST_SIZE];
+    let len = tagfs::tagfs_read(object, 0, &mut buffer).map_err(|_| InitError::BadManifest)?;
+    let manifest = core::str::from_utf8(&buffer[..len]).map_err(|_| InitError::BadManifest)?;
+
+    let mut services = SERVICES.lock();
+    for (number, line) in manifest.lines().enumerate() {
+        match parse_line(line) {
+            Some(Ok(service)) => services.try_push(service).map_err(|_| InitError::TooManyServices)?,
+            Some(Err(e)) => {
+                crate::klog!(Info, "Service manifest line {}: {:?}", number + 1, e);
+            }
+            None => {}
+        }
+    }
+    drop(services);
+
+    start_due();
+    Ok(())
+}
+
+/// Start `service`'s executable with its declared permissions
+fn start(service: &mut Service) {
+    let now = crate::scheduler::ticks();
+    let mut argv = Strings::new();
+    let result = core::iter::once(service.name.as_str())
+        .chain(service.args.split_whitespace())
+        .try_for_each(|arg| argv.push(arg))
+        .map_err(InitError::from)
+        .and_then(|()| tagfs::tagfs_query(&service.executable).ok_or(InitError::MissingExecutable))
+        .and_then(|object| Ok(process::spawn(object, process::INIT, &argv, &Strings::new())?))
+        .and_then(|pid| {
+            if crate::capability::set_tokens(pid, service.permissions).is_err() {
+                let _ = process::kill(pid, process::FAULT_STATUS);
+                return Err(InitError::Capability);
+            }
+            Ok(pid)
+        });
+    match result {
+        Ok(pid) => {
+            crate::klog!(Info, "Started service {} as process {}", service.name.as_str(), pid);
+            service.state = ServiceState::Running(pid);
+            service.started_at = now;
+        }
+        Err(e) => {
+            crate::klog!(Warn, "Service {} failed to start: {:?}", service.name.as_str(), e);
+            fail(service, now);
+        }
+    }
+}
+
+/// Count a failure of `service` and schedule its restart, unless it has
+/// failed too often
+fn fail(service: &mut Service, now: u64) {
+    service.failures += 1;
+    service.state = if service.failures >= MAX_RESTARTS {
+        crate::klog!(Warn, "Giving up on service {}", service.name.as_str());
+        ServiceState::Failed
+    } else {
+        ServiceState::Restarting(now + RESTART_DELAY_TICKS)
+    };
+}
+
+/// Note that the service running as `pid` exited with `status`
+fn exited(pid: u32, status: i32) {
+    let now = crate::scheduler::ticks();
+    let mut services = SERVICES.lock();
+    let Some(service) = services.iter_mut().find(|s| s.state == ServiceState::Running(pid)) else {
+        return;
+    };
+    crate::klog!(Info, "Service {} exited with status {}", service.name.as_str(), status);
+    if now - service.started_at >= STABLE_TICKS {
+        service.failures = 0;
+    }
+    match service.restart {
+        Restart::Always if status == 0 => service.state = ServiceState::Restarting(now + RESTART_DELAY_TICKS),
+        Restart::Always | Restart::OnFailure if status != 0 => fail(service, now),
+        _ => service.state = ServiceState::Stopped(status),
+    }
+}
+
+/// Note that an exception stopped the service running as `pid`; its exit
+/// follows
+fn faulted(pid: u32, vector: u64, rip: u64) {
+    let services = SERVICES.lock();
+    if let Some(service) = services.iter().find(|s| s.state == ServiceState::Running(pid)) {
+        crate::klog!(Warn, "Service {} raised exception {} at {:#x}", service.name.as_str(), vector, rip);
+    }
+}
+
+/// Warn that a storage device crossed the health thresholds in `flags`
+fn health_event(device: u32, flags: u32) {
+    let name = crate::storage::info(device).map_or("?", |info| info.name);
+    for (flag, what) in [
+        (smart::HEALTH_TEMPERATURE, "is too hot"),
+        (smart::HEALTH_WEAR, "is worn out"),
+        (smart::HEALTH_MEDIA_ERRORS, "has media errors"),
+        (smart::HEALTH_CRITICAL_WARNING, "raised a critical warning"),
+    ] {
+        if flags & flag != 0 {
+            crate::klog!(Warn, "Storage device {} ({}) {}", device, name, what);
+        }
+    }
+}
+
+fn start_due() {
+    let now = crate::scheduler::ticks();
+    for service in SERVICES.lock().iter_mut() {
+        if matches!(service.state, ServiceState::Restarting(at) if at <= now) {
+            start(service);
+        }
+    }
+}
+
+/// Handle exit, fault and health notifications and restart services that are due,
+/// from the idle loop
+pub fn poll() {
+    let Some(channel) = *CHANNEL.lock() else {
+        return;
+    };
+    let mut buffer = [0; ipc::MAX_MESSAGE_SIZE];
+    while let Ok((header, len)) = ipc::msg_recv(channel, &mut buffer) {
+        let data = &buffer[..len];
+        if data.len() < 8 {
+            continue;
+        }
+        let pid = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        let half = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+        let word = |i: usize| u64::from_le_bytes(data[i..i + 8].try_into().unwrap());
+        match header.msg_type {
+            process::PROCESS_EXIT_MSG => exited(pid, i32::from_le_bytes([data[4], data[5], data[6], data[7]])),
+            process::PROCESS_FAULT_MSG if data.len() >= 40 => faulted(pid, word(8), word(24)),
+            smart::HEALTH_EVENT_MSG => health_event(half(0), half(4)),
+            _ => {}
+        }
+    }
+    start_due();
+}
+
+/// Details of every service
+pub fn services() -> ArrayVec<ServiceInfo, MAX_SERVICES> {
+    SERVICES
+        .lock()
+        .iter()
+        .map(|s| ServiceInfo {
+            name: s.name,
+            state: s.state,
+            restart: s.restart,
+            permissions: s.permissions,
+            failures: s.failures,
+        })
+        .collect()
+}
+
+/// Init errors
+#[derive(Debug)]
+pub enum InitError {
+    BadManifest,
+    TooManyServices,
+    MissingExecutable,
+    Capability,
+    Ipc(IpcError),
+    User(super::UserError),
+}
+
+impl From<IpcError> for InitError {
+    fn from(e: IpcError) -> Self {
+        InitError::Ipc(e)
+    }
+}
+
+impl From<super::UserError> for InitError {
+    fn from(e: super::UserError) -> Self {
+        InitError::User(e)
+    }
+}
@@ -0,0 +1,372 @@
+//! Program loading
+//!
+//! An executable stored in TagFS is loaded into a fresh address space:
+//! each `PT_LOAD` segment is mapped page by page with the permissions it
+//! asks for (never executable unless it says so), its file bytes are read
+//! straight into the new frames and the rest is left zeroed. The stack
+//! at the top of the user half starts with the program's arguments and
+//! environment (see [`super::args`]) and grows as it is used (see
+//! [`super::stack`]).
+//! A program with a TLS template starts with its first thread's block
+//! set up (see [`super::tls`]), and one with a dynamic section is linked
+//! with the shared objects it needs (see [`super::dynamic`]).
+//! Position-independent executables load at [`PIE_BASE`] moved up by a
+//! random number of pages, and shared objects start a random distance
+//! above theirs, unless `norandmaps` is on the command line.
+//!
+//! A program branded for Linux (see [`super::elf`]) runs with the Linux
+//! personality, and an unbranded one with the personality it is loaded
+//! for: its parent's when spawned, the caller's on `exec`. A Linux
+//! program starts as Linux starts it. Its stack is laid out by
+//! [`crate::compat::linux::exec`], nothing is linked for it, as a static
+//! program relocates itself, and it sets up its own TLS block; one that
+//! names an interpreter is refused.
+
+use arrayvec::ArrayVec;
+use x86_64::structures::paging::{Page, PageTableFlags as Flags};
+use x86_64::VirtAddr;
+
+use super::address_space::{AddressSpace, USER_START};
+use super::args::Strings;
+use super::brk::Break;
+use super::dynamic;
+use super::elf::{self, ElfError, Segment, Tls, HEADER_SIZE, MAX_PHDRS, PHDR_SIZE};
+use super::process::Personality;
+use super::stack::{self, STACK_TOP};
+use super::tls::{self, TLS_START};
+use super::usermode::UserContext;
+use super::UserError;
+use crate::compat::linux;
+use crate::kernel::memory::MapError;
+use crate::tagfs;
+
+/// Segments must end below the TLS blocks, which lie below the stack
+pub const IMAGE_END: u64 = TLS_START;
+
+/// Where a position-independent executable is loaded, before the random
+/// offset
+pub const PIE_BASE: u64 = 0x40_0000;
+
+/// Pages the base of a position-independent executable may move by, 1 GiB
+const PIE_RANDOM_PAGES: u64 = 1 << 18;
+
+/// Bytes of notes read to look for a Linux brand
+const MAX_NOTES: usize = 256;
+
+/// An ELF file's loadable parts, at the addresses it asks for plus `base`
+#[derive(Clone, Debug)]
+pub struct Image {
+    pub object_id: u64,
+    /// Load bias of a relocatable file, 0 for one that loads where it says
+    pub base: u64,
+    pub relocatable: bool,
+    pub entry: u64,
+    pub segments: ArrayVec<Segment, MAX_PHDRS>,
+    pub tls: Option<Tls>,
+    /// Address of the dynamic section
+    pub dynamic: Option<u64>,
+    /// Address of the program headers once loaded, if a segment holds them
+    pub phdr: Option<u64>,
+    pub phnum: usize,
+    /// Whether the file names an interpreter
+    pub interpreter: bool,
+    /// Whether the file is branded for Linux
+    pub linux: bool,
+}
+
+impl Image {
+    /// Read the headers of the ELF file in a TagFS object
+    pub fn read(object_id: u64) -> Result<Self, UserError> {
+        let mut header = [0u8; HEADER_SIZE];
+        read_exact(object_id, 0, &mut header)?;
+        let header = elf::parse_header(&header)?;
+
+        let mut table = [0u8; MAX_PHDRS * PHDR_SIZE];
+        let table = &mut table[..header.phnum * PHDR_SIZE];
+        read_exact(object_id, header.phoff, table)?;
+
+        let mut image = Image {
+            object_id,
+            base: 0,
+            relocatable: header.relocatable,
+            entry: header.entry,
+            segments: ArrayVec::new(),
+            tls: None,
+            dynamic: None,
+            phdr: None,
+            phnum: header.phnum,
+            interpreter: false,
+            linux: header.linux,
+        };
+        let mut notes = ArrayVec::<(u64, u64), MAX_PHDRS>::new();
+        for phdr in table.chunks_exact(PHDR_SIZE) {
+            image.tls = elf::parse_tls(phdr)?.or(image.tls);
+            image.dynamic = elf::parse_dynamic(phdr)?.or(image.dynamic);
+            image.phdr = elf::parse_phdr_table(phdr)?.or(image.phdr);
+            image.interpreter |= elf::parse_interpreter(phdr)?;
+            if let Some(segment) = elf::parse_phdr(phdr)?.filter(|s| s.mem_size > 0) {
+                image.segments.push(segment);
+            }
+            if let Some(note) = elf::parse_note(phdr)? {
+                notes.push(note);
+            }
+        }
+        if image.segments.is_empty() {
+            return Err(UserError::InvalidProgram(ElfError::Malformed));
+        }
+        // Without a `PT_PHDR`, the headers are wherever the segment that
+        // maps their file bytes puts them
+        image.phdr = image.phdr.or_else(|| {
+            let table_end = header.phoff + (header.phnum * PHDR_SIZE) as u64;
+            image
+                .segments
+                .iter()
+                .find(|s| s.offset <= header.phoff && table_end <= s.offset + s.file_size)
+                .map(|s| s.vaddr + (header.phoff - s.offset))
+        });
+        for (offset, size) in notes {
+            let mut buffer = [0u8; MAX_NOTES];
+            let buffer = &mut buffer[..(size as usize).min(MAX_NOTES)];
+            read_exact(object_id, offset, buffer)?;
+            image.linux |= elf::is_linux_note(buffer);
+        }
+        Ok(image)
+    }
+
+    /// Lowest and highest addresses the segments cover, the highest
+    /// exclusive
+    pub fn extent(&self) -> (u64, u64) {
+        let start = self.segments.iter().map(|s| s.vaddr).min().unwrap_or(0);
+        let end = self.segments.iter().map(Segment::end).max().unwrap_or(0);
+        (start, end)
+    }
+
+    /// Move a relocatable image so that its first page loads at `at`
+    pub fn place(&mut self, at: u64) -> Result<(), UserError> {
+        let start = self.extent().0 & !0xfff;
+        let base = at.checked_sub(start).filter(|_| self.relocatable).ok_or(UserError::BadAddress)?;
+        self.base = base;
+        self.entry += base;
+        for segment in self.segments.iter_mut() {
+            segment.vaddr += base;
+        }
+        if let Some(tls) = self.tls.as_mut() {
+            tls.vaddr += base;
+        }
+        if let Some(dynamic) = self.dynamic.as_mut() {
+            *dynamic += base;
+        }
+        if let Some(phdr) = self.phdr.as_mut() {
+            *phdr += base;
+        }
+        Ok(())
+    }
+
+    /// Check that everything lies in the part of the user half images
+    /// may use
+    pub fn check(&self) -> Result<(), UserError> {
+        let (start, end) = self.extent();
+        if start < USER_START || end > IMAGE_END {
+            return Err(UserError::BadAddress);
+        }
+        if let Some(tls) = &self.tls {
+            if tls.vaddr < USER_START || tls.vaddr + tls.file_size > IMAGE_END {
+                return Err(UserError::BadAddress);
+            }
+        }
+        Ok(())
+    }
+
+    /// Map the segments into `space` and fill them from the file
+    pub fn map(&self, space: &mut AddressSpace) -> Result<(), UserError> {
+        for segment in &self.segments {
+            load_segment(self.object_id, space, segment)?;
+        }
+        Ok(())
+    }
+}
+
+/// A program ready to run
+pub struct Program {
+    pub space: AddressSpace,
+    pub entry: VirtAddr,
+    /// Initial stack pointer
+    pub stack_top: VirtAddr,
+    pub argc: u64,
+    pub argv: VirtAddr,
+    pub envp: VirtAddr,
+    /// TLS template, if the program has one
+    pub tls: Option<Tls>,
+    /// `fs` base of the first thread, 0 without TLS
+    pub fs_base: u64,
+    /// Empty data area after the executable
+    pub brk: Break,
+    /// The system call interface the program uses
+    pub personality: Personality,
+}
+
+impl Program {
+    /// Registers to start the program with
+    pub fn context(&self) -> UserContext {
+        let context = UserContext::new(self.entry.as_u64(), self.stack_top.as_u64());
+        match self.personality {
+            Personality::Native => UserContext {
+                rdi: self.argc,
+                rsi: self.argv.as_u64(),
+                rdx: self.envp.as_u64(),
+                ..context
+            },
+            // `rdx` would be a function for the program to call at exit
+            Personality::Linux => context,
+        }
+    }
+}
+
+/// Read exactly `buffer.len()` bytes at `offset`
+fn read_exact(object_id: u64, offset: u64, buffer: &mut [u8]) -> Result<(), UserError> {
+    let read = tagfs::tagfs_read(object_id, offset, buffer)?;
+    if read < buffer.len() {
+        return Err(UserError::InvalidProgram(ElfError::Truncated));
+    }
+    Ok(())
+}
+
+fn flags(segment: &Segment) -> Flags {
+    let mut flags = Flags::empty();
+    if segment.writable {
+        flags |= Flags::WRITABLE;
+    }
+    if !segment.executable {
+        flags |= Flags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// Map a segment and fill it from the file
+fn load_segment(object_id: u64, space: &mut AddressSpace, segment: &Segment) -> Result<(), UserError> {
+    let first = Page::containing_address(VirtAddr::new(segment.vaddr));
+    let last = Page::containing_address(VirtAddr::new(segment.end() - 1));
+    for page in Page::range_inclusive(first, last) {
+        space.map(page, flags(segment))?;
+    }
+
+    let mut done = 0;
+    while done < segment.file_size {
+        let bytes = space
+            .bytes_at(VirtAddr::new(segment.vaddr + done))
+            .ok_or(UserError::Map(MapError::MapFailed))?;
+        let len = (bytes.len() as u64).min(segment.file_size - done) as usize;
+        read_exact(object_id, segment.offset + done, &mut bytes[..len])?;
+        done += len as u64;
+    }
+    Ok(())
+}
+
+/// Copy the argument and environment strings to the top of the stack,
+/// with the vectors pointing at them below, returning the stack pointer
+/// and the addresses of `argv` and `envp`
+fn place_args(space: &mut AddressSpace, argv: &Strings, envp: &Strings) -> Result<(u64, u64, u64), MapError> {
+    let envp_strings = STACK_TOP - envp.packed().len() as u64;
+    let argv_strings = envp_strings - argv.packed().len() as u64;
+    space.write(VirtAddr::new(envp_strings), envp.packed())?;
+    space.write(VirtAddr::new(argv_strings), argv.packed())?;
+
+    // argc, argv, null, envp, null
+    let words = 1 + argv.count() + 1 + envp.count() + 1;
+    let sp = (argv_strings - words as u64 * 8) & !0xf;
+    let mut addr = sp;
+    let mut push = |space: &mut AddressSpace, value: u64| {
+        let result = space.write(VirtAddr::new(addr), &value.to_le_bytes());
+        addr += 8;
+        result
+    };
+    push(space, argv.count() as u64)?;
+    for offset in argv.offsets() {
+        push(space, argv_strings + offset as u64)?;
+    }
+    push(space, 0)?;
+    for offset in envp.offsets() {
+        push(space, envp_strings + offset as u64)?;
+    }
+    push(space, 0)?;
+
+    let argv_addr = sp + 8;
+    Ok((sp, argv_addr, argv_addr + (argv.count() as u64 + 1) * 8))
+}
+
+/// A random offset of fewer than `pages` pages, or none with `norandmaps`
+pub(super) fn random_pages(pages: u64) -> u64 {
+    if crate::boot::cmdline::flag("norandmaps") {
+        return 0;
+    }
+    crate::kernel::random::below(pages) * 4096
+}
+
+/// Load the executable in a TagFS object, with arguments and environment
+/// on its stack, for `personality` unless it is branded for Linux
+///
+/// On failure the partly built address space is torn down again.
+pub fn load(object_id: u64, argv: &Strings, envp: &Strings, personality: Personality) -> Result<Program, UserError> {
+    let mut image = Image::read(object_id)?;
+    let personality = if image.linux { Personality::Linux } else { personality };
+    if personality == Personality::Linux && image.interpreter {
+        return Err(UserError::InvalidProgram(ElfError::Dynamic));
+    }
+    if image.relocatable {
+        image.place(PIE_BASE + random_pages(PIE_RANDOM_PAGES))?;
+    }
+    image.check()?;
+    let entry_ok = image
+        .segments
+        .iter()
+        .any(|s| s.executable && (s.vaddr..s.end()).contains(&image.entry));
+    if !entry_ok {
+        return Err(UserError::BadAddress);
+    }
+
+    let mut space = AddressSpace::new()?;
+    match build(&mut space, &image, argv, envp, personality) {
+        Ok(((sp, argv_addr, envp_addr), fs_base)) => Ok(Program {
+            space,
+            entry: VirtAddr::new(image.entry),
+            stack_top: VirtAddr::new(sp),
+            argc: argv.count() as u64,
+            argv: VirtAddr::new(argv_addr),
+            envp: VirtAddr::new(envp_addr),
+            tls: image.tls,
+            fs_base,
+            brk: Break::new(image.extent().1),
+            personality,
+        }),
+        Err(e) => {
+            space.destroy();
+            Err(e)
+        }
+    }
+}
+
+/// Fill a new address space with the program and its libraries, the
+/// first TLS block and the stack, returning where the stack starts and
+/// the `fs` base
+fn build(
+    space: &mut AddressSpace,
+    image: &Image,
+    argv: &Strings,
+    envp: &Strings,
+    personality: Personality,
+) -> Result<((u64, u64, u64), u64), UserError> {
+    image.map(space)?;
+    if personality == Personality::Linux {
+        stack::map_initial(space)?;
+        return Ok((linux::exec::place_args(space, image, argv, envp)?, 0));
+    }
+    if image.dynamic.is_some() {
+        dynamic::link(space, image)?;
+    }
+    let fs_base = match &image.tls {
+        Some(tls) => tls::setup(space, tls, 0)?,
+        None => 0,
+    };
+    stack::map_initial(space)?;
+    Ok((place_args(space, argv, envp)?, fs_base))
+}
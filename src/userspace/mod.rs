@@ -1,7 +1,92 @@
 //! Userspace environment and system call interface
+//!
+//! Programs are ELF64 executables stored in TagFS. Loading one
+//! builds a fresh address space with its segments and an initial stack;
+//! programs run in ring 3, and an exception they raise goes to a handler
+//! the program registered or stops the program, never the kernel.
+//! Programs reach the kernel through the numbered system calls in
+//! [`syscall`], and run as processes, each in one or more threads, that
+//! can start, replace and wait for others. System services are processes
+//! [`init`] starts from a manifest and restarts when they fail.
+
+pub mod address_space;
+pub mod args;
+pub mod brk;
+pub mod console;
+pub mod cow;
+pub mod dynamic;
+pub mod elf;
+pub mod exception;
+pub mod futex;
+pub mod handle;
+pub mod init;
+pub mod loader;
+pub mod process;
+pub mod stack;
+pub mod syscall;
+pub mod tls;
+pub mod uaccess;
+pub mod usermode;
+
+use crate::kernel::memory::MapError;
 
 /// Initialize userspace environment
 pub fn init() {
-    // TODO: Set up userspace memory regions
-    // TODO: Load initial userspace programs
+    syscall::init();
+    if let Err(e) = init::init() {
+        crate::klog!(Error, "Service supervisor failed to start: {:?}", e);
+    }
+}
+
+/// Load the executable stored in a TagFS object
+pub fn load_program(object_id: u64, argv: &args::Strings, envp: &args::Strings) -> Result<loader::Program, UserError> {
+    loader::load(object_id, argv, envp, process::Personality::Native)
+}
+
+/// Start the executable in a TagFS object as a new process with the given
+/// arguments and no environment, returning its process ID
+pub fn spawn(object_id: u64, argv: &args::Strings) -> Result<u32, UserError> {
+    process::spawn(object_id, process::INIT, argv, &args::Strings::new())
+}
+
+/// Run ready processes, from the idle loop
+pub fn poll() {
+    init::poll();
+    process::poll();
+}
+
+/// Userspace errors
+#[derive(Debug)]
+pub enum UserError {
+    InvalidProgram(elf::ElfError),
+    /// A segment or the entry point lies outside the user half
+    BadAddress,
+    Map(MapError),
+    Storage(crate::tagfs::TagFsError),
+    TooManyProcesses,
+    NoSuchProcess,
+    /// Argument or environment strings are malformed or too large
+    BadArguments,
+    TooManyThreads,
+    NoSuchThread,
+    /// A thread priority or affinity out of range
+    BadSetting,
+}
+
+impl From<elf::ElfError> for UserError {
+    fn from(e: elf::ElfError) -> Self {
+        UserError::InvalidProgram(e)
+    }
+}
+
+impl From<MapError> for UserError {
+    fn from(e: MapError) -> Self {
+        UserError::Map(e)
+    }
+}
+
+impl From<crate::tagfs::TagFsError> for UserError {
+    fn from(e: crate::tagfs::TagFsError) -> Self {
+        UserError::Storage(e)
+    }
 }
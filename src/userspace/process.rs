@@ -0,0 +1,1063 @@
+//! Processes and threads
+//!
+//! A process is a program image in its own address space, run by one or
+//! more threads. Each thread has its own stack, TLS block and the user
+//! registers it last stopped with; the first one's ID is the process ID.
+//! Ready threads take turns from the idle loop by stride scheduling, each
+//! running until its slice ends or it yields, blocks or exits. A thread's
+//! stride is its priority (smaller runs more often), and its affinity
+//! mask names the CPUs, among the first 64, that may run it. Only the
+//! bootstrap processor's idle loop runs user threads for now, so a mask
+//! has to include it.
+//!
+//! A thread that exits stays behind with its status until another thread
+//! of the process joins it; the last thread to exit ends the process. A
+//! process that exits gives back its address space, capability tokens
+//! and grants at once, and stays behind as a zombie holding its status
+//! until its parent collects it with `wait`. A parent keeps at most
+//! [`MAX_ZOMBIES`] uncollected children; past that the oldest status is
+//! dropped, so a parent that never waits cannot fill the table.
+//!
+//! Processes the kernel started are children of [`INIT`], the service
+//! supervisor, and so are orphans, whose parent exited first. Their
+//! exits are reported on the channel given to [`notify_exits`] instead of
+//! being waited for, along with every exception that stops a process
+//! (see [`super::exception`]).
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::FsBase;
+use x86_64::VirtAddr;
+
+use super::address_space::AddressSpace;
+use super::args::Strings;
+use super::brk::Break;
+use super::elf::Tls;
+use super::exception::{self, ExceptionInfo, Handler};
+use super::handle;
+use super::loader::{self, Program};
+use super::stack::{self, STACK_OVERFLOW};
+use super::tls;
+use super::usermode::{self, Exit, UserContext};
+use super::UserError;
+use crate::capability;
+use crate::compat::vfs::Path;
+use crate::ipc::{self, grant, MessageHeader};
+use crate::kernel::pmu;
+use crate::scheduler::TaskDesc;
+use crate::storage::aio;
+
+/// Processes that can exist at once, zombies included
+pub const MAX_PROCESSES: usize = 64;
+
+/// Threads that can exist at once, across all processes
+pub const MAX_THREADS: usize = 256;
+
+/// Stride of a new process's first thread
+pub const DEFAULT_STRIDE: u32 = 100;
+
+/// Strides a thread may be given
+pub const MIN_STRIDE: u32 = 10;
+pub const MAX_STRIDE: u32 = 10_000;
+
+/// CPUs that run user threads: the application processors only halt in
+/// their idle loop
+pub const USER_CPUS: u64 = 1;
+
+/// Parent of the processes the kernel started and of orphans
+pub const INIT: u32 = 0;
+
+/// Uncollected exited children one parent may keep
+pub const MAX_ZOMBIES: usize = 16;
+
+/// Status reported for a process stopped by an exception: this plus the
+/// exception vector
+pub const FAULT_STATUS: i32 = 128;
+
+/// Sent when a process the kernel started exits; payload: process ID
+/// (u32), status (i32)
+pub const PROCESS_EXIT_MSG: u32 = 0x5052_0001;
+
+/// Sent when an exception stops a process; payload: process ID (u32),
+/// thread ID (u32), vector (u64), error code (u64), instruction pointer
+/// (u64), faulting address (u64)
+pub const PROCESS_FAULT_MSG: u32 = 0x5052_0002;
+
+/// Where a process is in its life, summed up from its threads
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    /// Every thread is waiting, for a child or another thread
+    Waiting,
+    /// Exited with a status its parent has not collected
+    Zombie(i32),
+}
+
+/// System call interface a process uses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Personality {
+    Native,
+    /// Linux's x86-64 system calls, emulated by [`crate::compat::linux`]
+    Linux,
+}
+
+/// Where a thread is in its life
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    /// Blocked in a system call it repeats when woken
+    Waiting,
+    /// Exited with a status no thread has joined yet
+    Exited(i32),
+}
+
+struct Process {
+    pid: u32,
+    parent: u32,
+    /// Exit status once the process has exited
+    zombie: Option<i32>,
+    /// When it exited, counted in exits, to find the oldest zombie
+    exited: u64,
+    /// `None` once the process has exited
+    space: Option<AddressSpace>,
+    /// Template of its threads' TLS blocks
+    tls: Option<Tls>,
+    /// Size the main stack may grow to
+    stack_limit: u64,
+    brk: Break,
+    personality: Personality,
+    /// Working directory of the file system view
+    cwd: Path,
+}
+
+struct Thread {
+    tid: u32,
+    pid: u32,
+    state: ThreadState,
+    context: UserContext,
+    fs_base: u64,
+    /// Slot of its TLS block
+    tls_slot: usize,
+    /// Stride scheduling state
+    task: TaskDesc,
+    /// CPUs that may run the thread, one bit each
+    affinity: u64,
+    /// Where its exceptions go
+    handler: Option<Handler>,
+    /// Whether the handler is running
+    handling: bool,
+    /// Removed as soon as it exits, with no one to join it
+    detached: bool,
+    /// Whether the performance counters run while it does
+    counting: bool,
+    /// What they have counted for it
+    counts: pmu::Counts,
+}
+
+struct Table {
+    processes: [Option<Process>; MAX_PROCESSES],
+    threads: [Option<Thread>; MAX_THREADS],
+    next_id: u32,
+    exits: u64,
+}
+
+static TABLE: Mutex<Table> = Mutex::new(Table {
+    processes: [const { None }; MAX_PROCESSES],
+    threads: [const { None }; MAX_THREADS],
+    next_id: 1,
+    exits: 0,
+});
+
+/// Thread running on this CPU, 0 while the kernel is
+static CURRENT_THREAD: AtomicU32 = AtomicU32::new(0);
+
+/// Process of the running thread
+static CURRENT: AtomicU32 = AtomicU32::new(0);
+
+/// Whether the running thread's process uses the Linux personality
+static CURRENT_LINUX: AtomicBool = AtomicBool::new(false);
+
+/// Channel told about exits of processes the kernel started
+static EXIT_CHANNEL: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Details of a process
+#[derive(Clone, Copy, Debug)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub parent: u32,
+    pub state: State,
+    pub threads: usize,
+}
+
+impl Table {
+    fn get_mut(&mut self, pid: u32) -> Option<&mut Process> {
+        self.processes.iter_mut().flatten().find(|p| p.pid == pid)
+    }
+
+    fn thread_mut(&mut self, tid: u32) -> Option<&mut Thread> {
+        self.threads.iter_mut().flatten().find(|t| t.tid == tid)
+    }
+
+    /// An unused process or thread ID; 0 is the kernel, and process IDs
+    /// index token storage
+    fn allocate_id(&mut self) -> u32 {
+        loop {
+            let id = self.next_id;
+            self.next_id = if id as usize + 1 >= capability::MAX_PROCESSES { 1 } else { id + 1 };
+            let used = self.processes.iter().flatten().any(|p| p.pid == id)
+                || self.threads.iter().flatten().any(|t| t.tid == id);
+            if !used {
+                return id;
+            }
+        }
+    }
+
+    fn remove(&mut self, pid: u32) {
+        for slot in self.processes.iter_mut() {
+            if slot.as_ref().is_some_and(|p| p.pid == pid) {
+                *slot = None;
+            }
+        }
+    }
+
+    fn remove_threads(&mut self, pid: u32, except: u32) {
+        for slot in self.threads.iter_mut() {
+            if slot.as_ref().is_some_and(|t| t.pid == pid && t.tid != except) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Let the waiting threads of `pid` repeat their calls
+    fn wake(&mut self, pid: u32) {
+        for thread in self.threads.iter_mut().flatten() {
+            if thread.pid == pid && thread.state == ThreadState::Waiting {
+                thread.state = ThreadState::Ready;
+            }
+        }
+    }
+
+    /// Add a thread, starting from the lowest pass so it is not starved
+    fn add_thread(&mut self, thread: Thread) -> Result<(), UserError> {
+        let index = self
+            .threads
+            .iter()
+            .position(|t| t.is_none())
+            .ok_or(UserError::TooManyThreads)?;
+        let pass = self.threads.iter().flatten().map(|t| t.task.pass).min().unwrap_or(0);
+        self.threads[index] = Some(Thread {
+            task: TaskDesc { pass, ..thread.task },
+            ..thread
+        });
+        Ok(())
+    }
+}
+
+fn thread(tid: u32, pid: u32, context: UserContext, stride: u32, affinity: u64) -> Thread {
+    Thread {
+        tid,
+        pid,
+        state: ThreadState::Ready,
+        context,
+        fs_base: 0,
+        tls_slot: 0,
+        task: TaskDesc::new(tid, stride),
+        affinity,
+        handler: None,
+        handling: false,
+        detached: false,
+        counting: false,
+        counts: [0; pmu::COUNTERS],
+    }
+}
+
+/// Process of the thread running on this CPU, 0 while the kernel is
+pub fn current() -> u32 {
+    CURRENT.load(Ordering::Relaxed)
+}
+
+/// Thread running on this CPU, 0 while the kernel is
+pub fn current_thread() -> u32 {
+    CURRENT_THREAD.load(Ordering::Relaxed)
+}
+
+/// Personality of the running thread's process
+pub fn current_personality() -> Personality {
+    match CURRENT_LINUX.load(Ordering::Relaxed) {
+        true => Personality::Linux,
+        false => Personality::Native,
+    }
+}
+
+/// Report exits of processes the kernel started on `channel`
+pub fn notify_exits(channel: u64) {
+    *EXIT_CHANNEL.lock() = Some(channel);
+}
+
+/// Send a message about `pid` on the exit channel
+fn report(msg_type: u32, pid: u32, payload: &[u8]) {
+    let Some(channel) = *EXIT_CHANNEL.lock() else {
+        return;
+    };
+    let header = MessageHeader {
+        id: 0,
+        sender: 0,
+        receiver: 0,
+        length: payload.len() as u32,
+        msg_type,
+    };
+    if ipc::msg_send(channel, header, payload).is_err() {
+        crate::klog!(Warn, "Message {:#x} about process {} not reported", msg_type, pid);
+    }
+}
+
+fn send_exit(pid: u32, status: i32) {
+    let mut payload = [0u8; 8];
+    payload[..4].copy_from_slice(&pid.to_le_bytes());
+    payload[4..].copy_from_slice(&status.to_le_bytes());
+    report(PROCESS_EXIT_MSG, pid, &payload);
+}
+
+fn send_fault(pid: u32, tid: u32, info: &ExceptionInfo) {
+    let mut payload = [0u8; 40];
+    payload[..4].copy_from_slice(&pid.to_le_bytes());
+    payload[4..8].copy_from_slice(&tid.to_le_bytes());
+    let words = [info.vector, info.error_code, info.context.rip, info.address];
+    for (chunk, word) in payload[8..].chunks_exact_mut(8).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    report(PROCESS_FAULT_MSG, pid, &payload);
+}
+
+fn new_process(parent: u32, space: AddressSpace, brk: Break) -> Process {
+    Process {
+        pid: 0,
+        parent,
+        zombie: None,
+        exited: 0,
+        space: Some(space),
+        tls: None,
+        stack_limit: stack::DEFAULT_LIMIT,
+        brk,
+        personality: Personality::Native,
+        cwd: Path::new(),
+    }
+}
+
+/// Add `process` running in the one thread `main`, whose IDs are filled
+/// in, undoing everything if it cannot be added
+fn add_process(table: &mut Table, mut process: Process, main: Thread) -> Result<u32, UserError> {
+    let destroy = |mut process: Process| {
+        if let Some(space) = process.space.take() {
+            space.destroy();
+        }
+    };
+    let Some(index) = table.processes.iter().position(|p| p.is_none()) else {
+        destroy(process);
+        return Err(UserError::TooManyProcesses);
+    };
+    let pid = table.allocate_id();
+    if capability::inherit_tokens(process.parent, pid).is_err() {
+        destroy(process);
+        return Err(UserError::TooManyProcesses);
+    }
+    if handle::inherit(process.parent, pid).is_err() {
+        capability::drop_tokens(pid);
+        destroy(process);
+        return Err(UserError::TooManyProcesses);
+    }
+    let main = Thread {
+        tid: pid,
+        pid,
+        task: TaskDesc::new(pid, main.task.stride),
+        ..main
+    };
+    if let Err(e) = table.add_thread(main) {
+        handle::release_process(pid);
+        capability::drop_tokens(pid);
+        destroy(process);
+        return Err(e);
+    }
+    process.pid = pid;
+    table.processes[index] = Some(process);
+    Ok(pid)
+}
+
+/// Start the executable in a TagFS object as a child of `parent`, with
+/// the parent's personality unless the executable is branded for one
+pub fn spawn(object_id: u64, parent: u32, argv: &Strings, envp: &Strings) -> Result<u32, UserError> {
+    let personality = TABLE.lock().get_mut(parent).map_or(Personality::Native, |p| p.personality);
+    let program = loader::load(object_id, argv, envp, personality)?;
+    let main = Thread {
+        fs_base: program.fs_base,
+        ..thread(0, 0, program.context(), DEFAULT_STRIDE, u64::MAX)
+    };
+    let process = Process {
+        tls: program.tls,
+        personality: program.personality,
+        ..new_process(parent, program.space, program.brk)
+    };
+    add_process(&mut TABLE.lock(), process, main)
+}
+
+/// Fork the process of the running thread `tid`, whose registers are
+/// `context`, returning the child's process ID
+///
+/// The child shares the parent's pages copy-on-write and gets copies of
+/// its capability tokens and handles; channels are named by global IDs,
+/// so it can use every channel the parent knows. Its one thread is a copy
+/// of the calling thread, TLS block included, and resumes from the same
+/// point with 0 in `rax`.
+pub fn fork(tid: u32, context: &UserContext) -> Result<u32, UserError> {
+    let mut table = TABLE.lock();
+    let caller = table.thread_mut(tid).ok_or(UserError::NoSuchProcess)?;
+    let (pid, stride, affinity) = (caller.pid, caller.task.stride, caller.affinity);
+    let (tls_slot, handler, handling) = (caller.tls_slot, caller.handler, caller.handling);
+    let process = table.get_mut(pid).ok_or(UserError::NoSuchProcess)?;
+    let (tls, stack_limit, brk, personality) = (process.tls, process.stack_limit, process.brk, process.personality);
+    let cwd = process.cwd;
+    let space = process.space.as_mut().ok_or(UserError::NoSuchProcess)?.fork()?;
+    // The parent's writable pages are now read-only
+    tlb::flush_all();
+
+    let main = Thread {
+        fs_base: FsBase::read().as_u64(),
+        tls_slot,
+        handler,
+        handling,
+        ..thread(0, 0, UserContext { rax: 0, ..*context }, stride, affinity)
+    };
+    let child = Process {
+        tls,
+        stack_limit,
+        personality,
+        cwd,
+        ..new_process(pid, space, brk)
+    };
+    add_process(&mut table, child, main)
+}
+
+/// Replace the image of the process of the running thread `tid`,
+/// returning the registers to start the new one with
+///
+/// The process's other threads end, and the caller starts over with the
+/// new image's first TLS block; the stack limit is kept. The old image stays in place if the new
+/// one cannot be loaded. The process keeps its personality unless the new
+/// image is branded for one.
+pub fn exec(tid: u32, object_id: u64, argv: &Strings, envp: &Strings) -> Result<UserContext, UserError> {
+    let program = loader::load(object_id, argv, envp, current_personality())?;
+    let context = program.context();
+    let Program {
+        space,
+        tls,
+        fs_base,
+        brk,
+        personality,
+        ..
+    } = program;
+
+    let mut table = TABLE.lock();
+    let pid = table.thread_mut(tid).map(|caller| caller.pid);
+    let Some(pid) = pid.filter(|&pid| table.get_mut(pid).is_some()) else {
+        drop(table);
+        space.destroy();
+        return Err(UserError::NoSuchProcess);
+    };
+    let (_, flags) = Cr3::read();
+    unsafe { Cr3::write(space.l4_frame(), flags) };
+    FsBase::write(VirtAddr::new(fs_base));
+
+    let caller = table.thread_mut(tid).expect("checked above");
+    caller.fs_base = fs_base;
+    caller.tls_slot = 0;
+    caller.handler = None;
+    caller.handling = false;
+    table.remove_threads(pid, tid);
+    let process = table.get_mut(pid).expect("checked above");
+    process.tls = tls;
+    process.brk = brk;
+    process.personality = personality;
+    let old = process.space.replace(space);
+    drop(table);
+    CURRENT_LINUX.store(personality == Personality::Linux, Ordering::Relaxed);
+    if let Some(old) = old {
+        old.destroy();
+    }
+    Ok(context)
+}
+
+/// Start a thread in the process of the running thread `tid`, at `entry`
+/// on the stack ending at `stack` with `arg` in `rdi`
+///
+/// The new thread has the creator's stride, affinity and exception
+/// handler, and a fresh TLS block if the program has a template.
+pub fn create_thread(tid: u32, entry: u64, stack: u64, arg: u64) -> Result<u32, UserError> {
+    let context = UserContext {
+        rdi: arg,
+        ..UserContext::new(entry, stack)
+    };
+    add_sibling(tid, context, None, false)
+}
+
+/// Start a detached thread in the process of the running thread `tid`
+/// with the registers `context`, and `fs_base` rather than a fresh TLS
+/// block if it is given
+pub fn clone_thread(tid: u32, context: UserContext, fs_base: Option<u64>) -> Result<u32, UserError> {
+    add_sibling(tid, context, fs_base, true)
+}
+
+fn add_sibling(tid: u32, context: UserContext, fs_base: Option<u64>, detached: bool) -> Result<u32, UserError> {
+    let mut table = TABLE.lock();
+    let (pid, stride, affinity, handler) = table
+        .thread_mut(tid)
+        .map(|t| (t.pid, t.task.stride, t.affinity, t.handler))
+        .ok_or(UserError::NoSuchProcess)?;
+    let tls_slot = (0..)
+        .find(|slot| !table.threads.iter().flatten().any(|t| t.pid == pid && t.tls_slot == *slot))
+        .unwrap_or(0);
+    let process = table.get_mut(pid).ok_or(UserError::NoSuchProcess)?;
+    let fs_base = match (fs_base, process.tls, process.space.as_mut()) {
+        (Some(fs_base), ..) => fs_base,
+        (None, Some(template), Some(space)) => tls::setup(space, &template, tls_slot)?,
+        _ => 0,
+    };
+
+    let new = table.allocate_id();
+    table.add_thread(Thread {
+        fs_base,
+        tls_slot,
+        // The creator's handler stack is its own
+        handler: handler.map(|h| Handler { stack: 0, ..h }),
+        detached,
+        ..thread(new, pid, context, stride, affinity)
+    })?;
+    Ok(new)
+}
+
+/// End the running thread `tid` with `status`; the last thread to end
+/// ends its process
+fn exit_thread(table: &mut Table, tid: u32, status: i32) {
+    let Some(pid) = table.thread_mut(tid).map(|t| t.pid) else {
+        return;
+    };
+    let others = table
+        .threads
+        .iter()
+        .flatten()
+        .any(|t| t.pid == pid && t.tid != tid && !matches!(t.state, ThreadState::Exited(_)));
+    if !others {
+        finish(table, pid, status);
+        return;
+    }
+    for slot in table.threads.iter_mut() {
+        match slot {
+            Some(thread) if thread.tid == tid && thread.detached => *slot = None,
+            Some(thread) if thread.tid == tid => thread.state = ThreadState::Exited(status),
+            _ => {}
+        }
+    }
+    table.wake(pid);
+}
+
+/// Collect the exited thread `target` of the process of thread `tid`,
+/// returning its status, or `None` if it is still running
+pub fn join(tid: u32, target: u32) -> Result<Option<i32>, UserError> {
+    let mut table = TABLE.lock();
+    let pid = table.thread_mut(tid).map(|t| t.pid).ok_or(UserError::NoSuchThread)?;
+    if target == tid {
+        return Err(UserError::NoSuchThread);
+    }
+    let thread = table
+        .thread_mut(target)
+        .filter(|t| t.pid == pid)
+        .ok_or(UserError::NoSuchThread)?;
+    let ThreadState::Exited(status) = thread.state else {
+        return Ok(None);
+    };
+    for slot in table.threads.iter_mut() {
+        if slot.as_ref().is_some_and(|t| t.tid == target) {
+            *slot = None;
+        }
+    }
+    Ok(Some(status))
+}
+
+/// Check that thread `target` (0 for `tid` itself) belongs to the process
+/// of thread `tid`, and change it with `f`
+fn with_sibling(tid: u32, target: u32, f: impl FnOnce(&mut Thread)) -> Result<(), UserError> {
+    let mut table = TABLE.lock();
+    let pid = table.thread_mut(tid).map(|t| t.pid).ok_or(UserError::NoSuchThread)?;
+    let target = if target == 0 { tid } else { target };
+    let thread = table
+        .thread_mut(target)
+        .filter(|t| t.pid == pid && !matches!(t.state, ThreadState::Exited(_)))
+        .ok_or(UserError::NoSuchThread)?;
+    f(thread);
+    Ok(())
+}
+
+/// Set the stride of a thread of the caller's process
+pub fn set_stride(tid: u32, target: u32, stride: u32) -> Result<(), UserError> {
+    if !(MIN_STRIDE..=MAX_STRIDE).contains(&stride) {
+        return Err(UserError::BadSetting);
+    }
+    with_sibling(tid, target, |thread| thread.task.stride = stride)
+}
+
+/// Set the CPUs that may run a thread of the caller's process
+pub fn set_affinity(tid: u32, target: u32, affinity: u64) -> Result<(), UserError> {
+    if affinity & USER_CPUS == 0 {
+        return Err(UserError::BadSetting);
+    }
+    with_sibling(tid, target, |thread| thread.affinity = affinity)
+}
+
+/// Process of thread `tid`
+pub fn thread_process(tid: u32) -> Option<u32> {
+    TABLE.lock().thread_mut(tid).map(|t| t.pid)
+}
+
+/// Stop this CPU's counters, adding what they counted to `thread`
+fn charge(thread: &mut Thread) {
+    for (total, count) in thread.counts.iter_mut().zip(pmu::stop()) {
+        *total += count;
+    }
+}
+
+/// Count thread `target` from zero, or stop counting it and keep what was
+/// counted
+///
+/// A thread counts from its next turn, or at once if it is the one
+/// running.
+pub fn set_counting(target: u32, on: bool) -> Result<(), UserError> {
+    let mut table = TABLE.lock();
+    let thread = table
+        .thread_mut(target)
+        .filter(|t| !matches!(t.state, ThreadState::Exited(_)))
+        .ok_or(UserError::NoSuchThread)?;
+    let running = target == current_thread();
+    if thread.counting && running {
+        charge(thread);
+    }
+    if on {
+        thread.counts = [0; pmu::COUNTERS];
+        if running {
+            pmu::start();
+        }
+    }
+    thread.counting = on;
+    Ok(())
+}
+
+/// What the performance counters have counted for thread `target`, and
+/// whether they still are
+pub fn counts(target: u32) -> Result<(pmu::Counts, bool), UserError> {
+    let mut table = TABLE.lock();
+    let thread = table.thread_mut(target).ok_or(UserError::NoSuchThread)?;
+    let mut counts = thread.counts;
+    if thread.counting && target == current_thread() {
+        for (total, count) in counts.iter_mut().zip(pmu::read()) {
+            *total += count;
+        }
+    }
+    Ok((counts, thread.counting))
+}
+
+/// Let the waiting threads of `pid` repeat their calls
+pub fn wake(pid: u32) {
+    TABLE.lock().wake(pid);
+}
+
+/// Make thread `tid` ready if it is waiting
+pub fn wake_thread(tid: u32) {
+    if let Some(thread) = TABLE.lock().thread_mut(tid).filter(|t| t.state == ThreadState::Waiting) {
+        thread.state = ThreadState::Ready;
+    }
+}
+
+/// Parent of `pid`
+pub fn parent(pid: u32) -> Option<u32> {
+    TABLE.lock().get_mut(pid).map(|p| p.parent)
+}
+
+/// Switch the process of thread `tid` to another system call interface,
+/// from its next call on
+pub fn set_personality(tid: u32, personality: Personality) -> Result<(), UserError> {
+    with_process(tid, |process| {
+        process.personality = personality;
+        Ok(())
+    })?;
+    if tid == current_thread() {
+        CURRENT_LINUX.store(personality == Personality::Linux, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Working directory of `pid`
+pub fn cwd(pid: u32) -> Option<Path> {
+    TABLE.lock().get_mut(pid).map(|p| p.cwd)
+}
+
+/// Set the working directory of the process of thread `tid`
+pub fn set_cwd(tid: u32, cwd: Path) -> Result<(), UserError> {
+    with_process(tid, |process| {
+        process.cwd = cwd;
+        Ok(())
+    })
+}
+
+/// Whether `pid` exists and has not exited
+pub fn is_running(pid: u32) -> bool {
+    TABLE.lock().get_mut(pid).is_some_and(|p| p.zombie.is_none())
+}
+
+/// Stop `pid` as if it had exited with `status`
+///
+/// No thread of `pid` may be the one making the call.
+pub fn kill(pid: u32, status: i32) -> Result<(), UserError> {
+    let mut table = TABLE.lock();
+    match table.get_mut(pid) {
+        Some(process) if process.zombie.is_none() => {
+            finish(&mut table, pid, status);
+            Ok(())
+        }
+        _ => Err(UserError::NoSuchProcess),
+    }
+}
+
+/// Collect an exited child of `parent` (any child when `child` is 0),
+/// returning its ID and status, or `None` if none has exited yet
+pub fn reap(parent: u32, child: u32) -> Result<Option<(u32, i32)>, UserError> {
+    let mut table = TABLE.lock();
+    let mut children = table
+        .processes
+        .iter()
+        .flatten()
+        .filter(|p| p.parent == parent && (child == 0 || p.pid == child))
+        .peekable();
+    if children.peek().is_none() {
+        return Err(UserError::NoSuchProcess);
+    }
+    let zombie = children.find_map(|p| p.zombie.map(|status| (p.pid, status)));
+    if let Some((pid, _)) = zombie {
+        table.remove(pid);
+    }
+    Ok(zombie)
+}
+
+/// Release everything `pid` holds and leave its status for the parent
+fn finish(table: &mut Table, pid: u32, status: i32) {
+    table.remove_threads(pid, 0);
+    let exited = table.exits;
+    table.exits += 1;
+    let Some(process) = table.get_mut(pid) else {
+        return;
+    };
+    let parent = process.parent;
+    process.zombie = Some(status);
+    process.exited = exited;
+    if let Some(space) = process.space.take() {
+        space.destroy();
+    }
+    handle::release_process(pid);
+    capability::drop_tokens(pid);
+    grant::grant_release_process(pid);
+    aio::io_release_process(pid);
+
+    // The children go to init, which is told about those already gone
+    for slot in table.processes.iter_mut() {
+        if let Some(child) = slot.as_mut().filter(|p| p.parent == pid) {
+            child.parent = INIT;
+            if let Some(status) = child.zombie {
+                send_exit(child.pid, status);
+                *slot = None;
+            }
+        }
+    }
+
+    if parent == INIT {
+        table.remove(pid);
+        send_exit(pid, status);
+    } else {
+        table.wake(parent);
+        drop_old_zombies(table, parent);
+    }
+}
+
+/// Drop the oldest statuses `parent` has not collected past [`MAX_ZOMBIES`]
+fn drop_old_zombies(table: &mut Table, parent: u32) {
+    loop {
+        let zombies = || table.processes.iter().flatten().filter(|p| p.parent == parent && p.zombie.is_some());
+        if zombies().count() <= MAX_ZOMBIES {
+            return;
+        }
+        let Some(oldest) = zombies().min_by_key(|p| p.exited).map(|p| p.pid) else {
+            return;
+        };
+        crate::klog!(Info, "Process {} never collected the status of process {}", parent, oldest);
+        table.remove(oldest);
+    }
+}
+
+fn with_process<R>(tid: u32, f: impl FnOnce(&mut Process) -> Result<R, UserError>) -> Result<R, UserError> {
+    let mut table = TABLE.lock();
+    let pid = table.thread_mut(tid).map(|t| t.pid).ok_or(UserError::NoSuchThread)?;
+    f(table.get_mut(pid).ok_or(UserError::NoSuchProcess)?)
+}
+
+/// Run `f` on the address space of process `pid`, active or not
+pub fn with_space<R>(pid: u32, f: impl FnOnce(&mut AddressSpace) -> R) -> Result<R, UserError> {
+    let mut table = TABLE.lock();
+    let process = table.get_mut(pid).ok_or(UserError::NoSuchProcess)?;
+    Ok(f(process.space.as_mut().ok_or(UserError::NoSuchProcess)?))
+}
+
+/// Set how far the main stack of the process of thread `tid` may grow,
+/// returning the previous limit; 0 only reads it
+///
+/// Pages the stack already has stay mapped when the limit is lowered.
+pub fn set_stack_limit(tid: u32, limit: u64) -> Result<u64, UserError> {
+    with_process(tid, |process| {
+        let old = process.stack_limit;
+        if limit != 0 {
+            process.stack_limit = stack::check_limit(limit).ok_or(UserError::BadSetting)?;
+            stack::set_current_limit(process.stack_limit);
+        }
+        Ok(old)
+    })
+}
+
+/// Move the program break of the process of thread `tid` to `end`,
+/// returning it; 0 only reads it
+pub fn set_break(tid: u32, end: u64) -> Result<u64, UserError> {
+    with_process(tid, |process| {
+        let space = process.space.as_mut().ok_or(UserError::NoSuchProcess)?;
+        match end {
+            0 => Ok(process.brk.end()),
+            end => process.brk.set(space, end),
+        }
+    })
+}
+
+/// Move the program break of the process of thread `tid` by
+/// `increment` bytes, returning where it was
+pub fn adjust_break(tid: u32, increment: i64) -> Result<u64, UserError> {
+    with_process(tid, |process| {
+        let space = process.space.as_mut().ok_or(UserError::NoSuchProcess)?;
+        process.brk.adjust(space, increment)
+    })
+}
+
+/// Run thread `tid` until it stops, if it is ready
+fn run(tid: u32) {
+    let mut table = TABLE.lock();
+    let Some(thread) = table.thread_mut(tid).filter(|t| t.state == ThreadState::Ready) else {
+        return;
+    };
+    let pid = thread.pid;
+    let context = thread.context;
+    let mut fs_base = thread.fs_base;
+    let counting = thread.counting;
+    let Some((l4, stack_limit, personality)) = table
+        .get_mut(pid)
+        .and_then(|p| Some((p.space.as_ref()?.l4_frame(), p.stack_limit, p.personality)))
+    else {
+        return;
+    };
+    if let Some(thread) = table.thread_mut(tid) {
+        thread.state = ThreadState::Running;
+        thread.task.pass += thread.task.stride as u64;
+    }
+    drop(table);
+
+    CURRENT.store(pid, Ordering::Relaxed);
+    CURRENT_THREAD.store(tid, Ordering::Relaxed);
+    stack::set_current_limit(stack_limit);
+    CURRENT_LINUX.store(personality == Personality::Linux, Ordering::Relaxed);
+    crate::trace!(ThreadRun, tid, pid);
+    if counting {
+        pmu::start();
+    }
+    let exit = usermode::run(&context, &mut fs_base, l4);
+    crate::trace!(ThreadStop, tid, pid);
+
+    // Counting may have been started or stopped while it ran
+    let mut table = TABLE.lock();
+    if let Some(thread) = table.thread_mut(tid).filter(|t| t.counting) {
+        charge(thread);
+    }
+    CURRENT_THREAD.store(0, Ordering::Relaxed);
+    CURRENT.store(0, Ordering::Relaxed);
+    CURRENT_LINUX.store(false, Ordering::Relaxed);
+
+    match exit {
+        Exit::Exited(status) => finish(&mut table, pid, status),
+        Exit::ThreadExited(status) => exit_thread(&mut table, tid, status),
+        Exit::Fault {
+            vector,
+            error_code,
+            address,
+            context,
+        } => {
+            let info = ExceptionInfo {
+                vector: vector as u64,
+                error_code: error_code.unwrap_or(0),
+                address: address.unwrap_or(0),
+                context,
+            };
+            if !handle_exception(&mut table, tid, &info) {
+                if vector == STACK_OVERFLOW {
+                    crate::klog!(Warn, "Process {} overflowed its stack at {:#x}", pid, context.rip);
+                } else {
+                    crate::klog!(
+                        Warn,
+                        "Process {} stopped by exception {} at {:#x} (address {:x?})",
+                        pid,
+                        vector,
+                        context.rip,
+                        address
+                    );
+                }
+                send_fault(pid, tid, &info);
+                finish(&mut table, pid, FAULT_STATUS + vector as i32);
+            }
+        }
+        Exit::Preempted(context) | Exit::Yielded(context) | Exit::Blocked(context) => {
+            if let Some(thread) = table.thread_mut(tid) {
+                thread.context = context;
+                thread.fs_base = fs_base;
+                thread.state = if matches!(exit, Exit::Blocked(_)) {
+                    ThreadState::Waiting
+                } else {
+                    ThreadState::Ready
+                };
+            }
+        }
+    }
+}
+
+/// Enter the handler of thread `tid` for an exception it raised, if it
+/// has one that is not already running
+fn handle_exception(table: &mut Table, tid: u32, info: &ExceptionInfo) -> bool {
+    let Some((pid, Some(handler), false)) = table.thread_mut(tid).map(|t| (t.pid, t.handler, t.handling)) else {
+        return false;
+    };
+    let Some(space) = table.get_mut(pid).and_then(|p| p.space.as_mut()) else {
+        return false;
+    };
+    let Some(context) = exception::deliver(space, &handler, info) else {
+        return false;
+    };
+    if let Some(thread) = table.thread_mut(tid) {
+        thread.context = context;
+        thread.handling = true;
+        thread.state = ThreadState::Ready;
+    }
+    true
+}
+
+/// Set where the exceptions of thread `tid` go, `None` to let them stop
+/// the process
+pub fn set_exception_handler(tid: u32, handler: Option<Handler>) -> Result<(), UserError> {
+    let mut table = TABLE.lock();
+    let thread = table.thread_mut(tid).ok_or(UserError::NoSuchThread)?;
+    thread.handler = handler;
+    Ok(())
+}
+
+/// Leave the exception handler thread `tid` is running, returning the
+/// registers to resume it with
+pub fn exception_return(tid: u32, context: &UserContext) -> Result<UserContext, UserError> {
+    let mut table = TABLE.lock();
+    let thread = table.thread_mut(tid).ok_or(UserError::NoSuchThread)?;
+    if !thread.handling {
+        return Err(UserError::BadSetting);
+    }
+    let context = exception::resume(context).ok_or(UserError::BadAddress)?;
+    thread.handling = false;
+    Ok(context)
+}
+
+/// The ready thread this CPU may run with the lowest pass
+fn next_thread(cpu: u32) -> Option<u32> {
+    TABLE
+        .lock()
+        .threads
+        .iter()
+        .flatten()
+        .filter(|t| t.state == ThreadState::Ready && (cpu >= 64 || t.affinity & (1 << cpu) != 0))
+        .min_by_key(|t| t.task.pass)
+        .map(|t| t.tid)
+}
+
+/// Give the ready threads as many turns as there are of them, lowest pass
+/// first, from the idle loop
+pub fn poll() {
+    let cpu = crate::kernel::percpu::current_cpu_id();
+    let ready = TABLE
+        .lock()
+        .threads
+        .iter()
+        .flatten()
+        .filter(|t| t.state == ThreadState::Ready)
+        .count();
+    for _ in 0..ready {
+        let Some(tid) = next_thread(cpu) else {
+            return;
+        };
+        run(tid);
+    }
+}
+
+/// Details of every process
+pub fn list() -> ArrayVec<ProcessInfo, MAX_PROCESSES> {
+    let table = TABLE.lock();
+    table
+        .processes
+        .iter()
+        .flatten()
+        .map(|p| {
+            let threads = || table.threads.iter().flatten().filter(|t| t.pid == p.pid);
+            let any = |state| threads().any(|t| t.state == state);
+            let state = match p.zombie {
+                Some(status) => State::Zombie(status),
+                None if any(ThreadState::Running) => State::Running,
+                None if any(ThreadState::Ready) => State::Ready,
+                None => State::Waiting,
+            };
+            ProcessInfo {
+                pid: p.pid,
+                parent: p.parent,
+                state,
+                threads: threads().count(),
+            }
+        })
+        .collect()
+}
+
+/// Details of every process with its depth below [`INIT`], parents before
+/// their children and children in order of ID
+pub fn tree() -> ArrayVec<(usize, ProcessInfo), MAX_PROCESSES> {
+    let mut all = list();
+    all.sort_unstable_by_key(|info| info.pid);
+    let mut tree = ArrayVec::new();
+    let mut pending: ArrayVec<(usize, ProcessInfo), MAX_PROCESSES> = all
+        .iter()
+        .rev()
+        .filter(|info| info.parent == INIT || !all.iter().any(|p| p.pid == info.parent))
+        .map(|info| (0, *info))
+        .collect();
+    while let Some((depth, info)) = pending.pop() {
+        tree.push((depth, info));
+        for child in all.iter().rev().filter(|c| c.parent == info.pid) {
+            pending.push((depth + 1, *child));
+        }
+    }
+    tree
+}
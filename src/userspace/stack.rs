@@ -0,0 +1,104 @@
+//! The main user stack
+//!
+//! A process's first stack is reserved at the top of the user half but
+//! mapped lazily: loading maps only the pages holding the arguments, and
+//! a page fault below them, or a system call given a buffer there, maps
+//! the pages down to the address touched. The stack grows this way up to
+//! the process's limit, which it can change with `SYS_STACK_LIMIT`.
+//!
+//! The page below the limit is never mapped. Touching it, or anything
+//! further down the reservation, is a stack overflow, raised to the
+//! thread as [`STACK_OVERFLOW`] rather than as a page fault. A handler can
+//! only take it on a stack of its own.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::structures::paging::{Page, PageTableFlags as Flags, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::address_space::{AddressSpace, USER_END};
+use super::tls::TLS_END;
+use crate::kernel::memory::MapError;
+
+/// Where the stack starts, growing down
+pub const STACK_TOP: u64 = USER_END;
+
+/// Mapped at load, enough for the arguments and environment at their
+/// largest
+pub const INITIAL_SIZE: u64 = 16 * 1024;
+
+/// Limits a process may set
+pub const MIN_LIMIT: u64 = INITIAL_SIZE;
+pub const MAX_LIMIT: u64 = 1024 * 1024 * 1024;
+pub const DEFAULT_LIMIT: u64 = 1024 * 1024;
+
+/// Unmapped space below the largest stack
+const GUARD_SIZE: u64 = 4096;
+
+/// Lowest address of the stack reservation
+const RESERVED_START: u64 = STACK_TOP - MAX_LIMIT - GUARD_SIZE;
+
+const _: () = assert!(RESERVED_START >= TLS_END);
+
+/// Exception vector of a stack overflow, the first after the CPU's own
+pub const STACK_OVERFLOW: u8 = 32;
+
+/// Limit of the process running on the CPU
+static LIMIT: AtomicU64 = AtomicU64::new(DEFAULT_LIMIT);
+
+/// What an access to an unmapped address meant for the stack
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Growth {
+    /// The stack grew to cover it
+    Grown,
+    /// It lies below the limit
+    Overflow,
+    /// It is not the stack's to handle
+    Outside,
+}
+
+/// Round a requested limit to whole pages, or `None` if it is out of range
+pub fn check_limit(limit: u64) -> Option<u64> {
+    let limit = limit.checked_add(0xfff)? & !0xfff;
+    (MIN_LIMIT..=MAX_LIMIT).contains(&limit).then_some(limit)
+}
+
+/// Make `limit` the one faults are checked against, as the process it
+/// belongs to starts running
+pub fn set_current_limit(limit: u64) {
+    LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// Map the pages the arguments go on
+pub fn map_initial(space: &mut AddressSpace) -> Result<(), MapError> {
+    let first = Page::containing_address(VirtAddr::new(STACK_TOP - INITIAL_SIZE));
+    let last = Page::containing_address(VirtAddr::new(STACK_TOP - 1));
+    for page in Page::range_inclusive(first, last) {
+        space.map(page, Flags::WRITABLE | Flags::NO_EXECUTE)?;
+    }
+    Ok(())
+}
+
+/// Grow the running process's stack in `space` down to the unmapped
+/// address `addr`, if it is the stack's
+pub fn grow(space: &mut AddressSpace, addr: u64) -> Growth {
+    if !(RESERVED_START..STACK_TOP).contains(&addr) {
+        return Growth::Outside;
+    }
+    if addr < STACK_TOP - LIMIT.load(Ordering::Relaxed) {
+        return Growth::Overflow;
+    }
+    let mut page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+    if space.flags(page.start_address()).is_some() {
+        return Growth::Outside;
+    }
+    // Pages between the address and the bottom of the stack are mapped
+    // too, so the stack stays in one piece
+    while page.start_address().as_u64() < STACK_TOP && space.flags(page.start_address()).is_none() {
+        if space.map(page, Flags::WRITABLE | Flags::NO_EXECUTE).is_err() {
+            return Growth::Outside;
+        }
+        page += 1;
+    }
+    Growth::Grown
+}
@@ -0,0 +1,1351 @@
+//! System calls
+//!
+//! Programs enter the kernel with `syscall`, or with `int 0x80` where
+//! that is not available. Both paths take the call number in `rax` and up
+//! to six arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`, and
+//! return in `rax`: a value, or a negated [`SyscallError`] code. Every
+//! other register except `rcx` and `r11` is preserved. A process with the
+//! Linux personality makes Linux's calls instead, which
+//! [`crate::compat::linux`] translates.
+//!
+//! `syscall` leaves the user stack in place, so the entry stub switches to
+//! the ring 0 stack the TSS names for interrupts. Both stubs save the
+//! caller's registers as a [`UserContext`], which handlers may change
+//! (`exec` starts a new image) or hand to the process table when the
+//! caller has to stop. Calls run with interrupts enabled; the stubs mask
+//! them again before returning.
+
+use core::arch::global_asm;
+use core::mem::size_of;
+
+use x86_64::registers::model_specific::{Efer, EferFlags, FsBase, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::{Page, PageTableFlags as Flags};
+use x86_64::VirtAddr;
+
+use super::address_space::{AddressSpace, USER_END, USER_START};
+use super::args::{self, Strings};
+use super::console;
+use super::exception;
+use super::futex::{self, FutexError, Timeout};
+use super::handle::{self, HandleError, Object};
+use super::process::{self, Personality};
+use super::uaccess::{self, Access};
+use super::usermode::{self, Exit, UserContext};
+use crate::capability::{self, CapabilityError, Permission};
+use crate::compat::errno::Errno;
+use crate::compat::fat32;
+use crate::compat::pipe::{self, PipeError};
+use crate::compat::pty::{self, PtyError};
+use crate::compat::vfs::{self, VfsError};
+use crate::gpu::bo::{self, BoHandle};
+use crate::gpu::framebuffer::PixelFormat;
+use crate::gpu::GpuError;
+use crate::ipc::{self, names, IpcError, MessageHeader, MAX_MESSAGE_SIZE};
+use crate::kernel::{clocksource, gdt, hrtimer, memory::MapError, pmu, power};
+use crate::storage::aio::{self, IoDescriptor, IoOp};
+use crate::storage::iosched::IoPriority;
+use crate::storage::{StorageError, WriteFlags};
+use crate::tagfs::{self, Tag, TagFsError};
+
+/// Interrupt vector of the fallback entry
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// Call numbers
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_YIELD: u64 = 1;
+pub const SYS_TICKS: u64 = 2;
+pub const SYS_IPC_CREATE: u64 = 3;
+pub const SYS_IPC_SEND: u64 = 4;
+pub const SYS_IPC_RECV: u64 = 5;
+pub const SYS_IPC_LOOKUP: u64 = 6;
+pub const SYS_TAGFS_CREATE: u64 = 7;
+pub const SYS_TAGFS_READ: u64 = 8;
+pub const SYS_TAGFS_QUERY: u64 = 9;
+pub const SYS_TAGFS_SIZE: u64 = 10;
+pub const SYS_TAGFS_DELETE: u64 = 11;
+pub const SYS_MAP: u64 = 12;
+pub const SYS_SPAWN: u64 = 13;
+pub const SYS_EXEC: u64 = 14;
+pub const SYS_WAIT: u64 = 15;
+pub const SYS_GETPID: u64 = 16;
+pub const SYS_FORK: u64 = 17;
+pub const SYS_LOG: u64 = 18;
+pub const SYS_THREAD_CREATE: u64 = 19;
+pub const SYS_THREAD_EXIT: u64 = 20;
+pub const SYS_THREAD_JOIN: u64 = 21;
+pub const SYS_GETTID: u64 = 22;
+pub const SYS_THREAD_PRIORITY: u64 = 23;
+pub const SYS_THREAD_AFFINITY: u64 = 24;
+pub const SYS_SET_FS_BASE: u64 = 25;
+pub const SYS_SET_EXCEPTION_HANDLER: u64 = 26;
+pub const SYS_EXCEPTION_RETURN: u64 = 27;
+pub const SYS_PROCESS_LIST: u64 = 28;
+pub const SYS_READ: u64 = 29;
+pub const SYS_WRITE: u64 = 30;
+pub const SYS_STACK_LIMIT: u64 = 31;
+pub const SYS_BRK: u64 = 32;
+pub const SYS_SBRK: u64 = 33;
+pub const SYS_OPEN: u64 = 34;
+pub const SYS_CLOSE: u64 = 35;
+pub const SYS_DUP: u64 = 36;
+pub const SYS_SEEK: u64 = 37;
+pub const SYS_PERSONALITY: u64 = 38;
+pub const SYS_FUTEX_WAIT: u64 = 39;
+pub const SYS_FUTEX_WAKE: u64 = 40;
+pub const SYS_KLOG_READ: u64 = 41;
+pub const SYS_POWER: u64 = 42;
+pub const SYS_PERF: u64 = 43;
+pub const SYS_IO: u64 = 44;
+
+/// Protection bits of `SYS_MAP`; pages are always readable
+pub const PROT_WRITE: u64 = 1 << 1;
+pub const PROT_EXEC: u64 = 1 << 2;
+
+/// One process as `SYS_PROCESS_LIST` writes it
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ProcessRecord {
+    pub pid: u32,
+    pub parent: u32,
+    /// Depth in the process tree, 0 for children of init
+    pub depth: u32,
+    /// 0 ready, 1 running, 2 waiting, 3 exited
+    pub state: u32,
+    /// Exit status once exited
+    pub status: i32,
+    pub threads: u32,
+}
+
+/// Length of the `syscall` and `int 0x80` instructions, to repeat a call
+const SYSCALL_LEN: u64 = 2;
+
+impl UserContext {
+    /// Number of the system call being made
+    pub fn number(&self) -> u64 {
+        self.rax
+    }
+
+    /// Arguments of the system call being made
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+
+    /// The context with the system call about to be made again
+    pub fn restart(&self) -> Self {
+        Self {
+            rip: self.rip - SYSCALL_LEN,
+            ..*self
+        }
+    }
+}
+
+type Handler = fn(&mut UserContext) -> Result<u64, SyscallError>;
+
+/// Handlers indexed by call number
+static TABLE: [Handler; 45] = [
+    sys_exit,
+    sys_yield,
+    sys_ticks,
+    sys_ipc_create,
+    sys_ipc_send,
+    sys_ipc_recv,
+    sys_ipc_lookup,
+    sys_tagfs_create,
+    sys_tagfs_read,
+    sys_tagfs_query,
+    sys_tagfs_size,
+    sys_tagfs_delete,
+    sys_map,
+    sys_spawn,
+    sys_exec,
+    sys_wait,
+    sys_getpid,
+    sys_fork,
+    sys_log,
+    sys_thread_create,
+    sys_thread_exit,
+    sys_thread_join,
+    sys_gettid,
+    sys_thread_priority,
+    sys_thread_affinity,
+    sys_set_fs_base,
+    sys_set_exception_handler,
+    sys_exception_return,
+    sys_process_list,
+    sys_read,
+    sys_write,
+    sys_stack_limit,
+    sys_brk,
+    sys_sbrk,
+    sys_open,
+    sys_close,
+    sys_dup,
+    sys_seek,
+    sys_personality,
+    sys_futex_wait,
+    sys_futex_wake,
+    sys_klog_read,
+    sys_power,
+    sys_perf,
+    sys_io,
+];
+
+/// User stack pointer while a `syscall` runs
+static mut USER_RSP: u64 = 0;
+
+/// Ring 0 stack `syscall` switches to
+static mut KERNEL_RSP: u64 = 0;
+
+/// User selectors `syscall` entry saves in the context
+static mut USER_CS: u64 = 0;
+static mut USER_SS: u64 = 0;
+
+global_asm!(
+    r#"
+.global syscall_entry
+syscall_entry:
+    mov [rip + {user_rsp}], rsp
+    mov rsp, [rip + {kernel_rsp}]
+    push qword ptr [rip + {user_ss}]
+    push qword ptr [rip + {user_rsp}]
+    push r11
+    push qword ptr [rip + {user_cs}]
+    push rcx
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    call {dispatch}
+    cli
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    mov rcx, [rsp]
+    mov r11, [rsp + 16]
+    mov rsp, [rsp + 24]
+    sysretq
+
+.global syscall_int_entry
+syscall_int_entry:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    call {dispatch}
+    cli
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    iretq
+"#,
+    user_rsp = sym USER_RSP,
+    kernel_rsp = sym KERNEL_RSP,
+    user_cs = sym USER_CS,
+    user_ss = sym USER_SS,
+    dispatch = sym dispatch,
+);
+
+extern "C" {
+    fn syscall_entry();
+    fn syscall_int_entry();
+}
+
+/// Enable `syscall` and point it at the entry stub
+pub fn init() {
+    let selectors = gdt::selectors();
+    unsafe {
+        KERNEL_RSP = gdt::privilege_stack_top().as_u64();
+        USER_CS = selectors.user_code.0 as u64;
+        USER_SS = selectors.user_data.0 as u64;
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+        LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+        SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG | RFlags::ALIGNMENT_CHECK);
+    }
+    if let Err(e) = Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    ) {
+        crate::klog!(Error, "syscall unavailable: {}", e);
+    }
+}
+
+/// Address of the `int 0x80` entry stub, for the IDT
+pub fn int_entry() -> VirtAddr {
+    VirtAddr::new(syscall_int_entry as *const () as u64)
+}
+
+extern "C" fn dispatch(context: &mut UserContext) {
+    crate::kernel::percpu::restore();
+    uaccess::close();
+    x86_64::instructions::interrupts::enable();
+    if process::current_personality() == Personality::Linux {
+        return crate::compat::linux::dispatch(context);
+    }
+    let result = call(context.number(), context);
+    context.rax = match result {
+        Ok(value) => value,
+        Err(e) => (-e.code()) as u64,
+    };
+}
+
+/// Make native call `number` with the arguments in `context`
+pub fn call(number: u64, context: &mut UserContext) -> Result<u64, SyscallError> {
+    match TABLE.get(number as usize) {
+        Some(handler) => handler(context),
+        None => Err(SyscallError::InvalidSyscall),
+    }
+}
+
+/// Process making the call
+fn caller() -> u32 {
+    process::current()
+}
+
+fn sys_exit(context: &mut UserContext) -> Result<u64, SyscallError> {
+    usermode::leave(Exit::Exited(context.rdi as i32))
+}
+
+fn sys_yield(context: &mut UserContext) -> Result<u64, SyscallError> {
+    usermode::leave(Exit::Yielded(UserContext { rax: 0, ..*context }))
+}
+
+fn sys_ticks(_context: &mut UserContext) -> Result<u64, SyscallError> {
+    Ok(crate::scheduler::ticks())
+}
+
+fn sys_ipc_create(_context: &mut UserContext) -> Result<u64, SyscallError> {
+    Ok(ipc::create_channel_for(caller())?)
+}
+
+/// channel, message type, buffer, length
+fn sys_ipc_send(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [channel, msg_type, addr, len, ..] = context.args();
+    if len as usize > MAX_MESSAGE_SIZE {
+        return Err(SyscallError::TooLarge);
+    }
+    let mut data = [0u8; MAX_MESSAGE_SIZE];
+    let data = &mut data[..len as usize];
+    uaccess::copy_from_user(data, addr)?;
+    let header = MessageHeader {
+        id: 0,
+        sender: caller(),
+        receiver: 0,
+        length: len as u32,
+        msg_type: msg_type as u32,
+    };
+    ipc::msg_send(channel, header, data)?;
+    Ok(0)
+}
+
+/// channel, buffer, capacity, header out (may be 0); returns the length
+fn sys_ipc_recv(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [channel, addr, capacity, header_addr, ..] = context.args();
+    capability::check_ipc_recv_permission(caller(), channel)?;
+    let capacity = (capacity as usize).min(MAX_MESSAGE_SIZE);
+    // Check before taking the message so a bad buffer does not lose it
+    uaccess::check(addr, capacity, true)?;
+    if header_addr != 0 {
+        uaccess::check(header_addr, core::mem::size_of::<MessageHeader>(), true)?;
+    }
+    if !ipc::msg_poll(channel)? {
+        return Err(SyscallError::WouldBlock);
+    }
+    let mut data = [0; MAX_MESSAGE_SIZE];
+    let (header, len) = ipc::msg_recv(channel, &mut data[..capacity])?;
+    uaccess::copy_to_user(addr, &data[..len])?;
+    if header_addr != 0 {
+        uaccess::write_value(header_addr, &header)?;
+    }
+    Ok(len as u64)
+}
+
+/// name, name length; returns the channel
+fn sys_ipc_lookup(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let access = Access::open();
+    let name = uaccess::user_str(&access, context.rdi, context.rsi as usize)?;
+    names::lookup(name).ok_or(SyscallError::NotFound)
+}
+
+fn user_tag(addr: u64, len: u64) -> Result<Tag, SyscallError> {
+    if len == 0 || len > 32 {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let access = Access::open();
+    Ok(Tag::new(uaccess::user_str(&access, addr, len as usize)?))
+}
+
+/// tag, tag length, data, data length; returns the object
+fn sys_tagfs_create(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [tag_addr, tag_len, addr, len, ..] = context.args();
+    capability::check_permission(caller(), Permission::FileCreate)?;
+    let tag = user_tag(tag_addr, tag_len)?;
+    let access = Access::open();
+    let data = uaccess::user_slice(&access, addr, len as usize)?;
+    Ok(tagfs::tagfs_create(&[tag], data)?)
+}
+
+/// object, offset, buffer, length; returns the bytes read
+fn sys_tagfs_read(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [object, offset, addr, len, ..] = context.args();
+    capability::check_permission(caller(), Permission::Read)?;
+    let access = Access::open();
+    let buffer = uaccess::user_slice_mut(&access, addr, len as usize)?;
+    Ok(tagfs::tagfs_read(object, offset, buffer)? as u64)
+}
+
+/// tag, tag length; returns the object
+fn sys_tagfs_query(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let tag = user_tag(context.rdi, context.rsi)?;
+    tagfs::tagfs_query(&tag).ok_or(SyscallError::NotFound)
+}
+
+fn sys_tagfs_size(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let meta = tagfs::tagfs_meta(context.rdi).ok_or(SyscallError::NotFound)?;
+    Ok(meta.size as u64)
+}
+
+fn sys_tagfs_delete(context: &mut UserContext) -> Result<u64, SyscallError> {
+    capability::check_permission(caller(), Permission::FileDelete)?;
+    tagfs::tagfs_delete(context.rdi)?;
+    Ok(0)
+}
+
+/// address, length, protection; maps zeroed pages
+fn sys_map(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [addr, len, prot, ..] = context.args();
+    if !addr.is_multiple_of(4096) || len == 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let end = addr.checked_add(len).ok_or(SyscallError::BadAddress)?;
+    if addr < USER_START || end > USER_END {
+        return Err(SyscallError::BadAddress);
+    }
+    let mut flags = Flags::empty();
+    if prot & PROT_WRITE != 0 {
+        flags |= Flags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= Flags::NO_EXECUTE;
+    }
+    let mut space = AddressSpace::active();
+    let first = Page::containing_address(VirtAddr::new(addr));
+    let last = Page::containing_address(VirtAddr::new(end - 1));
+    for page in Page::range_inclusive(first, last) {
+        space.map(page, flags)?;
+    }
+    Ok(addr)
+}
+
+/// Copy packed argument or environment strings (see [`super::args`])
+fn user_strings(addr: u64, len: u64) -> Result<Strings, SyscallError> {
+    if len as usize > args::MAX_STRINGS_SIZE {
+        return Err(SyscallError::TooLarge);
+    }
+    let access = Access::open();
+    Ok(Strings::from_packed(uaccess::user_slice(&access, addr, len as usize)?)?)
+}
+
+/// object, arguments, their length, environment, its length; returns the
+/// child's process ID
+fn sys_spawn(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [object, argv, argv_len, envp, envp_len, ..] = context.args();
+    capability::check_permission(caller(), Permission::Execute)?;
+    let argv = user_strings(argv, argv_len)?;
+    let envp = user_strings(envp, envp_len)?;
+    Ok(process::spawn(object, caller(), &argv, &envp)? as u64)
+}
+
+/// object, arguments, their length, environment, its length; replaces the
+/// caller's image and does not return on success
+fn sys_exec(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [object, argv, argv_len, envp, envp_len, ..] = context.args();
+    capability::check_permission(caller(), Permission::Execute)?;
+    let argv = user_strings(argv, argv_len)?;
+    let envp = user_strings(envp, envp_len)?;
+    *context = process::exec(process::current_thread(), object, &argv, &envp)?;
+    Ok(0)
+}
+
+/// child (0 for any), status out (may be 0); returns the child's process
+/// ID once it has exited
+fn sys_wait(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [child, status_addr, ..] = context.args();
+    if status_addr != 0 {
+        uaccess::check(status_addr, 4, true)?;
+    }
+    match process::reap(caller(), child as u32)? {
+        Some((pid, status)) => {
+            if status_addr != 0 {
+                uaccess::write_value(status_addr, &status)?;
+            }
+            Ok(pid as u64)
+        }
+        None => usermode::leave(Exit::Blocked(context.restart())),
+    }
+}
+
+fn sys_getpid(_context: &mut UserContext) -> Result<u64, SyscallError> {
+    Ok(caller() as u64)
+}
+
+/// Returns the child's process ID to the parent and 0 to the child
+fn sys_fork(context: &mut UserContext) -> Result<u64, SyscallError> {
+    Ok(process::fork(process::current_thread(), context)? as u64)
+}
+
+/// text, length; writes UTF-8 text to the kernel console
+fn sys_log(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [addr, len, ..] = context.args();
+    if len as usize > MAX_MESSAGE_SIZE {
+        return Err(SyscallError::TooLarge);
+    }
+    let access = Access::open();
+    crate::serial_print!("{}", uaccess::user_str(&access, addr, len as usize)?);
+    Ok(len)
+}
+
+/// entry, stack top, argument; the thread starts at `entry` with the
+/// argument in `rdi`, and the call returns its thread ID
+fn sys_thread_create(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [entry, stack, arg, ..] = context.args();
+    if !(USER_START..USER_END).contains(&entry) || !(USER_START..=USER_END).contains(&stack) {
+        return Err(SyscallError::BadAddress);
+    }
+    Ok(process::create_thread(process::current_thread(), entry, stack, arg)? as u64)
+}
+
+/// status; ends the calling thread, or the process if it is the last
+fn sys_thread_exit(context: &mut UserContext) -> Result<u64, SyscallError> {
+    usermode::leave(Exit::ThreadExited(context.rdi as i32))
+}
+
+/// thread, status out (may be 0); waits for a thread of the same process
+/// to exit
+fn sys_thread_join(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [tid, status_addr, ..] = context.args();
+    if status_addr != 0 {
+        uaccess::check(status_addr, 4, true)?;
+    }
+    match process::join(process::current_thread(), tid as u32)? {
+        Some(status) => {
+            if status_addr != 0 {
+                uaccess::write_value(status_addr, &status)?;
+            }
+            Ok(0)
+        }
+        None => usermode::leave(Exit::Blocked(context.restart())),
+    }
+}
+
+fn sys_gettid(_context: &mut UserContext) -> Result<u64, SyscallError> {
+    Ok(process::current_thread() as u64)
+}
+
+/// thread (0 for the caller), stride; a smaller stride runs more often
+fn sys_thread_priority(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [tid, stride, ..] = context.args();
+    let stride = u32::try_from(stride).map_err(|_| SyscallError::InvalidArgument)?;
+    process::set_stride(process::current_thread(), tid as u32, stride)?;
+    Ok(0)
+}
+
+/// thread (0 for the caller), mask of the CPUs that may run it
+fn sys_thread_affinity(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [tid, mask, ..] = context.args();
+    process::set_affinity(process::current_thread(), tid as u32, mask)?;
+    Ok(0)
+}
+
+/// address; points the caller's `fs` base at its own TLS block
+fn sys_set_fs_base(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let addr = context.rdi;
+    if addr >= USER_END {
+        return Err(SyscallError::BadAddress);
+    }
+    FsBase::write(VirtAddr::new(addr));
+    Ok(0)
+}
+
+/// entry (0 to remove), stack top (0 for the thread's own stack); sets
+/// the handler for the caller's exceptions
+fn sys_set_exception_handler(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [entry, stack, ..] = context.args();
+    let handler = match entry {
+        0 => None,
+        entry if (USER_START..USER_END).contains(&entry) && stack <= USER_END => {
+            Some(exception::Handler { entry, stack })
+        }
+        _ => return Err(SyscallError::BadAddress),
+    };
+    process::set_exception_handler(process::current_thread(), handler)?;
+    Ok(0)
+}
+
+/// exception info; resumes the caller from the registers in the info its
+/// handler was given
+fn sys_exception_return(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let info: exception::ExceptionInfo = uaccess::read_value(context.rdi)?;
+    let resumed = process::exception_return(process::current_thread(), &info.context)?;
+    // Resumed through `iretq`, which restores every register
+    usermode::leave(Exit::Yielded(resumed))
+}
+
+/// buffer, capacity in records; fills the buffer with the process tree,
+/// parents first, and returns how many processes there are
+fn sys_process_list(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [addr, capacity, ..] = context.args();
+    let tree = process::tree();
+    let count = tree.len().min(capacity as usize);
+    uaccess::check(addr, count * size_of::<ProcessRecord>(), true)?;
+    for (i, (depth, info)) in tree.iter().take(count).enumerate() {
+        let (state, status) = match info.state {
+            process::State::Ready => (0, 0),
+            process::State::Running => (1, 0),
+            process::State::Waiting => (2, 0),
+            process::State::Zombie(status) => (3, status),
+        };
+        let record = ProcessRecord {
+            pid: info.pid,
+            parent: info.parent,
+            depth: *depth as u32,
+            state,
+            status,
+            threads: info.threads as u32,
+        };
+        uaccess::write_value(addr + (i * size_of::<ProcessRecord>()) as u64, &record)?;
+    }
+    Ok(tree.len() as u64)
+}
+
+/// Kernel view of a buffer object's bytes from `offset` on
+fn buffer_bytes(bo: BoHandle, offset: u64) -> Result<&'static mut [u8], SyscallError> {
+    let fb = bo::bo_framebuffer(bo)?;
+    let size = fb.size() as u64;
+    let len = size.saturating_sub(offset) as usize;
+    Ok(unsafe { core::slice::from_raw_parts_mut((fb.base + offset.min(size)) as *mut u8, len) })
+}
+
+/// Wake thread `tid`, blocked reading a timer that has expired
+fn wake_reader(tid: u64) {
+    process::wake_thread(tid as u32);
+}
+
+/// handle, buffer, length; returns how many bytes were read, 0 at the
+/// end
+///
+/// Standard input waits for a line, and a timer until it expires, when it
+/// reads as the number of expirations (u64), woken by a high-resolution
+/// timer. A channel gives the data of
+/// its next message, or fails with `WouldBlock`, while a pipe waits for
+/// data or for its last write end to close, and a pseudo-terminal for
+/// input or output, or for its other end to close. A directory cannot be
+/// read this way.
+fn sys_read(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [fd, addr, len, ..] = context.args();
+    let pid = caller();
+    let access = Access::open();
+    let buffer = uaccess::user_slice_mut(&access, addr, len as usize)?;
+    match handle::get(pid, fd)? {
+        Object::Console(console::STDIN) => match console::read(pid, buffer) {
+            Some(read) => Ok(read as u64),
+            None => usermode::leave(Exit::Blocked(context.restart())),
+        },
+        Object::Console(_) => Err(SyscallError::InvalidArgument),
+        Object::File { id, offset } => {
+            let read = tagfs::tagfs_read(id, offset, buffer)?;
+            handle::update(pid, fd, |object| object.advance(read as u64))?;
+            Ok(read as u64)
+        }
+        Object::Channel(channel) => {
+            capability::check_ipc_recv_permission(pid, channel)?;
+            if !ipc::msg_poll(channel)? {
+                return Err(SyscallError::WouldBlock);
+            }
+            let (_, len) = ipc::msg_recv(channel, buffer)?;
+            Ok(len as u64)
+        }
+        Object::Buffer { bo, offset, .. } => {
+            let bytes = buffer_bytes(bo, offset)?;
+            let len = bytes.len().min(buffer.len());
+            buffer[..len].copy_from_slice(&bytes[..len]);
+            handle::update(pid, fd, |object| object.advance(len as u64))?;
+            Ok(len as u64)
+        }
+        Object::Timer { deadline, .. } => {
+            if buffer.len() < size_of::<u64>() {
+                return Err(SyscallError::InvalidArgument);
+            }
+            match handle::update(pid, fd, |timer| timer.expire(clocksource::nanoseconds()))? {
+                None => Ok(0),
+                // A wake-up before the deadline leaves its timer to expire
+                // harmlessly; with none free, poll instead
+                Some(0) => match hrtimer::start(deadline, wake_reader, process::current_thread() as u64) {
+                    Ok(_) => usermode::leave(Exit::Blocked(context.restart())),
+                    Err(_) => usermode::leave(Exit::Yielded(context.restart())),
+                },
+                Some(count) => {
+                    buffer[..size_of::<u64>()].copy_from_slice(&count.to_le_bytes());
+                    Ok(size_of::<u64>() as u64)
+                }
+            }
+        }
+        Object::Staged { slot, offset } => {
+            let read = vfs::read_staged(slot, offset, buffer);
+            handle::update(pid, fd, |object| object.advance(read as u64))?;
+            Ok(read as u64)
+        }
+        Object::Fat {
+            volume, entry, offset, ..
+        } => {
+            let read = fat32::read(volume, entry, offset, buffer)?;
+            handle::update(pid, fd, |object| object.advance(read as u64))?;
+            Ok(read as u64)
+        }
+        Object::Pipe { read: Some(pipe), .. } => match pipe::read(pipe, buffer)? {
+            Some(read) => Ok(read as u64),
+            None => usermode::leave(Exit::Yielded(context.restart())),
+        },
+        Object::Pty { pty, end } => match pty::read(pty, end, buffer)? {
+            Some(read) => Ok(read as u64),
+            None => usermode::leave(Exit::Yielded(context.restart())),
+        },
+        Object::Null => Ok(0),
+        Object::Directory { .. } | Object::Pipe { .. } | Object::Ptmx => Err(SyscallError::InvalidArgument),
+    }
+}
+
+/// handle, buffer, length; returns how many bytes were written
+///
+/// Standard output and error go to the console, a channel sends the data
+/// as a message of type 0, and a buffer object is written in place, as is
+/// a file open for writing. A pipe waits for room, and fails with
+/// `BrokenPipe` once no read end is open; a pseudo-terminal waits for
+/// room too.
+fn sys_write(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [fd, addr, len, ..] = context.args();
+    let pid = caller();
+    let access = Access::open();
+    match handle::get(pid, fd)? {
+        Object::Console(console::STDOUT | console::STDERR) => {
+            let len = len.min(MAX_MESSAGE_SIZE as u64);
+            console::write(uaccess::user_slice(&access, addr, len as usize)?);
+            Ok(len)
+        }
+        Object::Channel(channel) => {
+            if len as usize > MAX_MESSAGE_SIZE {
+                return Err(SyscallError::TooLarge);
+            }
+            let header = MessageHeader {
+                id: 0,
+                sender: pid,
+                receiver: 0,
+                length: len as u32,
+                msg_type: 0,
+            };
+            ipc::msg_send(channel, header, uaccess::user_slice(&access, addr, len as usize)?)?;
+            Ok(len)
+        }
+        Object::Buffer { bo, offset, .. } => {
+            let data = uaccess::user_slice(&access, addr, len as usize)?;
+            let bytes = buffer_bytes(bo, offset)?;
+            let len = bytes.len().min(data.len());
+            bytes[..len].copy_from_slice(&data[..len]);
+            handle::update(pid, fd, |object| object.advance(len as u64))?;
+            Ok(len as u64)
+        }
+        Object::Staged { slot, offset } => {
+            let data = uaccess::user_slice(&access, addr, len as usize)?;
+            let (len, end) = vfs::write_staged(slot, offset, data).map_err(|_| SyscallError::TooLarge)?;
+            handle::update(pid, fd, |object| object.offset_mut().map(|offset| *offset = end))?;
+            Ok(len as u64)
+        }
+        Object::Fat {
+            volume,
+            entry,
+            offset,
+            write: true,
+            append,
+        } => {
+            let data = uaccess::user_slice(&access, addr, len as usize)?;
+            let offset = if append { fat32::size(volume, entry)? } else { offset };
+            let written = fat32::write(volume, entry, offset, data)?;
+            handle::update(pid, fd, |object| object.offset_mut().map(|at| *at = offset + written as u64))?;
+            Ok(written as u64)
+        }
+        Object::Pipe { write: Some(pipe), .. } => {
+            match pipe::write(pipe, pid, uaccess::user_slice(&access, addr, len as usize)?)? {
+                Some(written) => Ok(written as u64),
+                None => usermode::leave(Exit::Yielded(context.restart())),
+            }
+        }
+        Object::Pty { pty, end } => match pty::write(pty, end, uaccess::user_slice(&access, addr, len as usize)?)? {
+            Some(written) => Ok(written as u64),
+            None => usermode::leave(Exit::Yielded(context.restart())),
+        },
+        Object::Null => Ok(len),
+        Object::Console(_)
+        | Object::File { .. }
+        | Object::Timer { .. }
+        | Object::Directory { .. }
+        | Object::Fat { .. }
+        | Object::Pipe { .. }
+        | Object::Ptmx => Err(SyscallError::InvalidArgument),
+    }
+}
+
+/// kind, then by kind: a stream number; an object; a channel; a buffer
+/// object handle, or 0, width, height and pixel format (0 XRGB, 1 XBGR)
+/// for a new one; nanoseconds to the first expiry and between later ones; 0 for
+/// the master of a new pseudo-terminal, or 1 and a handle to a master for
+/// its slave. Returns the handle
+fn sys_open(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [kind, a, b, c, d, ..] = context.args();
+    let pid = caller();
+    let object = match kind {
+        handle::KIND_CONSOLE if a <= console::STDERR => Object::Console(a),
+        handle::KIND_OBJECT => {
+            capability::check_permission(pid, Permission::Read)?;
+            tagfs::tagfs_meta(a).ok_or(SyscallError::NotFound)?;
+            Object::File { id: a, offset: 0 }
+        }
+        handle::KIND_CHANNEL => {
+            ipc::msg_poll(a)?;
+            Object::Channel(a)
+        }
+        handle::KIND_BUFFER if a != 0 => {
+            bo::bo_info(BoHandle(a))?;
+            Object::Buffer {
+                bo: BoHandle(a),
+                creator: None,
+                offset: 0,
+            }
+        }
+        handle::KIND_BUFFER => {
+            let format = match d {
+                0 => PixelFormat::Xrgb8888,
+                1 => PixelFormat::Xbgr8888,
+                _ => return Err(SyscallError::InvalidArgument),
+            };
+            let width = u32::try_from(b).map_err(|_| SyscallError::InvalidArgument)?;
+            let height = u32::try_from(c).map_err(|_| SyscallError::InvalidArgument)?;
+            Object::Buffer {
+                bo: bo::bo_create(pid, width, height, format)?,
+                creator: Some(pid),
+                offset: 0,
+            }
+        }
+        handle::KIND_TIMER if a > 0 => Object::timer(clocksource::nanoseconds(), a, b),
+        handle::KIND_PTY if a == 0 => Object::Pty {
+            pty: pty::create()?,
+            end: pty::End::Master,
+        },
+        handle::KIND_PTY if a == 1 => {
+            let Object::Pty {
+                pty,
+                end: pty::End::Master,
+            } = handle::get(pid, b)?
+            else {
+                return Err(SyscallError::InvalidArgument);
+            };
+            // Its master's holder needs no unlocking
+            pty::set_locked(pty, false)?;
+            pty::open_slave(pty)?;
+            Object::Pty {
+                pty,
+                end: pty::End::Slave,
+            }
+        }
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    Ok(handle::open(pid, object)?)
+}
+
+/// handle
+fn sys_close(context: &mut UserContext) -> Result<u64, SyscallError> {
+    handle::close(caller(), context.rdi)?;
+    Ok(0)
+}
+
+/// handle, target handle or `u64::MAX` for the lowest free one; returns
+/// the new handle
+fn sys_dup(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [fd, target, ..] = context.args();
+    let target = (target != u64::MAX).then_some(target);
+    Ok(handle::dup(caller(), fd, target)?)
+}
+
+/// handle, offset; sets where reads of an object or buffer continue
+fn sys_seek(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [fd, to, ..] = context.args();
+    handle::update(caller(), fd, |object| match object.offset_mut() {
+        Some(offset) => {
+            *offset = to;
+            Ok(to)
+        }
+        None => Err(SyscallError::InvalidArgument),
+    })?
+}
+
+/// limit in bytes, 0 to leave it; sets how far the caller's main stack
+/// may grow, returning the previous limit
+fn sys_stack_limit(context: &mut UserContext) -> Result<u64, SyscallError> {
+    Ok(process::set_stack_limit(process::current_thread(), context.rdi)?)
+}
+
+/// address, 0 to leave it; moves the caller's program break, returning
+/// the break
+fn sys_brk(context: &mut UserContext) -> Result<u64, SyscallError> {
+    Ok(process::set_break(process::current_thread(), context.rdi)?)
+}
+
+/// increment in bytes, which may be negative; moves the caller's program
+/// break, returning where it was
+fn sys_sbrk(context: &mut UserContext) -> Result<u64, SyscallError> {
+    Ok(process::adjust_break(process::current_thread(), context.rdi as i64)?)
+}
+
+/// personality: 0 native, 1 Linux; the caller's process makes its next
+/// calls through that interface
+fn sys_personality(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let personality = match context.rdi {
+        0 => Personality::Native,
+        1 => Personality::Linux,
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    process::set_personality(process::current_thread(), personality)?;
+    Ok(0)
+}
+
+/// address, value, timeout in ticks or 0 for none; waits while the
+/// 32-bit word at the address holds the value, until `SYS_FUTEX_WAKE`
+/// wakes the caller. `WouldBlock` if the value has already changed.
+fn sys_futex_wait(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [addr, value, ticks, ..] = context.args();
+    let timeout = (ticks != 0).then_some(Timeout::After(ticks));
+    futex::wait(context, addr, value as u32, futex::ANY, timeout)?;
+    Ok(0)
+}
+
+/// address, count; wakes up to that many threads of the caller's process
+/// waiting on the address, returning how many
+fn sys_futex_wake(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [addr, count, ..] = context.args();
+    Ok(futex::wake(process::current(), addr, count, futex::ANY))
+}
+
+/// buffer, length, sequence number; fills the buffer with kernel log
+/// records from that number on, a line each, and returns how many bytes
+/// were written
+fn sys_klog_read(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [addr, len, sequence, ..] = context.args();
+    let access = Access::open();
+    let buffer = uaccess::user_slice_mut(&access, addr, len as usize)?;
+    Ok(crate::kernel::klog::read(sequence, buffer) as u64)
+}
+
+/// What `SYS_POWER` does
+pub const POWER_OFF: u64 = 0;
+pub const POWER_REBOOT: u64 = 1;
+
+/// action; turns the machine off or reboots it, and returns only if
+/// turning it off failed. Needs the power permission.
+fn sys_power(context: &mut UserContext) -> Result<u64, SyscallError> {
+    capability::check_permission(caller(), Permission::Power)?;
+    match context.rdi {
+        POWER_OFF => {
+            let e = power::shutdown();
+            crate::klog!(Error, "Power off failed: {:?}", e);
+            Err(SyscallError::IoError)
+        }
+        POWER_REBOOT => power::reboot(),
+        _ => Err(SyscallError::InvalidArgument),
+    }
+}
+
+/// What `SYS_PERF` does
+pub const PERF_START: u64 = 0;
+pub const PERF_STOP: u64 = 1;
+pub const PERF_READ: u64 = 2;
+
+/// A thread's counts as `SYS_PERF` writes them
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct PerfCounts {
+    pub cycles: u64,
+    pub instructions: u64,
+    /// 0 where the processor cannot count them
+    pub llc_misses: u64,
+    /// 1 while the thread is being counted
+    pub counting: u64,
+}
+
+/// action, thread (0 for the caller), buffer for `PERF_READ`; starts or
+/// stops counting cycles, instructions and cache misses for the thread,
+/// or writes its [`PerfCounts`]. Returns the counters the processor has,
+/// a bit each in the order of [`pmu::Counter`]. Threads of other
+/// processes need the trace permission.
+fn sys_perf(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [action, tid, addr, ..] = context.args();
+    let pmu = pmu::pmu().ok_or(SyscallError::NotFound)?;
+    let tid = match tid as u32 {
+        0 => process::current_thread(),
+        tid => tid,
+    };
+    if process::thread_process(tid).ok_or(SyscallError::NotFound)? != caller() {
+        capability::check_permission(caller(), Permission::Trace)?;
+    }
+    match action {
+        PERF_START | PERF_STOP => process::set_counting(tid, action == PERF_START)?,
+        PERF_READ => {
+            let (counts, counting) = process::counts(tid)?;
+            let record = PerfCounts {
+                cycles: counts[pmu::Counter::Cycles as usize],
+                instructions: counts[pmu::Counter::Instructions as usize],
+                llc_misses: counts[pmu::Counter::LlcMisses as usize],
+                counting: counting as u64,
+            };
+            uaccess::write_value(addr, &record)?;
+        }
+        _ => return Err(SyscallError::InvalidArgument),
+    }
+    Ok(pmu.supported as u64)
+}
+
+/// What `SYS_IO` does
+pub const IO_RING_CREATE: u64 = 0;
+pub const IO_RING_DESTROY: u64 = 1;
+pub const IO_SUBMIT: u64 = 2;
+pub const IO_DOORBELL: u64 = 3;
+pub const IO_REAP: u64 = 4;
+
+/// Operations of an [`IoRecord`]
+pub const IO_READ: u32 = 0;
+pub const IO_WRITE: u32 = 1;
+pub const IO_FLUSH: u32 = 2;
+
+/// Flags of a write in an [`IoRecord`]
+pub const IO_PREFLUSH: u32 = 1 << 0;
+pub const IO_FUA: u32 = 1 << 1;
+
+/// A submission as `IO_SUBMIT` reads it
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct IoRecord {
+    pub op: u32,
+    pub device: u32,
+    /// Byte offset on the device
+    pub offset: u64,
+    pub buffer: u64,
+    pub length: u32,
+    pub flags: u32,
+    /// Returned in the completion
+    pub user_data: u64,
+}
+
+/// A completion as `IO_REAP` writes it
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct IoCompletionRecord {
+    pub user_data: u64,
+    /// Bytes transferred, or the negated error code
+    pub result: i64,
+}
+
+/// action, then the priority class (0 realtime, 1 best effort, 2 idle)
+/// and a channel the caller receives on to notify (`u64::MAX` for none)
+/// for `IO_RING_CREATE`, or else the ring and, for `IO_SUBMIT` and
+/// `IO_REAP`, an [`IoRecord`] to read or [`IoCompletionRecord`] to write.
+/// Creating a ring returns its ID, and ringing its doorbell the number of
+/// submissions started; reaping with no completion waiting is
+/// `WouldBlock`. Reads need the read permission, and writes and flushes
+/// the write permission.
+fn sys_io(context: &mut UserContext) -> Result<u64, SyscallError> {
+    let [action, ring, addr, ..] = context.args();
+    let pid = caller();
+    match action {
+        IO_RING_CREATE => {
+            let priority = match ring {
+                0 => IoPriority::Realtime,
+                1 => IoPriority::BestEffort,
+                2 => IoPriority::Idle,
+                _ => return Err(SyscallError::InvalidArgument),
+            };
+            let channel = match addr {
+                u64::MAX => None,
+                channel if ipc::receiver(channel)? == pid => Some(channel),
+                _ => return Err(SyscallError::PermissionDenied),
+            };
+            Ok(aio::io_ring_create(pid, channel, priority)?)
+        }
+        IO_RING_DESTROY => {
+            aio::io_ring_destroy(pid, ring)?;
+            Ok(0)
+        }
+        IO_SUBMIT => {
+            let record: IoRecord = uaccess::read_value(addr)?;
+            let (op, permission) = match record.op {
+                IO_READ => (IoOp::Read, Permission::Read),
+                IO_WRITE => (IoOp::Write, Permission::Write),
+                IO_FLUSH => (IoOp::Flush, Permission::Write),
+                _ => return Err(SyscallError::InvalidArgument),
+            };
+            if record.flags & !(IO_PREFLUSH | IO_FUA) != 0 {
+                return Err(SyscallError::InvalidArgument);
+            }
+            capability::check_permission(pid, permission)?;
+            let mut flags = WriteFlags::NONE;
+            if record.flags & IO_PREFLUSH != 0 {
+                flags = flags | WriteFlags::PREFLUSH;
+            }
+            if record.flags & IO_FUA != 0 {
+                flags = flags | WriteFlags::FUA;
+            }
+            let desc = IoDescriptor {
+                op,
+                device: record.device,
+                offset: record.offset,
+                buffer: record.buffer,
+                length: record.length,
+                flags,
+                user_data: record.user_data,
+            };
+            aio::io_submit(pid, ring, desc)?;
+            Ok(0)
+        }
+        IO_DOORBELL => Ok(aio::io_doorbell(pid, ring)? as u64),
+        IO_REAP => {
+            let completion = aio::io_reap(pid, ring)?.ok_or(SyscallError::WouldBlock)?;
+            let record = IoCompletionRecord {
+                user_data: completion.user_data,
+                result: match completion.result {
+                    Ok(len) => len as i64,
+                    Err(e) => -SyscallError::from(e).code(),
+                },
+            };
+            uaccess::write_value(addr, &record)?;
+            Ok(0)
+        }
+        _ => Err(SyscallError::InvalidArgument),
+    }
+}
+
+/// System call errors, returned negated in `rax`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    InvalidSyscall,
+    /// A pointer argument is not accessible user memory
+    BadAddress,
+    InvalidArgument,
+    PermissionDenied,
+    NotFound,
+    OutOfMemory,
+    /// Nothing to receive yet
+    WouldBlock,
+    TooLarge,
+    IoError,
+    /// No room left in a kernel table
+    Exhausted,
+    /// Not a valid executable
+    BadExecutable,
+    /// No such child to wait for
+    NoChild,
+    /// Not a handle the caller holds
+    BadHandle,
+    /// Written to a pipe nothing reads
+    BrokenPipe,
+    /// A wait ran out of time
+    TimedOut,
+}
+
+impl SyscallError {
+    /// Code returned (negated) to the program
+    pub fn code(self) -> i64 {
+        match self {
+            SyscallError::InvalidSyscall => 1,
+            SyscallError::BadAddress => 2,
+            SyscallError::InvalidArgument => 3,
+            SyscallError::PermissionDenied => 4,
+            SyscallError::NotFound => 5,
+            SyscallError::OutOfMemory => 6,
+            SyscallError::WouldBlock => 7,
+            SyscallError::TooLarge => 8,
+            SyscallError::IoError => 9,
+            SyscallError::Exhausted => 10,
+            SyscallError::BadExecutable => 11,
+            SyscallError::NoChild => 12,
+            SyscallError::BadHandle => 13,
+            SyscallError::BrokenPipe => 14,
+            SyscallError::TimedOut => 15,
+        }
+    }
+}
+
+impl From<IpcError> for SyscallError {
+    fn from(e: IpcError) -> Self {
+        match e {
+            IpcError::BufferFull => SyscallError::WouldBlock,
+            IpcError::BufferEmpty => SyscallError::WouldBlock,
+            IpcError::MessageTooLarge => SyscallError::TooLarge,
+            IpcError::InvalidChannel | IpcError::InvalidGrant => SyscallError::NotFound,
+            IpcError::PermissionDenied => SyscallError::PermissionDenied,
+            IpcError::OutOfMemory => SyscallError::OutOfMemory,
+            IpcError::TooManyChannels | IpcError::TooManyGrants => SyscallError::Exhausted,
+            IpcError::InvalidMessage | IpcError::NameInUse => SyscallError::InvalidArgument,
+        }
+    }
+}
+
+impl From<TagFsError> for SyscallError {
+    fn from(e: TagFsError) -> Self {
+        match e {
+            TagFsError::ObjectNotFound => SyscallError::NotFound,
+            TagFsError::InvalidTag => SyscallError::InvalidArgument,
+            TagFsError::HashTableFull | TagFsError::StorageFull | TagFsError::TooManyWatchers => {
+                SyscallError::Exhausted
+            }
+            TagFsError::IoError | TagFsError::DeviceGone => SyscallError::IoError,
+        }
+    }
+}
+
+impl From<VfsError> for SyscallError {
+    fn from(e: VfsError) -> Self {
+        Errno::from(e).into()
+    }
+}
+
+impl From<PipeError> for SyscallError {
+    fn from(e: PipeError) -> Self {
+        Errno::from(e).into()
+    }
+}
+
+impl From<PtyError> for SyscallError {
+    fn from(e: PtyError) -> Self {
+        Errno::from(e).into()
+    }
+}
+
+impl From<FutexError> for SyscallError {
+    fn from(e: FutexError) -> Self {
+        Errno::from(e).into()
+    }
+}
+
+impl From<StorageError> for SyscallError {
+    fn from(e: StorageError) -> Self {
+        match e {
+            StorageError::DeviceNotFound | StorageError::DeviceGone | StorageError::InvalidRing => {
+                SyscallError::NotFound
+            }
+            StorageError::QueueFull => SyscallError::WouldBlock,
+            StorageError::TooManyRings => SyscallError::Exhausted,
+            StorageError::InvalidBuffer => SyscallError::BadAddress,
+            StorageError::OutOfRange => SyscallError::InvalidArgument,
+            StorageError::OutOfMemory => SyscallError::OutOfMemory,
+            StorageError::ReadOnly => SyscallError::PermissionDenied,
+            _ => SyscallError::IoError,
+        }
+    }
+}
+
+impl From<HandleError> for SyscallError {
+    fn from(e: HandleError) -> Self {
+        match e {
+            HandleError::BadHandle => SyscallError::BadHandle,
+            HandleError::TooManyHandles | HandleError::TooManyOpen => SyscallError::Exhausted,
+        }
+    }
+}
+
+impl From<GpuError> for SyscallError {
+    fn from(e: GpuError) -> Self {
+        match e {
+            GpuError::PermissionDenied => SyscallError::PermissionDenied,
+            GpuError::OutOfMemory => SyscallError::OutOfMemory,
+            GpuError::TooManyBuffers => SyscallError::Exhausted,
+            GpuError::InvalidBuffer => SyscallError::NotFound,
+            _ => SyscallError::InvalidArgument,
+        }
+    }
+}
+
+impl From<CapabilityError> for SyscallError {
+    fn from(_: CapabilityError) -> Self {
+        SyscallError::PermissionDenied
+    }
+}
+
+impl From<super::UserError> for SyscallError {
+    fn from(e: super::UserError) -> Self {
+        match e {
+            super::UserError::InvalidProgram(_) | super::UserError::BadAddress => SyscallError::BadExecutable,
+            super::UserError::BadArguments | super::UserError::BadSetting => SyscallError::InvalidArgument,
+            super::UserError::Map(e) => e.into(),
+            super::UserError::Storage(e) => e.into(),
+            super::UserError::TooManyProcesses | super::UserError::TooManyThreads => SyscallError::Exhausted,
+            super::UserError::NoSuchProcess => SyscallError::NoChild,
+            super::UserError::NoSuchThread => SyscallError::NotFound,
+        }
+    }
+}
+
+impl From<MapError> for SyscallError {
+    fn from(e: MapError) -> Self {
+        match e {
+            MapError::OutOfMemory | MapError::RegionExhausted => SyscallError::OutOfMemory,
+            _ => SyscallError::BadAddress,
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Thread-local storage
+//!
+//! An executable's `PT_TLS` segment is the template of every thread's TLS
+//! block: the initialized part is copied from the image and the rest is
+//! zeroed. Blocks follow the x86-64 ELF layout, variant II: the block ends
+//! at the thread pointer, which is the `fs` base, and the thread pointer
+//! points at a control block whose first word is the thread pointer
+//! itself, so code finds it with `mov rax, fs:0`.
+//!
+//! Each thread of a process has a numbered slot in a region below the
+//! initial stack, mapped when the thread starts; a slot is reused once
+//! the thread in it has been joined. A runtime that lays out its own
+//! blocks can point `fs` elsewhere with `SYS_SET_FS_BASE`.
+
+use x86_64::structures::paging::{Page, PageTableFlags as Flags};
+use x86_64::VirtAddr;
+
+use super::address_space::AddressSpace;
+use super::elf::{ElfError, Tls};
+use super::UserError;
+
+/// Start of the TLS block region
+pub const TLS_START: u64 = 0x70_0000_0000;
+
+/// End of the TLS block region, exclusive
+pub const TLS_END: u64 = 0x78_0000_0000;
+
+/// Size of the thread control block: the self pointer, then room for the
+/// runtime's own per-thread words
+pub const TCB_SIZE: u64 = 64;
+
+/// Bytes copied at a time
+const CHUNK: usize = 512;
+
+fn align_up(value: u64, align: u64) -> Option<u64> {
+    Some(value.checked_add(align - 1)? & !(align - 1))
+}
+
+/// Bytes from the start of the block to the thread pointer
+fn block_size(tls: &Tls) -> Option<u64> {
+    align_up(tls.mem_size, tls.align)
+}
+
+/// Address space one slot takes
+fn slot_size(tls: &Tls) -> Option<u64> {
+    align_up(block_size(tls)?.checked_add(TCB_SIZE)?, 4096)
+}
+
+/// Map and fill the TLS block in `slot` of `space`, returning the thread
+/// pointer to load into the `fs` base
+pub fn setup(space: &mut AddressSpace, tls: &Tls, slot: usize) -> Result<u64, UserError> {
+    let (block_size, slot_size) = block_size(tls)
+        .zip(slot_size(tls))
+        .ok_or(UserError::InvalidProgram(ElfError::Unsupported))?;
+    let base = (slot as u64)
+        .checked_mul(slot_size)
+        .and_then(|offset| offset.checked_add(TLS_START))
+        .filter(|base| base.checked_add(slot_size).is_some_and(|end| end <= TLS_END))
+        .ok_or(UserError::TooManyThreads)?;
+    let first = Page::containing_address(VirtAddr::new(base));
+    let last = Page::containing_address(VirtAddr::new(base + slot_size - 1));
+    for page in Page::range_inclusive(first, last) {
+        space.map(page, Flags::WRITABLE | Flags::NO_EXECUTE)?;
+    }
+
+    // A reused slot still holds the last thread's values
+    let zeros = [0u8; CHUNK];
+    let mut done = 0;
+    while done < slot_size {
+        let len = (slot_size - done).min(CHUNK as u64);
+        space.write(VirtAddr::new(base + done), &zeros[..len as usize])?;
+        done += len;
+    }
+
+    let mut chunk = [0u8; CHUNK];
+    let mut done = 0;
+    while done < tls.file_size {
+        let len = (tls.file_size - done).min(CHUNK as u64) as usize;
+        space.read(VirtAddr::new(tls.vaddr + done), &mut chunk[..len])?;
+        space.write(VirtAddr::new(base + done), &chunk[..len])?;
+        done += len as u64;
+    }
+
+    let pointer = base + block_size;
+    space.write(VirtAddr::new(pointer), &pointer.to_le_bytes())?;
+    Ok(pointer)
+}
@@ -0,0 +1,170 @@
+//! Access to user memory from system calls
+//!
+//! System calls run on the caller's page tables, so user buffers are
+//! reachable directly once they are checked: every page of the range must
+//! lie in the user half and be mapped user-accessible, and writable when
+//! the kernel writes to it. Copy-on-write pages in a range the kernel
+//! writes to are copied during the check, and a range on the stack below
+//! its mapped pages grows it. Nothing user memory holds is trusted
+//! beyond the copy that was made of it.
+//!
+//! Where the processor has SMAP the kernel faults on any user page it
+//! touches while RFLAGS.AC is clear, so user memory is only reachable
+//! inside an [`Access`]: the copies here open one of their own, and a
+//! slice into user memory borrows one the system call opened, so it
+//! cannot be used once the access has closed. Entry from user mode and
+//! every way back to the scheduler close it whatever was left open.
+
+use core::arch::asm;
+use core::marker::PhantomData;
+use core::mem::size_of;
+
+use x86_64::registers::rflags::{self, RFlags};
+use x86_64::structures::paging::PageTableFlags as Flags;
+use x86_64::VirtAddr;
+
+use super::address_space::{AddressSpace, USER_END, USER_START};
+use super::cow::COW;
+use super::process;
+use super::stack::{self, Growth};
+use super::syscall::SyscallError;
+use crate::kernel::cpu;
+
+/// User memory opened to the kernel, until dropped
+///
+/// AC is put back as it was, so accesses nest.
+pub struct Access {
+    was_open: bool,
+    /// The flag is the CPU's, so an access cannot move to another
+    _cpu: PhantomData<*const ()>,
+}
+
+impl Access {
+    pub fn open() -> Self {
+        let was_open = rflags::read().contains(RFlags::ALIGNMENT_CHECK);
+        if cpu::smap_enabled() && !was_open {
+            unsafe { asm!("stac", options(nomem, nostack)) };
+        }
+        Access { was_open, _cpu: PhantomData }
+    }
+}
+
+impl Drop for Access {
+    fn drop(&mut self) {
+        if cpu::smap_enabled() && !self.was_open {
+            unsafe { asm!("clac", options(nomem, nostack)) };
+        }
+    }
+}
+
+/// Close user memory, on entry from user mode, which can leave AC set,
+/// and on leaving a system call without unwinding its accesses
+pub fn close() {
+    if cpu::smap_enabled() {
+        unsafe { asm!("clac", options(nomem, nostack)) };
+    }
+}
+
+/// Check that `len` bytes at `addr` are user memory the caller may read,
+/// or write when `write` is set
+pub fn check(addr: u64, len: usize, write: bool) -> Result<(), SyscallError> {
+    if len == 0 {
+        return Ok(());
+    }
+    check_in(&mut AddressSpace::active(), addr, len, write, true)
+}
+
+/// [`check`] against `space`, growing the stack only if `grow` is set,
+/// as only the active process's stack can be grown
+fn check_in(space: &mut AddressSpace, addr: u64, len: usize, write: bool, grow: bool) -> Result<(), SyscallError> {
+    let end = addr.checked_add(len as u64).ok_or(SyscallError::BadAddress)?;
+    if addr < USER_START || end > USER_END {
+        return Err(SyscallError::BadAddress);
+    }
+
+    let mut page = addr & !0xfff;
+    while page < end {
+        if space.flags(VirtAddr::new(page)).is_none() && !(grow && stack::grow(space, page) == Growth::Grown) {
+            return Err(SyscallError::BadAddress);
+        }
+        let mut flags = space.flags(VirtAddr::new(page)).ok_or(SyscallError::BadAddress)?;
+        if write && flags.contains(COW) && space.resolve_cow(VirtAddr::new(page))? {
+            flags |= Flags::WRITABLE;
+        }
+        if !flags.contains(Flags::USER_ACCESSIBLE) || (write && !flags.contains(Flags::WRITABLE)) {
+            return Err(SyscallError::BadAddress);
+        }
+        page += 4096;
+    }
+    Ok(())
+}
+
+/// A checked user buffer to read from, for as long as `access` is open
+pub fn user_slice(_access: &Access, addr: u64, len: usize) -> Result<&[u8], SyscallError> {
+    check(addr, len, false)?;
+    if len == 0 {
+        return Ok(&[]);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(addr as *const u8, len) })
+}
+
+/// A checked user buffer to write to, for as long as `access` is open
+#[allow(clippy::mut_from_ref)]
+pub fn user_slice_mut(_access: &Access, addr: u64, len: usize) -> Result<&mut [u8], SyscallError> {
+    check(addr, len, true)?;
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    Ok(unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len) })
+}
+
+/// Copy user memory at `addr` into `buffer`
+pub fn copy_from_user(buffer: &mut [u8], addr: u64) -> Result<(), SyscallError> {
+    let access = Access::open();
+    buffer.copy_from_slice(user_slice(&access, addr, buffer.len())?);
+    Ok(())
+}
+
+/// Copy `data` to user memory at `addr`
+pub fn copy_to_user(addr: u64, data: &[u8]) -> Result<(), SyscallError> {
+    let access = Access::open();
+    user_slice_mut(&access, addr, data.len())?.copy_from_slice(data);
+    Ok(())
+}
+
+/// Copy the memory of process `pid` at `addr` into `buffer`, for work done
+/// on its behalf when its address space may not be the active one
+pub fn copy_from_process(pid: u32, buffer: &mut [u8], addr: u64) -> Result<(), SyscallError> {
+    process::with_space(pid, |space| {
+        check_in(space, addr, buffer.len(), false, false)?;
+        space.read(VirtAddr::new(addr), buffer).map_err(|_| SyscallError::BadAddress)
+    })?
+}
+
+/// Copy `data` to the memory of process `pid` at `addr`, as
+/// [`copy_from_process`]
+pub fn copy_to_process(pid: u32, addr: u64, data: &[u8]) -> Result<(), SyscallError> {
+    process::with_space(pid, |space| {
+        check_in(space, addr, data.len(), true, false)?;
+        space.write(VirtAddr::new(addr), data).map_err(|_| SyscallError::BadAddress)
+    })?
+}
+
+/// Read a plain value, valid whatever its bytes, from user memory at `addr`
+pub fn read_value<T: Copy>(addr: u64) -> Result<T, SyscallError> {
+    check(addr, size_of::<T>(), false)?;
+    let _access = Access::open();
+    Ok(unsafe { core::ptr::read_unaligned(addr as *const T) })
+}
+
+/// Write a plain value to user memory at `addr`
+pub fn write_value<T: Copy>(addr: u64, value: &T) -> Result<(), SyscallError> {
+    let bytes = unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    copy_to_user(addr, bytes)
+}
+
+/// A string of `len` bytes at `addr`, which must be UTF-8, for as long as
+/// `access` is open
+pub fn user_str(access: &Access, addr: u64, len: usize) -> Result<&str, SyscallError> {
+    core::str::from_utf8(user_slice(access, addr, len)?).map_err(|_| SyscallError::InvalidArgument)
+}
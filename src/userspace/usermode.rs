@@ -0,0 +1,248 @@
+//! Entering and leaving ring 3
+//!
+//! [`run`] switches to a program's address space and enters it with
+//! `iretq` from a saved [`UserContext`], after saving the kernel's
+//! callee-saved registers and stack pointer. Every way back into the
+//! kernel saves the user registers in the same layout: system calls, the
+//! timer interrupt and exceptions. When the program has to stop running,
+//! for good or until it is resumed, the kernel records why and jumps back
+//! to the saved kernel stack, so `run` returns as if the program had
+//! called it.
+//!
+//! A program runs for at most [`SLICE_TICKS`] before the timer preempts
+//! it. An exception raised by user code never reaches the kernel's fault
+//! paths; it stops the program instead, unless it is a write to a
+//! copy-on-write page, which is copied and the write retried.
+
+use core::arch::global_asm;
+
+use spin::Mutex;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::FsBase;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+use super::address_space::AddressSpace;
+use super::stack::{self, Growth, STACK_OVERFLOW};
+use crate::kernel::gdt;
+
+/// RFLAGS for user code: interrupts enabled, reserved bit 1 set
+const USER_RFLAGS: u64 = 0x202;
+
+/// Page fault error code bits of a write to a present page
+const WRITE_PROTECTION_FAULT: u64 = 0b11;
+
+/// Page fault error code bit set when the page was present
+const PAGE_PRESENT: u64 = 0b1;
+
+/// Timer ticks a program runs before it is preempted
+pub const SLICE_TICKS: u64 = 2;
+
+/// User registers, in the order the entry stubs push them, ending in an
+/// `iretq` frame
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UserContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl UserContext {
+    /// Registers for a program starting at `entry` on `stack`
+    pub fn new(entry: u64, stack: u64) -> Self {
+        let selectors = gdt::selectors();
+        Self {
+            rip: entry,
+            cs: selectors.user_code.0 as u64,
+            rflags: USER_RFLAGS,
+            rsp: stack,
+            ss: selectors.user_data.0 as u64,
+            ..Self::default()
+        }
+    }
+
+    /// Whether the registers were saved from ring 3
+    pub fn is_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+/// Why a program stopped running
+#[derive(Clone, Copy, Debug)]
+pub enum Exit {
+    /// The program asked to exit
+    Exited(i32),
+    /// The running thread asked to exit
+    ThreadExited(i32),
+    /// An exception in user mode
+    Fault {
+        vector: u8,
+        error_code: Option<u64>,
+        /// Faulting address, for page faults
+        address: Option<u64>,
+        /// Registers when it was raised
+        context: UserContext,
+    },
+    /// Its time slice ran out
+    Preempted(UserContext),
+    /// It gave up the CPU
+    Yielded(UserContext),
+    /// It has to wait; the context repeats the system call when resumed
+    Blocked(UserContext),
+}
+
+/// Kernel stack pointer saved by `user_enter`
+static mut KERNEL_RSP: u64 = 0;
+
+/// How the running program stopped, set before jumping back
+static EXIT: Mutex<Option<Exit>> = Mutex::new(None);
+
+/// Tick the running program's slice started at
+static SLICE_START: Mutex<u64> = Mutex::new(0);
+
+global_asm!(
+    r#"
+.global user_enter
+user_enter:
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rsi], rsp
+    mov rsp, rdi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    iretq
+
+.global user_leave
+user_leave:
+    mov rsp, rdi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    ret
+"#
+);
+
+extern "C" {
+    /// Save the kernel context in `*saved_rsp` and resume `context`
+    fn user_enter(context: *const UserContext, saved_rsp: *mut u64);
+    /// Return from `user_enter` on the stack it saved
+    fn user_leave(saved_rsp: u64) -> !;
+}
+
+/// Run a program in ring 3 from `context`, on the address space whose
+/// level 4 table is `l4`, until it stops
+///
+/// `fs_base` is loaded before the program runs and updated with the base
+/// it stopped with, which `SYS_SET_FS_BASE` may have changed.
+pub fn run(context: &UserContext, fs_base: &mut u64, l4: PhysFrame) -> Exit {
+    let interrupts = x86_64::instructions::interrupts::are_enabled();
+    let (kernel_space, flags) = Cr3::read();
+    let entry = *context;
+    *EXIT.lock() = None;
+    *SLICE_START.lock() = crate::scheduler::ticks();
+
+    FsBase::write(VirtAddr::new_truncate(*fs_base));
+    unsafe {
+        Cr3::write(l4, flags);
+        user_enter(&entry, &raw mut KERNEL_RSP);
+        Cr3::write(kernel_space, flags);
+    }
+    *fs_base = FsBase::read().as_u64();
+
+    // Every way out leaves with interrupts off
+    if interrupts {
+        x86_64::instructions::interrupts::enable();
+    }
+    EXIT.lock().take().expect("user program stopped without a reason")
+}
+
+/// Stop the running program for `exit` and return to [`run`]
+pub fn leave(exit: Exit) -> ! {
+    x86_64::instructions::interrupts::disable();
+    super::uaccess::close();
+    *EXIT.lock() = Some(exit);
+    unsafe { user_leave(KERNEL_RSP) }
+}
+
+/// Called from the timer interrupt: preempt the program once its slice
+/// is used up
+pub fn tick(context: &UserContext) {
+    if !context.is_user() {
+        return;
+    }
+    let start = *SLICE_START.lock();
+    if crate::scheduler::ticks() >= start + SLICE_TICKS {
+        leave(Exit::Preempted(*context));
+    }
+}
+
+/// Called first by exception handlers: an exception raised in user mode
+/// stops the program instead of the kernel, unless it is a write to a
+/// copy-on-write page, which is copied and the write retried, or an
+/// access to the stack below its mapped pages, which grows it
+pub fn check_fault(vector: u8, context: &UserContext, error_code: Option<u64>, address: Option<u64>) {
+    if !context.is_user() {
+        return;
+    }
+    let mut vector = vector;
+    if vector == 14 && error_code.is_some_and(|e| e & PAGE_PRESENT == 0) {
+        if let Some(address) = address {
+            match stack::grow(&mut AddressSpace::active(), address) {
+                Growth::Grown => return,
+                Growth::Overflow => vector = STACK_OVERFLOW,
+                Growth::Outside => {}
+            }
+        }
+    }
+    if vector == 14 && error_code.is_some_and(|e| e & WRITE_PROTECTION_FAULT == WRITE_PROTECTION_FAULT) {
+        if let Some(address) = address {
+            if let Ok(true) = AddressSpace::active().resolve_cow(VirtAddr::new(address)) {
+                return;
+            }
+        }
+    }
+    leave(Exit::Fault {
+        vector,
+        error_code,
+        address,
+        context: *context,
+    });
+}
@@ -0,0 +1,40 @@
+#!/bin/sh
+# Fill the symbol table of a linked kernel, for panic backtraces:
+#
+#     tools/embed-symbols.sh target/x86_64-zen_os/debug/zen-os
+#
+# Run it after `cargo build` and before the boot image is made. The table
+# has a `ZSYM` line, then the kernel's functions in address order as
+# `<address> <size> <name>`, padded with zeros to the size of the
+# `.zen_symbols` section, which it must fit.
+set -eu
+
+kernel=$1
+table=$(mktemp)
+trap 'rm -f "$table"' EXIT
+
+size=$(objdump -h "$kernel" | awk '$2 == ".zen_symbols" { print $3 }')
+if [ -z "$size" ]; then
+    echo "$kernel has no .zen_symbols section" >&2
+    exit 1
+fi
+size=$((0x$size))
+
+{
+    echo ZSYM
+    nm --defined-only --numeric-sort --print-size --demangle "$kernel" |
+        awk 'NF >= 4 && ($3 == "t" || $3 == "T") {
+            name = $4
+            for (i = 5; i <= NF; i++) name = name " " $i
+            print $1, $2, name
+        }'
+} > "$table"
+
+used=$(wc -c < "$table")
+if [ "$used" -ge "$size" ]; then
+    echo "symbol table needs $used bytes; .zen_symbols holds $size" >&2
+    exit 1
+fi
+truncate -s "$size" "$table"
+objcopy --update-section .zen_symbols="$table" "$kernel"
+echo "$(($(wc -l < "$table") - 1)) symbols embedded in $kernel"
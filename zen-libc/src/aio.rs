@@ -0,0 +1,103 @@
+//! Asynchronous block I/O
+//!
+//! A ring queues reads, writes and flushes of a storage device, which
+//! start when its doorbell is rung and finish in any order; each
+//! completion carries back the `user_data` of its submission. A ring can
+//! name a channel of the caller's to be sent a message when completions
+//! are waiting. Reads need the `read` permission, and writes and flushes
+//! the `write` permission.
+
+use crate::ipc::Channel;
+use crate::syscall::{sys, Error, SYS_IO};
+use crate::Result;
+
+const IO_RING_CREATE: u64 = 0;
+const IO_RING_DESTROY: u64 = 1;
+const IO_SUBMIT: u64 = 2;
+const IO_DOORBELL: u64 = 3;
+const IO_REAP: u64 = 4;
+
+/// Operations of a [`Submission`]
+pub const READ: u32 = 0;
+pub const WRITE: u32 = 1;
+/// Completes once everything submitted before it is durable
+pub const FLUSH: u32 = 2;
+
+/// Flags of a write: flush the device cache before it, and have it on
+/// stable media when it completes
+pub const PREFLUSH: u32 = 1 << 0;
+pub const FUA: u32 = 1 << 1;
+
+/// Message type of the notification sent on a ring's channel, carrying
+/// the number of completions posted as a `u32`
+pub const COMPLETION_MSG: u32 = 0x494F_0001;
+
+/// Scheduling class of a ring's requests
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    Realtime = 0,
+    BestEffort = 1,
+    /// Only when nothing else is waiting
+    Idle = 2,
+}
+
+/// A request, as the kernel reads it
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Submission {
+    pub op: u32,
+    pub device: u32,
+    /// Byte offset on the device
+    pub offset: u64,
+    /// Must stay valid until the request completes
+    pub buffer: u64,
+    pub length: u32,
+    pub flags: u32,
+    pub user_data: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Completion {
+    user_data: u64,
+    result: i64,
+}
+
+/// A ring, by its kernel ID
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ring(pub u64);
+
+impl Ring {
+    /// Create a ring, notifying `channel` of completions if given
+    pub fn create(priority: Priority, channel: Option<Channel>) -> Result<Self> {
+        let channel = channel.map_or(u64::MAX, |channel| channel.0);
+        sys!(SYS_IO, IO_RING_CREATE, priority as u64, channel).map(Ring)
+    }
+
+    /// Destroy the ring, dropping completions not yet reaped
+    pub fn destroy(self) -> Result<()> {
+        sys!(SYS_IO, IO_RING_DESTROY, self.0).map(|_| ())
+    }
+
+    /// Queue a request; `WouldBlock` if the ring is full
+    pub fn submit(&self, submission: &Submission) -> Result<()> {
+        sys!(SYS_IO, IO_SUBMIT, self.0, submission as *const Submission).map(|_| ())
+    }
+
+    /// Start the queued requests, returning how many were started
+    pub fn doorbell(&self) -> Result<usize> {
+        sys!(SYS_IO, IO_DOORBELL, self.0).map(|started| started as usize)
+    }
+
+    /// The `user_data` of a finished request and the bytes it transferred
+    /// or why it failed; `WouldBlock` if none has finished
+    pub fn reap(&self) -> Result<(u64, Result<usize>)> {
+        let mut completion = Completion::default();
+        sys!(SYS_IO, IO_REAP, self.0, &mut completion as *mut Completion)?;
+        let result = match completion.result {
+            len if len >= 0 => Ok(len as usize),
+            code => Err(Error::from_code(-code)),
+        };
+        Ok((completion.user_data, result))
+    }
+}
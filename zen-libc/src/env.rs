@@ -0,0 +1,77 @@
+//! Arguments and environment
+//!
+//! The kernel leaves both on the new program's stack as NUL-terminated
+//! strings, which stay in place for the program's life.
+
+use core::ffi::CStr;
+
+struct Vectors {
+    argc: usize,
+    argv: *const *const u8,
+    envp: *const *const u8,
+}
+
+static mut VECTORS: Vectors = Vectors {
+    argc: 0,
+    argv: core::ptr::null(),
+    envp: core::ptr::null(),
+};
+
+/// Record the vectors the program started with
+///
+/// # Safety
+/// Called once, from the entry point, with what the kernel passed.
+pub(crate) unsafe fn init(argc: usize, argv: *const *const u8, envp: *const *const u8) {
+    VECTORS = Vectors { argc, argv, envp };
+}
+
+/// The strings of a null-terminated vector
+pub struct Strings {
+    next: *const *const u8,
+}
+
+impl Iterator for Strings {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<&'static str> {
+        if self.next.is_null() {
+            return None;
+        }
+        loop {
+            let s = unsafe { *self.next };
+            if s.is_null() {
+                self.next = core::ptr::null();
+                return None;
+            }
+            self.next = unsafe { self.next.add(1) };
+            // Strings that are not UTF-8 are skipped
+            if let Ok(s) = unsafe { CStr::from_ptr(s.cast()) }.to_str() {
+                return Some(s);
+            }
+        }
+    }
+}
+
+/// Number of arguments, the program name included
+pub fn argc() -> usize {
+    unsafe { (*core::ptr::addr_of!(VECTORS)).argc }
+}
+
+/// The program's arguments, starting with its name
+pub fn args() -> Strings {
+    Strings {
+        next: unsafe { (*core::ptr::addr_of!(VECTORS)).argv },
+    }
+}
+
+/// The environment's `NAME=value` strings
+pub fn vars() -> Strings {
+    Strings {
+        next: unsafe { (*core::ptr::addr_of!(VECTORS)).envp },
+    }
+}
+
+/// Value of an environment variable
+pub fn var(name: &str) -> Option<&'static str> {
+    vars().find_map(|s| s.strip_prefix(name)?.strip_prefix('='))
+}
@@ -0,0 +1,81 @@
+//! Exception handlers
+//!
+//! A thread that registers a handler is given the exceptions it raises
+//! instead of being stopped with them: the handler runs on the stack it
+//! was registered with, or just below the thread's own, and gets an
+//! [`ExceptionInfo`]. It resumes the thread with [`resume`], from the
+//! registers in the info, after changing them if it wants to carry on
+//! somewhere else. An exception raised while the handler runs stops the
+//! process.
+
+use crate::syscall::{sys, SYS_EXCEPTION_RETURN, SYS_SET_EXCEPTION_HANDLER};
+use crate::Result;
+
+/// Exception vectors a handler can be given
+pub const DIVIDE_ERROR: u64 = 0;
+pub const INVALID_OPCODE: u64 = 6;
+pub const STACK_SEGMENT_FAULT: u64 = 12;
+pub const GENERAL_PROTECTION_FAULT: u64 = 13;
+pub const PAGE_FAULT: u64 = 14;
+pub const SIMD_FLOATING_POINT: u64 = 19;
+/// The main stack grew past its limit; only a handler with its own stack
+/// can take it
+pub const STACK_OVERFLOW: u64 = 32;
+
+/// Registers of the thread when it raised the exception
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// What a handler is given
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ExceptionInfo {
+    pub vector: u64,
+    /// Error code the CPU pushed, 0 for exceptions without one
+    pub error_code: u64,
+    /// Address a page fault was raised for
+    pub address: u64,
+    pub registers: Registers,
+}
+
+/// A handler; it must not return, but end in [`resume`] or an exit
+pub type Handler = extern "C" fn(&mut ExceptionInfo) -> !;
+
+/// Send the calling thread's exceptions to `handler`, run on the stack
+/// ending at `stack` (0 for the thread's own)
+pub fn set_handler(handler: Handler, stack: usize) -> Result<()> {
+    sys!(SYS_SET_EXCEPTION_HANDLER, handler as usize, stack).map(|_| ())
+}
+
+/// Let the calling thread's exceptions stop the process again
+pub fn clear_handler() -> Result<()> {
+    sys!(SYS_SET_EXCEPTION_HANDLER, 0, 0).map(|_| ())
+}
+
+/// Leave a handler, resuming the thread from `info.registers`
+pub fn resume(info: &ExceptionInfo) -> ! {
+    let _ = sys!(SYS_EXCEPTION_RETURN, info as *const ExceptionInfo);
+    unreachable!("exception return failed")
+}
@@ -0,0 +1,102 @@
+//! Handles
+//!
+//! What a program opens is reached through a handle, a small number
+//! indexing the process's own table in the kernel: TagFS objects, IPC
+//! channels, GPU buffer objects, timers, pseudo-terminals and the
+//! console's streams, which every program starts with at 0, 1 and 2. A
+//! child starts with copies of its parent's handles. Duplicates share the
+//! open object, read position included, and the object is released when
+//! its last handle is closed, which exit does for every handle left.
+
+use crate::syscall::{sys, SYS_CLOSE, SYS_DUP, SYS_OPEN, SYS_READ, SYS_SEEK, SYS_WRITE};
+use crate::Result;
+
+/// Handles one process may hold
+pub const MAX_HANDLES: usize = 64;
+
+/// Kinds of handle
+pub const KIND_CONSOLE: u64 = 0;
+pub const KIND_OBJECT: u64 = 1;
+pub const KIND_CHANNEL: u64 = 2;
+pub const KIND_BUFFER: u64 = 3;
+pub const KIND_TIMER: u64 = 4;
+pub const KIND_PTY: u64 = 5;
+
+/// Pixel formats of buffer objects
+pub const FORMAT_XRGB8888: u64 = 0;
+pub const FORMAT_XBGR8888: u64 = 1;
+
+/// A TagFS object, read from the start
+pub fn open_object(object: u64) -> Result<u64> {
+    sys!(SYS_OPEN, KIND_OBJECT, object)
+}
+
+/// An IPC channel; reads take the data of its next message and writes
+/// send one
+pub fn open_channel(channel: u64) -> Result<u64> {
+    sys!(SYS_OPEN, KIND_CHANNEL, channel)
+}
+
+/// A console stream, by its standard handle number
+pub fn open_console(stream: u64) -> Result<u64> {
+    sys!(SYS_OPEN, KIND_CONSOLE, stream)
+}
+
+/// A buffer object another process sent the handle of
+pub fn open_buffer(buffer: u64) -> Result<u64> {
+    sys!(SYS_OPEN, KIND_BUFFER, buffer)
+}
+
+/// A new buffer object, destroyed when its last handle is closed
+pub fn create_buffer(width: u32, height: u32, format: u64) -> Result<u64> {
+    sys!(SYS_OPEN, KIND_BUFFER, 0, width, height, format)
+}
+
+/// A timer expiring `delay` nanoseconds from now, then every `interval`
+/// nanoseconds unless that is 0; reading it waits for an expiry and gives
+/// how many there were as a `u64`
+pub fn timer(delay: u64, interval: u64) -> Result<u64> {
+    sys!(SYS_OPEN, KIND_TIMER, delay, interval)
+}
+
+/// The master of a new pseudo-terminal; what it writes is input to the
+/// slave, through the line discipline, and it reads the slave's output
+pub fn open_pty() -> Result<u64> {
+    sys!(SYS_OPEN, KIND_PTY, 0)
+}
+
+/// The slave of the pseudo-terminal whose master is `master`, to be the
+/// terminal of a program the master hosts
+pub fn open_pty_slave(master: u64) -> Result<u64> {
+    sys!(SYS_OPEN, KIND_PTY, 1, master)
+}
+
+/// Read into `buffer`, returning how many bytes were read, 0 at the end
+pub fn read(handle: u64, buffer: &mut [u8]) -> Result<usize> {
+    sys!(SYS_READ, handle, buffer.as_mut_ptr(), buffer.len()).map(|len| len as usize)
+}
+
+/// Write `data`, returning how many bytes were written
+pub fn write(handle: u64, data: &[u8]) -> Result<usize> {
+    sys!(SYS_WRITE, handle, data.as_ptr(), data.len()).map(|len| len as usize)
+}
+
+/// Set where reads of an object or buffer continue
+pub fn seek(handle: u64, offset: u64) -> Result<u64> {
+    sys!(SYS_SEEK, handle, offset)
+}
+
+/// Another handle to the same open object, the lowest free one
+pub fn dup(handle: u64) -> Result<u64> {
+    sys!(SYS_DUP, handle, u64::MAX)
+}
+
+/// Make `target` a handle to the same open object, closing what it was
+pub fn dup_to(handle: u64, target: u64) -> Result<u64> {
+    sys!(SYS_DUP, handle, target)
+}
+
+/// Close a handle
+pub fn close(handle: u64) -> Result<()> {
+    sys!(SYS_CLOSE, handle).map(|_| ())
+}
@@ -0,0 +1,124 @@
+//! Heap
+//!
+//! A bump allocator over pages mapped on demand with `SYS_MAP`, in a
+//! region well above where programs are linked. Memory is reused only
+//! once every allocation has been freed, like the kernel's own heap, so it
+//! suits programs that allocate in phases.
+//!
+//! Allocators of their own can instead grow the data area after the
+//! program with [`brk`] and [`sbrk`], which the kernel maps and unmaps as
+//! the program break moves.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::syscall::{sys, PROT_WRITE, SYS_BRK, SYS_MAP, SYS_SBRK};
+use crate::Result;
+
+/// Start of the heap region
+pub const HEAP_START: usize = 0x40_0000_0000;
+
+/// Largest the heap grows
+pub const HEAP_MAX: usize = 0x10_0000_0000;
+
+/// Bytes mapped at a time
+const GROW_SIZE: usize = 64 * 1024;
+
+struct State {
+    next: usize,
+    /// End of the mapped part
+    mapped: usize,
+    allocations: usize,
+}
+
+/// The allocator; a flag keeps one thread at a time in it, and the
+/// others yield until it is free
+pub struct Heap {
+    busy: AtomicBool,
+    state: core::cell::UnsafeCell<State>,
+}
+
+unsafe impl Sync for Heap {}
+
+impl Heap {
+    pub const fn new() -> Self {
+        Self {
+            busy: AtomicBool::new(false),
+            state: core::cell::UnsafeCell::new(State {
+                next: HEAP_START,
+                mapped: HEAP_START,
+                allocations: 0,
+            }),
+        }
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        while self.busy.swap(true, Ordering::Acquire) {
+            crate::process::yield_now();
+        }
+        let result = f(unsafe { &mut *self.state.get() });
+        self.busy.store(false, Ordering::Release);
+        result
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with(|state| {
+            let start = align_up(state.next, layout.align());
+            let Some(end) = start.checked_add(layout.size()).filter(|end| *end <= HEAP_START + HEAP_MAX) else {
+                return null_mut();
+            };
+            if end > state.mapped {
+                let grow = align_up(end - state.mapped, GROW_SIZE);
+                if sys!(SYS_MAP, state.mapped, grow, PROT_WRITE).is_err() {
+                    return null_mut();
+                }
+                state.mapped += grow;
+            }
+            state.next = end;
+            state.allocations += 1;
+            start as *mut u8
+        })
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+        self.with(|state| {
+            state.allocations -= 1;
+            if state.allocations == 0 {
+                state.next = HEAP_START;
+            }
+        });
+    }
+}
+
+/// Move the program break to `end`, returning it; memory between the
+/// end of the program and the break is readable and writable
+pub fn brk(end: usize) -> Result<usize> {
+    sys!(SYS_BRK, end).map(|end| end as usize)
+}
+
+/// The program break
+pub fn current_break() -> Result<usize> {
+    sys!(SYS_BRK, 0).map(|end| end as usize)
+}
+
+/// Move the program break by `increment` bytes, returning where it was,
+/// which is the start of the new memory when it grows
+pub fn sbrk(increment: isize) -> Result<*mut u8> {
+    sys!(SYS_SBRK, increment as i64).map(|old| old as *mut u8)
+}
+
+#[global_allocator]
+static HEAP: Heap = Heap::new();
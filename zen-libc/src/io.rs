@@ -0,0 +1,148 @@
+//! Console input and output
+//!
+//! Every program starts with standard input, output and error on the
+//! console. [`print!`] and [`println!`] write to standard output and
+//! [`eprint!`] and [`eprintln!`] to standard error; they format into a
+//! small buffer and write it out whenever it fills, so output needs no
+//! heap. Input arrives a line at a time, once Enter is pressed, while the
+//! program holds the console. The kernel's log can be read back too.
+
+use core::fmt::{self, Write};
+
+use crate::syscall::{sys, SYS_KLOG_READ, SYS_READ, SYS_WRITE};
+use crate::Result;
+
+/// Standard handles
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// Bytes formatted before they are written out
+const BUFFER_SIZE: usize = 256;
+
+/// Write bytes to a handle, usually a standard one, returning how many
+/// were written
+pub fn write(handle: u64, data: &[u8]) -> Result<usize> {
+    sys!(SYS_WRITE, handle, data.as_ptr(), data.len()).map(|len| len as usize)
+}
+
+/// Write all of `data` to a handle
+pub fn write_all(handle: u64, mut data: &[u8]) -> Result<()> {
+    while !data.is_empty() {
+        let len = write(handle, data)?;
+        data = &data[len..];
+    }
+    Ok(())
+}
+
+/// Write text to standard output
+pub fn write_str(text: &str) -> Result<()> {
+    write_all(STDOUT, text.as_bytes())
+}
+
+/// Read standard input into `buffer`, waiting for a line if none is
+/// ready; returns how many bytes were read, 0 at the end of input
+pub fn read(buffer: &mut [u8]) -> Result<usize> {
+    sys!(SYS_READ, STDIN, buffer.as_mut_ptr(), buffer.len()).map(|len| len as usize)
+}
+
+/// Read one line of standard input into `buffer`, without its newline;
+/// a line longer than `buffer` is cut short and the rest left for the
+/// next read. Returns `None` at the end of input.
+pub fn read_line(buffer: &mut [u8]) -> Result<Option<&str>> {
+    let mut len = 0;
+    while len < buffer.len() {
+        let mut byte = [0u8];
+        if read(&mut byte)? == 0 {
+            if len == 0 {
+                return Ok(None);
+            }
+            break;
+        }
+        if byte[0] == b'\n' {
+            break;
+        }
+        buffer[len] = byte[0];
+        len += 1;
+    }
+    core::str::from_utf8(&buffer[..len]).map(Some).map_err(|_| crate::Error::InvalidArgument)
+}
+
+/// Read kernel log records from sequence number `from` on into `buffer`,
+/// a line each as `<priority>,<sequence>,<microseconds>,-;<module>: <text>`;
+/// returns how many bytes were read, whole lines only, 0 with none left
+pub fn kernel_log(buffer: &mut [u8], from: u64) -> Result<usize> {
+    sys!(SYS_KLOG_READ, buffer.as_mut_ptr(), buffer.len(), from).map(|len| len as usize)
+}
+
+/// Formats into a buffer, writing whole UTF-8 sequences out as it fills
+struct Console {
+    fd: u64,
+    buffer: [u8; BUFFER_SIZE],
+    len: usize,
+}
+
+impl Console {
+    fn flush(&mut self) {
+        let _ = write_all(self.fd, &self.buffer[..self.len]);
+        self.len = 0;
+    }
+}
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let mut bytes = [0u8; 4];
+            let bytes = c.encode_utf8(&mut bytes).as_bytes();
+            if self.len + bytes.len() > BUFFER_SIZE {
+                self.flush();
+            }
+            self.buffer[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+            self.len += bytes.len();
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(fd: u64, args: fmt::Arguments) {
+    let mut console = Console {
+        fd,
+        buffer: [0; BUFFER_SIZE],
+        len: 0,
+    };
+    let _ = console.write_fmt(args);
+    console.flush();
+}
+
+/// Print to standard output
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::io::_print($crate::io::STDOUT, format_args!($($arg)*))
+    };
+}
+
+/// Print a line to standard output
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($fmt:expr) => ($crate::print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::print!(concat!($fmt, "\n"), $($arg)*));
+}
+
+/// Print to standard error
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => {
+        $crate::io::_print($crate::io::STDERR, format_args!($($arg)*))
+    };
+}
+
+/// Print a line to standard error
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($fmt:expr) => ($crate::eprint!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::eprint!(concat!($fmt, "\n"), $($arg)*));
+}
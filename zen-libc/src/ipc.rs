@@ -0,0 +1,71 @@
+//! IPC channels
+//!
+//! Channels carry typed messages of up to [`MAX_MESSAGE_SIZE`] bytes.
+//! Services publish theirs under a name, found with [`Channel::lookup`].
+
+use crate::syscall::{sys, SYS_IPC_CREATE, SYS_IPC_LOOKUP, SYS_IPC_RECV, SYS_IPC_SEND};
+use crate::{Error, Result};
+
+/// Largest message payload
+pub const MAX_MESSAGE_SIZE: usize = 4096;
+
+/// Header of a received message, as the kernel lays it out
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessageHeader {
+    pub id: u64,
+    /// Process that sent the message, 0 for the kernel
+    pub sender: u32,
+    pub receiver: u32,
+    /// Payload length as sent, which may exceed what was received
+    pub length: u32,
+    pub msg_type: u32,
+}
+
+/// A channel, by its kernel ID
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Channel(pub u64);
+
+impl Channel {
+    /// Create a channel
+    pub fn create() -> Result<Self> {
+        sys!(SYS_IPC_CREATE).map(Channel)
+    }
+
+    /// The channel published under `name`
+    pub fn lookup(name: &str) -> Result<Self> {
+        sys!(SYS_IPC_LOOKUP, name.as_ptr(), name.len()).map(Channel)
+    }
+
+    /// Send a message
+    pub fn send(&self, msg_type: u32, data: &[u8]) -> Result<()> {
+        if data.len() > MAX_MESSAGE_SIZE {
+            return Err(Error::TooLarge);
+        }
+        sys!(SYS_IPC_SEND, self.0, msg_type, data.as_ptr(), data.len()).map(|_| ())
+    }
+
+    /// Take a message into `buffer` if one is waiting, returning its header
+    /// and the bytes received
+    pub fn try_recv(&self, buffer: &mut [u8]) -> Result<(MessageHeader, usize)> {
+        let mut header = MessageHeader::default();
+        let len = sys!(
+            SYS_IPC_RECV,
+            self.0,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut header as *mut MessageHeader
+        )?;
+        Ok((header, len as usize))
+    }
+
+    /// Wait for a message, yielding until one arrives
+    pub fn recv(&self, buffer: &mut [u8]) -> Result<(MessageHeader, usize)> {
+        loop {
+            match self.try_recv(buffer) {
+                Err(Error::WouldBlock) => crate::process::yield_now(),
+                result => return result,
+            }
+        }
+    }
+}
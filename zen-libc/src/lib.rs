@@ -0,0 +1,62 @@
+//! Zen OS userspace runtime
+//!
+//! Everything a static Zen OS executable needs without reaching into the
+//! kernel: the `_start` entry point, typed wrappers for every system call,
+//! threads, a heap, handles, console printing and IPC channels. The call
+//! numbers, argument order and error codes here are the kernel's ABI and
+//! only ever grow.
+//!
+//! A program names its entry function with [`entry!`]:
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! zen_libc::entry!(main);
+//!
+//! fn main() -> i32 {
+//!     zen_libc::println!("hello from pid {}", zen_libc::process::getpid());
+//!     0
+//! }
+//! ```
+//!
+//! Build for `x86_64-unknown-none`. A static link is simplest; the
+//! kernel also loads position-independent executables and links programs
+//! against shared objects stored in TagFS under their names as tags.
+
+#![no_std]
+
+pub mod aio;
+pub mod env;
+pub mod exception;
+pub mod handle;
+pub mod io;
+pub mod ipc;
+pub mod perf;
+pub mod process;
+pub mod syscall;
+pub mod tagfs;
+pub mod thread;
+
+#[cfg(feature = "heap")]
+pub mod heap;
+
+mod start;
+
+pub use syscall::Error;
+
+/// Result of a system call
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Name the program's entry function, `fn() -> i32`; its return value is
+/// the exit status
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[no_mangle]
+        extern "C" fn __zen_main() -> i32 {
+            let main: fn() -> i32 = $main;
+            main()
+        }
+    };
+}
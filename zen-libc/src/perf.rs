@@ -0,0 +1,53 @@
+//! Performance counters
+//!
+//! The kernel counts cycles, instructions and last-level cache misses for
+//! each thread it is asked to, only while that thread runs. Threads of
+//! other processes can be counted with the `trace` permission.
+
+use crate::syscall::{sys, SYS_PERF};
+use crate::Result;
+
+const PERF_START: u64 = 0;
+const PERF_STOP: u64 = 1;
+const PERF_READ: u64 = 2;
+
+/// Counters, a bit each in what [`start`], [`stop`] and [`read`] return
+pub const CYCLES: u32 = 1 << 0;
+pub const INSTRUCTIONS: u32 = 1 << 1;
+pub const LLC_MISSES: u32 = 1 << 2;
+
+/// What has been counted for a thread
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Counts {
+    pub cycles: u64,
+    pub instructions: u64,
+    /// 0 where the processor cannot count them
+    pub llc_misses: u64,
+    counting: u64,
+}
+
+impl Counts {
+    /// Whether the thread is still being counted
+    pub fn counting(&self) -> bool {
+        self.counting != 0
+    }
+}
+
+/// Count thread `tid` (0 for the caller) from zero, returning the
+/// counters the processor has; `NotFound` if it has none
+pub fn start(tid: u32) -> Result<u32> {
+    sys!(SYS_PERF, PERF_START, tid).map(|counters| counters as u32)
+}
+
+/// Stop counting thread `tid` (0 for the caller), keeping its counts
+pub fn stop(tid: u32) -> Result<u32> {
+    sys!(SYS_PERF, PERF_STOP, tid).map(|counters| counters as u32)
+}
+
+/// What has been counted for thread `tid` (0 for the caller)
+pub fn read(tid: u32) -> Result<Counts> {
+    let mut counts = Counts::default();
+    sys!(SYS_PERF, PERF_READ, tid, &mut counts as *mut Counts)?;
+    Ok(counts)
+}
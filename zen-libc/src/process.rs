@@ -0,0 +1,162 @@
+//! Processes
+
+use crate::syscall::{
+    sys, SYS_EXEC, SYS_EXIT, SYS_FORK, SYS_GETPID, SYS_PERSONALITY, SYS_POWER, SYS_PROCESS_LIST, SYS_SPAWN,
+    SYS_STACK_LIMIT, SYS_TICKS, SYS_WAIT, SYS_YIELD,
+};
+use crate::{Error, Result};
+
+/// Bytes of strings one argument or environment vector may hold
+pub const MAX_STRINGS_SIZE: usize = 4096;
+
+/// Sizes the main stack may be limited to; it grows as it is used
+pub const MIN_STACK_LIMIT: usize = 16 * 1024;
+pub const MAX_STACK_LIMIT: usize = 1024 * 1024 * 1024;
+pub const DEFAULT_STACK_LIMIT: usize = 1024 * 1024;
+
+/// Where a process is in its life, as [`list`] reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    Waiting,
+    Exited(i32),
+}
+
+/// One process in the tree [`list`] fills in
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessRecord {
+    pub pid: u32,
+    pub parent: u32,
+    /// Depth in the tree, 0 for children of init
+    pub depth: u32,
+    state: u32,
+    status: i32,
+    pub threads: u32,
+}
+
+impl ProcessRecord {
+    pub fn state(&self) -> State {
+        match self.state {
+            0 => State::Ready,
+            1 => State::Running,
+            2 => State::Waiting,
+            _ => State::Exited(self.status),
+        }
+    }
+}
+
+/// Strings packed back to back, each ending in NUL, as the kernel takes
+/// them
+struct Packed {
+    data: [u8; MAX_STRINGS_SIZE],
+    len: usize,
+}
+
+impl Packed {
+    fn new(strings: &[&str]) -> Result<Self> {
+        let mut packed = Packed {
+            data: [0; MAX_STRINGS_SIZE],
+            len: 0,
+        };
+        for s in strings {
+            let end = packed.len + s.len() + 1;
+            if s.contains('\0') || end > MAX_STRINGS_SIZE {
+                return Err(Error::InvalidArgument);
+            }
+            packed.data[packed.len..end - 1].copy_from_slice(s.as_bytes());
+            packed.len = end;
+        }
+        Ok(packed)
+    }
+}
+
+/// End the program with `status`
+pub fn exit(status: i32) -> ! {
+    let _ = sys!(SYS_EXIT, status as i64);
+    unreachable!("exit returned")
+}
+
+/// Give up the rest of this time slice
+pub fn yield_now() {
+    let _ = sys!(SYS_YIELD);
+}
+
+/// Timer ticks since boot
+pub fn ticks() -> u64 {
+    sys!(SYS_TICKS).unwrap_or(0)
+}
+
+/// This process's ID
+pub fn getpid() -> u32 {
+    sys!(SYS_GETPID).unwrap_or(0) as u32
+}
+
+/// Start the executable in a TagFS object as a child with arguments and
+/// environment (`NAME=value` strings), returning its ID
+pub fn spawn(object: u64, args: &[&str], env: &[&str]) -> Result<u32> {
+    let args = Packed::new(args)?;
+    let env = Packed::new(env)?;
+    sys!(SYS_SPAWN, object, args.data.as_ptr(), args.len, env.data.as_ptr(), env.len).map(|pid| pid as u32)
+}
+
+/// Replace this program with the executable in a TagFS object, with
+/// arguments and environment; returns only if that fails
+pub fn exec(object: u64, args: &[&str], env: &[&str]) -> Result<core::convert::Infallible> {
+    let args = Packed::new(args)?;
+    let env = Packed::new(env)?;
+    sys!(SYS_EXEC, object, args.data.as_ptr(), args.len, env.data.as_ptr(), env.len)?;
+    unreachable!("exec returned")
+}
+
+/// Fork this process: the child's ID in the parent, 0 in the child
+pub fn fork() -> Result<u32> {
+    sys!(SYS_FORK).map(|pid| pid as u32)
+}
+
+/// Wait for a child to exit (any child when `child` is 0), returning its
+/// ID and status
+pub fn wait(child: u32) -> Result<(u32, i32)> {
+    let mut status = 0i32;
+    let pid = sys!(SYS_WAIT, child, &mut status as *mut i32)?;
+    Ok((pid as u32, status))
+}
+
+/// Set how far the main stack may grow, returning the previous limit;
+/// growing past it raises [`STACK_OVERFLOW`](crate::exception::STACK_OVERFLOW)
+pub fn set_stack_limit(limit: usize) -> Result<usize> {
+    sys!(SYS_STACK_LIMIT, limit).map(|old| old as usize)
+}
+
+/// The current limit of the main stack
+pub fn stack_limit() -> Result<usize> {
+    sys!(SYS_STACK_LIMIT, 0).map(|limit| limit as usize)
+}
+
+/// Turn the machine off; returns only if that fails. Needs the `power`
+/// permission.
+pub fn power_off() -> Result<core::convert::Infallible> {
+    sys!(SYS_POWER, 0)?;
+    unreachable!("power off returned")
+}
+
+/// Reboot the machine; returns only without the `power` permission
+pub fn reboot() -> Result<core::convert::Infallible> {
+    sys!(SYS_POWER, 1)?;
+    unreachable!("reboot returned")
+}
+
+/// Make every later system call of this process a Linux x86-64 one
+///
+/// Nothing in this library works afterwards; this is for a loader about
+/// to jump into a Linux program it has mapped.
+pub fn enter_linux_personality() -> Result<()> {
+    sys!(SYS_PERSONALITY, 1).map(|_| ())
+}
+
+/// Fill `records` with the process tree, parents before their children,
+/// returning how many processes there are, which may be more than fit
+pub fn list(records: &mut [ProcessRecord]) -> Result<usize> {
+    sys!(SYS_PROCESS_LIST, records.as_mut_ptr(), records.len()).map(|count| count as usize)
+}
@@ -0,0 +1,37 @@
+//! Program entry
+//!
+//! The kernel enters `_start` with `argc`, `argv` and `envp` in `rdi`,
+//! `rsi` and `rdx` and the stack pointer just below them on a fresh stack.
+//! `_start` records them for [`crate::env`], calls the function named with
+//! [`entry!`](crate::entry) and exits with its status.
+
+use core::arch::global_asm;
+
+global_asm!(
+    r#"
+.global _start
+_start:
+    xor rbp, rbp
+    and rsp, -16
+    call {start}
+    ud2
+"#,
+    start = sym start,
+);
+
+extern "C" {
+    fn __zen_main() -> i32;
+}
+
+extern "C" fn start(argc: usize, argv: *const *const u8, envp: *const *const u8) -> ! {
+    unsafe { crate::env::init(argc, argv, envp) };
+    let status = unsafe { __zen_main() };
+    crate::process::exit(status)
+}
+
+#[cfg(feature = "panic-handler")]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    crate::println!("panic: {}", info);
+    crate::process::exit(101)
+}
@@ -0,0 +1,161 @@
+//! Raw system calls
+//!
+//! The call number goes in `rax` and up to six arguments in `rdi`, `rsi`,
+//! `rdx`, `r10`, `r8` and `r9`. The kernel returns a value in `rax`, or a
+//! negated error code; `rcx` and `r11` are clobbered by `syscall` itself.
+
+use core::arch::asm;
+
+/// Call numbers
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_YIELD: u64 = 1;
+pub const SYS_TICKS: u64 = 2;
+pub const SYS_IPC_CREATE: u64 = 3;
+pub const SYS_IPC_SEND: u64 = 4;
+pub const SYS_IPC_RECV: u64 = 5;
+pub const SYS_IPC_LOOKUP: u64 = 6;
+pub const SYS_TAGFS_CREATE: u64 = 7;
+pub const SYS_TAGFS_READ: u64 = 8;
+pub const SYS_TAGFS_QUERY: u64 = 9;
+pub const SYS_TAGFS_SIZE: u64 = 10;
+pub const SYS_TAGFS_DELETE: u64 = 11;
+pub const SYS_MAP: u64 = 12;
+pub const SYS_SPAWN: u64 = 13;
+pub const SYS_EXEC: u64 = 14;
+pub const SYS_WAIT: u64 = 15;
+pub const SYS_GETPID: u64 = 16;
+pub const SYS_FORK: u64 = 17;
+pub const SYS_LOG: u64 = 18;
+pub const SYS_THREAD_CREATE: u64 = 19;
+pub const SYS_THREAD_EXIT: u64 = 20;
+pub const SYS_THREAD_JOIN: u64 = 21;
+pub const SYS_GETTID: u64 = 22;
+pub const SYS_THREAD_PRIORITY: u64 = 23;
+pub const SYS_THREAD_AFFINITY: u64 = 24;
+pub const SYS_SET_FS_BASE: u64 = 25;
+pub const SYS_SET_EXCEPTION_HANDLER: u64 = 26;
+pub const SYS_EXCEPTION_RETURN: u64 = 27;
+pub const SYS_PROCESS_LIST: u64 = 28;
+pub const SYS_READ: u64 = 29;
+pub const SYS_WRITE: u64 = 30;
+pub const SYS_STACK_LIMIT: u64 = 31;
+pub const SYS_BRK: u64 = 32;
+pub const SYS_SBRK: u64 = 33;
+pub const SYS_OPEN: u64 = 34;
+pub const SYS_CLOSE: u64 = 35;
+pub const SYS_DUP: u64 = 36;
+pub const SYS_SEEK: u64 = 37;
+pub const SYS_PERSONALITY: u64 = 38;
+pub const SYS_FUTEX_WAIT: u64 = 39;
+pub const SYS_FUTEX_WAKE: u64 = 40;
+pub const SYS_KLOG_READ: u64 = 41;
+pub const SYS_POWER: u64 = 42;
+pub const SYS_PERF: u64 = 43;
+pub const SYS_IO: u64 = 44;
+
+/// Protection bits of `SYS_MAP`; pages are always readable
+pub const PROT_WRITE: u64 = 1 << 1;
+pub const PROT_EXEC: u64 = 1 << 2;
+
+/// Errors the kernel returns, by code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    InvalidSyscall,
+    /// A pointer argument is not accessible memory
+    BadAddress,
+    InvalidArgument,
+    PermissionDenied,
+    NotFound,
+    OutOfMemory,
+    /// Nothing to receive yet
+    WouldBlock,
+    TooLarge,
+    IoError,
+    /// No room left in a kernel table
+    Exhausted,
+    /// Not a valid executable
+    BadExecutable,
+    /// No such child to wait for
+    NoChild,
+    /// Not a handle the process holds
+    BadHandle,
+    /// Written to a pipe nothing reads
+    BrokenPipe,
+    /// A wait ran out of time
+    TimedOut,
+    /// A code this version does not know
+    Unknown(i64),
+}
+
+impl Error {
+    pub(crate) fn from_code(code: i64) -> Self {
+        match code {
+            1 => Error::InvalidSyscall,
+            2 => Error::BadAddress,
+            3 => Error::InvalidArgument,
+            4 => Error::PermissionDenied,
+            5 => Error::NotFound,
+            6 => Error::OutOfMemory,
+            7 => Error::WouldBlock,
+            8 => Error::TooLarge,
+            9 => Error::IoError,
+            10 => Error::Exhausted,
+            11 => Error::BadExecutable,
+            12 => Error::NoChild,
+            13 => Error::BadHandle,
+            14 => Error::BrokenPipe,
+            15 => Error::TimedOut,
+            code => Error::Unknown(code),
+        }
+    }
+}
+
+/// Make a system call, returning its raw result
+///
+/// # Safety
+/// Pointer arguments must be valid for what the call does with them.
+#[inline(always)]
+pub unsafe fn syscall(number: u64, args: [u64; 6]) -> i64 {
+    let result: i64;
+    asm!(
+        "syscall",
+        inlateout("rax") number as i64 => result,
+        in("rdi") args[0],
+        in("rsi") args[1],
+        in("rdx") args[2],
+        in("r10") args[3],
+        in("r8") args[4],
+        in("r9") args[5],
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack),
+    );
+    result
+}
+
+/// Make a system call, splitting its result into a value or an error
+///
+/// # Safety
+/// As for [`syscall`].
+#[inline(always)]
+pub unsafe fn call(number: u64, args: [u64; 6]) -> crate::Result<u64> {
+    let result = syscall(number, args);
+    if result < 0 {
+        Err(Error::from_code(-result))
+    } else {
+        Ok(result as u64)
+    }
+}
+
+/// [`call`] with fewer arguments, the rest zero; callers make sure the
+/// pointers they pass are valid
+macro_rules! sys {
+    ($number:expr $(, $arg:expr)* $(,)?) => {{
+        let mut args = [0u64; 6];
+        let given = [$($arg as u64),*];
+        args[..given.len()].copy_from_slice(&given);
+        unsafe { $crate::syscall::call($number, args) }
+    }};
+}
+
+pub(crate) use sys;
@@ -0,0 +1,41 @@
+//! TagFS objects
+
+use crate::syscall::{sys, SYS_TAGFS_CREATE, SYS_TAGFS_DELETE, SYS_TAGFS_QUERY, SYS_TAGFS_READ, SYS_TAGFS_SIZE};
+use crate::{Error, Result};
+
+/// Longest tag
+pub const MAX_TAG_LEN: usize = 32;
+
+fn check_tag(tag: &str) -> Result<()> {
+    if tag.is_empty() || tag.len() > MAX_TAG_LEN {
+        return Err(Error::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// Create an object with one tag, returning its ID
+pub fn create(tag: &str, data: &[u8]) -> Result<u64> {
+    check_tag(tag)?;
+    sys!(SYS_TAGFS_CREATE, tag.as_ptr(), tag.len(), data.as_ptr(), data.len())
+}
+
+/// Read from an object at `offset`, returning the bytes read
+pub fn read(object: u64, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+    sys!(SYS_TAGFS_READ, object, offset, buffer.as_mut_ptr(), buffer.len()).map(|len| len as usize)
+}
+
+/// The object with a tag
+pub fn query(tag: &str) -> Result<u64> {
+    check_tag(tag)?;
+    sys!(SYS_TAGFS_QUERY, tag.as_ptr(), tag.len())
+}
+
+/// Size of an object in bytes
+pub fn size(object: u64) -> Result<u64> {
+    sys!(SYS_TAGFS_SIZE, object)
+}
+
+/// Delete an object
+pub fn delete(object: u64) -> Result<()> {
+    sys!(SYS_TAGFS_DELETE, object).map(|_| ())
+}
@@ -0,0 +1,170 @@
+//! Threads
+//!
+//! Threads share the process's memory and each run on a stack of their
+//! own, taken from a fixed set of slots above the heap. A slot is mapped
+//! the first time it is used and handed out again once its thread has
+//! been joined; the unmapped half below each stack catches overflows.
+//! The kernel gives every thread its own copy of the program's
+//! `#[thread_local]` data, reached through `fs`.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::syscall::{
+    sys, PROT_WRITE, SYS_FUTEX_WAIT, SYS_FUTEX_WAKE, SYS_GETTID, SYS_MAP, SYS_SET_FS_BASE, SYS_THREAD_AFFINITY,
+    SYS_THREAD_CREATE, SYS_THREAD_EXIT, SYS_THREAD_JOIN, SYS_THREAD_PRIORITY,
+};
+use crate::{Error, Result};
+
+/// Start of the thread stack region
+pub const STACKS_START: usize = 0x60_0000_0000;
+
+/// Size of one thread's stack
+pub const STACK_SIZE: usize = 64 * 1024;
+
+/// Address space each slot takes, its stack and the guard below it
+const SLOT_SIZE: usize = 2 * STACK_SIZE;
+
+/// Threads that can have a stack at once
+pub const MAX_THREADS: usize = 64;
+
+/// Strides the kernel accepts; a smaller stride runs more often
+pub const MIN_STRIDE: u32 = 10;
+pub const MAX_STRIDE: u32 = 10_000;
+pub const DEFAULT_STRIDE: u32 = 100;
+
+/// Slots in use, one bit each
+static USED: AtomicU64 = AtomicU64::new(0);
+
+/// Slots whose stack is mapped
+static MAPPED: AtomicU64 = AtomicU64::new(0);
+
+/// What a new thread finds at the top of its stack
+#[repr(C)]
+struct Start {
+    main: fn(u64) -> i32,
+    arg: u64,
+}
+
+/// A thread to join
+#[derive(Debug)]
+pub struct JoinHandle {
+    tid: u32,
+    slot: usize,
+}
+
+impl JoinHandle {
+    /// The thread's ID
+    pub fn tid(&self) -> u32 {
+        self.tid
+    }
+
+    /// Wait for the thread to exit, returning its status
+    pub fn join(self) -> Result<i32> {
+        let mut status = 0i32;
+        sys!(SYS_THREAD_JOIN, self.tid, &mut status as *mut i32)?;
+        release(self.slot);
+        Ok(status)
+    }
+}
+
+fn claim() -> Result<usize> {
+    let mut used = USED.load(Ordering::Relaxed);
+    loop {
+        let slot = (!used).trailing_zeros() as usize;
+        if slot >= MAX_THREADS {
+            return Err(Error::Exhausted);
+        }
+        match USED.compare_exchange_weak(used, used | 1 << slot, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => return Ok(slot),
+            Err(current) => used = current,
+        }
+    }
+}
+
+fn release(slot: usize) {
+    USED.fetch_and(!(1 << slot), Ordering::Release);
+}
+
+/// Top of the stack in `slot`, mapping it if it never was
+fn stack(slot: usize) -> Result<usize> {
+    let bottom = STACKS_START + slot * SLOT_SIZE + STACK_SIZE;
+    if MAPPED.load(Ordering::Relaxed) & 1 << slot == 0 {
+        sys!(SYS_MAP, bottom, STACK_SIZE, PROT_WRITE)?;
+        MAPPED.fetch_or(1 << slot, Ordering::Relaxed);
+    }
+    Ok(bottom + STACK_SIZE)
+}
+
+extern "C" fn thread_main(start: *const Start) -> ! {
+    let Start { main, arg } = unsafe { start.read() };
+    exit(main(arg))
+}
+
+/// Start a thread running `main(arg)`; its return value is the thread's
+/// exit status
+pub fn spawn(main: fn(u64) -> i32, arg: u64) -> Result<JoinHandle> {
+    let slot = claim()?;
+    let top = match stack(slot) {
+        Ok(top) => top,
+        Err(e) => {
+            release(slot);
+            return Err(e);
+        }
+    };
+    // `thread_main` is entered as if called, with the stack 8 bytes off
+    // 16-byte alignment
+    let start = (top - core::mem::size_of::<Start>()) as *mut Start;
+    unsafe { start.write(Start { main, arg }) };
+    let entry = thread_main as extern "C" fn(*const Start) -> ! as usize;
+    match sys!(SYS_THREAD_CREATE, entry, start as usize - 8, start) {
+        Ok(tid) => Ok(JoinHandle { tid: tid as u32, slot }),
+        Err(e) => {
+            release(slot);
+            Err(e)
+        }
+    }
+}
+
+/// End the calling thread with `status`; the last thread ends the process
+pub fn exit(status: i32) -> ! {
+    let _ = sys!(SYS_THREAD_EXIT, status as i64);
+    unreachable!("thread exit returned")
+}
+
+/// The calling thread's ID; the first thread's is the process ID
+pub fn gettid() -> u32 {
+    sys!(SYS_GETTID).unwrap_or(0) as u32
+}
+
+/// Set the stride of a thread of this process (0 for the caller)
+pub fn set_priority(tid: u32, stride: u32) -> Result<()> {
+    sys!(SYS_THREAD_PRIORITY, tid, stride).map(|_| ())
+}
+
+/// Wait while `word` holds `value`, until another thread wakes it or
+/// `ticks` pass (0 for no limit); `WouldBlock` if it no longer holds it
+pub fn futex_wait(word: &AtomicU32, value: u32, ticks: u64) -> Result<()> {
+    sys!(SYS_FUTEX_WAIT, word.as_ptr(), value, ticks).map(|_| ())
+}
+
+/// Wake up to `count` threads waiting on `word`, returning how many
+pub fn futex_wake(word: &AtomicU32, count: u32) -> usize {
+    sys!(SYS_FUTEX_WAKE, word.as_ptr(), count).unwrap_or(0) as usize
+}
+
+/// Set the CPUs, among the first 64, that may run a thread of this
+/// process (0 for the caller); the mask must include CPU 0, the only one
+/// that runs user threads so far
+pub fn set_affinity(tid: u32, mask: u64) -> Result<()> {
+    sys!(SYS_THREAD_AFFINITY, tid, mask).map(|_| ())
+}
+
+/// Point the calling thread's `fs` base at a TLS block laid out by the
+/// caller, in place of the one the kernel set up
+///
+/// # Safety
+/// Thread-local accesses go through the new base from then on; it must
+/// point at a valid block whose first word is its own address.
+pub unsafe fn set_fs_base(addr: usize) -> Result<()> {
+    sys!(SYS_SET_FS_BASE, addr).map(|_| ())
+}